@@ -0,0 +1,185 @@
+// Benchmarks are programs run a number of times, recording the median time
+// each run took and the number of instructions it ran. Results are saved as
+// JSON so that a later run can be compared against them.
+use std::fmt::Write;
+
+use crate::json::{self, quote, Json};
+
+// Changes in time smaller than this percentage are taken to be noise.
+pub const THRESHOLD: f64 = 5.0;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sample {
+    pub name: String,
+    pub runs: usize,
+    pub median_ms: f64,
+    pub instructions: u64,
+}
+
+// Writes the samples as a JSON object, along with the version that ran them.
+pub fn to_json(version: &str, samples: &[Sample]) -> String {
+    let mut json = format!(
+        "{{\n  \"version\": {},\n  \"benchmarks\": [",
+        quote(version)
+    );
+    for (i, sample) in samples.iter().enumerate() {
+        let _ = write!(
+            json,
+            "{}\n    {{\"name\": {}, \"runs\": {}, \"median_ms\": {}, \"instructions\": {}}}",
+            if i == 0 { "" } else { "," },
+            quote(&sample.name),
+            sample.runs,
+            sample.median_ms,
+            sample.instructions
+        );
+    }
+    json.push_str(if samples.is_empty() {
+        "]\n}\n"
+    } else {
+        "\n  ]\n}\n"
+    });
+    json
+}
+
+// Reads the samples from JSON written by to_json. Other fields are ignored,
+// so that files written by later versions can still be compared.
+pub fn from_json(s: &str) -> Option<Vec<Sample>> {
+    let benchmarks = match json::parse(s)? {
+        Json::Object(mut fields) => fields.remove("benchmarks")?,
+        _ => return None,
+    };
+    let mut samples = Vec::new();
+    match benchmarks {
+        Json::Array(benchmarks) => {
+            for benchmark in benchmarks {
+                let fields = match benchmark {
+                    Json::Object(fields) => fields,
+                    _ => return None,
+                };
+                let number = |field: &str| match fields.get(field) {
+                    Some(Json::Number(n)) if *n >= 0.0 => Some(*n),
+                    _ => None,
+                };
+                samples.push(Sample {
+                    name: match fields.get("name") {
+                        Some(Json::String(name)) => name.to_string(),
+                        _ => return None,
+                    },
+                    runs: number("runs")? as usize,
+                    median_ms: number("median_ms")?,
+                    instructions: number("instructions")? as u64,
+                });
+            }
+        }
+        _ => return None,
+    }
+    Some(samples)
+}
+
+// The percentage by which a measurement changed from its baseline.
+fn change(old: f64, new: f64) -> f64 {
+    if old == 0.0 {
+        0.0
+    } else {
+        100.0 * (new - old) / old
+    }
+}
+
+// Compares samples against a baseline by name, returning a line for each
+// sample and the number of regressions and improvements in time.
+pub fn compare(baseline: &[Sample], samples: &[Sample]) -> (Vec<String>, usize, usize) {
+    let mut lines = Vec::new();
+    let (mut regressions, mut improvements) = (0, 0);
+    for sample in samples {
+        let old = match baseline.iter().find(|old| old.name == sample.name) {
+            Some(old) => old,
+            None => {
+                lines.push(format!("{}: new", sample.name));
+                continue;
+            }
+        };
+        let time = change(old.median_ms, sample.median_ms);
+        let verdict = if time > THRESHOLD {
+            regressions += 1;
+            "regression"
+        } else if time < -THRESHOLD {
+            improvements += 1;
+            "improvement"
+        } else {
+            "no change"
+        };
+        lines.push(format!(
+            "{}: {:.3} ms -> {:.3} ms ({:+.1}%), {} -> {} instructions ({:+.1}%), {}",
+            sample.name,
+            old.median_ms,
+            sample.median_ms,
+            time,
+            old.instructions,
+            sample.instructions,
+            change(old.instructions as f64, sample.instructions as f64),
+            verdict
+        ));
+    }
+    (lines, regressions, improvements)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bench::{compare, from_json, to_json, Sample};
+
+    #[test]
+    fn results() {
+        let samples = vec![
+            Sample {
+                name: "examples/fact.plover".to_string(),
+                runs: 10,
+                median_ms: 1.5,
+                instructions: 1200,
+            },
+            Sample {
+                name: "a \"quoted\"\tname".to_string(),
+                runs: 3,
+                median_ms: 0.25,
+                instructions: 0,
+            },
+        ];
+        let json = to_json("0.1.0 (abc)", &samples);
+        assert!(json.contains("\"name\": \"a \\\"quoted\\\"\\tname\""));
+        assert_eq!(from_json(&json), Some(samples.clone()));
+        assert_eq!(from_json(&to_json("", &[])), Some(Vec::new()));
+        assert_eq!(
+            from_json("{\"benchmarks\": [{\"name\": \"\\u0041\", \"runs\": 1, \"median_ms\": 2e0, \"instructions\": 3, \"extra\": [null, true]}]}"),
+            Some(vec![Sample {
+                name: "A".to_string(),
+                runs: 1,
+                median_ms: 2.0,
+                instructions: 3,
+            }])
+        );
+        assert_eq!(from_json("{\"benchmarks\": [}"), None);
+        assert_eq!(from_json("[]"), None);
+
+        let mut newer = samples.clone();
+        newer[0].median_ms = 1.2;
+        newer[0].instructions = 900;
+        newer[1].median_ms = 0.26;
+        newer.push(Sample {
+            name: "other".to_string(),
+            runs: 10,
+            median_ms: 1.0,
+            instructions: 10,
+        });
+        let (lines, regressions, improvements) = compare(&samples, &newer);
+        assert_eq!(
+            lines,
+            vec![
+                "examples/fact.plover: 1.500 ms -> 1.200 ms (-20.0%), 1200 -> 900 instructions (-25.0%), improvement",
+                "a \"quoted\"\tname: 0.250 ms -> 0.260 ms (+4.0%), 0 -> 0 instructions (+0.0%), no change",
+                "other: new",
+            ]
+        );
+        assert_eq!((regressions, improvements), (0, 1));
+        let (_, regressions, improvements) = compare(&newer, &samples);
+        assert_eq!((regressions, improvements), (1, 0));
+    }
+}
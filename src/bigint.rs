@@ -0,0 +1,384 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::ops::{Add, Mul, Neg, Sub};
+use std::str::FromStr;
+
+// An arbitrary precision integer, stored as a sign and a magnitude of 32 bit
+// digits with the least significant first. The magnitude never has leading
+// zero digits, so zero is the empty magnitude and is never negative.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct BigInt {
+    negative: bool,
+    magnitude: Vec<u32>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ParseBigIntError;
+
+impl BigInt {
+    fn new(negative: bool, mut magnitude: Vec<u32>) -> BigInt {
+        while magnitude.last() == Some(&0) {
+            magnitude.pop();
+        }
+        BigInt {
+            negative: negative && !magnitude.is_empty(),
+            magnitude,
+        }
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.magnitude.is_empty()
+    }
+
+    pub fn abs(&self) -> BigInt {
+        BigInt::new(false, self.magnitude.clone())
+    }
+
+    pub fn to_i64(&self) -> Option<i64> {
+        if self.magnitude.len() > 2 {
+            return None;
+        }
+        let mut value: u64 = 0;
+        for digit in self.magnitude.iter().rev() {
+            value = (value << 32) | u64::from(*digit);
+        }
+        if self.negative {
+            if value <= i64::MAX as u64 + 1 {
+                Some((value as i64).wrapping_neg())
+            } else {
+                None
+            }
+        } else if value <= i64::MAX as u64 {
+            Some(value as i64)
+        } else {
+            None
+        }
+    }
+
+    pub fn pow(&self, mut exponent: u64) -> BigInt {
+        let mut result = BigInt::from(1);
+        let mut base = self.clone();
+        while exponent > 0 {
+            if exponent & 1 == 1 {
+                result = &result * &base;
+            }
+            exponent >>= 1;
+            if exponent > 0 {
+                base = &base * &base;
+            }
+        }
+        result
+    }
+
+    // Division truncates towards zero, and the remainder has the sign of the
+    // dividend, as for i64. Both are None when dividing by zero.
+    pub fn checked_div_rem(&self, other: &BigInt) -> Option<(BigInt, BigInt)> {
+        if other.is_zero() {
+            return None;
+        }
+        let (quotient, remainder) = div_rem(&self.magnitude, &other.magnitude);
+        Some((
+            BigInt::new(self.negative != other.negative, quotient),
+            BigInt::new(self.negative, remainder),
+        ))
+    }
+
+    pub fn checked_div(&self, other: &BigInt) -> Option<BigInt> {
+        self.checked_div_rem(other).map(|(quotient, _)| quotient)
+    }
+
+    pub fn checked_rem(&self, other: &BigInt) -> Option<BigInt> {
+        self.checked_div_rem(other).map(|(_, remainder)| remainder)
+    }
+
+    // Writes the number in a radix from 2 to 36, with lowercase letters for
+    // the digits above nine.
+    pub fn to_str_radix(&self, radix: u32) -> String {
+        if self.is_zero() {
+            return "0".to_string();
+        }
+        let mut digits = Vec::new();
+        let mut magnitude = self.magnitude.clone();
+        while !magnitude.is_empty() {
+            let (quotient, remainder) = div_rem_small(&magnitude, radix);
+            digits.push(std::char::from_digit(remainder, radix).unwrap_or('?'));
+            magnitude = quotient;
+        }
+        if self.negative {
+            digits.push('-');
+        }
+        digits.iter().rev().collect()
+    }
+}
+
+impl From<i64> for BigInt {
+    fn from(value: i64) -> BigInt {
+        let magnitude = value.unsigned_abs();
+        BigInt::new(value < 0, vec![magnitude as u32, (magnitude >> 32) as u32])
+    }
+}
+
+impl FromStr for BigInt {
+    type Err = ParseBigIntError;
+
+    fn from_str(s: &str) -> Result<BigInt, ParseBigIntError> {
+        let (negative, digits) = match s.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, s),
+        };
+        if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(ParseBigIntError);
+        }
+        let mut magnitude = Vec::new();
+        for chunk in digits.as_bytes().chunks(9) {
+            let mut value = 0;
+            for b in chunk {
+                value = value * 10 + u32::from(b - b'0');
+            }
+            mul_small_add(&mut magnitude, 10u32.pow(chunk.len() as u32), value);
+        }
+        Ok(BigInt::new(negative, magnitude))
+    }
+}
+
+impl fmt::Display for BigInt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_zero() {
+            return write!(f, "0");
+        }
+        let mut chunks = Vec::new();
+        let mut magnitude = self.magnitude.clone();
+        while !magnitude.is_empty() {
+            let (quotient, remainder) = div_rem_small(&magnitude, 1_000_000_000);
+            chunks.push(remainder);
+            magnitude = quotient;
+        }
+        if self.negative {
+            write!(f, "-")?;
+        }
+        write!(f, "{}", chunks.pop().unwrap_or(0))?;
+        for chunk in chunks.iter().rev() {
+            write!(f, "{:09}", chunk)?;
+        }
+        Ok(())
+    }
+}
+
+impl Ord for BigInt {
+    fn cmp(&self, other: &BigInt) -> Ordering {
+        match (self.negative, other.negative) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (false, false) => cmp_magnitude(&self.magnitude, &other.magnitude),
+            (true, true) => cmp_magnitude(&other.magnitude, &self.magnitude),
+        }
+    }
+}
+
+impl PartialOrd for BigInt {
+    fn partial_cmp(&self, other: &BigInt) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Neg for &BigInt {
+    type Output = BigInt;
+
+    fn neg(self) -> BigInt {
+        BigInt::new(!self.negative, self.magnitude.clone())
+    }
+}
+
+impl Add for &BigInt {
+    type Output = BigInt;
+
+    fn add(self, other: &BigInt) -> BigInt {
+        if self.negative == other.negative {
+            return BigInt::new(
+                self.negative,
+                add_magnitude(&self.magnitude, &other.magnitude),
+            );
+        }
+        match cmp_magnitude(&self.magnitude, &other.magnitude) {
+            Ordering::Less => BigInt::new(
+                other.negative,
+                sub_magnitude(&other.magnitude, &self.magnitude),
+            ),
+            _ => BigInt::new(
+                self.negative,
+                sub_magnitude(&self.magnitude, &other.magnitude),
+            ),
+        }
+    }
+}
+
+impl Sub for &BigInt {
+    type Output = BigInt;
+
+    fn sub(self, other: &BigInt) -> BigInt {
+        self + &-other
+    }
+}
+
+impl Mul for &BigInt {
+    type Output = BigInt;
+
+    fn mul(self, other: &BigInt) -> BigInt {
+        let mut product = vec![0u32; self.magnitude.len() + other.magnitude.len()];
+        for (i, x) in self.magnitude.iter().enumerate() {
+            let mut carry = 0u64;
+            for (j, y) in other.magnitude.iter().enumerate() {
+                let digit = u64::from(product[i + j]) + u64::from(*x) * u64::from(*y) + carry;
+                product[i + j] = digit as u32;
+                carry = digit >> 32;
+            }
+            product[i + other.magnitude.len()] = carry as u32;
+        }
+        BigInt::new(self.negative != other.negative, product)
+    }
+}
+
+fn cmp_magnitude(x: &[u32], y: &[u32]) -> Ordering {
+    x.len()
+        .cmp(&y.len())
+        .then_with(|| x.iter().rev().cmp(y.iter().rev()))
+}
+
+fn add_magnitude(x: &[u32], y: &[u32]) -> Vec<u32> {
+    let (long, short) = if x.len() >= y.len() { (x, y) } else { (y, x) };
+    let mut sum = Vec::with_capacity(long.len() + 1);
+    let mut carry = 0u64;
+    for (i, digit) in long.iter().enumerate() {
+        let digit = u64::from(*digit) + u64::from(short.get(i).copied().unwrap_or(0)) + carry;
+        sum.push(digit as u32);
+        carry = digit >> 32;
+    }
+    sum.push(carry as u32);
+    sum
+}
+
+// Requires x >= y.
+fn sub_magnitude(x: &[u32], y: &[u32]) -> Vec<u32> {
+    let mut difference = Vec::with_capacity(x.len());
+    let mut borrow = 0i64;
+    for (i, digit) in x.iter().enumerate() {
+        let mut digit = i64::from(*digit) - i64::from(y.get(i).copied().unwrap_or(0)) - borrow;
+        borrow = 0;
+        if digit < 0 {
+            digit += 1 << 32;
+            borrow = 1;
+        }
+        difference.push(digit as u32);
+    }
+    difference
+}
+
+fn mul_small_add(x: &mut Vec<u32>, factor: u32, addend: u32) {
+    let mut carry = u64::from(addend);
+    for digit in x.iter_mut() {
+        let product = u64::from(*digit) * u64::from(factor) + carry;
+        *digit = product as u32;
+        carry = product >> 32;
+    }
+    if carry > 0 {
+        x.push(carry as u32);
+    }
+}
+
+fn div_rem_small(x: &[u32], divisor: u32) -> (Vec<u32>, u32) {
+    let mut quotient = vec![0u32; x.len()];
+    let mut remainder = 0u64;
+    for (i, digit) in x.iter().enumerate().rev() {
+        let dividend = (remainder << 32) | u64::from(*digit);
+        quotient[i] = (dividend / u64::from(divisor)) as u32;
+        remainder = dividend % u64::from(divisor);
+    }
+    while quotient.last() == Some(&0) {
+        quotient.pop();
+    }
+    (quotient, remainder as u32)
+}
+
+// Long division one bit at a time, which is simple and fast enough for the
+// sizes of numbers that scripts work with.
+fn div_rem(x: &[u32], y: &[u32]) -> (Vec<u32>, Vec<u32>) {
+    if y.len() == 1 {
+        let (quotient, remainder) = div_rem_small(x, y[0]);
+        return (quotient, vec![remainder]);
+    }
+    let mut quotient = vec![0u32; x.len()];
+    let mut remainder: Vec<u32> = Vec::new();
+    for i in (0..x.len() * 32).rev() {
+        let bit = (x[i / 32] >> (i % 32)) & 1;
+        mul_small_add(&mut remainder, 2, bit);
+        if cmp_magnitude(&remainder, y) != Ordering::Less {
+            remainder = sub_magnitude(&remainder, y);
+            while remainder.last() == Some(&0) {
+                remainder.pop();
+            }
+            quotient[i / 32] |= 1 << (i % 32);
+        }
+    }
+    (quotient, remainder)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bigint::BigInt;
+
+    fn big(s: &str) -> BigInt {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn arithmetic() {
+        let x = big("123456789012345678901234567890");
+        let y = big("-987654321098765432109876543210");
+        assert_eq!((&x + &y).to_string(), "-864197532086419753208641975320");
+        assert_eq!((&x - &y).to_string(), "1111111110111111111011111111100");
+        assert_eq!(
+            (&x * &y).to_string(),
+            "-121932631137021795226185032733622923332237463801111263526900"
+        );
+        assert_eq!(x.checked_div(&y).unwrap().to_string(), "0");
+        assert_eq!(y.checked_div(&x).unwrap().to_string(), "-8");
+        assert_eq!(
+            y.checked_rem(&x).unwrap().to_string(),
+            "-9000000000900000000090"
+        );
+        assert_eq!((&x - &x).to_string(), "0");
+        assert_eq!(-&(&x - &x), BigInt::from(0));
+        assert!(x.checked_div(&BigInt::from(0)).is_none());
+        assert!(y < x);
+        assert!(big("-2") < big("-1"));
+        assert_eq!(big("-3").pow(41).to_string(), "-36472996377170786403");
+        assert_eq!(x.pow(0), BigInt::from(1));
+        assert_eq!(BigInt::from(0).pow(5), BigInt::from(0));
+        assert_eq!(big("-3").pow(41).to_str_radix(16), "-1fa2a1cf67b5fb863");
+        assert_eq!(BigInt::from(0).to_str_radix(2), "0");
+
+        for (a, b) in [(7, 2), (-7, 2), (7, -2), (-7, -2), (i64::MIN, 3)] {
+            let (x, y) = (BigInt::from(a), BigInt::from(b));
+            assert_eq!(x.checked_div(&y), Some(BigInt::from(a / b)));
+            assert_eq!(x.checked_rem(&y), Some(BigInt::from(a % b)));
+        }
+    }
+
+    #[test]
+    fn conversions() {
+        for n in [0, 1, -1, 1 << 32, i64::MAX, i64::MIN] {
+            assert_eq!(BigInt::from(n).to_i64(), Some(n));
+            assert_eq!(BigInt::from(n).to_string(), n.to_string());
+            assert_eq!(big(&n.to_string()), BigInt::from(n));
+        }
+        assert_eq!(big("9223372036854775808").to_i64(), None);
+        assert_eq!(big("-9223372036854775809").to_i64(), None);
+        assert_eq!(big("-0"), BigInt::from(0));
+        assert_eq!(
+            big("000100000000000000000000").to_string(),
+            "100000000000000000000"
+        );
+        assert!("1e5".parse::<BigInt>().is_err());
+        assert!("-".parse::<BigInt>().is_err());
+    }
+}
@@ -0,0 +1,930 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+use crate::codegen::{ErrorKind, InterpreterError, RuntimeError};
+use crate::panics;
+use crate::symbol::{Names, Symbol};
+use crate::typeinfer::Type;
+use crate::verify;
+use crate::version::BYTECODE_VERSION;
+use crate::vm::{FunctionInfo, Opcode, VirtualMachine};
+
+// A compiled program is written as the magic number and bytecode version,
+// followed by the address of the first instruction of the program and the
+// instructions themselves, including those of the builtins, and then the
+// symbol table sorted by entry ip. Integers are
+// written as little endian, and strings and sequences are prefixed by their
+// length. Each opcode is written as a tag followed by its operands.
+const MAGIC: &[u8] = b"PLBC";
+
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+struct Writer<'a> {
+    bytes: Vec<u8>,
+    names: &'a Names,
+}
+
+impl Writer<'_> {
+    fn byte(&mut self, byte: u8) {
+        self.bytes.push(byte);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes.extend(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.bytes.extend(&value.to_le_bytes());
+    }
+
+    fn usize(&mut self, value: usize) {
+        self.u64(value as u64);
+    }
+
+    fn i64(&mut self, value: i64) {
+        self.bytes.extend(&value.to_le_bytes());
+    }
+
+    fn string(&mut self, s: &str) {
+        self.usize(s.len());
+        self.bytes.extend(s.as_bytes());
+    }
+
+    // Identifiers are written by name, as symbols are only indices into the
+    // names of the virtual machine that compiled the program.
+    fn symbol(&mut self, id: Symbol) {
+        let names = self.names;
+        self.string(names.name(id));
+    }
+
+    fn typ(&mut self, typ: &Type) {
+        match typ {
+            Type::Boolean => self.byte(0),
+            Type::Channel(element) => {
+                self.byte(1);
+                self.typ(element);
+            }
+            Type::Datatype(s) => {
+                self.byte(2);
+                self.string(s);
+            }
+            Type::Float => self.byte(3),
+            Type::Function(param, body) => {
+                self.byte(4);
+                self.typ(param);
+                self.typ(body);
+            }
+            Type::Integer => self.byte(5),
+            Type::Pid => self.byte(6),
+            Type::Polymorphic(s) => {
+                self.byte(7);
+                self.string(s);
+            }
+            Type::Record(fields) => {
+                self.byte(8);
+                self.usize(fields.len());
+                for (field, typ) in fields {
+                    self.string(field);
+                    self.typ(typ);
+                }
+            }
+            Type::String => self.byte(9),
+            Type::Tuple(elements) => {
+                self.byte(10);
+                self.usize(elements.len());
+                for element in elements {
+                    self.typ(element);
+                }
+            }
+            Type::Unit => self.byte(11),
+            Type::BigInt => self.byte(12),
+            Type::Bytes => self.byte(13),
+            Type::Timestamp => self.byte(14),
+            Type::Char => self.byte(15),
+            Type::Array(element) => {
+                self.byte(16);
+                self.typ(element);
+            }
+            Type::Map(key, value) => {
+                self.byte(17);
+                self.typ(key);
+                self.typ(value);
+            }
+            Type::List(element) => {
+                self.byte(18);
+                self.typ(element);
+            }
+        }
+    }
+
+    fn opcode(&mut self, opcode: &Opcode) {
+        match opcode {
+            Opcode::Add => self.byte(0),
+            Opcode::And => self.byte(1),
+            Opcode::Arg(slot) => {
+                self.byte(2);
+                self.usize(*slot);
+            }
+            Opcode::Bconst(b) => {
+                self.byte(3);
+                self.byte(*b as u8);
+            }
+            Opcode::Call => self.byte(4),
+            Opcode::Concat => self.byte(5),
+            Opcode::Div => self.byte(6),
+            Opcode::Dup => self.byte(7),
+            Opcode::Equal => self.byte(8),
+            Opcode::ExtVal => self.byte(9),
+            Opcode::Field(field) => {
+                self.byte(10);
+                self.string(field);
+            }
+            Opcode::Dconst(typ, variant) => {
+                self.byte(11);
+                self.string(typ);
+                self.string(variant);
+            }
+            Opcode::Fconst(id, ip, upvalues) => {
+                self.byte(12);
+                match id {
+                    Some(id) => {
+                        self.byte(1);
+                        self.symbol(*id);
+                    }
+                    None => self.byte(0),
+                }
+                self.usize(*ip);
+                // The upvalues are sorted so that compiling the same program
+                // always gives the same bytecode.
+                let mut upvalues: Vec<_> = upvalues.iter().collect();
+                let names = self.names;
+                upvalues.sort_by_key(|(id, _)| names.name(**id));
+                self.usize(upvalues.len());
+                for (id, (slot, typ)) in upvalues {
+                    self.symbol(*id);
+                    self.usize(*slot);
+                    self.typ(typ);
+                }
+            }
+            Opcode::GetEnv(id) => {
+                self.byte(13);
+                self.symbol(*id);
+            }
+            Opcode::Greater => self.byte(14),
+            Opcode::GreaterEqual => self.byte(15),
+            Opcode::Iconst(i) => {
+                self.byte(16);
+                self.i64(*i);
+            }
+            Opcode::Jmp(offset) => {
+                self.byte(17);
+                self.i64(*offset);
+            }
+            Opcode::Jz(offset) => {
+                self.byte(18);
+                self.i64(*offset);
+            }
+            Opcode::Less => self.byte(19),
+            Opcode::LessEqual => self.byte(20),
+            Opcode::Memo => self.byte(21),
+            Opcode::Mod => self.byte(22),
+            Opcode::Mul => self.byte(23),
+            Opcode::ParMap => self.byte(24),
+            Opcode::Not => self.byte(25),
+            Opcode::NotEqual => self.byte(26),
+            Opcode::Or => self.byte(27),
+            Opcode::Pop => self.byte(28),
+            Opcode::Print => self.byte(29),
+            Opcode::Rconst(r) => {
+                self.byte(30);
+                self.u64(r.to_bits());
+            }
+            Opcode::Recv => self.byte(31),
+            Opcode::Receive => self.byte(32),
+            Opcode::Rec(group) => {
+                self.byte(33);
+                self.usize(group.len());
+                for (id, ip) in group {
+                    self.symbol(*id);
+                    self.usize(*ip);
+                }
+            }
+            Opcode::Record(fields) => {
+                self.byte(34);
+                self.usize(fields.len());
+                for field in fields {
+                    self.string(field);
+                }
+            }
+            Opcode::Ret => self.byte(35),
+            Opcode::Sconst(s) => {
+                self.byte(36);
+                self.string(s);
+            }
+            Opcode::SelfPid => self.byte(37),
+            Opcode::Send => self.byte(38),
+            Opcode::SetEnv(id) => {
+                self.byte(39);
+                self.symbol(*id);
+            }
+            Opcode::Spawn => self.byte(40),
+            Opcode::Srcpos(line, col) => {
+                self.byte(41);
+                self.usize(*line);
+                self.usize(*col);
+            }
+            Opcode::Sub => self.byte(42),
+            Opcode::TailCall => self.byte(43),
+            Opcode::Tconst(n) => {
+                self.byte(44);
+                self.usize(*n);
+            }
+            Opcode::TypeEq(variant) => {
+                self.byte(45);
+                self.string(variant);
+            }
+            Opcode::Uconst => self.byte(46),
+            Opcode::Untuple => self.byte(47),
+            Opcode::Update(fields) => {
+                self.byte(48);
+                self.usize(fields.len());
+                for field in fields {
+                    self.string(field);
+                }
+            }
+            Opcode::Yield => self.byte(49),
+            Opcode::On => self.byte(50),
+            Opcode::SortBy => self.byte(51),
+            Opcode::FloatToString => self.byte(52),
+            Opcode::ParseFloat => self.byte(53),
+            Opcode::Show => self.byte(54),
+            Opcode::Abs => self.byte(55),
+            Opcode::Min => self.byte(56),
+            Opcode::Max => self.byte(57),
+            Opcode::Fst => self.byte(58),
+            Opcode::Snd => self.byte(59),
+            Opcode::Nconst(n) => {
+                self.byte(60);
+                self.string(&n.to_string());
+            }
+            Opcode::WrappingAdd => self.byte(61),
+            Opcode::WrappingSub => self.byte(62),
+            Opcode::WrappingMul => self.byte(63),
+            Opcode::SaturatingAdd => self.byte(64),
+            Opcode::SaturatingSub => self.byte(65),
+            Opcode::SaturatingMul => self.byte(66),
+            Opcode::Popcount => self.byte(67),
+            Opcode::LeadingZeros => self.byte(68),
+            Opcode::RotateLeft => self.byte(69),
+            Opcode::RotateRight => self.byte(70),
+            Opcode::HashValue => self.byte(71),
+            Opcode::Neg => self.byte(72),
+            Opcode::Jnz(offset) => {
+                self.byte(73);
+                self.i64(*offset);
+            }
+            Opcode::Xconst(bytes) => {
+                self.byte(74);
+                self.usize(bytes.len());
+                self.bytes.extend(bytes);
+            }
+            Opcode::BytesLength => self.byte(75),
+            Opcode::ByteAt => self.byte(76),
+            Opcode::BytesSlice => self.byte(77),
+            Opcode::StringToBytes => self.byte(78),
+            Opcode::BytesToString => self.byte(79),
+            Opcode::HexEncode => self.byte(80),
+            Opcode::HexDecode => self.byte(81),
+            Opcode::Base64Encode => self.byte(82),
+            Opcode::Base64Decode => self.byte(83),
+            Opcode::Now => self.byte(84),
+            Opcode::FormatTime => self.byte(85),
+            Opcode::ParseTime => self.byte(86),
+            Opcode::AddTime => self.byte(87),
+            Opcode::TimeDiff => self.byte(88),
+            Opcode::HttpGet => self.byte(89),
+            Opcode::HttpPost => self.byte(90),
+            Opcode::RunCommand => self.byte(91),
+            Opcode::Propagate => self.byte(92),
+            Opcode::Scope => self.byte(93),
+            Opcode::Try(offset) => {
+                self.byte(94);
+                self.i64(*offset);
+            }
+            Opcode::EndTry => self.byte(95),
+            Opcode::Diff => self.byte(96),
+            Opcode::Pow => self.byte(97),
+            Opcode::Cconst(c) => {
+                self.byte(98);
+                self.u64(u64::from(*c));
+            }
+            Opcode::CharAt => self.byte(99),
+            Opcode::CharCode => self.byte(100),
+            Opcode::CharFromCode => self.byte(101),
+            Opcode::FoldChars => self.byte(102),
+            Opcode::Length => self.byte(103),
+            Opcode::Join => self.byte(104),
+            Opcode::StringSlice => self.byte(105),
+            Opcode::StringSplit => self.byte(106),
+            Opcode::StringContains => self.byte(107),
+            Opcode::Aconst(n) => {
+                self.byte(108);
+                self.usize(*n);
+            }
+            Opcode::ArrayAt => self.byte(109),
+            Opcode::ArrayPush => self.byte(110),
+            Opcode::Mconst(n) => {
+                self.byte(111);
+                self.usize(*n);
+            }
+            Opcode::MapInsert => self.byte(112),
+            Opcode::MapLookup => self.byte(113),
+            Opcode::MapRemove => self.byte(114),
+            Opcode::MapKeys => self.byte(115),
+            Opcode::MapHasKey => self.byte(116),
+            Opcode::Cons => self.byte(117),
+            Opcode::Lconst(n) => {
+                self.byte(118);
+                self.usize(*n);
+            }
+            Opcode::ListHead => self.byte(119),
+            Opcode::ListTail => self.byte(120),
+            Opcode::Exit => self.byte(121),
+            Opcode::Abort => self.byte(122),
+            Opcode::Range => self.byte(123),
+            Opcode::ArrayMap => self.byte(124),
+            Opcode::Defer => self.byte(125),
+            Opcode::ArrayFilter => self.byte(126),
+            Opcode::ArrayFoldl => self.byte(127),
+            Opcode::ArrayFoldr => self.byte(128),
+            Opcode::FormatInt => self.byte(129),
+            Opcode::StringChars => self.byte(130),
+            Opcode::StringByteLength => self.byte(131),
+            Opcode::StringByteSlice => self.byte(132),
+            Opcode::Graphemes => self.byte(133),
+            Opcode::ToLower => self.byte(134),
+            Opcode::ToUpper => self.byte(135),
+            Opcode::Nfc => self.byte(136),
+            Opcode::Nfd => self.byte(137),
+            Opcode::CsvParse => self.byte(139),
+            Opcode::CsvString => self.byte(140),
+            Opcode::RegexMatch => self.byte(141),
+            Opcode::RegexCaptures => self.byte(142),
+            Opcode::RegexReplace => self.byte(143),
+            Opcode::Expect(typ) => {
+                self.byte(138);
+                self.typ(typ);
+            }
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    names: &'a mut Names,
+}
+
+fn invalid() -> InterpreterError {
+    InterpreterError::new(
+        ErrorKind::Runtime {
+            kind: RuntimeError::InvalidBytecode,
+            backtrace: Vec::new(),
+        },
+        "Invalid bytecode.".to_string(),
+        usize::MAX,
+        usize::MAX,
+    )
+}
+
+impl Reader<'_> {
+    fn take(&mut self, n: usize) -> Result<&[u8], InterpreterError> {
+        if self.bytes.len() - self.pos < n {
+            return Err(invalid());
+        }
+        let bytes = &self.bytes[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, InterpreterError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, InterpreterError> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn u64(&mut self) -> Result<u64, InterpreterError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn usize(&mut self) -> Result<usize, InterpreterError> {
+        Ok(self.u64()? as usize)
+    }
+
+    fn i64(&mut self) -> Result<i64, InterpreterError> {
+        Ok(self.u64()? as i64)
+    }
+
+    fn bool(&mut self) -> Result<bool, InterpreterError> {
+        match self.byte()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(invalid()),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, InterpreterError> {
+        let len = self.usize()?;
+        match String::from_utf8(self.take(len)?.to_vec()) {
+            Ok(s) => Ok(s),
+            Err(_) => Err(invalid()),
+        }
+    }
+
+    // Identifiers are interned again as they are read, in the names of the
+    // virtual machine the program is loaded into.
+    fn symbol(&mut self) -> Result<Symbol, InterpreterError> {
+        let name = self.string()?;
+        Ok(self.names.intern(&name))
+    }
+
+    fn strings(&mut self) -> Result<Vec<String>, InterpreterError> {
+        let len = self.usize()?;
+        let mut strings = Vec::new();
+        for _ in 0..len {
+            strings.push(self.string()?);
+        }
+        Ok(strings)
+    }
+
+    fn typ(&mut self) -> Result<Type, InterpreterError> {
+        match self.byte()? {
+            0 => Ok(Type::Boolean),
+            1 => Ok(Type::Channel(Box::new(self.typ()?))),
+            2 => Ok(Type::Datatype(self.string()?)),
+            3 => Ok(Type::Float),
+            4 => {
+                let param = self.typ()?;
+                let body = self.typ()?;
+                Ok(Type::Function(Box::new(param), Box::new(body)))
+            }
+            5 => Ok(Type::Integer),
+            6 => Ok(Type::Pid),
+            7 => Ok(Type::Polymorphic(self.string()?)),
+            8 => {
+                let len = self.usize()?;
+                let mut fields = Vec::new();
+                for _ in 0..len {
+                    let field = self.string()?;
+                    fields.push((field, self.typ()?));
+                }
+                Ok(Type::Record(fields))
+            }
+            9 => Ok(Type::String),
+            10 => {
+                let len = self.usize()?;
+                let mut elements = Vec::new();
+                for _ in 0..len {
+                    elements.push(self.typ()?);
+                }
+                Ok(Type::Tuple(elements))
+            }
+            11 => Ok(Type::Unit),
+            12 => Ok(Type::BigInt),
+            13 => Ok(Type::Bytes),
+            14 => Ok(Type::Timestamp),
+            15 => Ok(Type::Char),
+            16 => Ok(Type::Array(Box::new(self.typ()?))),
+            17 => Ok(Type::Map(Box::new(self.typ()?), Box::new(self.typ()?))),
+            18 => Ok(Type::List(Box::new(self.typ()?))),
+            _ => Err(invalid()),
+        }
+    }
+
+    fn opcode(&mut self) -> Result<Opcode, InterpreterError> {
+        Ok(match self.byte()? {
+            0 => Opcode::Add,
+            1 => Opcode::And,
+            2 => Opcode::Arg(self.usize()?),
+            3 => Opcode::Bconst(self.bool()?),
+            4 => Opcode::Call,
+            5 => Opcode::Concat,
+            6 => Opcode::Div,
+            7 => Opcode::Dup,
+            8 => Opcode::Equal,
+            9 => Opcode::ExtVal,
+            10 => Opcode::Field(self.string()?),
+            11 => {
+                let typ = self.string()?;
+                Opcode::Dconst(typ, self.string()?)
+            }
+            12 => {
+                let id = if self.bool()? {
+                    Some(self.symbol()?)
+                } else {
+                    None
+                };
+                let ip = self.usize()?;
+                let len = self.usize()?;
+                let mut upvalues = HashMap::new();
+                for _ in 0..len {
+                    let id = self.symbol()?;
+                    let slot = self.usize()?;
+                    upvalues.insert(id, (slot, self.typ()?));
+                }
+                Opcode::Fconst(id, ip, upvalues)
+            }
+            13 => Opcode::GetEnv(self.symbol()?),
+            14 => Opcode::Greater,
+            15 => Opcode::GreaterEqual,
+            16 => Opcode::Iconst(self.i64()?),
+            17 => Opcode::Jmp(self.i64()?),
+            18 => Opcode::Jz(self.i64()?),
+            19 => Opcode::Less,
+            20 => Opcode::LessEqual,
+            21 => Opcode::Memo,
+            22 => Opcode::Mod,
+            23 => Opcode::Mul,
+            24 => Opcode::ParMap,
+            25 => Opcode::Not,
+            26 => Opcode::NotEqual,
+            27 => Opcode::Or,
+            28 => Opcode::Pop,
+            29 => Opcode::Print,
+            30 => Opcode::Rconst(f64::from_bits(self.u64()?)),
+            31 => Opcode::Recv,
+            32 => Opcode::Receive,
+            33 => {
+                let len = self.usize()?;
+                let mut group = Vec::new();
+                for _ in 0..len {
+                    let id = self.symbol()?;
+                    group.push((id, self.usize()?));
+                }
+                Opcode::Rec(group)
+            }
+            34 => Opcode::Record(self.strings()?),
+            35 => Opcode::Ret,
+            36 => Opcode::Sconst(self.string()?),
+            37 => Opcode::SelfPid,
+            38 => Opcode::Send,
+            39 => Opcode::SetEnv(self.symbol()?),
+            40 => Opcode::Spawn,
+            41 => {
+                let line = self.usize()?;
+                Opcode::Srcpos(line, self.usize()?)
+            }
+            42 => Opcode::Sub,
+            43 => Opcode::TailCall,
+            44 => Opcode::Tconst(self.usize()?),
+            45 => Opcode::TypeEq(self.string()?),
+            46 => Opcode::Uconst,
+            47 => Opcode::Untuple,
+            48 => Opcode::Update(self.strings()?),
+            49 => Opcode::Yield,
+            50 => Opcode::On,
+            51 => Opcode::SortBy,
+            52 => Opcode::FloatToString,
+            53 => Opcode::ParseFloat,
+            54 => Opcode::Show,
+            55 => Opcode::Abs,
+            56 => Opcode::Min,
+            57 => Opcode::Max,
+            58 => Opcode::Fst,
+            59 => Opcode::Snd,
+            60 => match self.string()?.parse() {
+                Ok(n) => Opcode::Nconst(n),
+                Err(_) => return Err(invalid()),
+            },
+            61 => Opcode::WrappingAdd,
+            62 => Opcode::WrappingSub,
+            63 => Opcode::WrappingMul,
+            64 => Opcode::SaturatingAdd,
+            65 => Opcode::SaturatingSub,
+            66 => Opcode::SaturatingMul,
+            67 => Opcode::Popcount,
+            68 => Opcode::LeadingZeros,
+            69 => Opcode::RotateLeft,
+            70 => Opcode::RotateRight,
+            71 => Opcode::HashValue,
+            72 => Opcode::Neg,
+            73 => Opcode::Jnz(self.i64()?),
+            74 => {
+                let len = self.usize()?;
+                Opcode::Xconst(self.take(len)?.to_vec())
+            }
+            75 => Opcode::BytesLength,
+            76 => Opcode::ByteAt,
+            77 => Opcode::BytesSlice,
+            78 => Opcode::StringToBytes,
+            79 => Opcode::BytesToString,
+            80 => Opcode::HexEncode,
+            81 => Opcode::HexDecode,
+            82 => Opcode::Base64Encode,
+            83 => Opcode::Base64Decode,
+            84 => Opcode::Now,
+            85 => Opcode::FormatTime,
+            86 => Opcode::ParseTime,
+            87 => Opcode::AddTime,
+            88 => Opcode::TimeDiff,
+            89 => Opcode::HttpGet,
+            90 => Opcode::HttpPost,
+            91 => Opcode::RunCommand,
+            92 => Opcode::Propagate,
+            93 => Opcode::Scope,
+            94 => Opcode::Try(self.i64()?),
+            95 => Opcode::EndTry,
+            96 => Opcode::Diff,
+            97 => Opcode::Pow,
+            98 => {
+                let code = u32::try_from(self.u64()?).map_err(|_| invalid())?;
+                Opcode::Cconst(char::from_u32(code).ok_or_else(invalid)?)
+            }
+            99 => Opcode::CharAt,
+            100 => Opcode::CharCode,
+            101 => Opcode::CharFromCode,
+            102 => Opcode::FoldChars,
+            103 => Opcode::Length,
+            104 => Opcode::Join,
+            105 => Opcode::StringSlice,
+            106 => Opcode::StringSplit,
+            107 => Opcode::StringContains,
+            108 => Opcode::Aconst(self.usize()?),
+            109 => Opcode::ArrayAt,
+            110 => Opcode::ArrayPush,
+            111 => Opcode::Mconst(self.usize()?),
+            112 => Opcode::MapInsert,
+            113 => Opcode::MapLookup,
+            114 => Opcode::MapRemove,
+            115 => Opcode::MapKeys,
+            116 => Opcode::MapHasKey,
+            117 => Opcode::Cons,
+            118 => Opcode::Lconst(self.usize()?),
+            119 => Opcode::ListHead,
+            120 => Opcode::ListTail,
+            121 => Opcode::Exit,
+            122 => Opcode::Abort,
+            123 => Opcode::Range,
+            124 => Opcode::ArrayMap,
+            125 => Opcode::Defer,
+            126 => Opcode::ArrayFilter,
+            127 => Opcode::ArrayFoldl,
+            128 => Opcode::ArrayFoldr,
+            129 => Opcode::FormatInt,
+            130 => Opcode::StringChars,
+            131 => Opcode::StringByteLength,
+            132 => Opcode::StringByteSlice,
+            133 => Opcode::Graphemes,
+            134 => Opcode::ToLower,
+            135 => Opcode::ToUpper,
+            136 => Opcode::Nfc,
+            137 => Opcode::Nfd,
+            138 => Opcode::Expect(self.typ()?),
+            139 => Opcode::CsvParse,
+            140 => Opcode::CsvString,
+            141 => Opcode::RegexMatch,
+            142 => Opcode::RegexCaptures,
+            143 => Opcode::RegexReplace,
+            _ => return Err(invalid()),
+        })
+    }
+}
+
+// Writes the program compiled into a virtual machine, which starts at the
+// current instruction.
+pub fn encode(vm: &VirtualMachine) -> Vec<u8> {
+    let mut writer = Writer {
+        bytes: Vec::new(),
+        names: &vm.names,
+    };
+    writer.bytes.extend(MAGIC);
+    writer.u32(BYTECODE_VERSION);
+    writer.usize(vm.ip);
+    writer.usize(vm.instructions.len());
+    for opcode in vm.instructions.iter() {
+        writer.opcode(opcode);
+    }
+    let mut symbols: Vec<_> = vm.symbols.iter().collect();
+    symbols.sort_by_key(|(ip, _)| **ip);
+    writer.usize(symbols.len());
+    for (ip, info) in symbols {
+        writer.usize(*ip);
+        match &info.name {
+            Some(name) => {
+                writer.byte(1);
+                writer.string(name);
+            }
+            None => writer.byte(0),
+        }
+        writer.usize(info.line);
+        writer.usize(info.col);
+    }
+    writer.bytes
+}
+
+// Replaces the program of a virtual machine with one read from bytecode, so
+// that running the virtual machine runs it. The builtins are compiled the
+// same way by every interpreter using the same bytecode version, so the ones
+// defined by the virtual machine refer to those in the loaded program.
+pub fn load(vm: &mut VirtualMachine, bytes: &[u8]) -> Result<(), InterpreterError> {
+    let _guard = panics::enter();
+    if !is_bytecode(bytes) {
+        return Err(invalid());
+    }
+    let mut reader = Reader {
+        bytes,
+        pos: MAGIC.len(),
+        names: Arc::make_mut(&mut vm.names),
+    };
+    let version = reader.u32()?;
+    if version != BYTECODE_VERSION {
+        return Err(InterpreterError::new(
+            ErrorKind::Runtime {
+                kind: RuntimeError::InvalidBytecode,
+                backtrace: Vec::new(),
+            },
+            format!(
+                "Unsupported bytecode version {}, expected {}.",
+                version, BYTECODE_VERSION
+            ),
+            usize::MAX,
+            usize::MAX,
+        ));
+    }
+    let entry = reader.usize()?;
+    let len = reader.usize()?;
+    let mut instructions = Vec::new();
+    for _ in 0..len {
+        instructions.push(reader.opcode()?);
+    }
+    let mut symbols = HashMap::new();
+    for _ in 0..reader.usize()? {
+        let ip = reader.usize()?;
+        let name = if reader.bool()? {
+            Some(reader.string()?)
+        } else {
+            None
+        };
+        let line = reader.usize()?;
+        let col = reader.usize()?;
+        symbols.insert(ip, FunctionInfo { name, line, col });
+    }
+    if reader.pos != bytes.len() || entry > instructions.len() {
+        return Err(invalid());
+    }
+    verify::verify(&instructions, entry, &vm.env, &vm.names)?;
+    vm.instructions = Arc::new(instructions);
+    vm.symbols = Arc::new(symbols);
+    vm.ip = entry;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bytecode;
+    use crate::codegen;
+    use crate::parser;
+    use crate::vm;
+    use crate::vm::Value;
+
+    macro_rules! roundtrip {
+        ($input:expr, $value:expr) => {{
+            let mut vm = vm::VirtualMachine::new();
+            let ast = parser::parse($input).ok().unwrap();
+            codegen::compile(&mut vm, &ast).ok().unwrap();
+            let bytes = bytecode::encode(&vm);
+            assert_eq!(bytecode::encode(&vm), bytes);
+            let mut vm = vm::VirtualMachine::new();
+            bytecode::load(&mut vm, &bytes).ok().unwrap();
+            assert_eq!(vm.run().ok(), Some(vm::Status::Finished($value)));
+        }};
+    }
+
+    #[test]
+    fn roundtrip() {
+        roundtrip!("1 + 2 * 3", Value::Integer(7));
+        roundtrip!("2 ** 3 ** 2", Value::Integer(512));
+        roundtrip!("-1.5 * 2.0", Value::Float(-3.0));
+        roundtrip!(
+            "fn f (n) -> -n * 100000000000000000000n end f (3n)",
+            Value::BigInt("-300000000000000000000".parse().unwrap())
+        );
+        roundtrip!("\"a\" ++ \"b\"", Value::Str("ab".to_string()));
+        roundtrip!(
+            "bytes_slice ((b\"\\x00\\xffab\", 1, 3))",
+            Value::Bytes(vec![255, 97])
+        );
+        roundtrip!(
+            "def adder := fn (t) -> fn (x) -> x + t end end
+             def f := adder (1)
+             f (2)",
+            Value::Integer(3)
+        );
+        roundtrip!(
+            "type List := Cons (integer, List) | Nil end
+             fn len (xs) -> match xs with Nil -> 0 | Cons (x, xs) -> 1 + len (xs) end end
+             len (Cons (1, Cons (2, Nil)))",
+            Value::Integer(2)
+        );
+        roundtrip!(
+            "letrec
+                 fn even (n) -> if n == 0 then true else odd (n - 1) end end
+                 fn odd (n) -> if n == 0 then false else even (n - 1) end end
+             end
+             even (10)",
+            Value::Boolean(true)
+        );
+        roundtrip!("{ { x = 1, y = 2 } with y = 3 }.y", Value::Integer(3));
+        roundtrip!(
+            "format_time (add_time (parse_time (\"1\", \"%s\"), 5), \"%s.%f\")",
+            Value::Str("1.005".to_string())
+        );
+        roundtrip!("try 1 / 0 catch e -> 2 end", Value::Integer(2));
+        roundtrip!("char_at ((\"añb\", 1)) > 'é'", Value::Boolean(true));
+        roundtrip!(
+            "length (slice ((concat ((\"a,b\", \"c\")), 0, 3)))",
+            Value::Integer(3)
+        );
+        roundtrip!(
+            "at (push (concat ([1], [2]), 3), 2) + length ([4])",
+            Value::Integer(4)
+        );
+        roundtrip!(
+            "lookup (insert (remove (%{1 => \"a\", 2 => \"b\"}, 1), 3, \"c\"), 3)",
+            Value::Str("c".to_string())
+        );
+        roundtrip!(
+            "match tail (0 :: %[1, 2]) with x :: xs -> x + head (xs) | %[] -> 0 end",
+            Value::Integer(3)
+        );
+        roundtrip!(
+            "let xs = for x in 1..4 do x * x end in at (xs, 2)",
+            Value::Integer(9)
+        );
+        roundtrip!(
+            "def me := self ()
+             fn f (n) -> defer send (me, n) n + 1 end
+             def r := f (1)
+             r + receive ()",
+            Value::Integer(3)
+        );
+        roundtrip!(
+            "let xs = filter ((fn (x) -> x > 1 end, map ((fn (x) -> x * 2 end, [0, 1, 2])))) in
+             foldl ((fn (a, x) -> a + x end, 0, xs)) - foldr ((fn (x, a) -> x - a end, 0, xs))",
+            Value::Integer(8)
+        );
+        roundtrip!(
+            "fn f (x) -> x end show (f)",
+            Value::Str("(fn f at 1:1)".to_string())
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        let mut vm = vm::VirtualMachine::new();
+        let ast = parser::parse("1 + 2").ok().unwrap();
+        codegen::compile(&mut vm, &ast).ok().unwrap();
+        let bytes = bytecode::encode(&vm);
+
+        let mut vm = vm::VirtualMachine::new();
+        let err = bytecode::load(&mut vm, &bytes[..bytes.len() - 1])
+            .err()
+            .unwrap();
+        assert_eq!(err.err, "Invalid bytecode.");
+        let err = bytecode::load(&mut vm, b"1 + 2").err().unwrap();
+        assert_eq!(err.err, "Invalid bytecode.");
+        let mut other = bytes.clone();
+        other[4] += 1;
+        let err = bytecode::load(&mut vm, &other).err().unwrap();
+        assert_eq!(
+            err.err,
+            format!(
+                "Unsupported bytecode version {}, expected {}.",
+                crate::version::BYTECODE_VERSION + 1,
+                crate::version::BYTECODE_VERSION
+            )
+        );
+
+        // Well formed bytecode is also rejected if it fails verification.
+        let mut vm = vm::VirtualMachine::new();
+        vm.ip = vm.emit(vec![vm::Opcode::Iconst(1), vm::Opcode::Add]);
+        let bytes = bytecode::encode(&vm);
+        let err = bytecode::load(&mut vm, &bytes).err().unwrap();
+        assert_eq!(
+            err.err,
+            format!(
+                "Invalid bytecode at {}: takes 2 values from a stack of 1.",
+                vm.ip + 1
+            )
+        );
+    }
+}
@@ -1,16 +1,130 @@
+use crate::bigint::BigInt;
+use crate::effects;
+use crate::panics;
 use crate::parser;
-use crate::typeinfer::{infer, type_of, Type, TypedAST};
+use crate::typeinfer::{self, infer, Type, TypedAST};
+use crate::verify;
 use crate::vm;
+use std::collections::BTreeSet;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::error::Error;
 use std::fmt;
+use std::sync::Arc;
+
+// The errors raised while running a program, so that embedders can tell them
+// apart without matching on their messages.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RuntimeError {
+    // A program gave up by calling abort.
+    Aborted,
+    ChannelClosed,
+    // A command run by a program could not be started or gave output that
+    // is not valid UTF-8.
+    CommandError,
+    Deadlock,
+    DivisionByZero,
+    // The head or tail of an empty list was taken.
+    EmptyList,
+    EffectNotPermitted,
+    // A program tried to redefine a global that the host froze.
+    Frozen,
+    // An index or range was outside of the value it was taken from.
+    IndexOutOfRange,
+    IntegerOverflow,
+    // A key was looked up in a map that does not have it.
+    KeyNotFound,
+    // An integer was raised to a negative power, or a bigint to one too large
+    // to compute.
+    InvalidExponent,
+    InvalidBytecode,
+    InvalidHandle,
+    // An instruction found values on the stack that the compiler never
+    // leaves there, which is only reported in checked mode.
+    InvalidStack,
+    // A function registered by the host returned an error.
+    NativeError,
+    // A network request failed.
+    NetworkError,
+    OutOfFuel,
+    // Standard output could not be written, as when it is a pipe whose
+    // reader has gone away.
+    OutputError,
+    StackOverflow,
+    StackUnderflow,
+    // A network request did not finish within the timeout.
+    Timeout,
+    // A builtin was given a value of the wrong type.
+    UnexpectedValue,
+    // A callback tried to wait for a message.
+    WouldBlock,
+}
+
+// A function that was being run when a runtime error occurred, along with the
+// address of the instruction it was running and its source position, which is
+// usize::MAX if it is not known. Lambdas and the program itself have no name.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Frame {
+    pub function: Option<String>,
+    pub ip: usize,
+    pub line: usize,
+    pub col: usize,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ErrorKind {
+    Parse,
+    // The expected and found types are given when the error is caused by two
+    // types that do not match.
+    Type {
+        expected: Option<Type>,
+        found: Option<Type>,
+    },
+    // A program performs effects that are not permitted.
+    Effect,
+    // A program uses something that can not be compiled for the chosen
+    // target.
+    Unsupported,
+    // A program stopped itself by calling exit with this status.
+    Exit(i32),
+    // The backtrace starts with the innermost frame.
+    Runtime {
+        kind: RuntimeError,
+        backtrace: Vec<Frame>,
+    },
+}
 
 #[derive(Debug)]
 pub struct InterpreterError {
+    pub kind: ErrorKind,
     pub err: String,
     pub line: usize,
     pub col: usize,
+    pub source: Option<Box<dyn Error + Send + Sync>>,
+}
+
+impl InterpreterError {
+    pub fn new(kind: ErrorKind, err: String, line: usize, col: usize) -> InterpreterError {
+        InterpreterError {
+            kind,
+            err,
+            line,
+            col,
+            source: None,
+        }
+    }
+
+    pub fn type_error(err: String, line: usize, col: usize) -> InterpreterError {
+        InterpreterError::new(
+            ErrorKind::Type {
+                expected: None,
+                found: None,
+            },
+            err,
+            line,
+            col,
+        )
+    }
 }
 
 impl fmt::Display for InterpreterError {
@@ -19,7 +133,39 @@ impl fmt::Display for InterpreterError {
     }
 }
 
-impl Error for InterpreterError {}
+impl Error for InterpreterError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match &self.source {
+            Some(source) => Some(&**source),
+            None => None,
+        }
+    }
+}
+
+impl From<parser::ParseError> for InterpreterError {
+    fn from(err: parser::ParseError) -> InterpreterError {
+        InterpreterError {
+            kind: ErrorKind::Parse,
+            err: err.msg.clone(),
+            line: err.line,
+            col: err.col,
+            source: Some(Box::new(err)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Warning {
+    pub msg: String,
+    pub line: usize,
+    pub col: usize,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Warning: {}", self.msg)
+    }
+}
 
 fn find_upvalues(
     ast: &TypedAST,
@@ -31,7 +177,7 @@ fn find_upvalues(
             find_upvalues(lhs, ids, upvalues);
             find_upvalues(rhs, ids, upvalues);
         }
-        TypedAST::Call(fun, args) => {
+        TypedAST::Call(fun, args, ..) => {
             find_upvalues(fun, ids, upvalues);
             find_upvalues(args, ids, upvalues);
         }
@@ -42,36 +188,98 @@ fn find_upvalues(
             }
             find_upvalues(value, ids, upvalues);
         }
-        TypedAST::Function(_, param, body) => {
+        TypedAST::Function(_, param, body, ..) => {
             let mut local_ids = ids.clone();
             find_upvalues(param, &mut local_ids, upvalues);
             find_upvalues(body, &mut local_ids, upvalues);
         }
-        TypedAST::If(conds, els) => {
+        TypedAST::If(conds, els, _, _) => {
             for cond in conds {
                 find_upvalues(&cond.0, ids, upvalues);
                 find_upvalues(&cond.1, ids, upvalues);
             }
-            find_upvalues(&els, ids, upvalues);
+            find_upvalues(els, ids, upvalues);
         }
         TypedAST::Identifier(typ, id) => {
             if let Some(offset) = ids.get(id) {
                 upvalues.insert(id.to_string(), (*offset, typ.clone()));
             }
         }
+        TypedAST::Letrec(functions) => {
+            for function in functions {
+                if let TypedAST::Function(Some(id), ..) = function {
+                    ids.remove(id);
+                }
+            }
+            for function in functions {
+                find_upvalues(function, ids, upvalues);
+            }
+        }
+        TypedAST::Let(param, value, body, _, _) | TypedAST::Try(value, param, body, _, _) => {
+            find_upvalues(value, ids, upvalues);
+            let mut local_ids = ids.clone();
+            find_upvalues(param, &mut local_ids, upvalues);
+            find_upvalues(body, &mut local_ids, upvalues);
+        }
+        TypedAST::Match(cond, _, cases) => {
+            find_upvalues(cond, ids, upvalues);
+            for case in cases {
+                let mut local_ids = ids.clone();
+                if let Some(param) = &case.1 {
+                    find_upvalues(param, &mut local_ids, upvalues);
+                }
+                if let Some(guard) = &case.2 {
+                    find_upvalues(guard, &mut local_ids, upvalues);
+                }
+                find_upvalues(&case.3, &mut local_ids, upvalues);
+            }
+        }
         TypedAST::Program(_, expressions) => {
             for expression in expressions {
                 find_upvalues(expression, ids, upvalues);
             }
         }
-        TypedAST::Tuple(_, elements) => {
+        TypedAST::Field(_, record, _) => {
+            find_upvalues(record, ids, upvalues);
+        }
+        TypedAST::For(_, function, iter) => {
+            find_upvalues(function, ids, upvalues);
+            find_upvalues(iter, ids, upvalues);
+        }
+        TypedAST::While(cond, body) => {
+            find_upvalues(cond, ids, upvalues);
+            find_upvalues(body, ids, upvalues);
+        }
+        TypedAST::Defer(function) => {
+            find_upvalues(function, ids, upvalues);
+        }
+        TypedAST::Record(_, fields) => {
+            for field in fields {
+                find_upvalues(&field.1, ids, upvalues);
+            }
+        }
+        TypedAST::Array(_, elements)
+        | TypedAST::List(_, elements)
+        | TypedAST::Tuple(_, elements) => {
             for element in elements {
                 find_upvalues(element, ids, upvalues);
             }
         }
-        TypedAST::UnaryOp(_, _, ast) => {
+        TypedAST::Map(_, entries) => {
+            for (key, value) in entries {
+                find_upvalues(key, ids, upvalues);
+                find_upvalues(value, ids, upvalues);
+            }
+        }
+        TypedAST::UnaryOp(_, _, ast) | TypedAST::Propagate(_, ast, _, _) => {
             find_upvalues(ast, ids, upvalues);
         }
+        TypedAST::Update(_, record, fields) => {
+            find_upvalues(record, ids, upvalues);
+            for field in fields {
+                find_upvalues(&field.1, ids, upvalues);
+            }
+        }
         _ => {}
     }
 }
@@ -92,20 +300,17 @@ fn generate(
                 parser::Operator::And => {
                     instr.push(vm::Opcode::And);
                 }
+                parser::Operator::Concat => {
+                    instr.push(vm::Opcode::Concat);
+                }
+                parser::Operator::Cons => {
+                    instr.push(vm::Opcode::Cons);
+                }
                 parser::Operator::Divide => {
                     instr.push(vm::Opcode::Div);
                 }
                 parser::Operator::Equal => {
-                    if let Type::Tuple(types) = type_of(rhs) {
-                        instr.push(vm::Opcode::Equal);
-                        for _ in 1..types.len() {
-                            instr.push(vm::Opcode::Rot);
-                            instr.push(vm::Opcode::Equal);
-                            instr.push(vm::Opcode::And);
-                        }
-                    } else {
-                        instr.push(vm::Opcode::Equal);
-                    }
+                    instr.push(vm::Opcode::Equal);
                 }
                 parser::Operator::Greater => {
                     instr.push(vm::Opcode::Greater);
@@ -128,20 +333,17 @@ fn generate(
                 parser::Operator::Multiply => {
                     instr.push(vm::Opcode::Mul);
                 }
+                parser::Operator::Range => {
+                    instr.push(vm::Opcode::Range);
+                }
+                parser::Operator::Power => {
+                    instr.push(vm::Opcode::Pow);
+                }
                 parser::Operator::Not => {
                     instr.push(vm::Opcode::Not);
                 }
                 parser::Operator::NotEqual => {
-                    if let Type::Tuple(types) = type_of(rhs) {
-                        instr.push(vm::Opcode::NotEqual);
-                        for _ in 1..types.len() {
-                            instr.push(vm::Opcode::Rot);
-                            instr.push(vm::Opcode::NotEqual);
-                            instr.push(vm::Opcode::Or);
-                        }
-                    } else {
-                        instr.push(vm::Opcode::NotEqual);
-                    }
+                    instr.push(vm::Opcode::NotEqual);
                 }
                 parser::Operator::Or => {
                     instr.push(vm::Opcode::Or);
@@ -154,111 +356,85 @@ fn generate(
         TypedAST::Boolean(b) => {
             instr.push(vm::Opcode::Bconst(*b));
         }
-        TypedAST::Call(fun, arg) => {
+        TypedAST::Call(fun, arg, line, col) => {
+            instr.push(vm::Opcode::Srcpos(*line, *col));
+            let start = instr.len();
             generate(arg, vm, instr, ids);
             generate(fun, vm, instr, ids);
+            // The position is set back to the call if the argument moved it,
+            // so that backtraces find the call just before returning to it.
+            if instr[start..]
+                .iter()
+                .any(|opcode| matches!(opcode, vm::Opcode::Srcpos(..)))
+            {
+                instr.push(vm::Opcode::Srcpos(*line, *col));
+            }
             instr.push(vm::Opcode::Call);
+            // Messages can be sent by any process, so the one received is
+            // checked against the type the program gives it, if any.
+            if let TypedAST::Identifier(Type::Function(_, body), name) = &**fun {
+                if name == "receive" && !matches!(**body, Type::Polymorphic(_)) {
+                    instr.push(vm::Opcode::Expect(*body.clone()));
+                }
+            }
+        }
+        TypedAST::Field(_, record, field) => {
+            generate(record, vm, instr, ids);
+            instr.push(vm::Opcode::Field(field.to_string()));
+        }
+        TypedAST::BigInt(n) => {
+            instr.push(vm::Opcode::Nconst(n.clone()));
+        }
+        TypedAST::Bytes(bytes) => {
+            instr.push(vm::Opcode::Xconst(bytes.clone()));
+        }
+        TypedAST::Char(c) => {
+            instr.push(vm::Opcode::Cconst(*c));
+        }
+        TypedAST::Float(f) => {
+            instr.push(vm::Opcode::Rconst(*f));
         }
         TypedAST::Datatype(typ, variants) => {
             for variant in variants {
                 if let Type::Datatype(_) = &variant.1 {
                     instr.push(vm::Opcode::Uconst);
-                    instr.push(vm::Opcode::Dconst(
-                        typ.to_string(),
-                        variant.0.to_string(),
-                        1,
-                    ));
-                    instr.push(vm::Opcode::SetEnv(variant.0.to_string()));
+                    instr.push(vm::Opcode::Dconst(typ.to_string(), variant.0.to_string()));
+                    instr.push(vm::Opcode::SetEnv(vm.symbol(&variant.0)));
                 } else {
-                    let count;
-                    if let Type::Function(fun, _) = &variant.1 {
-                        match &**fun {
-                            Type::Tuple(elements) => {
-                                count = elements.len();
-                            }
-                            _ => {
-                                count = 1;
-                            }
-                        }
-                    } else {
-                        unreachable!();
-                    }
-
-                    let mut fn_instr = Vec::new();
-                    fn_instr.push(vm::Opcode::Dconst(
-                        typ.to_string(),
-                        variant.0.to_string(),
-                        count,
-                    ));
-
-                    fn_instr.push(vm::Opcode::Ret(0));
-                    let ip = vm.instructions.len();
-                    vm.instructions.extend(fn_instr);
+                    // The constructor argument is on top of the stack when
+                    // the constructor function is called.
+                    let fn_instr = vec![
+                        vm::Opcode::Dconst(typ.to_string(), variant.0.to_string()),
+                        vm::Opcode::Ret,
+                    ];
+                    let ip = vm.emit(fn_instr);
                     instr.push(vm::Opcode::Fconst(None, ip, HashMap::new()));
-                    instr.push(vm::Opcode::SetEnv(variant.0.to_string()));
+                    instr.push(vm::Opcode::SetEnv(vm.symbol(&variant.0)));
                 }
             }
             instr.push(vm::Opcode::Uconst);
         }
         TypedAST::Define(_, id, value) => {
-            generate(&value, vm, instr, ids);
+            generate(value, vm, instr, ids);
             instr.push(vm::Opcode::Dup);
-            instr.push(vm::Opcode::SetEnv(id.to_string()));
+            instr.push(vm::Opcode::SetEnv(vm.symbol(id)));
         }
-        TypedAST::Function(id, param, body) => {
-            let mut fn_instr = Vec::new();
-            let mut local_ids = ids.clone();
-            let mut param_ids = HashSet::new();
-            let mut count = 0;
-            match &**param {
-                TypedAST::Identifier(_, id) => {
-                    count = 2;
-                    local_ids.insert(id.to_string(), 0);
-                    param_ids.insert(id.to_string());
-                }
-                TypedAST::Tuple(_, elements) => {
-                    for element in elements {
-                        if let TypedAST::Identifier(_, id) = element {
-                            local_ids.insert(id.to_string(), count);
-                            param_ids.insert(id.to_string());
-                        }
-                        count += 1;
-                    }
-                }
-                TypedAST::Unit => {
-                    count = 1;
-                }
-                _ => unreachable!(),
-            }
-
+        TypedAST::Function(id, param, body, line, col) => {
             // We find the "upvalues", function arguments from enclosing
             // functions that are used in this function and place them in the
             // environment instead of retrieving them from the stack.
             let mut upvalues = HashMap::new();
             let mut upvalue_ids = ids.clone();
             find_upvalues(body, &mut upvalue_ids, &mut upvalues);
-            for upvalue in &upvalues {
-                let id = upvalue.0;
-                if param_ids.contains(id) {
-                    continue;
-                }
-                if ids.get(id).is_some() {
-                    local_ids.remove(id);
-                }
-            }
-
-            generate(&body, vm, &mut fn_instr, &local_ids);
-            fn_instr.push(vm::Opcode::Ret(count - 1));
-            let ip = vm.instructions.len();
-            vm.instructions.extend(fn_instr);
-            instr.push(vm::Opcode::Fconst(id.clone(), ip, upvalues));
+            let pos = Some((*line, *col));
+            generate_function(id, pos, param, body, false, upvalues, vm, instr, ids);
 
             if let Some(id) = id {
                 instr.push(vm::Opcode::Dup);
-                instr.push(vm::Opcode::SetEnv(id.to_string()));
+                instr.push(vm::Opcode::SetEnv(vm.symbol(id)));
             }
         }
-        TypedAST::If(conds, els) => {
+        TypedAST::If(conds, els, _, _) => {
             let start_ip = instr.len();
             let els_ptr: *const TypedAST = &**els;
             for cond in conds {
@@ -270,7 +446,7 @@ fn generate(
                 instr.extend(then);
                 instr.push(vm::Opcode::Jmp(els_ptr as i64));
             }
-            generate(&els, vm, instr, ids);
+            generate(els, vm, instr, ids);
 
             for i in start_ip..instr.len() {
                 if let vm::Opcode::Jmp(ptr) = instr[i] {
@@ -285,32 +461,110 @@ fn generate(
             Some(offset) => instr.push(vm::Opcode::Arg(*offset)),
             None => {
                 // type checking ensures this is a valid identifier
-                instr.push(vm::Opcode::GetEnv(id.to_string()))
+                instr.push(vm::Opcode::GetEnv(vm.symbol(id)))
             }
         },
         TypedAST::Integer(i) => {
             instr.push(vm::Opcode::Iconst(*i));
         }
+        TypedAST::Letrec(functions) => {
+            // A function may call any other function in the group, which the
+            // virtual machine rebuilds from the caller's environment, so every
+            // function captures the upvalues that any of them use.
+            let mut upvalues = HashMap::new();
+            for function in functions {
+                if let TypedAST::Function(_, _, body, ..) = function {
+                    let mut upvalue_ids = ids.clone();
+                    find_upvalues(body, &mut upvalue_ids, &mut upvalues);
+                }
+            }
+            let mut group = Vec::new();
+            let mut recs = Vec::new();
+            for (i, function) in functions.iter().enumerate() {
+                if let TypedAST::Function(Some(id), param, body, line, col) = function {
+                    if i > 0 {
+                        instr.push(vm::Opcode::Pop);
+                    }
+                    generate_function(
+                        &Some(id.to_string()),
+                        Some((*line, *col)),
+                        param,
+                        body,
+                        false,
+                        upvalues.clone(),
+                        vm,
+                        instr,
+                        ids,
+                    );
+                    if let Some(vm::Opcode::Fconst(_, ip, _)) = instr.last() {
+                        group.push((vm.symbol(id), *ip));
+                    }
+                    // The group is filled in once every function has been
+                    // generated.
+                    recs.push(instr.len());
+                    instr.push(vm::Opcode::Rec(Vec::new()));
+                    instr.push(vm::Opcode::Dup);
+                    instr.push(vm::Opcode::SetEnv(vm.symbol(id)));
+                }
+            }
+            for rec in recs {
+                instr[rec] = vm::Opcode::Rec(group.clone());
+            }
+        }
+        TypedAST::Let(param, value, body, _, _) => {
+            // The body is compiled like a function applied to the value, so
+            // the binding lives in its own frame and does not leak into the
+            // enclosing environment.
+            generate(value, vm, instr, ids);
+            generate_scope(param, body, vm, instr, ids);
+            instr.push(vm::Opcode::Call);
+        }
         TypedAST::Match(cond, _, cases) => {
-            generate(&cond, vm, instr, ids);
+            generate(cond, vm, instr, ids);
             let start_ip = instr.len();
             let cond_ptr: *const TypedAST = &**cond;
             for case in cases {
                 let mut then = Vec::new();
-                instr.push(vm::Opcode::Dup);
-                instr.push(vm::Opcode::TypeEq(case.0.to_string()));
                 if let Some(param) = &case.1 {
                     then.push(vm::Opcode::ExtVal);
-                    let fun =
-                        TypedAST::Function(None, Box::new(param.clone()), Box::new(case.2.clone()));
-                    generate(&fun, vm, &mut then, ids);
+                    generate_scope(param, &case.3, vm, &mut then, ids);
                     then.push(vm::Opcode::Call);
                 } else {
-                    generate(&case.2, vm, &mut then, ids);
+                    then.push(vm::Opcode::Pop);
+                    generate(&case.3, vm, &mut then, ids);
+                }
+                // A guard is evaluated with the value still on the stack, so
+                // that the next case can be tried if it does not hold. Its
+                // parameters are extracted once for the guard and again for
+                // the body.
+                let mut guard = Vec::new();
+                match (&case.1, &case.2) {
+                    (Some(param), Some(cond)) => {
+                        guard.push(vm::Opcode::Dup);
+                        guard.push(vm::Opcode::ExtVal);
+                        generate_scope(param, cond, vm, &mut guard, ids);
+                        guard.push(vm::Opcode::Call);
+                    }
+                    (None, Some(cond)) => generate(cond, vm, &mut guard, ids),
+                    (_, None) => {}
+                }
+                if !guard.is_empty() {
+                    guard.push(vm::Opcode::Jz(2 + then.len() as i64));
+                }
+                guard.extend(then);
+                // A wildcard without a guard is always the last case and
+                // matches anything.
+                if case.0 == "_" {
+                    instr.extend(guard);
+                    if case.2.is_none() {
+                        break;
+                    }
+                } else {
+                    instr.push(vm::Opcode::Dup);
+                    instr.push(vm::Opcode::TypeEq(case.0.to_string()));
+                    instr.push(vm::Opcode::Jz(2 + guard.len() as i64));
+                    instr.extend(guard);
                 }
-                let offset = 2 + then.len() as i64;
-                instr.push(vm::Opcode::Jz(offset));
-                instr.extend(then);
                 instr.push(vm::Opcode::Jmp(cond_ptr as i64));
             }
 
@@ -324,23 +578,102 @@ fn generate(
             }
         }
         TypedAST::Program(_, expressions) => {
+            let mut local_ids = ids.clone();
             for i in 0..expressions.len() {
-                generate(&expressions[i], vm, instr, ids);
+                copy_params(&expressions[i], vm, instr, &mut local_ids);
+                generate(&expressions[i], vm, instr, &local_ids);
                 if i + 1 != expressions.len() {
                     instr.push(vm::Opcode::Pop);
                 }
             }
         }
+        TypedAST::While(cond, body) => {
+            let mut local_ids = ids.clone();
+            copy_params(body, vm, instr, &mut local_ids);
+            let start = instr.len();
+            generate(cond, vm, instr, &local_ids);
+            let mut looped = Vec::new();
+            generate(body, vm, &mut looped, &local_ids);
+            looped.push(vm::Opcode::Pop);
+            instr.push(vm::Opcode::Jz(looped.len() as i64 + 2));
+            instr.extend(looped);
+            instr.push(vm::Opcode::Jmp(start as i64 - instr.len() as i64));
+            instr.push(vm::Opcode::Uconst);
+        }
+        TypedAST::Propagate(_, value, _, _) => {
+            generate(value, vm, instr, ids);
+            instr.push(vm::Opcode::Propagate);
+        }
+        TypedAST::Record(_, fields) => {
+            for field in fields {
+                generate(&field.1, vm, instr, ids);
+            }
+            instr.push(vm::Opcode::Record(
+                fields.iter().map(|field| field.0.to_string()).collect(),
+            ));
+        }
+        TypedAST::String(s) => {
+            instr.push(vm::Opcode::Sconst(s.to_string()));
+        }
+        TypedAST::Try(body, param, handler, _, _) => {
+            // The handler is compiled like a let body applied to the error
+            // message, which is pushed once the stack is unwound.
+            let mut protected = Vec::new();
+            generate(body, vm, &mut protected, ids);
+            let mut catch = Vec::new();
+            generate_scope(param, handler, vm, &mut catch, ids);
+            catch.push(vm::Opcode::Call);
+            instr.push(vm::Opcode::Try(protected.len() as i64 + 3));
+            instr.extend(protected);
+            instr.push(vm::Opcode::EndTry);
+            instr.push(vm::Opcode::Jmp(catch.len() as i64 + 1));
+            instr.extend(catch);
+        }
         TypedAST::Tuple(_, elements) => {
-            for element in elements.iter().rev() {
-                generate(&element, vm, instr, ids);
+            for element in elements {
+                generate(element, vm, instr, ids);
+            }
+            instr.push(vm::Opcode::Tconst(elements.len()));
+        }
+        TypedAST::Array(_, elements) => {
+            for element in elements {
+                generate(element, vm, instr, ids);
+            }
+            instr.push(vm::Opcode::Aconst(elements.len()));
+        }
+        TypedAST::List(_, elements) => {
+            for element in elements {
+                generate(element, vm, instr, ids);
+            }
+            instr.push(vm::Opcode::Lconst(elements.len()));
+        }
+        TypedAST::For(_, function, iter) => {
+            generate(function, vm, instr, ids);
+            generate(iter, vm, instr, ids);
+            instr.push(vm::Opcode::Tconst(2));
+            instr.push(vm::Opcode::ArrayMap);
+        }
+        TypedAST::Defer(function) => {
+            generate(function, vm, instr, ids);
+            instr.push(vm::Opcode::Defer);
+            instr.push(vm::Opcode::Uconst);
+        }
+        TypedAST::Map(_, entries) => {
+            for (key, value) in entries {
+                generate(key, vm, instr, ids);
+                generate(value, vm, instr, ids);
             }
+            instr.push(vm::Opcode::Mconst(entries.len()));
         }
-        TypedAST::UnaryOp(_, op, ast) => {
+        TypedAST::UnaryOp(typ, op, ast) => {
             generate(ast, vm, instr, ids);
             match op {
                 parser::Operator::Minus => {
-                    instr.push(vm::Opcode::Iconst(0));
+                    match typ {
+                        Type::Float => instr.push(vm::Opcode::Rconst(0.0)),
+                        Type::BigInt => instr.push(vm::Opcode::Nconst(BigInt::from(0))),
+                        _ => instr.push(vm::Opcode::Iconst(0)),
+                    }
                     instr.push(vm::Opcode::Sub);
                 }
                 parser::Operator::Not => {
@@ -348,177 +681,3971 @@ fn generate(
                 }
                 _ => unreachable!(),
             }
-        }
-        TypedAST::Unit => {
-            instr.push(vm::Opcode::Uconst);
-        }
+        }
+        TypedAST::Unit => {
+            instr.push(vm::Opcode::Uconst);
+        }
+        TypedAST::Update(_, record, fields) => {
+            generate(record, vm, instr, ids);
+            for field in fields {
+                generate(&field.1, vm, instr, ids);
+            }
+            instr.push(vm::Opcode::Update(
+                fields.iter().map(|field| field.0.to_string()).collect(),
+            ));
+        }
+    }
+}
+
+// Generates the code for a let body or a match case with a parameter, which
+// is compiled like a function applied to the value. If the body uses ? or
+// defer, the function starts with Scope so that they apply to the enclosing
+// function.
+fn generate_scope(
+    param: &TypedAST,
+    body: &TypedAST,
+    vm: &mut vm::VirtualMachine,
+    instr: &mut Vec<vm::Opcode>,
+    ids: &HashMap<String, usize>,
+) {
+    let mut upvalues = HashMap::new();
+    let mut upvalue_ids = ids.clone();
+    find_upvalues(body, &mut upvalue_ids, &mut upvalues);
+    let scope = escapes_scope(body);
+    generate_function(&None, None, param, body, scope, upvalues, vm, instr, ids);
+}
+
+// Parameters that an expression defines again are copied to the environment
+// before it runs, and looked up there from then on, so that they have the
+// same value whether or not the definition has been made.
+fn copy_params(
+    ast: &TypedAST,
+    vm: &mut vm::VirtualMachine,
+    instr: &mut Vec<vm::Opcode>,
+    ids: &mut HashMap<String, usize>,
+) {
+    for name in defined(ast) {
+        if let Some(offset) = ids.remove(&name) {
+            instr.push(vm::Opcode::Arg(offset));
+            instr.push(vm::Opcode::SetEnv(vm.symbol(&name)));
+        }
+    }
+}
+
+// The identifiers that an expression defines in the environment it is run in,
+// leaving out those defined in functions, let bodies and other scopes in it.
+// Those defined in branches that may not be taken are included.
+fn defined(ast: &TypedAST) -> BTreeSet<String> {
+    let mut names = BTreeSet::new();
+    let mut pending = vec![ast];
+    while let Some(ast) = pending.pop() {
+        match ast {
+            TypedAST::Define(_, id, value) => {
+                names.insert(id.to_string());
+                pending.push(value);
+            }
+            TypedAST::BinaryOp(_, _, lhs, rhs, _, _) => pending.extend([&**lhs, &**rhs]),
+            TypedAST::Call(fun, arg, ..) => pending.extend([&**fun, &**arg]),
+            TypedAST::If(conds, els, _, _) => {
+                for (cond, then) in conds {
+                    pending.extend([cond, then]);
+                }
+                pending.push(els);
+            }
+            TypedAST::While(cond, body) => pending.extend([&**cond, &**body]),
+            TypedAST::Let(_, value, ..) | TypedAST::Try(value, ..) => pending.push(value),
+            TypedAST::Match(cond, _, cases) => {
+                pending.push(cond);
+                pending.extend(
+                    cases
+                        .iter()
+                        .filter(|case| case.1.is_none())
+                        .flat_map(|case| case.2.iter().chain([&case.3])),
+                );
+            }
+            TypedAST::For(_, _, ast)
+            | TypedAST::UnaryOp(_, _, ast)
+            | TypedAST::Field(_, ast, _)
+            | TypedAST::Propagate(_, ast, _, _) => pending.push(ast),
+            TypedAST::Array(_, expressions)
+            | TypedAST::List(_, expressions)
+            | TypedAST::Program(_, expressions)
+            | TypedAST::Tuple(_, expressions) => pending.extend(expressions),
+            TypedAST::Map(_, entries) => {
+                for (key, value) in entries {
+                    pending.extend([key, value]);
+                }
+            }
+            TypedAST::Record(_, fields) => pending.extend(fields.iter().map(|field| &field.1)),
+            TypedAST::Update(_, record, fields) => {
+                pending.push(record);
+                pending.extend(fields.iter().map(|field| &field.1));
+            }
+            _ => {}
+        }
+    }
+    names
+}
+
+// Returns whether an expression uses ? or defer outside of any function in it.
+fn escapes_scope(ast: &TypedAST) -> bool {
+    match ast {
+        TypedAST::Defer(..) | TypedAST::Propagate(..) => true,
+        TypedAST::BinaryOp(_, _, lhs, rhs, _, _) => escapes_scope(lhs) || escapes_scope(rhs),
+        TypedAST::Call(fun, arg, ..) => escapes_scope(fun) || escapes_scope(arg),
+        TypedAST::Define(_, _, value) => escapes_scope(value),
+        TypedAST::If(conds, els, _, _) => {
+            conds
+                .iter()
+                .any(|(cond, then)| escapes_scope(cond) || escapes_scope(then))
+                || escapes_scope(els)
+        }
+        TypedAST::Let(_, value, body, _, _) | TypedAST::Try(value, _, body, _, _) => {
+            escapes_scope(value) || escapes_scope(body)
+        }
+        TypedAST::Match(cond, _, cases) => {
+            escapes_scope(cond)
+                || cases.iter().any(|case| {
+                    case.2.as_ref().is_some_and(escapes_scope) || escapes_scope(&case.3)
+                })
+        }
+        TypedAST::Array(_, expressions)
+        | TypedAST::List(_, expressions)
+        | TypedAST::Program(_, expressions)
+        | TypedAST::Tuple(_, expressions) => expressions.iter().any(escapes_scope),
+        TypedAST::UnaryOp(_, _, ast) | TypedAST::Field(_, ast, _) => escapes_scope(ast),
+        TypedAST::For(_, _, iter) => escapes_scope(iter),
+        TypedAST::While(cond, body) => escapes_scope(cond) || escapes_scope(body),
+        TypedAST::Map(_, entries) => entries
+            .iter()
+            .any(|(key, value)| escapes_scope(key) || escapes_scope(value)),
+        TypedAST::Record(_, fields) => fields.iter().any(|field| escapes_scope(&field.1)),
+        TypedAST::Update(_, record, fields) => {
+            escapes_scope(record) || fields.iter().any(|field| escapes_scope(&field.1))
+        }
+        _ => false,
+    }
+}
+
+// Generates the code for a function with the given upvalues, leaving the
+// function on the stack. A scope is a let body or match case rather than a
+// function of its own, and has no position so is left out of the symbol
+// table.
+#[allow(clippy::too_many_arguments)]
+fn generate_function(
+    id: &Option<String>,
+    pos: Option<(usize, usize)>,
+    param: &TypedAST,
+    body: &TypedAST,
+    scope: bool,
+    upvalues: HashMap<String, (usize, Type)>,
+    vm: &mut vm::VirtualMachine,
+    instr: &mut Vec<vm::Opcode>,
+    ids: &HashMap<String, usize>,
+) {
+    let mut fn_instr = Vec::new();
+    if scope {
+        fn_instr.push(vm::Opcode::Scope);
+    }
+    let mut local_ids = ids.clone();
+    let mut param_ids = HashSet::new();
+    match param {
+        TypedAST::Identifier(_, id) => {
+            local_ids.insert(id.to_string(), 0);
+            param_ids.insert(id.to_string());
+        }
+        TypedAST::Tuple(_, elements) => {
+            // Tuple arguments are unpacked so each element occupies
+            // its own slot in the frame.
+            fn_instr.push(vm::Opcode::Untuple);
+            for (offset, element) in elements.iter().enumerate() {
+                if let TypedAST::Identifier(_, id) = element {
+                    local_ids.insert(id.to_string(), offset);
+                    param_ids.insert(id.to_string());
+                }
+            }
+        }
+        TypedAST::Unit => {}
+        _ => unreachable!(),
+    }
+
+    for upvalue in &upvalues {
+        let id = upvalue.0;
+        if param_ids.contains(id) {
+            continue;
+        }
+        if ids.get(id).is_some() {
+            local_ids.remove(id);
+        }
+    }
+
+    generate(body, vm, &mut fn_instr, &local_ids);
+    fn_instr.push(vm::Opcode::Ret);
+    if vm.optimize {
+        optimize(&mut fn_instr);
+    }
+    mark_tail_calls(&mut fn_instr);
+    let ip = vm.emit(fn_instr);
+    if let Some((line, col)) = pos {
+        Arc::make_mut(&mut vm.symbols).insert(
+            ip,
+            vm::FunctionInfo {
+                name: id.clone(),
+                line,
+                col,
+            },
+        );
+    }
+    let upvalues = upvalues
+        .into_iter()
+        .map(|(id, upvalue)| (vm.symbol(&id), upvalue))
+        .collect();
+    instr.push(vm::Opcode::Fconst(
+        id.as_deref().map(|id| vm.symbol(id)),
+        ip,
+        upvalues,
+    ));
+}
+
+// A call is in tail position if the only thing left to do after it returns
+// is to return from the enclosing function, possibly after jumping to the end
+// of an if or match expression. These are replaced with TailCall, which
+// reuses the current frame rather than growing the callstack.
+fn mark_tail_calls(instr: &mut [vm::Opcode]) {
+    for i in 0..instr.len() {
+        if let vm::Opcode::Call = instr[i] {
+            let mut next = i + 1;
+            while let Some(vm::Opcode::Jmp(offset)) = instr.get(next) {
+                next = (next as i64 + offset) as usize;
+            }
+            if let Some(vm::Opcode::Ret) = instr.get(next) {
+                instr[i] = vm::Opcode::TailCall;
+            }
+        }
+    }
+}
+
+// Folds the arithmetic, comparison and boolean instructions of a function, or
+// of the code for a program, whose operands are integer or boolean constants,
+// and removes conditional jumps on constants. Arithmetic that would overflow
+// or divide by zero is left to fail, or not, at runtime as usual. The result
+// is then cleaned up with peephole, after removing the code that folding
+// conditional jumps has made unreachable.
+pub fn optimize(instr: &mut Vec<vm::Opcode>) {
+    while rewrite(instr, fold_at) {}
+    remove_unreachable(instr);
+    peephole(instr);
+}
+
+// Removes the instructions that can not be reached from the first one, such
+// as those following a jump or a return that are not jumped to, and then the
+// jumps that only go to the next instruction. Calls, even in tail position,
+// are assumed to return, as native functions do.
+fn remove_unreachable(instr: &mut Vec<vm::Opcode>) {
+    let mut reachable = vec![false; instr.len()];
+    let mut pending = vec![0];
+    while let Some(ip) = pending.pop() {
+        if ip >= instr.len() || reachable[ip] {
+            continue;
+        }
+        reachable[ip] = true;
+        match &instr[ip] {
+            vm::Opcode::Jmp(offset) => pending.push((ip as i64 + offset) as usize),
+            vm::Opcode::Jnz(offset) | vm::Opcode::Jz(offset) | vm::Opcode::Try(offset) => {
+                pending.push(ip + 1);
+                pending.push((ip as i64 + offset) as usize);
+            }
+            vm::Opcode::Ret => {}
+            _ => pending.push(ip + 1),
+        }
+    }
+    remove(instr, &reachable);
+
+    loop {
+        let kept: Vec<bool> = instr
+            .iter()
+            .map(|opcode| !matches!(opcode, vm::Opcode::Jmp(1)))
+            .collect();
+        if kept.iter().all(|kept| *kept) {
+            break;
+        }
+        remove(instr, &kept);
+    }
+}
+
+// Removes the instructions that are not kept. Jumps to a removed instruction
+// go to the next one that is kept instead.
+fn remove(instr: &mut Vec<vm::Opcode>, kept: &[bool]) {
+    let mut positions = Vec::with_capacity(instr.len() + 1);
+    let mut count = 0;
+    for is_kept in kept {
+        positions.push(count);
+        if *is_kept {
+            count += 1;
+        }
+    }
+    positions.push(count);
+
+    let mut remaining = Vec::with_capacity(count);
+    for (ip, opcode) in instr.iter().enumerate() {
+        if !kept[ip] {
+            continue;
+        }
+        match jump_offset(opcode) {
+            Some(offset) => {
+                let target = positions[(ip as i64 + offset) as usize];
+                remaining.push(with_offset(opcode, target as i64 - positions[ip] as i64));
+            }
+            None => remaining.push(opcode.clone()),
+        }
+    }
+    *instr = remaining;
+}
+
+// Replaces short sequences of instructions with shorter ones that do the
+// same: negation with neg, a jump on the negation of a condition with jnz,
+// and values that are duplicated only to be discarded.
+pub fn peephole(instr: &mut Vec<vm::Opcode>) {
+    use vm::Opcode::{Dup, Iconst, Jnz, Jz, Nconst, Neg, Not, Pop, SetEnv, Sub};
+
+    while rewrite(instr, |code, targets| match code {
+        [Iconst(0), Sub, ..] if !targets[1] => Some((vec![Neg], 2)),
+        [Nconst(n), Sub, ..] if n.is_zero() && !targets[1] => Some((vec![Neg], 2)),
+        [Not, Jz(target), ..] if !targets[1] => Some((vec![Jnz(*target)], 2)),
+        [Dup, Pop, ..] if !targets[1] => Some((Vec::new(), 2)),
+        [Dup, SetEnv(id), Pop, ..] if !targets[1] && !targets[2] => Some((vec![SetEnv(*id)], 3)),
+        _ => None,
+    }) {}
+}
+
+fn jump_offset(opcode: &vm::Opcode) -> Option<i64> {
+    match opcode {
+        vm::Opcode::Jmp(offset)
+        | vm::Opcode::Jnz(offset)
+        | vm::Opcode::Jz(offset)
+        | vm::Opcode::Try(offset) => Some(*offset),
+        _ => None,
+    }
+}
+
+fn with_offset(opcode: &vm::Opcode, offset: i64) -> vm::Opcode {
+    match opcode {
+        vm::Opcode::Jmp(_) => vm::Opcode::Jmp(offset),
+        vm::Opcode::Jnz(_) => vm::Opcode::Jnz(offset),
+        vm::Opcode::Jz(_) => vm::Opcode::Jz(offset),
+        vm::Opcode::Try(_) => vm::Opcode::Try(offset),
+        opcode => opcode.clone(),
+    }
+}
+
+// Applies a rule at each instruction, which is given the code from there on
+// and returns the instructions to replace some of it with, and how many are
+// replaced. Jumps are made absolute while instructions are replaced, and the
+// rule is told which instructions are jumped to so that it does not merge
+// them into the instructions before them. Returns whether anything changed.
+fn rewrite<F>(instr: &mut Vec<vm::Opcode>, rule: F) -> bool
+where
+    F: Fn(&[vm::Opcode], &[bool]) -> Option<(Vec<vm::Opcode>, usize)>,
+{
+    let mut targets = vec![false; instr.len() + 1];
+    let mut code = Vec::new();
+    for (ip, opcode) in instr.iter().enumerate() {
+        match jump_offset(opcode) {
+            Some(offset) => {
+                let target = (ip as i64 + offset) as usize;
+                targets[target] = true;
+                code.push(with_offset(opcode, target as i64));
+            }
+            None => code.push(opcode.clone()),
+        }
+    }
+
+    let mut rewritten = Vec::new();
+    let mut positions = vec![0; code.len() + 1];
+    let mut changed = false;
+    let mut ip = 0;
+    while ip < code.len() {
+        positions[ip] = rewritten.len();
+        match rule(&code[ip..], &targets[ip..]) {
+            Some((replacement, len)) => {
+                for position in &mut positions[ip..ip + len] {
+                    *position = rewritten.len();
+                }
+                rewritten.extend(replacement);
+                ip += len;
+                changed = true;
+            }
+            None => {
+                rewritten.push(code[ip].clone());
+                ip += 1;
+            }
+        }
+    }
+    positions[code.len()] = rewritten.len();
+
+    for (ip, opcode) in rewritten.iter_mut().enumerate() {
+        if let Some(target) = jump_offset(opcode) {
+            *opcode = with_offset(opcode, positions[target as usize] as i64 - ip as i64);
+        }
+    }
+    *instr = rewritten;
+    changed
+}
+
+// Returns the instructions to replace those at the start of the code with,
+// and how many are replaced. Source positions between the operands and the
+// instruction that is folded are dropped along with them.
+fn fold_at(code: &[vm::Opcode], targets: &[bool]) -> Option<(Vec<vm::Opcode>, usize)> {
+    use vm::Opcode::{Bconst, Iconst, Jmp, Jnz, Jz, Not, Srcpos};
+
+    match code {
+        [Bconst(b), Not, ..] if !targets[1] => return Some((vec![Bconst(!b)], 2)),
+        [Bconst(true), Jz(_), ..] | [Bconst(false), Jnz(_), ..] if !targets[1] => {
+            return Some((Vec::new(), 2));
+        }
+        [Bconst(false), Jz(target), ..] | [Bconst(true), Jnz(target), ..] if !targets[1] => {
+            return Some((vec![Jmp(*target)], 2));
+        }
+        [Iconst(_) | Bconst(_), ..] => {}
+        _ => return None,
+    }
+    let mut next = 1;
+    while let Some(Srcpos(..)) = code.get(next) {
+        next += 1;
+    }
+    let op = code.get(next + 1)?;
+    if targets[1..next + 2].iter().any(|target| *target) {
+        return None;
+    }
+    let value = fold_binary(op, &code[next], &code[0])?;
+    Some((vec![value], next + 2))
+}
+
+// Evaluates an instruction taking two constants, where x was pushed last.
+fn fold_binary(op: &vm::Opcode, x: &vm::Opcode, y: &vm::Opcode) -> Option<vm::Opcode> {
+    use vm::Opcode::{Bconst, Iconst};
+
+    Some(match (op, x, y) {
+        (vm::Opcode::Add, Iconst(x), Iconst(y)) => Iconst(x.checked_add(*y)?),
+        (vm::Opcode::Sub, Iconst(x), Iconst(y)) => Iconst(x.checked_sub(*y)?),
+        (vm::Opcode::Mul, Iconst(x), Iconst(y)) => Iconst(x.checked_mul(*y)?),
+        (vm::Opcode::Div, Iconst(x), Iconst(y)) => Iconst(x.checked_div(*y)?),
+        (vm::Opcode::Mod, Iconst(x), Iconst(y)) => Iconst(x.checked_rem(*y)?),
+        (vm::Opcode::Pow, Iconst(x), Iconst(y)) => Iconst(vm::checked_pow(*x, *y)?),
+        (vm::Opcode::Equal, Iconst(x), Iconst(y)) => Bconst(x == y),
+        (vm::Opcode::Equal, Bconst(x), Bconst(y)) => Bconst(x == y),
+        (vm::Opcode::NotEqual, Iconst(x), Iconst(y)) => Bconst(x != y),
+        (vm::Opcode::NotEqual, Bconst(x), Bconst(y)) => Bconst(x != y),
+        (vm::Opcode::Greater, Iconst(x), Iconst(y)) => Bconst(x > y),
+        (vm::Opcode::GreaterEqual, Iconst(x), Iconst(y)) => Bconst(x >= y),
+        (vm::Opcode::Less, Iconst(x), Iconst(y)) => Bconst(x < y),
+        (vm::Opcode::LessEqual, Iconst(x), Iconst(y)) => Bconst(x <= y),
+        (vm::Opcode::And, Bconst(x), Bconst(y)) => Bconst(*x && *y),
+        (vm::Opcode::Or, Bconst(x), Bconst(y)) => Bconst(*x || *y),
+        _ => return None,
+    })
+}
+
+// Evaluates an expression at compile time if it only involves literals and
+// identifiers that are known to be bound to constants.
+fn const_value(ast: &TypedAST, consts: &HashMap<String, vm::Value>) -> Option<vm::Value> {
+    use vm::Value::{Boolean, Integer};
+    match ast {
+        TypedAST::BinaryOp(_, op, lhs, rhs, _, _) => {
+            match (op, const_value(lhs, consts)?, const_value(rhs, consts)?) {
+                (parser::Operator::And, Boolean(x), Boolean(y)) => Some(Boolean(x && y)),
+                (parser::Operator::Or, Boolean(x), Boolean(y)) => Some(Boolean(x || y)),
+                (parser::Operator::Equal, x, y) => Some(Boolean(x == y)),
+                (parser::Operator::NotEqual, x, y) => Some(Boolean(x != y)),
+                (parser::Operator::Greater, Integer(x), Integer(y)) => Some(Boolean(x > y)),
+                (parser::Operator::GreaterEqual, Integer(x), Integer(y)) => Some(Boolean(x >= y)),
+                (parser::Operator::Less, Integer(x), Integer(y)) => Some(Boolean(x < y)),
+                (parser::Operator::LessEqual, Integer(x), Integer(y)) => Some(Boolean(x <= y)),
+                (parser::Operator::Plus, Integer(x), Integer(y)) => x.checked_add(y).map(Integer),
+                (parser::Operator::Minus, Integer(x), Integer(y)) => x.checked_sub(y).map(Integer),
+                (parser::Operator::Multiply, Integer(x), Integer(y)) => {
+                    x.checked_mul(y).map(Integer)
+                }
+                (parser::Operator::Divide, Integer(x), Integer(y)) => x.checked_div(y).map(Integer),
+                (parser::Operator::Mod, Integer(x), Integer(y)) => x.checked_rem(y).map(Integer),
+                (parser::Operator::Power, Integer(x), Integer(y)) => {
+                    vm::checked_pow(x, y).map(Integer)
+                }
+                _ => None,
+            }
+        }
+        TypedAST::Boolean(b) => Some(Boolean(*b)),
+        TypedAST::Identifier(_, id) => consts.get(id).cloned(),
+        TypedAST::Integer(i) => Some(Integer(*i)),
+        TypedAST::UnaryOp(_, op, ast) => match (op, const_value(ast, consts)?) {
+            (parser::Operator::Minus, Integer(i)) => i.checked_neg().map(Integer),
+            (parser::Operator::Not, Boolean(b)) => Some(Boolean(!b)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn remove_params(param: &TypedAST, consts: &mut HashMap<String, vm::Value>) {
+    match param {
+        TypedAST::Identifier(_, id) => {
+            consts.remove(id);
+        }
+        TypedAST::Tuple(_, elements) => {
+            for element in elements {
+                remove_params(element, consts);
+            }
+        }
+        _ => {}
+    }
+}
+
+// Folds an expression that is only evaluated conditionally. Definitions made
+// inside of it may or may not have happened afterwards, so any constant they
+// change is no longer known.
+fn fold_branch(
+    ast: &mut TypedAST,
+    consts: &mut HashMap<String, vm::Value>,
+    warnings: &mut Vec<Warning>,
+) {
+    let mut local_consts = consts.clone();
+    fold_conditions(ast, &mut local_consts, warnings);
+    consts.retain(|id, value| local_consts.get(id) == Some(value));
+}
+
+// Removes the branches of an if expression that can never be taken, returning
+// the expression to use in its place if only one is left.
+fn fold_if(
+    conds: &mut Vec<(TypedAST, TypedAST)>,
+    els: &mut TypedAST,
+    line: usize,
+    col: usize,
+    consts: &mut HashMap<String, vm::Value>,
+    warnings: &mut Vec<Warning>,
+) -> Option<TypedAST> {
+    let mut values = Vec::new();
+    for (i, cond) in conds.iter_mut().enumerate() {
+        if i == 0 {
+            fold_conditions(&mut cond.0, consts, warnings);
+        } else {
+            fold_branch(&mut cond.0, consts, warnings);
+        }
+        values.push(const_value(&cond.0, consts));
+        fold_branch(&mut cond.1, consts, warnings);
+    }
+    fold_branch(els, consts, warnings);
+
+    let mut kept = Vec::new();
+    let mut taken = None;
+    for (cond, value) in conds.drain(..).zip(values) {
+        match value {
+            Some(vm::Value::Boolean(true)) => {
+                warnings.push(Warning {
+                    msg: "Condition is always true, the remaining branches are never evaluated."
+                        .to_string(),
+                    line,
+                    col,
+                });
+                taken = Some(cond.1);
+                break;
+            }
+            Some(vm::Value::Boolean(false)) => {
+                warnings.push(Warning {
+                    msg: "Condition is always false, the branch is never evaluated.".to_string(),
+                    line,
+                    col,
+                });
+            }
+            _ => kept.push(cond),
+        }
+    }
+
+    let taken = match taken {
+        Some(taken) => taken,
+        None => std::mem::replace(els, TypedAST::Unit),
+    };
+    if kept.is_empty() {
+        Some(taken)
+    } else {
+        *conds = kept;
+        *els = taken;
+        None
+    }
+}
+
+// Replaces if expressions whose conditions are known at compile time with the
+// branch that is taken, so that constant definitions can be used for simple
+// compile time configuration. The consts map holds the definitions known to be
+// bound to a constant at the current point in the program. These are the
+// values that closures capture, so a later redefinition does not affect
+// functions that were already defined.
+fn fold_conditions(
+    ast: &mut TypedAST,
+    consts: &mut HashMap<String, vm::Value>,
+    warnings: &mut Vec<Warning>,
+) {
+    let mut folded = None;
+    match ast {
+        TypedAST::BinaryOp(_, _, lhs, rhs, _, _) => {
+            fold_conditions(rhs, consts, warnings);
+            fold_conditions(lhs, consts, warnings);
+        }
+        TypedAST::Call(fun, arg, ..) => {
+            fold_conditions(arg, consts, warnings);
+            fold_conditions(fun, consts, warnings);
+        }
+        TypedAST::Datatype(_, variants) => {
+            for variant in variants {
+                consts.remove(&variant.0);
+            }
+        }
+        TypedAST::Define(_, id, value) => {
+            fold_conditions(value, consts, warnings);
+            match const_value(value, consts) {
+                Some(value) => {
+                    consts.insert(id.to_string(), value);
+                }
+                None => {
+                    consts.remove(id);
+                }
+            }
+        }
+        TypedAST::Function(id, param, body, ..) => {
+            let mut local_consts = consts.clone();
+            remove_params(param, &mut local_consts);
+            if let Some(id) = id {
+                local_consts.remove(id);
+                consts.remove(id);
+            }
+            fold_conditions(body, &mut local_consts, warnings);
+        }
+        TypedAST::If(conds, els, line, col) => {
+            folded = fold_if(conds, els, *line, *col, consts, warnings);
+        }
+        TypedAST::Letrec(functions) => {
+            for function in functions.iter() {
+                if let TypedAST::Function(Some(id), ..) = function {
+                    consts.remove(id);
+                }
+            }
+            for function in functions {
+                fold_conditions(function, consts, warnings);
+            }
+        }
+        TypedAST::Let(param, value, body, line, col) => {
+            fold_conditions(value, consts, warnings);
+            let mut local_consts = consts.clone();
+            remove_params(param, &mut local_consts);
+            if let (TypedAST::Identifier(_, id), Some(value)) =
+                (&**param, const_value(value, consts))
+            {
+                local_consts.insert(id.to_string(), value);
+            }
+            fold_conditions(body, &mut local_consts, warnings);
+            // A deferred expression is only bound so that the body can follow it.
+            if let TypedAST::Identifier(_, id) = &**param {
+                if !is_used(body, id) && !matches!(**value, TypedAST::Defer(_)) {
+                    warnings.push(Warning {
+                        msg: format!("Unused let binding {}.", id),
+                        line: *line,
+                        col: *col,
+                    });
+                }
+            }
+        }
+        TypedAST::Try(body, param, handler, _, _) => {
+            fold_conditions(body, &mut consts.clone(), warnings);
+            let mut local_consts = consts.clone();
+            remove_params(param, &mut local_consts);
+            fold_conditions(handler, &mut local_consts, warnings);
+        }
+        TypedAST::Match(cond, _, cases) => {
+            fold_conditions(cond, consts, warnings);
+            for case in cases {
+                match &case.1 {
+                    // Cases with parameters are compiled to functions.
+                    Some(param) => {
+                        let mut local_consts = consts.clone();
+                        remove_params(param, &mut local_consts);
+                        if let Some(guard) = &mut case.2 {
+                            fold_conditions(guard, &mut local_consts, warnings);
+                        }
+                        fold_conditions(&mut case.3, &mut local_consts, warnings);
+                    }
+                    None => {
+                        if let Some(guard) = &mut case.2 {
+                            fold_branch(guard, consts, warnings);
+                        }
+                        fold_branch(&mut case.3, consts, warnings);
+                    }
+                }
+            }
+        }
+        TypedAST::Array(_, expressions)
+        | TypedAST::List(_, expressions)
+        | TypedAST::Program(_, expressions)
+        | TypedAST::Tuple(_, expressions) => {
+            for expression in expressions {
+                fold_conditions(expression, consts, warnings);
+            }
+        }
+        TypedAST::UnaryOp(_, _, ast)
+        | TypedAST::Field(_, ast, _)
+        | TypedAST::Propagate(_, ast, _, _) => {
+            fold_conditions(ast, consts, warnings);
+        }
+        TypedAST::For(_, function, iter) => {
+            fold_conditions(function, consts, warnings);
+            fold_conditions(iter, consts, warnings);
+        }
+        TypedAST::While(cond, body) => {
+            // Whatever the body defines may change between iterations and
+            // may not be defined at all if the loop never runs.
+            let names = defined(body);
+            for name in &names {
+                consts.remove(name);
+            }
+            fold_conditions(cond, consts, warnings);
+            fold_conditions(body, consts, warnings);
+            for name in &names {
+                consts.remove(name);
+            }
+        }
+        TypedAST::Defer(function) => fold_conditions(function, consts, warnings),
+        TypedAST::Map(_, entries) => {
+            for (key, value) in entries {
+                fold_conditions(key, consts, warnings);
+                fold_conditions(value, consts, warnings);
+            }
+        }
+        TypedAST::Record(_, fields) => {
+            for field in fields {
+                fold_conditions(&mut field.1, consts, warnings);
+            }
+        }
+        TypedAST::Update(_, record, fields) => {
+            fold_conditions(record, consts, warnings);
+            for field in fields {
+                fold_conditions(&mut field.1, consts, warnings);
+            }
+        }
+        _ => {}
+    }
+    if let Some(folded) = folded {
+        *ast = folded;
+    }
+}
+
+// Returns whether an identifier appears anywhere in an expression. Shadowing
+// is not taken into account, so an identifier may be considered used when it
+// is not, but never the other way around.
+fn is_used(ast: &TypedAST, id: &str) -> bool {
+    match ast {
+        TypedAST::BinaryOp(_, _, lhs, rhs, _, _) => is_used(lhs, id) || is_used(rhs, id),
+        TypedAST::Call(fun, arg, ..) => is_used(fun, id) || is_used(arg, id),
+        TypedAST::Define(_, _, value) => is_used(value, id),
+        TypedAST::Function(_, _, body, ..) => is_used(body, id),
+        TypedAST::For(_, function, iter) => is_used(function, id) || is_used(iter, id),
+        TypedAST::While(cond, body) => is_used(cond, id) || is_used(body, id),
+        TypedAST::Defer(function) => is_used(function, id),
+        TypedAST::Identifier(_, name) => name == id,
+        TypedAST::If(conds, els, _, _) => {
+            conds
+                .iter()
+                .any(|(cond, then)| is_used(cond, id) || is_used(then, id))
+                || is_used(els, id)
+        }
+        TypedAST::Let(_, value, body, _, _) | TypedAST::Try(value, _, body, _, _) => {
+            is_used(value, id) || is_used(body, id)
+        }
+        TypedAST::Letrec(functions) => functions.iter().any(|function| is_used(function, id)),
+        TypedAST::Match(cond, _, cases) => {
+            is_used(cond, id)
+                || cases.iter().any(|case| {
+                    case.2.as_ref().is_some_and(|guard| is_used(guard, id)) || is_used(&case.3, id)
+                })
+        }
+        TypedAST::Array(_, expressions)
+        | TypedAST::List(_, expressions)
+        | TypedAST::Program(_, expressions)
+        | TypedAST::Tuple(_, expressions) => {
+            expressions.iter().any(|expression| is_used(expression, id))
+        }
+        TypedAST::UnaryOp(_, _, ast)
+        | TypedAST::Field(_, ast, _)
+        | TypedAST::Propagate(_, ast, _, _) => is_used(ast, id),
+        TypedAST::Map(_, entries) => entries
+            .iter()
+            .any(|(key, value)| is_used(key, id) || is_used(value, id)),
+        TypedAST::Record(_, fields) => fields.iter().any(|field| is_used(&field.1, id)),
+        TypedAST::Update(_, record, fields) => {
+            is_used(record, id) || fields.iter().any(|field| is_used(&field.1, id))
+        }
+        _ => false,
+    }
+}
+
+pub fn eval(vm: &mut vm::VirtualMachine, ast: &parser::AST) -> Result<vm::Value, InterpreterError> {
+    eval_with_type(vm, ast).map(|(value, _)| value)
+}
+
+// Compiles a program, leaving the virtual machine ready to run it, and
+// returns its inferred type.
+pub fn compile(vm: &mut vm::VirtualMachine, ast: &parser::AST) -> Result<Type, InterpreterError> {
+    let _guard = panics::enter();
+    let mut ast = ast.clone();
+    parser::resolve_features(&mut ast, &vm.features);
+    let mut typed_ast = infer(
+        &ast,
+        Arc::make_mut(&mut vm.env.types),
+        Arc::make_mut(&mut vm.env.datatypes),
+    )?;
+    let mut consts = vm
+        .env
+        .values
+        .iter()
+        .filter(|(_, value)| matches!(value, vm::Value::Boolean(_) | vm::Value::Integer(_)))
+        .map(|(id, value)| (vm.names.name(*id).to_string(), value.clone()))
+        .collect();
+    fold_conditions(&mut typed_ast, &mut consts, &mut vm.warnings);
+    let mut latent = (*vm.env.effects).clone();
+    let effects = effects::infer(&typed_ast, &mut latent);
+    let denied: Vec<String> = effects
+        .difference(&vm.allowed_effects)
+        .map(|effect| effect.to_string())
+        .collect();
+    if !denied.is_empty() {
+        return Err(InterpreterError::new(
+            ErrorKind::Effect,
+            format!("Effect not permitted: {}.", denied.join(", ")),
+            usize::MAX,
+            usize::MAX,
+        ));
+    }
+    vm.env.effects = Arc::new(latent);
+    let mut instr = Vec::new();
+    let ids = HashMap::new();
+    generate(&typed_ast, vm, &mut instr, &ids);
+    if vm.optimize {
+        optimize(&mut instr);
+    }
+    vm.ip = vm.emit(instr);
+    // Generated code should always pass the verifier, which is checked in
+    // debug builds to catch mistakes in code generation.
+    debug_assert!(verify::verify(&vm.instructions, vm.ip, &vm.env, &vm.names).is_ok());
+    Ok(typeinfer::type_of(&typed_ast))
+}
+
+// Names the functions of a virtual machine by their entry ips, from its
+// symbol table.
+pub fn function_names(vm: &vm::VirtualMachine) -> HashMap<usize, String> {
+    vm.symbols
+        .iter()
+        .map(|(ip, info)| {
+            let name = info.name.as_deref().unwrap_or("lambda");
+            (*ip, name.to_string())
+        })
+        .collect()
+}
+
+// Lists the instructions of a virtual machine with their addresses. The
+// instructions are grouped by the function they belong to, starting with the
+// builtins, and the code for the program about to run is labelled main.
+// Functions are labelled from the symbol table with where they are defined,
+// and let bodies and match cases as scopes. Jumps are shown with the address
+// they jump to rather than their offset.
+pub fn disassemble(vm: &vm::VirtualMachine) -> String {
+    let mut labels = HashMap::new();
+    for (ip, info) in vm.symbols.iter() {
+        let name = info.name.as_deref().unwrap_or("lambda");
+        let label = if info.line == usize::MAX {
+            format!("{} @{}", name, ip)
+        } else {
+            format!("{} @{} ({}:{})", name, ip, info.line, info.col)
+        };
+        labels.insert(*ip, label);
+    }
+    for opcode in vm.instructions.iter() {
+        if let vm::Opcode::Fconst(None, ip, _) = opcode {
+            labels
+                .entry(*ip)
+                .or_insert_with(|| format!("scope @{}", ip));
+        }
+    }
+    if vm.ip < vm.instructions.len() {
+        labels.insert(vm.ip, format!("main @{}", vm.ip));
+    }
+
+    let mut listing = String::new();
+    for (ip, opcode) in vm.instructions.iter().enumerate() {
+        if let Some(label) = labels.get(&ip) {
+            if ip != 0 {
+                listing.push('\n');
+            }
+            listing.push_str(&format!("{}:\n", label));
+        }
+        let instruction = match opcode {
+            vm::Opcode::Jmp(offset) => format!("jmp @{}", ip as i64 + offset),
+            vm::Opcode::Jz(offset) => format!("jz @{}", ip as i64 + offset),
+            vm::Opcode::Jnz(offset) => format!("jnz @{}", ip as i64 + offset),
+            vm::Opcode::Try(offset) => format!("try @{}", ip as i64 + offset),
+            opcode => opcode.display(&vm.names).to_string(),
+        };
+        listing.push_str(&format!("{:>6}  {}\n", ip, instruction));
+    }
+    listing
+}
+
+// Evaluates a program, returning its value along with its inferred type.
+// Yields are ignored, the program is run to completion.
+pub fn eval_with_type(
+    vm: &mut vm::VirtualMachine,
+    ast: &parser::AST,
+) -> Result<(vm::Value, Type), InterpreterError> {
+    let typ = compile(vm, ast)?;
+    loop {
+        if let vm::Status::Finished(value) = vm.run()? {
+            return Ok((value, typ));
+        }
+    }
+}
+
+// Evaluates a program whose value is a function, returning a handle to it.
+pub fn eval_handle(
+    vm: &mut vm::VirtualMachine,
+    ast: &parser::AST,
+) -> Result<vm::FunctionHandle, InterpreterError> {
+    let fun = eval(vm, ast)?;
+    vm.handle(fun)
+}
+
+// Compiles a program and runs it until it finishes or yields. A program that
+// has yielded is continued with VirtualMachine::run.
+pub fn start(
+    vm: &mut vm::VirtualMachine,
+    ast: &parser::AST,
+) -> Result<vm::Status, InterpreterError> {
+    compile(vm, ast)?;
+    vm.run()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::codegen;
+    use crate::parser;
+    use crate::symbol::Names;
+    use crate::typeinfer::Type;
+    use crate::vm;
+    use crate::vm::Value;
+
+    macro_rules! eval {
+        ($input:expr, Datatype, $value:expr) => {{
+            let mut vm = vm::VirtualMachine::new();
+            match parser::parse($input) {
+                Ok(ast) => match codegen::eval(&mut vm, &ast) {
+                    Ok(v) => match v {
+                        Value::Datatype(_, _, v) => {
+                            assert_eq!(v, $value);
+                        }
+                        _ => {
+                            assert!(false);
+                        }
+                    },
+                    Err(_) => {
+                        assert!(false);
+                    }
+                },
+                Err(_) => {
+                    assert!(false);
+                }
+            }
+        }};
+        ($input:expr, Tuple, $($value:expr),*) => {{
+            let mut vm = vm::VirtualMachine::new();
+            match parser::parse($input) {
+                Ok(ast) => match codegen::eval(&mut vm, &ast) {
+                    Ok(v) => match v {
+                        Value::Tuple(elements) => {
+                            let mut i = 0;
+                            $(
+                                assert!(i < elements.len());
+                                assert_eq!(elements[i], $value);
+                                i += 1;
+                                assert!(i != 0);  // Silence warning
+                            )*
+                        }
+                        _ => {
+                            assert!(false);
+                        }
+                    },
+                    Err(_) => {
+                        assert!(false);
+                    }
+                },
+                Err(_) => {
+                    assert!(false);
+                }
+            }
+        }};
+        ($input:expr, Unit) => {{
+            let mut vm = vm::VirtualMachine::new();
+            match parser::parse($input) {
+                Ok(ast) => match codegen::eval(&mut vm, &ast) {
+                    Ok(v) => {
+                        assert_eq!(v, Value::Unit);
+                    },
+                    Err(_) => {
+                        assert!(false);
+                    }
+                },
+                Err(_) => {
+                    assert!(false);
+                }
+            }
+        }};
+        ($input:expr, $type:tt, $value:expr) => {{
+            let mut vm = vm::VirtualMachine::new();
+            match parser::parse($input) {
+                Ok(ast) => match codegen::eval(&mut vm, &ast) {
+                    Ok(v) => match v {
+                        Value::$type(t) => {
+                            assert_eq!(t, $value);
+                        }
+                        _ => {
+                            assert!(false);
+                        }
+                    },
+                    Err(_) => {
+                        assert!(false);
+                    }
+                },
+                Err(_) => {
+                    assert!(false);
+                }
+            }
+        }};
+    }
+
+    macro_rules! evalfails {
+        ($input:expr, $err:expr) => {{
+            let mut vm = vm::VirtualMachine::new();
+            match parser::parse($input) {
+                Ok(ast) => match codegen::eval(&mut vm, &ast) {
+                    Ok(_) => {
+                        assert!(false);
+                    }
+                    Err(err) => {
+                        assert_eq!(err.err, $err);
+                    }
+                },
+                Err(_) => {
+                    assert!(false);
+                }
+            }
+        }};
+    }
+
+    #[test]
+    fn error_kinds() {
+        use crate::codegen::{ErrorKind, InterpreterError, RuntimeError};
+        use crate::typeinfer::Type;
+        use std::error::Error;
+
+        let failure = |src: &str| {
+            let mut vm = vm::VirtualMachine::new();
+            let ast = parser::parse(src).ok().unwrap();
+            codegen::eval(&mut vm, &ast).err().unwrap()
+        };
+        let err = failure("1 + true");
+        assert_eq!(
+            err.kind,
+            ErrorKind::Type {
+                expected: Some(Type::Integer),
+                found: Some(Type::Boolean)
+            }
+        );
+        let err = failure("x");
+        assert_eq!(
+            err.kind,
+            ErrorKind::Type {
+                expected: None,
+                found: None
+            }
+        );
+        let err = failure(
+            "fn f (x) -> 1 / x end
+                           fn g (x) -> f (x) + 1 end
+                           g (0)",
+        );
+        match err.kind {
+            ErrorKind::Runtime { kind, backtrace } => {
+                assert_eq!(kind, RuntimeError::DivisionByZero);
+                let names: Vec<Option<String>> = backtrace
+                    .iter()
+                    .map(|frame| frame.function.clone())
+                    .collect();
+                assert_eq!(
+                    names,
+                    vec![Some("f".to_string()), Some("g".to_string()), None]
+                );
+                let positions: Vec<(usize, usize)> = backtrace
+                    .iter()
+                    .map(|frame| (frame.line, frame.col))
+                    .collect();
+                assert_eq!(positions, vec![(1, 15), (2, 40), (3, 28)]);
+            }
+            _ => unreachable!(),
+        }
+        let err = failure("receive (())");
+        assert!(matches!(
+            err.kind,
+            ErrorKind::Runtime {
+                kind: RuntimeError::Deadlock,
+                ..
+            }
+        ));
+        assert!(err.source().is_none());
+        let err = failure("try exit (3) catch e -> 0 end");
+        assert_eq!(err.kind, ErrorKind::Exit(3));
+        assert_eq!(err.err, "Exited with status 3.");
+        let err = failure("1 + abort (\"gave up\")");
+        assert!(matches!(
+            err.kind,
+            ErrorKind::Runtime {
+                kind: RuntimeError::Aborted,
+                ..
+            }
+        ));
+        assert_eq!(err.err, "gave up");
+
+        let mut vm = vm::VirtualMachine::new();
+        vm.allowed_effects.clear();
+        let ast = parser::parse("print (1)").ok().unwrap();
+        let err = codegen::eval(&mut vm, &ast).err().unwrap();
+        assert_eq!(err.kind, ErrorKind::Effect);
+
+        let err: InterpreterError = parser::parse("1 +").err().unwrap().into();
+        assert_eq!(err.kind, ErrorKind::Parse);
+        assert_eq!(err.source().unwrap().to_string(), err.err);
+    }
+
+    #[test]
+    fn exit_and_abort() {
+        eval!(
+            "try abort (\"no \" ++ \"luck\") catch e -> e end",
+            Str,
+            "no luck"
+        );
+        eval!(
+            "fn first (l) -> match l with x :: xs -> x | %[] -> abort (\"empty\") end end
+             first (%[2])",
+            Integer,
+            2
+        );
+        evalfails!("abort (\"empty\")", "empty");
+        evalfails!(
+            "exit (2 ** 40)",
+            "Exit status 1099511627776 is out of range."
+        );
+        evalfails!(
+            "exit (true)",
+            "Type error: expected integer but found boolean."
+        );
+    }
+
+    #[test]
+    fn fuel() {
+        let mut vm = vm::VirtualMachine::new();
+        let ast = parser::parse("fn loop (n) -> loop (n + 1) end loop (0)")
+            .ok()
+            .unwrap();
+        vm.set_fuel(10000);
+        let err = codegen::eval(&mut vm, &ast).err().unwrap();
+        assert_eq!(err.err, "Out of fuel.");
+        assert!(matches!(
+            err.kind,
+            codegen::ErrorKind::Runtime {
+                kind: codegen::RuntimeError::OutOfFuel,
+                ..
+            }
+        ));
+        assert_eq!(vm.fuel(), Some(0));
+        vm.reset();
+
+        vm.set_fuel(1000);
+        let ast = parser::parse("1 + 2").ok().unwrap();
+        assert_eq!(codegen::eval(&mut vm, &ast).ok(), Some(Value::Integer(3)));
+        let left = vm.fuel().unwrap();
+        assert!(left < 1000);
+
+        // Callbacks and workers use the same fuel.
+        let ast = parser::parse(
+            "fn count (n) -> if n == 0 then 0 else count (n - 1) end end
+             sort_by (fn (x, y) -> count (100) < count (100) end, [1, 2])",
+        )
+        .ok()
+        .unwrap();
+        vm.set_fuel(100);
+        let err = codegen::eval(&mut vm, &ast).err().unwrap();
+        assert_eq!(err.err, "Out of fuel.");
+        vm.reset();
+        let ast = parser::parse(
+            "fn count (n) -> if n == 0 then 0 else count (n - 1) end end
+             par_map (count, [100, 100, 100, 100])",
+        )
+        .ok()
+        .unwrap();
+        vm.set_fuel(1000);
+        let err = codegen::eval(&mut vm, &ast).err().unwrap();
+        assert_eq!(err.err, "Out of fuel.");
+        vm.reset();
+        vm.set_fuel(100000);
+        assert!(codegen::eval(&mut vm, &ast).is_ok());
+        assert!(vm.fuel().unwrap() < 100000 - 400);
+        vm.clear_fuel();
+        assert!(codegen::eval(&mut vm, &ast).is_ok());
+        assert_eq!(vm.fuel(), None);
+    }
+
+    #[test]
+    fn call_depth() {
+        evalfails!(
+            "fn sum (n) -> if n == 0 then 0 else n + sum (n - 1) end end sum (100000)",
+            "Stack overflow."
+        );
+        eval!(
+            "fn sum (n) -> if n == 0 then 0 else n + sum (n - 1) end end sum (1000)",
+            Integer,
+            500500
+        );
+        evalfails!(
+            "def sum := memo (fn sum (n) -> if n == 0 then 0 else n + sum (n - 1) end end)
+             sum (100000)",
+            "Stack overflow."
+        );
+        let mut vm = vm::VirtualMachine::new();
+        vm.max_call_depth = 10;
+        let ast = parser::parse(
+            "fn sum (n) -> if n == 0 then 0 else n + sum (n - 1) end end
+             sort_by (fn (x, y) -> sum (x) < sum (y) end, [20, 10])",
+        )
+        .ok()
+        .unwrap();
+        let err = codegen::eval(&mut vm, &ast).err().unwrap();
+        assert_eq!(err.err, "Stack overflow.");
+        assert!(matches!(
+            err.kind,
+            codegen::ErrorKind::Runtime {
+                kind: codegen::RuntimeError::StackOverflow,
+                ..
+            }
+        ));
+        vm.reset();
+        let ast =
+            parser::parse("fn sum (n) -> if n == 0 then 0 else n + sum (n - 1) end end sum (5)")
+                .ok()
+                .unwrap();
+        assert_eq!(codegen::eval(&mut vm, &ast).ok(), Some(Value::Integer(15)));
+    }
+
+    #[test]
+    fn soft_limits() {
+        let mut vm = vm::VirtualMachine::new();
+        vm.call_depth_warning = Some(50);
+        let ast = parser::parse(
+            "fn sum (n) -> if n == 0 then 0 else n + sum (n - 1) end end
+             sum (100) + sum (100)",
+        )
+        .ok()
+        .unwrap();
+        assert_eq!(
+            codegen::eval(&mut vm, &ast).ok(),
+            Some(Value::Integer(10100))
+        );
+        let warnings: Vec<String> = vm.warnings.drain(..).map(|w| w.to_string()).collect();
+        assert_eq!(warnings, vec!["Warning: Call depth reached 50 frames."]);
+        vm.reset();
+        let ast = parser::parse("sum (10)").ok().unwrap();
+        assert!(codegen::eval(&mut vm, &ast).is_ok());
+        assert!(vm.warnings.is_empty());
+
+        let mut vm = vm::VirtualMachine::new();
+        vm.fuel_warning = Some(100);
+        vm.set_fuel(10000);
+        let ast = parser::parse("fn loop (n) -> loop (n + 1) end\nloop (0)")
+            .ok()
+            .unwrap();
+        let err = codegen::eval(&mut vm, &ast).err().unwrap();
+        assert_eq!(err.err, "Out of fuel.");
+        assert_eq!(vm.warnings.len(), 1);
+        assert_eq!(
+            vm.warnings[0].msg,
+            "Only 100 instructions of fuel are left."
+        );
+        assert_eq!(vm.warnings[0].line, 1);
+    }
+
+    #[test]
+    fn profile() {
+        let mut vm = vm::VirtualMachine::new();
+        vm.profile = Some(vm::Profile::new());
+        let ast = parser::parse("fn f (n) -> n * 2 end\n\nf (1) + f (2)")
+            .ok()
+            .unwrap();
+        assert_eq!(codegen::eval(&mut vm, &ast).ok(), Some(Value::Integer(6)));
+        let mut profile = vm.profile.take().unwrap();
+        profile.stop();
+        let counts: Vec<(usize, u64)> = (1..=3)
+            .map(|line| (line, profile.lines.get(&line).map_or(0, |l| l.count)))
+            .collect();
+        assert_eq!(counts, vec![(1, 11), (2, 0), (3, 9)]);
+        assert_eq!(
+            profile.folded(&codegen::function_names(&vm)),
+            "main 14\nmain;f 10\n"
+        );
+    }
+
+    #[test]
+    fn tail_calls() {
+        let mut vm = vm::VirtualMachine::new();
+        let ast = parser::parse(
+            "fn f (n) ->
+                 if n == 0 then 0 else f (n - 1) end
+             end",
+        )
+        .ok()
+        .unwrap();
+        let start = vm.instructions.len();
+        assert!(codegen::eval(&mut vm, &ast).is_ok());
+        assert!(vm.instructions[start..]
+            .iter()
+            .any(|op| matches!(op, vm::Opcode::TailCall)));
+        assert!(!vm.instructions[start..]
+            .iter()
+            .any(|op| matches!(op, vm::Opcode::Call)));
+    }
+
+    #[test]
+    fn constant_conditions() {
+        let mut vm = vm::VirtualMachine::new();
+        let ast = parser::parse(
+            "def DEBUG := false
+             fn f (n) ->
+                 if DEBUG then n elsif n > 0 then 1 else 0 end
+             end
+             if ~DEBUG && (1 < 2) then f (2) else 0 end",
+        )
+        .ok()
+        .unwrap();
+        let start = vm.instructions.len();
+        assert_eq!(
+            codegen::eval(&mut vm, &ast).ok(),
+            Some(vm::Value::Integer(1))
+        );
+        assert_eq!(vm.warnings.len(), 2);
+        assert_eq!(
+            vm.instructions[start..]
+                .iter()
+                .filter(|op| matches!(op, vm::Opcode::Jz(_)))
+                .count(),
+            1
+        );
+
+        vm.warnings.clear();
+        let ast = parser::parse("if DEBUG then 1 else 2 end").ok().unwrap();
+        assert_eq!(
+            codegen::eval(&mut vm, &ast).ok(),
+            Some(vm::Value::Integer(2))
+        );
+        assert_eq!(vm.warnings.len(), 1);
+
+        vm.warnings.clear();
+        let ast = parser::parse(
+            "fn g (DEBUG) -> if DEBUG then 1 else 2 end end
+             g (true)",
+        )
+        .ok()
+        .unwrap();
+        assert_eq!(
+            codegen::eval(&mut vm, &ast).ok(),
+            Some(vm::Value::Integer(1))
+        );
+        assert!(vm.warnings.is_empty());
+    }
+
+    #[test]
+    fn unused_let() {
+        let mut vm = vm::VirtualMachine::new();
+        let ast = parser::parse(
+            "let x = 1 in
+                 let y = 2 in
+                     x + 3",
+        )
+        .ok()
+        .unwrap();
+        assert_eq!(
+            codegen::eval(&mut vm, &ast).ok(),
+            Some(vm::Value::Integer(4))
+        );
+        assert_eq!(vm.warnings.len(), 1);
+        assert_eq!(vm.warnings[0].msg, "Unused let binding y.");
+        assert_eq!((vm.warnings[0].line, vm.warnings[0].col), (2, 18));
+    }
+
+    #[test]
+    fn effects() {
+        let mut vm = vm::VirtualMachine::new();
+        let ast = parser::parse("print (\"hello\")").ok().unwrap();
+        assert_eq!(codegen::eval(&mut vm, &ast).ok(), Some(vm::Value::Unit));
+
+        // Printed lines can be captured by the host.
+        vm.output = Some(String::new());
+        let captured = parser::parse("print (\"hello\"); print ((1, 'a'))")
+            .ok()
+            .unwrap();
+        assert!(codegen::eval(&mut vm, &captured).is_ok());
+        assert_eq!(vm.output.take().as_deref(), Some("hello\n(1, 'a')\n"));
+
+        vm.allowed_effects.clear();
+        assert_eq!(
+            codegen::eval(&mut vm, &ast).err().map(|err| err.err),
+            Some("Effect not permitted: io.".to_string())
+        );
+
+        let ast = parser::parse("fn f (x) -> print (x) end").ok().unwrap();
+        assert!(codegen::eval(&mut vm, &ast).is_ok());
+        let ast = parser::parse("f (1)").ok().unwrap();
+        assert_eq!(
+            codegen::eval(&mut vm, &ast).err().map(|err| err.err),
+            Some("Effect not permitted: io.".to_string())
+        );
+
+        // Calls through values returned by functions are checked at runtime.
+        let ast = parser::parse(
+            "def mk := fn (t) -> fn (x) -> print (x + t) end end
+             def g := mk (1)
+             g (2)",
+        )
+        .ok()
+        .unwrap();
+        assert_eq!(
+            codegen::eval(&mut vm, &ast).err().map(|err| err.err),
+            Some("Effect not permitted: io.".to_string())
+        );
+    }
+
+    #[test]
+    fn features() {
+        let src = "def level := when feature = \"debug\" then
+                       2
+                   else
+                       when feature = \"trace\" then 1 else undefined end
+                   end";
+        let ast = parser::parse(src).ok().unwrap();
+
+        let mut vm = vm::VirtualMachine::new();
+        assert!(codegen::eval(&mut vm, &ast).is_err());
+
+        let mut vm = vm::VirtualMachine::new();
+        vm.features.insert("trace".to_string());
+        assert_eq!(
+            codegen::eval(&mut vm, &ast).ok(),
+            Some(vm::Value::Integer(1))
+        );
+
+        let mut vm = vm::VirtualMachine::new();
+        vm.features.insert("debug".to_string());
+        assert_eq!(
+            codegen::eval(&mut vm, &ast).ok(),
+            Some(vm::Value::Integer(2))
+        );
+
+        let ast = parser::parse("when feature = \"trace\" then 1 end")
+            .ok()
+            .unwrap();
+        assert_eq!(codegen::eval(&mut vm, &ast).ok(), Some(vm::Value::Unit));
+    }
+
+    #[test]
+    fn optimize() {
+        use vm::Opcode::*;
+
+        let listing = |instr: &[vm::Opcode]| -> Vec<String> {
+            instr.iter().map(|opcode| opcode.to_string()).collect()
+        };
+
+        let mut instr = vec![
+            Srcpos(1, 1),
+            Iconst(2),
+            Srcpos(1, 5),
+            Iconst(3),
+            Iconst(4),
+            Mul,
+            Add,
+            Iconst(14),
+            Equal,
+            Not,
+            Jz(3),
+            Iconst(1),
+            Jmp(2),
+            Iconst(2),
+            Ret,
+        ];
+        codegen::optimize(&mut instr);
+        assert_eq!(listing(&instr), listing(&[Srcpos(1, 1), Iconst(2), Ret]));
+
+        // Arithmetic that fails at runtime is left alone, as are constants
+        // that are jumped to.
+        let mut instr = vec![Iconst(0), Iconst(1), Div, Iconst(1), Iconst(i64::MAX), Add];
+        codegen::optimize(&mut instr);
+        assert_eq!(instr.len(), 6);
+        let mut instr = vec![
+            Arg(0),
+            Jz(3),
+            Iconst(1),
+            Jmp(2),
+            Iconst(2),
+            Iconst(3),
+            Add,
+            Ret,
+        ];
+        codegen::optimize(&mut instr);
+        assert_eq!(instr.len(), 8);
+
+        // Code that is not reached is removed, along with jumps that then
+        // go to the next instruction.
+        let mut instr = vec![
+            Arg(0),
+            Jz(4),
+            Iconst(1),
+            Ret,
+            Iconst(2),
+            Jmp(2),
+            Iconst(3),
+            Ret,
+        ];
+        codegen::optimize(&mut instr);
+        assert_eq!(
+            listing(&instr),
+            listing(&[Arg(0), Jz(3), Iconst(1), Ret, Ret])
+        );
+
+        let run = |src: &str, optimize: bool| {
+            let mut vm = vm::VirtualMachine::new();
+            vm.optimize = optimize;
+            let ast = parser::parse(src).ok().unwrap();
+            let value = codegen::eval(&mut vm, &ast).map_err(|err| err.err);
+            (value, vm.instructions.len())
+        };
+        for src in [
+            "def x := (2 * 3 + 1) * -2 x - 1",
+            "fn f (n) -> if n > 2 * 3 then ~(1 < 2) || (n == 10) else (1 - 2) == -1 end end
+             def t := (f (7), f (10), f (0))",
+            "fn g (n) -> n / (1 - 1) end g (1)",
+        ] {
+            let (plain, plain_len) = run(src, false);
+            let (optimized, optimized_len) = run(src, true);
+            assert_eq!(plain, optimized);
+            assert!(optimized_len < plain_len);
+        }
+    }
+
+    #[test]
+    fn peephole() {
+        use crate::bigint::BigInt;
+        use vm::Opcode::*;
+
+        let listing = |instr: &[vm::Opcode]| -> Vec<String> {
+            instr.iter().map(|opcode| opcode.to_string()).collect()
+        };
+
+        let x = Names::new().intern("x");
+        let mut instr = vec![
+            Arg(0),
+            Iconst(0),
+            Sub,
+            Dup,
+            SetEnv(x),
+            Pop,
+            GetEnv(x),
+            Nconst(BigInt::from(0)),
+            Sub,
+            Dup,
+            Pop,
+            Arg(1),
+            Not,
+            Jz(3),
+            Iconst(1),
+            Ret,
+            Iconst(2),
+            Ret,
+        ];
+        codegen::peephole(&mut instr);
+        assert_eq!(
+            listing(&instr),
+            listing(&[
+                Arg(0),
+                Neg,
+                SetEnv(x),
+                GetEnv(x),
+                Neg,
+                Arg(1),
+                Jnz(3),
+                Iconst(1),
+                Ret,
+                Iconst(2),
+                Ret
+            ])
+        );
+
+        // Instructions that are jumped to are kept.
+        let mut instr = vec![Arg(0), Jz(2), Arg(1), Iconst(0), Sub, Not, Jz(1), Ret];
+        codegen::peephole(&mut instr);
+        assert_eq!(
+            listing(&instr),
+            listing(&[Arg(0), Jz(2), Arg(1), Neg, Jnz(1), Ret])
+        );
+        let mut instr = vec![Arg(0), Jz(2), Iconst(0), Sub, Ret];
+        codegen::peephole(&mut instr);
+        assert_eq!(instr.len(), 5);
+
+        let mut vm = vm::VirtualMachine::new();
+        vm.optimize = true;
+        vm.overflow = vm::Overflow::Wrap;
+        let ast = parser::parse(
+            "fn f (n) -> if ~(n > 2) then -n else n end end
+             def t := (f (1), f (3), f (-9223372036854775807 - 1), -(1.5))",
+        )
+        .ok()
+        .unwrap();
+        assert_eq!(
+            codegen::eval(&mut vm, &ast).ok(),
+            Some(Value::Tuple(vec![
+                Value::Integer(-1),
+                Value::Integer(3),
+                Value::Integer(i64::MIN),
+                Value::Float(-1.5)
+            ]))
+        );
+        let listing = codegen::disassemble(&vm);
+        assert!(listing.contains("jnz @"));
+        assert!(listing.contains("neg"));
+    }
+
+    #[test]
+    fn records() {
+        eval!("{ x = 1, y = 2 }.x", Integer, 1);
+        eval!(
+            "def p := { x = 1, y = 2 } def q := { p with y = 3 } (p.y, q.y)",
+            Tuple,
+            Value::Integer(2),
+            Value::Integer(3)
+        );
+        eval!("{ a = { b = \"s\" } }.a.b", Str, "s");
+        eval!("{ x = 1, y = 2 } == { y = 2, x = 1 }", Boolean, true);
+        eval!(
+            "fn f (p) -> p.x + 1 end
+             f ({ x = 1 })",
+            Integer,
+            2
+        );
+        evalfails!(
+            "fn f (p) -> p.x end
+             f (1)",
+            "Expected record but found 1."
+        );
+        eval!("(1, \"two\", 3.0).1", Str, "two");
+        eval!("def t := ((1, 2), 3) t.0.1 + t.1", Integer, 5);
+        eval!(
+            "fn second (t) -> t.1 end
+             second ((false, true, false))",
+            Boolean,
+            true
+        );
+        evalfails!(
+            "fn third (t) -> t.2 end
+             third ((1, 2))",
+            "Index 2 is out of range for 2 elements."
+        );
+
+        let mut vm = vm::VirtualMachine::new();
+        let ast = parser::parse("{ y = false, x = 1 }").ok().unwrap();
+        let value = codegen::eval(&mut vm, &ast).ok().unwrap();
+        assert_eq!(value.to_string(), "{x = 1, y = false}");
+    }
+
+    #[test]
+    fn lets() {
+        eval!("let x = 2 in x * x", Integer, 4);
+        eval!(
+            "fn f (n) -> let m = n + 1 in let k = m * 2 in k + n end
+             f (1)",
+            Integer,
+            5
+        );
+        eval!(
+            "fn g (n) -> let h = fn (x) -> x + n end in h (1) end
+             g (2)",
+            Integer,
+            3
+        );
+        eval!(
+            "def deep := fn (a) -> let b = a + 1 in fn (c) -> a + b + c end end
+             def r := (deep (1)) (10)",
+            Integer,
+            13
+        );
+        eval!(
+            "def x := 1
+             def y := let x = 2 in x + 1
+             def t := (x, y)",
+            Tuple,
+            Value::Integer(1),
+            Value::Integer(3)
+        );
+
+        eval!(
+            "fn swap (p) -> let (x, y) = p in (y, x) end
+             def t := swap ((1, 2))",
+            Tuple,
+            Value::Integer(2),
+            Value::Integer(1)
+        );
+        eval!(
+            "def n := 10
+             let (a, b) = (n + 1, fn (x) -> x * n end) in b (a)",
+            Integer,
+            110
+        );
+
+        let mut vm = vm::VirtualMachine::new();
+        let ast = parser::parse("let x = 1 in def y := x + 1").ok().unwrap();
+        assert!(codegen::eval(&mut vm, &ast).is_ok());
+        let (x, y) = (vm.symbol("x"), vm.symbol("y"));
+        assert!(!vm.env.values.contains_key(&x));
+        assert!(!vm.env.values.contains_key(&y));
+    }
+
+    #[test]
+    fn letrec() {
+        eval!(
+            "letrec
+                 fn even (n) -> if n == 0 then true else odd (n - 1) end end
+                 fn odd (n) -> if n == 0 then false else even (n - 1) end end
+             end
+             def t := (even (10), odd (7), even (100001))",
+            Tuple,
+            Value::Boolean(true),
+            Value::Boolean(true),
+            Value::Boolean(false)
+        );
+        eval!(
+            "fn f (k) ->
+                 letrec
+                     fn g (n) -> if n == 0 then k else h (n - 1) end end
+                     fn h (n) -> if n == 0 then 0 - k else g (n - 1) end end
+                 end
+                 def t := (g (3), h (3))
+             end
+             f (5)",
+            Tuple,
+            Value::Integer(-5),
+            Value::Integer(5)
+        );
+        eval!(
+            "letrec
+                 fn f (n) -> if n == 0 then 0 else g (n - 1) end end
+                 fn g (n) -> def h := fn (x) -> f (n) + x end h (1) end
+             end
+             g (3)",
+            Integer,
+            4
+        );
+    }
+
+    #[test]
+    fn scopes() {
+        // A closure keeps the values defined when it was created, even if
+        // the function that created it defines them again.
+        eval!(
+            "fn outer (x) ->
+                 def y := x + 1
+                 def f := fn (z) -> x + y + z end
+                 def y := 100
+                 f (1) + y
+             end
+             outer (1)",
+            Integer,
+            104
+        );
+        eval!(
+            "fn count (n) ->
+                 def inc := fn (x) -> x + 1 end
+                 if n == 0 then 0 else inc (count (n - 1)) end
+             end
+             count (1000)",
+            Integer,
+            1000
+        );
+        eval!(
+            "def a := 1
+             def f := fn (x) -> x + a end
+             def a := 2
+             (f (0), a)",
+            Tuple,
+            Value::Integer(1),
+            Value::Integer(2)
+        );
+    }
+
+    #[test]
+    fn processes() {
+        eval!(
+            "def parent := self ()
+             fn sum () ->
+                 fn iter (total) ->
+                     def msg := receive ()
+                     if msg == 0 then send (parent, total) else iter (total + msg) end
+                 end
+                 iter (0)
+             end
+             def p := spawn (sum)
+             def a := send (p, 1)
+             def b := send (p, 2)
+             def c := send (p, 0)
+             receive ()",
+            Integer,
+            3
+        );
+        eval!(
+            "def parent := self ()
+             fn echo () -> send (parent, (self (), receive ())) end
+             def p := spawn (echo)
+             def q := spawn (echo)
+             def a := send (q, 1)
+             def b := send (p, 2)
+             def first := receive ()
+             def second := receive ()
+             (first == (p, 2)) && (second == (q, 1))",
+            Boolean,
+            true
+        );
+        eval!("def p := spawn (fn () -> receive () end) send (p, 1)", Unit);
+        evalfails!(
+            "receive ()",
+            "Deadlock: every process is waiting for a message."
+        );
+        evalfails!(
+            "def parent := self ()
+             def p := spawn (fn () -> send (parent, 1 / 0) end)
+             receive ()",
+            "Division by zero."
+        );
+        evalfails!(
+            "par_map (fn (x) -> spawn (fn () -> x end) end, [1, 2])",
+            "Effect not permitted: nondeterminism."
+        );
+        evalfails!("send (1, 2)", "Expected pid or channel but found 1.");
+        evalfails!(
+            "def a := send ((self (), \"hi\")) receive () + 1",
+            "Expected integer but found \"hi\"."
+        );
+        evalfails!(
+            "def a := send ((self (), (1, true))) def p := receive () fst (p) + snd (p)",
+            "Expected (integer, integer) but found (1, true)."
+        );
+        evalfails!(
+            "def a := send ((self (), fn (x, y) -> x end))
+             def f := receive ()
+             f (5) + 1",
+            "Expected integer -> integer but found (lambda at 1:26)."
+        );
+        eval!(
+            "def a := send ((self (), fn (x) -> x end)) def f := receive () 1",
+            Integer,
+            1
+        );
+        eval!(
+            "def a := send ((self (), [1, 2])) map (fn (x) -> x + 1 end, receive ())",
+            Array,
+            vec![Value::Integer(2), Value::Integer(3)]
+        );
+        evalfails!(
+            "def r := receive r ()",
+            "Type error: receive can only be called directly."
+        );
+    }
+
+    #[test]
+    fn channels() {
+        let mut vm = vm::VirtualMachine::new();
+        let input = vm.channel("input", Type::Integer);
+        let output = vm.channel("output", Type::Integer);
+        let ast = parser::parse(
+            "fn iter (total) ->
+                 def x := recv (input)
+                 if x == 0 then
+                     total
+                 else
+                     let sent = send (output, x * x) in iter (total + x)
+                 end
+             end
+             iter (0)",
+        )
+        .ok()
+        .unwrap();
+        std::thread::scope(|scope| {
+            let script = scope.spawn(|| codegen::eval(&mut vm, &ast));
+            for i in 1..4 {
+                assert!(input.send(Value::Integer(i)));
+                assert_eq!(output.recv(), Some(Value::Integer(i * i)));
+            }
+            input.send(Value::Integer(0));
+            assert_eq!(script.join().unwrap().ok(), Some(Value::Integer(6)));
+        });
+        assert_eq!(output.try_recv(), None);
+
+        input.send(Value::Integer(1));
+        input.close();
+        assert!(!input.send(Value::Integer(2)));
+        let ast = parser::parse("def a := recv (input) recv (input)")
+            .ok()
+            .unwrap();
+        let err = codegen::eval(&mut vm, &ast).err().unwrap();
+        assert_eq!(err.err, "Channel is closed.");
+        evalfails!(
+            "recv (1)",
+            "Type error: expected t1 channel but found integer."
+        );
+
+        let mut vm = vm::VirtualMachine::new();
+        let input = vm.channel("input", Type::Integer);
+        let output = vm.channel("output", Type::Integer);
+        for (src, msg) in [
+            (
+                "send (output, \"hi\")",
+                "Type error: expected integer but found string.",
+            ),
+            (
+                "recv (input) ++ \"!\"",
+                "Type error: expected string but found integer.",
+            ),
+        ] {
+            let ast = parser::parse(src).ok().unwrap();
+            assert_eq!(codegen::eval(&mut vm, &ast).err().unwrap().err, msg);
+        }
+        input.send(Value::Str("hi".to_string()));
+        let ast = parser::parse("recv (input) + 1").ok().unwrap();
+        let err = codegen::eval(&mut vm, &ast).err().unwrap();
+        assert_eq!(err.err, "Expected integer but found \"hi\".");
+        vm.reset();
+        let ast = parser::parse("fn f (c) -> send (c, \"hi\") end f (output)")
+            .ok()
+            .unwrap();
+        let err = codegen::eval(&mut vm, &ast).err().unwrap();
+        assert_eq!(err.err, "Expected integer but found \"hi\".");
+        assert_eq!(output.try_recv(), None);
+    }
+
+    #[test]
+    fn yields() {
+        let src = "fn count (n) ->
+                       if n == 0 then 42 else let y = yield_now () in count (n - 1) end
+                   end
+                   count (3)";
+        let mut vm = vm::VirtualMachine::new();
+        let ast = parser::parse(src).ok().unwrap();
+        assert_eq!(
+            codegen::start(&mut vm, &ast).ok(),
+            Some(vm::Status::Yielded)
+        );
+        assert_eq!(vm.run().ok(), Some(vm::Status::Yielded));
+        assert_eq!(vm.run().ok(), Some(vm::Status::Yielded));
+        assert_eq!(
+            vm.run().ok(),
+            Some(vm::Status::Finished(Value::Integer(42)))
+        );
+
+        let mut vm = vm::VirtualMachine::new();
+        vm.deadline = Some(std::time::Instant::now() + std::time::Duration::from_secs(60));
+        assert_eq!(
+            codegen::start(&mut vm, &ast).ok(),
+            Some(vm::Status::Finished(Value::Integer(42)))
+        );
+
+        eval!(src, Integer, 42);
+        eval!(
+            "par_map (fn (x) -> let y = yield_now () in x end, [1, 2])",
+            Array,
+            vec![Value::Integer(1), Value::Integer(2)]
+        );
+    }
+
+    #[test]
+    fn events() {
+        let mut vm = vm::VirtualMachine::new();
+        let ast = parser::parse(
+            "def scale := 10
+             def a := on (\"click\", fn (x) -> x * scale end)
+             def b := on (\"click\", fn (x) -> x + 1 end)
+             on (\"key\", fn (k) -> k ++ \"!\" end)",
+        )
+        .ok()
+        .unwrap();
+        assert_eq!(codegen::eval(&mut vm, &ast).ok(), Some(Value::Unit));
+        assert_eq!(
+            vm.emit_event("click", Value::Integer(2)).ok(),
+            Some(vec![Value::Integer(20), Value::Integer(3)])
+        );
+        assert_eq!(
+            vm.emit_event("key", Value::Str("a".to_string())).ok(),
+            Some(vec![Value::Str("a!".to_string())])
+        );
+        assert_eq!(vm.emit_event("none", Value::Unit).ok(), Some(vec![]));
+
+        let ast = parser::parse("on (\"click\", fn (x) -> 1 / x end)")
+            .ok()
+            .unwrap();
+        assert_eq!(codegen::eval(&mut vm, &ast).ok(), Some(Value::Unit));
+        let err = vm.emit_event("click", Value::Integer(0)).err().unwrap();
+        assert_eq!(err.err, "Division by zero.");
+        assert!(vm.stack.is_empty());
+
+        evalfails!(
+            "def f := 1 on (\"click\", f)",
+            "Type error: expected (string, t1 -> t2) but found (string, integer)."
+        );
+    }
+
+    #[test]
+    fn symbols() {
+        let mut vm = vm::VirtualMachine::new();
+        let start = vm.instructions.len();
+        let ast = parser::parse(
+            "fn f (x) -> let y = x in y end
+             def g := fn (x) -> x end
+             def shown := (show (f), show (g), show (print), show ((1, f)))
+             shown",
+        )
+        .ok()
+        .unwrap();
+        assert_eq!(
+            codegen::eval(&mut vm, &ast).ok(),
+            Some(Value::Tuple(vec![
+                Value::Str("(fn f at 1:1)".to_string()),
+                Value::Str("(lambda at 2:23)".to_string()),
+                Value::Str("(fn print)".to_string()),
+                Value::Str("(1, (fn f at 1:1))".to_string()),
+            ]))
+        );
+        let names = codegen::function_names(&vm);
+        assert_eq!(names.values().filter(|name| *name == "f").count(), 1);
+        assert_eq!(
+            names
+                .iter()
+                .filter(|(ip, name)| **ip >= start && *name == "lambda")
+                .count(),
+            1
+        );
+        let listing = codegen::disassemble(&vm);
+        assert!(listing
+            .lines()
+            .any(|line| line.starts_with("f @") && line.ends_with(" (1:1):")));
+        assert!(listing.lines().any(|line| line.starts_with("scope @")));
+        let ast = parser::parse(
+            "fn h (xs) -> at (sort_by (fn (x, y) -> 1 / 0 > x end, xs), 0) end
+             h ([1, 2])",
+        )
+        .ok()
+        .unwrap();
+        match codegen::eval(&mut vm, &ast).err().unwrap().kind {
+            codegen::ErrorKind::Runtime { backtrace, .. } => {
+                let names: Vec<Option<String>> =
+                    backtrace.into_iter().map(|frame| frame.function).collect();
+                assert_eq!(
+                    names,
+                    vec![
+                        None,
+                        Some("sort_by".to_string()),
+                        Some("h".to_string()),
+                        None
+                    ]
+                );
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn disassemble() {
+        let mut vm = vm::VirtualMachine::new();
+        let ast = parser::parse("fn f (x) -> if x then 1 else 2 end end f (true)")
+            .ok()
+            .unwrap();
+        codegen::compile(&mut vm, &ast).ok().unwrap();
+        let listing = codegen::disassemble(&vm);
+        assert!(listing.starts_with("print @0:\n     0  print\n     1  ret\n"));
+        let labels: Vec<&str> = listing.lines().filter(|line| line.ends_with(':')).collect();
+        assert!(labels[labels.len() - 2].starts_with("f @"));
+        assert_eq!(labels[labels.len() - 1], format!("main @{}:", vm.ip));
+        let jz = listing
+            .lines()
+            .skip_while(|line| !line.starts_with("f @"))
+            .find(|line| line.contains("jz"))
+            .unwrap();
+        let target: usize = jz.split('@').nth(1).unwrap().parse().unwrap();
+        assert!(listing
+            .lines()
+            .any(|line| line.starts_with(&format!("{:>6}  const 2", target))));
+    }
+
+    #[test]
+    fn handles() {
+        let mut vm = vm::VirtualMachine::new();
+        let ast = parser::parse("def n := 10 fn (x) -> x + n end")
+            .ok()
+            .unwrap();
+        let add = codegen::eval_handle(&mut vm, &ast).ok().unwrap();
+        let ast = parser::parse("fn (x) -> 100 / x end").ok().unwrap();
+        let div = codegen::eval_handle(&mut vm, &ast).ok().unwrap();
+        for i in 0..3 {
+            assert_eq!(
+                vm.call_handle(&add, Value::Integer(i)).ok(),
+                Some(Value::Integer(i + 10))
+            );
+        }
+        let err = vm.call_handle(&div, Value::Integer(0)).err().unwrap();
+        assert_eq!(err.err, "Division by zero.");
+        assert_eq!(
+            vm.call_handle(&div, Value::Integer(4)).ok(),
+            Some(Value::Integer(25))
+        );
+
+        let mut other = vm::VirtualMachine::new();
+        let err = other.call_handle(&add, Value::Integer(1)).err().unwrap();
+        assert_eq!(err.err, "Invalid function handle.");
+
+        vm.release(div);
+        let ast = parser::parse("fn (x) -> x end").ok().unwrap();
+        let id = codegen::eval_handle(&mut vm, &ast).ok().unwrap();
+        assert_eq!(vm.call_handle(&id, Value::Unit).ok(), Some(Value::Unit));
+        assert_eq!(
+            vm.call_handle(&add, Value::Integer(1)).ok(),
+            Some(Value::Integer(11))
+        );
+
+        let ast = parser::parse("1").ok().unwrap();
+        let err = codegen::eval_handle(&mut vm, &ast).err().unwrap();
+        assert_eq!(err.err, "Expected function but found 1.");
+    }
+
+    #[test]
+    fn debugger() {
+        let src = "fn f (x) -> x * 2 end
+                   f (1) + f (2)";
+        let mut vm = vm::VirtualMachine::new();
+        let ast = parser::parse(src).ok().unwrap();
+        let mut debugger = vm::Debugger::new();
+        debugger.lines.insert(1);
+        vm.debugger = Some(debugger);
+        assert_eq!(
+            codegen::start(&mut vm, &ast).ok(),
+            Some(vm::Status::Breakpoint)
+        );
+        assert_eq!(vm.instructions[vm.ip].to_string(), "srcpos 1 15");
+        assert_eq!(vm.callstack.len(), 1);
+        let f = vm.ip;
+        assert_eq!(vm.run().ok(), Some(vm::Status::Breakpoint));
+        assert_eq!(vm.ip, f);
+        assert_eq!(vm.run().ok(), Some(vm::Status::Finished(Value::Integer(6))));
+
+        let mut debugger = vm::Debugger::new();
+        debugger.step = true;
+        vm.debugger = Some(debugger);
+        let ast = parser::parse("1 + 2").ok().unwrap();
+        let start = match codegen::start(&mut vm, &ast).ok() {
+            Some(vm::Status::Breakpoint) => vm.ip,
+            _ => usize::MAX,
+        };
+        let mut steps = 0;
+        while vm.run().ok() == Some(vm::Status::Breakpoint) {
+            steps += 1;
+            assert_eq!(vm.ip, start + steps);
+        }
+        assert!(steps > 0);
+        let f = vm.symbol("f");
+        assert!(vm.environment().values.contains_key(&f));
+
+        let ast = parser::parse("3 * 4").ok().unwrap();
+        assert_eq!(codegen::eval(&mut vm, &ast).ok(), Some(Value::Integer(12)));
+    }
+
+    #[test]
+    fn par_map() {
+        eval!(
+            "fn fib (n) -> if n < 2 then n else fib (n - 1) + fib (n - 2) end end
+             par_map (fib, [10, 11, 12, 1, 2])",
+            Array,
+            vec![
+                Value::Integer(55),
+                Value::Integer(89),
+                Value::Integer(144),
+                Value::Integer(1),
+                Value::Integer(1)
+            ]
+        );
+        eval!(
+            "def n := 10
+             par_map (fn (x) -> x + n end, [1, 2, 3])",
+            Array,
+            vec![Value::Integer(11), Value::Integer(12), Value::Integer(13)]
+        );
+        eval!(
+            "def inc := memo (fn (x) -> x + 1 end)
+             def a := inc (1)
+             par_map (inc, [1, 2])",
+            Array,
+            vec![Value::Integer(2), Value::Integer(3)]
+        );
+        eval!("par_map (fn (x) -> x end, [])", Array, vec![]);
+        eval!(
+            "par_map (fn (x) -> x > 1 end, [1, 2])",
+            Array,
+            vec![Value::Boolean(false), Value::Boolean(true)]
+        );
+        evalfails!("par_map (print, [1, 2])", "Effect not permitted: io.");
+        evalfails!("par_map (fn (x) -> 1 / x end, [1, 0])", "Division by zero.");
+        evalfails!(
+            "par_map (fn (x) -> x end, (1, 2))",
+            "Type error: expected (t3 -> t4, [t3]) but found (t1 -> t1, (integer, integer))."
+        );
+        evalfails!(
+            "par_map (fn (x) -> x + 1 end, [1]) + 1",
+            "Type error: expected integer but found [integer]."
+        );
+    }
+
+    #[test]
+    fn sort_by() {
+        eval!(
+            "sort_by (fn (x, y) -> x < y end, [3, 1, 2, 5, 4])",
+            Array,
+            vec![
+                Value::Integer(1),
+                Value::Integer(2),
+                Value::Integer(3),
+                Value::Integer(4),
+                Value::Integer(5)
+            ]
+        );
+        eval!(
+            "def pairs := sort_by (fn (x, y) -> x.k < y.k end,
+                                   [{k = 1, v = 1}, {k = 0, v = 2}, {k = 1, v = 3}])
+             par_map (fn (p) -> p.v end, pairs)",
+            Array,
+            vec![Value::Integer(2), Value::Integer(1), Value::Integer(3)]
+        );
+        eval!(
+            "fn sort (t) -> sort_by (fn (x, y) -> x < y end, t) end
+             sort_by (fn (x, y) -> sort ([y, x]) == [x, y] end, [3, 1, 2])",
+            Array,
+            vec![Value::Integer(1), Value::Integer(2), Value::Integer(3)]
+        );
+        eval!("sort_by (fn (x, y) -> x < y end, [])", Array, vec![]);
+        evalfails!(
+            "sort_by (fn (a, b) -> a < b end, (3, 1, 2)) + 1",
+            "Type error: expected ((t5, t5) -> boolean, [t5]) but found ((integer, integer) -> boolean, (integer, integer, integer))."
+        );
+        evalfails!(
+            "sort_by (fn (x, y) -> 1 / (x - y) < 0 end, [1, 1])",
+            "Division by zero."
+        );
+        evalfails!(
+            "sort_by (fn (x, y) -> receive () end, [1, 2])",
+            "Can not wait for a message inside a callback."
+        );
+
+        // Calls made while a program has yielded leave it to continue as
+        // before, whether or not they succeed.
+        let mut vm = vm::VirtualMachine::new();
+        let ast = parser::parse("fn (x) -> 10 / x end").ok().unwrap();
+        let div = codegen::eval_handle(&mut vm, &ast).ok().unwrap();
+        let ast = parser::parse("let x = yield_now () in (1, 2)")
+            .ok()
+            .unwrap();
+        assert_eq!(
+            codegen::start(&mut vm, &ast).ok(),
+            Some(vm::Status::Yielded)
+        );
+        assert_eq!(
+            vm.call_handle(&div, Value::Integer(5)).ok(),
+            Some(Value::Integer(2))
+        );
+        let err = vm.call_handle(&div, Value::Integer(0)).err().unwrap();
+        assert_eq!(err.err, "Division by zero.");
+        assert_eq!(
+            vm.run().ok(),
+            Some(vm::Status::Finished(Value::Tuple(vec![
+                Value::Integer(1),
+                Value::Integer(2)
+            ])))
+        );
+    }
+
+    #[test]
+    fn floats() {
+        eval!("float_to_string (0.1 + 0.2)", Str, "0.30000000000000004");
+        eval!("float_to_string (1.0e100)", Str, "1e100");
+        eval!("float_to_string (3.0)", Str, "3.0");
+        eval!("parse_float (\"2.5e-3\")", Float, 0.0025);
+        eval!(
+            "def x := 1.0 / 3.0
+             parse_float (float_to_string (x)) == x",
+            Boolean,
+            true
+        );
+        eval!("float_to_string (1.0 / 0.0)", Str, "inf");
+        evalfails!("parse_float (\"1,5\")", "Can not parse \"1,5\" as a float.");
+        for x in [
+            0.1,
+            1.0 / 3.0,
+            1e-7,
+            123456789.125,
+            f64::MAX,
+            f64::MIN_POSITIVE,
+        ] {
+            let s = vm::format_float(x);
+            assert_eq!(s.parse::<f64>().ok(), Some(x));
+            let ast = parser::parse(&s).ok().unwrap();
+            let mut vm = vm::VirtualMachine::new();
+            assert_eq!(codegen::eval(&mut vm, &ast).ok(), Some(Value::Float(x)));
+        }
+    }
+
+    #[test]
+    fn format_ints() {
+        eval!("format_int ((255, 16, \"\"))", Str, "ff");
+        eval!("format_int ((1234567, 10, \",\"))", Str, "1,234,567");
+        eval!("format_int ((-123456, 10, \"_\"))", Str, "-123_456");
+        eval!("format_int ((3735928559, 16, \"_\"))", Str, "dead_beef");
+        eval!("format_int ((10, 2, \" \"))", Str, "1010");
+        eval!("format_int ((0, 36, \"_\"))", Str, "0");
+        eval!(
+            "format_int ((-9223372036854775807 - 1, 16, \"\"))",
+            Str,
+            "-8000000000000000"
+        );
+        evalfails!("format_int ((1, 1, \"\"))", "Invalid radix 1.");
+        evalfails!("format_int ((1, 37, \"\"))", "Invalid radix 37.");
+
+        let ints = vm::IntFormat::new(16, "_").unwrap();
+        let value = Value::Tuple(vec![Value::Integer(65535), Value::Integer(-65536)]);
+        let symbols = std::collections::HashMap::new();
+        assert_eq!(
+            value.display(&symbols).ints(&ints).to_string(),
+            "(ffff, -1_0000)"
+        );
+        assert_eq!(value.display(&symbols).to_string(), "(65535, -65536)");
+    }
+
+    #[test]
+    fn bigints() {
+        use crate::bigint::BigInt;
+
+        let big = |s: &str| Value::BigInt(s.parse::<BigInt>().unwrap());
+        let run = |src: &str, overflow: vm::Overflow| {
+            let mut vm = vm::VirtualMachine::new();
+            vm.overflow = overflow;
+            let ast = parser::parse(src).ok().unwrap();
+            codegen::eval(&mut vm, &ast)
+        };
+
+        let fact = "fn fact (n) -> if n == 0n then 1n else n * fact (n - 1n) end end fact (25n)";
+        assert_eq!(
+            run(fact, vm::Overflow::Wrap).ok(),
+            Some(big("15511210043330985984000000"))
+        );
+        assert_eq!(
+            run("-(2n * 9223372036854775807n) / 3n % 7n", vm::Overflow::Wrap).ok(),
+            Some(big("-4"))
+        );
+        assert_eq!(
+            run("(10n > 9n, -1n <= -2n, 5n == 5n)", vm::Overflow::Wrap).ok(),
+            Some(Value::Tuple(vec![
+                Value::Boolean(true),
+                Value::Boolean(false),
+                Value::Boolean(true)
+            ]))
+        );
+        eval!("show (12345678901234567890n)", Str, "12345678901234567890");
+        evalfails!("1n / 0n", "Division by zero.");
+        evalfails!("1n + 1", "Type error: expected bigint but found integer.");
+
+        // Integers overflow with an error unless the virtual machine wraps or
+        // promotes them.
+        let fact = "fn fact (n) -> if n == 0 then 1 else n * fact (n - 1) end end fact (25)";
+        assert_eq!(
+            run(fact, vm::Overflow::Trap).err().map(|err| err.err),
+            Some("Integer overflow.".to_string())
+        );
+        assert_eq!(
+            run(fact, vm::Overflow::Wrap).ok(),
+            Some(Value::Integer(7034535277573963776))
+        );
+        assert_eq!(
+            run(fact, vm::Overflow::Promote).ok(),
+            Some(big("15511210043330985984000000"))
+        );
+        let src = "def x := 9223372036854775807 + 1
+                   (x - 1, x > 0, abs (-x - 1), min ((x, 1)), x - x == 0)";
+        assert_eq!(
+            run(src, vm::Overflow::Promote).ok(),
+            Some(Value::Tuple(vec![
+                Value::Integer(i64::MAX),
+                Value::Boolean(true),
+                big("9223372036854775809"),
+                Value::Integer(1),
+                Value::Boolean(true)
+            ]))
+        );
+        assert_eq!(
+            run("(-9223372036854775807 - 1) / -1", vm::Overflow::Promote).ok(),
+            Some(big("9223372036854775808"))
+        );
+        assert_eq!(
+            run("(-9223372036854775807 - 1) / -1", vm::Overflow::Wrap).ok(),
+            Some(Value::Integer(i64::MIN))
+        );
+        assert_eq!(
+            run("2n * 3n", vm::Overflow::Promote).ok(),
+            Some(Value::Integer(6))
+        );
+    }
+
+    #[test]
+    fn integer_overflow() {
+        evalfails!("9223372036854775807 + 1", "Integer overflow.");
+        evalfails!("-9223372036854775807 - 2", "Integer overflow.");
+        evalfails!("4611686018427387904 * 2", "Integer overflow.");
+        evalfails!("(-9223372036854775807 - 1) / -1", "Integer overflow.");
+        evalfails!("(-9223372036854775807 - 1) % -1", "Integer overflow.");
+        evalfails!("-(-9223372036854775807 - 1)", "Integer overflow.");
+        evalfails!("abs (-9223372036854775807 - 1)", "Integer overflow.");
+        eval!("9223372036854775806 + 1", Integer, i64::MAX);
+        eval!("try 9223372036854775807 + 1 catch e -> 0 end", Integer, 0);
+
+        let mut vm = vm::VirtualMachine::new();
+        let ast = parser::parse("def x := 1\n9223372036854775807 * (x + 1)")
+            .ok()
+            .unwrap();
+        let err = codegen::eval(&mut vm, &ast).err().unwrap();
+        assert_eq!(err.line, 2);
+        assert!(matches!(
+            err.kind,
+            codegen::ErrorKind::Runtime {
+                kind: codegen::RuntimeError::IntegerOverflow,
+                ..
+            }
+        ));
+        vm.reset();
+        vm.overflow = vm::Overflow::Wrap;
+        assert_eq!(codegen::eval(&mut vm, &ast).ok(), Some(Value::Integer(-2)));
+    }
+
+    #[test]
+    fn pipes() {
+        eval!(
+            "fn inc (x) -> x + 1 end
+             fn double (x) -> x * 2 end
+             3 + 1 |> inc |> double",
+            Integer,
+            10
+        );
+        eval!("1..4 |> length", Integer, 3);
+        eval!("(2, 3) |> fn (x, y) -> x * y end", Integer, 6);
+        eval!("(1 |> show) ++ \"!\" |> length", Integer, 2);
+        eval!(
+            "let xs = [1, 2, 3] in (fn (x) -> x * x end, xs) |> map |> length",
+            Integer,
+            3
+        );
+        evalfails!("1 |> 2", "Type error: attempt to call non-lambda value.");
+        evalfails!(
+            "fn inc (x) -> x + 1 end 1 |> inc (2)",
+            "Type error: attempt to call non-lambda value."
+        );
+    }
+
+    #[test]
+    fn powers() {
+        eval!("2 ** 10", Integer, 1024);
+        eval!("-2 ** 2", Integer, -4);
+        eval!("(-2) ** 3 * 2 ** 3 ** 2", Integer, -4096);
+        eval!("7 ** 0 + 0 ** 0 + (-1) ** 9223372036854775807", Integer, 1);
+        eval!("(-2) ** 63", Integer, i64::MIN);
+        eval!("fn f (x) -> x ** 2 end f (9)", Integer, 81);
+        eval!("4.0 ** 0.5", Float, 2.0);
+        eval!("show (10n ** 30n)", Str, "1000000000000000000000000000000");
+        evalfails!("2 ** 63", "Integer overflow.");
+        evalfails!("2 ** -1", "Negative exponent.");
+        evalfails!("2n ** -1n", "Negative exponent.");
+        evalfails!("2n ** 10000000000000000000n", "Exponent too large.");
+        evalfails!("2 ** 1.5", "Type error: expected float but found integer.");
+
+        let run = |src: &str, overflow: vm::Overflow| {
+            let mut vm = vm::VirtualMachine::new();
+            vm.overflow = overflow;
+            vm.optimize = true;
+            let ast = parser::parse(src).ok().unwrap();
+            codegen::eval(&mut vm, &ast).ok()
+        };
+        assert_eq!(
+            run("3 ** 41", vm::Overflow::Wrap),
+            Some(Value::Integer(3i64.wrapping_pow(41)))
+        );
+        assert_eq!(run("2 ** 64", vm::Overflow::Wrap), Some(Value::Integer(0)));
+        assert_eq!(
+            run("2 ** 64 - 2 ** 64 + 2 ** 62", vm::Overflow::Promote),
+            Some(Value::Integer(1 << 62))
+        );
+        assert_eq!(
+            run("2 ** 10 - 1", vm::Overflow::Trap),
+            Some(Value::Integer(1023))
+        );
+    }
+
+    #[test]
+    fn wrapping_and_saturating() {
+        eval!("wrapping_add ((9223372036854775807, 1))", Integer, i64::MIN);
+        eval!(
+            "wrapping_sub ((-9223372036854775807 - 1, 1))",
+            Integer,
+            i64::MAX
+        );
+        eval!("wrapping_mul ((4611686018427387904, 2))", Integer, i64::MIN);
+        eval!(
+            "saturating_add ((9223372036854775807, 1))",
+            Integer,
+            i64::MAX
+        );
+        eval!(
+            "saturating_sub ((-9223372036854775807, 5))",
+            Integer,
+            i64::MIN
+        );
+        eval!(
+            "saturating_mul ((4611686018427387904, -4))",
+            Integer,
+            i64::MIN
+        );
+        eval!(
+            "saturating_add ((2, 3)) + wrapping_mul ((2, 3))",
+            Integer,
+            11
+        );
+        evalfails!(
+            "wrapping_add ((1n, 2n))",
+            "Type error: expected (integer, integer) but found (bigint, bigint)."
+        );
+
+        // The overflow mode of the virtual machine does not apply.
+        let mut vm = vm::VirtualMachine::new();
+        vm.overflow = vm::Overflow::Promote;
+        let ast = parser::parse("wrapping_add ((9223372036854775807, 1))")
+            .ok()
+            .unwrap();
+        assert_eq!(
+            codegen::eval(&mut vm, &ast).ok(),
+            Some(Value::Integer(i64::MIN))
+        );
+    }
+
+    #[test]
+    fn bits() {
+        eval!("popcount (255)", Integer, 8);
+        eval!("popcount (-1)", Integer, 64);
+        eval!("leading_zeros (1)", Integer, 63);
+        eval!("leading_zeros (0)", Integer, 64);
+        eval!("leading_zeros (-1)", Integer, 0);
+        eval!("rotate_left ((1, 63))", Integer, i64::MIN);
+        eval!("rotate_right ((1, 1))", Integer, i64::MIN);
+        eval!("rotate_left ((3, 64))", Integer, 3);
+        eval!(
+            "rotate_left ((1, -1)) == rotate_right ((1, 1))",
+            Boolean,
+            true
+        );
+        eval!("rotate_right ((6, 1))", Integer, 3);
+        evalfails!(
+            "popcount (true)",
+            "Type error: expected integer but found boolean."
+        );
+    }
+
+    #[test]
+    fn bytes() {
+        eval!("b\"a\\x00\\xff\"", Bytes, vec![97, 0, 255]);
+        eval!("bytes_length (b\"abc\")", Integer, 3);
+        eval!("byte_at ((b\"\\x89PNG\", 0))", Integer, 137);
+        eval!("bytes_slice ((b\"hello\", 1, 3))", Bytes, b"el".to_vec());
+        eval!("bytes_slice ((b\"hello\", 5, 5))", Bytes, Vec::<u8>::new());
+        eval!("string_to_bytes (\"é\")", Bytes, vec![0xc3, 0xa9]);
+        eval!("bytes_to_string (b\"\\xc3\\xa9\")", Str, "é");
+        eval!("b\"ab\" == string_to_bytes (\"ab\")", Boolean, true);
+        eval!("show (b\"\\\"\\x01a\\n\")", Str, "b\"\\\"\\x01a\\n\"");
+        evalfails!(
+            "byte_at ((b\"ab\", 2))",
+            "Index 2 is out of range for 2 bytes."
+        );
+        evalfails!(
+            "byte_at ((b\"ab\", -1))",
+            "Index -1 is out of range for 2 bytes."
+        );
+        evalfails!(
+            "bytes_slice ((b\"ab\", 2, 1))",
+            "Range 2 to 1 is out of range for 2 bytes."
+        );
+        evalfails!(
+            "bytes_to_string (b\"ab\\xff\")",
+            "Invalid UTF-8 in bytes at offset 2."
+        );
+        evalfails!(
+            "b\"ab\" ++ \"c\"",
+            "Type error: expected string but found bytes."
+        );
+    }
+
+    #[test]
+    fn chars() {
+        eval!("'a'", Char, 'a');
+        eval!("'\\''", Char, '\'');
+        eval!("char_at ((\"héllo\", 1))", Char, 'é');
+        eval!("'a' < 'b'", Boolean, true);
+        eval!("'é' >= 'z'", Boolean, true);
+        eval!("'a' == char_at ((\"abc\", 0))", Boolean, true);
+        eval!(
+            "fn is_digit (c) -> (c >= '0') && (c <= '9') end is_digit ('7')",
+            Boolean,
+            true
+        );
+        eval!("char_code ('A') + char_code ('λ')", Integer, 1020);
+        eval!("char_from_code (97)", Char, 'a');
+        eval!("show ('x') ++ show ('\\n')", Str, "x\n");
+        eval!(
+            "fn count (t) -> if snd (t) == 'l' then fst (t) + 1 else fst (t) end end
+             fold_chars ((count, 0, \"hello\"))",
+            Integer,
+            2
+        );
+        eval!("fold_chars ((fn (t) -> snd (t) end, 'z', \"\"))", Char, 'z');
+        evalfails!(
+            "char_at ((\"ab\", 2))",
+            "Index 2 is out of range for 2 characters."
+        );
+        evalfails!("char_from_code (-1)", "Invalid character code -1.");
+        evalfails!("char_from_code (55296)", "Invalid character code 55296.");
+        evalfails!("'a' < 1", "Type error: expected char but found integer.");
+        evalfails!(
+            "'a' ++ \"b\"",
+            "Type error: expected string but found char."
+        );
+    }
+
+    #[test]
+    fn string_builtins() {
+        eval!("length (\"héllo\")", Integer, 5);
+        eval!("length (\"\")", Integer, 0);
+        eval!("concat ((\"ab\", \"cd\"))", Str, "abcd");
+        eval!("slice ((\"héllo\", 1, 3))", Str, "él");
+        eval!("slice ((\"abc\", 3, 3))", Str, "");
+        eval!(
+            "split ((\"a,b,,c\", \",\"))",
+            Array,
+            vec![
+                Value::Str("a".to_string()),
+                Value::Str("b".to_string()),
+                Value::Str("".to_string()),
+                Value::Str("c".to_string())
+            ]
+        );
+        eval!("at (split ((\"key=value\", \"=\")), 0)", Str, "key");
+        evalfails!(
+            "fst (split ((\"a\", \",\")))",
+            "Type error: expected (t1, t2) but found [string]."
+        );
+        eval!("contains ((\"hello\", \"ell\"))", Boolean, true);
+        eval!("contains ((\"hello\", \"\"))", Boolean, true);
+        eval!("contains ((\"hello\", \"le\"))", Boolean, false);
+        evalfails!(
+            "slice ((\"abc\", 2, 4))",
+            "Range 2 to 4 is out of range for 3 characters."
+        );
+        evalfails!(
+            "slice ((\"abc\", 2, 1))",
+            "Range 2 to 1 is out of range for 3 characters."
+        );
+        evalfails!(
+            "split ((\"abc\", \"\"))",
+            "Cannot split at an empty separator."
+        );
+        evalfails!(
+            "length (b\"abc\")",
+            "Type error: expected string or array but found bytes."
+        );
+    }
+
+    #[test]
+    fn case_conversion() {
+        eval!("to_upper (\"straße\")", Str, "STRASSE");
+        eval!("to_lower (\"İSTANBUL\")", Str, "i\u{307}stanbul");
+        eval!("to_lower (\"ΟΔΟΣ\")", Str, "οδος");
+        eval!(
+            "to_lower (\"Hello\") == to_lower (\"hELLO\")",
+            Boolean,
+            true
+        );
+        if cfg!(feature = "normalization") {
+            eval!("nfc (\"e\u{301}\")", Str, "\u{e9}");
+            eval!("nfd (\"\u{e9}\")", Str, "e\u{301}");
+            eval!(
+                "nfc (\"caf\u{e9}\") == nfc (\"cafe\u{301}\") |> show",
+                Str,
+                "true"
+            );
+        } else {
+            evalfails!("nfc (\"a\")", "Unknown identifier: nfc.");
+        }
+    }
+
+    #[test]
+    fn unicode_strings() {
+        eval!("chars (\"né\") == ['n', 'é']", Boolean, true);
+        eval!(
+            "let codes = for c in chars (\"日本\") do char_code (c) end in codes == [26085, 26412]",
+            Boolean,
+            true
+        );
+        eval!("(length (\"日本\"), byte_length (\"日本\")).1", Integer, 6);
+        eval!("byte_slice ((\"日本語\", 3, 9))", Str, "本語");
+        eval!("byte_slice ((\"abc\", 3, 3))", Str, "");
+        evalfails!(
+            "byte_slice ((\"日本\", 1, 3))",
+            "Range 1 to 3 is out of range for 6 bytes or splits a character."
+        );
+        evalfails!(
+            "byte_slice ((\"abc\", 1, 4))",
+            "Range 1 to 4 is out of range for 3 bytes or splits a character."
+        );
+        if cfg!(feature = "graphemes") {
+            eval!("graphemes (\"e\u{301}👍🏽!\") |> length", Integer, 3);
+        } else {
+            evalfails!("graphemes (\"a\")", "Unknown identifier: graphemes.");
+        }
+    }
+
+    #[test]
+    fn csv() {
+        if cfg!(feature = "csv") {
+            eval!(
+                "csv_parse (\"name,age\\nAda,36\\n\") == [[\"name\", \"age\"], [\"Ada\", \"36\"]]",
+                Boolean,
+                true
+            );
+            eval!(
+                "csv_string ([[\"a, b\", \"say \\\"hi\\\"\"], [\"1\"]])",
+                Str,
+                "\"a, b\",\"say \"\"hi\"\"\"\n1\n"
+            );
+            eval!(
+                "def rows := [[\"x\", \"\"], [\"\"], [\"line\\nbreak\"]]
+                 csv_parse (csv_string (rows)) == rows",
+                Boolean,
+                true
+            );
+            evalfails!(
+                "csv_parse (\"a,\\\"b\")",
+                "Unterminated quoted field in CSV at line 1."
+            );
+        } else {
+            evalfails!("csv_parse (\"a\")", "Unknown identifier: csv_parse.");
+        }
+    }
+
+    #[test]
+    fn regex() {
+        if cfg!(feature = "regex") {
+            eval!(
+                "regex_match (\"^\\\\d{3}-\\\\d{4}$\", \"555-1234\")",
+                Boolean,
+                true
+            );
+            eval!("regex_match (\"b+\", \"aaa\")", Boolean, false);
+            eval!(
+                "match regex_captures (\"(\\\\w+)@(\\\\w+)\", \"mail ada@example now\") with
+                     Some (groups) -> at (groups, 2)
+                     | None -> \"\"
+                 end",
+                Str,
+                "example"
+            );
+            eval!("regex_captures (\"x\", \"abc\") == None", Boolean, true);
+            eval!(
+                "regex_replace (\"(\\\\w+) (\\\\w+)\", \"hello world\", \"$2 $1\")",
+                Str,
+                "world hello"
+            );
+            evalfails!(
+                "regex_match (\"(a\", \"a\")",
+                "Invalid regex \"(a\": unclosed group."
+            );
+        } else {
+            evalfails!(
+                "regex_match (\"a\", \"a\")",
+                "Unknown identifier: regex_match."
+            );
+        }
+    }
+
+    #[test]
+    fn arrays() {
+        eval!("at ([10, 20, 30], 1)", Integer, 20);
+        eval!("length ([1, 2, 3]) + length (\"ab\")", Integer, 5);
+        eval!("length ([])", Integer, 0);
+        eval!("at (at ([[1], [2, 3]], 1), 0)", Integer, 2);
+        eval!(
+            "def xs := [1, 2]
+             def ys := push (xs, 3)
+             (length (xs), length (ys), at (ys, 2))",
+            Tuple,
+            Value::Integer(2),
+            Value::Integer(3),
+            Value::Integer(3)
+        );
+        eval!("at (concat ([\"a\"], [\"b\", \"c\"]), 2)", Str, "c");
+        eval!(
+            "fn sum (xs, i) -> if i == length (xs) then 0 else at (xs, i) + sum (xs, i + 1) end end
+             sum ([1, 2, 3, 4], 0)",
+            Integer,
+            10
+        );
+        eval!("[1, 2] == [1, 2]", Boolean, true);
+        eval!("try at ([1, 2], 2) catch e -> 0 end", Integer, 0);
+        evalfails!("at ([1, 2], 2)", "Index 2 is out of range for 2 elements.");
+        evalfails!(
+            "at ([1, 2], -1)",
+            "Index -1 is out of range for 2 elements."
+        );
+        evalfails!(
+            "[1, true]",
+            "Type error: expected integer but found boolean."
+        );
+        evalfails!(
+            "at (push ([1], 2), 0) ++ \"x\"",
+            "Type error: expected string but found integer."
+        );
+
+        for (src, typ) in [
+            ("at ([\"a\"], 0)", "string"),
+            ("push ([1.5], 2.5)", "[float]"),
+            ("concat ([1], [2])", "[integer]"),
+            ("concat (\"a\", \"b\")", "string"),
+            ("fn (s) -> length (s) end", "string -> integer"),
+            (
+                "fn (xs) -> length (xs) + at (xs, 0) end",
+                "[integer] -> integer",
+            ),
+        ] {
+            let ast = parser::parse(src).ok().unwrap();
+            let found = codegen::compile(&mut vm::VirtualMachine::new(), &ast);
+            assert_eq!(found.ok().unwrap().to_string(), typ);
+        }
+        evalfails!(
+            "length (1)",
+            "Type error: expected string or array but found integer."
+        );
+
+        let mut vm = vm::VirtualMachine::new();
+        let ast = parser::parse("[[1], [2, 3], []]").ok().unwrap();
+        let value = codegen::eval(&mut vm, &ast).ok().unwrap();
+        assert_eq!(value.to_string(), "[[1], [2, 3], []]");
+    }
+
+    #[test]
+    fn maps() {
+        eval!("lookup (%{\"a\" => 1, \"b\" => 2}, \"b\")", Integer, 2);
+        eval!("lookup (%{1 => 2, 1 => 3}, 1)", Integer, 3);
+        eval!(
+            "def m := insert (%{}, (1, \"x\"), true)
+             (has_key (m, (1, \"x\")), has_key (remove (m, (1, \"x\")), (1, \"x\")))",
+            Tuple,
+            Value::Boolean(true),
+            Value::Boolean(false)
+        );
+        eval!(
+            "keys (insert (insert (%{3 => 0, 1 => 0}, 2, 0), 3, 1))",
+            Array,
+            vec![Value::Integer(3), Value::Integer(1), Value::Integer(2)]
+        );
+        eval!("%{1 => 2, 3 => 4} == %{3 => 4, 1 => 2}", Boolean, true);
+        eval!("%{1 => 2} == insert (%{1 => 2}, 1, 5)", Boolean, false);
+        eval!(
+            "hash (%{1 => 2, 3 => 4}) == hash (insert (%{3 => 4}, 1, 2))",
+            Boolean,
+            true
+        );
+        eval!("try lookup (%{}, 1) catch e -> 0 end", Integer, 0);
+        evalfails!("lookup (%{1 => 2}, 3)", "Key 3 is not in the map.");
+        evalfails!(
+            "%{1 => \"a\", 2 => 3}",
+            "Type error: expected string but found integer."
+        );
+        evalfails!(
+            "%{1.5 => 1}",
+            "Type error: expected a hashable type but found float."
+        );
+        evalfails!(
+            "insert (%{}, fn (x) -> x + 1 end, 1)",
+            "Type error: expected a hashable type but found integer -> integer."
+        );
+        evalfails!(
+            "fn (k) -> remove (%{}, k) end (2.5)",
+            "Type error: expected a hashable type but found float."
+        );
+
+        for (src, typ) in [
+            ("insert (%{}, \"a\", 1.5)", "%{string => float}"),
+            ("keys (%{'a' => 1})", "[char]"),
+            (
+                "fn (m, k) -> lookup (m, k) + 1 end",
+                "(%{t2 => integer}, t2) -> integer",
+            ),
+        ] {
+            let ast = parser::parse(src).ok().unwrap();
+            let found = codegen::compile(&mut vm::VirtualMachine::new(), &ast);
+            assert_eq!(found.ok().unwrap().to_string(), typ);
+        }
+
+        let mut vm = vm::VirtualMachine::new();
+        let ast = parser::parse("%{\"a\" => [1], \"b\" => []}").ok().unwrap();
+        let value = codegen::eval(&mut vm, &ast).ok().unwrap();
+        assert_eq!(value.to_string(), "%{\"a\" => [1], \"b\" => []}");
+    }
+
+    #[test]
+    fn lists() {
+        eval!("head (1 :: 2 :: %[])", Integer, 1);
+        eval!("head (tail (%[1, 2, 3]))", Integer, 2);
+        eval!("1 :: %[2] == %[1, 2]", Boolean, true);
+        eval!("%[1] == %[1, 2]", Boolean, false);
+        eval!(
+            "fn sum (l) ->
+                 match l with
+                   x :: xs -> x + sum (xs)
+                 | %[] -> 0
+                 end
+             end
+             sum (%[1, 2, 3, 4])",
+            Integer,
+            10
+        );
+        eval!("match %[] with h :: t -> 1 | _ -> 2 end", Integer, 2);
+        eval!("try head (%[]) catch e -> 0 end", Integer, 0);
+        evalfails!("head (%[])", "Head of an empty list.");
+        evalfails!("tail (tail (%[1]))", "Tail of an empty list.");
+        evalfails!(
+            "1 :: 2",
+            "Type error: expected integer list but found integer."
+        );
+        evalfails!(
+            "match %[1] with x :: xs -> x end",
+            "Missing variants in match of list: %[]."
+        );
+
+        for (src, typ) in [
+            ("tail (%[\"a\"])", "string list"),
+            ("fn (l) -> head (l) + 1 end", "integer list -> integer"),
+            ("%[fn (x) -> x end]", "(t2 -> t2) list"),
+        ] {
+            let ast = parser::parse(src).ok().unwrap();
+            let found = codegen::compile(&mut vm::VirtualMachine::new(), &ast);
+            assert_eq!(found.ok().unwrap().to_string(), typ);
+        }
+
+        let mut vm = vm::VirtualMachine::new();
+        let ast = parser::parse("%[%[1], %[]] :: %[]").ok().unwrap();
+        let value = codegen::eval(&mut vm, &ast).ok().unwrap();
+        assert_eq!(value.to_string(), "%[%[%[1], %[]]]");
+    }
+
+    #[test]
+    fn ranges() {
+        eval!("length (0..5)", Integer, 5);
+        eval!("length (3..1)", Integer, 0);
+        eval!("at (-2..2, 0)", Integer, -2);
+        eval!("1..3 == [1, 2]", Boolean, true);
+        eval!("at (for x in [1.5] do x + x end, 0)", Float, 3.0);
+        eval!(
+            "let squares = for x in 1..5 do x * x end in at (squares, 3)",
+            Integer,
+            16
+        );
+        eval!(
+            "let n = 10 in
+             let sums = for (a, b) in [(1, 2), (3, 4)] do a + b + n end in
+             at (sums, 0) + at (sums, 1)",
+            Integer,
+            30
+        );
+        eval!(
+            "fn total (xs) ->
+                 let rows = for i in 0..length (xs) do at (xs, i) * i end in
+                 at (rows, length (rows) - 1)
+             end
+             total ([4, 5, 6])",
+            Integer,
+            12
+        );
+        eval!(
+            "length (for i in 0..3 do for j in i..3 do (i, j) end end)",
+            Integer,
+            3
+        );
+        eval!(
+            "let r = try for x in 0..3 do 6 / (x - 1) end catch e -> [0] end in r == [0]",
+            Boolean,
+            true
+        );
+        evalfails!(
+            "for x in [1, 2] do x ++ \"a\" end",
+            "Type error: expected string but found integer."
+        );
+    }
+
+    #[test]
+    fn while_loops() {
+        eval!(
+            "def i := 0
+             def total := 0
+             while i < 5 do
+                 def total := total + i
+                 def i := i + 1
+             end
+             total",
+            Integer,
+            10
+        );
+        eval!("while false do 1 end", Unit);
+        eval!(
+            "fn countdown (n) ->
+                 def steps := []
+                 while n > 0 do
+                     def steps := push (steps, n)
+                     def n := n - 1
+                 end
+                 (steps, n)
+             end
+             countdown (3) == ([3, 2, 1], 0)",
+            Boolean,
+            true
+        );
+        eval!("fn f (n) -> def n := n + 1 n end f (1)", Integer, 2);
+        eval!("def y := do def x := 2 x * x end y + x", Integer, 6);
+        evalfails!(
+            "while 1 do 2 end",
+            "Type error: expected boolean but found integer."
+        );
+        evalfails!(
+            "def x := 1 while x < 2 do def x := \"a\" end",
+            "Type error: expected integer but found string."
+        );
+
+        let mut vm = vm::VirtualMachine::new();
+        vm.set_fuel(1000);
+        let ast = parser::parse("while true do 1 end").unwrap();
+        let err = codegen::eval(&mut vm, &ast).unwrap_err();
+        assert_eq!(err.err, "Out of fuel.");
+    }
+
+    #[test]
+    fn sequences() {
+        eval!("print (1); 2", Integer, 2);
+        eval!("def x := 1; def y := x + 1; x + y", Integer, 3);
+        eval!("fn f (x) -> x + 1 end; f (1)", Integer, 2);
+        eval!(
+            "fn f (n) -> print (n); n * 2 end
+             print (f (1)); f (2)",
+            Integer,
+            4
+        );
+        evalfails!("1; 2", "Type error: expected unit but found integer.");
+        evalfails!(
+            "def xs := [1]; push (xs, 2); xs",
+            "Type error: expected unit but found [integer]."
+        );
+    }
+
+    #[test]
+    fn match_guards() {
+        let sign = "type Option := Some (integer) | None end
+             fn sign (o) ->
+                 match o with
+                     Some (x) when x > 0 -> 1
+                     | Some (x) when x < 0 -> -1
+                     | Some (x) -> 0
+                     | None -> 2
+                 end
+             end
+            ";
+        eval!(&format!("{} sign (Some (5))", sign), Integer, 1);
+        eval!(&format!("{} sign (Some (-5))", sign), Integer, -1);
+        eval!(&format!("{} sign (Some (0))", sign), Integer, 0);
+        eval!(&format!("{} sign (None)", sign), Integer, 2);
+        eval!(
+            "def limit := 3
+             fn small (l) ->
+                 match l with
+                     %[] when limit > 5 -> 1
+                     | x :: xs when x < limit -> 2
+                     | _ when limit == 3 -> 3
+                     | _ -> 4
+                 end
+             end
+             small (%[]) * 100 + small (%[1]) * 10 + small (%[4])",
+            Integer,
+            323
+        );
+        eval!(
+            "fn equal_to (n) -> fn (l) -> match l with x :: xs when x == n -> 1 | _ -> 0 end end end
+             def two := equal_to (2)
+             two (%[2]) * 10 + two (%[3])",
+            Integer,
+            10
+        );
+    }
+
+    #[test]
+    fn higher_order() {
+        eval!(
+            "map ((fn (x) -> x * 2 end, [1, 2, 3])) == [2, 4, 6]",
+            Boolean,
+            true
+        );
+        eval!("at (map ((show, [1, 2])), 1)", Str, "2");
+        eval!(
+            "filter ((fn (x) -> x > 2 end, 0..6)) == [3, 4, 5]",
+            Boolean,
+            true
+        );
+        eval!(
+            "foldl ((fn (acc, x) -> acc - x end, 0, [1, 2, 3]))",
+            Integer,
+            -6
+        );
+        eval!(
+            "foldr ((fn (x, acc) -> x - acc end, 0, [1, 2, 3]))",
+            Integer,
+            2
+        );
+        eval!(
+            "foldl ((fn (acc, x) -> acc ++ x end, \"\", map ((show, [1, 2, 3]))))",
+            Str,
+            "123"
+        );
+        eval!(
+            "let n = 10 in
+             let rows = map ((fn (x) -> map ((fn (y) -> x * y + n end, [1, 2])) end, [1, 2])) in
+             foldr ((fn (row, acc) -> foldl ((fn (a, b) -> a + b end, acc, row)) end, 0, rows))",
+            Integer,
+            49
+        );
+        eval!(
+            "try length (map ((fn (x) -> 6 / x end, [1, 0]))) catch e -> -1 end",
+            Integer,
+            -1
+        );
+        eval!(
+            "length (filter ((fn (x) -> x end, []))) + foldl ((fn (a, b) -> a end, 7, []))",
+            Integer,
+            7
+        );
+        evalfails!(
+            "filter ((fn (x) -> x + 1 end, [1]))",
+            "Type error: expected (t5 -> boolean, [t5]) but found (integer -> integer, [integer])."
+        );
+        eval!(
+            "fn twice (f, x) -> f (f (x)) end
+             twice (fn (x) -> x * 3 end, 2)",
+            Integer,
+            18
+        );
+
+        // A function returned by a function defined in an earlier program
+        // can be called once its type is known.
+        let mut vm = vm::VirtualMachine::new();
+        let ast = parser::parse("fn adder (n) -> fn (x) -> x + n end end def add := adder (1)")
+            .ok()
+            .unwrap();
+        assert!(codegen::eval(&mut vm, &ast).is_ok());
+        let ast = parser::parse("add (41)").ok().unwrap();
+        assert_eq!(codegen::eval(&mut vm, &ast).ok(), Some(Value::Integer(42)));
+    }
+
+    #[test]
+    fn defers() {
+        eval!(
+            "def me := self ()
+             fn f (n) ->
+                 defer send (me, n)
+                 defer send (me, n + 1)
+                 n * 10
+             end
+             def r := f (1)
+             (r, receive (), receive ())",
+            Tuple,
+            Value::Integer(10),
+            Value::Integer(2),
+            Value::Integer(1)
+        );
+        // The deferred send runs once the tail call has returned.
+        eval!(
+            "def me := self ()
+             fn g (n) -> send (me, n) end
+             fn f (n) ->
+                 defer send (me, 0)
+                 let m = n + 1 in g (m)
+             end
+             def r := f (1)
+             (receive (), receive ())",
+            Tuple,
+            Value::Integer(2),
+            Value::Integer(0)
+        );
+        eval!(
+            "def me := self ()
+             fn f (n) ->
+                 defer send (me, n)
+                 100 / n
+             end
+             def r := try f (0) catch e -> 0 end
+             (r, receive ())",
+            Tuple,
+            Value::Integer(0),
+            Value::Integer(0)
+        );
+        eval!(
+            "def me := self ()
+             fn f (o) ->
+                 defer send (me, 1)
+                 let v = o? in Some (v)
+             end
+             def r := f (None)
+             receive ()",
+            Integer,
+            1
+        );
+        eval!(
+            "def me := self ()
+             fn f (n) ->
+                 let d = defer send (me, n) in
+                 n + 1
+             end
+             def r := f (1) * f (2)
+             (r, receive (), receive ())",
+            Tuple,
+            Value::Integer(6),
+            Value::Integer(2),
+            Value::Integer(1)
+        );
+        eval!(
+            "fn f () -> defer abort (\"second\") abort (\"first\") end
+             try f () catch e -> e end",
+            Str,
+            "second"
+        );
+        eval!(
+            "def me := self ()
+             defer send (me, 2)
+             def a := send (me, 1)
+             receive ()",
+            Integer,
+            1
+        );
+        evalfails!("fn f () -> defer (1 / 0) 1 end f ()", "Division by zero.");
+    }
+
+    #[test]
+    fn encodings() {
+        eval!("hex_encode (b\"\\x00\\xffa\")", Str, "00ff61");
+        eval!("hex_decode (\"00FF61\")", Bytes, vec![0, 255, 97]);
+        eval!(
+            "base64_encode (string_to_bytes (\"hello\"))",
+            Str,
+            "aGVsbG8="
+        );
+        eval!(
+            "bytes_to_string (base64_decode (\"aGVsbG8=\"))",
+            Str,
+            "hello"
+        );
+        evalfails!("hex_decode (\"0x10\")", "Invalid hex at offset 1.");
+        evalfails!("base64_decode (\"aGVsbG8\")", "Invalid base64 at offset 4.");
+    }
+
+    #[test]
+    fn times() {
+        eval!(
+            "parse_time (\"2000-03-01 12:00\", \"%Y-%m-%d %H:%M\")",
+            Timestamp,
+            951_912_000_000
+        );
+        eval!(
+            "def t := parse_time (\"2000-02-28\", \"%Y-%m-%d\")
+             format_time (add_time (t, 36 * 60 * 60 * 1000), \"%Y-%m-%d %H:%M\")",
+            Str,
+            "2000-02-29 12:00"
+        );
+        eval!(
+            "def t := parse_time (\"1700000000\", \"%s\")
+             time_diff (t, add_time (t, 1500))",
+            Integer,
+            -1500
+        );
+        evalfails!(
+            "parse_time (\"12:60\", \"%H:%M\")",
+            "Can not parse \"12:60\" as a time in format \"%H:%M\"."
+        );
+        evalfails!(
+            "format_time (parse_time (\"0\", \"%s\"), \"%Y %Q\")",
+            "Unknown directive %Q in time format."
+        );
+
+        let mut vm = vm::VirtualMachine::new();
+        let ast = parser::parse("time_diff (now (), now ()) <= 0")
+            .ok()
+            .unwrap();
+        assert_eq!(
+            codegen::eval(&mut vm, &ast).err().map(|err| err.err),
+            Some("Unknown identifier: now.".to_string())
+        );
+
+        vm.enable_time();
+        assert_eq!(
+            codegen::eval(&mut vm, &ast).ok(),
+            Some(Value::Boolean(true))
+        );
+
+        vm.allowed_effects.remove(&crate::effects::Effect::Time);
+        assert_eq!(
+            codegen::eval(&mut vm, &ast).err().map(|err| err.err),
+            Some("Effect not permitted: time.".to_string())
+        );
+    }
+
+    #[test]
+    fn commands() {
+        let mut vm = vm::VirtualMachine::new();
+        let ast = parser::parse("run_command (\"true\", [])").ok().unwrap();
+        assert_eq!(
+            codegen::eval(&mut vm, &ast).err().map(|err| err.err),
+            Some("Unknown identifier: run_command.".to_string())
+        );
+
+        vm.enable_process();
+        let ast = parser::parse(
+            "def r := run_command (\"sh\", [\"-c\", \"echo $0; echo e >&2; exit 3\", \"o\"])
+             (r, run_command (\"echo\", [\"hi\"]))",
+        )
+        .ok()
+        .unwrap();
+        assert_eq!(
+            codegen::eval(&mut vm, &ast)
+                .ok()
+                .map(|value| value.to_string()),
+            Some("((3, \"o\\n\", \"e\\n\"), (0, \"hi\\n\", \"\"))".to_string())
+        );
+        for (program, err) in [
+            (
+                "run_command (\"echo\", \"hi\")",
+                "Type error: expected (string, [string]) but found (string, string).",
+            ),
+            (
+                "run_command (\"echo\", (\"a\", \"b\"))",
+                "Type error: expected (string, [string]) but found (string, (string, string)).",
+            ),
+        ] {
+            let ast = parser::parse(program).ok().unwrap();
+            assert_eq!(
+                codegen::eval(&mut vm, &ast).err().map(|err| err.err),
+                Some(err.to_string())
+            );
+            vm.reset();
+        }
+        let ast = parser::parse("run_command (\"/nonexistent/command\", [])")
+            .ok()
+            .unwrap();
+        let err = codegen::eval(&mut vm, &ast).err().unwrap();
+        assert!(err.err.starts_with("Can not run /nonexistent/command: "));
+        vm.reset();
+        vm.allowed_effects.remove(&crate::effects::Effect::Process);
+        assert_eq!(
+            codegen::eval(&mut vm, &ast).err().map(|err| err.err),
+            Some("Effect not permitted: process.".to_string())
+        );
+    }
+
+    #[test]
+    fn http() {
+        use std::io::{Read, Write};
+        use std::net::TcpListener;
+        use std::time::Duration;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let mut requests = Vec::new();
+            for response in [
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n\r\nhello",
+                "HTTP/1.1 201 Created\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nok\r\n0\r\n\r\n",
+            ] {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") && !request.ends_with(b"{}") {
+                    let n = stream.read(&mut buf).unwrap();
+                    request.extend(&buf[..n]);
+                }
+                requests.push(String::from_utf8(request).unwrap());
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+            // Accepts a connection without answering it.
+            let _stream = listener.accept().unwrap();
+            std::thread::sleep(Duration::from_millis(500));
+            requests
+        });
+
+        let mut vm = vm::VirtualMachine::new();
+        let ast = parser::parse("http_get (\"http://localhost/\")")
+            .ok()
+            .unwrap();
+        assert_eq!(
+            codegen::eval(&mut vm, &ast).err().map(|err| err.err),
+            Some("Unknown identifier: http_get.".to_string())
+        );
+
+        vm.enable_net();
+        vm.net_timeout = Duration::from_millis(100);
+        let url = format!("http://127.0.0.1:{}/a?b=1", port);
+        let program = format!(
+            "def r := http_get ({:?})
+             def p := http_post (({:?}, \"application/json\", \"{{}}\"))
+             (r.status, r.headers, r.body, p.status, p.body)",
+            url, url
+        );
+        let ast = parser::parse(&program).ok().unwrap();
+        assert_eq!(
+            codegen::eval(&mut vm, &ast)
+                .ok()
+                .map(|value| value.to_string()),
+            Some("(200, \"content-type: text/plain\", \"hello\", 201, \"ok\")".to_string())
+        );
+        // The deadline limits a request that would otherwise be given longer.
+        vm.net_timeout = Duration::from_secs(30);
+        vm.deadline = Some(std::time::Instant::now() + Duration::from_millis(100));
+        let ast = parser::parse(&format!("http_get ({:?})", url))
+            .ok()
+            .unwrap();
+        let err = codegen::eval(&mut vm, &ast).err().unwrap();
+        vm.deadline = None;
+        assert_eq!(err.err, format!("Request to {} timed out.", url));
+        assert!(matches!(
+            err.kind,
+            codegen::ErrorKind::Runtime {
+                kind: codegen::RuntimeError::Timeout,
+                ..
+            }
+        ));
+        vm.reset();
+
+        let requests = server.join().unwrap();
+        assert!(requests[0].starts_with("GET /a?b=1 HTTP/1.1\r\n"));
+        assert!(requests[1].starts_with("POST /a?b=1 HTTP/1.1\r\n"));
+        assert!(requests[1].contains("\r\nContent-Type: application/json\r\n"));
+        assert!(requests[1].ends_with("\r\n\r\n{}"));
+
+        let ast = parser::parse("http_get (\"https://example.com/\")")
+            .ok()
+            .unwrap();
+        assert_eq!(
+            codegen::eval(&mut vm, &ast).err().map(|err| err.err),
+            Some(
+                "Request to https://example.com/ failed: Only http URLs are supported.".to_string()
+            )
+        );
+        vm.reset();
+        vm.allowed_effects.remove(&crate::effects::Effect::Net);
+        assert_eq!(
+            codegen::eval(&mut vm, &ast).err().map(|err| err.err),
+            Some("Effect not permitted: net.".to_string())
+        );
+    }
+
+    #[test]
+    fn hashes() {
+        // These are fixed by the documented algorithm, so they must not
+        // change between versions or platforms.
+        eval!("hash (1)", Integer, -1306494776482804796);
+        eval!("hash (\"abc\")", Integer, 3937386813790840848);
+        eval!("hash ((1, \"a\", 2.5))", Integer, -8231501813090480037);
+        eval!("hash (-0.0) == hash (0.0)", Boolean, true);
+        eval!("hash ((1, 2)) == hash ((2, 1))", Boolean, false);
+        eval!(
+            "type T := A (x) | B end hash (A (1)) == hash (A (1))",
+            Boolean,
+            true
+        );
+        let mut vm = vm::VirtualMachine::new();
+        let ast = parser::parse("hash ({ a = 1, b = (2, fn x -> x end) })")
+            .ok()
+            .unwrap();
+        let err = codegen::eval(&mut vm, &ast).err().unwrap();
+        assert_eq!(err.err, "Can not hash a function.");
+        evalfails!("fn f (x) -> x end hash ([f])", "Can not hash function f.");
+        evalfails!(
+            "fn f (x) -> x end hash (%{1 => memo (f)})",
+            "Can not hash function f."
+        );
+    }
+
+    #[test]
+    fn prelude() {
+        eval!("show (42)", Str, "42");
+        eval!("show ((1, 2.5, true))", Str, "(1, 2.5, true)");
+        eval!("show (\"text\")", Str, "text");
+        eval!("show ({ a = 1 }) ++ \"!\"", Str, "{a = 1}!");
+        eval!("abs (-7) + abs (7)", Integer, 14);
+        eval!("min ((3, -2))", Integer, -2);
+        eval!("max ((3, -2))", Integer, 3);
+        eval!("fst ((1, \"a\")) + 1", Integer, 2);
+        eval!("snd ((1, \"a\")) ++ \"b\"", Str, "ab");
+        eval!("fst (snd ((1, (true, 2))))", Boolean, true);
+        eval!("print (show (max ((1, 2))))", Unit);
+        evalfails!("abs (1.5)", "Type error: expected integer but found float.");
+        evalfails!(
+            "min ((1, true))",
+            "Type error: expected (integer, integer) but found (integer, boolean)."
+        );
+        evalfails!(
+            "fst ((1, 2, 3))",
+            "Type error: expected (t1, t2) but found (integer, integer, integer)."
+        );
+        evalfails!(
+            "snd (1)",
+            "Type error: expected (t1, t2) but found integer."
+        );
+        eval!(
+            "fn id (x) -> x end
+             let p = (id (1), id (true)) in (fst (p), snd (p))",
+            Tuple,
+            Value::Integer(1),
+            Value::Boolean(true)
+        );
+    }
+
+    #[test]
+    fn parser_combinators() {
+        eval!("show (digit (\"7up\"))", Str, "Some (7, \"up\")");
+        eval!("digit (\"up\") == None", Boolean, true);
+        eval!("digit (\"\") == None", Boolean, true);
+        eval!("def p := char ('a')\np (\"b\") == None", Boolean, true);
+        eval!(
+            "def number := many (digit)
+             show (number (\"123x\"))",
+            Str,
+            "Some ([1, 2, 3], \"x\")"
+        );
+        eval!(
+            "def sign := seq (char ('-'), digit)
+             show (sign (\"-4\")) ++ show (sign (\"4\"))",
+            Str,
+            "Some (('-', 4), \"\")None"
+        );
+        eval!(
+            "def p := many (char ('a'))\nshow (p (\"\"))",
+            Str,
+            "Some ([], \"\")"
+        );
+        eval!(
+            "fn apply (f) -> f (1) end
+             apply (fn (x) -> x + 1 end)",
+            Integer,
+            2
+        );
+        eval!("fn digit (x) -> x * 2 end digit (4)", Integer, 8);
+        evalfails!(
+            "many (5)",
+            "Type error: expected string -> Option but found integer."
+        );
+        evalfails!(
+            "char (\"a\")",
+            "Type error: expected char but found string."
+        );
+    }
+
+    #[test]
+    fn native_functions() {
+        use crate::codegen::{ErrorKind, RuntimeError};
+        use crate::typeinfer::Type;
+
+        let mut vm = vm::VirtualMachine::new();
+        vm.register(
+            "sqrt",
+            Type::Function(Box::new(Type::Float), Box::new(Type::Float)),
+            |arg| match arg {
+                Value::Float(x) if x >= 0.0 => Ok(Value::Float(x.sqrt())),
+                value => Err(format!("Can not take the square root of {}.", value)),
+            },
+        );
+        vm.register(
+            "hypot",
+            Type::Function(
+                Box::new(Type::Tuple(vec![Type::Float, Type::Float])),
+                Box::new(Type::Float),
+            ),
+            |arg| match arg {
+                Value::Tuple(args) => match args.as_slice() {
+                    [Value::Float(x), Value::Float(y)] => Ok(Value::Float(x.hypot(*y))),
+                    _ => Err("Expected two floats.".to_string()),
+                },
+                _ => Err("Expected two floats.".to_string()),
+            },
+        );
+        let mut eval = |src: &str| codegen::eval(&mut vm, &parser::parse(src).ok().unwrap());
+
+        assert_eq!(eval("sqrt (16.0)").ok(), Some(Value::Float(4.0)));
+        assert_eq!(eval("hypot (3.0, 4.0)").ok(), Some(Value::Float(5.0)));
+        assert_eq!(
+            eval("fn f (x) -> sqrt (x + 1.0) end f (8.0)").ok(),
+            Some(Value::Float(3.0))
+        );
+        assert_eq!(
+            eval("par_map (sqrt, [1.0, 4.0])").ok(),
+            Some(Value::Array(vec![Value::Float(1.0), Value::Float(2.0)]))
+        );
+        assert_eq!(
+            eval("sort_by (fn (x, y) -> sqrt (x) > sqrt (y) end, [1.0, 9.0, 4.0])").ok(),
+            Some(Value::Array(vec![
+                Value::Float(9.0),
+                Value::Float(4.0),
+                Value::Float(1.0)
+            ]))
+        );
+        let err = eval("sqrt (1)").err().unwrap();
+        assert!(matches!(err.kind, ErrorKind::Type { .. }));
+        let err = eval("1.0 + sqrt (0.0 - 1.0)").err().unwrap();
+        assert_eq!(err.err, "Can not take the square root of -1.0.");
+        assert!(matches!(
+            err.kind,
+            ErrorKind::Runtime {
+                kind: RuntimeError::NativeError,
+                ..
+            }
+        ));
+        assert_eq!(
+            eval("sqrt").ok().map(|v| v.to_string()),
+            Some("(native sqrt)".to_string())
+        );
+
+        let sqrt = vm.symbol("sqrt");
+        let sqrt = vm.env.values[&sqrt].clone();
+        assert_eq!(
+            vm.call(sqrt, Value::Float(2.25)).ok(),
+            Some(Value::Float(1.5))
+        );
+    }
+
+    #[test]
+    fn frozen_globals() {
+        use crate::codegen::{ErrorKind, RuntimeError};
+        use crate::typeinfer::Type;
+
+        let mut vm = vm::VirtualMachine::new();
+        vm.global("limit", Type::Integer, Value::Integer(10));
+        vm.global("name", Type::String, Value::Str("plover".to_string()));
+        vm.register(
+            "double",
+            Type::Function(Box::new(Type::Integer), Box::new(Type::Integer)),
+            |arg| match arg {
+                Value::Integer(n) => Ok(Value::Integer(n * 2)),
+                _ => Err("Expected an integer.".to_string()),
+            },
+        );
+        vm.freeze("limit");
+        vm.freeze("double");
+        let mut eval = |src: &str| codegen::eval(&mut vm, &parser::parse(src).ok().unwrap());
+
+        assert_eq!(eval("double (limit)").ok(), Some(Value::Integer(20)));
+        assert_eq!(
+            eval("let limit = 1 in limit + 1").ok(),
+            Some(Value::Integer(2))
+        );
+        assert_eq!(
+            eval("fn f (limit) -> limit end f (3)").ok(),
+            Some(Value::Integer(3))
+        );
+        let err = eval("def limit := 100").err().unwrap();
+        assert_eq!(
+            err.err,
+            "Can not redefine limit, which the host has frozen."
+        );
+        assert!(matches!(
+            err.kind,
+            ErrorKind::Runtime {
+                kind: RuntimeError::Frozen,
+                ..
+            }
+        ));
+        assert!(eval("fn double (x) -> x end").is_err());
+        assert_eq!(eval("double (limit)").ok(), Some(Value::Integer(20)));
+        assert_eq!(
+            eval("def name := \"other\" name").ok(),
+            Some(Value::Str("other".to_string()))
+        );
     }
-}
 
-fn to_typed_value(vm: &mut vm::VirtualMachine, typ: &Type) -> Option<vm::Value> {
-    match typ {
-        Type::Tuple(types) => {
-            let mut values = Vec::new();
-            for i in 0..types.len() {
-                match to_typed_value(vm, &types[types.len() - i - 1]) {
-                    Some(value) => {
-                        values.push(value);
-                    }
-                    None => {
-                        return None;
-                    }
-                }
-            }
-            Some(vm::Value::Tuple(values))
-        }
-        _ => match vm.stack.pop() {
-            Some(value) => Some(value),
-            None => None,
-        },
+    #[test]
+    fn memo() {
+        eval!(
+            "def fib := memo (fn fib (n) -> if n < 2 then n else fib (n - 1) + fib (n - 2) end end)
+             fib (80)",
+            Integer,
+            23416728348467685
+        );
+        eval!(
+            "def sq := memo (fn (x) -> x * x end)
+             def t := (sq (3), sq (3), sq (4))",
+            Tuple,
+            Value::Integer(9),
+            Value::Integer(9),
+            Value::Integer(16)
+        );
+        eval!(
+            "def first := memo (fn (p) -> p.x end)
+             first ({ x = (1, \"a\"), y = 2.5 })",
+            Tuple,
+            Value::Integer(1),
+            Value::Str("a".to_string())
+        );
+        evalfails!(
+            "def f := memo (fn (x) -> print (x) end)
+             f (1)",
+            "Effect not permitted in memoized function: io."
+        );
+
+        let mut vm = vm::VirtualMachine::new();
+        let ast = parser::parse("def sq := memo (fn (x) -> x * x end) sq (2)")
+            .ok()
+            .unwrap();
+        assert!(codegen::eval(&mut vm, &ast).is_ok());
+        assert_eq!(
+            vm.memos[0].get(&Value::Integer(2)),
+            Some(&Value::Integer(4))
+        );
+        assert!(vm.memo_calls.is_empty());
     }
-}
 
-pub fn eval(vm: &mut vm::VirtualMachine, ast: &parser::AST) -> Result<vm::Value, InterpreterError> {
-    match infer(ast, &mut vm.env.types) {
-        Ok(typed_ast) => {
-            let mut instr = Vec::new();
-            let ids = HashMap::new();
-            generate(&typed_ast, vm, &mut instr, &ids);
-            vm.ip = vm.instructions.len();
-            vm.instructions.extend(instr);
-            // TODO: This is useful for debugging. Add an argument to enable it.
-            //println!("disassembly:");
-            //for i in 0..vm.instructions.len() {
-            //    println!("  {} {}", i, vm.instructions[i]);
-            //}
-            match vm.run() {
-                Ok(()) => match to_typed_value(vm, &type_of(&typed_ast)) {
-                    Some(value) => Ok(value),
-                    None => Err(InterpreterError {
-                        err: "Stack underflow.".to_string(),
-                        line: usize::max_value(),
-                        col: usize::max_value(),
-                    }),
-                },
-                Err(err) => Err(err),
-            }
-        }
-        Err(err) => Err(err),
+    #[test]
+    fn propagation() {
+        let some = |v| Value::Datatype("Option".to_string(), "Some".to_string(), Box::new(v));
+        let none = Value::Datatype(
+            "Option".to_string(),
+            "None".to_string(),
+            Box::new(Value::Unit),
+        );
+        let err = |s: &str| {
+            Value::Datatype(
+                "Result".to_string(),
+                "Err".to_string(),
+                Box::new(Value::Str(s.to_string())),
+            )
+        };
+        eval!(
+            "def half := fn (n) -> if n % 2 == 0 then Some (n / 2) else None end end
+             def quarter := fn (n) -> Some (half (half (n)?)?) end
+             def r := (quarter (8), quarter (6))",
+            Tuple,
+            some(Value::Integer(2)),
+            none.clone()
+        );
+        // A ? in a let body or match case returns from the function.
+        eval!(
+            "def half := fn (n) -> if n % 2 == 0 then Some (n / 2) else None end end
+             def f := fn (n) ->
+                 let m = half (n)? in
+                 match half (m) with
+                     Some (k) -> let j = half (k)? in Some (j + 100)
+                   | None -> Some (0)
+                 end
+             end
+             def r := (f (16), f (4), f (6), f (1))",
+            Tuple,
+            some(Value::Integer(102)),
+            none.clone(),
+            some(Value::Integer(0)),
+            none
+        );
+        eval!(
+            "def parse := fn (s) -> if s == \"\" then Err (\"empty\") else Ok (s) end end
+             def both := fn (a, b) -> Ok ((parse (a)?, parse (b)?)) end
+             def r := (both (\"a\", \"\"), both (\"\", \"b\"))",
+            Tuple,
+            err("empty"),
+            err("empty")
+        );
+        // A let body in tail position does not lose track of the function.
+        eval!(
+            "fn count (n) ->
+                 if n == 0 then Err (\"done\") else let m = Ok (n)? in count (m - 1) end
+             end
+             count (100000)",
+            Datatype,
+            Box::new(Value::Str("done".to_string()))
+        );
+        eval!(
+            "def sq := memo (fn (x) -> Some (x? * x?) end)
+             def t := (sq (Some (3)), sq (None), sq (Some (3)))",
+            Tuple,
+            some(Value::Integer(9)),
+            Value::Datatype(
+                "Option".to_string(),
+                "None".to_string(),
+                Box::new(Value::Unit)
+            ),
+            some(Value::Integer(9))
+        );
+        evalfails!(
+            "Some (1)?",
+            "Type error: ? can only be used inside a function."
+        );
+        evalfails!(
+            "def f := fn (x) -> x? + 1 end f (1)",
+            "Type error: expected Option or Result but found integer."
+        );
+        evalfails!(
+            "def f := fn (x) -> Ok (Some (x)?) end",
+            "Type error: expected Option but found Result."
+        );
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use crate::codegen;
-    use crate::parser;
-    use crate::vm;
-    use crate::vm::Value;
+    #[test]
+    fn try_catch() {
+        eval!(
+            "def safe_div := fn (a, b) -> try a / b catch e -> 0 end end
+             def r := (safe_div (7, 2), safe_div (1, 0))",
+            Tuple,
+            Value::Integer(3),
+            Value::Integer(0)
+        );
+        eval!(
+            "try show (1 % 0) catch e -> e end",
+            Str,
+            "Division by zero.".to_string()
+        );
+        // The stack and the call stack are unwound to where the try began.
+        eval!(
+            "fn deep (n) -> if n == 0 then 1 / 0 else deep (n - 1) + 1 end end
+             def r := (1, try deep (50) catch e -> 2 end, 3)",
+            Tuple,
+            Value::Integer(1),
+            Value::Integer(2),
+            Value::Integer(3)
+        );
+        eval!(
+            "fn forever (n) -> forever (n + 1) + 1 end
+             try show (forever (0)) catch e -> e end",
+            Str,
+            "Stack overflow.".to_string()
+        );
+        eval!(
+            "try try 1 / 0 catch e -> 2 / 0 end catch e -> 7 end",
+            Integer,
+            7
+        );
+        eval!("try 1 catch e -> 2 end", Integer, 1);
+        // A ? inside a try returns from the function, leaving the try.
+        eval!(
+            "def f := fn (x) -> Some (try x? catch e -> 0 end) end
+             def r := (f (None), try 1 / 0 catch e -> 2 end)",
+            Tuple,
+            Value::Datatype(
+                "Option".to_string(),
+                "None".to_string(),
+                Box::new(Value::Unit)
+            ),
+            Value::Integer(2)
+        );
+        // Errors are caught inside and outside of functions called back by
+        // builtins.
+        eval!(
+            "def lt := fn (x, y) -> try x / 0 < y catch e -> x < y end end
+             def xs := sort_by (lt, [3, 1, 2])
+             try sort_by (fn (x, y) -> 1 / 0 < 1 end, [3, 1]) catch e -> xs end",
+            Array,
+            vec![Value::Integer(1), Value::Integer(2), Value::Integer(3)]
+        );
+        evalfails!(
+            "try 1 catch e -> \"a\" end",
+            "Type error: expected integer but found string."
+        );
 
-    macro_rules! eval {
-        ($input:expr, Datatype, $value:expr) => {{
-            let mut vm = vm::VirtualMachine::new();
-            match parser::parse($input) {
-                Ok(ast) => match codegen::eval(&mut vm, &ast) {
-                    Ok(v) => match v {
-                        Value::Datatype(_, _, v) => {
-                            assert_eq!(v, $value);
-                        }
-                        _ => {
-                            assert!(false);
-                        }
-                    },
-                    Err(_) => {
-                        assert!(false);
-                    }
-                },
-                Err(_) => {
-                    assert!(false);
-                }
-            }
-        }};
-        ($input:expr, Tuple, $($value:expr),*) => {{
-            let mut vm = vm::VirtualMachine::new();
-            match parser::parse($input) {
-                Ok(ast) => match codegen::eval(&mut vm, &ast) {
-                    Ok(v) => match v {
-                        Value::Tuple(elements) => {
-                            let mut i = 0;
-                            $(
-                                assert!(i < elements.len());
-                                assert_eq!(elements[i], $value);
-                                i += 1;
-                                assert!(i != 0);  // Silence warning
-                            )*
-                        }
-                        _ => {
-                            assert!(false);
-                        }
-                    },
-                    Err(_) => {
-                        assert!(false);
-                    }
-                },
-                Err(_) => {
-                    assert!(false);
-                }
-            }
-        }};
-        ($input:expr, Unit) => {{
-            let mut vm = vm::VirtualMachine::new();
-            match parser::parse($input) {
-                Ok(ast) => match codegen::eval(&mut vm, &ast) {
-                    Ok(v) => {
-                        assert_eq!(v, Value::Unit);
-                    },
-                    Err(_) => {
-                        assert!(false);
-                    }
-                },
-                Err(_) => {
-                    assert!(false);
-                }
-            }
-        }};
-        ($input:expr, $type:tt, $value:expr) => {{
-            let mut vm = vm::VirtualMachine::new();
-            match parser::parse($input) {
-                Ok(ast) => match codegen::eval(&mut vm, &ast) {
-                    Ok(v) => match v {
-                        Value::$type(t) => {
-                            assert_eq!(t, $value);
-                        }
-                        _ => {
-                            assert!(false);
-                        }
-                    },
-                    Err(_) => {
-                        assert!(false);
-                    }
-                },
-                Err(_) => {
-                    assert!(false);
-                }
-            }
-        }};
+        let mut vm = vm::VirtualMachine::new();
+        vm.set_fuel(1000);
+        let ast =
+            parser::parse("fn forever (n) -> forever (n) end try forever (0) catch e -> 0 end")
+                .ok()
+                .unwrap();
+        let err = codegen::eval(&mut vm, &ast).err().unwrap();
+        assert_eq!(err.err, "Out of fuel.");
     }
 
-    macro_rules! evalfails {
-        ($input:expr, $err:expr) => {{
-            let mut vm = vm::VirtualMachine::new();
-            match parser::parse($input) {
-                Ok(ast) => match codegen::eval(&mut vm, &ast) {
-                    Ok(_) => {
-                        assert!(false);
-                    }
-                    Err(err) => {
-                        assert_eq!(err.err, $err);
-                    }
-                },
-                Err(_) => {
-                    assert!(false);
-                }
+    #[test]
+    fn diff() {
+        eval!(
+            "show (diff (({ a = 1, b = (2, 3) }, { b = (2, 4), a = 1 })))",
+            Str,
+            "Some (\".b.1\", \"3\", \"4\")"
+        );
+        eval!(
+            "show (diff ((Some (\"x\"), Some (\"y\"))))",
+            Str,
+            "Some (\".Some\", \"\\\"x\\\"\", \"\\\"y\\\"\")"
+        );
+        eval!(
+            "show (diff ((Some (1), None)))",
+            Str,
+            "Some (\"\", \"Some (1)\", \"None\")"
+        );
+        eval!("show (diff (((1, 2), (1, 2))))", Str, "None");
+        evalfails!(
+            "diff ((1, true))",
+            "Type error: expected (t1, t1) but found (integer, boolean)."
+        );
+    }
+
+    #[test]
+    fn examples() {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/examples");
+        let mut count = 0;
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|ext| ext == "plover") {
+                let src = std::fs::read_to_string(&path).unwrap();
+                let ast = parser::parse(&src).ok().unwrap();
+                let mut vm = vm::VirtualMachine::new();
+                assert!(codegen::eval(&mut vm, &ast).is_ok(), "{:?}", path);
+                count += 1;
             }
-        }};
+        }
+        assert!(count >= 6);
     }
 
     #[test]
@@ -539,6 +4666,33 @@ mod tests {
         eval!("1 > 2", Boolean, false);
         eval!("2 >= 2", Boolean, true);
         eval!("5 * 4 * 3 * 2 * 1", Integer, 120);
+        eval!("1.5 + 2.25", Float, 3.75);
+        eval!("1.5 - 2.0", Float, -0.5);
+        eval!("1.5 * 2.0", Float, 3.0);
+        eval!("1.0 / 4.0", Float, 0.25);
+        eval!("5.5 % 2.0", Float, 1.5);
+        eval!("-2.5", Float, -2.5);
+        eval!("1e3", Float, 1000.0);
+        eval!("1.5 < 2.0", Boolean, true);
+        eval!("2.0 >= 2.0", Boolean, true);
+        eval!("0.5 == 0.5", Boolean, true);
+        eval!("fn x -> x * x + 0.5 end (1.5)", Float, 2.75);
+        eval!("fn x -> x * x end (3)", Integer, 9);
+        evalfails!("1.5 + 1", "Type error: expected float but found integer.");
+        eval!("\"hello\"", Str, "hello");
+        eval!("\"hello\" ++ \", \" ++ \"world\"", Str, "hello, world");
+        eval!("\"a\\tb\"", Str, "a\tb");
+        eval!("\"abc\" == \"abc\"", Boolean, true);
+        eval!("\"abc\" ~= \"abd\"", Boolean, true);
+        eval!(
+            "fn greet (name) -> \"hello \" ++ name end greet (\"plover\")",
+            Str,
+            "hello plover"
+        );
+        evalfails!(
+            "\"a\" ++ true",
+            "Type error: expected string but found boolean."
+        );
         evalfails!(
             "1 + true",
             "Type error: expected integer but found boolean."
@@ -584,6 +4738,17 @@ mod tests {
         eval!("if true then 1 else 2 end", Integer, 1);
         eval!("if false then 1 else 2 end", Integer, 2);
         eval!("if false then 1 elsif true then 2 else 3 end", Integer, 2);
+        eval!("if false then 1 elif true then 2 else 3 end", Integer, 2);
+        eval!("if true then () end", Unit);
+        eval!("if false then print (1) elif false then () end", Unit);
+        evalfails!(
+            "if true then 1 end",
+            "Type error: expected unit but found integer."
+        );
+        evalfails!(
+            "if true then () elif false then 2 end",
+            "Type error: expected unit but found integer."
+        );
         eval!(
             "if true then if false then 1 else 2 end else 3 end",
             Integer,
@@ -626,6 +4791,19 @@ mod tests {
             Value::Integer(2)
         );
         eval!("fn (x, y) -> x + y end (1, 2)", Integer, 3);
+        eval!("fn (x, y, z) -> x + y + z end (1, 2, 3)", Integer, 6);
+        eval!(
+            "(fn (x, y, z) -> x + y + z end (1, 2, 3), 7)",
+            Tuple,
+            Value::Integer(6),
+            Value::Integer(7)
+        );
+        eval!(
+            "def t := (1, 2) fn f (x) -> x end f (t)",
+            Tuple,
+            Value::Integer(1),
+            Value::Integer(2)
+        );
         eval!("(1, 1) == (1, 0)", Boolean, false);
         eval!("(1, 1, 1) == (1, 1, 0)", Boolean, false);
         eval!("(1, 1, 1, 1) == (1, 1, 1, 0)", Boolean, false);
@@ -739,6 +4917,31 @@ mod tests {
             Integer,
             120
         );
+        eval!(
+            "fn count (n, acc) ->
+                 if n == 0 then
+                    acc
+                 else
+                    count (n - 1, acc + 1)
+                 end
+             end
+             count (100000, 0)
+        ",
+            Integer,
+            100000
+        );
+        eval!(
+            "fn even (n) ->
+                 fn odd (n) ->
+                     if n == 0 then false else even (n - 1) end
+                 end
+                 if n == 0 then true else odd (n - 1) end
+             end
+             even (100001)
+        ",
+            Boolean,
+            false
+        );
         eval!(
             "type E := A | B end
              match B with
@@ -800,5 +5003,38 @@ mod tests {
             Integer,
             3
         );
+        eval!(
+            "type Shape := Circle (r) | Square (s) | Point end
+             fn sides (shape) ->
+                 match shape with
+                    Square (s) -> 4
+                    | _ -> 0
+                 end
+             end
+             def shapes := (sides (Square (2)), sides (Point), sides (Circle (1)))
+            ",
+            Tuple,
+            Value::Integer(4),
+            Value::Integer(0),
+            Value::Integer(0)
+        );
+        // A nested match refers to the parameters of the function around it.
+        eval!(
+            "type Pair := Cons (a, b) | Null end
+             fn sum (xs, ys) ->
+                 match xs with
+                    Null -> 0
+                    | Cons (x, rest) ->
+                        match ys with
+                           Null -> x
+                           | Cons (y, more) -> x + y
+                        end
+                 end
+             end
+             sum (Cons (1, Null), Cons (2, Null))
+            ",
+            Integer,
+            3
+        );
     }
 }
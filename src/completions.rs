@@ -0,0 +1,365 @@
+// The subcommands and options of the command line, from which the completion
+// scripts for each shell are generated. An option that takes a value names
+// what it is, which for a file means that files are completed after it.
+pub struct Opt {
+    pub name: &'static str,
+    pub value: Option<&'static str>,
+    pub help: &'static str,
+}
+
+pub struct Command {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub options: &'static [Opt],
+    // The words that can be given in place of a file, if any.
+    pub words: &'static [&'static str],
+}
+
+const fn opt(name: &'static str, help: &'static str) -> Opt {
+    Opt {
+        name,
+        value: None,
+        help,
+    }
+}
+
+const fn valued(name: &'static str, value: &'static str, help: &'static str) -> Opt {
+    Opt {
+        name,
+        value: Some(value),
+        help,
+    }
+}
+
+// Options accepted by every command, as well as by the interpreter itself.
+pub const OPTIONS: &[Opt] = &[
+    opt("-O", "optimize the compiled program"),
+    opt("--crash-report", "write a report of any crash"),
+    opt("--emit-bytecode", "print the compiled bytecode"),
+    opt("--pure", "permit no effects"),
+    opt("--wrapping", "wrap integers around on overflow"),
+    opt("--bigint", "promote integers to bigints on overflow"),
+    opt("--allow-net", "permit network requests"),
+    opt("--allow-process", "permit running commands"),
+    opt("--allow-time", "permit reading the clock"),
+    valued("--define", "feature", "select a feature"),
+    valued("--radix", "radix", "print integers in this radix"),
+    valued(
+        "--digit-separator",
+        "separator",
+        "separate groups of digits",
+    ),
+];
+
+// Options that are only accepted on their own, before any command.
+pub const TOP_LEVEL: &[Opt] = &[
+    opt("--opcodes", "list the instructions of the virtual machine"),
+    opt("--version", "show the version"),
+];
+
+pub const SHELLS: &[&str] = &["bash", "zsh", "fish"];
+
+pub const COMMANDS: &[Command] = &[
+    Command {
+        name: "bench",
+        help: "time programs and compare them with earlier results",
+        options: &[
+            valued("--runs", "runs", "the number of times to run each program"),
+            valued("--baseline", "file", "the results to compare with"),
+            valued("--save", "file", "the file to save the results to"),
+        ],
+        words: &[],
+    },
+    Command {
+        name: "build",
+        help: "compile a program to bytecode or wasm",
+        options: &[
+            valued("-o", "file", "the file to write"),
+            opt("--target=bytecode", "compile to bytecode"),
+            opt("--target=wasm", "compile to WebAssembly"),
+        ],
+        words: &[],
+    },
+    Command {
+        name: "completions",
+        help: "print a shell completion script",
+        options: &[],
+        words: SHELLS,
+    },
+    Command {
+        name: "debug",
+        help: "run a program in the debugger",
+        options: &[],
+        words: &[],
+    },
+    Command {
+        name: "examples",
+        help: "run the example programs",
+        options: &[],
+        words: &["run-all"],
+    },
+    Command {
+        name: "fmt",
+        help: "format programs",
+        options: &[opt("--check", "list the files that are not formatted")],
+        words: &[],
+    },
+    Command {
+        name: "kernel",
+        help: "run as a Jupyter kernel",
+        options: &[opt("--install", "install the kernel for Jupyter")],
+        words: &[],
+    },
+    Command {
+        name: "learn",
+        help: "start the tutorial",
+        options: &[],
+        words: &[],
+    },
+    Command {
+        name: "profile",
+        help: "run a program and count its instructions",
+        options: &[
+            opt("--annotate", "print the source with its counts"),
+            valued(
+                "--flamegraph",
+                "file",
+                "write the call stacks for flamegraphs",
+            ),
+        ],
+        words: &[],
+    },
+    Command {
+        name: "repl",
+        help: "start the REPL",
+        options: &[opt("--json", "read and write each entry as a line of JSON")],
+        words: &[],
+    },
+    Command {
+        name: "run",
+        help: "run programs without starting the REPL",
+        options: &[],
+        words: &[],
+    },
+];
+
+pub fn command(name: &str) -> Option<&'static Command> {
+    COMMANDS.iter().find(|command| command.name == name)
+}
+
+fn names(options: &[Opt]) -> Vec<&'static str> {
+    options.iter().map(|option| option.name).collect()
+}
+
+fn file_options() -> Vec<&'static str> {
+    COMMANDS
+        .iter()
+        .flat_map(|command| command.options)
+        .chain(OPTIONS)
+        .filter(|option| option.value == Some("file"))
+        .map(|option| option.name)
+        .collect()
+}
+
+fn valued_options() -> Vec<&'static str> {
+    COMMANDS
+        .iter()
+        .flat_map(|command| command.options)
+        .chain(OPTIONS)
+        .filter(|option| option.value.is_some_and(|value| value != "file"))
+        .map(|option| option.name)
+        .collect()
+}
+
+fn bash() -> String {
+    let mut first = names(TOP_LEVEL);
+    first.extend(names(OPTIONS));
+    first.extend(COMMANDS.iter().map(|command| command.name));
+    let mut s = String::new();
+    s.push_str("_plover() {\n");
+    s.push_str("    local cur=\"${COMP_WORDS[COMP_CWORD]}\"\n");
+    s.push_str("    local prev=\"${COMP_WORDS[COMP_CWORD-1]}\"\n");
+    s.push_str("    case \"$prev\" in\n");
+    s.push_str(&format!("        {})\n", file_options().join("|")));
+    s.push_str("            COMPREPLY=($(compgen -f -- \"$cur\"))\n");
+    s.push_str("            return ;;\n");
+    s.push_str(&format!("        {})\n", valued_options().join("|")));
+    s.push_str("            COMPREPLY=()\n");
+    s.push_str("            return ;;\n");
+    s.push_str("    esac\n");
+    s.push_str("    local words\n");
+    s.push_str("    if [ \"$COMP_CWORD\" -eq 1 ]; then\n");
+    s.push_str(&format!("        words=\"{}\"\n", first.join(" ")));
+    s.push_str("    else\n");
+    s.push_str("        case \"${COMP_WORDS[1]}\" in\n");
+    for command in COMMANDS {
+        let mut words = names(command.options);
+        words.extend(command.words);
+        words.extend(names(OPTIONS));
+        s.push_str(&format!(
+            "            {}) words=\"{}\" ;;\n",
+            command.name,
+            words.join(" ")
+        ));
+    }
+    s.push_str(&format!(
+        "            *) words=\"{}\" ;;\n",
+        names(OPTIONS).join(" ")
+    ));
+    s.push_str("        esac\n");
+    s.push_str("    fi\n");
+    s.push_str("    COMPREPLY=($(compgen -W \"$words\" -- \"$cur\") $(compgen -f -- \"$cur\"))\n");
+    s.push_str("}\n");
+    s.push_str("complete -o filenames -F _plover plover\n");
+    s
+}
+
+// Quotes a description for zsh, where a colon separates it from its option.
+fn described(name: &str, help: &str) -> String {
+    format!("'{}:{}'", name.replace(':', "\\:"), help)
+}
+
+fn zsh() -> String {
+    let options: Vec<String> = OPTIONS
+        .iter()
+        .map(|option| described(option.name, option.help))
+        .collect();
+    let mut s = String::new();
+    s.push_str("#compdef plover\n\n");
+    s.push_str("_plover() {\n");
+    s.push_str("    local -a options\n");
+    s.push_str("    case $words[CURRENT-1] in\n");
+    s.push_str(&format!("        {})\n", file_options().join("|")));
+    s.push_str("            _files\n");
+    s.push_str("            return ;;\n");
+    s.push_str(&format!("        {})\n", valued_options().join("|")));
+    s.push_str("            return ;;\n");
+    s.push_str("    esac\n");
+    s.push_str(&format!("    options=({})\n", options.join(" ")));
+    s.push_str("    if (( CURRENT == 2 )); then\n");
+    let mut first: Vec<String> = COMMANDS
+        .iter()
+        .map(|command| described(command.name, command.help))
+        .collect();
+    first.extend(
+        TOP_LEVEL
+            .iter()
+            .map(|option| described(option.name, option.help)),
+    );
+    s.push_str(&format!(
+        "        local -a commands=({})\n",
+        first.join(" ")
+    ));
+    s.push_str("        _describe command commands\n");
+    s.push_str("    else\n");
+    s.push_str("        case $words[2] in\n");
+    for command in COMMANDS {
+        let mut words: Vec<String> = command
+            .options
+            .iter()
+            .map(|option| described(option.name, option.help))
+            .collect();
+        words.extend(command.words.iter().map(|word| format!("'{}'", word)));
+        if !words.is_empty() {
+            s.push_str(&format!(
+                "            {}) options+=({}) ;;\n",
+                command.name,
+                words.join(" ")
+            ));
+        }
+    }
+    s.push_str("        esac\n");
+    s.push_str("    fi\n");
+    s.push_str("    _describe option options\n");
+    s.push_str("    _files\n");
+    s.push_str("}\n\n");
+    s.push_str("_plover \"$@\"\n");
+    s
+}
+
+// Fish names options without their dashes, as short ones with -s and long ones
+// with -l, and a long option given a value after = has that value completed.
+fn fish_option(option: &Opt, condition: &str) -> String {
+    let mut s = format!("complete -c plover -n '{}'", condition);
+    match option.name.split_once('=') {
+        Some((name, value)) => {
+            s.push_str(&format!(" -l {} -x -a {}", &name[2..], value));
+        }
+        None if option.name.starts_with("--") => s.push_str(&format!(" -l {}", &option.name[2..])),
+        None => s.push_str(&format!(" -s {}", &option.name[1..])),
+    }
+    match option.value {
+        Some("file") => s.push_str(" -r -F"),
+        Some(_) => s.push_str(" -x"),
+        None => {}
+    }
+    s.push_str(&format!(" -d '{}'\n", option.help));
+    s
+}
+
+fn fish() -> String {
+    let mut s = String::new();
+    for option in TOP_LEVEL {
+        s.push_str(&fish_option(option, "__fish_use_subcommand"));
+    }
+    for option in OPTIONS {
+        s.push_str(&fish_option(option, "true"));
+    }
+    for command in COMMANDS {
+        s.push_str(&format!(
+            "complete -c plover -n '__fish_use_subcommand' -a {} -d '{}'\n",
+            command.name, command.help
+        ));
+        let condition = format!("__fish_seen_subcommand_from {}", command.name);
+        for option in command.options {
+            s.push_str(&fish_option(option, &condition));
+        }
+        if !command.words.is_empty() {
+            s.push_str(&format!(
+                "complete -c plover -n '{}; and not __fish_seen_subcommand_from {}' -a '{}'\n",
+                condition,
+                command.words.join(" "),
+                command.words.join(" ")
+            ));
+        }
+    }
+    s
+}
+
+pub fn script(shell: &str) -> Option<String> {
+    match shell {
+        "bash" => Some(bash()),
+        "zsh" => Some(zsh()),
+        "fish" => Some(fish()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::completions::{script, COMMANDS, OPTIONS};
+
+    #[test]
+    fn scripts() {
+        for shell in ["bash", "zsh", "fish"] {
+            let script = script(shell).unwrap();
+            for command in COMMANDS {
+                assert!(script.contains(command.name));
+                for option in command.options {
+                    let name = option.name.split('=').next().unwrap();
+                    assert!(script.contains(name.trim_start_matches('-')));
+                }
+            }
+            for option in OPTIONS {
+                assert!(script.contains(option.name.trim_start_matches('-')));
+            }
+        }
+        assert!(script("bash")
+            .unwrap()
+            .contains("            fmt) words=\"--check -O "));
+        assert!(script("fish")
+            .unwrap()
+            .contains("-l target -x -a wasm -d 'compile to WebAssembly'"));
+        assert_eq!(script("csh"), None);
+    }
+}
@@ -0,0 +1,67 @@
+use std::fs::File;
+use std::io::{self, Write};
+
+use plover::vm;
+
+// A crash report bundles everything needed to reproduce an internal error:
+// every source evaluated in the session, the bytecode that was generated for
+// it and the state of the virtual machine when the error occurred.
+pub fn write(
+    path: &str,
+    msg: &str,
+    sources: &[(String, String)],
+    vm: &vm::VirtualMachine,
+) -> io::Result<()> {
+    let mut file = File::create(path)?;
+
+    writeln!(file, "Plover crash report")?;
+    writeln!(file, "===================")?;
+    writeln!(file)?;
+    writeln!(file, "version: {}", plover::version())?;
+    writeln!(file, "error: {}", msg)?;
+    writeln!(file)?;
+
+    writeln!(file, "Sources")?;
+    writeln!(file, "-------")?;
+    for (filename, src) in sources {
+        writeln!(file, "--> {}", filename)?;
+        writeln!(file, "{}", src)?;
+    }
+    writeln!(file)?;
+
+    writeln!(file, "Bytecode")?;
+    writeln!(file, "--------")?;
+    for (i, instr) in vm.instructions.iter().enumerate() {
+        writeln!(file, "  {} {}", i, instr.display(&vm.names))?;
+    }
+    writeln!(file)?;
+
+    writeln!(file, "Virtual machine")?;
+    writeln!(file, "---------------")?;
+    writeln!(file, "ip: {}", vm.ip)?;
+    writeln!(file, "srcpos: {} {}", vm.line, vm.col)?;
+    writeln!(file, "stack:")?;
+    for (i, value) in vm.stack.iter().enumerate() {
+        writeln!(file, "  {} {}", i, value)?;
+    }
+    writeln!(file, "callstack:")?;
+    for (ip, env, sp, return_ip) in &vm.callstack {
+        writeln!(
+            file,
+            "  @{} sp {} return @{} env {:?}",
+            ip,
+            sp,
+            return_ip,
+            env.bindings(&vm.names)
+                .iter()
+                .map(|(id, _)| vm.names.name(*id))
+                .collect::<Vec<_>>()
+        )?;
+    }
+    writeln!(file, "env:")?;
+    for (id, value) in vm.env.bindings(&vm.names) {
+        writeln!(file, "  {} {}", vm.names.name(id), value)?;
+    }
+
+    Ok(())
+}
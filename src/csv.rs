@@ -0,0 +1,154 @@
+// Comma-separated values, following RFC 4180. Records end with a line feed or
+// a carriage return and line feed, except that the last one need not, and
+// fields that hold a comma, a quote or a line break are written in quotes,
+// with each quote inside of them doubled.
+
+pub fn parse(s: &str) -> Result<Vec<Vec<String>>, String> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut line = 1;
+    let mut chars = s.chars().peekable();
+    while chars.peek().is_some() {
+        let mut field = String::new();
+        let quoted = chars.peek() == Some(&'"');
+        if quoted {
+            let start = line;
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some('"') if chars.peek() == Some(&'"') => {
+                        chars.next();
+                        field.push('"');
+                    }
+                    Some('"') => break,
+                    Some(c) => {
+                        if c == '\n' {
+                            line += 1;
+                        }
+                        field.push(c);
+                    }
+                    None => {
+                        return Err(format!(
+                            "Unterminated quoted field in CSV at line {}.",
+                            start
+                        ))
+                    }
+                }
+            }
+        }
+        // Whether the field ends its record, rather than being followed by
+        // another.
+        let last = loop {
+            match chars.next() {
+                Some(',') => break false,
+                Some('\n') => break true,
+                Some('\r') if chars.peek() == Some(&'\n') => {
+                    chars.next();
+                    break true;
+                }
+                None => break true,
+                Some(_) if quoted => {
+                    return Err(format!(
+                        "Expected a comma or a line break after a quoted field in CSV at line {}.",
+                        line
+                    ))
+                }
+                Some(c) => field.push(c),
+            }
+        };
+        record.push(field);
+        if last {
+            records.push(std::mem::take(&mut record));
+            line += 1;
+        }
+    }
+    // A comma at the very end is followed by an empty field.
+    if !record.is_empty() {
+        record.push(String::new());
+        records.push(record);
+    }
+    Ok(records)
+}
+
+pub fn format(records: &[Vec<&str>]) -> String {
+    let mut s = String::new();
+    for record in records {
+        // A record of one empty field is quoted, as an empty line is read
+        // back as such a record but would look like nothing was written.
+        if let [""] = record[..] {
+            s.push_str("\"\"");
+        }
+        for (i, field) in record.iter().enumerate() {
+            if i != 0 {
+                s.push(',');
+            }
+            if field.contains([',', '"', '\r', '\n']) {
+                s.push('"');
+                s.push_str(&field.replace('"', "\"\""));
+                s.push('"');
+            } else {
+                s.push_str(field);
+            }
+        }
+        s.push('\n');
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::csv::{format, parse};
+
+    #[test]
+    fn parsing() {
+        let records = |rows: &[&[&str]]| -> Vec<Vec<String>> {
+            rows.iter()
+                .map(|row| row.iter().map(|field| field.to_string()).collect())
+                .collect()
+        };
+        assert_eq!(parse(""), Ok(Vec::new()));
+        assert_eq!(
+            parse("a,b\r\n1,2\n"),
+            Ok(records(&[&["a", "b"], &["1", "2"]]))
+        );
+        assert_eq!(
+            parse("a,,\n\nb"),
+            Ok(records(&[&["a", "", ""], &[""], &["b"]]))
+        );
+        assert_eq!(
+            parse("\"x, \"\"y\"\"\",\"line\nbreak\"\n\"\""),
+            Ok(records(&[&["x, \"y\"", "line\nbreak"], &[""]]))
+        );
+        assert_eq!(parse("a\rb,"), Ok(records(&[&["a\rb", ""]])));
+        assert_eq!(parse("a\"b"), Ok(records(&[&["a\"b"]])));
+        assert_eq!(
+            parse("a\n\"b\nc"),
+            Err("Unterminated quoted field in CSV at line 2.".to_string())
+        );
+        assert_eq!(
+            parse("a\n\"b\"c"),
+            Err(
+                "Expected a comma or a line break after a quoted field in CSV at line 2."
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn formatting() {
+        let rows: Vec<Vec<&str>> = vec![
+            vec!["a", "b, c"],
+            vec![""],
+            vec!["say \"hi\"", "two\nlines", ""],
+        ];
+        let s = format(&rows);
+        assert_eq!(s, "a,\"b, c\"\n\"\"\n\"say \"\"hi\"\"\",\"two\nlines\",\n");
+        assert_eq!(
+            parse(&s),
+            Ok(rows
+                .iter()
+                .map(|row| row.iter().map(|field| field.to_string()).collect())
+                .collect())
+        );
+    }
+}
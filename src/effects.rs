@@ -0,0 +1,321 @@
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::typeinfer::TypedAST;
+
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Effect {
+    Io,
+    Net,
+    Nondeterminism,
+    Process,
+    Time,
+}
+
+impl fmt::Display for Effect {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Effect::Io => write!(f, "io"),
+            Effect::Net => write!(f, "net"),
+            Effect::Nondeterminism => write!(f, "nondeterminism"),
+            Effect::Process => write!(f, "process"),
+            Effect::Time => write!(f, "time"),
+        }
+    }
+}
+
+pub type Effects = BTreeSet<Effect>;
+
+pub fn all() -> Effects {
+    [
+        Effect::Io,
+        Effect::Net,
+        Effect::Nondeterminism,
+        Effect::Process,
+        Effect::Time,
+    ]
+    .iter()
+    .cloned()
+    .collect()
+}
+
+fn remove_params(param: &TypedAST, latent: &mut HashMap<String, Effects>) {
+    match param {
+        TypedAST::Identifier(_, id) => {
+            latent.remove(id);
+        }
+        TypedAST::Tuple(_, elements) => {
+            for element in elements {
+                remove_params(element, latent);
+            }
+        }
+        _ => {}
+    }
+}
+
+// Returns the effects performed by evaluating an expression, along with the
+// latent effects of the function it evaluates to, which are performed when
+// that function is called. The latent map holds the latent effects of the
+// functions bound to identifiers.
+//
+// This is only an approximation: calls through function values that are not
+// bound to a known identifier, such as function arguments, are assumed to be
+// pure. The virtual machine checks the effects of builtins as they are run,
+// so these are caught at runtime instead.
+fn analyze(ast: &TypedAST, latent: &mut HashMap<String, Effects>) -> (Effects, Effects) {
+    match ast {
+        TypedAST::BinaryOp(_, _, lhs, rhs, _, _) => {
+            let (mut effects, _) = analyze(rhs, latent);
+            effects.extend(analyze(lhs, latent).0);
+            (effects, Effects::new())
+        }
+        TypedAST::Call(fun, arg, ..) => {
+            let (mut effects, _) = analyze(arg, latent);
+            let (fun_effects, fun_latent) = analyze(fun, latent);
+            effects.extend(fun_effects);
+            effects.extend(fun_latent);
+            (effects, Effects::new())
+        }
+        TypedAST::Datatype(_, variants) => {
+            for variant in variants {
+                latent.remove(&variant.0);
+            }
+            (Effects::new(), Effects::new())
+        }
+        TypedAST::Define(_, id, value) => {
+            let (effects, value_latent) = analyze(value, latent);
+            latent.insert(id.to_string(), value_latent.clone());
+            (effects, value_latent)
+        }
+        TypedAST::Field(_, record, _) => (analyze(record, latent).0, Effects::new()),
+        TypedAST::Defer(function) => (analyze(function, latent).1, Effects::new()),
+        TypedAST::For(_, function, iter) => {
+            let (mut effects, _) = analyze(iter, latent);
+            effects.extend(analyze(function, latent).1);
+            (effects, Effects::new())
+        }
+        TypedAST::While(cond, body) => {
+            // A second pass picks up functions defined by the body that are
+            // called on the next iteration.
+            let mut effects = Effects::new();
+            for _ in 0..2 {
+                effects.extend(analyze(cond, latent).0);
+                effects.extend(analyze(body, latent).0);
+            }
+            (effects, Effects::new())
+        }
+        TypedAST::Function(id, param, body, ..) => {
+            let mut local_latent = latent.clone();
+            remove_params(param, &mut local_latent);
+            if let Some(id) = id {
+                local_latent.insert(id.to_string(), Effects::new());
+            }
+            let (body_effects, _) = analyze(body, &mut local_latent);
+            if let Some(id) = id {
+                latent.insert(id.to_string(), body_effects.clone());
+            }
+            (Effects::new(), body_effects)
+        }
+        TypedAST::Identifier(_, id) => {
+            (Effects::new(), latent.get(id).cloned().unwrap_or_default())
+        }
+        TypedAST::If(conds, els, _, _) => {
+            let mut effects = Effects::new();
+            let mut branch_latent = Effects::new();
+            for (cond, then) in conds {
+                effects.extend(analyze(cond, latent).0);
+                let (then_effects, then_latent) = analyze(then, latent);
+                effects.extend(then_effects);
+                branch_latent.extend(then_latent);
+            }
+            let (els_effects, els_latent) = analyze(els, latent);
+            effects.extend(els_effects);
+            branch_latent.extend(els_latent);
+            (effects, branch_latent)
+        }
+        TypedAST::Let(param, value, body, _, _) => {
+            let (mut effects, value_latent) = analyze(value, latent);
+            let mut local_latent = latent.clone();
+            remove_params(param, &mut local_latent);
+            if let TypedAST::Identifier(_, id) = &**param {
+                local_latent.insert(id.to_string(), value_latent);
+            }
+            let (body_effects, body_latent) = analyze(body, &mut local_latent);
+            effects.extend(body_effects);
+            (effects, body_latent)
+        }
+        TypedAST::Try(body, param, handler, _, _) => {
+            let (mut effects, mut branch_latent) = analyze(body, &mut latent.clone());
+            let mut local_latent = latent.clone();
+            remove_params(param, &mut local_latent);
+            let (handler_effects, handler_latent) = analyze(handler, &mut local_latent);
+            effects.extend(handler_effects);
+            branch_latent.extend(handler_latent);
+            (effects, branch_latent)
+        }
+        TypedAST::Letrec(functions) => {
+            // The functions may call each other, so their latent effects are
+            // recomputed until they no longer change.
+            for function in functions {
+                if let TypedAST::Function(Some(id), ..) = function {
+                    latent.insert(id.to_string(), Effects::new());
+                }
+            }
+            let mut last_latent = Effects::new();
+            loop {
+                let before = latent.clone();
+                for function in functions {
+                    if let TypedAST::Function(Some(id), param, body, ..) = function {
+                        let mut local_latent = latent.clone();
+                        remove_params(param, &mut local_latent);
+                        let (body_effects, _) = analyze(body, &mut local_latent);
+                        latent.insert(id.to_string(), body_effects.clone());
+                        last_latent = body_effects;
+                    }
+                }
+                if *latent == before {
+                    break;
+                }
+            }
+            (Effects::new(), last_latent)
+        }
+        TypedAST::Match(cond, _, cases) => {
+            let (mut effects, _) = analyze(cond, latent);
+            let mut case_latent = Effects::new();
+            for case in cases {
+                let mut local_latent = latent.clone();
+                if let Some(param) = &case.1 {
+                    remove_params(param, &mut local_latent);
+                }
+                if let Some(guard) = &case.2 {
+                    let (guard_effects, _) = analyze(guard, &mut local_latent);
+                    effects.extend(guard_effects);
+                }
+                let (case_effects, latent) = analyze(&case.3, &mut local_latent);
+                effects.extend(case_effects);
+                case_latent.extend(latent);
+            }
+            (effects, case_latent)
+        }
+        TypedAST::Program(_, expressions) => {
+            let mut effects = Effects::new();
+            let mut last_latent = Effects::new();
+            for expression in expressions {
+                let (expression_effects, expression_latent) = analyze(expression, latent);
+                effects.extend(expression_effects);
+                last_latent = expression_latent;
+            }
+            (effects, last_latent)
+        }
+        TypedAST::Record(_, fields) => {
+            let mut effects = Effects::new();
+            for field in fields {
+                effects.extend(analyze(&field.1, latent).0);
+            }
+            (effects, Effects::new())
+        }
+        TypedAST::Array(_, elements)
+        | TypedAST::List(_, elements)
+        | TypedAST::Tuple(_, elements) => {
+            let mut effects = Effects::new();
+            for element in elements {
+                effects.extend(analyze(element, latent).0);
+            }
+            (effects, Effects::new())
+        }
+        TypedAST::Map(_, entries) => {
+            let mut effects = Effects::new();
+            for (key, value) in entries {
+                effects.extend(analyze(key, latent).0);
+                effects.extend(analyze(value, latent).0);
+            }
+            (effects, Effects::new())
+        }
+        TypedAST::UnaryOp(_, _, ast) | TypedAST::Propagate(_, ast, _, _) => {
+            (analyze(ast, latent).0, Effects::new())
+        }
+        TypedAST::Update(_, record, fields) => {
+            let (mut effects, _) = analyze(record, latent);
+            for field in fields {
+                effects.extend(analyze(&field.1, latent).0);
+            }
+            (effects, Effects::new())
+        }
+        TypedAST::BigInt(_)
+        | TypedAST::Boolean(_)
+        | TypedAST::Bytes(_)
+        | TypedAST::Char(_)
+        | TypedAST::Float(_)
+        | TypedAST::Integer(_)
+        | TypedAST::String(_)
+        | TypedAST::Unit => (Effects::new(), Effects::new()),
+    }
+}
+
+// Infers the effects performed by evaluating a program, updating the latent
+// effects of the functions it defines.
+pub fn infer(ast: &TypedAST, latent: &mut HashMap<String, Effects>) -> Effects {
+    analyze(ast, latent).0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::effects;
+    use crate::effects::{Effect, Effects};
+    use crate::parser;
+    use crate::typeinfer;
+
+    macro_rules! effects {
+        ($input:expr, $($effect:expr),*) => {{
+            let mut ids = HashMap::new();
+            let mut datatypes = HashMap::new();
+            let mut latent = HashMap::new();
+            ids.insert(
+                "print".to_string(),
+                typeinfer::Type::Function(
+                    Box::new(typeinfer::Type::Polymorphic("a".to_string())),
+                    Box::new(typeinfer::Type::Unit),
+                ),
+            );
+            latent.insert("print".to_string(), [Effect::Io].iter().cloned().collect());
+            let ast = parser::parse($input).ok().unwrap();
+            let typed_ast = typeinfer::infer(&ast, &mut ids, &mut datatypes).ok().unwrap();
+            let expected: Effects = [$($effect),*].iter().cloned().collect();
+            assert_eq!(effects::infer(&typed_ast, &mut latent), expected);
+        }};
+    }
+
+    #[test]
+    fn effects() {
+        effects!("1 + 2",);
+        effects!("print (1)", Effect::Io);
+        effects!("fn f (x) -> print (x) end",);
+        effects!("fn f (x) -> print (x) end f (1)", Effect::Io);
+        effects!("def p := print p (1)", Effect::Io);
+        effects!(
+            "fn f (x) -> fn g (y) -> print (y) end end
+             def h := f (1)",
+        );
+        effects!(
+            "fn f (x) -> if x == 0 then print (x) else f (x - 1) end end
+             f (2)",
+            Effect::Io
+        );
+        effects!(
+            "fn f (print) -> print end
+             f (1)",
+        );
+        effects!(
+            "letrec
+                 fn f (x) -> g (x) end
+                 fn g (x) -> if x == 0 then print (x) else f (x - 1) end end
+             end
+             f (1)",
+            Effect::Io
+        );
+        effects!("letrec fn f (x) -> g (x) end fn g (x) -> x end end f (1)",);
+    }
+}
@@ -0,0 +1,112 @@
+// Text encodings of bytes. Decoding returns the offset of the first character
+// that could not be decoded on failure.
+
+const HEX_DIGITS: &[u8] = b"0123456789abcdef";
+
+const BASE64_DIGITS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+// Writes each byte as two lowercase hex digits.
+pub fn hex_encode(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        s.push(HEX_DIGITS[usize::from(b >> 4)] as char);
+        s.push(HEX_DIGITS[usize::from(b & 0xf)] as char);
+    }
+    s
+}
+
+// Reads pairs of hex digits in either case. A digit left over at the end is
+// an error at its offset.
+pub fn hex_decode(s: &str) -> Result<Vec<u8>, usize> {
+    let digit = |offset: usize| match s.as_bytes().get(offset) {
+        Some(c) => (*c as char).to_digit(16).map(|d| d as u8).ok_or(offset),
+        None => Err(offset - 1),
+    };
+    let mut bytes = Vec::with_capacity(s.len() / 2);
+    for offset in (0..s.len()).step_by(2) {
+        let high = digit(offset)?;
+        bytes.push(high << 4 | digit(offset + 1)?);
+    }
+    Ok(bytes)
+}
+
+// Writes bytes with the standard base64 alphabet, padded with = to a multiple
+// of four characters.
+pub fn base64_encode(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let mut group = [0; 3];
+        group[..chunk.len()].copy_from_slice(chunk);
+        let bits = u32::from(group[0]) << 16 | u32::from(group[1]) << 8 | u32::from(group[2]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                s.push(BASE64_DIGITS[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                s.push('=');
+            }
+        }
+    }
+    s
+}
+
+// Reads base64 with the standard alphabet. The padding is required, and is
+// only allowed at the end.
+pub fn base64_decode(s: &str) -> Result<Vec<u8>, usize> {
+    let input = s.as_bytes();
+    if !input.len().is_multiple_of(4) {
+        return Err(input.len() - input.len() % 4);
+    }
+    let mut bytes = Vec::with_capacity(input.len() / 4 * 3);
+    for (start, chunk) in input.chunks(4).enumerate().map(|(i, c)| (i * 4, c)) {
+        let last = start + 4 == input.len();
+        let padding = chunk.iter().rev().take_while(|c| **c == b'=').count();
+        if padding > 2 || (padding > 0 && !last) {
+            return Err(start + 4 - padding);
+        }
+        let mut bits = 0;
+        for (i, c) in chunk[..4 - padding].iter().enumerate() {
+            match BASE64_DIGITS.iter().position(|digit| digit == c) {
+                Some(value) => bits |= (value as u32) << (18 - 6 * i),
+                None => return Err(start + i),
+            }
+        }
+        let decoded = [(bits >> 16) as u8, (bits >> 8) as u8, bits as u8];
+        bytes.extend(&decoded[..3 - padding]);
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::encoding::{base64_decode, base64_encode, hex_decode, hex_encode};
+
+    #[test]
+    fn hex() {
+        assert_eq!(hex_encode(b"\x00\x7f\xff"), "007fff");
+        assert_eq!(hex_decode("007fFF"), Ok(b"\x00\x7f\xff".to_vec()));
+        assert_eq!(hex_decode(""), Ok(Vec::new()));
+        assert_eq!(hex_decode("0g"), Err(1));
+        assert_eq!(hex_decode("abc"), Err(2));
+        assert_eq!(hex_decode("é0"), Err(0));
+    }
+
+    #[test]
+    fn base64() {
+        for (bytes, encoded) in [
+            (&b""[..], ""),
+            (b"f", "Zg=="),
+            (b"fo", "Zm8="),
+            (b"foo", "Zm9v"),
+            (b"foob", "Zm9vYg=="),
+            (b"\xff\xfe", "//4="),
+        ] {
+            assert_eq!(base64_encode(bytes), encoded);
+            assert_eq!(base64_decode(encoded), Ok(bytes.to_vec()));
+        }
+        assert_eq!(base64_decode("Zm9"), Err(0));
+        assert_eq!(base64_decode("Zm9vY"), Err(4));
+        assert_eq!(base64_decode("Zm-v"), Err(2));
+        assert_eq!(base64_decode("Zg==Zm9v"), Err(2));
+        assert_eq!(base64_decode("Z==="), Err(1));
+    }
+}
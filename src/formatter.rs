@@ -0,0 +1,783 @@
+use crate::parser::{self, Operator, Trivia, AST};
+use crate::typeinfer;
+use crate::vm;
+
+// Programs are formatted with four spaces of indentation. An if, function,
+// let, try or when expression is written on one line if it fits within the
+// width, and match and letrec expressions, as well as bodies of more than one
+// expression, are always broken over several lines. Tuples, arrays and lists
+// that do not fit, including the arguments of a call, are written with one
+// element on each line.
+const INDENT: usize = 4;
+const WIDTH: usize = 80;
+
+fn pad(indent: usize) -> String {
+    " ".repeat(indent)
+}
+
+// The precedence of an expression as an operand, where a higher precedence
+// binds more tightly. Expressions that can not be operands have none.
+fn precedence(ast: &AST) -> usize {
+    match ast {
+        AST::BinaryOp(op, _, _, _, _) => match op {
+            Operator::Equal | Operator::NotEqual => 1,
+            Operator::Greater
+            | Operator::GreaterEqual
+            | Operator::Less
+            | Operator::LessEqual
+            | Operator::Range => 2,
+            Operator::Cons => 3,
+            Operator::Concat | Operator::Minus | Operator::Or | Operator::Plus => 4,
+            Operator::And | Operator::Divide | Operator::Mod | Operator::Multiply => 5,
+            Operator::Power => 7,
+            Operator::Not => unreachable!(),
+        },
+        AST::UnaryOp(_, _, _, _) => 6,
+        AST::Block(..)
+        | AST::Define(..)
+        | AST::Datatype(..)
+        | AST::Defer(..)
+        | AST::For(..)
+        | AST::Function(..)
+        | AST::If(..)
+        | AST::Let(..)
+        | AST::Letrec(..)
+        | AST::Match(..)
+        | AST::Program(..)
+        | AST::Sequence(..)
+        | AST::Try(..)
+        | AST::When(..)
+        | AST::While(..) => 0,
+        _ => 8,
+    }
+}
+
+// Writes an expression, in parentheses unless its precedence is at least the
+// given one.
+fn operand(trivia: &Trivia, ast: &AST, min: usize, indent: usize) -> String {
+    if precedence(ast) < min {
+        format!("({})", render(trivia, ast, indent))
+    } else {
+        render(trivia, ast, indent)
+    }
+}
+
+fn param(trivia: &Trivia, ast: &AST, indent: usize) -> String {
+    match ast {
+        AST::Tuple(..) | AST::Unit(..) => render(trivia, ast, indent),
+        _ => format!("({})", render(trivia, ast, indent)),
+    }
+}
+
+// Writes the elements of a tuple of at least two, or of a nonempty array or
+// list, on lines of their own.
+fn broken(trivia: &Trivia, ast: &AST, indent: usize) -> Option<String> {
+    let (open, elements) = match ast {
+        AST::Tuple(elements, _, _) if elements.len() > 1 => ("(", elements),
+        AST::Array(elements, _, _) if !elements.is_empty() => ("[", elements),
+        AST::List(elements, _, _) if !elements.is_empty() => ("%[", elements),
+        _ => return None,
+    };
+    let close = if open == "(" { ")" } else { "]" };
+    let inner = indent + INDENT;
+    let elements: Vec<String> = elements.iter().map(|e| render(trivia, e, inner)).collect();
+    Some(format!(
+        "{}\n{}{}\n{}{}",
+        open,
+        pad(inner),
+        elements.join(&format!(",\n{}", pad(inner))),
+        pad(indent),
+        close
+    ))
+}
+
+// Writes an expression following the given text on its line, breaking the
+// elements of a tuple, array or list, or the arguments of a call, when they
+// do not fit after it.
+fn after(trivia: &Trivia, prefix: String, ast: &AST, indent: usize) -> String {
+    if let Some(s) = flat(trivia, ast).filter(|s| indent + prefix.len() + s.len() <= WIDTH) {
+        return prefix + &s;
+    }
+    if let Some(s) = broken(trivia, ast, indent) {
+        return prefix + &s;
+    }
+    match ast {
+        AST::Call(fun, arg, _, _) => {
+            let fun = match **fun {
+                AST::Identifier(..) => render(trivia, fun, indent),
+                _ => format!("({})", render(trivia, fun, indent)),
+            };
+            match **arg {
+                AST::Tuple(..) | AST::Unit(..) => {
+                    after(trivia, format!("{}{} ", prefix, fun), arg, indent)
+                }
+                _ => after(trivia, format!("{}{} (", prefix, fun), arg, indent) + ")",
+            }
+        }
+        _ => prefix + &render(trivia, ast, indent),
+    }
+}
+
+// Writes the elements of a tuple, array or list on a single line, if they
+// can be.
+fn flat_elements(trivia: &Trivia, elements: &[AST]) -> Option<String> {
+    let elements: Option<Vec<String>> = elements.iter().map(|e| flat(trivia, e)).collect();
+    Some(elements?.join(", "))
+}
+
+fn fields(trivia: &Trivia, fields: &[(String, AST)], indent: usize) -> String {
+    fields
+        .iter()
+        .map(|(id, value)| format!("{} = {}", id, render(trivia, value, indent)))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn variant(trivia: &Trivia, id: &str, param: &Option<AST>, indent: usize) -> String {
+    match param {
+        Some(AST::Tuple(params, _, _)) if id == typeinfer::CONS => format!(
+            "{} :: {}",
+            render(trivia, &params[0], indent),
+            render(trivia, &params[1], indent)
+        ),
+        Some(param) => format!("{} {}", id, self::param(trivia, param, indent)),
+        None => id.to_string(),
+    }
+}
+
+fn function(trivia: &Trivia, name: &Option<String>, param: &AST) -> String {
+    match name {
+        Some(name) => format!("fn {} {} ->", name, self::param(trivia, param, 0)),
+        None => format!("fn {} ->", self::param(trivia, param, 0)),
+    }
+}
+
+// Writes an expression on a single line, if it can be.
+fn flat(trivia: &Trivia, ast: &AST) -> Option<String> {
+    let s = match ast {
+        AST::Function(name, param, body, _, _) => {
+            format!(
+                "{} {} end",
+                function(trivia, name, param),
+                flat(trivia, body)?
+            )
+        }
+        AST::For(param, iter, body, _, _) => {
+            format!(
+                "for {} in {} do {} end",
+                flat(trivia, param)?,
+                flat(trivia, iter)?,
+                flat(trivia, body)?
+            )
+        }
+        AST::While(cond, body, _, _) => {
+            format!(
+                "while {} do {} end",
+                flat(trivia, cond)?,
+                flat(trivia, body)?
+            )
+        }
+        AST::Block(body, _, _) => format!("do {} end", flat(trivia, body)?),
+        AST::Define(id, value, _, _) => {
+            format!("def {} := {}", flat(trivia, id)?, flat(trivia, value)?)
+        }
+        AST::Call(fun, arg, _, _) => {
+            let fun = match **fun {
+                AST::Identifier(..) => flat(trivia, fun)?,
+                _ => format!("({})", flat(trivia, fun)?),
+            };
+            match **arg {
+                AST::Tuple(..) | AST::Unit(..) => format!("{} {}", fun, flat(trivia, arg)?),
+                _ => format!("{} ({})", fun, flat(trivia, arg)?),
+            }
+        }
+        AST::Array(elements, _, _) => format!("[{}]", flat_elements(trivia, elements)?),
+        AST::List(elements, _, _) => format!("%[{}]", flat_elements(trivia, elements)?),
+        AST::Tuple(elements, _, _) if elements.len() == 1 => {
+            format!("({},)", flat(trivia, &elements[0])?)
+        }
+        AST::Tuple(elements, _, _) => format!("({})", flat_elements(trivia, elements)?),
+        AST::Sequence(expressions, _, _) => {
+            let expressions: Option<Vec<String>> =
+                expressions.iter().map(|expr| flat(trivia, expr)).collect();
+            expressions?.join("; ")
+        }
+        AST::If(conds, els, _, _) => {
+            let mut s = String::new();
+            for (i, (cond, then)) in conds.iter().enumerate() {
+                let keyword = if i == 0 { "if" } else { " elsif" };
+                s.push_str(&format!(
+                    "{} {} then {}",
+                    keyword,
+                    flat(trivia, cond)?,
+                    flat(trivia, then)?
+                ));
+            }
+            match els {
+                Some(els) => format!("{} else {} end", s, flat(trivia, els)?),
+                None => format!("{} end", s),
+            }
+        }
+        AST::Let(id, value, body, _, _) => {
+            format!(
+                "let {} = {} in {}",
+                flat(trivia, id)?,
+                flat(trivia, value)?,
+                flat(trivia, body)?
+            )
+        }
+        AST::Try(body, id, handler, _, _) => {
+            format!(
+                "try {} catch {} -> {} end",
+                flat(trivia, body)?,
+                flat(trivia, id)?,
+                flat(trivia, handler)?
+            )
+        }
+        AST::When(feature, body, els, _, _) => match els {
+            Some(els) => format!(
+                "when feature = {} then {} else {} end",
+                vm::Value::Str(feature.to_string()),
+                flat(trivia, body)?,
+                flat(trivia, els)?
+            ),
+            None => format!(
+                "when feature = {} then {} end",
+                vm::Value::Str(feature.to_string()),
+                flat(trivia, body)?
+            ),
+        },
+        AST::Program(expressions, _, _)
+            if expressions.len() == 1 && !trivia.has_comments(parser::start(&expressions[0])) =>
+        {
+            flat(trivia, &expressions[0])?
+        }
+        AST::Letrec(..) | AST::Match(..) | AST::Program(..) => return None,
+        _ => render(trivia, ast, 0),
+    };
+    if s.contains('\n') {
+        None
+    } else {
+        Some(s)
+    }
+}
+
+fn fits(trivia: &Trivia, ast: &AST, indent: usize) -> Option<String> {
+    flat(trivia, ast).filter(|s| indent + s.len() <= WIDTH)
+}
+
+// Writes an expression starting at the given indentation, which each line
+// after the first is indented by.
+fn render(trivia: &Trivia, ast: &AST, indent: usize) -> String {
+    let inner = indent + INDENT;
+    match ast {
+        // Powers are right associative, and their exponent may be negated.
+        AST::BinaryOp(Operator::Power, lhs, rhs, _, _) => format!(
+            "{} ** {}",
+            operand(trivia, lhs, 8, indent),
+            operand(trivia, rhs, 6, indent)
+        ),
+        // Consing is right associative as well.
+        AST::BinaryOp(Operator::Cons, lhs, rhs, _, _) => format!(
+            "{} :: {}",
+            operand(trivia, lhs, 4, indent),
+            operand(trivia, rhs, 3, indent)
+        ),
+        // Ranges are written without spaces, as in 1..10.
+        AST::BinaryOp(Operator::Range, lhs, rhs, _, _) => format!(
+            "{}..{}",
+            operand(trivia, lhs, 2, indent),
+            operand(trivia, rhs, 3, indent)
+        ),
+        AST::BinaryOp(op, lhs, rhs, _, _) => {
+            let prec = precedence(ast);
+            format!(
+                "{} {} {}",
+                operand(trivia, lhs, prec, indent),
+                op,
+                operand(trivia, rhs, prec + 1, indent)
+            )
+        }
+        AST::Boolean(b, _, _) => b.to_string(),
+        AST::BigInt(n, _, _) => format!("{}n", n),
+        AST::Bytes(bytes, _, _) => vm::Value::Bytes(bytes.clone()).to_string(),
+        AST::Char(c, _, _) => vm::Value::Char(*c).to_string(),
+        AST::Call(..) => after(trivia, String::new(), ast, indent),
+        AST::Datatype(name, variants, _, _) => {
+            let variants: Vec<String> = variants
+                .iter()
+                .map(|(id, param)| variant(trivia, id, param, inner))
+                .collect();
+            let s = format!("type {} := {} end", name, variants.join(" | "));
+            if indent + s.len() <= WIDTH {
+                s
+            } else {
+                format!(
+                    "type {} :=\n{}{}\n{}end",
+                    name,
+                    pad(inner),
+                    variants.join(&format!("\n{}| ", pad(inner))),
+                    pad(indent)
+                )
+            }
+        }
+        AST::Defer(value, _, _) => format!("defer {}", render(trivia, value, indent)),
+        // A value that does not fit after the name is written on the next
+        // line if it fits there.
+        AST::Define(id, value, _, _) => {
+            let prefix = format!("def {} :=", render(trivia, id, indent));
+            match (fits(trivia, ast, indent), fits(trivia, value, inner)) {
+                (Some(s), _) => s,
+                (None, Some(value)) => format!("{}\n{}{}", prefix, pad(inner), value),
+                (None, None) => after(trivia, prefix + " ", value, indent),
+            }
+        }
+        AST::Field(record, field, _, _) => {
+            format!("{}.{}", operand(trivia, record, 7, indent), field)
+        }
+        AST::Float(n, _, _) => vm::format_float(*n),
+        AST::Function(name, param, body, _, _) => match fits(trivia, ast, indent) {
+            Some(s) => s,
+            None => format!(
+                "{}\n{}{}\n{}end",
+                function(trivia, name, param),
+                pad(inner),
+                render(trivia, body, inner),
+                pad(indent)
+            ),
+        },
+        AST::For(param, iter, body, _, _) => match fits(trivia, ast, indent) {
+            Some(s) => s,
+            None => format!(
+                "for {} in {} do\n{}{}\n{}end",
+                render(trivia, param, indent),
+                render(trivia, iter, indent),
+                pad(inner),
+                render(trivia, body, inner),
+                pad(indent)
+            ),
+        },
+        AST::While(cond, body, _, _) => match fits(trivia, ast, indent) {
+            Some(s) => s,
+            None => format!(
+                "while {} do\n{}{}\n{}end",
+                render(trivia, cond, indent),
+                pad(inner),
+                render(trivia, body, inner),
+                pad(indent)
+            ),
+        },
+        AST::Block(body, _, _) => match fits(trivia, ast, indent) {
+            Some(s) => s,
+            None => format!(
+                "do\n{}{}\n{}end",
+                pad(inner),
+                render(trivia, body, inner),
+                pad(indent)
+            ),
+        },
+        AST::Identifier(id, _, _) => id.to_string(),
+        AST::If(conds, els, _, _) => match fits(trivia, ast, indent) {
+            Some(s) => s,
+            None => {
+                let mut s = String::new();
+                for (i, (cond, then)) in conds.iter().enumerate() {
+                    let keyword = if i == 0 { "if" } else { "elsif" };
+                    s.push_str(&format!(
+                        "{} {} then\n{}{}\n{}",
+                        keyword,
+                        render(trivia, cond, indent),
+                        pad(inner),
+                        render(trivia, then, inner),
+                        pad(indent)
+                    ));
+                }
+                match els {
+                    Some(els) => format!(
+                        "{}else\n{}{}\n{}end",
+                        s,
+                        pad(inner),
+                        render(trivia, els, inner),
+                        pad(indent)
+                    ),
+                    None => format!("{}end", s),
+                }
+            }
+        },
+        AST::Integer(n, _, _) => n.to_string(),
+        AST::Let(id, value, body, _, _) => match fits(trivia, ast, indent) {
+            Some(s) => s,
+            None => format!(
+                "let {} = {} in\n{}{}",
+                render(trivia, id, indent),
+                render(trivia, value, indent),
+                pad(indent),
+                render(trivia, body, indent)
+            ),
+        },
+        AST::Letrec(functions, _, _) => {
+            let mut s = "letrec\n".to_string();
+            for function in functions {
+                s.push_str(&format!(
+                    "{}{}\n",
+                    pad(inner),
+                    render(trivia, function, inner)
+                ));
+            }
+            format!("{}{}end", s, pad(indent))
+        }
+        AST::Match(value, cases, _, _) => {
+            let mut s = format!("match {} with\n", render(trivia, value, indent));
+            for (i, (id, param, guard, expr)) in cases.iter().enumerate() {
+                let bar = if i == 0 { "" } else { "| " };
+                let mut pattern = variant(trivia, id, param, inner);
+                if let Some(guard) = guard {
+                    pattern.push_str(&format!(" when {}", render(trivia, guard, inner)));
+                }
+                let line = format!("{}{}{} -> ", pad(inner), bar, pattern);
+                match fits(trivia, expr, line.len()) {
+                    Some(expr) => s.push_str(&format!("{}{}\n", line, expr)),
+                    None => s.push_str(&format!(
+                        "{}{}{} ->\n{}{}\n",
+                        pad(inner),
+                        bar,
+                        pattern,
+                        pad(inner + INDENT),
+                        render(trivia, expr, inner + INDENT)
+                    )),
+                }
+            }
+            format!("{}{}end", s, pad(indent))
+        }
+        AST::Program(expressions, _, _) => {
+            let mut s = String::new();
+            for (i, expr) in expressions.iter().enumerate() {
+                if i > 0 {
+                    s.push('\n');
+                    if trivia.blank_before.contains(&parser::start(expr)) {
+                        s.push('\n');
+                    }
+                    s.push_str(&pad(indent));
+                }
+                s.push_str(&statement(trivia, expr, indent));
+            }
+            s
+        }
+        AST::Sequence(expressions, _, _) => match fits(trivia, ast, indent) {
+            Some(s) => s,
+            None => {
+                let lines: Vec<String> = expressions
+                    .iter()
+                    .map(|expr| render(trivia, expr, indent))
+                    .collect();
+                lines.join(&format!(";\n{}", pad(indent)))
+            }
+        },
+        AST::Propagate(value, _, _) => format!("{}?", operand(trivia, value, 7, indent)),
+        AST::Map(entries, _, _) if entries.is_empty() => "%{}".to_string(),
+        AST::Map(entries, _, _) => {
+            let entries: Vec<String> = entries
+                .iter()
+                .map(|(key, value)| {
+                    let key = render(trivia, key, indent);
+                    format!("{} => {}", key, render(trivia, value, indent))
+                })
+                .collect();
+            format!("%{{ {} }}", entries.join(", "))
+        }
+        AST::Record(record, _, _) => format!("{{ {} }}", fields(trivia, record, indent)),
+        AST::String(s, _, _) => vm::Value::Str(s.to_string()).to_string(),
+        AST::Try(body, id, handler, _, _) => match fits(trivia, ast, indent) {
+            Some(s) => s,
+            None => format!(
+                "try\n{}{}\n{}catch {} ->\n{}{}\n{}end",
+                pad(inner),
+                render(trivia, body, inner),
+                pad(indent),
+                render(trivia, id, indent),
+                pad(inner),
+                render(trivia, handler, inner),
+                pad(indent)
+            ),
+        },
+        AST::Tuple(elements, _, _) if elements.len() == 1 => {
+            format!("({},)", render(trivia, &elements[0], indent))
+        }
+        AST::Array(..) | AST::List(..) | AST::Tuple(..) => fits(trivia, ast, indent)
+            .or_else(|| broken(trivia, ast, indent))
+            .unwrap_or_else(|| flat(trivia, ast).unwrap_or_default()),
+        AST::UnaryOp(op, value, _, _) => format!("{}{}", op, operand(trivia, value, 6, indent)),
+        AST::Unit(_, _) => "()".to_string(),
+        AST::Update(record, updates, _, _) => format!(
+            "{{ {} with {} }}",
+            render(trivia, record, indent),
+            fields(trivia, updates, indent)
+        ),
+        AST::When(feature, body, els, _, _) => match fits(trivia, ast, indent) {
+            Some(s) => s,
+            None => {
+                let mut s = format!(
+                    "when feature = {} then\n{}{}\n{}",
+                    vm::Value::Str(feature.to_string()),
+                    pad(inner),
+                    render(trivia, body, inner),
+                    pad(indent)
+                );
+                if let Some(els) = els {
+                    s.push_str(&format!(
+                        "else\n{}{}\n{}",
+                        pad(inner),
+                        render(trivia, els, inner),
+                        pad(indent)
+                    ));
+                }
+                format!("{}end", s)
+            }
+        },
+    }
+}
+
+// Writes an expression in a program or body along with its comments, those
+// on lines of their own before and after it and others at the end of its last
+// line.
+fn statement(trivia: &Trivia, ast: &AST, indent: usize) -> String {
+    let start = parser::start(ast);
+    let mut s = String::new();
+    for comment in trivia.leading.get(&start).into_iter().flatten() {
+        if comment.blank_after {
+            s.push_str(&format!("{}\n\n{}", comment.text, pad(indent)));
+        } else {
+            s.push_str(&format!("{}\n{}", comment.text, pad(indent)));
+        }
+    }
+    s.push_str(&render(trivia, ast, indent));
+    for comment in trivia.trailing.get(&start).into_iter().flatten() {
+        if comment.own_line {
+            s.push_str(&format!("\n{}{}", pad(indent), comment.text));
+        } else {
+            s.push_str(&format!(" {}", comment.text));
+        }
+    }
+    s
+}
+
+// Writes a program in the canonical layout, which parses back to the same
+// program. Top level expressions are separated by a blank line when either of
+// them spans several lines, or when there was one between them before.
+pub fn format(ast: &AST) -> String {
+    format_with_comments(ast, &Trivia::default())
+}
+
+// Writes a program in the canonical layout along with the comments found by
+// parser::parse_with_comments.
+pub fn format_with_comments(ast: &AST, trivia: &Trivia) -> String {
+    let expressions = match ast {
+        AST::Program(expressions, _, _) => expressions.iter().collect(),
+        _ => vec![ast],
+    };
+    let mut s = String::new();
+    if expressions.is_empty() {
+        for comment in trivia
+            .leading
+            .get(&parser::start(ast))
+            .into_iter()
+            .flatten()
+        {
+            s.push_str(&format!("{}\n", comment.text));
+        }
+    }
+    let mut multiline = false;
+    for (i, expr) in expressions.iter().enumerate() {
+        let rendered = statement(trivia, expr, 0);
+        if i > 0 {
+            s.push('\n');
+            if multiline
+                || rendered.contains('\n')
+                || trivia.blank_before.contains(&parser::start(expr))
+            {
+                s.push('\n');
+            }
+        }
+        multiline = rendered.contains('\n');
+        s.push_str(&rendered);
+    }
+    if !expressions.is_empty() {
+        s.push('\n');
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::formatter::{format, format_with_comments};
+    use crate::parser;
+
+    macro_rules! formats {
+        ($input:expr, $expected:expr) => {{
+            let ast = parser::parse($input).ok().unwrap();
+            let formatted = format(&ast);
+            assert_eq!(formatted, $expected);
+            let reparsed = parser::parse(&formatted).ok().unwrap();
+            assert_eq!(reparsed.to_string(), ast.to_string());
+            assert_eq!(format(&reparsed), formatted);
+        }};
+    }
+
+    #[test]
+    fn expressions() {
+        formats!("1+2*3", "1 + 2 * 3\n");
+        formats!("(1+2)*3", "(1 + 2) * 3\n");
+        formats!("1-(2-3)", "1 - (2 - 3)\n");
+        formats!("(1-2)-3", "1 - 2 - 3\n");
+        formats!("-(1+2) + ~true", "-(1 + 2) + ~true\n");
+        formats!("f((1,2))", "f (1, 2)\n");
+        formats!("f(())", "f ()\n");
+        formats!("(f(1))(2)", "(f (1)) (2)\n");
+        formats!("(fn x->x end)(1)", "(fn (x) -> x end) (1)\n");
+        formats!("(1,)", "(1,)\n");
+        formats!("t.0.1", "t.0.1\n");
+        formats!("[1,2,[]]", "[1, 2, []]\n");
+        formats!("f([1,2])", "f ([1, 2])\n");
+        formats!("%{1=>\"a\",2=>x+1}", "%{ 1 => \"a\", 2 => x + 1 }\n");
+        formats!("insert(%{},k,v)", "insert (%{}, k, v)\n");
+        formats!("%[1,2]", "%[1, 2]\n");
+        formats!(
+            "(1::2::%[])::(3+4::%[])",
+            "(1 :: 2 :: %[]) :: 3 + 4 :: %[]\n"
+        );
+        formats!("(a==b)::l", "(a == b) :: l\n");
+        formats!("(0 .. n)==(a+1..b)", "0..n == a + 1..b\n");
+        formats!("for x in 1..4 do x*x end", "for x in 1..4 do x * x end\n");
+        formats!("while i<4 do i end", "while i < 4 do i end\n");
+        formats!("do f(x) end", "do f (x) end\n");
+        formats!("print(1) ;print(2);  3", "print (1); print (2); 3\n");
+        formats!(
+            "while i < 4 do print (i) def i := i + 1 end",
+            "while i < 4 do\n    print (i)\n    def i := i + 1\nend\n"
+        );
+        formats!(
+            "fn f (x) -> defer close(x) read(x) end",
+            "fn f (x) ->\n    defer close (x)\n    read (x)\nend\n"
+        );
+        formats!(
+            "for (first,second) in pairs_of_numbers do let total = first + second in total * total end",
+            "for (first, second) in pairs_of_numbers do\n    let total = first + second in total * total\nend\n"
+        );
+        formats!(
+            "match l with x::xs -> x | %[] -> 0 end",
+            "match l with\n    x :: xs -> x\n    | %[] -> 0\nend\n"
+        );
+        formats!(
+            "match l with x::xs when x>0 -> x | _  when   ok -> 1 | _ -> 0 end",
+            "match l with\n    x :: xs when x > 0 -> x\n    | _ when ok -> 1\n    | _ -> 0\nend\n"
+        );
+        formats!("let (a,b) = t in a", "let (a, b) = t in a\n");
+        formats!("{a=1,b=\"x\\n\"}.a", "{ a = 1, b = \"x\\n\" }.a\n");
+        formats!("{ {x=1} with x=2 }", "{ { x = 1 } with x = 2 }\n");
+        formats!("(r.f)(1)", "(r.f) (1)\n");
+        formats!("f(('\\'','\\t'))", "f ('\\'', '\\t')\n");
+        formats!("-f(x)? + (a+b)?", "-f (x)? + (a + b)?\n");
+        formats!(
+            "(-2)**(3**2)*(2**3)**-1",
+            "(-2) ** 3 ** 2 * (2 ** 3) ** -1\n"
+        );
+        formats!(
+            "1.5 2e10 3n b\"\\x00a\"",
+            "1.5\n20000000000.0\n3n\nb\"\\0a\"\n"
+        );
+    }
+
+    #[test]
+    fn layout() {
+        formats!(
+            "def x:=1 def y:=2 fn f(a,b)->def c:=a+b c*2 end f(x,y)",
+            "def x := 1\ndef y := 2\n\nfn f (a, b) ->\n    def c := a + b\n    c * 2\nend\n\nf (x, y)\n"
+        );
+        formats!(
+            "if a then 1 elsif b then 2 else 3 end",
+            "if a then 1 elsif b then 2 else 3 end\n"
+        );
+        formats!(
+            "if a then f (1) elif b then g (2) end",
+            "if a then f (1) elsif b then g (2) end\n"
+        );
+        formats!("try f(x) catch e->0 end", "try f (x) catch e -> 0 end\n");
+        formats!(
+            "try some_longer_function_name (x, y) catch err -> another_longer_function_name (err) end",
+            "try\n    some_longer_function_name (x, y)\ncatch err ->\n    another_longer_function_name (err)\nend\n"
+        );
+        formats!(
+            "fn f (x) -> if x == 0 then some_longer_function_name (x) else another_longer_function_name (x - 1) end end",
+            "fn f (x) ->\n    if x == 0 then\n        some_longer_function_name (x)\n    else\n        another_longer_function_name (x - 1)\n    end\nend\n"
+        );
+        formats!(
+            "type T := A (integer, b) | B end match A (1, 2) with A (x, y) -> x | _ -> 0 end",
+            "type T := A (integer, b) | B end\n\nmatch A (1, 2) with\n    A (x, y) -> x\n    | _ -> 0\nend\n"
+        );
+        formats!(
+            "letrec fn even (n) -> if n == 0 then true else odd (n - 1) end end
+             fn odd (n) -> if n == 0 then false else even (n - 1) end end end",
+            "letrec\n    fn even (n) -> if n == 0 then true else odd (n - 1) end end\n    fn odd (n) -> if n == 0 then false else even (n - 1) end end\nend\n"
+        );
+        formats!("let x = 1 in x + 1", "let x = 1 in x + 1\n");
+        formats!(
+            "when feature = \"debug\" then print (1) 2 else 3 end",
+            "when feature = \"debug\" then\n    print (1)\n    2\nelse\n    3\nend\n"
+        );
+        formats!(
+            "some_longer_function_name (first_argument, second_argument, [third, fourth, fifth])",
+            "some_longer_function_name (\n    first_argument,\n    second_argument,\n    [third, fourth, fifth]\n)\n"
+        );
+        formats!(
+            "fn f (x) -> g ([some_longer_element_name, another_longer_element_name, yet_another_element_name]) end",
+            "fn f (x) ->\n    g ([\n        some_longer_element_name,\n        another_longer_element_name,\n        yet_another_element_name\n    ])\nend\n"
+        );
+        formats!(
+            "def numbers := Cons (5, Cons (3, Cons (9, Cons (1, Cons (4, Cons (8, Cons (2, Nil)))))))",
+            "def numbers :=\n    Cons (5, Cons (3, Cons (9, Cons (1, Cons (4, Cons (8, Cons (2, Nil)))))))\n"
+        );
+        formats!(
+            "def t := (some_longer_element_name, another_longer_element_name, yet_another_one, one_more)",
+            "def t := (\n    some_longer_element_name,\n    another_longer_element_name,\n    yet_another_one,\n    one_more\n)\n"
+        );
+    }
+
+    #[test]
+    fn comments() {
+        let formats = |input: &str, expected: &str| {
+            let (ast, trivia) = parser::parse_with_comments(input).ok().unwrap();
+            let formatted = format_with_comments(&ast, &trivia);
+            assert_eq!(formatted, expected);
+            let (reparsed, trivia) = parser::parse_with_comments(&formatted).ok().unwrap();
+            assert_eq!(reparsed.to_string(), ast.to_string());
+            assert_eq!(format_with_comments(&reparsed, &trivia), formatted);
+        };
+        formats("# a\n/* b */ 1+2 # c\n", "# a\n/* b */\n1 + 2 # c\n");
+        formats(
+            "def f := fn x -> # doc\n x end f (1)",
+            "def f := fn (x) ->\n    # doc\n    x\nend\n\nf (1)\n",
+        );
+        formats(
+            "fn f () -> 1 // one\n# end\nend",
+            "fn f () ->\n    1 // one\n    # end\nend\n",
+        );
+        formats("f (1, # one\n 2)", "# one\nf (1, 2)\n");
+        formats(
+            "# only comments\n\n/* here */",
+            "# only comments\n/* here */\n",
+        );
+        formats("", "");
+        formats(
+            "def x := 1\n\n\ndef y := 2\ndef z := 3\n\n# w\ndef w := 4",
+            "def x := 1\n\ndef y := 2\ndef z := 3\n\n# w\ndef w := 4\n",
+        );
+        formats(
+            "# header\n\ndef x := 1\n# a\n\n# b\ndef y := 2",
+            "# header\n\ndef x := 1\n\n# a\n\n# b\ndef y := 2\n",
+        );
+        formats(
+            "fn f () ->\n    def a := 1\n\n    def b := 2\n    a + b\nend",
+            "fn f () ->\n    def a := 1\n\n    def b := 2\n    a + b\nend\n",
+        );
+    }
+}
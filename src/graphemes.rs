@@ -0,0 +1,132 @@
+// Splits text into extended grapheme clusters, the characters that a reader
+// sees, following the rules of Unicode Standard Annex #29. The properties of
+// each character are taken from the Unicode tables that pest includes, which
+// approximate the grapheme cluster break property closely enough for text
+// such as accented letters, Hangul and emoji sequences.
+use pest::unicode;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Cr,
+    Lf,
+    Control,
+    Extend,
+    Zwj,
+    RegionalIndicator,
+    Prepend,
+    SpacingMark,
+    L,
+    V,
+    T,
+    Lv,
+    Lvt,
+    Pictographic,
+    Other,
+}
+
+fn kind(c: char) -> Kind {
+    match c {
+        '\r' => Kind::Cr,
+        '\n' => Kind::Lf,
+        '\u{200d}' => Kind::Zwj,
+        '\u{1100}'..='\u{115f}' | '\u{a960}'..='\u{a97c}' => Kind::L,
+        '\u{1160}'..='\u{11a7}' | '\u{d7b0}'..='\u{d7c6}' => Kind::V,
+        '\u{11a8}'..='\u{11ff}' | '\u{d7cb}'..='\u{d7fb}' => Kind::T,
+        // Precomposed syllables have a trailing consonant unless they fall
+        // on the first of each run of 28.
+        '\u{ac00}'..='\u{d7a3}' if (u32::from(c) - 0xac00) % 28 == 0 => Kind::Lv,
+        '\u{ac00}'..='\u{d7a3}' => Kind::Lvt,
+        _ if unicode::GRAPHEME_EXTEND(c) || unicode::EMOJI_MODIFIER(c) => Kind::Extend,
+        _ if unicode::REGIONAL_INDICATOR(c) => Kind::RegionalIndicator,
+        _ if unicode::PREPENDED_CONCATENATION_MARK(c) => Kind::Prepend,
+        _ if unicode::SPACING_MARK(c) => Kind::SpacingMark,
+        _ if unicode::CONTROL(c)
+            || unicode::FORMAT(c)
+            || unicode::LINE_SEPARATOR(c)
+            || unicode::PARAGRAPH_SEPARATOR(c) =>
+        {
+            Kind::Control
+        }
+        _ if unicode::EXTENDED_PICTOGRAPHIC(c) => Kind::Pictographic,
+        _ => Kind::Other,
+    }
+}
+
+// Splits a string into its grapheme clusters, in order.
+pub fn split(s: &str) -> Vec<&str> {
+    let mut clusters = Vec::new();
+    let mut chars = s.char_indices();
+    let mut prev = match chars.next() {
+        Some((_, c)) => kind(c),
+        None => return clusters,
+    };
+    let mut start = 0;
+    // The regional indicators in a row before the current character, and
+    // whether it follows a pictograph with only extenders and a zero width
+    // joiner after it.
+    let mut indicators = usize::from(prev == Kind::RegionalIndicator);
+    let mut pictograph = prev == Kind::Pictographic;
+    let mut joined = false;
+    for (i, c) in chars {
+        let next = kind(c);
+        let join = match (prev, next) {
+            (Kind::Cr, Kind::Lf) => true,
+            (Kind::Cr | Kind::Lf | Kind::Control, _) => false,
+            (_, Kind::Cr | Kind::Lf | Kind::Control) => false,
+            (Kind::L, Kind::L | Kind::V | Kind::Lv | Kind::Lvt) => true,
+            (Kind::Lv | Kind::V, Kind::V | Kind::T) => true,
+            (Kind::Lvt | Kind::T, Kind::T) => true,
+            (_, Kind::Extend | Kind::Zwj | Kind::SpacingMark) => true,
+            (Kind::Prepend, _) => true,
+            (Kind::Zwj, Kind::Pictographic) => joined,
+            (Kind::RegionalIndicator, Kind::RegionalIndicator) => indicators % 2 == 1,
+            _ => false,
+        };
+        if !join {
+            clusters.push(&s[start..i]);
+            start = i;
+        }
+        joined = pictograph && next == Kind::Zwj;
+        pictograph = match next {
+            Kind::Pictographic => true,
+            Kind::Extend => pictograph,
+            _ => false,
+        };
+        indicators = match next {
+            Kind::RegionalIndicator => indicators + 1,
+            _ => 0,
+        };
+        prev = next;
+    }
+    clusters.push(&s[start..]);
+    clusters
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::graphemes;
+
+    #[test]
+    fn split() {
+        assert_eq!(graphemes::split(""), Vec::<&str>::new());
+        assert_eq!(graphemes::split("abc"), vec!["a", "b", "c"]);
+        assert_eq!(
+            graphemes::split("e\u{301}t\u{e9}"),
+            vec!["e\u{301}", "t", "\u{e9}"]
+        );
+        assert_eq!(graphemes::split("a\r\nb"), vec!["a", "\r\n", "b"]);
+        assert_eq!(
+            graphemes::split("\u{1112}\u{1161}\u{11ab}\u{d55c}"),
+            vec!["\u{1112}\u{1161}\u{11ab}", "\u{d55c}"]
+        );
+        assert_eq!(
+            graphemes::split("\u{1f469}\u{1f3fd}\u{200d}\u{1f4bb}!"),
+            vec!["\u{1f469}\u{1f3fd}\u{200d}\u{1f4bb}", "!"]
+        );
+        assert_eq!(
+            graphemes::split("\u{1f1eb}\u{1f1f7}\u{1f1ef}\u{1f1f5}\u{1f1fa}"),
+            vec!["\u{1f1eb}\u{1f1f7}", "\u{1f1ef}\u{1f1f5}", "\u{1f1fa}"]
+        );
+        assert_eq!(graphemes::split("a\u{200d}b"), vec!["a\u{200d}", "b"]);
+    }
+}
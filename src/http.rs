@@ -0,0 +1,234 @@
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+// A minimal HTTP/1.1 client for the http_get and http_post builtins. Only
+// plain http URLs are supported, and every request closes its connection.
+
+pub struct Response {
+    pub status: i64,
+    // The header lines as "name: value", with the names in lowercase.
+    pub headers: Vec<String>,
+    pub body: Vec<u8>,
+}
+
+pub enum Error {
+    Timeout,
+    Failed(String),
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        match err.kind() {
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => Error::Timeout,
+            _ => Error::Failed(err.to_string()),
+        }
+    }
+}
+
+fn fail<T>(msg: &str) -> Result<T, Error> {
+    Err(Error::Failed(msg.to_string()))
+}
+
+// Splits a URL into the host, port and path.
+fn split_url(url: &str) -> Result<(&str, u16, &str), Error> {
+    let rest = match url.strip_prefix("http://") {
+        Some(rest) => rest,
+        None => return fail("Only http URLs are supported"),
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((_, port)) if port.ends_with(']') => (authority, 80),
+        Some((host, port)) => match port.parse() {
+            Ok(port) => (host, port),
+            Err(_) => return fail("Invalid port"),
+        },
+        None => (authority, 80),
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() || path.contains(|c: char| c.is_whitespace() || c.is_control()) {
+        return fail("Invalid URL");
+    }
+    Ok((host, port, path))
+}
+
+fn remaining(deadline: Instant) -> Result<Duration, Error> {
+    match deadline.checked_duration_since(Instant::now()) {
+        Some(left) if !left.is_zero() => Ok(left),
+        _ => Err(Error::Timeout),
+    }
+}
+
+fn connect(host: &str, port: u16, deadline: Instant) -> Result<TcpStream, Error> {
+    let mut last = Error::Failed("Could not resolve host".to_string());
+    for addr in (host, port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, remaining(deadline)?) {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last = err.into(),
+        }
+    }
+    Err(last)
+}
+
+// Decodes a body sent with chunked transfer encoding, ignoring any trailers.
+fn dechunk(mut data: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut body = Vec::new();
+    loop {
+        let end = match data.windows(2).position(|w| w == b"\r\n") {
+            Some(end) => end,
+            None => return fail("Invalid chunked body"),
+        };
+        let line = String::from_utf8_lossy(&data[..end]);
+        let size = line.split(';').next().unwrap_or("").trim();
+        let size = match usize::from_str_radix(size, 16) {
+            Ok(size) => size,
+            Err(_) => return fail("Invalid chunked body"),
+        };
+        data = &data[end + 2..];
+        if size == 0 {
+            return Ok(body);
+        }
+        // The size comes from the server, so it may be larger than any body.
+        if data.len().saturating_sub(2) < size {
+            return fail("Invalid chunked body");
+        }
+        body.extend(&data[..size]);
+        data = &data[size + 2..];
+    }
+}
+
+fn parse_response(data: &[u8]) -> Result<Response, Error> {
+    let end = match data.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(end) => end,
+        None => return fail("Invalid response"),
+    };
+    let head = String::from_utf8_lossy(&data[..end]);
+    let mut lines = head.split("\r\n");
+    let status = lines
+        .next()
+        .and_then(|line| line.strip_prefix("HTTP/1."))
+        .and_then(|line| line.split(' ').nth(1))
+        .and_then(|status| status.parse().ok());
+    let status = match status {
+        Some(status) => status,
+        None => return fail("Invalid response"),
+    };
+    let mut headers = Vec::new();
+    let mut chunked = false;
+    let mut length = None;
+    for line in lines {
+        let (name, value) = match line.split_once(':') {
+            Some((name, value)) => (name.trim().to_lowercase(), value.trim()),
+            None => return fail("Invalid response"),
+        };
+        if name == "transfer-encoding" && value.eq_ignore_ascii_case("chunked") {
+            chunked = true;
+        } else if name == "content-length" {
+            length = value.parse::<usize>().ok();
+        }
+        headers.push(format!("{}: {}", name, value));
+    }
+    let body = &data[end + 4..];
+    let body = if chunked {
+        dechunk(body)?
+    } else {
+        body[..length.unwrap_or(body.len()).min(body.len())].to_vec()
+    };
+    Ok(Response {
+        status,
+        headers,
+        body,
+    })
+}
+
+// Sends a request and reads the whole response, failing with a timeout if it
+// does not finish in time.
+pub fn request(
+    method: &str,
+    url: &str,
+    body: Option<(&str, &[u8])>,
+    timeout: Duration,
+) -> Result<Response, Error> {
+    let deadline = Instant::now() + timeout;
+    let (host, port, path) = split_url(url)?;
+    let mut stream = connect(host, port, deadline)?;
+    let host_header = match (host.contains(':'), port) {
+        (true, 80) => format!("[{}]", host),
+        (true, _) => format!("[{}]:{}", host, port),
+        (false, 80) => host.to_string(),
+        (false, _) => format!("{}:{}", host, port),
+    };
+    let mut request = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: plover\r\nConnection: close\r\n",
+        method, path, host_header
+    );
+    if let Some((content_type, body)) = body {
+        request.push_str(&format!(
+            "Content-Type: {}\r\nContent-Length: {}\r\n",
+            content_type,
+            body.len()
+        ));
+    }
+    request.push_str("\r\n");
+    let mut bytes = request.into_bytes();
+    if let Some((_, body)) = body {
+        bytes.extend(body);
+    }
+    stream.set_write_timeout(Some(remaining(deadline)?))?;
+    stream.write_all(&bytes)?;
+
+    let mut data = Vec::new();
+    let mut buf = [0; 8192];
+    loop {
+        stream.set_read_timeout(Some(remaining(deadline)?))?;
+        match stream.read(&mut buf)? {
+            0 => break,
+            n => data.extend(&buf[..n]),
+        }
+    }
+    parse_response(&data)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::http::{dechunk, parse_response, split_url};
+
+    #[test]
+    fn urls() {
+        assert!(matches!(
+            split_url("http://example.com"),
+            Ok(("example.com", 80, "/"))
+        ));
+        assert!(matches!(
+            split_url("http://127.0.0.1:8080/a?b=c"),
+            Ok(("127.0.0.1", 8080, "/a?b=c"))
+        ));
+        assert!(matches!(split_url("http://[::1]/"), Ok(("::1", 80, "/"))));
+        assert!(matches!(split_url("http://[::1]:81"), Ok(("::1", 81, "/"))));
+        assert!(split_url("https://example.com/").is_err());
+        assert!(split_url("http://:80/").is_err());
+        assert!(split_url("http://host/a b").is_err());
+    }
+
+    #[test]
+    fn responses() {
+        let response =
+            parse_response(b"HTTP/1.1 404 Not Found\r\nContent-Length: 2\r\nX-A: b\r\n\r\nhi!")
+                .ok()
+                .unwrap();
+        assert_eq!(response.status, 404);
+        assert_eq!(response.headers, vec!["content-length: 2", "x-a: b"]);
+        assert_eq!(response.body, b"hi");
+        assert_eq!(
+            dechunk(b"3\r\nabc\r\n1;x=y\r\nd\r\n0\r\n\r\n").ok(),
+            Some(b"abcd".to_vec())
+        );
+        assert!(dechunk(b"5\r\nabc\r\n").is_err());
+        assert!(dechunk(b"ffffffffffffffff\r\nabc\r\n0\r\n\r\n").is_err());
+        assert!(dechunk(b"1\r\n").is_err());
+        assert!(parse_response(b"SMTP 200\r\n\r\n").is_err());
+    }
+}
@@ -0,0 +1,146 @@
+// A small reader and writer for the JSON used by the command line, such as
+// benchmark results and the messages of the REPL's JSON mode.
+use std::collections::HashMap;
+use std::fmt::Write;
+
+// Reads a JSON value that makes up the whole of a string.
+pub fn parse(s: &str) -> Option<Json> {
+    let mut parser = Parser {
+        chars: s.chars().peekable(),
+    };
+    let json = parser.value()?;
+    parser.skip_whitespace();
+    if parser.chars.next().is_some() {
+        return None;
+    }
+    Some(json)
+}
+
+// Writes a string as a JSON string.
+pub fn quote(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// Only the kernel reads the values of booleans.
+#[cfg_attr(not(feature = "kernel"), allow(dead_code))]
+pub enum Json {
+    Null,
+    Boolean(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(HashMap<String, Json>),
+}
+
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+}
+
+impl Parser<'_> {
+    fn skip_whitespace(&mut self) {
+        while self.chars.next_if(|c| c.is_ascii_whitespace()).is_some() {}
+    }
+
+    fn literal(&mut self, word: &str, json: Json) -> Option<Json> {
+        for c in word.chars() {
+            self.chars.next_if_eq(&c)?;
+        }
+        Some(json)
+    }
+
+    fn value(&mut self) -> Option<Json> {
+        self.skip_whitespace();
+        match self.chars.peek()? {
+            'n' => self.literal("null", Json::Null),
+            't' => self.literal("true", Json::Boolean(true)),
+            'f' => self.literal("false", Json::Boolean(false)),
+            '"' => self.string().map(Json::String),
+            '[' => {
+                self.chars.next();
+                let mut elements = Vec::new();
+                self.skip_whitespace();
+                if self.chars.next_if_eq(&']').is_none() {
+                    loop {
+                        elements.push(self.value()?);
+                        self.skip_whitespace();
+                        match self.chars.next()? {
+                            ',' => {}
+                            ']' => break,
+                            _ => return None,
+                        }
+                    }
+                }
+                Some(Json::Array(elements))
+            }
+            '{' => {
+                self.chars.next();
+                let mut fields = HashMap::new();
+                self.skip_whitespace();
+                if self.chars.next_if_eq(&'}').is_none() {
+                    loop {
+                        self.skip_whitespace();
+                        let key = self.string()?;
+                        self.skip_whitespace();
+                        self.chars.next_if_eq(&':')?;
+                        fields.insert(key, self.value()?);
+                        self.skip_whitespace();
+                        match self.chars.next()? {
+                            ',' => {}
+                            '}' => break,
+                            _ => return None,
+                        }
+                    }
+                }
+                Some(Json::Object(fields))
+            }
+            _ => {
+                let mut number = String::new();
+                while let Some(c) = self
+                    .chars
+                    .next_if(|c| c.is_ascii_digit() || "+-.eE".contains(*c))
+                {
+                    number.push(c);
+                }
+                number.parse().ok().map(Json::Number)
+            }
+        }
+    }
+
+    fn string(&mut self) -> Option<String> {
+        self.chars.next_if_eq(&'"')?;
+        let mut s = String::new();
+        loop {
+            match self.chars.next()? {
+                '"' => return Some(s),
+                '\\' => s.push(match self.chars.next()? {
+                    'n' => '\n',
+                    'r' => '\r',
+                    't' => '\t',
+                    'b' => '\u{8}',
+                    'f' => '\u{c}',
+                    'u' => {
+                        let hex: String = (0..4).filter_map(|_| self.chars.next()).collect();
+                        char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?
+                    }
+                    c => c,
+                }),
+                c => s.push(c),
+            }
+        }
+    }
+}
@@ -0,0 +1,683 @@
+// A Jupyter kernel, so that Plover can be used in notebooks. Jupyter starts the
+// kernel with a connection file naming the ports of its five channels and the
+// key that messages are signed with. Requests arrive on the shell and control
+// channels and are answered on the connection they came from, while what
+// happens as they run, such as printed output and results, is published to
+// every frontend on iopub. Cells are evaluated like entries in the JSON mode of
+// the REPL, so :fuel works in them too.
+use std::env;
+use std::fs;
+use std::io;
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::process;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use pest::unicode::{XID_CONTINUE, XID_START};
+use plover::{parser, time, typeinfer, vm};
+
+use crate::json::{self, quote, Json};
+use crate::zmq::Connection;
+
+// Separates the routing identities of a message from its signed parts.
+const DELIMITER: &[u8] = b"<IDS|MSG>";
+const PROTOCOL_VERSION: &str = "5.3";
+
+const ROUND: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+// Messages are signed with HMAC-SHA256, which is implemented here following
+// FIPS 180-4 and RFC 2104 rather than adding a dependency for it.
+fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut state: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+        0x5be0cd19,
+    ];
+    let mut padded = data.to_vec();
+    padded.push(0x80);
+    while padded.len() % 64 != 56 {
+        padded.push(0);
+    }
+    padded.extend((data.len() as u64 * 8).to_be_bytes());
+    for block in padded.chunks(64) {
+        let mut w = [0u32; 64];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = state;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(ROUND[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+        for (word, value) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *word = word.wrapping_add(value);
+        }
+    }
+    let mut digest = [0; 32];
+    for (i, word) in state.iter().enumerate() {
+        digest[i * 4..i * 4 + 4].copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+// The HMAC of the parts of a message, one after the other, as hexadecimal.
+fn sign(key: &[u8], parts: &[&[u8]]) -> String {
+    let mut block = [0; 64];
+    if key.len() > 64 {
+        block[..32].copy_from_slice(&sha256(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let mut inner: Vec<u8> = block.iter().map(|b| b ^ 0x36).collect();
+    for part in parts {
+        inner.extend(*part);
+    }
+    let mut outer: Vec<u8> = block.iter().map(|b| b ^ 0x5c).collect();
+    outer.extend(sha256(&inner));
+    sha256(&outer)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// Compares a signature with the one expected in time that only depends on
+// their lengths, so that how long a message takes to be rejected does not
+// tell how much of its signature was right.
+fn signature_matches(signature: &[u8], expected: &[u8]) -> bool {
+    signature.len() == expected.len()
+        && signature
+            .iter()
+            .zip(expected)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn field<'a>(json: &'a Json, name: &str) -> Option<&'a Json> {
+    match json {
+        Json::Object(fields) => fields.get(name),
+        _ => None,
+    }
+}
+
+fn string_field(json: &Json, name: &str) -> String {
+    match field(json, name) {
+        Some(Json::String(s)) => s.to_string(),
+        _ => String::new(),
+    }
+}
+
+fn bool_field(json: &Json, name: &str, default: bool) -> bool {
+    match field(json, name) {
+        Some(Json::Boolean(b)) => *b,
+        _ => default,
+    }
+}
+
+fn quote_all(lines: &[String]) -> String {
+    let quoted: Vec<String> = lines.iter().map(|line| quote(line)).collect();
+    format!("[{}]", quoted.join(", "))
+}
+
+struct Message {
+    // The routing identities that the message arrived with, which its
+    // replies are sent back with.
+    ids: Vec<Vec<u8>>,
+    // The header as it was sent, which becomes the parent header of the
+    // messages sent in response.
+    header: String,
+    msg_type: String,
+    username: String,
+    content: Json,
+}
+
+// Reads a message, checking its signature. Messages that are not signed with
+// the key are ignored, as anyone may be able to connect to the ports.
+fn parse_message(key: &[u8], frames: Vec<Vec<u8>>) -> Option<Message> {
+    let delimiter = frames.iter().position(|frame| frame == DELIMITER)?;
+    let (ids, parts) = frames.split_at(delimiter);
+    let [_, signature, header, parent, metadata, content, ..] = parts else {
+        return None;
+    };
+    if !key.is_empty()
+        && !signature_matches(
+            signature,
+            sign(key, &[header, parent, metadata, content]).as_bytes(),
+        )
+    {
+        return None;
+    }
+    let header = String::from_utf8(header.to_vec()).ok()?;
+    let parsed = json::parse(&header)?;
+    Some(Message {
+        ids: ids.to_vec(),
+        msg_type: string_field(&parsed, "msg_type"),
+        username: string_field(&parsed, "username"),
+        header,
+        content: json::parse(std::str::from_utf8(content).ok()?)?,
+    })
+}
+
+// The names that could complete the identifier before the cursor, which is
+// counted in characters, along with where the identifier starts.
+fn complete<'a>(
+    code: &str,
+    cursor: usize,
+    names: impl Iterator<Item = &'a str>,
+) -> (Vec<String>, usize) {
+    let chars: Vec<char> = code.chars().collect();
+    let cursor = cursor.min(chars.len());
+    let mut start = cursor;
+    while start > 0 && XID_CONTINUE(chars[start - 1]) {
+        start -= 1;
+    }
+    let prefix: String = chars[start..cursor].iter().collect();
+    let mut matches: Vec<String> = parser::KEYWORDS
+        .iter()
+        .copied()
+        .chain(names)
+        .filter(|name| {
+            name.starts_with(&prefix)
+                && name.chars().next().is_some_and(XID_START)
+                && name.chars().all(XID_CONTINUE)
+        })
+        .map(|name| name.to_string())
+        .collect();
+    matches.sort();
+    matches.dedup();
+    (matches, start)
+}
+
+struct Kernel {
+    key: Vec<u8>,
+    session: String,
+    sent: u64,
+    // The connections of the frontends subscribed to iopub.
+    subscribers: Arc<Mutex<Vec<Connection>>>,
+    execution_count: u64,
+    fuel: Option<u64>,
+    vm: vm::VirtualMachine,
+}
+
+impl Kernel {
+    fn frames(
+        &mut self,
+        ids: &[Vec<u8>],
+        parent: &Message,
+        msg_type: &str,
+        content: &str,
+    ) -> Vec<Vec<u8>> {
+        self.sent += 1;
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as i64);
+        let header = format!(
+            "{{\"msg_id\": {}, \"session\": {}, \"username\": {}, \"date\": {}, \"msg_type\": {}, \"version\": {}}}",
+            quote(&format!("{}-{}", self.session, self.sent)),
+            quote(&self.session),
+            quote(&parent.username),
+            quote(&time::format(millis, "%Y-%m-%dT%H:%M:%S.%fZ").unwrap_or_default()),
+            quote(msg_type),
+            quote(PROTOCOL_VERSION)
+        );
+        let signature = sign(
+            &self.key,
+            &[
+                header.as_bytes(),
+                parent.header.as_bytes(),
+                b"{}",
+                content.as_bytes(),
+            ],
+        );
+        let mut frames = ids.to_vec();
+        frames.extend([
+            DELIMITER.to_vec(),
+            signature.into_bytes(),
+            header.into_bytes(),
+            parent.header.as_bytes().to_vec(),
+            b"{}".to_vec(),
+            content.as_bytes().to_vec(),
+        ]);
+        frames
+    }
+
+    fn reply(
+        &mut self,
+        connection: &mut Connection,
+        parent: &Message,
+        msg_type: &str,
+        content: &str,
+    ) {
+        let frames = self.frames(&parent.ids, parent, msg_type, content);
+        // A frontend that has gone away can not be told anything.
+        let _ = connection.send(&frames);
+    }
+
+    // Sends a message to every frontend, forgetting those that have gone.
+    fn publish(&mut self, parent: &Message, msg_type: &str, content: &str) {
+        let topic = format!("kernel.{}.{}", self.session, msg_type).into_bytes();
+        let frames = self.frames(&[topic], parent, msg_type, content);
+        let subscribers = Arc::clone(&self.subscribers);
+        let mut subscribers = subscribers.lock().unwrap();
+        subscribers.retain_mut(|subscriber| subscriber.send(&frames).is_ok());
+    }
+
+    fn handle(&mut self, connection: &mut Connection, message: Message) {
+        self.publish(&message, "status", "{\"execution_state\": \"busy\"}");
+        match message.msg_type.as_str() {
+            "kernel_info_request" => {
+                let version = plover::version();
+                let content = format!(
+                    "{{\"status\": \"ok\", \"protocol_version\": {}, \"implementation\": \"plover\", \"implementation_version\": {}, \"language_info\": {{\"name\": \"plover\", \"version\": {}, \"mimetype\": \"text/x-plover\", \"file_extension\": \".plover\"}}, \"banner\": {}, \"help_links\": []}}",
+                    quote(PROTOCOL_VERSION),
+                    quote(version.version),
+                    quote(version.version),
+                    quote(&format!("Plover {}", version))
+                );
+                self.reply(connection, &message, "kernel_info_reply", &content);
+            }
+            "execute_request" => self.execute(connection, &message),
+            "complete_request" => {
+                let code = string_field(&message.content, "code");
+                let cursor = match field(&message.content, "cursor_pos") {
+                    Some(Json::Number(n)) => *n as usize,
+                    _ => code.chars().count(),
+                };
+                let names = Arc::clone(&self.vm.env.types);
+                let (matches, start) = complete(&code, cursor, names.keys().map(|k| k.as_str()));
+                let content = format!(
+                    "{{\"status\": \"ok\", \"matches\": {}, \"cursor_start\": {}, \"cursor_end\": {}, \"metadata\": {{}}}}",
+                    quote_all(&matches),
+                    start,
+                    cursor.min(code.chars().count())
+                );
+                self.reply(connection, &message, "complete_reply", &content);
+            }
+            "is_complete_request" => {
+                let code = string_field(&message.content, "code");
+                let status = if parser::parse(&code).is_ok() {
+                    "complete"
+                } else if parser::incomplete(&code) {
+                    "incomplete"
+                } else {
+                    "invalid"
+                };
+                let content = format!("{{\"status\": \"{}\", \"indent\": \"\"}}", status);
+                self.reply(connection, &message, "is_complete_reply", &content);
+            }
+            "inspect_request" => {
+                let content =
+                    "{\"status\": \"ok\", \"found\": false, \"data\": {}, \"metadata\": {}}";
+                self.reply(connection, &message, "inspect_reply", content);
+            }
+            "history_request" => {
+                let content = "{\"status\": \"ok\", \"history\": []}";
+                self.reply(connection, &message, "history_reply", content);
+            }
+            "comm_info_request" => {
+                let content = "{\"status\": \"ok\", \"comms\": {}}";
+                self.reply(connection, &message, "comm_info_reply", content);
+            }
+            // Cells run to completion, or until they run out of fuel, so there
+            // is nothing to interrupt by the time the request is read.
+            "interrupt_request" => {
+                self.reply(
+                    connection,
+                    &message,
+                    "interrupt_reply",
+                    "{\"status\": \"ok\"}",
+                );
+            }
+            "shutdown_request" => {
+                let restart = bool_field(&message.content, "restart", false);
+                let content = format!("{{\"status\": \"ok\", \"restart\": {}}}", restart);
+                self.reply(connection, &message, "shutdown_reply", &content);
+                self.publish(&message, "status", "{\"execution_state\": \"idle\"}");
+                process::exit(0);
+            }
+            _ => {}
+        }
+        self.publish(&message, "status", "{\"execution_state\": \"idle\"}");
+    }
+
+    fn execute(&mut self, connection: &mut Connection, message: &Message) {
+        let code = string_field(&message.content, "code");
+        let silent = bool_field(&message.content, "silent", false);
+        if !silent && bool_field(&message.content, "store_history", true) {
+            self.execution_count += 1;
+        }
+        let count = self.execution_count;
+        if !silent {
+            let content = format!(
+                "{{\"code\": {}, \"execution_count\": {}}}",
+                quote(&code),
+                count
+            );
+            self.publish(message, "execute_input", &content);
+        }
+
+        // A cell that calls exit fails with an error reply, rather than
+        // stopping the kernel, which is left to the frontend to shut down.
+        let entry = crate::run_entry(&code, &mut self.vm, &mut self.fuel);
+        let filename = format!("<cell {}>", count);
+        let lines: Vec<&str> = code.split('\n').collect();
+        if !silent && !entry.output.is_empty() {
+            let content = format!(
+                "{{\"name\": \"stdout\", \"text\": {}}}",
+                quote(&entry.output)
+            );
+            self.publish(message, "stream", &content);
+        }
+        for warning in entry.warnings.iter().filter(|_| !silent) {
+            let mut text = vec![format!("Warning: {}", warning.msg)];
+            if warning.line != usize::MAX {
+                text.extend(crate::position_lines(
+                    &filename,
+                    &lines,
+                    warning.line,
+                    warning.col,
+                ));
+            }
+            let content = format!(
+                "{{\"name\": \"stderr\", \"text\": {}}}",
+                quote(&(text.join("\n") + "\n"))
+            );
+            self.publish(message, "stream", &content);
+        }
+
+        match &entry.result {
+            Ok(Some((value, typ))) if !silent && !matches!(typ, typeinfer::Type::Unit) => {
+                let content = format!(
+                    "{{\"execution_count\": {}, \"data\": {{\"text/plain\": {}}}, \"metadata\": {{}}}}",
+                    count,
+                    quote(&format!("{} : {}", value, typ))
+                );
+                self.publish(message, "execute_result", &content);
+            }
+            Ok(_) => {}
+            Err(err) => {
+                // The traceback is shown as it is, so it can be colored.
+                let mut traceback = vec![format!("\x1b[0;31mInterpreterError\x1b[0m: {}", err.err)];
+                if err.line != usize::MAX {
+                    traceback.extend(crate::position_lines(&filename, &lines, err.line, err.col));
+                }
+                traceback.extend(crate::backtrace_lines(&filename, err));
+                let error = format!(
+                    "\"ename\": \"InterpreterError\", \"evalue\": {}, \"traceback\": {}",
+                    quote(&err.err),
+                    quote_all(&traceback)
+                );
+                self.publish(message, "error", &format!("{{{}}}", error));
+                let content = format!(
+                    "{{\"status\": \"error\", \"execution_count\": {}, {}}}",
+                    count, error
+                );
+                self.reply(connection, message, "execute_reply", &content);
+                return;
+            }
+        }
+        let content = format!(
+            "{{\"status\": \"ok\", \"execution_count\": {}, \"payload\": [], \"user_expressions\": {{}}}}",
+            count
+        );
+        self.reply(connection, message, "execute_reply", &content);
+    }
+}
+
+// Accepts connections on a channel, handing each to the handler on a thread of
+// its own once it has completed the handshake.
+fn serve<F>(listener: TcpListener, socket_type: &'static str, handler: F)
+where
+    F: Fn(Connection) + Send + Sync + 'static,
+{
+    let handler = Arc::new(handler);
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let handler = Arc::clone(&handler);
+            thread::spawn(move || {
+                if let Ok(connection) = Connection::handshake(stream, socket_type) {
+                    handler(connection);
+                }
+            });
+        }
+    });
+}
+
+// Reads and ignores whatever a peer sends until it goes away.
+fn drain(mut connection: Connection) {
+    while connection.recv().is_ok() {}
+}
+
+// Runs the kernel for the connection file that Jupyter started it with, until
+// a frontend asks it to shut down. Returns false if the file can not be used.
+pub fn run(filename: &str, vm: vm::VirtualMachine) -> io::Result<bool> {
+    let config = match fs::read_to_string(filename)
+        .ok()
+        .and_then(|s| json::parse(&s))
+    {
+        Some(config) => config,
+        None => {
+            println!("Unable to read connection file {}.", filename);
+            return Ok(false);
+        }
+    };
+    let scheme = string_field(&config, "signature_scheme");
+    let key = string_field(&config, "key");
+    if !key.is_empty() && scheme != "hmac-sha256" {
+        println!("Unsupported signature scheme {}.", scheme);
+        return Ok(false);
+    }
+    let ip = string_field(&config, "ip");
+    let listen = |name: &str| match field(&config, name) {
+        Some(Json::Number(port)) => TcpListener::bind((ip.as_str(), *port as u16)),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("missing {}", name),
+        )),
+    };
+    let shell = listen("shell_port")?;
+    let control = listen("control_port")?;
+    let iopub = listen("iopub_port")?;
+    let stdin = listen("stdin_port")?;
+    let heartbeat = listen("hb_port")?;
+
+    serve(heartbeat, "REP", |mut connection| {
+        while let Ok(frames) = connection.recv() {
+            if connection.send(&frames).is_err() {
+                break;
+            }
+        }
+    });
+    let subscribers = Arc::new(Mutex::new(Vec::new()));
+    let publish_to = Arc::clone(&subscribers);
+    serve(iopub, "PUB", move |connection| {
+        if let Ok(reader) = connection.try_clone() {
+            publish_to.lock().unwrap().push(connection);
+            drain(reader);
+        }
+    });
+    serve(stdin, "ROUTER", drain);
+    // Requests from every connection are handled one at a time, in the order
+    // they arrive, along with the connection to answer them on.
+    let (sender, requests) = mpsc::channel();
+    for listener in [shell, control] {
+        let sender = sender.clone();
+        serve(listener, "ROUTER", move |mut connection| {
+            while let Ok(frames) = connection.recv() {
+                let writer = match connection.try_clone() {
+                    Ok(writer) => writer,
+                    Err(_) => break,
+                };
+                if sender.send((writer, frames)).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    let started = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_nanos());
+    let mut kernel = Kernel {
+        key: key.into_bytes(),
+        session: format!("{:x}-{:x}", process::id(), started),
+        sent: 0,
+        subscribers,
+        execution_count: 0,
+        fuel: Some(crate::REPL_FUEL),
+        vm,
+    };
+    for (mut connection, frames) in requests {
+        if let Some(message) = parse_message(&kernel.key, frames) {
+            kernel.handle(&mut connection, message);
+        }
+    }
+    Ok(true)
+}
+
+// Where Jupyter looks for the kernels installed by the user.
+fn kernels_dir() -> Option<PathBuf> {
+    let data = match env::var_os("JUPYTER_DATA_DIR") {
+        Some(dir) => PathBuf::from(dir),
+        None if cfg!(windows) => PathBuf::from(env::var_os("APPDATA")?).join("jupyter"),
+        None if cfg!(target_os = "macos") => {
+            PathBuf::from(env::var_os("HOME")?).join("Library/Jupyter")
+        }
+        None => match env::var_os("XDG_DATA_HOME") {
+            Some(dir) => PathBuf::from(dir).join("jupyter"),
+            None => PathBuf::from(env::var_os("HOME")?).join(".local/share/jupyter"),
+        },
+    };
+    Some(data.join("kernels"))
+}
+
+// Writes the kernel spec that tells Jupyter how to start this interpreter as a
+// kernel, with the options given, and returns the directory it is in.
+pub fn install(options: &[&str]) -> io::Result<PathBuf> {
+    let dir = match kernels_dir() {
+        Some(dir) => dir.join("plover"),
+        None => return Err(io::Error::new(io::ErrorKind::NotFound, "no home directory")),
+    };
+    let exe = env::current_exe()?;
+    let mut argv = vec![exe.to_string_lossy().to_string(), "kernel".to_string()];
+    argv.extend(options.iter().map(|option| option.to_string()));
+    argv.push("{connection_file}".to_string());
+    let spec = format!(
+        "{{\n  \"argv\": {},\n  \"display_name\": \"Plover\",\n  \"language\": \"plover\",\n  \"interrupt_mode\": \"message\"\n}}\n",
+        quote_all(&argv)
+    );
+    fs::create_dir_all(&dir)?;
+    fs::write(dir.join("kernel.json"), spec)?;
+    Ok(dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::json::{self, Json};
+    use crate::kernel::{complete, parse_message, sha256, sign, signature_matches, DELIMITER};
+
+    #[test]
+    fn signatures() {
+        let hex =
+            |digest: [u8; 32]| -> String { digest.iter().map(|b| format!("{:02x}", b)).collect() };
+        assert_eq!(
+            hex(sha256(b"")),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            hex(sha256(&[b'a'; 1000])),
+            "41edece42d63e8d9bf515a9ba6932e1c20cbc9f5a5d134645adb5db1b9737ea3"
+        );
+        // RFC 4231 test cases 2 and 6, the second with a key longer than a block.
+        assert_eq!(
+            sign(b"Jefe", &[b"what do ya want ", b"for nothing?"]),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert_eq!(
+            sign(
+                &[0xaa; 131],
+                &[b"Test Using Larger Than Block-Size Key - Hash Key First"]
+            ),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+    }
+
+    #[test]
+    fn messages() {
+        let header = b"{\"msg_type\": \"execute_request\", \"username\": \"ada\"}".to_vec();
+        let content = b"{\"code\": \"1 + 2\", \"silent\": false}".to_vec();
+        let signature = sign(b"key", &[&header, b"{}", b"{}", &content]);
+        let frames = vec![
+            b"client".to_vec(),
+            DELIMITER.to_vec(),
+            signature.into_bytes(),
+            header,
+            b"{}".to_vec(),
+            b"{}".to_vec(),
+            content,
+        ];
+        let message = parse_message(b"key", frames.clone()).unwrap();
+        assert_eq!(message.ids, vec![b"client".to_vec()]);
+        assert_eq!(message.msg_type, "execute_request");
+        assert_eq!(message.username, "ada");
+        assert!(matches!(
+            json::parse(&message.header),
+            Some(Json::Object(_))
+        ));
+        assert!(matches!(
+            crate::kernel::field(&message.content, "code"),
+            Some(Json::String(code)) if code == "1 + 2"
+        ));
+        assert!(parse_message(b"other key", frames).is_none());
+        assert!(signature_matches(b"abc", b"abc"));
+        assert!(!signature_matches(b"abd", b"abc"));
+        assert!(!signature_matches(b"ab", b"abc"));
+    }
+
+    #[test]
+    fn completions() {
+        let names = ["length", "let_me", "x₂", "print"];
+        let (matches, start) = complete("1 + le", 6, names.iter().copied());
+        assert_eq!(matches, vec!["length", "let", "let_me", "letrec"]);
+        assert_eq!(start, 4);
+        let (matches, start) = complete("pr (é)", 2, names.iter().copied());
+        assert_eq!(matches, vec!["print"]);
+        assert_eq!(start, 0);
+        let (matches, _) = complete("x", 1, names.iter().copied());
+        assert!(matches.is_empty());
+    }
+}
@@ -0,0 +1,248 @@
+use std::io;
+
+use plover::{codegen, parser, vm};
+
+use crate::repl;
+
+// An exercise is answered by a program, which is checked by running it and
+// then each of the checks in the same virtual machine, comparing their values
+// with the expected ones. A check without an expression is the value of the
+// answer itself.
+struct Exercise {
+    lesson: &'static str,
+    task: &'static str,
+    checks: &'static [(&'static str, &'static str)],
+    hint: &'static str,
+    solution: &'static str,
+}
+
+const EXERCISES: &[Exercise] = &[
+    Exercise {
+        lesson: "Expressions",
+        task: "Arithmetic is written as usual, with * and / binding more tightly than +
+and -. Write an expression for the number of seconds in a day.",
+        checks: &[("", "86400")],
+        hint: "A day has 24 hours of 60 minutes, each of which has 60 seconds.",
+        solution: "24 * 60 * 60",
+    },
+    Exercise {
+        lesson: "Expressions",
+        task: "Strings are joined with ++. Join \"hello\" and \"world\" with a space
+in between.",
+        checks: &[("", "\"hello world\"")],
+        hint: "The space is a string of its own, \" \".",
+        solution: "\"hello\" ++ \" \" ++ \"world\"",
+    },
+    Exercise {
+        lesson: "Expressions",
+        task: "let names a value within an expression, as in let x = 2 in x * x.
+Use let to name 7 * 6 and give that value plus its square.",
+        checks: &[("", "1806")],
+        hint: "The body of the let can use the name as often as it likes.",
+        solution: "let n = 7 * 6 in n + n * n",
+    },
+    Exercise {
+        lesson: "Functions",
+        task: "Functions are defined with fn name (parameter) -> body end. Define
+square, which multiplies a number by itself.",
+        checks: &[
+            ("square (3)", "9"),
+            ("square (-4)", "16"),
+            ("square (0)", "0"),
+        ],
+        hint: "The body is the parameter times itself.",
+        solution: "fn square (x) -> x * x end",
+    },
+    Exercise {
+        lesson: "Functions",
+        task: "if c then a elsif d then b else e end picks one of its branches.
+Define sign, which gives -1 for negative numbers, 0 for zero and 1 for
+positive numbers.",
+        checks: &[("sign (-5)", "-1"), ("sign (0)", "0"), ("sign (7)", "1")],
+        hint: "Compare the number with 0 using < and ==.",
+        solution: "fn sign (n) -> if n < 0 then -1 elsif n == 0 then 0 else 1 end end",
+    },
+    Exercise {
+        lesson: "Functions",
+        task: "A function takes several parameters as a tuple, as in fn f (a, b).
+Define clamp, which takes a number and a lower and upper bound and gives the
+number limited to lie between them.",
+        checks: &[
+            ("clamp (5, 0, 10)", "5"),
+            ("clamp (-3, 0, 10)", "0"),
+            ("clamp (42, 0, 10)", "10"),
+        ],
+        hint: "Check the lower bound first, then the upper one.",
+        solution: "fn clamp (n, lo, hi) -> if n < lo then lo elsif n > hi then hi else n end end",
+    },
+    Exercise {
+        lesson: "Recursion",
+        task: "A function can call itself. Define fact, where fact (0) is 1 and
+fact (n) is n * fact (n - 1).",
+        checks: &[
+            ("fact (0)", "1"),
+            ("fact (5)", "120"),
+            ("fact (10)", "3628800"),
+        ],
+        hint: "Use an if expression to stop when n is 0.",
+        solution: "fn fact (n) -> if n == 0 then 1 else n * fact (n - 1) end end",
+    },
+    Exercise {
+        lesson: "Recursion",
+        task: "Define fib, which gives the nth Fibonacci number, where fib (0) is 0,
+fib (1) is 1 and each one after is the sum of the two before it.",
+        checks: &[("fib (0)", "0"), ("fib (1)", "1"), ("fib (10)", "55")],
+        hint: "Numbers below 2 are their own Fibonacci number.",
+        solution: "fn fib (n) -> if n < 2 then n else fib (n - 1) + fib (n - 2) end end",
+    },
+    Exercise {
+        lesson: "Recursion",
+        task: "Define digits, which gives the sum of the digits of a number that is
+not negative. n % 10 is its last digit and n / 10 the digits before it.",
+        checks: &[
+            ("digits (0)", "0"),
+            ("digits (7)", "7"),
+            ("digits (1234)", "10"),
+        ],
+        hint: "A number below 10 is a single digit.",
+        solution: "fn digits (n) -> if n < 10 then n else n % 10 + digits (n / 10) end end",
+    },
+];
+
+// Answers are stopped after this many instructions, so that one that never
+// finishes can be tried again.
+const FUEL: u64 = 1_000_000;
+
+const LEARN_HELP: &str = "\
+Type an answer to check it, or one of:
+  hint             show a hint for this exercise
+  solution         show a solution to this exercise
+  skip             go on to the next exercise
+  quit             stop the tutorial
+  help             show this list";
+
+fn evaluate(vm: &mut vm::VirtualMachine, src: &str) -> Result<vm::Value, String> {
+    let ast = parser::parse(src).map_err(|err| err.msg)?;
+    codegen::eval(vm, &ast).map_err(|err| err.err)
+}
+
+// Returns why an answer is wrong, if it is.
+fn check(exercise: &Exercise, answer: &str) -> Option<String> {
+    let mut vm = vm::VirtualMachine::new();
+    vm.set_fuel(FUEL);
+    let value = match evaluate(&mut vm, answer) {
+        Ok(value) => value,
+        Err(err) => return Some(err),
+    };
+    for (expr, expected) in exercise.checks {
+        let found = if expr.is_empty() {
+            value.clone()
+        } else {
+            match evaluate(&mut vm, expr) {
+                Ok(found) => found,
+                Err(err) => return Some(format!("{} fails: {}", expr, err)),
+            }
+        };
+        let expected = evaluate(&mut vm::VirtualMachine::new(), expected).unwrap();
+        if found != expected {
+            return Some(if expr.is_empty() {
+                format!("The answer is {} rather than {}.", found, expected)
+            } else {
+                format!("{} is {} rather than {}.", expr, found, expected)
+            });
+        }
+    }
+    None
+}
+
+// Reads lines until they form a complete program, or returns None at the end
+// of the input.
+fn read_answer(editor: &mut repl::Editor) -> io::Result<Option<String>> {
+    let mut src = String::new();
+    loop {
+        let prompt = if src.is_empty() { "learn> " } else { "... " };
+        match editor.read_line(prompt)? {
+            repl::Input::Line(line) => {
+                if !src.is_empty() {
+                    src.push('\n');
+                }
+                src.push_str(&line);
+                if src.trim().is_empty() {
+                    src.clear();
+                } else if !parser::incomplete(&src) {
+                    return Ok(Some(src));
+                }
+            }
+            repl::Input::Interrupted => src.clear(),
+            repl::Input::Eof if src.is_empty() => return Ok(None),
+            repl::Input::Eof => return Ok(Some(src)),
+        }
+    }
+}
+
+// Walks through the exercises in order, until they are all done or the user
+// quits.
+pub fn learn(editor: &mut repl::Editor) -> io::Result<()> {
+    println!("Welcome to the Plover tutorial! Type help for a list of commands.");
+    let mut lesson = "";
+    for (i, exercise) in EXERCISES.iter().enumerate() {
+        if exercise.lesson != lesson {
+            lesson = exercise.lesson;
+            println!("\n== {} ==", lesson);
+        }
+        println!(
+            "\nExercise {} of {}\n{}",
+            i + 1,
+            EXERCISES.len(),
+            exercise.task
+        );
+        loop {
+            let answer = match read_answer(editor)? {
+                Some(answer) => answer,
+                None => return Ok(()),
+            };
+            match answer.trim() {
+                "hint" => println!("{}", exercise.hint),
+                "solution" => println!("{}", exercise.solution),
+                "skip" => break,
+                "quit" => return Ok(()),
+                "help" => println!("{}", LEARN_HELP),
+                _ => match check(exercise, &answer) {
+                    Some(problem) => {
+                        println!("{}", problem);
+                        println!("Try again, or type hint for a hint.");
+                    }
+                    None => {
+                        println!("Correct!");
+                        break;
+                    }
+                },
+            }
+        }
+    }
+    println!("\nThat was the last exercise. The readme describes the rest of the language.");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::learn::{check, EXERCISES};
+
+    #[test]
+    fn solutions() {
+        for exercise in EXERCISES {
+            assert_eq!(check(exercise, exercise.solution), None);
+        }
+        assert_eq!(
+            check(&EXERCISES[3], "fn square (x) -> x + x end"),
+            Some("square (3) is 6 rather than 9.".to_string())
+        );
+        assert_eq!(
+            check(&EXERCISES[0], "24 * 60"),
+            Some("The answer is 1440 rather than 86400.".to_string())
+        );
+        assert!(check(&EXERCISES[6], "fn fact (n) -> n * fact (n - 1) end")
+            .unwrap()
+            .starts_with("fact (0) fails: "));
+    }
+}
@@ -0,0 +1,29 @@
+extern crate pest;
+#[macro_use]
+extern crate pest_derive;
+
+pub mod bigint;
+pub mod bytecode;
+pub mod codegen;
+#[cfg(feature = "csv")]
+pub mod csv;
+pub mod effects;
+pub mod encoding;
+pub mod formatter;
+#[cfg(feature = "graphemes")]
+pub mod graphemes;
+pub mod http;
+pub mod panics;
+pub mod parser;
+#[cfg(feature = "regex")]
+pub mod regex;
+pub mod symbol;
+pub mod time;
+pub mod typeinfer;
+pub mod unification;
+pub mod verify;
+pub mod version;
+pub mod vm;
+pub mod wasm;
+
+pub use version::version;
@@ -1,72 +1,1332 @@
 use std::cmp::min;
 use std::env;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::prelude::*;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::process;
+use std::time::Instant;
 
-extern crate pest;
-#[macro_use]
-extern crate pest_derive;
+mod bench;
+mod completions;
+mod crashreport;
+mod json;
+#[cfg(feature = "kernel")]
+mod kernel;
+mod learn;
+mod repl;
+#[cfg(feature = "kernel")]
+mod zmq;
 
-mod codegen;
-mod parser;
-mod typeinfer;
-mod unification;
-mod vm;
+use plover::{bytecode, codegen, formatter, parser, typeinfer, vm, wasm};
 
-use std::io::{self, BufRead, Write};
+use std::io;
 
-fn eval(filename: &str, src: &str, vm: &mut vm::VirtualMachine) {
+const CRASH_REPORT: &str = "plover-crash-report.txt";
+
+// The number of instructions each entry in the REPL may run by default, so that
+// a runaway loop stops rather than hanging the session.
+const REPL_FUEL: u64 = 10_000_000;
+
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg.to_string()
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        "unknown error".to_string()
+    }
+}
+
+// The lines that point out a position in the source, with the line it is on.
+fn position_lines(filename: &str, lines: &[&str], line: usize, col: usize) -> Vec<String> {
+    let line = min(lines.len(), line);
+    let col = min(lines[line - 1].len(), col);
+    let width = line.to_string().len() + 2;
+    vec![
+        format!("{s:>width$}|", s = " ", width = width),
+        format!(" {} | {}", line, lines[line - 1]),
+        format!("{s:>width$}|{s:>col$}^", s = " ", width = width, col = col),
+        format!("--> {}:{}:{}", filename, line, col),
+    ]
+}
+
+fn show_position(filename: &str, lines: &[&str], line: usize, col: usize) {
+    for line in position_lines(filename, lines, line, col) {
+        println!("{}", line);
+    }
+}
+
+// Lists the functions that were running when a runtime error occurred, from
+// the innermost out, unless the error occurred in the program itself. Runs of
+// the same frame, such as those left by deep recursion, are listed once along
+// with the number of times they are repeated.
+fn backtrace_lines(filename: &str, err: &codegen::InterpreterError) -> Vec<String> {
+    let backtrace = match &err.kind {
+        codegen::ErrorKind::Runtime { backtrace, .. } if backtrace.len() > 1 => backtrace,
+        _ => return Vec::new(),
+    };
+    let mut lines: Vec<String> = Vec::new();
+    let mut repeated = 0;
+    for (i, frame) in backtrace.iter().enumerate() {
+        let function = match &frame.function {
+            Some(name) => format!("function '{}'", name),
+            None if i + 1 == backtrace.len() => "the program".to_string(),
+            None => "a lambda".to_string(),
+        };
+        let line = if frame.line == usize::MAX {
+            format!("  in {}", function)
+        } else {
+            format!(
+                "  in {} ({}:{}:{})",
+                function, filename, frame.line, frame.col
+            )
+        };
+        if lines.last() == Some(&line) {
+            repeated += 1;
+            continue;
+        }
+        if repeated > 0 {
+            lines.push(format!("  ... repeated {} times", repeated));
+            repeated = 0;
+        }
+        lines.push(line);
+    }
+    if repeated > 0 {
+        lines.push(format!("  ... repeated {} times", repeated));
+    }
+    lines
+}
+
+fn show_backtrace(filename: &str, err: &codegen::InterpreterError) {
+    for line in backtrace_lines(filename, err) {
+        println!("{}", line);
+    }
+}
+
+// A program that calls exit stops the interpreter with its status, rather than
+// being reported as failing. One whose output can no longer be written stops
+// it as well, as there is nowhere to report the error.
+fn exit_on_request(err: &codegen::InterpreterError) {
+    match err.kind {
+        codegen::ErrorKind::Exit(status) => {
+            let _ = io::stdout().flush();
+            process::exit(status);
+        }
+        codegen::ErrorKind::Runtime {
+            kind: codegen::RuntimeError::OutputError,
+            ..
+        } => process::exit(1),
+        _ => {}
+    }
+}
+
+// Writes a crash report for an internal error, which may be a panic or an
+// instruction that found unexpected values in checked mode.
+fn report_crash(msg: &str, sources: &[(String, String)], vm: &vm::VirtualMachine) {
+    let written = crashreport::write(CRASH_REPORT, msg, sources, vm);
+    let msg = msg.trim_end_matches('.');
+    match written {
+        Ok(()) => println!(
+            "Internal error: {}. Crash report written to {}.",
+            msg, CRASH_REPORT
+        ),
+        Err(err) => println!(
+            "Internal error: {}. Unable to write crash report: {}.",
+            msg, err
+        ),
+    }
+}
+
+fn is_internal(err: &codegen::InterpreterError) -> bool {
+    matches!(
+        err.kind,
+        codegen::ErrorKind::Runtime {
+            kind: codegen::RuntimeError::InvalidStack,
+            ..
+        }
+    )
+}
+
+// Evaluates a program, reporting any warnings and errors, and returns its value
+// and type if it succeeds.
+fn eval(
+    filename: &str,
+    src: &str,
+    vm: &mut vm::VirtualMachine,
+    sources: Option<&[(String, String)]>,
+    emit_bytecode: bool,
+) -> Option<(vm::Value, typeinfer::Type)> {
     let lines: Vec<&str> = src.split('\n').collect();
-    match parser::parse(&src) {
-        Ok(ast) => match codegen::eval(vm, &ast) {
-            Ok(v) => {
-                println!("{}", v);
+    let ast = match parser::parse(src) {
+        Ok(ast) => ast,
+        Err(err) => {
+            println!("{}", err.msg);
+            return None;
+        }
+    };
+
+    let run = |vm: &mut vm::VirtualMachine| {
+        if !emit_bytecode {
+            return codegen::eval_with_type(vm, &ast);
+        }
+        let typ = codegen::compile(vm, &ast)?;
+        print!("{}", codegen::disassemble(vm));
+        loop {
+            if let vm::Status::Finished(value) = vm.run()? {
+                return Ok((value, typ));
             }
+        }
+    };
+    let result = match sources {
+        Some(sources) => match panic::catch_unwind(AssertUnwindSafe(|| run(vm))) {
+            Ok(result) => result,
+            Err(payload) => {
+                report_crash(&panic_message(&*payload), sources, vm);
+                vm.reset();
+                vm.warnings.drain(0..);
+                return None;
+            }
+        },
+        None => run(vm),
+    };
+
+    for warning in vm.warnings.drain(0..) {
+        println!("{}", warning);
+        show_position(filename, &lines, warning.line, warning.col);
+    }
+
+    match result {
+        Ok(result) => Some(result),
+        Err(err) => {
+            exit_on_request(&err);
+            match sources {
+                Some(sources) if is_internal(&err) => report_crash(&err.err, sources, vm),
+                _ => {
+                    println!("{}", err);
+                    show_position(filename, &lines, err.line, err.col);
+                    show_backtrace(filename, &err);
+                }
+            }
+            vm.reset();
+            None
+        }
+    }
+}
+
+fn read_source(filename: &str) -> io::Result<String> {
+    let mut file = File::open(filename)?;
+    let mut program = String::new();
+    file.read_to_string(&mut program)?;
+    Ok(program)
+}
+
+// Compiles a program to bytecode without running it.
+fn build(
+    filename: &str,
+    src: &str,
+    output: &str,
+    target_wasm: bool,
+    vm: &mut vm::VirtualMachine,
+) -> bool {
+    let lines: Vec<&str> = src.split('\n').collect();
+    let ast = match parser::parse(src) {
+        Ok(ast) => ast,
+        Err(err) => {
+            println!("{}", err.msg);
+            return false;
+        }
+    };
+    if target_wasm {
+        let module = match wasm::compile(vm, &ast) {
+            Ok(module) => module,
             Err(err) => {
-                let line = min(lines.len(), err.line);
-                let col = min(lines[line - 1].len(), err.col);
-                let width = line.to_string().len() + 2;
                 println!("{}", err);
-                println!("{s:>width$}|", s = " ", width = width);
-                println!(" {} | {}", line, lines[line - 1]);
-                print!("{s:>width$}|", s = " ", width = width);
-                println!("{s:>width$}^", s = " ", width = col);
-                println!("--> {}:{}", filename, line);
-                vm.stack.drain(0..);
+                if err.line != usize::MAX {
+                    show_position(filename, &lines, err.line, err.col);
+                }
+                return false;
             }
-        },
+        };
+        return match fs::write(output, module) {
+            Ok(()) => true,
+            Err(err) => {
+                println!("Unable to write {}: {}.", output, err);
+                false
+            }
+        };
+    }
+    let result = codegen::compile(vm, &ast);
+    for warning in vm.warnings.drain(0..) {
+        println!("{}", warning);
+        show_position(filename, &lines, warning.line, warning.col);
+    }
+    if let Err(err) = result {
+        println!("{}", err);
+        show_position(filename, &lines, err.line, err.col);
+        return false;
+    }
+    match fs::write(output, bytecode::encode(vm)) {
+        Ok(()) => true,
+        Err(err) => {
+            println!("Unable to write {}: {}.", output, err);
+            false
+        }
+    }
+}
+
+// Rewrites a file in the canonical layout, or with check set only reports
+// whether it is already formatted, returning whether the file was formatted.
+fn format_file(filename: &str, check: bool) -> bool {
+    let src = match read_source(filename) {
+        Ok(src) => src,
+        Err(err) => {
+            println!("Unable to read {}: {}.", filename, err);
+            return false;
+        }
+    };
+    let formatted = match parser::parse_with_comments(&src) {
+        Ok((ast, trivia)) => formatter::format_with_comments(&ast, &trivia),
+        Err(err) => {
+            println!("{}", err.msg);
+            return false;
+        }
+    };
+    if formatted == src {
+        return true;
+    }
+    if check {
+        println!("{} is not formatted.", filename);
+        return false;
+    }
+    match fs::write(filename, formatted) {
+        Ok(()) => true,
+        Err(err) => {
+            println!("Unable to write {}: {}.", filename, err);
+            false
+        }
+    }
+}
+
+// Runs a program that was compiled to bytecode. The source is not available,
+// so errors are reported with their position alone.
+fn run_bytecode(
+    filename: &str,
+    bytes: &[u8],
+    vm: &mut vm::VirtualMachine,
+    sources: Option<&[(String, String)]>,
+    emit_bytecode: bool,
+) -> Option<vm::Value> {
+    // Bytecode may not have been produced by the compiler, so instructions
+    // that find unexpected values are reported rather than panicking.
+    vm.checked = true;
+    let result = bytecode::load(vm, bytes).and_then(|()| {
+        if emit_bytecode {
+            print!("{}", codegen::disassemble(vm));
+        }
+        loop {
+            if let vm::Status::Finished(value) = vm.run()? {
+                break Ok(value);
+            }
+        }
+    });
+    match result {
+        Ok(value) => Some(value),
+        Err(err) => {
+            exit_on_request(&err);
+            if let (Some(sources), true) = (sources, is_internal(&err)) {
+                report_crash(&err.err, sources, vm);
+                return None;
+            }
+            println!("{}", err);
+            if err.line == usize::MAX {
+                println!("--> {}", filename);
+            } else {
+                println!("--> {}:{}:{}", filename, err.line, err.col);
+            }
+            show_backtrace(filename, &err);
+            None
+        }
+    }
+}
+
+// Runs a program while counting the instructions run on each line, then
+// shows either the lines it spent the most time on or, when annotating, the
+// whole source with the counts and share of the time next to each line.
+fn profile(
+    filename: &str,
+    src: &str,
+    vm: &mut vm::VirtualMachine,
+    annotate: bool,
+    flamegraph: Option<&str>,
+) -> bool {
+    vm.profile = Some(vm::Profile::new());
+    let result = eval(filename, src, vm, None, false);
+    let mut profile = vm.profile.take().unwrap();
+    profile.stop();
+    if let Some((v, _)) = &result {
+        println!("{}", v.display(&vm.symbols).ints(&vm.int_format));
+    }
+    let lines: Vec<&str> = src.split('\n').collect();
+    let total = profile.total().as_secs_f64();
+    let percent = |line: &vm::LineProfile| {
+        if total > 0.0 {
+            100.0 * line.time.as_secs_f64() / total
+        } else {
+            0.0
+        }
+    };
+    println!("{:>12} {:>7}", "count", "time");
+    if annotate {
+        for (i, text) in lines.iter().enumerate() {
+            match profile.lines.get(&(i + 1)) {
+                Some(line) => println!("{:>12} {:>6.1}% | {}", line.count, percent(line), text),
+                None => println!("{:>12} {:>7} | {}", "", "", text),
+            }
+        }
+    } else {
+        let mut hot: Vec<_> = profile
+            .lines
+            .iter()
+            .filter(|(line, _)| **line > 0 && **line <= lines.len())
+            .collect();
+        hot.sort_by(|a, b| b.1.time.cmp(&a.1.time).then(a.0.cmp(b.0)));
+        for (i, line) in hot.into_iter().take(10) {
+            println!(
+                "{:>12} {:>6.1}% | {}:{}: {}",
+                line.count,
+                percent(line),
+                filename,
+                i,
+                lines[i - 1].trim()
+            );
+        }
+    }
+    if let Some(output) = flamegraph {
+        let folded = profile.folded(&codegen::function_names(vm));
+        if let Err(err) = fs::write(output, folded) {
+            println!("Unable to write {}: {}.", output, err);
+            return false;
+        }
+    }
+    result.is_some()
+}
+
+const DEBUG_HELP: &str = "\
+Commands:
+  step, s          execute one instruction
+  continue, c      run until the next breakpoint
+  break ADDR       stop before the instruction at ADDR
+  line LINE        stop at the start of LINE
+  delete           remove every breakpoint
+  stack            show the stack
+  callstack, bt    show the call stack
+  env              show the current environment
+  list             show the program's instructions
+  quit, q          stop debugging
+  help             show this list";
+
+// A new virtual machine with the options given on the command line, which are
+// kept on a template.
+fn configured(template: &vm::VirtualMachine) -> vm::VirtualMachine {
+    let mut vm = vm::VirtualMachine::new();
+    vm.optimize = template.optimize;
+    vm.overflow = template.overflow;
+    vm.allowed_effects = template.allowed_effects.clone();
+    vm.features = template.features.clone();
+    vm.int_format = template.int_format.clone();
+    vm
+}
+
+// Runs each program the given number of times on a new virtual machine,
+// printing and returning the median time and the instructions run, and
+// compares them with a baseline if one is given.
+fn bench(
+    filenames: &[&String],
+    template: &vm::VirtualMachine,
+    runs: usize,
+    baseline: Option<&[bench::Sample]>,
+) -> Option<Vec<bench::Sample>> {
+    let mut samples = Vec::new();
+    for filename in filenames {
+        let program = match read_source(filename) {
+            Ok(program) => program,
+            Err(err) => {
+                println!("Unable to read {}: {}.", filename, err);
+                return None;
+            }
+        };
+        let lines: Vec<&str> = program.split('\n').collect();
+        let ast = match parser::parse(&program) {
+            Ok(ast) => ast,
+            Err(err) => {
+                println!("{}", err.msg);
+                return None;
+            }
+        };
+        let mut times = Vec::new();
+        let mut instructions = 0;
+        for _ in 0..runs {
+            let mut vm = configured(template);
+            vm.set_fuel(u64::MAX);
+            let start = Instant::now();
+            let result = codegen::eval(&mut vm, &ast);
+            times.push(start.elapsed().as_secs_f64() * 1000.0);
+            if let Err(err) = result {
+                println!("{}", err);
+                show_position(filename, &lines, err.line, err.col);
+                return None;
+            }
+            instructions = u64::MAX - vm.fuel().unwrap_or(u64::MAX);
+        }
+        times.sort_by(f64::total_cmp);
+        let sample = bench::Sample {
+            name: filename.to_string(),
+            runs,
+            median_ms: times[times.len() / 2],
+            instructions,
+        };
+        println!(
+            "{}: {:.3} ms, {} instructions",
+            sample.name, sample.median_ms, sample.instructions
+        );
+        samples.push(sample);
+    }
+    if let Some(baseline) = baseline {
+        let (lines, regressions, improvements) = bench::compare(baseline, &samples);
+        println!();
+        for line in lines {
+            println!("{}", line);
+        }
+        println!(
+            "{} regressions, {} improvements.",
+            regressions, improvements
+        );
+    }
+    Some(samples)
+}
+
+// Runs every program in a directory in a virtual machine of its own, set up
+// like the given one, and returns whether they all succeeded.
+fn run_examples(dir: &str, template: &vm::VirtualMachine) -> io::Result<bool> {
+    let mut filenames = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "plover") {
+            filenames.push(path.to_string_lossy().to_string());
+        }
+    }
+    filenames.sort();
+    let mut failed = 0;
+    for filename in &filenames {
+        println!("== {}", filename);
+        let program = read_source(filename)?;
+        let mut vm = configured(template);
+        match eval(filename, &program, &mut vm, None, false) {
+            Some((v, _)) => println!("{}", v.display(&vm.symbols).ints(&vm.int_format)),
+            None => failed += 1,
+        }
+    }
+    println!("{} examples run, {} failed.", filenames.len(), failed);
+    Ok(failed == 0)
+}
+
+// Steps through a program under the control of the user, starting from its
+// first instruction.
+fn debug(filename: &str, src: &str, vm: &mut vm::VirtualMachine) -> io::Result<bool> {
+    let lines: Vec<&str> = src.split('\n').collect();
+    let ast = match parser::parse(src) {
+        Ok(ast) => ast,
         Err(err) => {
             println!("{}", err.msg);
+            return Ok(false);
+        }
+    };
+    let result = codegen::compile(vm, &ast);
+    for warning in vm.warnings.drain(0..) {
+        println!("{}", warning);
+        show_position(filename, &lines, warning.line, warning.col);
+    }
+    if let Err(err) = result {
+        println!("{}", err);
+        show_position(filename, &lines, err.line, err.col);
+        return Ok(false);
+    }
+    let mut debugger = vm::Debugger::new();
+    debugger.step = true;
+    vm.debugger = Some(debugger);
+
+    let mut editor = repl::Editor::new();
+    loop {
+        match vm.run() {
+            Ok(vm::Status::Finished(value)) => {
+                println!("{}", value.display(&vm.symbols).ints(&vm.int_format));
+                return Ok(true);
+            }
+            Ok(vm::Status::Yielded) => continue,
+            Ok(vm::Status::Breakpoint) => {}
+            Err(err) => {
+                exit_on_request(&err);
+                println!("{}", err);
+                show_position(filename, &lines, err.line, err.col);
+                return Ok(false);
+            }
+        }
+        // A srcpos instruction gives the line it is about to start.
+        let line = match vm.instructions[vm.ip] {
+            vm::Opcode::Srcpos(line, _) => line,
+            _ => vm.line,
+        };
+        if line != usize::MAX && line <= lines.len() {
+            println!("{}:{}: {}", filename, line, lines[line - 1].trim());
+        }
+        println!(
+            "{:>6}  {}",
+            vm.ip,
+            vm.instructions[vm.ip].display(&vm.names)
+        );
+        loop {
+            let line = match editor.read_line("(debug) ")? {
+                repl::Input::Line(line) => line,
+                repl::Input::Interrupted => continue,
+                repl::Input::Eof => return Ok(true),
+            };
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let debugger = vm.debugger.as_mut().unwrap();
+            match words.as_slice() {
+                ["step"] | ["s"] => {
+                    debugger.step = true;
+                    break;
+                }
+                ["continue"] | ["c"] => {
+                    debugger.step = false;
+                    break;
+                }
+                ["break", addr] => match addr.parse::<usize>() {
+                    Ok(addr) if addr < vm.instructions.len() => {
+                        debugger.breakpoints.insert(addr);
+                    }
+                    _ => println!("Invalid address {}.", addr),
+                },
+                ["line", line] => match line.parse::<usize>() {
+                    Ok(line) if line > 0 && line <= lines.len() => {
+                        debugger.lines.insert(line);
+                    }
+                    _ => println!("Invalid line {}.", line),
+                },
+                ["delete"] => {
+                    debugger.breakpoints.clear();
+                    debugger.lines.clear();
+                }
+                ["stack"] => {
+                    for (i, value) in vm.stack.iter().enumerate().rev() {
+                        println!(
+                            "{:>6}  {}",
+                            i,
+                            value.display(&vm.symbols).ints(&vm.int_format)
+                        );
+                    }
+                }
+                ["callstack"] | ["bt"] => {
+                    for (i, (ip, env, _, ret)) in vm.callstack.iter().enumerate().rev() {
+                        let name = match &env.fun {
+                            Some((id, _)) => vm.names.name(*id),
+                            None => "lambda",
+                        };
+                        println!("{:>6}  {} @{}, returns to {}", i, name, ip, ret);
+                    }
+                }
+                ["env"] => {
+                    for (id, value) in vm.environment().bindings(&vm.names) {
+                        println!(
+                            "{} = {}",
+                            vm.names.name(id),
+                            value.display(&vm.symbols).ints(&vm.int_format)
+                        );
+                    }
+                }
+                ["list"] => print!("{}", codegen::disassemble(vm)),
+                ["quit"] | ["q"] => return Ok(true),
+                ["help"] => println!("{}", DEBUG_HELP),
+                [] => {}
+                _ => println!("Unknown command, type help for a list of commands."),
+            }
+        }
+    }
+}
+
+// Reads a pasted block up to a line holding :end or the end of the input
+// without evaluating any of it, or returns None if it is empty or discarded.
+fn read_paste(editor: &mut repl::Editor) -> io::Result<Option<String>> {
+    let mut block = String::new();
+    loop {
+        match editor.read_line("")? {
+            repl::Input::Line(line) if line.trim() == ":end" => break,
+            repl::Input::Line(line) => {
+                block.push_str(&line);
+                block.push('\n');
+            }
+            repl::Input::Interrupted => return Ok(None),
+            repl::Input::Eof => break,
         }
     }
+    Ok(if block.trim().is_empty() {
+        None
+    } else {
+        Some(block)
+    })
+}
+
+// Changes the fuel given to each entry in the REPL with :fuel N or :fuel off,
+// or shows it when no argument is given, returning the message to show.
+fn fuel_command(arg: &str, fuel: &mut Option<u64>) -> String {
+    match arg {
+        "" => {}
+        "off" => *fuel = None,
+        n => match n.parse() {
+            Ok(n) => *fuel = Some(n),
+            Err(_) => return "Usage: :fuel [instructions|off]".to_string(),
+        },
+    }
+    match fuel {
+        Some(n) => format!("Each entry may run {} instructions.", n),
+        None => "Entries may run any number of instructions.".to_string(),
+    }
+}
+
+// Writes a position as JSON, or null for errors that are not tied to one.
+fn json_position(line: usize, col: usize) -> String {
+    if line == usize::MAX {
+        "\"line\": null, \"col\": null".to_string()
+    } else {
+        format!("\"line\": {}, \"col\": {}", line, col)
+    }
+}
+
+// The outcome of an entry evaluated for a tool rather than a person: the value
+// and type it gave, if it was not a command, or the error it failed with, along
+// with what it printed, its warnings and how long it took.
+struct Entry {
+    result: Result<Option<(String, typeinfer::Type)>, codegen::InterpreterError>,
+    output: String,
+    warnings: Vec<codegen::Warning>,
+    time_ms: f64,
+}
+
+// Evaluates an entry with the fuel given to each, capturing what it prints. An
+// entry may also be a :fuel command, whose message is given as its output.
+fn run_entry(src: &str, vm: &mut vm::VirtualMachine, fuel: &mut Option<u64>) -> Entry {
+    let start = Instant::now();
+    let (output, result) = match src.trim().strip_prefix(":fuel") {
+        Some(arg) if arg.is_empty() || arg.starts_with(char::is_whitespace) => {
+            (fuel_command(arg.trim(), fuel) + "\n", Ok(None))
+        }
+        _ => {
+            match fuel {
+                Some(n) => vm.set_fuel(*n),
+                None => vm.clear_fuel(),
+            }
+            vm.output = Some(String::new());
+            let result = parser::parse(src)
+                .map_err(codegen::InterpreterError::from)
+                .and_then(|ast| codegen::eval_with_type(vm, &ast));
+            let output = vm.output.take().unwrap_or_default();
+            let result = match result {
+                Ok((value, typ)) => Ok(Some((
+                    value.display(&vm.symbols).ints(&vm.int_format).to_string(),
+                    typ,
+                ))),
+                Err(err) => {
+                    vm.reset();
+                    Err(err)
+                }
+            };
+            (output, result)
+        }
+    };
+    Entry {
+        result,
+        output,
+        warnings: vm.warnings.drain(0..).collect(),
+        time_ms: start.elapsed().as_secs_f64() * 1000.0,
+    }
+}
+
+// Writes an entry as a line of JSON for the JSON mode of the REPL.
+fn entry_json(entry: &Entry) -> String {
+    let warnings: Vec<String> = entry
+        .warnings
+        .iter()
+        .map(|warning| {
+            format!(
+                "{{\"message\": {}, {}}}",
+                json::quote(&warning.msg),
+                json_position(warning.line, warning.col)
+            )
+        })
+        .collect();
+    let (value, typ, error) = match &entry.result {
+        Ok(Some((value, typ))) => (
+            json::quote(value),
+            json::quote(&typ.to_string()),
+            "null".to_string(),
+        ),
+        Ok(None) => ("null".to_string(), "null".to_string(), "null".to_string()),
+        Err(err) => (
+            "null".to_string(),
+            "null".to_string(),
+            format!(
+                "{{\"message\": {}, {}}}",
+                json::quote(&err.err),
+                json_position(err.line, err.col)
+            ),
+        ),
+    };
+    format!(
+        "{{\"value\": {}, \"type\": {}, \"output\": {}, \"warnings\": [{}], \"error\": {}, \"time_ms\": {}}}",
+        value,
+        typ,
+        json::quote(&entry.output),
+        warnings.join(", "),
+        error,
+        entry.time_ms
+    )
+}
+
+// Runs the REPL for tools rather than people. Each line of input is a JSON
+// string holding the source of an entry, and each entry is answered with one
+// line of JSON. Files given on the command line are evaluated first, and are
+// answered in the same way.
+fn json_repl(filenames: &[&String], vm: &mut vm::VirtualMachine) -> io::Result<()> {
+    let mut fuel = Some(REPL_FUEL);
+    let mut run = |src: &str| {
+        let entry = run_entry(src, vm, &mut fuel);
+        if let Err(err) = &entry.result {
+            exit_on_request(err);
+        }
+        entry_json(&entry)
+    };
+    let mut stdout = io::stdout();
+    for filename in filenames {
+        let program = read_source(filename)?;
+        writeln!(stdout, "{}", run(&program))?;
+    }
+    stdout.flush()?;
+    for line in io::stdin().lock().lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let answer = match json::parse(&line) {
+            Some(json::Json::String(src)) => run(&src),
+            _ => format!(
+                "{{\"value\": null, \"type\": null, \"output\": \"\", \"warnings\": [], \"error\": {{\"message\": {}, {}}}, \"time_ms\": 0}}",
+                json::quote("Expected a JSON string."),
+                json_position(usize::MAX, usize::MAX)
+            ),
+        };
+        writeln!(stdout, "{}", answer)?;
+        stdout.flush()?;
+    }
+    Ok(())
 }
 
 fn main() -> io::Result<()> {
     let mut vm = vm::VirtualMachine::new();
     let args: Vec<String> = env::args().collect();
-    for filename in args.iter().skip(1) {
-        let mut file = File::open(&filename)?;
-        let mut program = String::new();
-        file.read_to_string(&mut program)?;
-        eval(&filename, &program, &mut vm);
+    if args.iter().any(|arg| arg == "--opcodes") {
+        print!("{}", vm::opcode_reference());
+        return Ok(());
     }
+    if args.iter().any(|arg| arg == "--version") {
+        let version = plover::version();
+        println!("plover {}", version);
+        println!("bytecode format {}", version.bytecode);
+        if version.features.is_empty() {
+            println!("features: none");
+        } else {
+            println!("features: {}", version.features.join(", "));
+        }
+        return Ok(());
+    }
+    // Running files evaluates them without starting the REPL afterwards, and
+    // exits with a nonzero status if any of them fail.
+    let command = match args.get(1).and_then(|arg| completions::command(arg)) {
+        Some(command) => command.name,
+        None => "",
+    };
+    let mut annotate = false;
+    let mut baseline = None;
+    let mut check = false;
+    let mut crash_report = false;
+    let mut emit_bytecode = false;
+    let mut filenames = Vec::new();
+    let mut flamegraph = None;
+    let mut install = false;
+    let mut json = false;
+    let mut output = None;
+    let mut radix = 10;
+    let mut runs = 10;
+    let mut save = None;
+    let mut separator = String::new();
+    let mut target_wasm = false;
+    let mut options = args.iter().skip(if command.is_empty() { 1 } else { 2 });
+    while let Some(arg) = options.next() {
+        match arg.as_str() {
+            "-o" if command == "build" => match options.next() {
+                Some(filename) => output = Some(filename.to_string()),
+                None => {
+                    println!("Missing file name for -o.");
+                    process::exit(2);
+                }
+            },
+            "--target=bytecode" if command == "build" => target_wasm = false,
+            "--target=wasm" if command == "build" => target_wasm = true,
+            target if command == "build" && target.starts_with("--target=") => {
+                println!("Unknown target {}.", &target["--target=".len()..]);
+                process::exit(2);
+            }
+            "--baseline" if command == "bench" => match options.next() {
+                Some(filename) => baseline = Some(filename.to_string()),
+                None => {
+                    println!("Missing file name for --baseline.");
+                    process::exit(2);
+                }
+            },
+            "--save" if command == "bench" => match options.next() {
+                Some(filename) => save = Some(filename.to_string()),
+                None => {
+                    println!("Missing file name for --save.");
+                    process::exit(2);
+                }
+            },
+            "--runs" if command == "bench" => match options.next().map(|runs| runs.parse()) {
+                Some(Ok(value)) if value > 0 => runs = value,
+                _ => {
+                    println!("Missing number of runs for --runs.");
+                    process::exit(2);
+                }
+            },
+            "--check" if command == "fmt" => check = true,
+            "--json" if command == "repl" => json = true,
+            "--install" if command == "kernel" => install = true,
+            "--annotate" if command == "profile" => annotate = true,
+            "--flamegraph" if command == "profile" => match options.next() {
+                Some(filename) => flamegraph = Some(filename.to_string()),
+                None => {
+                    println!("Missing file name for --flamegraph.");
+                    process::exit(2);
+                }
+            },
+            "-O" => vm.optimize = true,
+            "--crash-report" => crash_report = true,
+            "--emit-bytecode" => emit_bytecode = true,
+            "--pure" => vm.allowed_effects.clear(),
+            "--wrapping" => vm.overflow = vm::Overflow::Wrap,
+            "--bigint" => vm.overflow = vm::Overflow::Promote,
+            "--allow-net" => vm.enable_net(),
+            "--allow-process" => vm.enable_process(),
+            "--allow-time" => vm.enable_time(),
+            "--define" => match options.next() {
+                Some(feature) => {
+                    vm.features.insert(feature.to_string());
+                }
+                None => {
+                    println!("Missing feature name for --define.");
+                    process::exit(2);
+                }
+            },
+            "--radix" => match options.next().map(|radix| radix.parse()) {
+                Some(Ok(value)) => radix = value,
+                Some(Err(_)) | None => {
+                    println!("Missing radix for --radix.");
+                    process::exit(2);
+                }
+            },
+            "--digit-separator" => match options.next() {
+                Some(value) => separator = value.to_string(),
+                None => {
+                    println!("Missing separator for --digit-separator.");
+                    process::exit(2);
+                }
+            },
+            _ => filenames.push(arg),
+        }
+    }
+    vm.int_format = match vm::IntFormat::new(radix, &separator) {
+        Some(format) => format,
+        None => {
+            println!("Invalid radix {}, expected 2 to 36.", radix);
+            process::exit(2);
+        }
+    };
 
-    let stdin = io::stdin();
-    let mut stdout = io::stdout();
+    let mut sources = Vec::new();
+    if command == "bench" {
+        if filenames.is_empty() {
+            println!(
+                "Usage: plover bench [options] [--runs n] [--baseline file] [--save file] file..."
+            );
+            process::exit(2);
+        }
+        let baseline = match baseline {
+            Some(filename) => match fs::read_to_string(&filename) {
+                Ok(json) => match bench::from_json(&json) {
+                    Some(samples) => Some(samples),
+                    None => {
+                        println!("Unable to read {}: invalid benchmark results.", filename);
+                        process::exit(1);
+                    }
+                },
+                Err(err) => {
+                    println!("Unable to read {}: {}.", filename, err);
+                    process::exit(1);
+                }
+            },
+            None => None,
+        };
+        let samples = match bench(&filenames, &vm, runs, baseline.as_deref()) {
+            Some(samples) => samples,
+            None => process::exit(1),
+        };
+        if let Some(output) = save {
+            let json = bench::to_json(&plover::version().to_string(), &samples);
+            if let Err(err) = fs::write(&output, json) {
+                println!("Unable to write {}: {}.", output, err);
+                process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+    if command == "build" {
+        if filenames.len() != 1 {
+            println!("Usage: plover build [options] [--target=wasm] file [-o output]");
+            process::exit(2);
+        }
+        let filename = filenames[0];
+        let program = match read_source(filename) {
+            Ok(program) => program,
+            Err(err) => {
+                println!("Unable to read {}: {}.", filename, err);
+                process::exit(1);
+            }
+        };
+        let output = output.unwrap_or_else(|| {
+            Path::new(filename)
+                .with_extension(if target_wasm { "wasm" } else { "plbc" })
+                .to_string_lossy()
+                .to_string()
+        });
+        if !build(filename, &program, &output, target_wasm, &mut vm) {
+            process::exit(1);
+        }
+        return Ok(());
+    }
+    if command == "fmt" {
+        if filenames.is_empty() {
+            println!("Usage: plover fmt [--check] file...");
+            process::exit(2);
+        }
+        let mut formatted = true;
+        for filename in filenames {
+            formatted &= format_file(filename, check);
+        }
+        if !formatted {
+            process::exit(1);
+        }
+        return Ok(());
+    }
+    if command == "examples" {
+        let dir = match filenames.as_slice() {
+            [run_all] if run_all.as_str() == "run-all" => "examples",
+            [run_all, dir] if run_all.as_str() == "run-all" => dir.as_str(),
+            _ => {
+                println!("Usage: plover examples run-all [options] [directory]");
+                process::exit(2);
+            }
+        };
+        if !run_examples(dir, &vm)? {
+            process::exit(1);
+        }
+        return Ok(());
+    }
+    if command == "completions" {
+        match filenames.as_slice() {
+            [shell] => match completions::script(shell) {
+                Some(script) => print!("{}", script),
+                None => {
+                    println!("Unknown shell {}.", shell);
+                    process::exit(2);
+                }
+            },
+            _ => {
+                println!(
+                    "Usage: plover completions {}",
+                    completions::SHELLS.join("|")
+                );
+                process::exit(2);
+            }
+        }
+        return Ok(());
+    }
+    if command == "learn" {
+        if !filenames.is_empty() {
+            println!("Usage: plover learn");
+            process::exit(2);
+        }
+        learn::learn(&mut repl::Editor::new())?;
+        return Ok(());
+    }
+    if command == "kernel" {
+        if install != filenames.is_empty() {
+            println!("Usage: plover kernel [options] connection-file");
+            println!("       plover kernel --install [options]");
+            process::exit(2);
+        }
+        if !cfg!(feature = "kernel") {
+            println!("kernel needs the kernel feature.");
+            process::exit(2);
+        }
+        #[cfg(feature = "kernel")]
+        if install {
+            // The kernel is started with the same options that it was
+            // installed with.
+            let options: Vec<&str> = args[2..]
+                .iter()
+                .map(|arg| arg.as_str())
+                .filter(|arg| *arg != "--install")
+                .collect();
+            let dir = kernel::install(&options)?;
+            println!("Installed the kernel in {}.", dir.display());
+        } else if !kernel::run(filenames[0], vm)? {
+            process::exit(1);
+        }
+        return Ok(());
+    }
+    if command == "debug" {
+        if filenames.len() != 1 {
+            println!("Usage: plover debug [options] file");
+            process::exit(2);
+        }
+        let filename = filenames[0];
+        let program = match read_source(filename) {
+            Ok(program) => program,
+            Err(err) => {
+                println!("Unable to read {}: {}.", filename, err);
+                process::exit(1);
+            }
+        };
+        if !debug(filename, &program, &mut vm)? {
+            process::exit(1);
+        }
+        return Ok(());
+    }
+    if command == "profile" {
+        if filenames.len() != 1 {
+            println!("Usage: plover profile [options] [--annotate] [--flamegraph output] file");
+            process::exit(2);
+        }
+        let filename = filenames[0];
+        let program = match read_source(filename) {
+            Ok(program) => program,
+            Err(err) => {
+                println!("Unable to read {}: {}.", filename, err);
+                process::exit(1);
+            }
+        };
+        if !profile(filename, &program, &mut vm, annotate, flamegraph.as_deref()) {
+            process::exit(1);
+        }
+        return Ok(());
+    }
+    if command == "run" {
+        if filenames.is_empty() {
+            println!("Usage: plover run [options] file...");
+            process::exit(2);
+        }
+        let mut result = None;
+        for filename in filenames {
+            let bytes = match fs::read(filename) {
+                Ok(bytes) => bytes,
+                Err(err) => {
+                    println!("Unable to read {}: {}.", filename, err);
+                    process::exit(1);
+                }
+            };
+            if bytecode::is_bytecode(&bytes) {
+                result = run_bytecode(
+                    filename,
+                    &bytes,
+                    &mut vm,
+                    if crash_report { Some(&sources) } else { None },
+                    emit_bytecode,
+                );
+            } else {
+                let program = match String::from_utf8(bytes) {
+                    Ok(program) => program,
+                    Err(_) => {
+                        println!("Unable to read {}: invalid UTF-8.", filename);
+                        process::exit(1);
+                    }
+                };
+                if crash_report {
+                    sources.push((filename.to_string(), program.to_string()));
+                }
+                result = eval(
+                    filename,
+                    &program,
+                    &mut vm,
+                    if crash_report { Some(&sources) } else { None },
+                    emit_bytecode,
+                )
+                .map(|(v, _)| v);
+            }
+            if result.is_none() {
+                process::exit(1);
+            }
+        }
+        if let Some(v) = result {
+            println!("{}", v.display(&vm.symbols).ints(&vm.int_format));
+        }
+        return Ok(());
+    }
+
+    if json {
+        return json_repl(&filenames, &mut vm);
+    }
+
+    for filename in filenames {
+        let program = read_source(filename)?;
+        if crash_report {
+            sources.push((filename.to_string(), program.to_string()));
+        }
+        if let Some((v, _)) = eval(
+            filename,
+            &program,
+            &mut vm,
+            if crash_report { Some(&sources) } else { None },
+            emit_bytecode,
+        ) {
+            println!("{}", v.display(&vm.symbols).ints(&vm.int_format));
+        }
+    }
+
+    let mut editor = repl::Editor::new();
     println!("Welcome to Plover!");
-    print!("> ");
-    stdout.flush()?;
 
-    for line in stdin.lock().lines() {
-        match line {
-            Ok(src) => {
-                eval("<stdin>", &src, &mut vm);
+    // Lines are collected until they form a complete program, so that a
+    // definition can be spread over several lines.
+    let mut src = String::new();
+    let mut fuel = Some(REPL_FUEL);
+    loop {
+        let prompt = if src.is_empty() { "> " } else { "... " };
+        let eof = match editor.read_line(prompt)? {
+            repl::Input::Line(line)
+                if src.is_empty() && line.split_whitespace().next() == Some(":fuel") =>
+            {
+                println!(
+                    "{}",
+                    fuel_command(line.trim()[":fuel".len()..].trim(), &mut fuel)
+                );
+                continue;
+            }
+            repl::Input::Line(line) if src.is_empty() && line.trim() == ":paste" => {
+                println!("Pasting, end with :end or Ctrl-D.");
+                match read_paste(&mut editor)? {
+                    Some(block) => src = block,
+                    None => continue,
+                }
+                false
+            }
+            repl::Input::Line(line) => {
+                if !src.is_empty() {
+                    src.push('\n');
+                }
+                src.push_str(&line);
+                if src.trim().is_empty() {
+                    src.clear();
+                    continue;
+                }
+                if parser::incomplete(&src) {
+                    continue;
+                }
+                false
+            }
+            repl::Input::Interrupted => {
+                src.clear();
+                continue;
             }
-            _ => break,
+            repl::Input::Eof if src.is_empty() => break,
+            repl::Input::Eof => true,
+        };
+        if crash_report {
+            sources.push(("<stdin>".to_string(), src.to_string()));
         }
-        print!("> ");
-        stdout.flush()?;
+        match fuel {
+            Some(n) => vm.set_fuel(n),
+            None => vm.clear_fuel(),
+        }
+        match eval(
+            "<stdin>",
+            &src,
+            &mut vm,
+            if crash_report { Some(&sources) } else { None },
+            emit_bytecode,
+        ) {
+            Some((v, typ)) => {
+                println!("{} : {}", v.display(&vm.symbols).ints(&vm.int_format), typ)
+            }
+            None if fuel.is_some() && vm.fuel() == Some(0) => {
+                println!("Use :fuel N to allow N instructions, or :fuel off for no limit.")
+            }
+            None => {}
+        }
+        if eof {
+            break;
+        }
+        src.clear();
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use plover::typeinfer::Type;
+    use plover::vm;
+
+    #[test]
+    fn entries() {
+        // Entries are run alike by the JSON mode of the REPL and the kernel, and
+        // each sees the globals defined by the ones before it.
+        let mut vm = vm::VirtualMachine::new();
+        let mut fuel = Some(crate::REPL_FUEL);
+        let entry = crate::run_entry("def x := 1 + 2", &mut vm, &mut fuel);
+        assert!(entry.result.is_ok());
+        let entry = crate::run_entry("x + 1", &mut vm, &mut fuel);
+        assert!(matches!(
+            &entry.result,
+            Ok(Some((value, Type::Integer))) if value == "4"
+        ));
+        assert!(crate::entry_json(&entry).contains("\"type\": \"integer\""));
+        let entry = crate::run_entry("x && true", &mut vm, &mut fuel);
+        assert!(matches!(
+            &entry.result,
+            Err(err) if err.err == "Type error: expected boolean but found integer."
+        ));
+    }
+
+    #[test]
+    fn examples_formatted() {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/examples");
+        let mut count = 0;
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|ext| ext == "plover") {
+                assert!(crate::format_file(&path.to_string_lossy(), true));
+                count += 1;
+            }
+        }
+        assert!(count > 0);
+    }
+}
@@ -0,0 +1,53 @@
+// With the panic-free feature, a panic raised while the interpreter is running
+// aborts the process, rather than unwinding to a caller that might catch it.
+// Running the tests this way checks that the interpreter only ever fails by
+// returning an error. Without the feature, entering the interpreter does
+// nothing.
+
+#[cfg(feature = "panic-free")]
+mod check {
+    use std::cell::Cell;
+    use std::panic;
+    use std::process;
+    use std::sync::Once;
+
+    thread_local! {
+        static DEPTH: Cell<usize> = const { Cell::new(0) };
+    }
+
+    static HOOK: Once = Once::new();
+
+    pub struct Guard;
+
+    impl Drop for Guard {
+        fn drop(&mut self) {
+            DEPTH.with(|depth| depth.set(depth.get() - 1));
+        }
+    }
+
+    pub fn enter() -> Guard {
+        HOOK.call_once(|| {
+            let default = panic::take_hook();
+            panic::set_hook(Box::new(move |info| {
+                default(info);
+                if DEPTH.with(|depth| depth.get()) > 0 {
+                    eprintln!("The interpreter panicked instead of returning an error.");
+                    process::abort();
+                }
+            }));
+        });
+        DEPTH.with(|depth| depth.set(depth.get() + 1));
+        Guard
+    }
+}
+
+#[cfg(feature = "panic-free")]
+pub use check::{enter, Guard};
+
+#[cfg(not(feature = "panic-free"))]
+pub struct Guard;
+
+#[cfg(not(feature = "panic-free"))]
+pub fn enter() -> Guard {
+    Guard
+}
@@ -1,7 +1,13 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
 use std::fmt;
 
+use crate::bigint::BigInt;
+use crate::panics;
 use crate::pest::Parser;
-use pest::iterators::Pair;
+use crate::typeinfer;
+use pest::iterators::{Pair, Pairs};
+use pest::unicode::{XID_CONTINUE, XID_START};
 
 #[derive(Parser)]
 #[grammar = "plover.pest"]
@@ -10,6 +16,8 @@ pub struct PloverParser;
 #[derive(Clone, Debug)]
 pub enum Operator {
     And,
+    Concat,
+    Cons,
     Divide,
     Equal,
     GreaterEqual,
@@ -23,12 +31,16 @@ pub enum Operator {
     NotEqual,
     Or,
     Plus,
+    Power,
+    Range,
 }
 
 impl fmt::Display for Operator {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Operator::And => write!(f, "&&"),
+            Operator::Concat => write!(f, "++"),
+            Operator::Cons => write!(f, "::"),
             Operator::Divide => write!(f, "/"),
             Operator::Equal => write!(f, "=="),
             Operator::Greater => write!(f, ">"),
@@ -42,33 +54,74 @@ impl fmt::Display for Operator {
             Operator::NotEqual => write!(f, "~="),
             Operator::Or => write!(f, "||"),
             Operator::Plus => write!(f, "+"),
+            Operator::Power => write!(f, "**"),
+            Operator::Range => write!(f, ".."),
         }
     }
 }
 
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone)]
 pub enum AST {
+    Array(Vec<AST>, usize, usize),
     BinaryOp(Operator, Box<AST>, Box<AST>, usize, usize),
     Boolean(bool, usize, usize),
+    BigInt(BigInt, usize, usize),
+    Block(Box<AST>, usize, usize),
+    Bytes(Vec<u8>, usize, usize),
     Call(Box<AST>, Box<AST>, usize, usize),
+    Char(char, usize, usize),
     Datatype(String, Vec<(String, Option<AST>)>, usize, usize),
+    Defer(Box<AST>, usize, usize),
     Define(Box<AST>, Box<AST>, usize, usize),
+    Field(Box<AST>, String, usize, usize),
+    Float(f64, usize, usize),
+    For(Box<AST>, Box<AST>, Box<AST>, usize, usize),
     Function(Option<String>, Box<AST>, Box<AST>, usize, usize),
     Identifier(String, usize, usize),
-    If(Vec<(AST, AST)>, Box<AST>, usize, usize),
+    If(Vec<(AST, AST)>, Option<Box<AST>>, usize, usize),
     Integer(i64, usize, usize),
-    Match(Box<AST>, Vec<(String, Option<AST>, AST)>, usize, usize),
+    Let(Box<AST>, Box<AST>, Box<AST>, usize, usize),
+    Letrec(Vec<AST>, usize, usize),
+    List(Vec<AST>, usize, usize),
+    Map(Vec<(AST, AST)>, usize, usize),
+    Match(
+        Box<AST>,
+        Vec<(String, Option<AST>, Option<AST>, AST)>,
+        usize,
+        usize,
+    ),
     Program(Vec<AST>, usize, usize),
+    Propagate(Box<AST>, usize, usize),
+    Record(Vec<(String, AST)>, usize, usize),
+    Sequence(Vec<AST>, usize, usize),
+    String(String, usize, usize),
+    Try(Box<AST>, Box<AST>, Box<AST>, usize, usize),
     Tuple(Vec<AST>, usize, usize),
     UnaryOp(Operator, Box<AST>, usize, usize),
     Unit(usize, usize),
+    Update(Box<AST>, Vec<(String, AST)>, usize, usize),
+    When(String, Box<AST>, Option<Box<AST>>, usize, usize),
+    While(Box<AST>, Box<AST>, usize, usize),
 }
 
 impl fmt::Display for AST {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
+            AST::Array(elements, _, _) => {
+                write!(f, "[")?;
+                for i in 0..elements.len() {
+                    write!(f, "{}", elements[i])?;
+                    if i + 1 != elements.len() {
+                        write!(f, ", ")?;
+                    }
+                }
+                write!(f, "]:Array")
+            }
             AST::BinaryOp(op, lhs, rhs, _, _) => write!(f, "({} {} {})", op, lhs, rhs),
             AST::Boolean(b, _, _) => write!(f, "{}:Boolean", b),
             AST::Call(fun, args, _, _) => write!(f, "(apply {} {})", fun, args),
+            AST::Char(c, _, _) => write!(f, "{:?}:Char", c),
             AST::Datatype(name, variants, _, _) => {
                 write!(f, "(")?;
                 for i in 0..variants.len() {
@@ -82,7 +135,24 @@ impl fmt::Display for AST {
                 }
                 write!(f, ") {}:Type", name)
             }
+            AST::Defer(value, _, _) => write!(f, "(defer {})", value),
             AST::Define(id, value, _, _) => write!(f, "(define {} {})", id, value),
+            AST::Let(id, value, body, _, _) => write!(f, "(let {} {} {})", id, value, body),
+            AST::For(param, iter, body, _, _) => write!(f, "(for {} {} {})", param, iter, body),
+            AST::While(cond, body, _, _) => write!(f, "(while {} {})", cond, body),
+            AST::Block(body, _, _) => write!(f, "(do {})", body),
+            AST::Try(body, id, handler, _, _) => write!(f, "(try {} {} {})", body, id, handler),
+            AST::Letrec(functions, _, _) => {
+                write!(f, "(letrec")?;
+                for function in functions {
+                    write!(f, " {}", function)?;
+                }
+                write!(f, ")")
+            }
+            AST::Field(record, field, _, _) => write!(f, "(. {} {})", record, field),
+            AST::BigInt(n, _, _) => write!(f, "{}:BigInt", n),
+            AST::Bytes(bytes, _, _) => write!(f, "{:?}:Bytes", bytes),
+            AST::Float(n, _, _) => write!(f, "{:?}:Float", n),
             AST::Function(id, param, body, _, _) => {
                 if let Some(id) = id {
                     write!(f, "({} {} {})", id, param, body)
@@ -92,21 +162,27 @@ impl fmt::Display for AST {
             }
             AST::Identifier(id, _, _) => write!(f, "{}:Identifier", id),
             AST::If(conds, els, _, _) => {
-                write!(f, "(if ")?;
+                write!(f, "(if")?;
                 for cond in conds {
-                    write!(f, "(cond {} {}) ", cond.0, cond.1)?;
+                    write!(f, " (cond {} {})", cond.0, cond.1)?;
+                }
+                match els {
+                    Some(els) => write!(f, " (else {}))", els),
+                    None => write!(f, ")"),
                 }
-                write!(f, "(else {}))", els)
             }
             AST::Integer(n, _, _) => write!(f, "{}:Integer", n),
             AST::Match(id, cases, _, _) => {
                 write!(f, "(match {} ", id)?;
                 for i in 0..cases.len() {
+                    write!(f, "(case {}", cases[i].0)?;
                     if let Some(param) = &cases[i].1 {
-                        write!(f, "(case {}: {} {})", cases[i].0, param, cases[i].2)?;
-                    } else {
-                        write!(f, "(case {} {})", cases[i].0, cases[i].2)?;
+                        write!(f, ": {}", param)?;
+                    }
+                    if let Some(guard) = &cases[i].2 {
+                        write!(f, " (when {})", guard)?;
                     }
+                    write!(f, " {})", cases[i].3)?;
                     if i + 1 != cases.len() {
                         write!(f, " ")?;
                     }
@@ -128,6 +204,45 @@ impl fmt::Display for AST {
                 }
                 Ok(())
             }
+            AST::Sequence(expressions, _, _) => {
+                write!(f, "(seq")?;
+                for expression in expressions {
+                    write!(f, " {}", expression)?;
+                }
+                write!(f, ")")
+            }
+            AST::List(elements, _, _) => {
+                write!(f, "%[")?;
+                for i in 0..elements.len() {
+                    write!(f, "{}", elements[i])?;
+                    if i + 1 != elements.len() {
+                        write!(f, ", ")?;
+                    }
+                }
+                write!(f, "]:List")
+            }
+            AST::Map(entries, _, _) => {
+                write!(f, "%{{")?;
+                for i in 0..entries.len() {
+                    write!(f, "{} => {}", entries[i].0, entries[i].1)?;
+                    if i + 1 != entries.len() {
+                        write!(f, ", ")?;
+                    }
+                }
+                write!(f, "}}:Map")
+            }
+            AST::Propagate(ast, _, _) => write!(f, "(? {})", ast),
+            AST::Record(fields, _, _) => {
+                write!(f, "{{")?;
+                for i in 0..fields.len() {
+                    write!(f, "{} = {}", fields[i].0, fields[i].1)?;
+                    if i + 1 != fields.len() {
+                        write!(f, ", ")?;
+                    }
+                }
+                write!(f, "}}:Record")
+            }
+            AST::String(s, _, _) => write!(f, "{:?}:String", s),
             AST::Tuple(elements, _, _) => {
                 write!(f, "(")?;
                 for i in 0..elements.len() {
@@ -140,50 +255,105 @@ impl fmt::Display for AST {
             }
             AST::UnaryOp(op, ast, _, _) => write!(f, "({} {})", op, ast),
             AST::Unit(_, _) => write!(f, "():Unit"),
+            AST::Update(record, fields, _, _) => {
+                write!(f, "(with {} ", record)?;
+                for i in 0..fields.len() {
+                    write!(f, "{} = {}", fields[i].0, fields[i].1)?;
+                    if i + 1 != fields.len() {
+                        write!(f, ", ")?;
+                    }
+                }
+                write!(f, ")")
+            }
+            AST::When(feature, body, els, _, _) => match els {
+                Some(els) => write!(f, "(when {:?} {} (else {}))", feature, body, els),
+                None => write!(f, "(when {:?} {})", feature, body),
+            },
         }
     }
 }
 
+#[derive(Debug)]
 pub struct ParseError {
     pub msg: String,
     pub line: usize,
     pub col: usize,
 }
 
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+impl Error for ParseError {}
+
+// A comment, including its delimiters. A comment on a line of its own is
+// written before or after the expression it is attached to, while one that
+// follows code stays at the end of the line. A blank line after a comment
+// that comes before its expression is kept.
+#[derive(Clone, Debug)]
+pub struct Comment {
+    pub text: String,
+    pub line: usize,
+    pub col: usize,
+    pub own_line: bool,
+    pub blank_after: bool,
+}
+
+// The comments in a program, which are attached to the expressions in the
+// program or a function body that they come before or after, keyed by the
+// position where each expression starts as given by parser::start. The
+// expressions that a blank line separates from the one before them are kept
+// as well.
+#[derive(Clone, Debug, Default)]
+pub struct Trivia {
+    pub leading: HashMap<(usize, usize), Vec<Comment>>,
+    pub trailing: HashMap<(usize, usize), Vec<Comment>>,
+    pub blank_before: HashSet<(usize, usize)>,
+}
+
+impl Trivia {
+    pub fn is_empty(&self) -> bool {
+        self.leading.is_empty() && self.trailing.is_empty() && self.blank_before.is_empty()
+    }
+
+    pub fn has_comments(&self, start: (usize, usize)) -> bool {
+        self.leading.contains_key(&start) || self.trailing.contains_key(&start)
+    }
+}
+
+// Replaces the escapes in the contents of a string or character literal.
+fn unescape(s: &str) -> String {
+    let mut value = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            value.push(match chars.next() {
+                Some('n') => '\n',
+                Some('r') => '\r',
+                Some('t') => '\t',
+                Some('0') => '\0',
+                Some(c) => c,
+                None => unreachable!(),
+            });
+        } else {
+            value.push(c);
+        }
+    }
+    value
+}
+
 #[allow(clippy::cognitive_complexity)]
 fn astify(pair: Pair<Rule>) -> AST {
     match pair.as_rule() {
-        Rule::addition => {
-            let mut inner = pair.into_inner();
-            let mut lhs = astify(inner.next().unwrap());
-            loop {
-                if inner.peek().is_none() {
-                    break;
-                } else {
-                    let pair = inner.next().unwrap();
-                    if let Rule::addition_op = pair.as_rule() {
-                        let (line, col) = pair.as_span().start_pos().line_col();
-                        let op = match pair.into_inner().next().unwrap().as_rule() {
-                            Rule::minus => Operator::Minus,
-                            Rule::or => Operator::Or,
-                            Rule::plus => Operator::Plus,
-                            _ => unreachable!(),
-                        };
-                        let rhs = inner.next().unwrap();
-                        lhs = AST::BinaryOp(op, Box::new(lhs), Box::new(astify(rhs)), line, col)
-                    } else {
-                        unreachable!();
-                    }
-                }
-            }
-            lhs
-        }
+        Rule::addition => astify_addition(pair),
         Rule::boolean => {
-            let (line, col) = pair.as_span().start_pos().line_col();
+            let (line, col) = pair.line_col();
             AST::Boolean(pair.as_str().trim().parse().unwrap(), line, col)
         }
         Rule::call => {
-            let (line, col) = pair.as_span().start_pos().line_col();
+            let (line, col) = pair.line_col();
             let mut inner = pair.into_inner();
             let value_or_fn = astify(inner.next().unwrap());
             if inner.peek().is_some() {
@@ -206,12 +376,13 @@ fn astify(pair: Pair<Rule>) -> AST {
                 } else {
                     let pair = inner.next().unwrap();
                     if let Rule::comparison_op = pair.as_rule() {
-                        let (line, col) = pair.as_span().start_pos().line_col();
+                        let (line, col) = pair.line_col();
                         let op = match pair.into_inner().next().unwrap().as_rule() {
                             Rule::greater => Operator::Greater,
                             Rule::greater_equal => Operator::GreaterEqual,
                             Rule::less => Operator::Less,
                             Rule::less_equal => Operator::LessEqual,
+                            Rule::range => Operator::Range,
                             _ => unreachable!(),
                         };
                         let rhs = inner.next().unwrap();
@@ -224,25 +395,27 @@ fn astify(pair: Pair<Rule>) -> AST {
             lhs
         }
         Rule::conditional => {
-            if pair.as_str().starts_with("if") {
-                let (line, col) = pair.as_span().start_pos().line_col();
+            // An if expression has a condition, a result and an optional
+            // else branch, while anything else is a single expression, which
+            // may be an identifier that starts with "if".
+            if pair.clone().into_inner().count() > 1 {
+                let (line, col) = pair.line_col();
                 let mut conds = Vec::<(AST, AST)>::new();
                 let mut inner = pair.into_inner();
-                loop {
-                    let cond_or_else = astify(inner.next().unwrap());
-                    if inner.peek().is_some() {
-                        let then = astify(inner.next().unwrap());
-                        conds.push((cond_or_else, then));
-                    } else {
-                        return AST::If(conds, Box::new(cond_or_else), line, col);
+                while let Some(cond_or_else) = inner.next() {
+                    let cond_or_else = astify(cond_or_else);
+                    match inner.next() {
+                        Some(then) => conds.push((cond_or_else, astify(then))),
+                        None => return AST::If(conds, Some(Box::new(cond_or_else)), line, col),
                     }
                 }
+                AST::If(conds, None, line, col)
             } else {
                 astify(pair.into_inner().next().unwrap())
             }
         }
         Rule::datatype => {
-            let (line, col) = pair.as_span().start_pos().line_col();
+            let (line, col) = pair.line_col();
             let mut inner = pair.into_inner();
             let name = inner.next().unwrap().as_str().trim();
             let mut variants = Vec::new();
@@ -263,38 +436,78 @@ fn astify(pair: Pair<Rule>) -> AST {
             AST::Datatype(name.into(), variants, line, col)
         }
         Rule::def => {
-            let (line, col) = pair.as_span().start_pos().line_col();
+            let (line, col) = pair.line_col();
             let mut inner = pair.into_inner();
             let id = astify(inner.next().unwrap());
             let value = astify(inner.next().unwrap());
             AST::Define(Box::new(id), Box::new(value), line, col)
         }
-        Rule::equality => {
+        Rule::letrec => {
+            let (line, col) = pair.line_col();
+            AST::Letrec(pair.into_inner().map(astify).collect(), line, col)
+        }
+        Rule::let_expr => {
+            let (line, col) = pair.line_col();
             let mut inner = pair.into_inner();
-            let mut lhs = astify(inner.next().unwrap());
-            loop {
-                if inner.peek().is_none() {
-                    break;
+            let id = astify(inner.next().unwrap());
+            let value = astify(inner.next().unwrap());
+            let body = astify(inner.next().unwrap());
+            AST::Let(Box::new(id), Box::new(value), Box::new(body), line, col)
+        }
+        Rule::for_expr => astify_for(pair),
+        Rule::while_expr => astify_while(pair),
+        Rule::block => {
+            let (line, col) = pair.line_col();
+            let body = astify(pair.into_inner().next().unwrap());
+            AST::Block(Box::new(body), line, col)
+        }
+        Rule::defer => astify_defer(pair),
+        Rule::try_expr => {
+            let (line, col) = pair.line_col();
+            let mut inner = pair.into_inner();
+            let body = astify(inner.next().unwrap());
+            let id = astify(inner.next().unwrap());
+            let handler = astify(inner.next().unwrap());
+            AST::Try(Box::new(body), Box::new(id), Box::new(handler), line, col)
+        }
+        Rule::equality => astify_equality(pair),
+        Rule::bigint => {
+            let (line, col) = pair.line_col();
+            let digits = pair.as_str().trim().trim_end_matches('n');
+            AST::BigInt(digits.parse().unwrap(), line, col)
+        }
+        Rule::bytes => {
+            let (line, col) = pair.line_col();
+            let s = pair.as_str();
+            let mut value = Vec::new();
+            let mut bytes = s[2..s.len() - 1].bytes();
+            while let Some(b) = bytes.next() {
+                if b == b'\\' {
+                    value.push(match bytes.next() {
+                        Some(b'n') => b'\n',
+                        Some(b'r') => b'\r',
+                        Some(b't') => b'\t',
+                        Some(b'0') => 0,
+                        Some(b'x') => {
+                            let digits = [bytes.next().unwrap(), bytes.next().unwrap()];
+                            let digits = std::str::from_utf8(&digits).unwrap();
+                            u8::from_str_radix(digits, 16).unwrap()
+                        }
+                        Some(b) => b,
+                        None => unreachable!(),
+                    });
                 } else {
-                    let pair = inner.next().unwrap();
-                    if let Rule::equality_op = pair.as_rule() {
-                        let (line, col) = pair.as_span().start_pos().line_col();
-                        let op = match pair.into_inner().next().unwrap().as_rule() {
-                            Rule::equal => Operator::Equal,
-                            Rule::not_equal => Operator::NotEqual,
-                            _ => unreachable!(),
-                        };
-                        let rhs = inner.next().unwrap();
-                        lhs = AST::BinaryOp(op, Box::new(lhs), Box::new(astify(rhs)), line, col)
-                    } else {
-                        unreachable!();
-                    }
+                    value.push(b);
                 }
             }
-            lhs
+            AST::Bytes(value, line, col)
+        }
+        Rule::float => {
+            let (line, col) = pair.line_col();
+            AST::Float(pair.as_str().trim().parse().unwrap(), line, col)
         }
         Rule::function => {
-            let (line, col) = pair.as_span().start_pos().line_col();
+            let (line, col) = pair.line_col();
             let mut inner = pair.into_inner();
             let id_or_param = inner.next().unwrap();
             let param_or_body = astify(inner.next().unwrap());
@@ -318,35 +531,10 @@ fn astify(pair: Pair<Rule>) -> AST {
             }
         }
         Rule::identifier => {
-            let (line, col) = pair.as_span().start_pos().line_col();
+            let (line, col) = pair.line_col();
             AST::Identifier(pair.as_str().trim().parse().unwrap(), line, col)
         }
-        Rule::match_expr => {
-            let (line, col) = pair.as_span().start_pos().line_col();
-            let mut inner = pair.into_inner();
-            let cond = astify(inner.next().unwrap());
-            let mut cases = Vec::new();
-            loop {
-                let variant = inner.next().unwrap();
-                let mut variant_inner = variant.into_inner();
-                let id = variant_inner.next().unwrap().as_str().to_string();
-                let mut params = Vec::new();
-                for param in variant_inner {
-                    params.push(astify(param));
-                }
-                let param = match params.len() {
-                    0 => None,
-                    1 => Some(params.pop().unwrap()),
-                    _ => Some(AST::Tuple(params, line, col)),
-                };
-                let expr = astify(inner.next().unwrap());
-                cases.push((id, param, expr));
-                if inner.peek().is_none() {
-                    break;
-                }
-            }
-            AST::Match(Box::new(cond), cases, line, col)
-        }
+        Rule::match_expr => astify_match(pair),
         Rule::multiplication => {
             let mut inner = pair.into_inner();
             let mut lhs = astify(inner.next().unwrap());
@@ -356,7 +544,7 @@ fn astify(pair: Pair<Rule>) -> AST {
                 } else {
                     let pair = inner.next().unwrap();
                     if let Rule::multiplication_op = pair.as_rule() {
-                        let (line, col) = pair.as_span().start_pos().line_col();
+                        let (line, col) = pair.line_col();
                         let op = match pair.into_inner().next().unwrap().as_rule() {
                             Rule::and => Operator::And,
                             Rule::divide => Operator::Divide,
@@ -374,24 +562,36 @@ fn astify(pair: Pair<Rule>) -> AST {
             lhs
         }
         Rule::number => {
-            let (line, col) = pair.as_span().start_pos().line_col();
+            let (line, col) = pair.line_col();
             AST::Integer(pair.as_str().trim().parse().unwrap(), line, col)
         }
         Rule::body | Rule::program => {
-            let (line, col) = pair.as_span().start_pos().line_col();
+            let (line, col) = pair.line_col();
             let mut exprs = Vec::new();
             for expr in pair.into_inner() {
                 if expr.as_rule() != Rule::EOI {
-                    exprs.push(astify(expr));
+                    exprs.push(astify_sequence(expr));
                 }
             }
             AST::Program(exprs, line, col)
         }
+        Rule::sequence => astify_sequence(pair),
+        Rule::string => {
+            let (line, col) = pair.line_col();
+            let s = pair.as_str();
+            AST::String(unescape(&s[1..s.len() - 1]), line, col)
+        }
+        Rule::char => {
+            let (line, col) = pair.line_col();
+            let s = pair.as_str();
+            let c = unescape(&s[1..s.len() - 1]).chars().next().unwrap();
+            AST::Char(c, line, col)
+        }
         Rule::unary => {
             let mut inner = pair.into_inner();
             let pair = inner.next().unwrap();
             if let Rule::unary_op = pair.as_rule() {
-                let (line, col) = pair.as_span().start_pos().line_col();
+                let (line, col) = pair.line_col();
                 let op = match pair.into_inner().next().unwrap().as_rule() {
                     Rule::minus => Operator::Minus,
                     Rule::not => Operator::Not,
@@ -399,11 +599,33 @@ fn astify(pair: Pair<Rule>) -> AST {
                 };
                 AST::UnaryOp(op, Box::new(astify(inner.next().unwrap())), line, col)
             } else {
-                astify(pair)
+                let base = astify(pair);
+                match inner.next() {
+                    Some(op) => {
+                        let (line, col) = op.line_col();
+                        let exponent = astify(inner.next().unwrap());
+                        AST::BinaryOp(
+                            Operator::Power,
+                            Box::new(base),
+                            Box::new(exponent),
+                            line,
+                            col,
+                        )
+                    }
+                    None => base,
+                }
             }
         }
+        Rule::array => {
+            let (line, col) = pair.line_col();
+            AST::Array(pair.into_inner().map(astify).collect(), line, col)
+        }
+        Rule::list => {
+            let (line, col) = pair.line_col();
+            AST::List(pair.into_inner().map(astify).collect(), line, col)
+        }
         Rule::tuple => {
-            let (line, col) = pair.as_span().start_pos().line_col();
+            let (line, col) = pair.line_col();
             let mut elements = Vec::new();
             let mut inner = pair.into_inner();
             while inner.peek().is_some() {
@@ -412,22 +634,1103 @@ fn astify(pair: Pair<Rule>) -> AST {
             AST::Tuple(elements, line, col)
         }
         Rule::unit => {
-            let (line, col) = pair.as_span().start_pos().line_col();
+            let (line, col) = pair.line_col();
             AST::Unit(line, col)
         }
+        Rule::projection => {
+            let mut inner = pair.into_inner();
+            let mut record = astify(inner.next().unwrap());
+            for field in inner {
+                let (line, col) = field.line_col();
+                record = match field.as_rule() {
+                    Rule::propagate => AST::Propagate(Box::new(record), line, col),
+                    _ => AST::Field(Box::new(record), field.as_str().to_string(), line, col),
+                };
+            }
+            record
+        }
+        Rule::map => {
+            let (line, col) = pair.line_col();
+            let entries = pair
+                .into_inner()
+                .map(|entry| {
+                    let mut inner = entry.into_inner();
+                    let key = astify(inner.next().unwrap());
+                    (key, astify(inner.next().unwrap()))
+                })
+                .collect();
+            AST::Map(entries, line, col)
+        }
+        Rule::record => {
+            let (line, col) = pair.line_col();
+            AST::Record(astify_fields(pair.into_inner()), line, col)
+        }
+        Rule::update => {
+            let (line, col) = pair.line_col();
+            let mut inner = pair.into_inner();
+            let record = astify(inner.next().unwrap());
+            AST::Update(Box::new(record), astify_fields(inner), line, col)
+        }
         Rule::value => astify(pair.into_inner().next().unwrap()),
+        Rule::when_expr => {
+            let (line, col) = pair.line_col();
+            let mut inner = pair.into_inner();
+            let feature = match astify(inner.next().unwrap()) {
+                AST::String(s, _, _) => s,
+                _ => unreachable!(),
+            };
+            let body = astify(inner.next().unwrap());
+            let els = inner.next().map(|els| Box::new(astify(els)));
+            AST::When(feature, Box::new(body), els, line, col)
+        }
         _ => unreachable!(),
     }
 }
 
+// These are kept out of astify, as every nested expression pays for the size
+// of its stack frame.
+fn astify_defer(pair: Pair<Rule>) -> AST {
+    let (line, col) = pair.line_col();
+    let value = astify(pair.into_inner().next().unwrap());
+    AST::Defer(Box::new(value), line, col)
+}
+
+fn astify_for(pair: Pair<Rule>) -> AST {
+    let (line, col) = pair.line_col();
+    let mut inner = pair.into_inner();
+    let param = astify(inner.next().unwrap());
+    let iter = astify(inner.next().unwrap());
+    let body = astify(inner.next().unwrap());
+    AST::For(Box::new(param), Box::new(iter), Box::new(body), line, col)
+}
+
+fn astify_match(pair: Pair<Rule>) -> AST {
+    let (line, col) = pair.line_col();
+    let mut inner = pair.into_inner();
+    let cond = astify(inner.next().unwrap());
+    let mut cases = Vec::new();
+    loop {
+        let variant = inner.next().unwrap();
+        let (id, param) = match variant.as_rule() {
+            Rule::wildcard => ("_".to_string(), None),
+            Rule::cons_pattern => {
+                let params = variant.into_inner().map(astify).collect();
+                let param = AST::Tuple(params, line, col);
+                (typeinfer::CONS.to_string(), Some(param))
+            }
+            Rule::empty_list => (typeinfer::NIL.to_string(), None),
+            _ => {
+                let mut variant_inner = variant.into_inner();
+                let id = variant_inner.next().unwrap().as_str().to_string();
+                let mut params = Vec::new();
+                for param in variant_inner {
+                    params.push(astify(param));
+                }
+                let param = match params.len() {
+                    0 => None,
+                    1 => Some(params.pop().unwrap()),
+                    _ => Some(AST::Tuple(params, line, col)),
+                };
+                (id, param)
+            }
+        };
+        let mut expr = inner.next().unwrap();
+        let guard = if let Rule::guard = expr.as_rule() {
+            let guard = astify(expr.into_inner().next().unwrap());
+            expr = inner.next().unwrap();
+            Some(guard)
+        } else {
+            None
+        };
+        cases.push((id, param, guard, astify(expr)));
+        if inner.peek().is_none() {
+            break;
+        }
+    }
+    AST::Match(Box::new(cond), cases, line, col)
+}
+
+// A sequence of a single expression is just that expression. Bodies astify
+// their sequences directly, so that they do not add a frame of astify to the
+// stack for each level of nesting.
+fn astify_sequence(pair: Pair<Rule>) -> AST {
+    let (line, col) = pair.line_col();
+    let mut exprs: Vec<AST> = pair.into_inner().map(astify).collect();
+    if exprs.len() == 1 {
+        exprs.pop().unwrap()
+    } else {
+        AST::Sequence(exprs, line, col)
+    }
+}
+
+fn astify_while(pair: Pair<Rule>) -> AST {
+    let (line, col) = pair.line_col();
+    let mut inner = pair.into_inner();
+    let cond = astify(inner.next().unwrap());
+    let body = astify(inner.next().unwrap());
+    AST::While(Box::new(cond), Box::new(body), line, col)
+}
+
+// The operands of each cons are the additions around it, as conses are parsed
+// along with them.
+fn astify_addition(pair: Pair<Rule>) -> AST {
+    let mut inner = pair.into_inner();
+    let mut lhs = astify(inner.next().unwrap());
+    let mut conses = Vec::new();
+    while let Some(pair) = inner.next() {
+        let (line, col) = pair.line_col();
+        let rhs = astify(inner.next().unwrap());
+        let op = match pair.as_rule() {
+            Rule::cons_op => {
+                conses.push((lhs, line, col));
+                lhs = rhs;
+                continue;
+            }
+            Rule::addition_op => match pair.into_inner().next().unwrap().as_rule() {
+                Rule::concat => Operator::Concat,
+                Rule::minus => Operator::Minus,
+                Rule::or => Operator::Or,
+                Rule::plus => Operator::Plus,
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        };
+        lhs = AST::BinaryOp(op, Box::new(lhs), Box::new(rhs), line, col)
+    }
+    while let Some((head, line, col)) = conses.pop() {
+        lhs = AST::BinaryOp(Operator::Cons, Box::new(head), Box::new(lhs), line, col);
+    }
+    lhs
+}
+
+// The equalities between pipes are built first, and each is then passed to
+// the function after it, so x |> f |> g is g (f (x)).
+fn astify_equality(pair: Pair<Rule>) -> AST {
+    let mut inner = pair.into_inner();
+    let mut lhs = astify(inner.next().unwrap());
+    let mut stages = Vec::new();
+    let mut pipes = Vec::new();
+    while let Some(pair) = inner.next() {
+        let (line, col) = pair.line_col();
+        let rhs = astify(inner.next().unwrap());
+        let op = match pair.as_rule() {
+            Rule::pipe_op => {
+                stages.push(lhs);
+                pipes.push((line, col));
+                lhs = rhs;
+                continue;
+            }
+            Rule::equality_op => match pair.into_inner().next().unwrap().as_rule() {
+                Rule::equal => Operator::Equal,
+                Rule::not_equal => Operator::NotEqual,
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        };
+        lhs = AST::BinaryOp(op, Box::new(lhs), Box::new(rhs), line, col)
+    }
+    stages.push(lhs);
+    let mut stages = stages.into_iter();
+    let mut arg = stages.next().unwrap();
+    for ((line, col), fun) in pipes.into_iter().zip(stages) {
+        arg = AST::Call(Box::new(fun), Box::new(arg), line, col);
+    }
+    arg
+}
+
+fn astify_fields(pairs: Pairs<Rule>) -> Vec<(String, AST)> {
+    let mut fields = Vec::new();
+    for field in pairs {
+        let mut inner = field.into_inner();
+        let id = inner.next().unwrap().as_str().to_string();
+        fields.push((id, astify(inner.next().unwrap())));
+    }
+    fields
+}
+
+// Replaces when expressions with the body selected by the enabled features.
+// This happens before type inference, so code for a disabled feature does not
+// need to type check.
+pub fn resolve_features(ast: &mut AST, features: &HashSet<String>) {
+    let mut resolved = None;
+    match ast {
+        AST::BinaryOp(_, lhs, rhs, _, _) => {
+            resolve_features(lhs, features);
+            resolve_features(rhs, features);
+        }
+        AST::Call(fun, arg, _, _) => {
+            resolve_features(fun, features);
+            resolve_features(arg, features);
+        }
+        AST::Defer(value, _, _) | AST::Define(_, value, _, _) | AST::Field(value, _, _, _) => {
+            resolve_features(value, features);
+        }
+        AST::Function(_, _, body, _, _) => {
+            resolve_features(body, features);
+        }
+        AST::If(conds, els, _, _) => {
+            for cond in conds {
+                resolve_features(&mut cond.0, features);
+                resolve_features(&mut cond.1, features);
+            }
+            if let Some(els) = els {
+                resolve_features(els, features);
+            }
+        }
+        AST::For(_, value, body, _, _)
+        | AST::Let(_, value, body, _, _)
+        | AST::Try(value, _, body, _, _)
+        | AST::While(value, body, _, _) => {
+            resolve_features(value, features);
+            resolve_features(body, features);
+        }
+        AST::Block(body, _, _) => resolve_features(body, features),
+        AST::Match(cond, cases, _, _) => {
+            resolve_features(cond, features);
+            for case in cases {
+                if let Some(guard) = &mut case.2 {
+                    resolve_features(guard, features);
+                }
+                resolve_features(&mut case.3, features);
+            }
+        }
+        AST::Array(expressions, _, _)
+        | AST::Letrec(expressions, _, _)
+        | AST::List(expressions, _, _)
+        | AST::Program(expressions, _, _)
+        | AST::Sequence(expressions, _, _)
+        | AST::Tuple(expressions, _, _) => {
+            for expression in expressions {
+                resolve_features(expression, features);
+            }
+        }
+        AST::Map(entries, _, _) => {
+            for (key, value) in entries {
+                resolve_features(key, features);
+                resolve_features(value, features);
+            }
+        }
+        AST::Record(fields, _, _) => {
+            for field in fields {
+                resolve_features(&mut field.1, features);
+            }
+        }
+        AST::Propagate(ast, _, _) | AST::UnaryOp(_, ast, _, _) => {
+            resolve_features(ast, features);
+        }
+        AST::Update(record, fields, _, _) => {
+            resolve_features(record, features);
+            for field in fields {
+                resolve_features(&mut field.1, features);
+            }
+        }
+        AST::When(feature, body, els, line, col) => {
+            let mut selected = if features.contains(feature) {
+                std::mem::replace(&mut **body, AST::Unit(*line, *col))
+            } else {
+                match els.take() {
+                    Some(els) => *els,
+                    None => AST::Unit(*line, *col),
+                }
+            };
+            resolve_features(&mut selected, features);
+            resolved = Some(selected);
+        }
+        _ => {}
+    }
+    if let Some(resolved) = resolved {
+        *ast = resolved;
+    }
+}
+
+// The reserved keywords, which are also listed in the grammar.
+pub const KEYWORDS: &[&str] = &[
+    "catch", "def", "defer", "do", "elif", "else", "elsif", "end", "false", "fn", "for", "if",
+    "in", "let", "letrec", "match", "then", "true", "try", "type", "when", "while", "with",
+];
+
+// Limits on the programs that are accepted, so that untrusted input can not
+// exhaust the stack or memory of the parser, type checker or compiler.
+#[derive(Clone, Debug)]
+pub struct Limits {
+    pub max_depth: usize,
+    pub max_size: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_depth: 512,
+            max_size: 1_000_000,
+        }
+    }
+}
+
+fn nesting_error(construct: &str, limits: &Limits, line: usize, col: usize) -> ParseError {
+    ParseError {
+        msg: format!(
+            "Expression nesting exceeds {} in {} at {}:{}.",
+            limits.max_depth, construct, line, col
+        ),
+        line,
+        col,
+    }
+}
+
+// A construct that is open while the source is scanned for nesting, or the
+// program itself, along with the prefixes chained inside of it so far. Operators, such as negations
+// and powers, take the operand that follows them and are chained until an
+// operator that binds less tightly. Lets, definitions and defers take a whole
+// expression and are chained for as long as each one is the body or value of
+// the one before.
+struct Nesting {
+    // Whether a for or while loop has yet to reach the do before its body.
+    header: bool,
+    operators: usize,
+    expressions: usize,
+}
+
+impl Nesting {
+    fn new(header: bool) -> Nesting {
+        Nesting {
+            header,
+            operators: 0,
+            expressions: 0,
+        }
+    }
+
+    fn chain(&self) -> usize {
+        self.operators + self.expressions
+    }
+}
+
+enum Token {
+    Open(&'static str, bool),
+    Close,
+    // A negation or a power, which takes the operand that follows it.
+    Operator(&'static str),
+    // A let, definition or defer, which takes the expression that follows it.
+    Prefix(&'static str),
+    // Where the value or body of a let or definition begins.
+    Slot,
+    Operand,
+    // A binary operator, which ends a chain of operators.
+    Binary,
+    // Anything else ends every chain, such as a comma or then.
+    Other,
+}
+
+// Checks how deeply expressions are nested before the source is parsed, as
+// the parser itself would run out of stack on deeply nested input. This covers
+// brackets and blocks as well as chains of prefixes, which are not delimited.
+fn check_nesting(src: &str, limits: &Limits) -> Result<(), ParseError> {
+    let mut open = vec![Nesting::new(false)];
+    let mut depth = 0;
+    let mut after_operand = false;
+    let mut chars = src.chars().peekable();
+    let (mut line, mut col) = (1, 1);
+    // The quote that started the string or character being scanned.
+    let mut quote = None;
+    while let Some(c) = chars.next() {
+        let (start_line, start_col) = (line, col);
+        if c == '\n' {
+            line += 1;
+            col = 1;
+        } else {
+            col += 1;
+        }
+        if quote.is_some() {
+            match c {
+                '\\' => {
+                    chars.next();
+                    col += 1;
+                }
+                c if quote == Some(c) => {
+                    quote = None;
+                    after_operand = true;
+                }
+                _ => {}
+            }
+            continue;
+        }
+        let next = chars.peek().copied();
+        let token = if let Some(token) = next.and_then(|next| operator(c, next)) {
+            chars.next();
+            col += 1;
+            token
+        } else {
+            match c {
+                '"' | '\'' => {
+                    quote = Some(c);
+                    continue;
+                }
+                c if c.is_whitespace() => continue,
+                '(' => Token::Open("parentheses", false),
+                '{' => Token::Open("a record", false),
+                '[' => Token::Open("an array", false),
+                ')' | '}' | ']' => Token::Close,
+                '=' => Token::Slot,
+                '-' if after_operand => Token::Binary,
+                '-' | '~' => Token::Operator("a unary operator"),
+                '.' | '?' => Token::Operand,
+                ',' | ';' | '|' => Token::Other,
+                c if c.is_ascii_digit() || c == '_' => Token::Operand,
+                c if XID_START(c) => {
+                    let mut word = c.to_string();
+                    while let Some(&c) = chars.peek() {
+                        if !XID_CONTINUE(c) {
+                            break;
+                        }
+                        word.push(c);
+                        chars.next();
+                        col += 1;
+                    }
+                    match word.as_str() {
+                        "fn" => Token::Open("a function", false),
+                        "for" => Token::Open("a for expression", true),
+                        "if" => Token::Open("an if expression", false),
+                        "letrec" => Token::Open("a letrec", false),
+                        "match" => Token::Open("a match expression", false),
+                        "try" => Token::Open("a try expression", false),
+                        "type" => Token::Open("a datatype", false),
+                        "while" => Token::Open("a while loop", true),
+                        // The do of a loop begins its body, and any other
+                        // begins a block.
+                        "do" if open.last().is_some_and(|nesting| nesting.header) => {
+                            if let Some(nesting) = open.last_mut() {
+                                nesting.header = false;
+                            }
+                            Token::Other
+                        }
+                        "do" => Token::Open("a block", false),
+                        // A when is also the guard of a case, which has no
+                        // end.
+                        "when" if next_word(chars.clone()) == "feature" => {
+                            Token::Open("a when expression", false)
+                        }
+                        "end" => Token::Close,
+                        "let" => Token::Prefix("a let expression"),
+                        "def" => Token::Prefix("a definition"),
+                        "defer" => Token::Prefix("a defer expression"),
+                        "in" => Token::Slot,
+                        "true" | "false" => Token::Operand,
+                        word if KEYWORDS.contains(&word) => Token::Other,
+                        _ => Token::Operand,
+                    }
+                }
+                _ => Token::Binary,
+            }
+        };
+        if let Token::Close = token {
+            if open.len() > 1 {
+                let closed = open.pop().unwrap();
+                depth -= closed.chain() + 1;
+            }
+            after_operand = true;
+            continue;
+        }
+        let nesting = open.last_mut().unwrap();
+        depth -= nesting.chain();
+        let construct = match token {
+            Token::Open(construct, header) => {
+                depth += nesting.chain() + 1;
+                open.push(Nesting::new(header));
+                after_operand = false;
+                construct
+            }
+            Token::Operator(construct) => {
+                nesting.operators += 1;
+                depth += nesting.chain();
+                after_operand = false;
+                construct
+            }
+            Token::Prefix(construct) => {
+                // After an operand, this begins the next expression of a
+                // sequence rather than the body of the one before.
+                if after_operand {
+                    nesting.operators = 0;
+                    nesting.expressions = 0;
+                }
+                nesting.expressions += 1;
+                depth += nesting.chain();
+                after_operand = false;
+                construct
+            }
+            Token::Slot | Token::Operand => {
+                depth += nesting.chain();
+                after_operand = matches!(token, Token::Operand);
+                continue;
+            }
+            Token::Binary => {
+                nesting.operators = 0;
+                depth += nesting.chain();
+                after_operand = false;
+                continue;
+            }
+            Token::Close | Token::Other => {
+                nesting.operators = 0;
+                nesting.expressions = 0;
+                after_operand = false;
+                continue;
+            }
+        };
+        if depth > limits.max_depth {
+            return Err(nesting_error(construct, limits, start_line, start_col));
+        }
+    }
+    Ok(())
+}
+
+// The operators and brackets written with two characters.
+fn operator(c: char, next: char) -> Option<Token> {
+    Some(match (c, next) {
+        ('%', '[') => Token::Open("a list", false),
+        ('%', '{') => Token::Open("a map", false),
+        ('*', '*') => Token::Operator("a power"),
+        (':', '=') => Token::Slot,
+        ('=' | '-', '>') => Token::Other,
+        ('=', '=') | (':', ':') | ('~', '=') | ('.', '.') | ('|', '|' | '>') => Token::Binary,
+        _ => return None,
+    })
+}
+
+// The word that follows in the source, if any.
+fn next_word(chars: impl Iterator<Item = char>) -> String {
+    chars
+        .skip_while(|c| c.is_whitespace())
+        .take_while(|&c| XID_CONTINUE(c))
+        .collect()
+}
+
+// Checks the depth and size of the program once it is parsed, which also
+// covers the nesting that is not delimited, such as chains of operators.
+fn check_limits(
+    ast: &AST,
+    limits: &Limits,
+    depth: usize,
+    size: &mut usize,
+) -> Result<(), ParseError> {
+    let (construct, line, col, children) = node(ast);
+    *size += 1;
+    if *size > limits.max_size {
+        return Err(ParseError {
+            msg: format!(
+                "Program size exceeds {} expressions at {}:{}.",
+                limits.max_size, line, col
+            ),
+            line,
+            col,
+        });
+    }
+    if depth > limits.max_depth {
+        return Err(nesting_error(construct, limits, line, col));
+    }
+    for child in children {
+        check_limits(child, limits, depth + 1, size)?;
+    }
+    Ok(())
+}
+
+// Describes an expression for errors, returning its position and the
+// expressions it contains.
+fn node(ast: &AST) -> (&'static str, usize, usize, Vec<&AST>) {
+    match ast {
+        AST::BinaryOp(_, lhs, rhs, line, col) => ("an operator", *line, *col, vec![lhs, rhs]),
+        AST::Call(fun, arg, line, col) => ("a call", *line, *col, vec![fun, arg]),
+        AST::Datatype(_, variants, line, col) => (
+            "a datatype",
+            *line,
+            *col,
+            variants.iter().filter_map(|v| v.1.as_ref()).collect(),
+        ),
+        AST::Defer(value, line, col) => ("a defer expression", *line, *col, vec![value]),
+        AST::Define(id, value, line, col) => ("a definition", *line, *col, vec![id, value]),
+        AST::Field(record, _, line, col) => ("a field", *line, *col, vec![record]),
+        AST::Function(_, param, body, line, col) => ("a function", *line, *col, vec![param, body]),
+        AST::If(conds, els, line, col) => {
+            let mut children: Vec<&AST> = Vec::new();
+            for (cond, then) in conds {
+                children.push(cond);
+                children.push(then);
+            }
+            if let Some(els) = els {
+                children.push(els);
+            }
+            ("an if expression", *line, *col, children)
+        }
+        AST::Let(id, value, body, line, col) => {
+            ("a let expression", *line, *col, vec![id, value, body])
+        }
+        AST::For(param, iter, body, line, col) => {
+            ("a for expression", *line, *col, vec![param, iter, body])
+        }
+        AST::While(cond, body, line, col) => ("a while loop", *line, *col, vec![cond, body]),
+        AST::Block(body, line, col) => ("a block", *line, *col, vec![body]),
+        AST::Letrec(functions, line, col) => ("a letrec", *line, *col, functions.iter().collect()),
+        AST::Match(cond, cases, line, col) => {
+            let mut children: Vec<&AST> = vec![cond];
+            for (_, param, guard, case) in cases {
+                children.extend(param.iter());
+                children.extend(guard.iter());
+                children.push(case);
+            }
+            ("a match expression", *line, *col, children)
+        }
+        AST::Program(expressions, line, col) => {
+            ("the program", *line, *col, expressions.iter().collect())
+        }
+        AST::Propagate(value, line, col) => ("an operator", *line, *col, vec![value]),
+        AST::Sequence(expressions, line, col) => {
+            ("a sequence", *line, *col, expressions.iter().collect())
+        }
+        AST::Record(fields, line, col) => (
+            "a record",
+            *line,
+            *col,
+            fields.iter().map(|f| &f.1).collect(),
+        ),
+        AST::Try(body, id, handler, line, col) => {
+            ("a try expression", *line, *col, vec![body, id, handler])
+        }
+        AST::Tuple(elements, line, col) => ("a tuple", *line, *col, elements.iter().collect()),
+        AST::Array(elements, line, col) => ("an array", *line, *col, elements.iter().collect()),
+        AST::List(elements, line, col) => ("a list", *line, *col, elements.iter().collect()),
+        AST::Map(entries, line, col) => (
+            "a map",
+            *line,
+            *col,
+            entries
+                .iter()
+                .flat_map(|(key, value)| [key, value])
+                .collect(),
+        ),
+        AST::UnaryOp(_, operand, line, col) => ("an operator", *line, *col, vec![operand]),
+        AST::Update(record, fields, line, col) => {
+            let mut children: Vec<&AST> = vec![record];
+            children.extend(fields.iter().map(|f| &f.1));
+            ("a record update", *line, *col, children)
+        }
+        AST::When(_, body, els, line, col) => {
+            let mut children: Vec<&AST> = vec![body];
+            children.extend(els.iter().map(|els| &**els));
+            ("a when expression", *line, *col, children)
+        }
+        AST::BigInt(_, line, col)
+        | AST::Boolean(_, line, col)
+        | AST::Bytes(_, line, col)
+        | AST::Char(_, line, col)
+        | AST::Float(_, line, col)
+        | AST::Identifier(_, line, col)
+        | AST::Integer(_, line, col)
+        | AST::String(_, line, col)
+        | AST::Unit(line, col) => ("a value", *line, *col, Vec::new()),
+    }
+}
+
 pub fn parse(src: &str) -> Result<AST, ParseError> {
+    parse_with_limits(src, &Limits::default())
+}
+
+pub fn parse_with_limits(src: &str, limits: &Limits) -> Result<AST, ParseError> {
+    parse_program(src, limits).map(|(ast, _)| ast)
+}
+
+// Parses a program along with its comments, which are otherwise discarded.
+pub fn parse_with_comments(src: &str) -> Result<(AST, Trivia), ParseError> {
+    let (ast, program) = parse_program(src, &Limits::default())?;
+    let trivia = attach_comments(src, &ast, program);
+    Ok((ast, trivia))
+}
+
+fn parse_program<'a>(src: &'a str, limits: &Limits) -> Result<(AST, Pair<'a, Rule>), ParseError> {
+    let _guard = panics::enter();
+    let blanked = blank_comments(src);
+    check_nesting(&blanked, limits)?;
+    match PloverParser::parse(Rule::program, src) {
+        Ok(mut program) => {
+            check_integers(program.clone())?;
+            let program = program.next().unwrap();
+            let ast = astify(program.clone());
+            check_limits(&ast, limits, 0, &mut 0)?;
+            Ok((ast, program))
+        }
+        Err(err) => {
+            let (line, col) = match err.line_col {
+                pest::error::LineColLocation::Pos(pos) => pos,
+                pest::error::LineColLocation::Span(start, _) => start,
+            };
+            // The errors from pest list what was expected instead, which is
+            // not much help for characters or keywords that are never
+            // accepted where they are.
+            let msg = err.to_string();
+            Err(invalid_character(&blanked)
+                .or_else(|| reserved_keyword(src, &err, line, col))
+                .unwrap_or(ParseError { msg, line, col }))
+        }
+    }
+}
+
+// Finds the comments outside of strings, as their byte ranges and the line
+// and column where they start. A block comment that is not closed runs to the
+// end of the source.
+fn find_comments(src: &str) -> Vec<(usize, usize, usize, usize)> {
+    let mut comments = Vec::new();
+    let (mut line, mut col) = (1, 1);
+    let mut quote = None;
+    let mut chars = src.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let next = chars.peek().map(|&(_, c)| c);
+        let end = if quote.is_some() {
+            match c {
+                '\\' => {
+                    chars.next();
+                    col += 1;
+                }
+                c if quote == Some(c) => quote = None,
+                _ => {}
+            }
+            None
+        } else if c == '"' || c == '\'' {
+            quote = Some(c);
+            None
+        } else if c == '#' || (c == '/' && next == Some('/')) {
+            Some(src[i..].find('\n').map_or(src.len(), |n| i + n))
+        } else if c == '/' && next == Some('*') {
+            Some(src[i + 2..].find("*/").map_or(src.len(), |n| i + n + 4))
+        } else {
+            None
+        };
+        if let Some(end) = end {
+            comments.push((i, end, line, col));
+        }
+        let mut c = c;
+        loop {
+            if c == '\n' {
+                line += 1;
+                col = 1;
+            } else {
+                col += 1;
+            }
+            // The characters of a comment are skipped, but still counted.
+            match end {
+                Some(end) if chars.peek().is_some_and(|&(j, _)| j < end) => {
+                    c = chars.next().unwrap().1
+                }
+                _ => break,
+            }
+        }
+    }
+    comments
+}
+
+// Replaces comments with spaces, keeping their line breaks, so that the
+// checks made on the source before it is parsed see the same lines and columns
+// without being confused by what comments contain.
+fn blank_comments(src: &str) -> String {
+    let mut blanked = String::with_capacity(src.len());
+    let mut last = 0;
+    for (start, end, _, _) in find_comments(src) {
+        blanked.push_str(&src[last..start]);
+        for c in src[start..end].chars() {
+            blanked.push(if c == '\n' { '\n' } else { ' ' });
+        }
+        last = end;
+    }
+    blanked.push_str(&src[last..]);
+    blanked
+}
+
+// Returns whether an expression uses ? outside of any function in it.
+pub fn propagates(ast: &AST) -> bool {
+    match ast {
+        AST::Propagate(..) => true,
+        AST::Function(..) => false,
+        _ => node(ast).3.into_iter().any(propagates),
+    }
+}
+
+// Returns the position of the start of an expression, which for an operator
+// or a field is that of its leftmost operand.
+pub fn start(ast: &AST) -> (usize, usize) {
+    let (_, line, col, children) = node(ast);
+    children
+        .into_iter()
+        .map(start)
+        .fold((line, col), |start, child| start.min(child))
+}
+
+type Comments = [(usize, usize, usize, usize)];
+
+// Moves the end of a span back over the whitespace and comments that follow
+// it, which pest includes.
+fn trim_end(src: &str, comments: &Comments, mut end: usize) -> usize {
+    loop {
+        end = src[..end].trim_end().len();
+        match comments.binary_search_by_key(&end, |comment| comment.1) {
+            Ok(i) => end = comments[i].0,
+            Err(_) => return end,
+        }
+    }
+}
+
+// A program or function body, with the byte range it covers, which extends
+// from where the previous part of the enclosing expression ends to where the
+// next one starts, and the byte ranges of its expressions.
+struct Scope {
+    start: usize,
+    end: usize,
+    expressions: Vec<(usize, usize)>,
+}
+
+fn find_scopes(
+    src: &str,
+    comments: &Comments,
+    pair: Pair<Rule>,
+    range: (usize, usize),
+    scopes: &mut Vec<Scope>,
+) {
+    let span = pair.as_span();
+    let inner: Vec<Pair<Rule>> = pair
+        .clone()
+        .into_inner()
+        .filter(|pair| pair.as_rule() != Rule::EOI)
+        .collect();
+    let ends: Vec<usize> = inner
+        .iter()
+        .map(|pair| trim_end(src, comments, pair.as_span().end()))
+        .collect();
+    if let Rule::body | Rule::program = pair.as_rule() {
+        scopes.push(Scope {
+            start: range.0,
+            end: range.1,
+            expressions: inner
+                .iter()
+                .zip(&ends)
+                .map(|(pair, end)| (pair.as_span().start(), *end))
+                .collect(),
+        });
+    }
+    for (i, child) in inner.iter().enumerate() {
+        let start = if i == 0 { span.start() } else { ends[i - 1] };
+        let end = inner
+            .get(i + 1)
+            .map_or(span.end(), |next| next.as_span().start());
+        find_scopes(src, comments, child.clone(), (start, end), scopes);
+    }
+}
+
+fn expression_starts(ast: &AST, starts: &mut Vec<(usize, usize)>) {
+    if let AST::Program(expressions, _, _) = ast {
+        starts.extend(expressions.iter().map(start));
+    }
+    for child in node(ast).3 {
+        expression_starts(child, starts);
+    }
+}
+
+// Attaches each comment to an expression in the innermost program or body
+// that contains it: to the expression it is inside of, the one it follows on
+// the same line, the one after it, or failing that the one before it.
+fn attach_comments(src: &str, ast: &AST, program: Pair<Rule>) -> Trivia {
+    let mut trivia = Trivia::default();
+    let comments = find_comments(src);
+    let mut scopes = Vec::new();
+    find_scopes(src, &comments, program, (0, src.len()), &mut scopes);
+    // The expressions start in the same order in the source as in the AST,
+    // which gives the position each one is keyed by.
+    let mut ranges: Vec<usize> = scopes
+        .iter()
+        .flat_map(|scope| scope.expressions.iter().map(|range| range.0))
+        .collect();
+    ranges.sort_unstable();
+    let mut starts = Vec::new();
+    expression_starts(ast, &mut starts);
+    starts.sort_unstable();
+    let keys: HashMap<usize, (usize, usize)> = ranges.into_iter().zip(starts).collect();
+
+    // Where the first of the comments before each expression starts, as a
+    // blank line after it belongs to the comment rather than the expression.
+    let mut first_leading = HashMap::new();
+    for (start, end, line, col) in comments {
+        let line_start = src[..start].rfind('\n').map_or(0, |i| i + 1);
+        let mut comment = Comment {
+            text: src[start..end].to_string(),
+            line,
+            col,
+            own_line: src[line_start..start].trim().is_empty(),
+            blank_after: false,
+        };
+        let scope = scopes
+            .iter()
+            .filter(|scope| scope.start <= start && start < scope.end)
+            .max_by_key(|scope| scope.start)
+            .unwrap();
+        let after = scope
+            .expressions
+            .iter()
+            .position(|range| range.0 > start)
+            .unwrap_or(scope.expressions.len());
+        let prev = after.checked_sub(1).map(|i| scope.expressions[i]);
+        let next = scope.expressions.get(after);
+        let (map, range) = match (prev, next) {
+            (Some(prev), _) if prev.1 > start => (&mut trivia.leading, prev),
+            (Some(prev), _) if !src[prev.1..start].contains('\n') => (&mut trivia.trailing, prev),
+            (_, Some(next)) => {
+                let space = &src[end..next.0];
+                let space = &space[..space.len() - space.trim_start().len()];
+                comment.blank_after = space.matches('\n').count() > 1;
+                first_leading.entry(next.0).or_insert(start);
+                (&mut trivia.leading, *next)
+            }
+            (Some(prev), None) => (&mut trivia.trailing, prev),
+            (None, None) => {
+                let (_, line, col, _) = node(ast);
+                trivia.leading.entry((line, col)).or_default().push(comment);
+                continue;
+            }
+        };
+        map.entry(keys[&range.0]).or_default().push(comment);
+    }
+    for scope in &scopes {
+        for pair in scope.expressions.windows(2) {
+            let end = first_leading.get(&pair[1].0).unwrap_or(&pair[1].0);
+            let lines: Vec<&str> = src[pair[0].1..*end].split('\n').collect();
+            if lines.len() > 2
+                && lines[1..lines.len() - 1]
+                    .iter()
+                    .any(|l| l.trim().is_empty())
+            {
+                trivia.blank_before.insert(keys[&pair[1].0]);
+            }
+        }
+    }
+    trivia
+}
+
+// Integer literals are checked before the AST is built, so one that does not
+// fit is reported where it is rather than failing the whole parse.
+fn check_integers(pairs: Pairs<Rule>) -> Result<(), ParseError> {
+    for pair in pairs.flatten() {
+        if pair.as_rule() == Rule::number && pair.as_str().trim().parse::<i64>().is_err() {
+            let (line, col) = pair.line_col();
+            return Err(ParseError {
+                msg: format!(
+                    "Integer literal {} out of range for Integer at {}:{}, write {}n for a BigInt.",
+                    pair.as_str().trim(),
+                    line,
+                    col,
+                    pair.as_str().trim()
+                ),
+                line,
+                col,
+            });
+        }
+    }
+    Ok(())
+}
+
+// The characters that can appear in a program outside of strings and
+// characters, other than those of identifiers and numbers.
+const SYMBOLS: &str = "()[]{}.,:=+-*/%<>~&|_?\"'";
+
+// Finds the first character outside of a string that can not appear in a
+// program. The parser may only fail after it, as a character inside brackets is
+// not examined until the whole of them is.
+fn invalid_character(src: &str) -> Option<ParseError> {
+    let (mut line, mut col) = (1, 1);
+    let mut quote = None;
+    let mut prev = None;
+    let mut chars = src.chars();
+    while let Some(c) = chars.next() {
+        if quote.is_some() {
+            match c {
+                '\\' => {
+                    chars.next();
+                    col += 1;
+                }
+                c if quote == Some(c) => quote = None,
+                _ => {}
+            }
+        } else if c == '"' || c == '\'' {
+            quote = Some(c);
+        } else if !(XID_CONTINUE(c) || c.is_ascii_whitespace() || SYMBOLS.contains(c)) {
+            let context = if prev.is_some_and(XID_CONTINUE) {
+                " in identifier"
+            } else {
+                ""
+            };
+            return Some(ParseError {
+                msg: format!(
+                    "Invalid character '{}' (U+{:04X}){} at {}:{}.",
+                    c, c as u32, context, line, col
+                ),
+                line,
+                col,
+            });
+        }
+        if c == '\n' {
+            line += 1;
+            col = 1;
+        } else {
+            col += 1;
+        }
+        prev = Some(c);
+    }
+    None
+}
+
+// Explains a parse error caused by a keyword being used where an identifier
+// was expected.
+fn reserved_keyword(
+    src: &str,
+    err: &pest::error::Error<Rule>,
+    line: usize,
+    col: usize,
+) -> Option<ParseError> {
+    let pos = match err.location {
+        pest::error::InputLocation::Pos(pos) => pos,
+        pest::error::InputLocation::Span((start, _)) => start,
+    };
+    let rest = src.get(pos..)?;
+    let word: String = rest.chars().take_while(|c| XID_CONTINUE(*c)).collect();
+    let expected_identifier = match &err.variant {
+        pest::error::ErrorVariant::ParsingError { positives, .. } => {
+            positives.contains(&Rule::identifier)
+        }
+        _ => false,
+    };
+    if expected_identifier && KEYWORDS.contains(&word.as_str()) {
+        return Some(ParseError {
+            msg: format!(
+                "Reserved keyword {} can not be used as an identifier at {}:{}.",
+                word, line, col
+            ),
+            line,
+            col,
+        });
+    }
+    None
+}
+
+// Returns true if the source is the beginning of a program rather than an
+// invalid one, because it only fails to parse where the input ends. This is
+// used by the REPL to prompt for more lines.
+pub fn incomplete(src: &str) -> bool {
+    if check_nesting(&blank_comments(src), &Limits::default()).is_err() {
+        return false;
+    }
+    let unclosed = find_comments(src).last().is_some_and(|&(start, _, _, _)| {
+        src[start..].starts_with("/*") && !src[start + 2..].contains("*/")
+    });
+    if unclosed {
+        return true;
+    }
     match PloverParser::parse(Rule::program, src) {
-        Ok(mut program) => Ok(astify(program.next().unwrap())),
-        Err(err) => Err(ParseError {
-            msg: err.to_string(),
-            line: 0,
-            col: 0,
-        }),
+        Ok(_) => false,
+        Err(err) => match err.location {
+            pest::error::InputLocation::Pos(pos) => pos >= src.trim_end().len(),
+            pest::error::InputLocation::Span((start, _)) => start >= src.trim_end().len(),
+        },
     }
 }
 
@@ -455,6 +1758,11 @@ mod tests {
         parse!("  42", "42:Integer");
         parse!("  42 ", "42:Integer");
         parse!("true", "true:Boolean");
+        parse!("3.14", "3.14:Float");
+        parse!("1e-5", "1e-5:Float");
+        parse!("2.5E3", "2500.0:Float");
+        parse!("-1.5", "(- 1.5:Float)");
+        parse!("1.5 * 2.0", "(* 1.5:Float 2.0:Float)");
         parse!("false", "false:Boolean");
         parse!("-42", "(- 42:Integer)");
         parse!("- 42", "(- 42:Integer)");
@@ -466,6 +1774,13 @@ mod tests {
         parse!("true && false", "(&& true:Boolean false:Boolean)");
         parse!("true || false", "(|| true:Boolean false:Boolean)");
         parse!("1 / 2 * 3", "(* (/ 1:Integer 2:Integer) 3:Integer)");
+        parse!("2 ** 3 * 4", "(* (** 2:Integer 3:Integer) 4:Integer)");
+        parse!("2 ** 3 ** 4", "(** 2:Integer (** 3:Integer 4:Integer))");
+        parse!("-2**-x", "(- (** 2:Integer (- x:Identifier)))");
+        parse!(
+            "f (x) ** r.y",
+            "(** (apply f:Identifier x:Identifier) (. r:Identifier y))"
+        );
         parse!("1 / 2 + 5", "(+ (/ 1:Integer 2:Integer) 5:Integer)");
         parse!("1 + 2", "(+ 1:Integer 2:Integer)");
         parse!("1 - 2", "(- 1:Integer 2:Integer)");
@@ -485,6 +1800,18 @@ mod tests {
             "2 * 3 ~= 1 - 2",
             "(~= (* 2:Integer 3:Integer) (- 1:Integer 2:Integer))"
         );
+        parse!(
+            "x |> f |> g",
+            "(apply g:Identifier (apply f:Identifier x:Identifier))"
+        );
+        parse!(
+            "1 + 2 |> f == y",
+            "(apply (== f:Identifier y:Identifier) (+ 1:Integer 2:Integer))"
+        );
+        parse!(
+            "(x |> f) < 2 |> g",
+            "(apply g:Identifier (< (apply f:Identifier x:Identifier) 2:Integer))"
+        );
         parse!("1 / (2 + 5)", "(/ 1:Integer (+ 2:Integer 5:Integer))");
         parse!(
             "(1 < 2) == false",
@@ -518,6 +1845,15 @@ mod tests {
              end",
             "(if (cond true:Boolean (if (cond true:Boolean 1:Integer) (else 2:Integer))) (cond false:Boolean 3:Integer) (else 4:Integer))"
         );
+        parse!(
+            "if a then f (1) elif b then g (2) end",
+            "(if (cond a:Identifier (apply f:Identifier 1:Integer)) (cond b:Identifier (apply g:Identifier 2:Integer)))"
+        );
+        parse!(
+            "if a then if b then 1 end else 2 end",
+            "(if (cond a:Identifier (if (cond b:Identifier 1:Integer))) (else 2:Integer))"
+        );
+        parse!("elifant", "elifant:Identifier");
         parse!("x", "x:Identifier");
         parse!("x2", "x2:Identifier");
         parse!(
@@ -585,6 +1921,11 @@ mod tests {
             "type Option := Some (x) | None end def a := Some (42)",
             "((Some: x:Identifier, None) Option:Type (define a:Identifier (apply Some:Identifier 42:Integer)))"
         );
+        parse!("\"\"", "\"\":String");
+        parse!("\"hello\"", "\"hello\":String");
+        parse!("\"a\\tb\\n\"", "\"a\\tb\\n\":String");
+        parse!("\"say \\\"hi\\\"\"", "\"say \\\"hi\\\"\":String");
+        parse!("\"a\" ++ \"b\"", "(++ \"a\":String \"b\":String)");
         parse!("()", "():Unit");
         parse!("(   )", "():Unit");
         parse!("fn f () -> () end", "(f ():Unit ():Unit)");
@@ -600,6 +1941,109 @@ mod tests {
             "match p with A -> 0 | B -> 1 end",
             "(match p:Identifier (case A 0:Integer) (case B 1:Integer))"
         );
+        parse!(
+            "match p with A (x) when x > 0 -> x | _ -> 0 end",
+            "(match p:Identifier (case A: x:Identifier (when (> x:Identifier 0:Integer)) x:Identifier) (case _ 0:Integer))"
+        );
+        parse!(
+            "when feature = \"debug\" then 1 else 2 end",
+            "(when \"debug\" 1:Integer (else 2:Integer))"
+        );
+        parse!(
+            "when feature = \"debug\" then def x := 1 x end",
+            "(when \"debug\" ((define x:Identifier 1:Integer) x:Identifier))"
+        );
+        parse!(
+            "let x = 1 in x + 1",
+            "(let x:Identifier 1:Integer (+ x:Identifier 1:Integer))"
+        );
+        parse!(
+            "let x = 1 in let y = x in y",
+            "(let x:Identifier 1:Integer (let y:Identifier x:Identifier y:Identifier))"
+        );
+        parse!(
+            "letrec fn f (x) -> g (x) end fn g (x) -> x end end",
+            "(letrec (f x:Identifier (apply g:Identifier x:Identifier)) (g x:Identifier x:Identifier))"
+        );
+        parse!(
+            "def index := letter",
+            "(define index:Identifier letter:Identifier)"
+        );
+        parse!(
+            "{ x = 1, y = true }",
+            "{x = 1:Integer, y = true:Boolean}:Record"
+        );
+        parse!("p.x.y", "(. (. p:Identifier x) y)");
+        parse!("t.0.1", "(. (. t:Identifier 0) 1)");
+        parse!("[]", "[]:Array");
+        parse!("%{}", "%{}:Map");
+        parse!(
+            "%{\"a\" => 1, x => y + 1,}",
+            "%{\"a\":String => 1:Integer, x:Identifier => (+ y:Identifier 1:Integer)}:Map"
+        );
+        parse!(
+            "f (%{1 => 2}, 3 % 2)",
+            "(apply f:Identifier (%{1:Integer => 2:Integer}:Map, (% 3:Integer 2:Integer)):Tuple)"
+        );
+        parse!("[1, x,]", "[1:Integer, x:Identifier]:Array");
+        parse!("%[]", "%[]:List");
+        parse!(
+            "1 :: 2 + 3 :: %[x,] == l",
+            "(== (:: 1:Integer (:: (+ 2:Integer 3:Integer) %[x:Identifier]:List)) l:Identifier)"
+        );
+        parse!("7 % 2 :: %[]", "(:: (% 7:Integer 2:Integer) %[]:List)");
+        parse!(
+            "1..n + 1 == r",
+            "(== (.. 1:Integer (+ n:Identifier 1:Integer)) r:Identifier)"
+        );
+        parse!(
+            "defer close (f) deferred",
+            "((defer (apply close:Identifier f:Identifier)) deferred:Identifier)"
+        );
+        parse!(
+            "for (i, x) in xs do i * x end",
+            "(for (i:Identifier, x:Identifier):Tuple xs:Identifier (* i:Identifier x:Identifier))"
+        );
+        parse!(
+            "while i < n do print (i) def i := i + 1 end",
+            "(while (< i:Identifier n:Identifier) ((apply print:Identifier i:Identifier) (define i:Identifier (+ i:Identifier 1:Integer))))"
+        );
+        parse!("do 1 end", "(do 1:Integer)");
+        parse!(
+            "def x := 1; print (x) x",
+            "((seq (define x:Identifier 1:Integer) (apply print:Identifier x:Identifier)) x:Identifier)"
+        );
+        parse!(
+            "fn f (x) -> g (x); x end",
+            "(f x:Identifier (seq (apply g:Identifier x:Identifier) x:Identifier))"
+        );
+        parse!("whiles + done", "(+ whiles:Identifier done:Identifier)");
+        parse!(
+            "match l with x :: xs -> x | %[] -> 0 end",
+            "(match l:Identifier (case ::: (x:Identifier, xs:Identifier):Tuple x:Identifier) (case %[] 0:Integer))"
+        );
+        parse!(
+            "f ([1, 2])",
+            "(apply f:Identifier [1:Integer, 2:Integer]:Array)"
+        );
+        parse!(
+            "let (a, b) = t in a",
+            "(let (a:Identifier, b:Identifier):Tuple t:Identifier a:Identifier)"
+        );
+        parse!(
+            "try f(x) catch e -> e end",
+            "(try (apply f:Identifier x:Identifier) e:Identifier e:Identifier)"
+        );
+        parse!(
+            "f(x)?.y?",
+            "(? (. (? (apply f:Identifier x:Identifier)) y))"
+        );
+        parse!("{ p with x = 1 }", "(with p:Identifier x = 1:Integer)");
+        parse!("-p.x", "(- (. p:Identifier x))");
+        parse!(
+            "match p with A -> 0 | _ -> 1 end",
+            "(match p:Identifier (case A 0:Integer) (case _ 1:Integer))"
+        );
         parse!(
             "match p with Cons (a, b) -> (1 + len(b)) | Null -> 0 end",
             "(match p:Identifier (case Cons: (a:Identifier, b:Identifier):Tuple (+ 1:Integer (apply len:Identifier b:Identifier))) (case Null 0:Integer))"
@@ -635,4 +2079,264 @@ mod tests {
             "(fact n:Identifier ((iter (n:Identifier, acc:Identifier):Tuple (if (cond (== n:Identifier 0:Integer) acc:Identifier) (else (apply iter:Identifier ((- n:Identifier 1:Integer), (* n:Identifier acc:Identifier)):Tuple)))) (apply iter:Identifier (n:Identifier, 1:Integer):Tuple)))"
         );
     }
+
+    #[test]
+    fn incomplete() {
+        assert!(parser::incomplete("fn f (x) ->"));
+        assert!(parser::incomplete("fn f (x) ->\n    x + 1\n"));
+        assert!(parser::incomplete("if x then 1 else"));
+        assert!(parser::incomplete("1 +  "));
+        assert!(parser::incomplete("(1, 2"));
+        assert!(!parser::incomplete("1 + 2"));
+        assert!(!parser::incomplete("fn f (x) -> x end"));
+        assert!(!parser::incomplete("1 + + 2"));
+        assert!(!parser::incomplete(")"));
+    }
+
+    #[test]
+    fn limits() {
+        let limits = parser::Limits {
+            max_depth: 4,
+            max_size: 20,
+        };
+        let err = parser::parse_with_limits("(((((1)))))", &limits)
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.msg,
+            "Expression nesting exceeds 4 in parentheses at 1:5."
+        );
+        assert_eq!((err.line, err.col), (1, 5));
+        let err = parser::parse_with_limits(
+            "1 +\n  fn (x) -> if x then { a = ((1)) } else \"(((\" end end",
+            &limits,
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            err.msg,
+            "Expression nesting exceeds 4 in parentheses at 2:30."
+        );
+        let err =
+            parser::parse_with_limits("let a = 1 in let b = 2 in let c = 3 in a + b + c", &limits)
+                .err()
+                .unwrap();
+        assert_eq!(
+            err.msg,
+            "Expression nesting exceeds 4 in an operator at 1:42."
+        );
+        let err = parser::parse_with_limits(
+            "(1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20)",
+            &limits,
+        )
+        .err()
+        .unwrap();
+        assert_eq!(err.msg, "Program size exceeds 20 expressions at 1:65.");
+        assert!(parser::parse_with_limits("((((1))))", &limits).is_ok());
+        assert!(parser::parse_with_limits(
+            "def a := (1) def b := (2) def c := (3) def d := (4) def e := (5)",
+            &limits
+        )
+        .is_ok());
+
+        for (src, msg) in [
+            (
+                "- - - - - 1",
+                "Expression nesting exceeds 4 in a unary operator at 1:9.",
+            ),
+            (
+                "2 ** 2 ** 2 ** -2 ** 2",
+                "Expression nesting exceeds 4 in a power at 1:19.",
+            ),
+            (
+                "let a = 1 in let b = 2 in let c = 3 in let d = 4 in let e = 5 in a",
+                "Expression nesting exceeds 4 in a let expression at 1:53.",
+            ),
+            (
+                "while a do do while b do (defer c) end end end",
+                "Expression nesting exceeds 4 in a defer expression at 1:27.",
+            ),
+            (
+                "def a := def b := def c := def d := def e := 1",
+                "Expression nesting exceeds 4 in a definition at 1:37.",
+            ),
+        ] {
+            let err = parser::parse_with_limits(src, &limits).err().unwrap();
+            assert_eq!(err.msg, msg);
+        }
+        assert!(parser::parse_with_limits(
+            "for x in xs do x end for y in ys do y end while a do b end while c do d end",
+            &limits
+        )
+        .is_ok());
+        assert!(parser::parse_with_limits(
+            "match x with a when a -> 1 | b when b -> 2 | c when c -> 3 end",
+            &limits
+        )
+        .is_ok());
+
+        for src in ["-".repeat(50000) + "1", "2 ** ".repeat(20000) + "1"] {
+            let err = parser::parse(&src).err().unwrap();
+            assert!(err.msg.starts_with("Expression nesting exceeds 512"));
+        }
+        let deep = format!("{}1{}", "(".repeat(1000), ")".repeat(1000));
+        let err = parser::parse(&deep).err().unwrap();
+        assert_eq!(
+            err.msg,
+            "Expression nesting exceeds 512 in parentheses at 1:513."
+        );
+        assert!(!parser::incomplete(&deep));
+
+        let err = parser::parse("1 + + 2").err().unwrap();
+        assert_eq!((err.line, err.col), (1, 5));
+    }
+
+    #[test]
+    fn integers() {
+        parse!("9223372036854775807", "9223372036854775807:Integer");
+        let err = parser::parse("1 +\n  99999999999999999999999")
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.msg,
+            "Integer literal 99999999999999999999999 out of range for Integer at 2:3, write 99999999999999999999999n for a BigInt."
+        );
+        assert_eq!((err.line, err.col), (2, 3));
+        let err = parser::parse("(1, 9223372036854775808)").err().unwrap();
+        assert_eq!((err.line, err.col), (1, 5));
+        parse!(
+            "\"99999999999999999999999\"",
+            "\"99999999999999999999999\":String"
+        );
+        parse!("99999999999999999999999n", "99999999999999999999999:BigInt");
+        parse!("-12n + 3n", "(+ (- 12:BigInt) 3:BigInt)");
+    }
+
+    #[test]
+    fn bytes() {
+        parse!("b\"ab\\x00\\xff\\n\"", "[97, 98, 0, 255, 10]:Bytes");
+        parse!("b\"\"", "[]:Bytes");
+        parse!("b", "b:Identifier");
+        parse!("f (b)", "(apply f:Identifier b:Identifier)");
+        assert!(parser::parse("b\"é\"").is_err());
+        assert!(parser::parse("b\"\\x1\"").is_err());
+    }
+
+    #[test]
+    fn chars() {
+        parse!("'a'", "'a':Char");
+        parse!("'\\n' == '\\''", "(== '\\n':Char '\\'':Char)");
+        parse!(
+            "f (('(', ','))",
+            "(apply f:Identifier ('(':Char, ',':Char):Tuple)"
+        );
+        parse!("'#' // '\"'", "'#':Char");
+        assert!(parser::parse("''").is_err());
+        assert!(parser::parse("'ab'").is_err());
+        assert!(!parser::incomplete("'('"));
+    }
+
+    #[test]
+    fn identifiers() {
+        parse!("café", "café:Identifier");
+        parse!("λ_1 (Δx)", "(apply λ_1:Identifier Δx:Identifier)");
+        parse!("def 変数 := 1", "(define 変数:Identifier 1:Integer)");
+        parse!("iffy", "iffy:Identifier");
+        parse!("ifé", "ifé:Identifier");
+
+        let err = parser::parse("def a€b := 1").err().unwrap();
+        assert_eq!(
+            err.msg,
+            "Invalid character '€' (U+20AC) in identifier at 1:6."
+        );
+        let err = parser::parse("f (x₂)").err().unwrap();
+        assert_eq!(
+            err.msg,
+            "Invalid character '₂' (U+2082) in identifier at 1:5."
+        );
+        assert_eq!((err.line, err.col), (1, 5));
+        let err = parser::parse("\"€\" ++\n1 +\u{a0}2").err().unwrap();
+        assert_eq!(err.msg, "Invalid character '\u{a0}' (U+00A0) at 2:4.");
+        for keyword in parser::KEYWORDS {
+            let err = parser::parse(&format!("def {} := 1", keyword))
+                .err()
+                .unwrap();
+            assert_eq!(
+                err.msg,
+                format!(
+                    "Reserved keyword {} can not be used as an identifier at 1:5.",
+                    keyword
+                )
+            );
+        }
+        let err = parser::parse("fn (x) -> let then = x in then end")
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.msg,
+            "Reserved keyword then can not be used as an identifier at 1:15."
+        );
+    }
+
+    #[test]
+    fn parentheses() {
+        parse!("((((((((1))))))))", "1:Integer");
+        parse!(
+            "((((((((1, 2), 3), 4), 5), 6), 7), 8), 9)",
+            "((((((((1:Integer, 2:Integer):Tuple, 3:Integer):Tuple, 4:Integer):Tuple, 5:Integer):Tuple, 6:Integer):Tuple, 7:Integer):Tuple, 8:Integer):Tuple, 9:Integer):Tuple"
+        );
+        parse!("(f) (1)", "(apply f:Identifier 1:Integer)");
+        parse!(
+            "((f (1)) (2))",
+            "(apply (apply f:Identifier 1:Integer) 2:Integer)"
+        );
+        parse!(
+            "f (({a = 1, b = \",)\"}))",
+            "(apply f:Identifier {a = 1:Integer, b = \",)\":String}:Record)"
+        );
+    }
+
+    #[test]
+    fn comments() {
+        parse!("1 # one\n+ 2 // two", "(+ 1:Integer 2:Integer)");
+        parse!(
+            "f (1, # )\n2) /* \"( */",
+            "(apply f:Identifier (1:Integer, 2:Integer):Tuple)"
+        );
+        parse!("\"# a // b /* c\"", "\"# a // b /* c\":String");
+        parse!("1 /* + 2 */ * 3", "(* 1:Integer 3:Integer)");
+        parse!("# café ✓\nx", "x:Identifier");
+        parse!("", "");
+        assert!(parser::parse("1 /* 2").is_err());
+        assert!(parser::incomplete("1 /* 2"));
+        assert!(!parser::incomplete("1 # (("));
+
+        let src = "# a\ndef x := 1 # b\nfn f () -> /* c */\n    x\n    // d\nend\n# e\n";
+        let trivia = parser::parse_with_comments(src).ok().unwrap().1;
+        let texts = |comments: Option<&Vec<parser::Comment>>| -> Vec<String> {
+            comments
+                .into_iter()
+                .flatten()
+                .map(|comment| comment.text.clone())
+                .collect()
+        };
+        assert_eq!(texts(trivia.leading.get(&(2, 1))), vec!["# a"]);
+        assert_eq!(texts(trivia.trailing.get(&(2, 1))), vec!["# b"]);
+        assert_eq!(texts(trivia.leading.get(&(4, 5))), vec!["/* c */"]);
+        assert_eq!(texts(trivia.trailing.get(&(4, 5))), vec!["// d"]);
+        assert_eq!(texts(trivia.trailing.get(&(3, 1))), vec!["# e"]);
+        let comment = &trivia.trailing[&(3, 1)][0];
+        assert_eq!((comment.line, comment.col, comment.own_line), (7, 1, true));
+        assert!(!trivia.trailing[&(2, 1)][0].own_line);
+        let ast = parser::parse_with_comments("1\n(1 + 2) * 3")
+            .ok()
+            .unwrap()
+            .0;
+        match ast {
+            parser::AST::Program(expressions, _, _) => {
+                assert_eq!(parser::start(&expressions[1]), (2, 2))
+            }
+            _ => unreachable!(),
+        }
+    }
 }
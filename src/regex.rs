@@ -0,0 +1,701 @@
+// Regular expressions, matched by simulating all the ways through a pattern at
+// once, so that matching takes time linear in the length of the text whatever
+// the pattern. Patterns have literals, ., ^ and $, classes such as [a-z] and
+// [^0-9], the escapes \d, \w, \s and \b and their negations, groups, which
+// capture unless written (?:...), alternation with |, and the repetitions *,
+// +, ?, {n}, {n,} and {n,m}, which are lazy when followed by ?. The leftmost
+// match is found, preferring the earlier alternative and, for repetitions,
+// the longest unless they are lazy.
+
+// Counted repetitions are expanded, so their counts are limited, as is the
+// size of the compiled pattern.
+const MAX_COUNT: usize = 1000;
+const MAX_INSTRUCTIONS: usize = 10_000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Perl {
+    Digit,
+    Word,
+    Space,
+}
+
+impl Perl {
+    fn matches(self, c: char) -> bool {
+        match self {
+            Perl::Digit => c.is_ascii_digit(),
+            Perl::Word => c.is_alphanumeric() || c == '_',
+            Perl::Space => c.is_whitespace(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum ClassItem {
+    Range(char, char),
+    // An escape such as \d, or \D when negated.
+    Perl(Perl, bool),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Class {
+    negated: bool,
+    items: Vec<ClassItem>,
+}
+
+impl Class {
+    fn matches(&self, c: char) -> bool {
+        let found = self.items.iter().any(|item| match *item {
+            ClassItem::Range(lo, hi) => lo <= c && c <= hi,
+            ClassItem::Perl(perl, negated) => perl.matches(c) != negated,
+        });
+        found != self.negated
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Node {
+    Empty,
+    Char(char),
+    Any,
+    Class(Class),
+    Start,
+    End,
+    // A word boundary, or with false a place that is not one.
+    Boundary(bool),
+    Group(Box<Node>, Option<usize>),
+    Concat(Vec<Node>),
+    Alternate(Vec<Node>),
+    Repeat {
+        node: Box<Node>,
+        min: usize,
+        max: Option<usize>,
+        greedy: bool,
+    },
+}
+
+struct Parser<'a> {
+    pattern: &'a str,
+    chars: Vec<char>,
+    pos: usize,
+    groups: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, reason: &str) -> String {
+        format!("Invalid regex {:?}: {}.", self.pattern, reason)
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn alternation(&mut self) -> Result<Node, String> {
+        let mut alternatives = vec![self.concatenation()?];
+        while self.eat('|') {
+            alternatives.push(self.concatenation()?);
+        }
+        if alternatives.len() == 1 {
+            Ok(alternatives.pop().unwrap())
+        } else {
+            Ok(Node::Alternate(alternatives))
+        }
+    }
+
+    fn concatenation(&mut self) -> Result<Node, String> {
+        let mut nodes = Vec::new();
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            let atom = self.atom()?;
+            nodes.push(self.repetition(atom)?);
+        }
+        match nodes.len() {
+            0 => Ok(Node::Empty),
+            1 => Ok(nodes.pop().unwrap()),
+            _ => Ok(Node::Concat(nodes)),
+        }
+    }
+
+    fn atom(&mut self) -> Result<Node, String> {
+        let c = self.peek().unwrap();
+        self.pos += 1;
+        Ok(match c {
+            '(' => {
+                let index = if self.eat('?') {
+                    if !self.eat(':') {
+                        return Err(self.error("only (?: groups are supported"));
+                    }
+                    None
+                } else {
+                    self.groups += 1;
+                    Some(self.groups)
+                };
+                let node = self.alternation()?;
+                if !self.eat(')') {
+                    return Err(self.error("unclosed group"));
+                }
+                Node::Group(Box::new(node), index)
+            }
+            '[' => Node::Class(self.class()?),
+            '.' => Node::Any,
+            '^' => Node::Start,
+            '$' => Node::End,
+            '\\' if self.eat('b') => Node::Boundary(true),
+            '\\' if self.eat('B') => Node::Boundary(false),
+            '\\' => match self.escape()? {
+                ClassItem::Range(c, _) => Node::Char(c),
+                item => Node::Class(Class {
+                    negated: false,
+                    items: vec![item],
+                }),
+            },
+            '*' | '+' | '?' | '{' => return Err(self.error("nothing to repeat")),
+            c => Node::Char(c),
+        })
+    }
+
+    // Reads what follows a backslash, outside or inside of a class, where a
+    // single character is given as a range of one.
+    fn escape(&mut self) -> Result<ClassItem, String> {
+        let c = match self.peek() {
+            Some(c) => c,
+            None => return Err(self.error("trailing backslash")),
+        };
+        self.pos += 1;
+        Ok(match c {
+            'd' => ClassItem::Perl(Perl::Digit, false),
+            'D' => ClassItem::Perl(Perl::Digit, true),
+            'w' => ClassItem::Perl(Perl::Word, false),
+            'W' => ClassItem::Perl(Perl::Word, true),
+            's' => ClassItem::Perl(Perl::Space, false),
+            'S' => ClassItem::Perl(Perl::Space, true),
+            'n' => ClassItem::Range('\n', '\n'),
+            'r' => ClassItem::Range('\r', '\r'),
+            't' => ClassItem::Range('\t', '\t'),
+            c if c.is_ascii_alphanumeric() => {
+                return Err(self.error(&format!("unknown escape \\{}", c)))
+            }
+            c => ClassItem::Range(c, c),
+        })
+    }
+
+    fn class(&mut self) -> Result<Class, String> {
+        let negated = self.eat('^');
+        let mut items = Vec::new();
+        let mut first = true;
+        loop {
+            let c = match self.peek() {
+                Some(c) => c,
+                None => return Err(self.error("unclosed class")),
+            };
+            self.pos += 1;
+            if c == ']' && !first {
+                break;
+            }
+            first = false;
+            let lo = match c {
+                '\\' => match self.escape()? {
+                    ClassItem::Range(lo, _) => lo,
+                    item => {
+                        items.push(item);
+                        continue;
+                    }
+                },
+                c => c,
+            };
+            if self.peek() == Some('-') && self.chars.get(self.pos + 1).is_some_and(|&c| c != ']') {
+                self.pos += 1;
+                let c = self.peek().unwrap();
+                self.pos += 1;
+                let hi = match c {
+                    '\\' => match self.escape()? {
+                        ClassItem::Range(hi, _) => hi,
+                        _ => return Err(self.error("invalid range in class")),
+                    },
+                    c => c,
+                };
+                if hi < lo {
+                    return Err(self.error("invalid range in class"));
+                }
+                items.push(ClassItem::Range(lo, hi));
+            } else {
+                items.push(ClassItem::Range(lo, lo));
+            }
+        }
+        Ok(Class { negated, items })
+    }
+
+    fn count(&mut self) -> Option<usize> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        self.chars[start..self.pos]
+            .iter()
+            .collect::<String>()
+            .parse()
+            .ok()
+    }
+
+    fn repetition(&mut self, mut node: Node) -> Result<Node, String> {
+        loop {
+            let (min, max) = match self.peek() {
+                Some('*') => (0, None),
+                Some('+') => (1, None),
+                Some('?') => (0, Some(1)),
+                Some('{') => {
+                    self.pos += 1;
+                    let min = match self.count() {
+                        Some(min) => min,
+                        None => return Err(self.error("invalid repetition")),
+                    };
+                    let max = if self.eat(',') {
+                        if self.peek() == Some('}') {
+                            None
+                        } else {
+                            match self.count() {
+                                Some(max) => Some(max),
+                                None => return Err(self.error("invalid repetition")),
+                            }
+                        }
+                    } else {
+                        Some(min)
+                    };
+                    if self.peek() != Some('}') || max.is_some_and(|max| max < min) {
+                        return Err(self.error("invalid repetition"));
+                    }
+                    if min.max(max.unwrap_or(0)) > MAX_COUNT {
+                        return Err(self.error("repetition count too large"));
+                    }
+                    (min, max)
+                }
+                _ => return Ok(node),
+            };
+            self.pos += 1;
+            let greedy = !self.eat('?');
+            node = Node::Repeat {
+                node: Box::new(node),
+                min,
+                max,
+                greedy,
+            };
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Inst {
+    Char(char),
+    Any,
+    Class(Class),
+    Start,
+    End,
+    Boundary(bool),
+    // Continues at both addresses, preferring the first.
+    Split(usize, usize),
+    Jump(usize),
+    // Records the current position in a capture slot.
+    Save(usize),
+    Match,
+}
+
+struct Compiler {
+    program: Vec<Inst>,
+}
+
+impl Compiler {
+    fn emit(&mut self, inst: Inst) -> Result<usize, String> {
+        if self.program.len() == MAX_INSTRUCTIONS {
+            return Err("pattern too large".to_string());
+        }
+        self.program.push(inst);
+        Ok(self.program.len() - 1)
+    }
+
+    // Emits a split whose targets are filled in afterwards.
+    fn split(&mut self, to: usize, greedy: bool) {
+        let here = self.program.len();
+        if let Inst::Split(x, y) = &mut self.program[to] {
+            if greedy {
+                *y = here;
+            } else {
+                *x = here;
+            }
+        }
+    }
+
+    fn compile(&mut self, node: &Node) -> Result<(), String> {
+        match node {
+            Node::Empty => {}
+            Node::Char(c) => {
+                self.emit(Inst::Char(*c))?;
+            }
+            Node::Any => {
+                self.emit(Inst::Any)?;
+            }
+            Node::Class(class) => {
+                self.emit(Inst::Class(class.clone()))?;
+            }
+            Node::Start => {
+                self.emit(Inst::Start)?;
+            }
+            Node::End => {
+                self.emit(Inst::End)?;
+            }
+            Node::Boundary(b) => {
+                self.emit(Inst::Boundary(*b))?;
+            }
+            Node::Group(node, index) => match index {
+                Some(index) => {
+                    self.emit(Inst::Save(2 * index))?;
+                    self.compile(node)?;
+                    self.emit(Inst::Save(2 * index + 1))?;
+                }
+                None => self.compile(node)?,
+            },
+            Node::Concat(nodes) => {
+                for node in nodes {
+                    self.compile(node)?;
+                }
+            }
+            Node::Alternate(nodes) => {
+                let mut jumps = Vec::new();
+                for (i, node) in nodes.iter().enumerate() {
+                    if i + 1 < nodes.len() {
+                        let split = self.emit(Inst::Split(0, 0))?;
+                        self.program[split] = Inst::Split(split + 1, 0);
+                        self.compile(node)?;
+                        jumps.push(self.emit(Inst::Jump(0))?);
+                        self.split(split, true);
+                    } else {
+                        self.compile(node)?;
+                    }
+                }
+                let end = self.program.len();
+                for jump in jumps {
+                    self.program[jump] = Inst::Jump(end);
+                }
+            }
+            Node::Repeat {
+                node,
+                min,
+                max,
+                greedy,
+            } => {
+                for _ in 0..*min {
+                    self.compile(node)?;
+                }
+                match max {
+                    None => {
+                        let split = self.emit(Inst::Split(0, 0))?;
+                        self.program[split] = if *greedy {
+                            Inst::Split(split + 1, 0)
+                        } else {
+                            Inst::Split(0, split + 1)
+                        };
+                        self.compile(node)?;
+                        self.emit(Inst::Jump(split))?;
+                        self.split(split, *greedy);
+                    }
+                    Some(max) => {
+                        let mut splits = Vec::new();
+                        for _ in *min..*max {
+                            let split = self.emit(Inst::Split(0, 0))?;
+                            self.program[split] = if *greedy {
+                                Inst::Split(split + 1, 0)
+                            } else {
+                                Inst::Split(0, split + 1)
+                            };
+                            splits.push(split);
+                            self.compile(node)?;
+                        }
+                        for split in splits {
+                            self.split(split, *greedy);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Regex {
+    program: Vec<Inst>,
+    // The number of capturing groups, not counting the whole match.
+    groups: usize,
+}
+
+// The threads waiting to run at a position, in order of priority, each with
+// its capture slots.
+struct Threads {
+    list: Vec<(usize, Vec<Option<usize>>)>,
+    seen: Vec<bool>,
+}
+
+impl Threads {
+    fn new(size: usize) -> Threads {
+        Threads {
+            list: Vec::new(),
+            seen: vec![false; size],
+        }
+    }
+
+    fn clear(&mut self) {
+        self.list.clear();
+        self.seen.iter_mut().for_each(|seen| *seen = false);
+    }
+}
+
+fn is_word(text: &[char], pos: usize) -> bool {
+    text.get(pos).is_some_and(|&c| Perl::Word.matches(c))
+}
+
+impl Regex {
+    pub fn new(pattern: &str) -> Result<Regex, String> {
+        let mut parser = Parser {
+            pattern,
+            chars: pattern.chars().collect(),
+            pos: 0,
+            groups: 0,
+        };
+        let node = parser.alternation()?;
+        if parser.pos < parser.chars.len() {
+            return Err(parser.error("unmatched )"));
+        }
+        let mut compiler = Compiler {
+            program: Vec::new(),
+        };
+        compiler
+            .compile(&node)
+            .and_then(|()| compiler.emit(Inst::Match).map(|_| ()))
+            .map_err(|reason| parser.error(&reason))?;
+        Ok(Regex {
+            program: compiler.program,
+            groups: parser.groups,
+        })
+    }
+
+    // Follows the instructions that do not consume a character, adding the
+    // threads that reach one that does.
+    fn add(
+        &self,
+        threads: &mut Threads,
+        pc: usize,
+        text: &[char],
+        pos: usize,
+        mut slots: Vec<Option<usize>>,
+    ) {
+        if threads.seen[pc] {
+            return;
+        }
+        threads.seen[pc] = true;
+        match self.program[pc] {
+            Inst::Jump(to) => self.add(threads, to, text, pos, slots),
+            Inst::Split(x, y) => {
+                self.add(threads, x, text, pos, slots.clone());
+                self.add(threads, y, text, pos, slots);
+            }
+            Inst::Save(slot) => {
+                slots[slot] = Some(pos);
+                self.add(threads, pc + 1, text, pos, slots);
+            }
+            Inst::Start if pos == 0 => self.add(threads, pc + 1, text, pos, slots),
+            Inst::End if pos == text.len() => self.add(threads, pc + 1, text, pos, slots),
+            Inst::Boundary(b) => {
+                let at = pos > 0 && is_word(text, pos - 1);
+                if (at != is_word(text, pos)) == b {
+                    self.add(threads, pc + 1, text, pos, slots);
+                }
+            }
+            Inst::Start | Inst::End => {}
+            _ => threads.list.push((pc, slots)),
+        }
+    }
+
+    // Finds the leftmost match starting at or after a position, giving the
+    // start and end of the whole match and of each group.
+    fn find_at(&self, text: &[char], start: usize) -> Option<Vec<Option<usize>>> {
+        let mut current = Threads::new(self.program.len());
+        let mut next = Threads::new(self.program.len());
+        let mut found = None;
+        for pos in start..=text.len() {
+            if found.is_none() {
+                let mut slots = vec![None; 2 * self.groups + 2];
+                slots[0] = Some(pos);
+                self.add(&mut current, 0, text, pos, slots);
+            }
+            if current.list.is_empty() && found.is_some() {
+                break;
+            }
+            for (pc, mut slots) in std::mem::take(&mut current.list) {
+                let c = text.get(pos).copied();
+                let matched = match &self.program[pc] {
+                    Inst::Char(expected) => c == Some(*expected),
+                    Inst::Any => c.is_some_and(|c| c != '\n'),
+                    Inst::Class(class) => c.is_some_and(|c| class.matches(c)),
+                    Inst::Match => {
+                        slots[1] = Some(pos);
+                        found = Some(slots);
+                        // The threads after this one have a lower priority.
+                        break;
+                    }
+                    _ => unreachable!(),
+                };
+                if matched {
+                    self.add(&mut next, pc + 1, text, pos + 1, slots);
+                }
+            }
+            std::mem::swap(&mut current, &mut next);
+            next.clear();
+        }
+        found
+    }
+
+    pub fn is_match(&self, text: &str) -> bool {
+        let text: Vec<char> = text.chars().collect();
+        self.find_at(&text, 0).is_some()
+    }
+
+    // The text of the first match and of each of its groups, where a group
+    // that took no part in the match is empty.
+    pub fn captures(&self, text: &str) -> Option<Vec<String>> {
+        let text: Vec<char> = text.chars().collect();
+        let slots = self.find_at(&text, 0)?;
+        Some(
+            slots
+                .chunks(2)
+                .map(|span| match span {
+                    [Some(start), Some(end)] => text[*start..*end].iter().collect(),
+                    _ => String::new(),
+                })
+                .collect(),
+        )
+    }
+
+    // Replaces every match, where $n in the replacement stands for the text
+    // of group n, $0 for the whole match and $$ for a dollar sign.
+    pub fn replace(&self, text: &str, replacement: &str) -> String {
+        let text: Vec<char> = text.chars().collect();
+        let replacement: Vec<char> = replacement.chars().collect();
+        let mut s = String::new();
+        let mut last = None;
+        let mut pos = 0;
+        while pos <= text.len() {
+            let slots = match self.find_at(&text, pos) {
+                Some(slots) => slots,
+                None => break,
+            };
+            let (start, end) = (slots[0].unwrap(), slots[1].unwrap());
+            // An empty match right after another one is skipped, as in
+            // replacing b* in abb, which gives -a- rather than -a--.
+            if start == end && last == Some(start) {
+                pos = start + 1;
+                continue;
+            }
+            s.extend(&text[last.unwrap_or(0)..start]);
+            let mut i = 0;
+            while i < replacement.len() {
+                let c = replacement[i];
+                i += 1;
+                if c != '$' {
+                    s.push(c);
+                } else if replacement.get(i) == Some(&'$') {
+                    s.push('$');
+                    i += 1;
+                } else {
+                    let digits = replacement[i..]
+                        .iter()
+                        .take_while(|c| c.is_ascii_digit())
+                        .count();
+                    let group = replacement[i..i + digits]
+                        .iter()
+                        .collect::<String>()
+                        .parse::<usize>();
+                    match group {
+                        Ok(group) => {
+                            if let Some([Some(start), Some(end)]) =
+                                slots.get(2 * group..2 * group + 2)
+                            {
+                                s.extend(&text[*start..*end]);
+                            }
+                        }
+                        Err(_) => s.push('$'),
+                    }
+                    i += digits;
+                }
+            }
+            last = Some(end);
+            // The next match starts after an empty one, so that replacing
+            // always moves on.
+            pos = if end == start { end + 1 } else { end };
+        }
+        s.extend(&text[last.unwrap_or(0)..]);
+        s
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::regex::Regex;
+
+    #[test]
+    fn matching() {
+        let captures = |pattern: &str, text: &str| Regex::new(pattern).unwrap().captures(text);
+        let groups = |groups: &[&str]| Some(groups.iter().map(|g| g.to_string()).collect());
+        assert_eq!(captures("b+", "abbbc"), groups(&["bbb"]));
+        assert_eq!(captures("b+?", "abbbc"), groups(&["b"]));
+        assert_eq!(
+            captures(r"(\d+)-(\d+)", "call 555-1234 now"),
+            groups(&["555-1234", "555", "1234"])
+        );
+        assert_eq!(captures("^a|b$", "cab"), groups(&["b"]));
+        assert_eq!(captures("a|ab", "ab"), groups(&["a"]));
+        assert_eq!(captures("(a)|(b)", "b"), groups(&["b", "", "b"]));
+        assert_eq!(captures("(?:ab){2,3}", "abababab"), groups(&["ababab"]));
+        assert_eq!(captures("x{2}", "xxx"), groups(&["xx"]));
+        assert_eq!(captures(r"[^\s,]+", " , héllo,"), groups(&["héllo"]));
+        assert_eq!(captures(r"[a-c\]-]+", "x-]ab"), groups(&["-]ab"]));
+        assert_eq!(captures(r"\bcat\b", "concat cat"), groups(&["cat"]));
+        assert_eq!(captures("a.c", "a\nc"), None);
+        assert_eq!(captures("(a*)*b", "aaac"), None);
+        assert_eq!(captures("", "abc"), groups(&[""]));
+
+        let re = Regex::new(r"(\w+)@(\w+)\.com").unwrap();
+        assert!(re.is_match("mail ada@example.com"));
+        assert!(!re.is_match("mail ada@example.org"));
+        assert_eq!(
+            re.replace("ada@example.com, bob@test.com", "$2:$1 ($$)"),
+            "example:ada ($), test:bob ($)"
+        );
+        assert_eq!(Regex::new("x*").unwrap().replace("abc", "-"), "-a-b-c-");
+        assert_eq!(Regex::new("b*").unwrap().replace("abb", "-"), "-a-");
+
+        for (pattern, reason) in [
+            ("(a", "unclosed group"),
+            ("a)", "unmatched )"),
+            ("[a", "unclosed class"),
+            ("*a", "nothing to repeat"),
+            ("a{2,1}", "invalid repetition"),
+            (r"\q", "unknown escape \\q"),
+            ("[z-a]", "invalid range in class"),
+            ("a{1001}", "repetition count too large"),
+        ] {
+            assert_eq!(
+                Regex::new(pattern).err(),
+                Some(format!("Invalid regex {:?}: {}.", pattern, reason))
+            );
+        }
+    }
+}
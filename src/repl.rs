@@ -0,0 +1,291 @@
+use std::cmp::min;
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, Read, Write};
+use std::path::PathBuf;
+
+const HISTORY_FILE: &str = ".plover_history";
+const HISTORY_SIZE: usize = 1000;
+
+pub enum Input {
+    Line(String),
+    Interrupted,
+    Eof,
+}
+
+enum Key {
+    Char(char),
+    Backspace,
+    Delete,
+    Down,
+    End,
+    Enter,
+    Eof,
+    Home,
+    Interrupt,
+    KillEnd,
+    KillStart,
+    Left,
+    Right,
+    Up,
+    Other,
+}
+
+// Puts the terminal into raw mode for as long as it is alive, so that keys are
+// read as they are pressed rather than a line at a time.
+struct RawMode {
+    original: libc::termios,
+}
+
+impl RawMode {
+    fn enable() -> io::Result<RawMode> {
+        unsafe {
+            let mut termios: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut termios) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let original = termios;
+            termios.c_iflag &=
+                !(libc::BRKINT | libc::ICRNL | libc::INPCK | libc::ISTRIP | libc::IXON);
+            termios.c_lflag &= !(libc::ECHO | libc::ICANON | libc::IEXTEN | libc::ISIG);
+            termios.c_cc[libc::VMIN] = 1;
+            termios.c_cc[libc::VTIME] = 0;
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSADRAIN, &termios) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(RawMode { original })
+        }
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSADRAIN, &self.original);
+        }
+    }
+}
+
+fn read_byte(stdin: &mut io::StdinLock) -> io::Result<Option<u8>> {
+    let mut byte = [0];
+    match stdin.read(&mut byte)? {
+        0 => Ok(None),
+        _ => Ok(Some(byte[0])),
+    }
+}
+
+fn read_key(stdin: &mut io::StdinLock) -> io::Result<Key> {
+    let byte = match read_byte(stdin)? {
+        Some(byte) => byte,
+        None => return Ok(Key::Eof),
+    };
+    match byte {
+        1 => Ok(Key::Home),
+        2 => Ok(Key::Left),
+        3 => Ok(Key::Interrupt),
+        4 => Ok(Key::Eof),
+        5 => Ok(Key::End),
+        6 => Ok(Key::Right),
+        8 | 127 => Ok(Key::Backspace),
+        11 => Ok(Key::KillEnd),
+        14 => Ok(Key::Down),
+        16 => Ok(Key::Up),
+        21 => Ok(Key::KillStart),
+        b'\r' | b'\n' => Ok(Key::Enter),
+        // Escape sequences for the arrow, home, end and delete keys.
+        27 => match (read_byte(stdin)?, read_byte(stdin)?) {
+            (Some(b'['), Some(b'A')) => Ok(Key::Up),
+            (Some(b'['), Some(b'B')) => Ok(Key::Down),
+            (Some(b'['), Some(b'C')) => Ok(Key::Right),
+            (Some(b'['), Some(b'D')) => Ok(Key::Left),
+            (Some(b'['), Some(b'H')) | (Some(b'O'), Some(b'H')) => Ok(Key::Home),
+            (Some(b'['), Some(b'F')) | (Some(b'O'), Some(b'F')) => Ok(Key::End),
+            (Some(b'['), Some(digit)) if digit.is_ascii_digit() => {
+                match (digit, read_byte(stdin)?) {
+                    (b'1', Some(b'~')) | (b'7', Some(b'~')) => Ok(Key::Home),
+                    (b'3', Some(b'~')) => Ok(Key::Delete),
+                    (b'4', Some(b'~')) | (b'8', Some(b'~')) => Ok(Key::End),
+                    _ => Ok(Key::Other),
+                }
+            }
+            _ => Ok(Key::Other),
+        },
+        byte if byte < 32 => Ok(Key::Other),
+        byte => {
+            // The length of a UTF-8 sequence is given by the leading bits of
+            // its first byte.
+            let len = match byte {
+                0xc0..=0xdf => 2,
+                0xe0..=0xef => 3,
+                0xf0..=0xf7 => 4,
+                _ => 1,
+            };
+            let mut bytes = vec![byte];
+            for _ in 1..len {
+                match read_byte(stdin)? {
+                    Some(byte) => bytes.push(byte),
+                    None => return Ok(Key::Eof),
+                }
+            }
+            match String::from_utf8(bytes) {
+                Ok(s) => Ok(s.chars().next().map_or(Key::Other, Key::Char)),
+                Err(_) => Ok(Key::Other),
+            }
+        }
+    }
+}
+
+fn redraw(prompt: &str, line: &[char], cursor: usize) -> io::Result<()> {
+    let mut stdout = io::stdout();
+    let text: String = line.iter().collect();
+    write!(stdout, "\r{}{}\x1b[K", prompt, text)?;
+    if cursor < line.len() {
+        write!(stdout, "\x1b[{}D", line.len() - cursor)?;
+    }
+    stdout.flush()
+}
+
+// Reads lines of input, with line editing and a history that is kept between
+// sessions when the input is a terminal.
+pub struct Editor {
+    history: Vec<String>,
+    path: Option<PathBuf>,
+    terminal: bool,
+}
+
+impl Editor {
+    pub fn new() -> Editor {
+        let terminal = unsafe {
+            libc::isatty(libc::STDIN_FILENO) == 1 && libc::isatty(libc::STDOUT_FILENO) == 1
+        };
+        let path = env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE));
+        let mut history: Vec<String> = match &path {
+            Some(path) if terminal => match fs::read_to_string(path) {
+                Ok(history) => history.lines().map(|line| line.to_string()).collect(),
+                Err(_) => Vec::new(),
+            },
+            _ => Vec::new(),
+        };
+        if history.len() > HISTORY_SIZE {
+            history.drain(..history.len() - HISTORY_SIZE);
+        }
+        Editor {
+            history,
+            path,
+            terminal,
+        }
+    }
+
+    pub fn read_line(&mut self, prompt: &str) -> io::Result<Input> {
+        if !self.terminal {
+            print!("{}", prompt);
+            io::stdout().flush()?;
+            let mut line = String::new();
+            if io::stdin().lock().read_line(&mut line)? == 0 {
+                return Ok(Input::Eof);
+            }
+            let len = line.trim_end_matches(&['\n', '\r'][..]).len();
+            line.truncate(len);
+            return Ok(Input::Line(line));
+        }
+        let input = {
+            let _raw = RawMode::enable()?;
+            self.edit(prompt)?
+        };
+        println!();
+        if let Input::Line(line) = &input {
+            self.add_history(line);
+        }
+        Ok(input)
+    }
+
+    fn edit(&mut self, prompt: &str) -> io::Result<Input> {
+        let stdin = io::stdin();
+        let mut stdin = stdin.lock();
+        let mut line: Vec<char> = Vec::new();
+        let mut cursor = 0;
+        // The history entry being shown, and the line that was being edited
+        // before moving back through the history.
+        let mut entry = self.history.len();
+        let mut edited = Vec::new();
+        redraw(prompt, &line, cursor)?;
+        loop {
+            match read_key(&mut stdin)? {
+                Key::Char(c) => {
+                    line.insert(cursor, c);
+                    cursor += 1;
+                }
+                Key::Backspace => {
+                    if cursor > 0 {
+                        cursor -= 1;
+                        line.remove(cursor);
+                    }
+                }
+                Key::Delete => {
+                    if cursor < line.len() {
+                        line.remove(cursor);
+                    }
+                }
+                Key::Down => {
+                    if entry < self.history.len() {
+                        entry += 1;
+                        line = if entry == self.history.len() {
+                            edited.clone()
+                        } else {
+                            self.history[entry].chars().collect()
+                        };
+                        cursor = line.len();
+                    }
+                }
+                Key::End => cursor = line.len(),
+                Key::Enter => return Ok(Input::Line(line.iter().collect())),
+                Key::Eof => {
+                    if line.is_empty() {
+                        return Ok(Input::Eof);
+                    }
+                    if cursor < line.len() {
+                        line.remove(cursor);
+                    }
+                }
+                Key::Home => cursor = 0,
+                Key::Interrupt => return Ok(Input::Interrupted),
+                Key::KillEnd => line.truncate(cursor),
+                Key::KillStart => {
+                    line.drain(..cursor);
+                    cursor = 0;
+                }
+                Key::Left => cursor = cursor.saturating_sub(1),
+                Key::Right => cursor = min(cursor + 1, line.len()),
+                Key::Up => {
+                    if entry > 0 {
+                        if entry == self.history.len() {
+                            edited = line.clone();
+                        }
+                        entry -= 1;
+                        line = self.history[entry].chars().collect();
+                        cursor = line.len();
+                    }
+                }
+                Key::Other => {}
+            }
+            redraw(prompt, &line, cursor)?;
+        }
+    }
+
+    // Saving the history is best effort, the session continues without it if
+    // the file can not be written.
+    fn add_history(&mut self, line: &str) {
+        if line.trim().is_empty() || self.history.last().map(|last| last.as_str()) == Some(line) {
+            return;
+        }
+        self.history.push(line.to_string());
+        if self.history.len() > HISTORY_SIZE {
+            self.history.remove(0);
+        }
+        if let Some(path) = &self.path {
+            let mut contents = self.history.join("\n");
+            contents.push('\n');
+            let _ = fs::write(path, contents);
+        }
+    }
+}
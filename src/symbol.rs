@@ -0,0 +1,63 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+// Identifiers are interned when a program is compiled or loaded, so that the
+// instructions which refer to them and the environments which bind them use a
+// small index rather than a string. The names are kept by the virtual machine
+// running the program, see VirtualMachine::symbol, and are freed along with it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Symbol(u32);
+
+// The names of the symbols of a program, in the order they were interned.
+#[derive(Clone, Debug, Default)]
+pub struct Names {
+    names: Vec<Arc<str>>,
+    symbols: HashMap<Arc<str>, Symbol>,
+}
+
+impl Names {
+    pub fn new() -> Names {
+        Names::default()
+    }
+
+    pub fn intern(&mut self, name: &str) -> Symbol {
+        if let Some(symbol) = self.symbols.get(name) {
+            return *symbol;
+        }
+        let name: Arc<str> = Arc::from(name);
+        let symbol = Symbol(self.names.len() as u32);
+        self.names.push(name.clone());
+        self.symbols.insert(name, symbol);
+        symbol
+    }
+
+    // Finds the symbol for a name without interning it.
+    pub fn lookup(&self, name: &str) -> Option<Symbol> {
+        self.symbols.get(name).copied()
+    }
+
+    pub fn name(&self, symbol: Symbol) -> &str {
+        &self.names[symbol.0 as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::symbol::Names;
+
+    #[test]
+    fn symbols() {
+        let mut names = Names::new();
+        let x = names.intern("x");
+        assert_eq!(names.intern("x"), x);
+        assert_ne!(names.intern("y"), x);
+        assert_eq!(names.name(x), "x");
+        assert_eq!(names.lookup("x"), Some(x));
+        assert_eq!(names.lookup("z"), None);
+
+        // Each set of names interns symbols of its own.
+        let mut other = Names::new();
+        assert_eq!(other.intern("y"), x);
+        assert_eq!(other.name(x), "y");
+    }
+}
@@ -0,0 +1,194 @@
+// Timestamps are milliseconds since the Unix epoch, and are formatted and
+// parsed in UTC using a subset of the strftime directives:
+//
+//   %Y year, %m month, %d day, %H hour, %M minute, %S second,
+//   %f milliseconds, %s seconds since the epoch, %% a literal %.
+
+const MILLIS_PER_DAY: i64 = 86_400_000;
+
+// Converts a date to days since the epoch, following Howard Hinnant's
+// days_from_civil.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+// The inverse of days_from_civil.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400;
+    (if month <= 2 { year + 1 } else { year }, month, day)
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+pub fn format(millis: i64, fmt: &str) -> Result<String, String> {
+    let (year, month, day) = civil_from_days(millis.div_euclid(MILLIS_PER_DAY));
+    let time = millis.rem_euclid(MILLIS_PER_DAY);
+    let mut s = String::new();
+    let mut chars = fmt.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            s.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('Y') => s.push_str(&format!("{:04}", year)),
+            Some('m') => s.push_str(&format!("{:02}", month)),
+            Some('d') => s.push_str(&format!("{:02}", day)),
+            Some('H') => s.push_str(&format!("{:02}", time / 3_600_000)),
+            Some('M') => s.push_str(&format!("{:02}", time / 60_000 % 60)),
+            Some('S') => s.push_str(&format!("{:02}", time / 1000 % 60)),
+            Some('f') => s.push_str(&format!("{:03}", time % 1000)),
+            Some('s') => s.push_str(&millis.div_euclid(1000).to_string()),
+            Some('%') => s.push('%'),
+            Some(c) => return Err(format!("Unknown directive %{} in time format.", c)),
+            None => return Err("Time format ends with %.".to_string()),
+        }
+    }
+    Ok(s)
+}
+
+// Reads a number of exactly the given number of digits, or of up to 18 digits
+// with an optional sign if digits is zero.
+fn number(input: &[u8], pos: &mut usize, digits: usize) -> Option<i64> {
+    let start = *pos;
+    if digits == 0 && matches!(input.get(*pos), Some(b'-') | Some(b'+')) {
+        *pos += 1;
+    }
+    let max = if digits == 0 { 18 } else { digits };
+    let len = input[*pos..]
+        .iter()
+        .take(max)
+        .take_while(|c| c.is_ascii_digit())
+        .count();
+    if len == 0 || (digits != 0 && len != digits) {
+        return None;
+    }
+    *pos += len;
+    std::str::from_utf8(&input[start..*pos]).ok()?.parse().ok()
+}
+
+pub fn parse(s: &str, fmt: &str) -> Result<i64, String> {
+    let fail = || format!("Can not parse {:?} as a time in format {:?}.", s, fmt);
+    let input = s.as_bytes();
+    let mut pos = 0;
+    let (mut year, mut month, mut day) = (1970, 1, 1);
+    let (mut hour, mut minute, mut second, mut millis) = (0, 0, 0, 0);
+    let mut epoch = None;
+    let mut chars = fmt.chars();
+    while let Some(c) = chars.next() {
+        let (field, digits) = match c {
+            '%' => match chars.next() {
+                Some('Y') => (&mut year, 0),
+                Some('m') => (&mut month, 2),
+                Some('d') => (&mut day, 2),
+                Some('H') => (&mut hour, 2),
+                Some('M') => (&mut minute, 2),
+                Some('S') => (&mut second, 2),
+                Some('f') => (&mut millis, 3),
+                Some('s') => {
+                    epoch = Some(number(input, &mut pos, 0).ok_or_else(fail)?);
+                    continue;
+                }
+                Some('%') => {
+                    if input.get(pos) != Some(&b'%') {
+                        return Err(fail());
+                    }
+                    pos += 1;
+                    continue;
+                }
+                Some(c) => return Err(format!("Unknown directive %{} in time format.", c)),
+                None => return Err("Time format ends with %.".to_string()),
+            },
+            c => {
+                let mut buf = [0; 4];
+                let c = c.encode_utf8(&mut buf).as_bytes();
+                if !input[pos..].starts_with(c) {
+                    return Err(fail());
+                }
+                pos += c.len();
+                continue;
+            }
+        };
+        *field = number(input, &mut pos, digits).ok_or_else(fail)?;
+    }
+    if pos != input.len() {
+        return Err(fail());
+    }
+    if let Some(seconds) = epoch {
+        return seconds.checked_mul(1000).ok_or_else(fail);
+    }
+    if !(1..=12).contains(&month)
+        || !(1..=days_in_month(year, month)).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 59
+        || year.abs() > 1_000_000
+    {
+        return Err(fail());
+    }
+    Ok(days_from_civil(year, month, day) * MILLIS_PER_DAY
+        + hour * 3_600_000
+        + minute * 60_000
+        + second * 1000
+        + millis)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::time::{format, parse};
+
+    #[test]
+    fn formatting() {
+        let fmt = "%Y-%m-%dT%H:%M:%S.%fZ";
+        assert_eq!(format(0, fmt), Ok("1970-01-01T00:00:00.000Z".to_string()));
+        assert_eq!(
+            format(951_827_696_789, fmt),
+            Ok("2000-02-29T12:34:56.789Z".to_string())
+        );
+        assert_eq!(format(-1, fmt), Ok("1969-12-31T23:59:59.999Z".to_string()));
+        assert_eq!(format(-1, "%s 100%%"), Ok("-1 100%".to_string()));
+        assert_eq!(
+            format(0, "%q"),
+            Err("Unknown directive %q in time format.".to_string())
+        );
+    }
+
+    #[test]
+    fn parsing() {
+        let fmt = "%Y-%m-%dT%H:%M:%S.%fZ";
+        for millis in &[0, -1, 951_827_696_789, 4_102_444_800_000] {
+            assert_eq!(parse(&format(*millis, fmt).unwrap(), fmt), Ok(*millis));
+        }
+        assert_eq!(parse("2000-03-01", "%Y-%m-%d"), Ok(951_868_800_000));
+        assert_eq!(parse("12:00", "%H:%M"), Ok(43_200_000));
+        assert_eq!(parse("1700000000", "%s"), Ok(1_700_000_000_000));
+        assert_eq!(
+            parse("2001-02-29", "%Y-%m-%d"),
+            Err("Can not parse \"2001-02-29\" as a time in format \"%Y-%m-%d\".".to_string())
+        );
+        assert!(parse("2000-1-01", "%Y-%m-%d").is_err());
+        assert!(parse("2000-01-01 ", "%Y-%m-%d").is_err());
+        assert!(parse("24:00", "%H:%M").is_err());
+    }
+}
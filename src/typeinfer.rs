@@ -2,17 +2,30 @@ use std::collections::HashMap;
 use std::collections::HashSet;
 use std::fmt;
 
-use crate::codegen::InterpreterError;
+use crate::bigint::BigInt;
+use crate::codegen::{ErrorKind, InterpreterError};
 use crate::parser;
 use crate::unification::unify;
 
 #[derive(Clone, Debug)]
 pub enum Type {
+    Array(Box<Type>),
+    BigInt,
     Boolean,
+    Bytes,
+    Channel(Box<Type>),
+    Char,
     Datatype(String),
+    Float,
     Function(Box<Type>, Box<Type>),
     Integer,
+    List(Box<Type>),
+    Map(Box<Type>, Box<Type>),
+    Pid,
     Polymorphic(String),
+    Record(Vec<(String, Type)>),
+    String,
+    Timestamp,
     Tuple(Vec<Type>),
     Unit,
 }
@@ -27,9 +40,38 @@ impl PartialEq for Type {
             }
         }
         match self {
+            Type::Array(element) => {
+                if let Type::Array(other_element) = other {
+                    element == other_element
+                } else {
+                    false
+                }
+            }
+            Type::BigInt => {
+                matches!(other, Type::BigInt)
+            }
             Type::Boolean => {
                 matches!(other, Type::Boolean)
             }
+            Type::Bytes => {
+                matches!(other, Type::Bytes)
+            }
+            Type::Timestamp => {
+                matches!(other, Type::Timestamp)
+            }
+            Type::Channel(element) => {
+                if let Type::Channel(other_element) = other {
+                    element == other_element
+                } else {
+                    false
+                }
+            }
+            Type::Char => {
+                matches!(other, Type::Char)
+            }
+            Type::Float => {
+                matches!(other, Type::Float)
+            }
             Type::Function(param, body) => {
                 if let Type::Function(other_param, other_body) = other {
                     param == other_param && body == other_body
@@ -40,6 +82,23 @@ impl PartialEq for Type {
             Type::Integer => {
                 matches!(other, Type::Integer)
             }
+            Type::List(element) => {
+                if let Type::List(other_element) = other {
+                    element == other_element
+                } else {
+                    false
+                }
+            }
+            Type::Map(key, value) => {
+                if let Type::Map(other_key, other_value) = other {
+                    key == other_key && value == other_value
+                } else {
+                    false
+                }
+            }
+            Type::Pid => {
+                matches!(other, Type::Pid)
+            }
             Type::Polymorphic(s) => {
                 if let Type::Polymorphic(t) = other {
                     s == t
@@ -47,6 +106,20 @@ impl PartialEq for Type {
                     true
                 }
             }
+            Type::Record(fields) => {
+                if let Type::Record(other_fields) = other {
+                    fields.len() == other_fields.len()
+                        && fields
+                            .iter()
+                            .zip(other_fields)
+                            .all(|(field, other)| field.0 == other.0 && field.1 == other.1)
+                } else {
+                    false
+                }
+            }
+            Type::String => {
+                matches!(other, Type::String)
+            }
             Type::Tuple(elements) => {
                 if let Type::Tuple(other_elements) = other {
                     for i in 0..elements.len() {
@@ -76,10 +149,37 @@ impl PartialEq for Type {
 impl fmt::Display for Type {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
+            Type::Array(element) => write!(f, "[{}]", element),
+            Type::BigInt => write!(f, "bigint"),
             Type::Boolean => write!(f, "boolean"),
+            Type::Bytes => write!(f, "bytes"),
+            Type::Channel(element) => match **element {
+                Type::Function(..) => write!(f, "({}) channel", element),
+                _ => write!(f, "{} channel", element),
+            },
+            Type::Char => write!(f, "char"),
+            Type::Float => write!(f, "float"),
             Type::Function(param, body) => write!(f, "{} -> {}", param, body),
             Type::Integer => write!(f, "integer"),
+            Type::List(element) => match **element {
+                Type::Function(..) => write!(f, "({}) list", element),
+                _ => write!(f, "{} list", element),
+            },
+            Type::Map(key, value) => write!(f, "%{{{} => {}}}", key, value),
+            Type::Pid => write!(f, "pid"),
             Type::Polymorphic(s) => write!(f, "{}", s),
+            Type::Record(fields) => {
+                write!(f, "{{")?;
+                for i in 0..fields.len() {
+                    write!(f, "{}: {}", fields[i].0, fields[i].1)?;
+                    if i + 1 != fields.len() {
+                        write!(f, ", ")?;
+                    }
+                }
+                write!(f, "}}")
+            }
+            Type::String => write!(f, "string"),
+            Type::Timestamp => write!(f, "timestamp"),
             Type::Tuple(elements) => {
                 write!(f, "(")?;
                 for i in 0..elements.len() {
@@ -98,6 +198,7 @@ impl fmt::Display for Type {
 
 #[derive(Clone, Debug)]
 pub enum TypedAST {
+    Array(Type, Vec<TypedAST>),
     BinaryOp(
         Type,
         parser::Operator,
@@ -106,47 +207,87 @@ pub enum TypedAST {
         usize,
         usize,
     ),
+    BigInt(BigInt),
     Boolean(bool),
-    Call(Box<TypedAST>, Box<TypedAST>),
+    Bytes(Vec<u8>),
+    Call(Box<TypedAST>, Box<TypedAST>, usize, usize),
+    Char(char),
     Datatype(Type, Vec<(String, Type)>),
     Define(Type, String, Box<TypedAST>),
-    Function(Option<String>, Box<TypedAST>, Box<TypedAST>),
+    Field(Type, Box<TypedAST>, String),
+    // The deferred expression is compiled as a function taking unit.
+    Defer(Box<TypedAST>),
+    Float(f64),
+    // The body of a for expression is checked and compiled as a function
+    // that is called with each element.
+    For(Type, Box<TypedAST>, Box<TypedAST>),
+    Function(Option<String>, Box<TypedAST>, Box<TypedAST>, usize, usize),
     Identifier(Type, String),
-    If(Vec<(TypedAST, TypedAST)>, Box<TypedAST>),
+    If(Vec<(TypedAST, TypedAST)>, Box<TypedAST>, usize, usize),
     Integer(i64),
+    Let(Box<TypedAST>, Box<TypedAST>, Box<TypedAST>, usize, usize),
+    Letrec(Vec<TypedAST>),
+    List(Type, Vec<TypedAST>),
+    Map(Type, Vec<(TypedAST, TypedAST)>),
     Match(
         Box<TypedAST>,
         Type,
-        Vec<(String, Option<TypedAST>, TypedAST)>,
+        Vec<(String, Option<TypedAST>, Option<TypedAST>, TypedAST)>,
     ),
     Program(Type, Vec<TypedAST>),
+    Propagate(Type, Box<TypedAST>, usize, usize),
+    Record(Type, Vec<(String, TypedAST)>),
+    String(String),
+    Try(Box<TypedAST>, Box<TypedAST>, Box<TypedAST>, usize, usize),
     Tuple(Type, Vec<TypedAST>),
     UnaryOp(Type, parser::Operator, Box<TypedAST>),
     Unit,
+    Update(Type, Box<TypedAST>, Vec<(String, TypedAST)>),
+    // A while loop has a condition and a body, and is unit.
+    While(Box<TypedAST>, Box<TypedAST>),
 }
 
 pub fn type_of(ast: &TypedAST) -> Type {
     match ast {
-        TypedAST::BinaryOp(typ, _, _, _, _, _)
+        TypedAST::Array(typ, _)
+        | TypedAST::BinaryOp(typ, _, _, _, _, _)
         | TypedAST::Datatype(typ, _)
         | TypedAST::Define(typ, _, _)
+        | TypedAST::Field(typ, _, _)
+        | TypedAST::For(typ, _, _)
         | TypedAST::Identifier(typ, _)
+        | TypedAST::List(typ, _)
+        | TypedAST::Map(typ, _)
         | TypedAST::Program(typ, _)
+        | TypedAST::Propagate(typ, _, _, _)
+        | TypedAST::Record(typ, _)
         | TypedAST::Tuple(typ, _)
-        | TypedAST::UnaryOp(typ, _, _) => typ.clone(),
+        | TypedAST::UnaryOp(typ, _, _)
+        | TypedAST::Update(typ, _, _) => typ.clone(),
+        TypedAST::BigInt(_) => Type::BigInt,
         TypedAST::Boolean(_) => Type::Boolean,
-        TypedAST::Call(fun, _) => match type_of(fun) {
+        TypedAST::Bytes(_) => Type::Bytes,
+        TypedAST::Char(_) => Type::Char,
+        TypedAST::Defer(_) | TypedAST::While(..) => Type::Unit,
+        TypedAST::Float(_) => Type::Float,
+        TypedAST::Let(_, _, body, _, _) | TypedAST::Try(body, _, _, _, _) => type_of(body),
+        TypedAST::Letrec(functions) => match functions.last() {
+            Some(function) => type_of(function),
+            None => unreachable!(),
+        },
+        TypedAST::Call(fun, ..) => match type_of(fun) {
             Type::Function(_, body) => *body,
             _ => unreachable!(),
         },
-        TypedAST::Function(_, param, body) => {
+        TypedAST::Function(_, param, body, ..) => {
             Type::Function(Box::new(type_of(param)), Box::new(type_of(body)))
         }
-        TypedAST::If(_, els) => type_of(&els),
+        TypedAST::If(_, els, _, _) => type_of(els),
         TypedAST::Integer(_) => Type::Integer,
+        TypedAST::String(_) => Type::String,
         TypedAST::Match(_, _, cases) => {
             if !cases.is_empty() {
-                type_of(&cases[0].2)
+                type_of(&cases[0].3)
             } else {
                 unreachable!()
             }
@@ -155,15 +296,373 @@ pub fn type_of(ast: &TypedAST) -> Type {
     }
 }
 
+// Arithmetic and comparison operators work on integers, floats or bigints, but
+// both operands must have the same type. If neither operand's type is known
+// yet, the choice is deferred until the constraints for the enclosing
+// function have been solved, see infer.
+fn numeric_type(lhs: &TypedAST, rhs: &TypedAST) -> Option<Type> {
+    match (type_of(lhs), type_of(rhs)) {
+        (Type::Float, _) | (_, Type::Float) => Some(Type::Float),
+        (Type::BigInt, _) | (_, Type::BigInt) => Some(Type::BigInt),
+        (Type::Polymorphic(_), Type::Polymorphic(_)) => None,
+        _ => Some(Type::Integer),
+    }
+}
+
+// The parameters of a variant are either type names, which fix the type of
+// that field, or any other identifier, which leaves it polymorphic.
+fn build_field_types(
+    id: &mut u64,
+    datatype: &str,
+    datatypes: &HashMap<String, HashSet<String>>,
+    ast: &parser::AST,
+) -> Result<Type, InterpreterError> {
+    match ast {
+        parser::AST::Identifier(s, _, _) => Ok(match s.as_str() {
+            "bigint" => Type::BigInt,
+            "boolean" => Type::Boolean,
+            "bytes" => Type::Bytes,
+            "char" => Type::Char,
+            "float" => Type::Float,
+            "integer" => Type::Integer,
+            "string" => Type::String,
+            "timestamp" => Type::Timestamp,
+            "unit" => Type::Unit,
+            _ if s == datatype || datatypes.contains_key(s) => Type::Datatype(s.to_string()),
+            _ => fresh_type(id),
+        }),
+        parser::AST::Tuple(elements, _, _) => {
+            let mut types = Vec::new();
+            for element in elements {
+                types.push(build_field_types(id, datatype, datatypes, element)?);
+            }
+            Ok(Type::Tuple(types))
+        }
+        _ => Ok(type_of(&build_param_constraints(
+            id,
+            &mut HashMap::new(),
+            ast,
+            false,
+        )?)),
+    }
+}
+
+// Ties the parameters of a match case to the declared field types of its
+// variant. Polymorphic fields place no constraint on the parameters.
+fn constrain_fields(
+    param: &Type,
+    fields: &Type,
+    constraints: &mut Vec<(Type, Type, usize, usize)>,
+    line: usize,
+    col: usize,
+) {
+    match (param, fields) {
+        (Type::Tuple(params), Type::Tuple(fields)) if params.len() == fields.len() => {
+            for (param, field) in params.iter().zip(fields) {
+                constrain_fields(param, field, constraints, line, col);
+            }
+        }
+        (_, Type::Polymorphic(_)) => {}
+        _ => constraints.push((fields.clone(), param.clone(), line, col)),
+    }
+}
+
+fn mismatch(expected: Type, found: Type, line: usize, col: usize) -> InterpreterError {
+    let mut err = "Type error: expected ".to_string();
+    err.push_str(&expected.to_string());
+    err.push_str(" but found ");
+    err.push_str(&found.to_string());
+    err.push('.');
+    InterpreterError::new(
+        ErrorKind::Type {
+            expected: Some(expected),
+            found: Some(found),
+        },
+        err,
+        line,
+        col,
+    )
+}
+
+fn expected_record(typ: &Type, line: usize, col: usize) -> InterpreterError {
+    let mut err = "Type error: expected record but found ".to_string();
+    err.push_str(&typ.to_string());
+    err.push('.');
+    InterpreterError::type_error(err, line, col)
+}
+
+fn missing_field(typ: &Type, field: &str, line: usize, col: usize) -> InterpreterError {
+    let mut err = "Type error: ".to_string();
+    err.push_str(&typ.to_string());
+    err.push_str(" has no field ");
+    err.push_str(field);
+    err.push('.');
+    InterpreterError::type_error(err, line, col)
+}
+
+fn missing_element(typ: &Type, index: &str, line: usize, col: usize) -> InterpreterError {
+    let mut err = "Type error: ".to_string();
+    err.push_str(&typ.to_string());
+    err.push_str(" has no element ");
+    err.push_str(index);
+    err.push('.');
+    InterpreterError::type_error(err, line, col)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn build_field_constraints(
+    id: &mut u64,
+    constraints: &mut Vec<(Type, Type, usize, usize)>,
+    classes: &mut Vec<(usize, Class, Type, usize, usize)>,
+    ids: &mut HashMap<String, Type>,
+    datatypes: &mut HashMap<String, HashSet<String>>,
+    fields: &[(String, parser::AST)],
+    line: usize,
+    col: usize,
+) -> Result<Vec<(String, TypedAST)>, InterpreterError> {
+    let mut typed_fields: Vec<(String, TypedAST)> = Vec::new();
+    for (field, value) in fields {
+        if typed_fields.iter().any(|f| f.0 == *field) {
+            let mut err = "Duplicate field in record: ".to_string();
+            err.push_str(field);
+            err.push('.');
+            return Err(InterpreterError::type_error(err, line, col));
+        }
+        let typed_value = build_constraints(id, constraints, classes, ids, datatypes, value)?;
+        typed_fields.push((field.to_string(), typed_value));
+    }
+    Ok(typed_fields)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn build_defer_constraints(
+    id: &mut u64,
+    constraints: &mut Vec<(Type, Type, usize, usize)>,
+    classes: &mut Vec<(usize, Class, Type, usize, usize)>,
+    ids: &mut HashMap<String, Type>,
+    datatypes: &mut HashMap<String, HashSet<String>>,
+    value: &parser::AST,
+    line: usize,
+    col: usize,
+) -> Result<TypedAST, InterpreterError> {
+    if parser::propagates(value) {
+        return Err(InterpreterError::type_error(
+            "Type error: ? can not be used in a deferred expression.".to_string(),
+            line,
+            col,
+        ));
+    }
+    let function = parser::AST::Function(
+        None,
+        Box::new(parser::AST::Unit(line, col)),
+        Box::new(value.clone()),
+        line,
+        col,
+    );
+    let typed_function = build_constraints(id, constraints, classes, ids, datatypes, &function)?;
+    Ok(TypedAST::Defer(Box::new(typed_function)))
+}
+
+// The body of a for expression is checked as a function called with each
+// element, given as the parameter, the array and the body.
+#[allow(clippy::too_many_arguments)]
+fn build_for_constraints(
+    id: &mut u64,
+    constraints: &mut Vec<(Type, Type, usize, usize)>,
+    classes: &mut Vec<(usize, Class, Type, usize, usize)>,
+    ids: &mut HashMap<String, Type>,
+    datatypes: &mut HashMap<String, HashSet<String>>,
+    (param, iter, body): (&parser::AST, &parser::AST, &parser::AST),
+    line: usize,
+    col: usize,
+) -> Result<TypedAST, InterpreterError> {
+    // A ? in the body would return from the function it is compiled
+    // to rather than from the one the loop is in.
+    if parser::propagates(body) {
+        return Err(InterpreterError::type_error(
+            "Type error: ? can not be used in the body of a for expression.".to_string(),
+            line,
+            col,
+        ));
+    }
+    let typed_iter = build_constraints(id, constraints, classes, ids, datatypes, iter)?;
+    // The parameter has the type of the elements before the body is
+    // checked, so that the operators in the body are resolved with it.
+    let mut local_ids = ids.clone();
+    let names = match param {
+        parser::AST::Tuple(elements, _, _) => elements.iter().collect(),
+        _ => vec![param],
+    };
+    for name in names {
+        if let parser::AST::Identifier(name, _, _) = name {
+            local_ids.remove(name);
+        }
+    }
+    let typed_param = build_param_constraints(id, &mut local_ids, param, true)?;
+    constraints.push((
+        Type::Array(Box::new(type_of(&typed_param))),
+        type_of(&typed_iter),
+        line,
+        col,
+    ));
+    let function = parser::AST::Function(
+        None,
+        Box::new(param.clone()),
+        Box::new(body.clone()),
+        line,
+        col,
+    );
+    let typed_function = build_constraints(
+        id,
+        constraints,
+        classes,
+        &mut local_ids,
+        datatypes,
+        &function,
+    )?;
+    let result = match type_of(&typed_function) {
+        Type::Function(_, result) => result,
+        _ => unreachable!(),
+    };
+    Ok(TypedAST::For(
+        Type::Array(result),
+        Box::new(typed_function),
+        Box::new(typed_iter),
+    ))
+}
+
+fn unreachable_case(variant: &str, line: usize, col: usize) -> InterpreterError {
+    let mut err = "Unreachable case in match: ".to_string();
+    err.push_str(variant);
+    err.push('.');
+    InterpreterError::type_error(err, line, col)
+}
+
+// Records that a case matches a variant, returning false if an earlier case
+// without a guard already did, so that this one can never be reached.
+fn cover(present_variants: &mut HashSet<String>, variant: &str, guarded: bool) -> bool {
+    if present_variants.contains(variant) {
+        return false;
+    }
+    if !guarded {
+        present_variants.insert(variant.to_string());
+    }
+    true
+}
+
+fn instantiate(id: &mut u64, typ: &Type, fresh: &mut HashMap<String, Type>) -> Type {
+    match typ {
+        Type::Polymorphic(s) => fresh
+            .entry(s.to_string())
+            .or_insert_with(|| fresh_type(id))
+            .clone(),
+        Type::Array(element) => Type::Array(Box::new(instantiate(id, element, fresh))),
+        Type::List(element) => Type::List(Box::new(instantiate(id, element, fresh))),
+        Type::Channel(element) => Type::Channel(Box::new(instantiate(id, element, fresh))),
+        Type::Map(key, value) => Type::Map(
+            Box::new(instantiate(id, key, fresh)),
+            Box::new(instantiate(id, value, fresh)),
+        ),
+        Type::Function(param, body) => Type::Function(
+            Box::new(instantiate(id, param, fresh)),
+            Box::new(instantiate(id, body, fresh)),
+        ),
+        Type::Record(fields) => Type::Record(
+            fields
+                .iter()
+                .map(|(field, typ)| (field.to_string(), instantiate(id, typ, fresh)))
+                .collect(),
+        ),
+        Type::Tuple(elements) => Type::Tuple(
+            elements
+                .iter()
+                .map(|element| instantiate(id, element, fresh))
+                .collect(),
+        ),
+        _ => typ.clone(),
+    }
+}
+
 fn fresh_type(id: &mut u64) -> Type {
     let typ = Type::Polymorphic("t".to_owned() + &id.to_string());
     *id += 1;
     typ
 }
 
+// Builtins that take either strings or arrays, such as length, have a second
+// type kept among the identifiers under their name followed by [], which no
+// identifier can have. It is used when they are called, with the type variable
+// named by SEQUENCE standing for a string or an array, which is checked once
+// the constraints of the enclosing function are solved. Their type as values
+// is the one for strings.
+pub const SEQUENCE: &str = "s";
+
+pub fn sequence_type(name: &str) -> String {
+    name.to_string() + "[]"
+}
+
+// The types that a type not yet known is limited to.
+#[derive(Clone, Copy)]
+enum Class {
+    Hashable,
+    Numeric,
+    Sequence,
+}
+
+// Whether values of a type can be used as the keys of a map. Floats are not,
+// as NaN is not equal to itself, and neither are functions, which are only
+// compared by their entry points.
+fn hashable(typ: &Type) -> bool {
+    match typ {
+        Type::Float | Type::Function(_, _) => false,
+        Type::Array(element) | Type::List(element) => hashable(element),
+        Type::Map(key, value) => hashable(key) && hashable(value),
+        Type::Record(fields) => fields.iter().all(|(_, typ)| hashable(typ)),
+        Type::Tuple(elements) => elements.iter().all(hashable),
+        _ => true,
+    }
+}
+
+// The key types of the maps among the parameters of a builtin.
+fn map_keys(typ: &Type) -> Vec<Type> {
+    match typ {
+        Type::Map(key, _) => vec![*key.clone()],
+        Type::Tuple(elements) => elements.iter().flat_map(map_keys).collect(),
+        _ => Vec::new(),
+    }
+}
+
+// Whether a type refers to type variables created during inference rather than
+// only those written in a declared type.
+fn has_fresh_types(typ: &Type) -> bool {
+    match typ {
+        Type::Polymorphic(s) => s
+            .strip_prefix('t')
+            .is_some_and(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit())),
+        Type::Array(element) | Type::Channel(element) | Type::List(element) => {
+            has_fresh_types(element)
+        }
+        Type::Map(key, value) => has_fresh_types(key) || has_fresh_types(value),
+        Type::Function(param, body) => has_fresh_types(param) || has_fresh_types(body),
+        Type::Record(fields) => fields.iter().any(|(_, typ)| has_fresh_types(typ)),
+        Type::Tuple(elements) => elements.iter().any(has_fresh_types),
+        _ => false,
+    }
+}
+
+// Whether an identifier refers to the receive builtin. The messages it takes
+// are only checked against the type the program expects where it is called,
+// so it can not be used as a value.
+fn is_receive(ids: &HashMap<String, Type>, name: &str) -> bool {
+    name == "receive"
+        && matches!(ids.get(name), Some(Type::Function(param, body))
+            if matches!(**param, Type::Unit)
+                && matches!(**body, Type::Polymorphic(_))
+                && !has_fresh_types(body))
+}
+
 fn build_param_constraints(
     id: &mut u64,
-    constraints: &mut Vec<(Type, Type, usize, usize)>,
     ids: &mut HashMap<String, Type>,
     ast: &parser::AST,
     insert_into_ids: bool,
@@ -189,44 +688,151 @@ fn build_param_constraints(
             let mut types = Vec::new();
             let mut typed_elements = Vec::new();
             for element in elements {
-                let typed_element =
-                    build_param_constraints(id, constraints, ids, &element, insert_into_ids)?;
+                let typed_element = build_param_constraints(id, ids, element, insert_into_ids)?;
                 types.push(type_of(&typed_element));
                 typed_elements.push(typed_element);
             }
             Ok(TypedAST::Tuple(Type::Tuple(types), typed_elements))
         }
         parser::AST::Unit(_, _) => Ok(TypedAST::Unit),
-        parser::AST::BinaryOp(_, _, _, line, col)
+        parser::AST::Array(_, line, col)
+        | parser::AST::BigInt(_, line, col)
+        | parser::AST::BinaryOp(_, _, _, line, col)
+        | parser::AST::Block(_, line, col)
         | parser::AST::Boolean(_, line, col)
+        | parser::AST::Bytes(_, line, col)
         | parser::AST::Call(_, _, line, col)
+        | parser::AST::Char(_, line, col)
         | parser::AST::Datatype(_, _, line, col)
         | parser::AST::Define(_, _, line, col)
+        | parser::AST::Field(_, _, line, col)
+        | parser::AST::Defer(_, line, col)
+        | parser::AST::Float(_, line, col)
+        | parser::AST::For(_, _, _, line, col)
         | parser::AST::Function(_, _, _, line, col)
         | parser::AST::If(_, _, line, col)
         | parser::AST::Integer(_, line, col)
+        | parser::AST::Let(_, _, _, line, col)
+        | parser::AST::Letrec(_, line, col)
+        | parser::AST::List(_, line, col)
+        | parser::AST::Map(_, line, col)
         | parser::AST::Match(_, _, line, col)
         | parser::AST::Program(_, line, col)
-        | parser::AST::UnaryOp(_, _, line, col) => Err(InterpreterError {
-            err: "Type error: lambda parameter must be identifier or tuple of identifiers."
-                .to_string(),
-            line: *line,
-            col: *col,
-        }),
+        | parser::AST::Propagate(_, line, col)
+        | parser::AST::Record(_, line, col)
+        | parser::AST::Sequence(_, line, col)
+        | parser::AST::String(_, line, col)
+        | parser::AST::Try(_, _, _, line, col)
+        | parser::AST::UnaryOp(_, _, line, col)
+        | parser::AST::Update(_, _, line, col)
+        | parser::AST::When(_, _, _, line, col)
+        | parser::AST::While(_, _, line, col) => Err(InterpreterError::type_error(
+            "Type error: lambda parameter must be identifier or tuple of identifiers.".to_string(),
+            *line,
+            *col,
+        )),
     }
 }
 
+// Lists are matched as if they were a datatype with these two variants, the
+// first of which has the head and tail of the list as its fields.
+pub const CONS: &str = "::";
+pub const NIL: &str = "%[]";
+
+// The type that the function being checked returns is kept among the
+// identifiers under a name that no identifier can have, for ? to refer to.
+const RETURN: &str = "?";
+
 fn build_constraints(
     id: &mut u64,
     constraints: &mut Vec<(Type, Type, usize, usize)>,
-    mut ids: &mut HashMap<String, Type>,
+    classes: &mut Vec<(usize, Class, Type, usize, usize)>,
+    ids: &mut HashMap<String, Type>,
     datatypes: &mut HashMap<String, HashSet<String>>,
     ast: &parser::AST,
 ) -> Result<TypedAST, InterpreterError> {
     match ast {
+        parser::AST::Array(elements, line, col) => {
+            // Every element of an array has the same type.
+            let typ = fresh_type(id);
+            let mut typed_elements = Vec::new();
+            for element in elements {
+                let typed_element =
+                    build_constraints(id, constraints, classes, ids, datatypes, element)?;
+                constraints.push((typ.clone(), type_of(&typed_element), *line, *col));
+                typed_elements.push(typed_element);
+            }
+            Ok(TypedAST::Array(Type::Array(Box::new(typ)), typed_elements))
+        }
+        parser::AST::Defer(value, line, col) => {
+            build_defer_constraints(id, constraints, classes, ids, datatypes, value, *line, *col)
+        }
+        parser::AST::For(param, iter, body, line, col) => build_for_constraints(
+            id,
+            constraints,
+            classes,
+            ids,
+            datatypes,
+            (param, iter, body),
+            *line,
+            *col,
+        ),
+        parser::AST::While(cond, body, line, col) => {
+            let typed_cond = build_constraints(id, constraints, classes, ids, datatypes, cond)?;
+            constraints.push((Type::Boolean, type_of(&typed_cond), *line, *col));
+            // A definition in the body that replaces one from before the loop
+            // is seen by the condition and the next time round, so it must
+            // keep its type. Those that are new to the body may not have been
+            // made once the loop is done.
+            let mut local_ids = ids.clone();
+            let typed_body =
+                build_constraints(id, constraints, classes, &mut local_ids, datatypes, body)?;
+            for (name, typ) in ids.iter() {
+                if let Some(redefined) = local_ids.get(name) {
+                    if redefined != typ {
+                        constraints.push((typ.clone(), redefined.clone(), *line, *col));
+                    }
+                }
+            }
+            Ok(TypedAST::While(Box::new(typed_cond), Box::new(typed_body)))
+        }
+        parser::AST::Block(body, _, _) => {
+            build_constraints(id, constraints, classes, ids, datatypes, body)
+        }
+        parser::AST::List(elements, line, col) => {
+            let typ = fresh_type(id);
+            let mut typed_elements = Vec::new();
+            for element in elements {
+                let typed_element =
+                    build_constraints(id, constraints, classes, ids, datatypes, element)?;
+                constraints.push((typ.clone(), type_of(&typed_element), *line, *col));
+                typed_elements.push(typed_element);
+            }
+            Ok(TypedAST::List(Type::List(Box::new(typ)), typed_elements))
+        }
+        parser::AST::Map(entries, line, col) => {
+            // Every key of a map has the same type, which must be hashable,
+            // and so does every value.
+            let key_type = fresh_type(id);
+            let value_type = fresh_type(id);
+            let mut typed_entries = Vec::new();
+            for (key, value) in entries {
+                let typed_key = build_constraints(id, constraints, classes, ids, datatypes, key)?;
+                let typed_value =
+                    build_constraints(id, constraints, classes, ids, datatypes, value)?;
+                constraints.push((key_type.clone(), type_of(&typed_key), *line, *col));
+                constraints.push((value_type.clone(), type_of(&typed_value), *line, *col));
+                typed_entries.push((typed_key, typed_value));
+            }
+            classes.push((usize::MAX, Class::Hashable, key_type.clone(), *line, *col));
+            Ok(TypedAST::Map(
+                Type::Map(Box::new(key_type), Box::new(value_type)),
+                typed_entries,
+            ))
+        }
         parser::AST::BinaryOp(op, lhs, rhs, line, col) => {
-            let typed_lhs = build_constraints(id, constraints, ids, datatypes, &lhs)?;
-            let typed_rhs = build_constraints(id, constraints, ids, datatypes, &rhs)?;
+            let typed_lhs = build_constraints(id, constraints, classes, ids, datatypes, lhs)?;
+            let typed_rhs = build_constraints(id, constraints, classes, ids, datatypes, rhs)?;
 
             let typ = fresh_type(id);
             match op {
@@ -235,21 +841,75 @@ fn build_constraints(
                     constraints.push((Type::Boolean, type_of(&typed_rhs), *line, *col));
                     constraints.push((typ.clone(), Type::Boolean, *line, *col));
                 }
+                parser::Operator::Range => {
+                    constraints.push((Type::Integer, type_of(&typed_lhs), *line, *col));
+                    constraints.push((Type::Integer, type_of(&typed_rhs), *line, *col));
+                    constraints.push((
+                        typ.clone(),
+                        Type::Array(Box::new(Type::Integer)),
+                        *line,
+                        *col,
+                    ));
+                }
+                parser::Operator::Cons => {
+                    let list = Type::List(Box::new(type_of(&typed_lhs)));
+                    constraints.push((list.clone(), type_of(&typed_rhs), *line, *col));
+                    constraints.push((typ.clone(), list, *line, *col));
+                }
+                parser::Operator::Concat => {
+                    constraints.push((Type::String, type_of(&typed_lhs), *line, *col));
+                    constraints.push((Type::String, type_of(&typed_rhs), *line, *col));
+                    constraints.push((typ.clone(), Type::String, *line, *col));
+                }
                 parser::Operator::Divide
                 | parser::Operator::Mod
                 | parser::Operator::Multiply
                 | parser::Operator::Minus
-                | parser::Operator::Plus => {
-                    constraints.push((Type::Integer, type_of(&typed_lhs), *line, *col));
-                    constraints.push((Type::Integer, type_of(&typed_rhs), *line, *col));
-                    constraints.push((typ.clone(), Type::Integer, *line, *col));
-                }
+                | parser::Operator::Plus
+                | parser::Operator::Power => match numeric_type(&typed_lhs, &typed_rhs) {
+                    Some(num) => {
+                        constraints.push((num.clone(), type_of(&typed_lhs), *line, *col));
+                        constraints.push((num.clone(), type_of(&typed_rhs), *line, *col));
+                        constraints.push((typ.clone(), num, *line, *col));
+                    }
+                    None => {
+                        constraints.push((type_of(&typed_lhs), type_of(&typed_rhs), *line, *col));
+                        constraints.push((typ.clone(), type_of(&typed_lhs), *line, *col));
+                        classes.push((usize::MAX, Class::Numeric, typ.clone(), *line, *col));
+                    }
+                },
                 parser::Operator::Greater
                 | parser::Operator::GreaterEqual
                 | parser::Operator::Less
                 | parser::Operator::LessEqual => {
-                    constraints.push((Type::Integer, type_of(&typed_lhs), *line, *col));
-                    constraints.push((Type::Integer, type_of(&typed_rhs), *line, *col));
+                    // Characters are ordered by their code points.
+                    let chars = matches!(type_of(&typed_lhs), Type::Char)
+                        || matches!(type_of(&typed_rhs), Type::Char);
+                    match numeric_type(&typed_lhs, &typed_rhs) {
+                        _ if chars => {
+                            constraints.push((Type::Char, type_of(&typed_lhs), *line, *col));
+                            constraints.push((Type::Char, type_of(&typed_rhs), *line, *col));
+                        }
+                        Some(num) => {
+                            constraints.push((num.clone(), type_of(&typed_lhs), *line, *col));
+                            constraints.push((num, type_of(&typed_rhs), *line, *col));
+                        }
+                        None => {
+                            constraints.push((
+                                type_of(&typed_lhs),
+                                type_of(&typed_rhs),
+                                *line,
+                                *col,
+                            ));
+                            classes.push((
+                                usize::MAX,
+                                Class::Numeric,
+                                type_of(&typed_lhs),
+                                *line,
+                                *col,
+                            ));
+                        }
+                    }
                     constraints.push((typ.clone(), Type::Boolean, *line, *col));
                 }
                 parser::Operator::Equal | parser::Operator::NotEqual => {
@@ -268,31 +928,107 @@ fn build_constraints(
                 *col,
             ))
         }
+        parser::AST::BigInt(n, _, _) => Ok(TypedAST::BigInt(n.clone())),
+        parser::AST::Bytes(bytes, _, _) => Ok(TypedAST::Bytes(bytes.clone())),
         parser::AST::Boolean(b, _, _) => Ok(TypedAST::Boolean(*b)),
+        parser::AST::Char(c, _, _) => Ok(TypedAST::Char(*c)),
         parser::AST::Call(fun, arg, line, col) => {
-            let typed_fun = build_constraints(id, constraints, &mut ids, datatypes, &fun)?;
-            let typed_arg = build_constraints(id, constraints, &mut ids, datatypes, &arg)?;
+            let mut typed_fun = match &**fun {
+                parser::AST::Identifier(name, _, _) if is_receive(ids, name) => {
+                    TypedAST::Identifier(ids[name].clone(), name.clone())
+                }
+                _ => build_constraints(id, constraints, classes, ids, datatypes, fun)?,
+            };
+            let typed_arg = build_constraints(id, constraints, classes, ids, datatypes, arg)?;
 
+            let mut instantiated = None;
             match &typed_fun {
-                TypedAST::Call(fun, _) => {
-                    if let TypedAST::Function(_, _, body) = &**fun {
-                        constraints.push((type_of(&body), type_of(&typed_arg), *line, *col));
+                // The result of a call can only be called in turn when it is
+                // known to be a function.
+                TypedAST::Call(fun, ..) if matches!(type_of(&typed_fun), Type::Function(..)) => {
+                    if let TypedAST::Function(_, _, body, ..) = &**fun {
+                        constraints.push((type_of(body), type_of(&typed_arg), *line, *col));
                     }
                 }
-                TypedAST::Function(_, params, _) => {
-                    constraints.push((type_of(&params), type_of(&typed_arg), *line, *col));
+                TypedAST::Function(_, params, ..) => {
+                    constraints.push((type_of(params), type_of(&typed_arg), *line, *col));
+                }
+                TypedAST::Identifier(Type::Function(fields, _), name)
+                    if datatypes.values().any(|variants| variants.contains(name)) =>
+                {
+                    constrain_fields(&type_of(&typed_arg), fields, constraints, *line, *col);
+                }
+                // The types of builtins and native functions are declared up
+                // front, so their arguments can be checked against a copy of
+                // the parameter type, and the call has the same copy of the
+                // result type. Functions defined by the program are not, as
+                // without let-polymorphism that would prevent using them with
+                // arguments of different types.
+                TypedAST::Identifier(Type::Function(param, body), name)
+                    if !has_fresh_types(param) && !has_fresh_types(body) =>
+                {
+                    let mut fresh = HashMap::new();
+                    let typ = match ids.get(&sequence_type(name)) {
+                        Some(typ) => {
+                            let typ = instantiate(id, typ, &mut fresh);
+                            let sequence = fresh[SEQUENCE].clone();
+                            classes.push((usize::MAX, Class::Sequence, sequence, *line, *col));
+                            typ
+                        }
+                        None => instantiate(
+                            id,
+                            &Type::Function(param.clone(), body.clone()),
+                            &mut fresh,
+                        ),
+                    };
+                    if let Type::Function(param, _) = &typ {
+                        constraints.push((*param.clone(), type_of(&typed_arg), *line, *col));
+                        // A map may have been made before the type of its keys
+                        // was known, so they are checked again where it is
+                        // given to a builtin.
+                        for key in map_keys(param) {
+                            classes.push((usize::MAX, Class::Hashable, key, *line, *col));
+                        }
+                    }
+                    // send takes a pid as well as a channel, but a channel
+                    // only takes messages of the type it carries.
+                    if name == "send" {
+                        if let Type::Tuple(args) = type_of(&typed_arg) {
+                            if let [Type::Channel(element), message] = &args[..] {
+                                constraints.push((*element.clone(), message.clone(), *line, *col));
+                            }
+                        }
+                    }
+                    instantiated = Some(TypedAST::Identifier(typ, name.clone()));
                 }
                 TypedAST::Identifier(Type::Function(_, _), _) => {}
+                // A value whose type is not known yet, such as a parameter,
+                // is taken to be a function from the type of the argument to
+                // a new type, so that functions can be passed to functions.
+                TypedAST::Identifier(typ @ Type::Polymorphic(_), name) if has_fresh_types(typ) => {
+                    let fun_type =
+                        Type::Function(Box::new(type_of(&typed_arg)), Box::new(fresh_type(id)));
+                    constraints.push((typ.clone(), fun_type.clone(), *line, *col));
+                    instantiated = Some(TypedAST::Identifier(fun_type, name.clone()));
+                }
                 _ => {
-                    return Err(InterpreterError {
-                        err: "Type error: attempt to call non-lambda value.".to_string(),
-                        line: *line,
-                        col: *col,
-                    });
+                    return Err(InterpreterError::type_error(
+                        "Type error: attempt to call non-lambda value.".to_string(),
+                        *line,
+                        *col,
+                    ));
                 }
             }
+            if let Some(instantiated) = instantiated {
+                typed_fun = instantiated;
+            }
 
-            Ok(TypedAST::Call(Box::new(typed_fun), Box::new(typed_arg)))
+            Ok(TypedAST::Call(
+                Box::new(typed_fun),
+                Box::new(typed_arg),
+                *line,
+                *col,
+            ))
         }
         parser::AST::Datatype(typ, variants, _, _) => {
             let mut all_variants = HashSet::new();
@@ -302,10 +1038,9 @@ fn build_constraints(
                 match &variant.1 {
                     Some(param) => {
                         // Type for constructor function
-                        let typed_param =
-                            build_param_constraints(id, constraints, &mut ids, &param, false)?;
+                        let field_types = build_field_types(id, typ, datatypes, param)?;
                         let typ = Type::Function(
-                            Box::new(type_of(&typed_param)),
+                            Box::new(field_types),
                             Box::new(Type::Datatype(typ.to_string())),
                         );
                         ids.insert(variant.0.to_string(), typ.clone());
@@ -326,7 +1061,8 @@ fn build_constraints(
         }
         parser::AST::Define(ident, value, line, col) => {
             if let parser::AST::Identifier(ident, _, _) = &**ident {
-                let typed_value = build_constraints(id, constraints, ids, datatypes, &value)?;
+                let typed_value =
+                    build_constraints(id, constraints, classes, ids, datatypes, value)?;
                 ids.insert(ident.to_string(), type_of(&typed_value));
                 Ok(TypedAST::Define(
                     type_of(&typed_value),
@@ -334,17 +1070,43 @@ fn build_constraints(
                     Box::new(typed_value),
                 ))
             } else {
-                Err(InterpreterError {
-                    err: "Type error: expected identifier.".to_string(),
-                    line: *line,
-                    col: *col,
-                })
+                Err(InterpreterError::type_error(
+                    "Type error: expected identifier.".to_string(),
+                    *line,
+                    *col,
+                ))
             }
         }
+        parser::AST::Field(record, field, line, col) => {
+            let typed_record = build_constraints(id, constraints, classes, ids, datatypes, record)?;
+            let typ = match type_of(&typed_record) {
+                Type::Record(fields) => match fields.iter().find(|f| f.0 == *field) {
+                    Some(f) => f.1.clone(),
+                    None => return Err(missing_field(&Type::Record(fields), field, *line, *col)),
+                },
+                Type::Tuple(elements) if field.parse::<usize>().is_ok() => {
+                    match elements.get(field.parse::<usize>().unwrap()) {
+                        Some(typ) => typ.clone(),
+                        None => {
+                            return Err(missing_element(&Type::Tuple(elements), field, *line, *col))
+                        }
+                    }
+                }
+                // The type of the record is not known here, so the field is
+                // checked when the program is run.
+                Type::Polymorphic(_) => fresh_type(id),
+                typ => return Err(expected_record(&typ, *line, *col)),
+            };
+            Ok(TypedAST::Field(
+                typ,
+                Box::new(typed_record),
+                field.to_string(),
+            ))
+        }
+        parser::AST::Float(f, _, _) => Ok(TypedAST::Float(*f)),
         parser::AST::Function(ident, param, body, line, col) => {
             let mut local_ids = ids.clone();
-            let typed_param =
-                build_param_constraints(id, constraints, &mut local_ids, &param, true)?;
+            let typed_param = build_param_constraints(id, &mut local_ids, param, true)?;
             let typed_body;
             if let Some(ident) = ident {
                 let typ = fresh_type(id);
@@ -356,29 +1118,49 @@ fn build_constraints(
                     ident.to_string(),
                     Type::Function(Box::new(type_of(&typed_param)), Box::new(typ.clone())),
                 );
-                typed_body = build_constraints(id, constraints, &mut local_ids, datatypes, &body)?;
+                local_ids.insert(RETURN.to_string(), typ.clone());
+                typed_body =
+                    build_constraints(id, constraints, classes, &mut local_ids, datatypes, body)?;
+                constraints.push((typ, type_of(&typed_body), *line, *col));
+            } else if parser::propagates(body) {
+                let typ = fresh_type(id);
+                local_ids.insert(RETURN.to_string(), typ.clone());
+                typed_body =
+                    build_constraints(id, constraints, classes, &mut local_ids, datatypes, body)?;
                 constraints.push((typ, type_of(&typed_body), *line, *col));
             } else {
-                typed_body = build_constraints(id, constraints, &mut local_ids, datatypes, &body)?;
+                typed_body =
+                    build_constraints(id, constraints, classes, &mut local_ids, datatypes, body)?;
+            }
+
+            // Keys are only checked once every constraint is solved, as they
+            // are never defaulted and may be fixed by a later use of the map.
+            for class in classes.iter_mut() {
+                if class.0 == usize::MAX && !matches!(class.1, Class::Hashable) {
+                    class.0 = constraints.len();
+                }
             }
 
             Ok(TypedAST::Function(
                 ident.clone(),
                 Box::new(typed_param),
                 Box::new(typed_body),
+                *line,
+                *col,
             ))
         }
         parser::AST::Identifier(s, line, col) => match ids.get(s) {
+            Some(_) if is_receive(ids, s) => Err(InterpreterError::type_error(
+                "Type error: receive can only be called directly.".to_string(),
+                *line,
+                *col,
+            )),
             Some(typ) => Ok(TypedAST::Identifier(typ.clone(), s.clone())),
             None => {
                 let mut err = "Unknown identifier: ".to_string();
                 err.push_str(s);
                 err.push('.');
-                Err(InterpreterError {
-                    err,
-                    line: *line,
-                    col: *col,
-                })
+                Err(InterpreterError::type_error(err, *line, *col))
             }
         },
         parser::AST::If(conds, els, line, col) => {
@@ -386,8 +1168,9 @@ fn build_constraints(
             let mut inferred_type = Type::Boolean;
             let mut typed_conds = Vec::new();
             for cond in conds {
-                let ifpart = build_constraints(id, constraints, ids, datatypes, &cond.0)?;
-                let thenpart = build_constraints(id, constraints, ids, datatypes, &cond.1)?;
+                let ifpart = build_constraints(id, constraints, classes, ids, datatypes, &cond.0)?;
+                let thenpart =
+                    build_constraints(id, constraints, classes, ids, datatypes, &cond.1)?;
                 constraints.push((Type::Boolean, type_of(&ifpart), *line, *col));
                 if first {
                     first = false;
@@ -398,21 +1181,157 @@ fn build_constraints(
 
                 typed_conds.push((ifpart, thenpart));
             }
-            let elsepart = build_constraints(id, constraints, ids, datatypes, &els)?;
-            constraints.push((inferred_type, type_of(&elsepart), *line, *col));
-            Ok(TypedAST::If(typed_conds, Box::new(elsepart)))
+            // Without an else, the value is unit whichever branch is taken,
+            // so the branches must be unit as well.
+            let elsepart = match els {
+                Some(els) => {
+                    let elsepart =
+                        build_constraints(id, constraints, classes, ids, datatypes, els)?;
+                    constraints.push((inferred_type, type_of(&elsepart), *line, *col));
+                    elsepart
+                }
+                None => {
+                    constraints.push((Type::Unit, inferred_type, *line, *col));
+                    TypedAST::Unit
+                }
+            };
+            Ok(TypedAST::If(typed_conds, Box::new(elsepart), *line, *col))
         }
         parser::AST::Integer(i, _, _) => Ok(TypedAST::Integer(*i)),
+        parser::AST::Letrec(functions, line, col) => {
+            // Each function is bound before any of them are checked, so that
+            // they can refer to each other. Once a function has been checked
+            // its actual type is unified with the type it was bound to.
+            let mut bound = Vec::new();
+            for function in functions {
+                if let parser::AST::Function(Some(ident), _, _, _, _) = function {
+                    let typ = Type::Function(Box::new(fresh_type(id)), Box::new(fresh_type(id)));
+                    ids.insert(ident.to_string(), typ.clone());
+                    bound.push(typ);
+                }
+            }
+            let mut typed_functions = Vec::new();
+            for (function, typ) in functions.iter().zip(bound) {
+                let typed_function =
+                    build_constraints(id, constraints, classes, ids, datatypes, function)?;
+                constraints.push((typ, type_of(&typed_function), *line, *col));
+                typed_functions.push(typed_function);
+            }
+            Ok(TypedAST::Letrec(typed_functions))
+        }
+        parser::AST::Let(ident, value, body, line, col) => {
+            if let parser::AST::Tuple(elements, _, _) = &**ident {
+                let typed_value =
+                    build_constraints(id, constraints, classes, ids, datatypes, value)?;
+                // Each element of the tuple is bound to its own identifier,
+                // which also fixes the number of elements the value has. The
+                // element types are taken from the value when it is already
+                // known to be a tuple, as for the type of a single binding.
+                let known = match type_of(&typed_value) {
+                    Type::Tuple(types) if types.len() == elements.len() => Some(types),
+                    _ => None,
+                };
+                let mut local_ids = ids.clone();
+                let mut types = Vec::new();
+                let mut typed_elements = Vec::new();
+                for (i, element) in elements.iter().enumerate() {
+                    match element {
+                        parser::AST::Identifier(ident, _, _) => {
+                            let typ = match &known {
+                                Some(types) => types[i].clone(),
+                                None => fresh_type(id),
+                            };
+                            local_ids.insert(ident.to_string(), typ.clone());
+                            types.push(typ.clone());
+                            typed_elements.push(TypedAST::Identifier(typ, ident.clone()));
+                        }
+                        _ => {
+                            return Err(InterpreterError::type_error(
+                                "Type error: expected identifier.".to_string(),
+                                *line,
+                                *col,
+                            ))
+                        }
+                    }
+                }
+                let typ = Type::Tuple(types);
+                constraints.push((typ.clone(), type_of(&typed_value), *line, *col));
+                let typed_body =
+                    build_constraints(id, constraints, classes, &mut local_ids, datatypes, body)?;
+                Ok(TypedAST::Let(
+                    Box::new(TypedAST::Tuple(typ, typed_elements)),
+                    Box::new(typed_value),
+                    Box::new(typed_body),
+                    *line,
+                    *col,
+                ))
+            } else if let parser::AST::Identifier(ident, _, _) = &**ident {
+                let typed_value =
+                    build_constraints(id, constraints, classes, ids, datatypes, value)?;
+                // The body is checked in its own scope so that neither the
+                // binding nor any definitions in the body are visible after
+                // the let expression.
+                let mut local_ids = ids.clone();
+                local_ids.insert(ident.to_string(), type_of(&typed_value));
+                let typed_body =
+                    build_constraints(id, constraints, classes, &mut local_ids, datatypes, body)?;
+                Ok(TypedAST::Let(
+                    Box::new(TypedAST::Identifier(type_of(&typed_value), ident.clone())),
+                    Box::new(typed_value),
+                    Box::new(typed_body),
+                    *line,
+                    *col,
+                ))
+            } else {
+                Err(InterpreterError::type_error(
+                    "Type error: expected identifier.".to_string(),
+                    *line,
+                    *col,
+                ))
+            }
+        }
+        parser::AST::Try(body, ident, handler, line, col) => {
+            if let parser::AST::Identifier(ident, _, _) = &**ident {
+                let mut local_ids = ids.clone();
+                let typed_body =
+                    build_constraints(id, constraints, classes, &mut local_ids, datatypes, body)?;
+                // The handler is given the message of the error.
+                let mut local_ids = ids.clone();
+                local_ids.insert(ident.to_string(), Type::String);
+                let typed_handler = build_constraints(
+                    id,
+                    constraints,
+                    classes,
+                    &mut local_ids,
+                    datatypes,
+                    handler,
+                )?;
+                constraints.push((type_of(&typed_body), type_of(&typed_handler), *line, *col));
+                Ok(TypedAST::Try(
+                    Box::new(typed_body),
+                    Box::new(TypedAST::Identifier(Type::String, ident.clone())),
+                    Box::new(typed_handler),
+                    *line,
+                    *col,
+                ))
+            } else {
+                Err(InterpreterError::type_error(
+                    "Type error: expected identifier.".to_string(),
+                    *line,
+                    *col,
+                ))
+            }
+        }
         parser::AST::Match(cond, cases, line, col) => {
-            let typed_cond = build_constraints(id, constraints, ids, datatypes, &cond)?;
+            let typed_cond = build_constraints(id, constraints, classes, ids, datatypes, cond)?;
             match type_of(&typed_cond) {
-                Type::Datatype(_) | Type::Polymorphic(_) => {}
+                Type::Datatype(_) | Type::List(_) | Type::Polymorphic(_) => {}
                 _ => {
-                    return Err(InterpreterError {
-                        err: "Match statement: expected datatype.".to_string(),
-                        line: *line,
-                        col: *col,
-                    });
+                    return Err(InterpreterError::type_error(
+                        "Match statement: expected datatype.".to_string(),
+                        *line,
+                        *col,
+                    ));
                 }
             }
 
@@ -420,32 +1339,106 @@ fn build_constraints(
             let mut inferred_type = Type::Unit;
             let mut typed_cases = Vec::new();
             let mut present_variants = HashSet::new();
-            let mut datatype = Type::Unit;
+            let mut datatype = type_of(&typed_cond);
+            let mut wildcard = false;
+            // How many variants were covered before the last wildcard with a
+            // guard, which can not be reached if that was all of them.
+            let mut guarded_wildcard = None;
             for case in cases {
+                // Nothing after a wildcard can match.
+                if wildcard {
+                    return Err(unreachable_case(&case.0, *line, *col));
+                }
+
                 let mut local_ids = ids.clone();
                 let typed_param = match &case.1 {
-                    Some(param) => Some(build_param_constraints(
-                        id,
-                        constraints,
-                        &mut local_ids,
-                        &param,
-                        true,
-                    )?),
+                    Some(param) => Some(build_param_constraints(id, &mut local_ids, param, true)?),
+                    None => None,
+                };
+                if let (Some(param), Some(Type::Function(fields, _))) =
+                    (&typed_param, ids.get(&case.0))
+                {
+                    constrain_fields(&type_of(param), fields, constraints, *line, *col);
+                }
+
+                // A guard sees the parameters of its case and must be a
+                // boolean.
+                let typed_guard = match &case.2 {
+                    Some(guard) => {
+                        let typed_guard = build_constraints(
+                            id,
+                            constraints,
+                            classes,
+                            &mut local_ids,
+                            datatypes,
+                            guard,
+                        )?;
+                        let (line, col) = parser::start(guard);
+                        constraints.push((Type::Boolean, type_of(&typed_guard), line, col));
+                        Some(typed_guard)
+                    }
                     None => None,
                 };
+                let guarded = typed_guard.is_some();
 
-                let typed_case =
-                    build_constraints(id, constraints, &mut local_ids, datatypes, &case.2)?;
+                let typed_case = build_constraints(
+                    id,
+                    constraints,
+                    classes,
+                    &mut local_ids,
+                    datatypes,
+                    &case.3,
+                )?;
                 if first {
                     inferred_type = type_of(&typed_case);
                 } else {
                     constraints.push((inferred_type.clone(), type_of(&typed_case), *line, *col));
                 }
 
+                // Cases with guards may not match, so they do not cover their
+                // variant and the cases after them can still be reached.
+                if case.0 == "_" {
+                    wildcard = !guarded;
+                    if guarded {
+                        guarded_wildcard = Some(present_variants.len());
+                    }
+                    typed_cases.push((case.0.to_string(), typed_param, typed_guard, typed_case));
+                    first = false;
+                    continue;
+                }
+
                 let variant_type;
+                if case.0 == CONS || case.0 == NIL {
+                    if !cover(&mut present_variants, &case.0, guarded) {
+                        return Err(unreachable_case(&case.0, *line, *col));
+                    }
+                    if first {
+                        datatype = Type::List(Box::new(fresh_type(id)));
+                        if let Type::Datatype(_) = type_of(&typed_cond) {
+                            return Err(mismatch(type_of(&typed_cond), datatype, *line, *col));
+                        }
+                        constraints.push((type_of(&typed_cond), datatype.clone(), *line, *col));
+                    } else if !matches!(datatype, Type::List(_)) {
+                        return Err(mismatch(
+                            datatype,
+                            Type::List(Box::new(fresh_type(id))),
+                            *line,
+                            *col,
+                        ));
+                    }
+                    if let (Some(param), Type::List(element)) = (&typed_param, &datatype) {
+                        let fields = Type::Tuple(vec![*element.clone(), datatype.clone()]);
+                        constraints.push((fields, type_of(param), *line, *col));
+                    }
+                    typed_cases.push((case.0.to_string(), typed_param, typed_guard, typed_case));
+                    first = false;
+                    continue;
+                }
                 match ids.get(&case.0) {
                     Some(typ) => {
-                        present_variants.insert(case.0.to_string());
+                        if !cover(&mut present_variants, &case.0, guarded) {
+                            return Err(unreachable_case(&case.0, *line, *col));
+                        }
                         let typ = match typ {
                             Type::Function(_, body) => body,
                             _ => typ,
@@ -462,16 +1455,7 @@ fn build_constraints(
                                 ));
                             }
                         } else if variant_type != datatype {
-                            let mut err = "Type error: expected ".to_string();
-                            err.push_str(&datatype.to_string());
-                            err.push_str(" but found ");
-                            err.push_str(&variant_type.to_string());
-                            err.push('.');
-                            return Err(InterpreterError {
-                                err,
-                                line: *line,
-                                col: *col,
-                            });
+                            return Err(mismatch(datatype, variant_type, *line, *col));
                         }
                     }
                     None => {
@@ -479,37 +1463,51 @@ fn build_constraints(
                         err.push_str(&case.0);
                         err.push('.');
 
-                        return Err(InterpreterError {
-                            err,
-                            line: *line,
-                            col: *col,
-                        });
+                        return Err(InterpreterError::type_error(err, *line, *col));
                     }
                 }
 
-                typed_cases.push((case.0.to_string(), typed_param, typed_case));
+                typed_cases.push((case.0.to_string(), typed_param, typed_guard, typed_case));
                 first = false;
             }
 
-            if let Some(all_variants) = datatypes.get(&datatype.to_string()) {
+            let list_variants = HashSet::from([CONS.to_string(), NIL.to_string()]);
+            let all_variants = match datatype {
+                Type::List(_) => Some(&list_variants),
+                _ => datatypes.get(&datatype.to_string()),
+            };
+            if let Some(all_variants) = all_variants {
                 let mut missing: Vec<&String> =
                     all_variants.difference(&present_variants).collect();
-                if !missing.is_empty() {
+                if guarded_wildcard == Some(all_variants.len()) {
+                    return Err(unreachable_case("_", *line, *col));
+                }
+                if wildcard {
+                    if missing.is_empty() {
+                        return Err(unreachable_case("_", *line, *col));
+                    }
+                } else if !missing.is_empty() {
                     missing.sort();
                     let mut err = "Missing variants in match of ".to_string();
-                    err.push_str(&datatype.to_string());
+                    match datatype {
+                        Type::List(_) => err.push_str("list"),
+                        _ => err.push_str(&datatype.to_string()),
+                    }
                     err.push(':');
                     for variant in missing {
                         err.push(' ');
                         err.push_str(variant);
                     }
                     err.push('.');
-                    return Err(InterpreterError {
-                        err,
-                        line: *line,
-                        col: *col,
-                    });
+                    return Err(InterpreterError::type_error(err, *line, *col));
                 }
+            } else if !wildcard {
+                // Only wildcards with guards, none of which may hold.
+                return Err(InterpreterError::type_error(
+                    "Missing variants in match: _.".to_string(),
+                    *line,
+                    *col,
+                ));
             }
 
             Ok(TypedAST::Match(Box::new(typed_cond), datatype, typed_cases))
@@ -517,7 +1515,7 @@ fn build_constraints(
         parser::AST::Program(expressions, line, col) => {
             let mut typed_expressions = Vec::new();
             for expr in expressions {
-                let typed_expr = build_constraints(id, constraints, ids, datatypes, &expr)?;
+                let typed_expr = build_constraints(id, constraints, classes, ids, datatypes, expr)?;
                 typed_expressions.push(typed_expr);
             }
             match typed_expressions.last() {
@@ -526,14 +1524,88 @@ fn build_constraints(
                     constraints.push((typ, type_of(expr), *line, *col));
                     Ok(TypedAST::Program(type_of(expr), typed_expressions))
                 }
-                None => unreachable!(),
+                // A program of nothing but whitespace and comments is empty.
+                None => Ok(TypedAST::Unit),
             }
         }
-        parser::AST::UnaryOp(op, ast, line, col) => {
-            let typed = build_constraints(id, constraints, ids, datatypes, ast)?;
-            let typ = fresh_type(id);
+        parser::AST::Sequence(expressions, _, _) => {
+            // The values of all but the last expression are thrown away, so
+            // they must be unit, unless they are definitions, which are run
+            // for the names they define.
+            let mut typed_expressions = Vec::new();
+            for (i, expr) in expressions.iter().enumerate() {
+                let typed_expr = build_constraints(id, constraints, classes, ids, datatypes, expr)?;
+                let definition = matches!(
+                    expr,
+                    parser::AST::Datatype(..)
+                        | parser::AST::Define(..)
+                        | parser::AST::Function(Some(_), ..)
+                        | parser::AST::Letrec(..)
+                );
+                if i + 1 != expressions.len() && !definition {
+                    let (line, col) = parser::start(expr);
+                    constraints.push((Type::Unit, type_of(&typed_expr), line, col));
+                }
+                typed_expressions.push(typed_expr);
+            }
+            let typ = type_of(typed_expressions.last().unwrap());
+            Ok(TypedAST::Program(typ, typed_expressions))
+        }
+        parser::AST::Record(fields, line, col) => {
+            let typed_fields = build_field_constraints(
+                id,
+                constraints,
+                classes,
+                ids,
+                datatypes,
+                fields,
+                *line,
+                *col,
+            )?;
+            let mut types: Vec<(String, Type)> = typed_fields
+                .iter()
+                .map(|field| (field.0.to_string(), type_of(&field.1)))
+                .collect();
+            types.sort_by(|a, b| a.0.cmp(&b.0));
+            Ok(TypedAST::Record(Type::Record(types), typed_fields))
+        }
+        parser::AST::Propagate(value, line, col) => {
+            let typed = build_constraints(id, constraints, classes, ids, datatypes, value)?;
+            let ret = match ids.get(RETURN) {
+                Some(ret) => ret.clone(),
+                None => {
+                    return Err(InterpreterError::type_error(
+                        "Type error: ? can only be used inside a function.".to_string(),
+                        *line,
+                        *col,
+                    ))
+                }
+            };
+            // The function returns what is not unwrapped, so it returns the
+            // same datatype. That it is an Option or Result is checked once
+            // the types are known.
+            constraints.push((ret, type_of(&typed), *line, *col));
+            Ok(TypedAST::Propagate(
+                fresh_type(id),
+                Box::new(typed),
+                *line,
+                *col,
+            ))
+        }
+        parser::AST::String(s, _, _) => Ok(TypedAST::String(s.to_string())),
+        parser::AST::UnaryOp(op, ast, line, col) => {
+            let typed = build_constraints(id, constraints, classes, ids, datatypes, ast)?;
+            let typ = fresh_type(id);
             let op_typ = match op {
-                parser::Operator::Minus => Type::Integer,
+                parser::Operator::Minus => match type_of(&typed) {
+                    Type::Float => Type::Float,
+                    Type::BigInt => Type::BigInt,
+                    Type::Polymorphic(_) => {
+                        classes.push((usize::MAX, Class::Numeric, typ.clone(), *line, *col));
+                        type_of(&typed)
+                    }
+                    _ => Type::Integer,
+                },
                 parser::Operator::Not => Type::Boolean,
                 _ => unreachable!(),
             };
@@ -548,13 +1620,45 @@ fn build_constraints(
             let mut types = Vec::new();
             let mut typed_elements = Vec::new();
             for element in elements {
-                let typed_element = build_constraints(id, constraints, ids, datatypes, &element)?;
+                let typed_element =
+                    build_constraints(id, constraints, classes, ids, datatypes, element)?;
                 types.push(type_of(&typed_element));
                 typed_elements.push(typed_element);
             }
             Ok(TypedAST::Tuple(Type::Tuple(types), typed_elements))
         }
         parser::AST::Unit(_, _) => Ok(TypedAST::Unit),
+        parser::AST::Update(record, fields, line, col) => {
+            let typed_record = build_constraints(id, constraints, classes, ids, datatypes, record)?;
+            let typed_fields = build_field_constraints(
+                id,
+                constraints,
+                classes,
+                ids,
+                datatypes,
+                fields,
+                *line,
+                *col,
+            )?;
+            let typ = type_of(&typed_record);
+            match &typ {
+                Type::Record(record_fields) => {
+                    for field in &typed_fields {
+                        match record_fields.iter().find(|f| f.0 == field.0) {
+                            Some(f) => {
+                                constraints.push((f.1.clone(), type_of(&field.1), *line, *col));
+                            }
+                            None => return Err(missing_field(&typ, &field.0, *line, *col)),
+                        }
+                    }
+                }
+                Type::Polymorphic(_) => {}
+                _ => return Err(expected_record(&typ, *line, *col)),
+            }
+            Ok(TypedAST::Update(typ, Box::new(typed_record), typed_fields))
+        }
+        // These are replaced by parser::resolve_features before type inference.
+        parser::AST::When(_, _, _, _, _) => unreachable!(),
     }
 }
 
@@ -566,12 +1670,25 @@ fn substitute_in_type<S: ::std::hash::BuildHasher>(
         Type::Polymorphic(s) => {
             if let Some(subst) = bindings.get(s) {
                 *typ = subst.clone();
+                substitute_in_type(bindings, typ);
             }
         }
+        Type::Array(element) | Type::Channel(element) | Type::List(element) => {
+            substitute_in_type(bindings, element)
+        }
+        Type::Map(key, value) => {
+            substitute_in_type(bindings, key);
+            substitute_in_type(bindings, value);
+        }
         Type::Function(param, body) => {
             substitute_in_type(bindings, param);
             substitute_in_type(bindings, body);
         }
+        Type::Record(fields) => {
+            fields
+                .iter_mut()
+                .for_each(|field| substitute_in_type(bindings, &mut field.1));
+        }
         Type::Tuple(elements) => {
             elements
                 .iter_mut()
@@ -595,32 +1712,53 @@ fn substitute<S: ::std::hash::BuildHasher>(
             substitute(bindings, lhs);
             substitute(bindings, rhs);
         }
-        TypedAST::Call(fun, args) => {
+        TypedAST::Call(fun, args, ..) => {
             substitute(bindings, fun);
             substitute(bindings, args);
         }
         TypedAST::Define(_, _, value) => {
             substitute(bindings, value);
         }
-        TypedAST::Function(_, param, body) => {
+        TypedAST::Field(typ, record, _) => {
+            substitute_in_type(bindings, typ);
+            substitute(bindings, record);
+        }
+        TypedAST::Function(_, param, body, ..) => {
             substitute(bindings, param);
             substitute(bindings, body);
         }
         TypedAST::Identifier(typ, _) => {
             substitute_in_type(bindings, typ);
         }
-        TypedAST::If(conds, els) => {
+        TypedAST::If(conds, els, _, _) => {
             for cond in conds {
                 substitute(bindings, &mut cond.0);
                 substitute(bindings, &mut cond.1);
             }
             substitute(bindings, els);
         }
+        TypedAST::Let(param, value, body, _, _) => {
+            substitute(bindings, param);
+            substitute(bindings, value);
+            substitute(bindings, body);
+        }
+        TypedAST::Try(body, _, handler, _, _) => {
+            substitute(bindings, body);
+            substitute(bindings, handler);
+        }
+        TypedAST::Letrec(functions) => {
+            for function in functions {
+                substitute(bindings, function);
+            }
+        }
         TypedAST::Match(cond, datatype, cases) => {
             substitute(bindings, cond);
             substitute_in_type(bindings, datatype);
             for case in cases {
-                substitute(bindings, &mut case.2);
+                if let Some(guard) = &mut case.2 {
+                    substitute(bindings, guard);
+                }
+                substitute(bindings, &mut case.3);
             }
         }
         TypedAST::Program(typ, expressions) => {
@@ -629,12 +1767,38 @@ fn substitute<S: ::std::hash::BuildHasher>(
                 substitute(bindings, expr);
             }
         }
-        TypedAST::Tuple(typ, elements) => {
+        TypedAST::While(cond, body) => {
+            substitute(bindings, cond);
+            substitute(bindings, body);
+        }
+        TypedAST::Record(typ, fields) => {
+            substitute_in_type(bindings, typ);
+            for field in fields {
+                substitute(bindings, &mut field.1);
+            }
+        }
+        TypedAST::Array(typ, elements)
+        | TypedAST::List(typ, elements)
+        | TypedAST::Tuple(typ, elements) => {
             substitute_in_type(bindings, typ);
             for element in elements {
                 substitute(bindings, element);
             }
         }
+        TypedAST::Map(typ, entries) => {
+            substitute_in_type(bindings, typ);
+            for (key, value) in entries {
+                substitute(bindings, key);
+                substitute(bindings, value);
+            }
+        }
+        TypedAST::Update(typ, record, fields) => {
+            substitute_in_type(bindings, typ);
+            substitute(bindings, record);
+            for field in fields {
+                substitute(bindings, &mut field.1);
+            }
+        }
         TypedAST::UnaryOp(typ, _, ast) => {
             if let Type::Polymorphic(s) = typ {
                 if let Some(subst) = bindings.get(s) {
@@ -643,41 +1807,166 @@ fn substitute<S: ::std::hash::BuildHasher>(
             }
             substitute(bindings, ast);
         }
+        TypedAST::For(typ, function, iter) => {
+            substitute_in_type(bindings, typ);
+            substitute(bindings, function);
+            substitute(bindings, iter);
+        }
+        TypedAST::Defer(function) => substitute(bindings, function),
+        TypedAST::Propagate(typ, ast, _, _) => {
+            substitute_in_type(bindings, typ);
+            substitute(bindings, ast);
+        }
         _ => {}
     }
 }
 
+// Operands of arithmetic operators whose types have not been fixed by
+// unification default to integers, and sequences to strings. Keys whose types
+// are not known are left as they are.
+fn default_class(
+    class: (usize, Class, Type, usize, usize),
+    bindings: &mut HashMap<String, Type>,
+) -> Result<(), InterpreterError> {
+    let (_, class, mut typ, line, col) = class;
+    substitute_in_type(bindings, &mut typ);
+    let expected = match (class, typ) {
+        (Class::Numeric, Type::Integer | Type::Float | Type::BigInt) => return Ok(()),
+        (Class::Sequence, Type::String | Type::Array(_)) => return Ok(()),
+        (Class::Hashable, typ) if hashable(&typ) => return Ok(()),
+        (Class::Numeric, typ @ Type::Polymorphic(_)) => {
+            unify(&[Type::Integer], &[typ], bindings);
+            return Ok(());
+        }
+        (Class::Sequence, typ @ Type::Polymorphic(_)) => {
+            unify(&[Type::String], &[typ], bindings);
+            return Ok(());
+        }
+        (Class::Numeric, typ) => ("integer or float", typ),
+        (Class::Sequence, typ) => ("string or array", typ),
+        (Class::Hashable, typ) => ("a hashable type", typ),
+    };
+    let mut err = "Type error: expected ".to_string();
+    err.push_str(expected.0);
+    err.push_str(" but found ");
+    err.push_str(&expected.1.to_string());
+    err.push('.');
+    Err(InterpreterError::type_error(err, line, col))
+}
+
+// Checks that ? is only applied to an Option or a Result, or to a value whose
+// type is still unknown, such as the parameter of a function never called.
+fn check_propagations(ast: &TypedAST) -> Result<(), InterpreterError> {
+    match ast {
+        TypedAST::Propagate(_, value, line, col) => match type_of(value) {
+            Type::Datatype(name) if name == "Option" || name == "Result" => {
+                check_propagations(value)
+            }
+            Type::Polymorphic(_) => check_propagations(value),
+            typ => {
+                let mut err = "Type error: expected Option or Result but found ".to_string();
+                err.push_str(&typ.to_string());
+                err.push('.');
+                Err(InterpreterError::type_error(err, *line, *col))
+            }
+        },
+        TypedAST::BinaryOp(_, _, lhs, rhs, _, _) => {
+            check_propagations(lhs)?;
+            check_propagations(rhs)
+        }
+        TypedAST::Call(fun, arg, ..) => {
+            check_propagations(fun)?;
+            check_propagations(arg)
+        }
+        TypedAST::If(conds, els, _, _) => {
+            for (cond, then) in conds {
+                check_propagations(cond)?;
+                check_propagations(then)?;
+            }
+            check_propagations(els)
+        }
+        TypedAST::For(_, value, body)
+        | TypedAST::Let(_, value, body, _, _)
+        | TypedAST::Try(value, _, body, _, _)
+        | TypedAST::While(value, body) => {
+            check_propagations(value)?;
+            check_propagations(body)
+        }
+        TypedAST::Match(cond, _, cases) => {
+            check_propagations(cond)?;
+            cases.iter().try_for_each(|case| {
+                if let Some(guard) = &case.2 {
+                    check_propagations(guard)?;
+                }
+                check_propagations(&case.3)
+            })
+        }
+        TypedAST::Defer(ast)
+        | TypedAST::Define(_, _, ast)
+        | TypedAST::Field(_, ast, _)
+        | TypedAST::Function(_, _, ast, ..)
+        | TypedAST::UnaryOp(_, _, ast) => check_propagations(ast),
+        TypedAST::Array(_, asts)
+        | TypedAST::Letrec(asts)
+        | TypedAST::List(_, asts)
+        | TypedAST::Program(_, asts)
+        | TypedAST::Tuple(_, asts) => asts.iter().try_for_each(check_propagations),
+        TypedAST::Map(_, entries) => entries.iter().try_for_each(|(key, value)| {
+            check_propagations(key)?;
+            check_propagations(value)
+        }),
+        TypedAST::Record(_, fields) => fields
+            .iter()
+            .try_for_each(|field| check_propagations(&field.1)),
+        TypedAST::Update(_, record, fields) => {
+            check_propagations(record)?;
+            fields
+                .iter()
+                .try_for_each(|field| check_propagations(&field.1))
+        }
+        _ => Ok(()),
+    }
+}
+
 pub fn infer(
     ast: &parser::AST,
-    mut ids: &mut HashMap<String, Type>,
+    ids: &mut HashMap<String, Type>,
+    datatypes: &mut HashMap<String, HashSet<String>>,
 ) -> Result<TypedAST, InterpreterError> {
     let mut id = 1;
+    let globals = ids.clone();
     let mut constraints = Vec::new();
-    let mut datatypes: HashMap<String, HashSet<String>> = HashMap::new();
+    let mut classes = Vec::new();
 
     let mut typed_ast =
-        build_constraints(&mut id, &mut constraints, &mut ids, &mut datatypes, &ast)?;
+        build_constraints(&mut id, &mut constraints, &mut classes, ids, datatypes, ast)?;
+    classes.sort_by_key(|class| class.0);
+    let mut classes = classes.into_iter().peekable();
     let mut bindings: HashMap<String, Type> = HashMap::new();
-    for mut constraint in constraints {
+    for (i, mut constraint) in constraints.into_iter().enumerate() {
+        while let Some(class) = classes.next_if(|class| class.0 <= i) {
+            default_class(class, &mut bindings)?;
+        }
         substitute_in_type(&bindings, &mut constraint.0);
         substitute_in_type(&bindings, &mut constraint.1);
-        let typ_first = constraint.0.to_string();
-        let typ_second = constraint.1.to_string();
+        let (expected, found) = (constraint.0.clone(), constraint.1.clone());
         if !unify(&[constraint.0], &[constraint.1], &mut bindings) {
-            let mut err = "Type error: expected ".to_string();
-            err.push_str(&typ_first);
-            err.push_str(" but found ");
-            err.push_str(&typ_second);
-            err.push('.');
-
-            return Err(InterpreterError {
-                err,
-                line: constraint.2,
-                col: constraint.3,
-            });
+            return Err(mismatch(expected, found, constraint.2, constraint.3));
         }
     }
+    for class in classes {
+        default_class(class, &mut bindings)?;
+    }
     substitute(&bindings, &mut typed_ast);
+    check_propagations(&typed_ast)?;
+    // Globals defined by this program are kept with the types the constraints
+    // were solved to, so that later programs do not see the type variables
+    // they were given while it was being checked.
+    for (name, typ) in ids.iter_mut() {
+        if globals.get(name) != Some(typ) {
+            substitute_in_type(&bindings, typ);
+        }
+    }
     Ok(typed_ast)
 }
 
@@ -692,8 +1981,9 @@ mod tests {
     macro_rules! infer {
         ($input:expr, $value:expr) => {{
             let mut ids = HashMap::new();
+            let mut datatypes = HashMap::new();
             match parser::parse($input) {
-                Ok(ast) => match typeinfer::infer(&ast, &mut ids) {
+                Ok(ast) => match typeinfer::infer(&ast, &mut ids, &mut datatypes) {
                     Ok(typed_ast) => {
                         assert_eq!(type_of(&typed_ast).to_string(), $value);
                     }
@@ -711,8 +2001,9 @@ mod tests {
     macro_rules! inferfails {
         ($input:expr, $err:expr, $line:expr, $col:expr) => {{
             let mut ids = HashMap::new();
+            let mut datatypes = HashMap::new();
             match parser::parse($input) {
-                Ok(ast) => match typeinfer::infer(&ast, &mut ids) {
+                Ok(ast) => match typeinfer::infer(&ast, &mut ids, &mut datatypes) {
                     Ok(_) => {
                         assert!(false);
                     }
@@ -768,6 +2059,50 @@ mod tests {
             1
         );
         infer!("(1, false)", "(integer, boolean)");
+        infer!("1.5", "float");
+        infer!("1.5 + 2.0 * 3.0", "float");
+        infer!("-1.5", "float");
+        infer!("1.5 < 2.0", "boolean");
+        infer!("fn x -> x * 2.0 end", "float -> float");
+        infer!("fn c -> c < 'a' end", "char -> boolean");
+        infer!("fn x -> 2.0 / x end", "float -> float");
+        infer!("fn x -> x * x end", "integer -> integer");
+        infer!("fn x -> x * x + 0.5 end", "float -> float");
+        infer!("fn x -> -x end", "integer -> integer");
+        inferfails!(
+            "fn (x, y) -> (x + y, ~x) end",
+            "Type error: expected integer or float but found boolean.",
+            1,
+            17
+        );
+        inferfails!(
+            "1.5 + 1",
+            "Type error: expected float but found integer.",
+            1,
+            5
+        );
+        inferfails!(
+            "1 < 2.5",
+            "Type error: expected float but found integer.",
+            1,
+            3
+        );
+        infer!("\"hello\"", "string");
+        infer!("\"a\" ++ \"b\"", "string");
+        infer!("\"a\" == \"b\"", "boolean");
+        infer!("fn s -> s ++ \"!\" end", "string -> string");
+        inferfails!(
+            "\"a\" ++ 1",
+            "Type error: expected string but found integer.",
+            1,
+            5
+        );
+        inferfails!(
+            "\"a\" + 1",
+            "Type error: expected integer but found string.",
+            1,
+            5
+        );
         inferfails!("a + 1", "Unknown identifier: a.", 1, 1);
         inferfails!("(1, a, false)", "Unknown identifier: a.", 1, 5);
         infer!("fn x -> x + 1 end", "integer -> integer");
@@ -966,5 +2301,424 @@ mod tests {
             2,
             14
         );
+        infer!(
+            "type E := A | B | C | D end
+             match A with
+                 A -> 0
+                 | _ -> 1
+             end
+            ",
+            "integer"
+        );
+        inferfails!(
+            "type E := A | B end
+             match A with
+                 A -> 0
+                 | B -> 1
+                 | A -> 2
+             end
+            ",
+            "Unreachable case in match: A.",
+            2,
+            14
+        );
+        inferfails!(
+            "type E := A | B end
+             match A with
+                 _ -> 0
+                 | B -> 1
+             end
+            ",
+            "Unreachable case in match: B.",
+            2,
+            14
+        );
+        inferfails!(
+            "type E := A | B end
+             match A with
+                 A -> 0
+                 | B -> 1
+                 | _ -> 2
+             end
+            ",
+            "Unreachable case in match: _.",
+            2,
+            14
+        );
+        inferfails!(
+            "type E := A | B end
+             match A with
+                 A -> 0
+                 | _ -> false
+             end
+            ",
+            "Type error: expected integer but found boolean.",
+            2,
+            14
+        );
+    }
+
+    #[test]
+    fn match_guards() {
+        infer!(
+            "type E := A (integer) | B end
+             match A (1) with
+                 A (x) when x > 0 -> 1
+                 | A (x) when x < 0 -> 2
+                 | A (x) -> 3
+                 | B -> 4
+             end
+            ",
+            "integer"
+        );
+        infer!(
+            "fn (l) -> match l with x :: xs when x -> 1 | _ -> 0 end end",
+            "boolean list -> integer"
+        );
+        inferfails!(
+            "type E := A (integer) | B end
+             match A (1) with
+                 A (x) when x > 0 -> 1
+                 | B -> 4
+             end
+            ",
+            "Missing variants in match of E: A.",
+            2,
+            14
+        );
+        inferfails!(
+            "type E := A (integer) | B end
+             match A (1) with
+                 A (x) -> 1
+                 | A (x) when x > 0 -> 2
+                 | B -> 4
+             end
+            ",
+            "Unreachable case in match: A.",
+            2,
+            14
+        );
+        inferfails!(
+            "type E := A | B end
+             match A with
+                 A -> 0
+                 | B -> 1
+                 | _ when true -> 2
+             end
+            ",
+            "Unreachable case in match: _.",
+            2,
+            14
+        );
+        inferfails!(
+            "fn (x) -> match x with _ when true -> 1 end end",
+            "Missing variants in match: _.",
+            1,
+            11
+        );
+        inferfails!(
+            "type E := A (integer) | B end
+             match A (1) with
+                 A (x) when x + 1 -> 1
+                 | _ -> 0
+             end
+            ",
+            "Type error: expected boolean but found integer.",
+            3,
+            29
+        );
+    }
+
+    #[test]
+    fn typed_fields() {
+        infer!(
+            "type Shape := Circle (integer) | Rect (integer, integer) end
+             Rect (1, 2)",
+            "Shape"
+        );
+        infer!(
+            "type List := Cons (integer, List) | Nil end
+             Cons (1, Cons (2, Nil))",
+            "List"
+        );
+        inferfails!(
+            "type Shape := Circle (integer) | Rect (integer, integer) end
+             Rect (true, 2)",
+            "Type error: expected integer but found boolean.",
+            2,
+            14
+        );
+        inferfails!(
+            "type List := Cons (integer, List) | Nil end
+             Cons (1, 2)",
+            "Type error: expected List but found integer.",
+            2,
+            14
+        );
+        inferfails!(
+            "type Shape := Circle (integer) | Named (string) end
+             match Circle (1) with
+                 Circle (r) -> r
+                 | Named (name) -> name
+             end",
+            "Type error: expected integer but found string.",
+            2,
+            14
+        );
+    }
+
+    #[test]
+    fn lets() {
+        infer!("let x = 1 in x + 1", "integer");
+        infer!("let x = 1.5 in let y = x * 2.0 in (x, y)", "(float, float)");
+        infer!("fn (n) -> let m = n + 1 in m end", "integer -> integer");
+        inferfails!("let x = 1 in x def y := x", "Unknown identifier: x.", 1, 25);
+        inferfails!("let x = 1 in def y := x y", "Unknown identifier: y.", 1, 25);
+        inferfails!(
+            "let x = 1 in x && true",
+            "Type error: expected boolean but found integer.",
+            1,
+            16
+        );
+        infer!("let (a, b) = (1, true) in (b, a)", "(boolean, integer)");
+        infer!(
+            "fn (p) -> let (x, y) = p in x + y end",
+            "(integer, integer) -> integer"
+        );
+        inferfails!(
+            "let (a, b) = (1, 2, 3) in a",
+            "Type error: expected (t1, t2) but found (integer, integer, integer).",
+            1,
+            1
+        );
+        inferfails!(
+            "let (a, 1) = (1, 2) in a",
+            "Type error: expected identifier.",
+            1,
+            1
+        );
+    }
+
+    #[test]
+    fn letrec() {
+        infer!(
+            "letrec
+                 fn even (n) -> if n == 0 then true else odd (n - 1) end end
+                 fn odd (n) -> if n == 0 then false else even (n - 1) end end
+             end",
+            "integer -> boolean"
+        );
+        infer!(
+            "letrec fn f (x) -> g (x) end fn g (x) -> x + 1 end end
+             f (1)",
+            "integer"
+        );
+        inferfails!(
+            "letrec
+                 fn f (n) -> if n == 0 then true else g (n - 1) end end
+                 fn g (n) -> if n == 0 then 0 else f (n - 1) end end
+             end",
+            "Type error: expected integer but found boolean.",
+            3,
+            30
+        );
+        inferfails!(
+            "letrec fn f (x) -> g (x) end end",
+            "Unknown identifier: g.",
+            1,
+            20
+        );
+    }
+
+    #[test]
+    fn records() {
+        infer!("{ y = 1, x = true }", "{x: boolean, y: integer}");
+        infer!("{ x = 1, y = 2 }.y", "integer");
+        infer!("def p := { x = 1 } { p with x = 2 }", "{x: integer}");
+        infer!("fn (p) -> p.x end", "t1 -> t2");
+        infer!(
+            "def p := { x = 1, y = 2 } def q := { y = 3, x = 4 } p == q",
+            "boolean"
+        );
+        inferfails!("{ x = 1, x = 2 }", "Duplicate field in record: x.", 1, 1);
+        infer!("(1, \"two\", 3.0).1", "string");
+        infer!("((1, 2), true).0.1", "integer");
+        infer!("fn (t) -> t.0 end", "t1 -> t2");
+        inferfails!(
+            "(1, 2).2",
+            "Type error: (integer, integer) has no element 2.",
+            1,
+            8
+        );
+        inferfails!(
+            "true.0",
+            "Type error: expected record but found boolean.",
+            1,
+            6
+        );
+        inferfails!(
+            "{ x = 1 }.y",
+            "Type error: {x: integer} has no field y.",
+            1,
+            11
+        );
+        inferfails!(
+            "{ { x = 1 } with x = false }",
+            "Type error: expected integer but found boolean.",
+            1,
+            1
+        );
+        inferfails!(
+            "{ 1 with x = 2 }",
+            "Type error: expected record but found integer.",
+            1,
+            1
+        );
+        inferfails!(
+            "def p := { x = 1 } def q := { y = 1 } p == q",
+            "Type error: expected {x: integer} but found {y: integer}.",
+            1,
+            41
+        );
+    }
+
+    #[test]
+    fn arrays() {
+        infer!("[1, 2, 3]", "[integer]");
+        infer!("[[true], []]", "[[boolean]]");
+        infer!("fn (x) -> [x, 1.5] end", "float -> [float]");
+    }
+
+    #[test]
+    fn maps() {
+        infer!("%{\"a\" => 1, \"b\" => 2}", "%{string => integer}");
+        infer!(
+            "%{(1, 'a') => [%{}]}",
+            "%{(integer, char) => [%{t4 => t5}]}"
+        );
+        infer!(
+            "fn (k, v) -> %{k => v, 1 => true} end",
+            "(integer, boolean) -> %{integer => boolean}"
+        );
+        inferfails!(
+            "%{1 => 2, true => 3}",
+            "Type error: expected integer but found boolean.",
+            1,
+            1
+        );
+        inferfails!(
+            "%{[1.5] => 2}",
+            "Type error: expected a hashable type but found [float].",
+            1,
+            1
+        );
+        inferfails!(
+            "fn (f) -> %{f => 1, fn (x) -> x end => 2} end",
+            "Type error: expected a hashable type but found t4 -> t4.",
+            1,
+            11
+        );
+        inferfails!(
+            "[1, \"a\"]",
+            "Type error: expected integer but found string.",
+            1,
+            1
+        );
+    }
+
+    #[test]
+    fn lists() {
+        infer!("%[1, 2]", "integer list");
+        infer!("true :: %[]", "boolean list");
+        infer!("%[%[], %['a']]", "char list list");
+        infer!(
+            "fn (l) -> match l with x :: xs -> x :: x :: xs | %[] -> %[0] end end",
+            "integer list -> integer list"
+        );
+        inferfails!(
+            "%[1] :: %[2]",
+            "Type error: expected integer list list but found integer list.",
+            1,
+            6
+        );
+        inferfails!(
+            "match %[1] with %[] -> 0 | h :: t -> 1 | _ -> 2 end",
+            "Unreachable case in match: _.",
+            1,
+            1
+        );
+        inferfails!(
+            "type T := A end match A with %[] -> 0 end",
+            "Type error: expected T but found t1 list.",
+            1,
+            17
+        );
+    }
+
+    #[test]
+    fn ranges() {
+        infer!("0..10", "[integer]");
+        infer!("for x in 1..3 do x == 2 end", "[boolean]");
+        infer!("for x in [1.5] do -x end", "[float]");
+        infer!("let x = \"a\" in for x in [1] do x + 1 end", "[integer]");
+        infer!(
+            "fn (xs) -> for (a, b) in xs do a + b end end",
+            "[(integer, integer)] -> [integer]"
+        );
+        inferfails!(
+            "1..2.0",
+            "Type error: expected integer but found float.",
+            1,
+            2
+        );
+        inferfails!(
+            "fn (xs) -> for x in xs do x? end end",
+            "Type error: ? can not be used in the body of a for expression.",
+            1,
+            12
+        );
+    }
+
+    #[test]
+    fn defers() {
+        infer!("defer 1", "unit");
+        infer!("fn (x) -> defer x + 1 x end", "integer -> integer");
+        inferfails!(
+            "fn (o) -> defer o? o end",
+            "Type error: ? can not be used in a deferred expression.",
+            1,
+            11
+        );
+    }
+
+    #[test]
+    fn datatypes_persist() {
+        let mut ids = HashMap::new();
+        let mut datatypes = HashMap::new();
+        let ast = parser::parse("type E := A | B end").ok().unwrap();
+        assert!(typeinfer::infer(&ast, &mut ids, &mut datatypes).is_ok());
+        let ast = parser::parse("match B with A -> 0 end").ok().unwrap();
+        assert_eq!(
+            typeinfer::infer(&ast, &mut ids, &mut datatypes)
+                .err()
+                .map(|err| err.err),
+            Some("Missing variants in match of E: B.".to_string())
+        );
+    }
+
+    #[test]
+    fn globals_persist() {
+        let mut ids = HashMap::new();
+        let mut datatypes = HashMap::new();
+        let ast = parser::parse("def x := 1 + 2").ok().unwrap();
+        assert!(typeinfer::infer(&ast, &mut ids, &mut datatypes).is_ok());
+        assert_eq!(ids["x"], typeinfer::Type::Integer);
+        let ast = parser::parse("x && true").ok().unwrap();
+        assert_eq!(
+            typeinfer::infer(&ast, &mut ids, &mut datatypes)
+                .err()
+                .map(|err| err.err),
+            Some("Type error: expected boolean but found integer.".to_string())
+        );
     }
 }
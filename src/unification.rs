@@ -8,6 +8,9 @@ fn unify_variable<S: ::std::hash::BuildHasher>(
     bindings: &mut HashMap<String, Type, S>,
 ) -> bool {
     match x {
+        // A variable trivially unifies with itself; binding it would create a
+        // cycle in the bindings.
+        Type::Polymorphic(s) if s == var => true,
         Type::Polymorphic(s) => match bindings.get(var) {
             Some(Type::Polymorphic(t)) => {
                 if s == t {
@@ -54,12 +57,61 @@ pub fn unify<S: ::std::hash::BuildHasher>(
         match x_iter.next() {
             Some(Type::Polymorphic(s)) => match y_iter.next() {
                 Some(token) => {
-                    matched = unify_variable(s, &token, bindings);
+                    matched = unify_variable(s, token, bindings);
                 }
                 None => {
                     matched = false;
                 }
             },
+            Some(Type::Array(s_element)) => match y_iter.next() {
+                Some(Type::Polymorphic(t)) => {
+                    matched = unify_variable(t, &Type::Array(s_element.clone()), bindings);
+                }
+                Some(Type::Array(t_element)) => {
+                    matched = unify(&[*s_element.clone()], &[*t_element.clone()], bindings);
+                }
+                _ => {
+                    matched = false;
+                }
+            },
+            Some(Type::Channel(s_element)) => match y_iter.next() {
+                Some(Type::Polymorphic(t)) => {
+                    matched = unify_variable(t, &Type::Channel(s_element.clone()), bindings);
+                }
+                Some(Type::Channel(t_element)) => {
+                    matched = unify(&[*s_element.clone()], &[*t_element.clone()], bindings);
+                }
+                _ => {
+                    matched = false;
+                }
+            },
+            Some(Type::List(s_element)) => match y_iter.next() {
+                Some(Type::Polymorphic(t)) => {
+                    matched = unify_variable(t, &Type::List(s_element.clone()), bindings);
+                }
+                Some(Type::List(t_element)) => {
+                    matched = unify(&[*s_element.clone()], &[*t_element.clone()], bindings);
+                }
+                _ => {
+                    matched = false;
+                }
+            },
+            Some(Type::Map(s_key, s_value)) => match y_iter.next() {
+                Some(Type::Polymorphic(t)) => {
+                    let map = Type::Map(s_key.clone(), s_value.clone());
+                    matched = unify_variable(t, &map, bindings);
+                }
+                Some(Type::Map(t_key, t_value)) => {
+                    matched = unify(
+                        &[*s_key.clone(), *s_value.clone()],
+                        &[*t_key.clone(), *t_value.clone()],
+                        bindings,
+                    );
+                }
+                _ => {
+                    matched = false;
+                }
+            },
             Some(Type::Tuple(s_elements)) => match y_iter.next() {
                 Some(Type::Polymorphic(t)) => {
                     matched = unify_variable(t, &Type::Tuple(s_elements.to_vec()), bindings);
@@ -71,6 +123,39 @@ pub fn unify<S: ::std::hash::BuildHasher>(
                     matched = false;
                 }
             },
+            Some(Type::Function(s_param, s_body)) => match y_iter.next() {
+                Some(Type::Polymorphic(t)) => {
+                    let s = Type::Function(s_param.clone(), s_body.clone());
+                    matched = unify_variable(t, &s, bindings);
+                }
+                Some(Type::Function(t_param, t_body)) => {
+                    matched = unify(
+                        &[*s_param.clone(), *s_body.clone()],
+                        &[*t_param.clone(), *t_body.clone()],
+                        bindings,
+                    );
+                }
+                _ => {
+                    matched = false;
+                }
+            },
+            Some(Type::Record(s_fields)) => match y_iter.next() {
+                Some(Type::Polymorphic(t)) => {
+                    matched = unify_variable(t, &Type::Record(s_fields.to_vec()), bindings);
+                }
+                Some(Type::Record(t_fields)) => {
+                    matched = s_fields.len() == t_fields.len()
+                        && s_fields.iter().zip(t_fields).all(|(s, t)| s.0 == t.0);
+                    if matched {
+                        let s_types: Vec<Type> = s_fields.iter().map(|f| f.1.clone()).collect();
+                        let t_types: Vec<Type> = t_fields.iter().map(|f| f.1.clone()).collect();
+                        matched = unify(&s_types, &t_types, bindings);
+                    }
+                }
+                _ => {
+                    matched = false;
+                }
+            },
             Some(s) => match y_iter.next() {
                 Some(Type::Polymorphic(t)) => {
                     matched = unify_variable(t, s, bindings);
@@ -127,5 +212,28 @@ mod tests {
 
         let mut bindings: HashMap<String, Type> = HashMap::new();
         assert!(!unify(&x, &y, &mut bindings));
+
+        let y = vec![Type::Polymorphic("'a".to_string())];
+
+        let mut bindings: HashMap<String, Type> = HashMap::new();
+        assert!(unify(&y, &y, &mut bindings));
+        assert_eq!(bindings.len(), 0);
+
+        let f = vec![Type::Function(
+            Box::new(Type::Polymorphic("'a".to_string())),
+            Box::new(Type::Polymorphic("'b".to_string())),
+        )];
+        let g = vec![Type::Function(
+            Box::new(Type::Integer),
+            Box::new(Type::Boolean),
+        )];
+
+        let mut bindings: HashMap<String, Type> = HashMap::new();
+        assert!(unify(&f, &g, &mut bindings));
+        assert_eq!(bindings.get("'a"), Some(&Type::Integer));
+        assert_eq!(bindings.get("'b"), Some(&Type::Boolean));
+
+        let mut bindings: HashMap<String, Type> = HashMap::new();
+        assert!(!unify(&g, &x[..1], &mut bindings));
     }
 }
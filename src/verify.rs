@@ -0,0 +1,591 @@
+use crate::codegen::{ErrorKind, InterpreterError, RuntimeError};
+use crate::symbol::{Names, Symbol};
+use crate::vm::{Environment, Opcode, Value};
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+
+// The ways in which a program can be found to be invalid before it is run.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Problem {
+    // A jump, function or letrec group refers to an address outside of the
+    // program.
+    OutOfBounds(i64),
+    // An instruction takes more values than are on the stack.
+    StackUnderflow { needed: usize, depth: usize },
+    // An argument or upvalue slot is past the end of the current frame.
+    InvalidSlot { slot: usize, depth: usize },
+    // Two paths reach an instruction with a different number of values on
+    // the stack.
+    InconsistentDepth { expected: usize, found: usize },
+    UndefinedIdentifier(String),
+    // Arguments can only be taken and functions only return inside of a
+    // function, which is never the case for the program itself.
+    OutsideFunction,
+    // A function reaches the end of the program without returning.
+    MissingReturn,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::OutOfBounds(ip) => write!(f, "address {} is out of bounds", ip),
+            Problem::StackUnderflow { needed, depth } => {
+                write!(f, "takes {} values from a stack of {}", needed, depth)
+            }
+            Problem::InvalidSlot { slot, depth } => {
+                write!(f, "slot {} is not in a frame of {}", slot, depth)
+            }
+            Problem::InconsistentDepth { expected, found } => write!(
+                f,
+                "reached with {} values on the stack instead of {}",
+                found, expected
+            ),
+            Problem::UndefinedIdentifier(id) => write!(f, "{} is never defined", id),
+            Problem::OutsideFunction => write!(f, "only valid inside of a function"),
+            Problem::MissingReturn => write!(f, "function never returns"),
+        }
+    }
+}
+
+// The first problem found by the verifier, which is the source of the error
+// it returns.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VerifyError {
+    pub ip: usize,
+    pub problem: Problem,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid bytecode at {}: {}.", self.ip, self.problem)
+    }
+}
+
+impl Error for VerifyError {}
+
+// What is known about the stack before an instruction runs. The depth counts
+// the values in the current frame, starting with the argument. Functions that
+// unpack a tuple argument have as many slots as the tuple has elements, which
+// is not known until they run, so their frames are not checked.
+#[derive(Clone, Copy, PartialEq)]
+struct State {
+    depth: usize,
+    function: bool,
+    unpacked: bool,
+}
+
+// The number of values an instruction takes from the stack and the number it
+// leaves in their place.
+fn stack_effect(opcode: &Opcode) -> (usize, usize) {
+    match opcode {
+        Opcode::Add
+        | Opcode::And
+        | Opcode::Call
+        | Opcode::Concat
+        | Opcode::Cons
+        | Opcode::Div
+        | Opcode::Equal
+        | Opcode::Greater
+        | Opcode::GreaterEqual
+        | Opcode::Less
+        | Opcode::LessEqual
+        | Opcode::Mod
+        | Opcode::Mul
+        | Opcode::Pow
+        | Opcode::NotEqual
+        | Opcode::Or
+        | Opcode::Range
+        | Opcode::Sub
+        | Opcode::TailCall => (2, 1),
+        Opcode::Arg(_)
+        | Opcode::Bconst(_)
+        | Opcode::Cconst(_)
+        | Opcode::Fconst(..)
+        | Opcode::GetEnv(_)
+        | Opcode::Iconst(_)
+        | Opcode::Nconst(_)
+        | Opcode::Rconst(_)
+        | Opcode::Sconst(_)
+        | Opcode::Uconst
+        | Opcode::Xconst(_) => (0, 1),
+        Opcode::Dup => (1, 2),
+        // Exit and abort never return, but leave the stack as a builtin would.
+        Opcode::Abort
+        | Opcode::Abs
+        | Opcode::AddTime
+        | Opcode::ArrayAt
+        | Opcode::ArrayFilter
+        | Opcode::ArrayFoldl
+        | Opcode::ArrayFoldr
+        | Opcode::ArrayMap
+        | Opcode::ArrayPush
+        | Opcode::Base64Decode
+        | Opcode::Base64Encode
+        | Opcode::ByteAt
+        | Opcode::BytesLength
+        | Opcode::BytesSlice
+        | Opcode::BytesToString
+        | Opcode::CharAt
+        | Opcode::CharCode
+        | Opcode::CharFromCode
+        | Opcode::CsvParse
+        | Opcode::CsvString
+        | Opcode::Dconst(..)
+        | Opcode::Diff
+        | Opcode::Exit
+        | Opcode::Expect(_)
+        | Opcode::ExtVal
+        | Opcode::Field(_)
+        | Opcode::FloatToString
+        | Opcode::FoldChars
+        | Opcode::FormatInt
+        | Opcode::FormatTime
+        | Opcode::Fst
+        | Opcode::Graphemes
+        | Opcode::HashValue
+        | Opcode::HexDecode
+        | Opcode::HexEncode
+        | Opcode::HttpGet
+        | Opcode::HttpPost
+        | Opcode::Join
+        | Opcode::LeadingZeros
+        | Opcode::Length
+        | Opcode::ListHead
+        | Opcode::ListTail
+        | Opcode::MapHasKey
+        | Opcode::MapInsert
+        | Opcode::MapKeys
+        | Opcode::MapLookup
+        | Opcode::MapRemove
+        | Opcode::Max
+        | Opcode::Memo
+        | Opcode::Min
+        | Opcode::Neg
+        | Opcode::Nfc
+        | Opcode::Nfd
+        | Opcode::Not
+        | Opcode::Now
+        | Opcode::On
+        | Opcode::ParMap
+        | Opcode::ParseFloat
+        | Opcode::ParseTime
+        | Opcode::Popcount
+        | Opcode::Print
+        | Opcode::Propagate
+        | Opcode::Rec(_)
+        | Opcode::RunCommand
+        | Opcode::Receive
+        | Opcode::Recv
+        | Opcode::RegexCaptures
+        | Opcode::RegexMatch
+        | Opcode::RegexReplace
+        | Opcode::RotateLeft
+        | Opcode::RotateRight
+        | Opcode::SaturatingAdd
+        | Opcode::SaturatingMul
+        | Opcode::SaturatingSub
+        | Opcode::SelfPid
+        | Opcode::Send
+        | Opcode::Show
+        | Opcode::Snd
+        | Opcode::SortBy
+        | Opcode::Spawn
+        | Opcode::StringByteLength
+        | Opcode::StringByteSlice
+        | Opcode::StringChars
+        | Opcode::StringContains
+        | Opcode::StringSlice
+        | Opcode::StringSplit
+        | Opcode::StringToBytes
+        | Opcode::TimeDiff
+        | Opcode::ToLower
+        | Opcode::ToUpper
+        | Opcode::TypeEq(_)
+        | Opcode::WrappingAdd
+        | Opcode::WrappingMul
+        | Opcode::WrappingSub
+        | Opcode::Yield => (1, 1),
+        Opcode::Defer | Opcode::Jnz(_) | Opcode::Jz(_) | Opcode::Pop | Opcode::SetEnv(_) => (1, 0),
+        Opcode::EndTry | Opcode::Jmp(_) | Opcode::Scope | Opcode::Srcpos(..) | Opcode::Try(_) => {
+            (0, 0)
+        }
+        Opcode::Aconst(n) | Opcode::Lconst(n) | Opcode::Tconst(n) => (*n, 1),
+        Opcode::Mconst(n) => (2 * n, 1),
+        Opcode::Record(fields) => (fields.len(), 1),
+        Opcode::Update(fields) => (fields.len() + 1, 1),
+        // The elements are not known, so they are not counted.
+        Opcode::Untuple => (1, 0),
+        Opcode::Ret => (1, 0),
+    }
+}
+
+fn fail(ip: usize, problem: Problem) -> Result<(), VerifyError> {
+    Err(VerifyError { ip, problem })
+}
+
+// Checks a program before it is run: that every jump and function stays
+// inside of it, that each instruction finds the values it takes on the stack,
+// that arguments are taken from slots in the current frame, and that every
+// identifier looked up in the environment is defined somewhere. Identifiers
+// are checked against every definition in the program, as the environment
+// they are looked up in is only known once it runs, and are named by the
+// names the program's symbols were interned in.
+pub fn verify(
+    instructions: &[Opcode],
+    entry: usize,
+    env: &Environment,
+    names: &Names,
+) -> Result<(), InterpreterError> {
+    check(instructions, entry, env, names).map_err(|err| InterpreterError {
+        kind: ErrorKind::Runtime {
+            kind: RuntimeError::InvalidBytecode,
+            backtrace: Vec::new(),
+        },
+        err: err.to_string(),
+        line: usize::MAX,
+        col: usize::MAX,
+        source: Some(Box::new(err)),
+    })
+}
+
+// Records the state an instruction is reached with, which must be the same
+// along every path to it, and queues it to be checked the first time.
+fn reach(
+    states: &mut [Option<State>],
+    pending: &mut Vec<usize>,
+    ip: usize,
+    state: State,
+) -> Result<(), VerifyError> {
+    match states[ip] {
+        Some(existing) if existing != state => fail(
+            ip,
+            Problem::InconsistentDepth {
+                expected: existing.depth,
+                found: state.depth,
+            },
+        ),
+        Some(_) => Ok(()),
+        None => {
+            states[ip] = Some(state);
+            pending.push(ip);
+            Ok(())
+        }
+    }
+}
+
+fn check(
+    instructions: &[Opcode],
+    entry: usize,
+    env: &Environment,
+    names: &Names,
+) -> Result<(), VerifyError> {
+    let len = instructions.len();
+    let mut defined: HashSet<Symbol> = env.values.keys().copied().collect();
+    let mut functions = Vec::new();
+    for value in env.values.values() {
+        match value {
+            Value::Function(ip, _) => functions.push((*ip, entry)),
+            Value::Memo(_, fun) => {
+                if let Value::Function(ip, _) = &**fun {
+                    functions.push((*ip, entry));
+                }
+            }
+            _ => {}
+        }
+    }
+    for (ip, opcode) in instructions.iter().enumerate() {
+        match opcode {
+            Opcode::Fconst(id, fun, upvalues) => {
+                if let Some(id) = id {
+                    defined.insert(*id);
+                }
+                defined.extend(upvalues.keys().copied());
+                functions.push((*fun, ip));
+            }
+            Opcode::Rec(group) => {
+                for (id, fun) in group {
+                    defined.insert(*id);
+                    functions.push((*fun, ip));
+                }
+            }
+            Opcode::SetEnv(id) => {
+                defined.insert(*id);
+            }
+            _ => {}
+        }
+    }
+
+    let mut states: Vec<Option<State>> = vec![None; len];
+    let mut pending = Vec::new();
+    if entry < len {
+        let state = State {
+            depth: 0,
+            function: false,
+            unpacked: false,
+        };
+        states[entry] = Some(state);
+        pending.push(entry);
+    }
+    for (fun, ip) in functions {
+        if fun >= len {
+            return fail(ip, Problem::OutOfBounds(fun as i64));
+        }
+        let state = State {
+            depth: 1,
+            function: true,
+            unpacked: false,
+        };
+        reach(&mut states, &mut pending, fun, state)?;
+    }
+
+    while let Some(ip) = pending.pop() {
+        let mut state = match states[ip] {
+            Some(state) => state,
+            None => continue,
+        };
+        let opcode = &instructions[ip];
+        match opcode {
+            Opcode::Arg(slot) => {
+                if !state.function {
+                    return fail(ip, Problem::OutsideFunction);
+                }
+                if !state.unpacked && *slot >= state.depth {
+                    return fail(
+                        ip,
+                        Problem::InvalidSlot {
+                            slot: *slot,
+                            depth: state.depth,
+                        },
+                    );
+                }
+            }
+            Opcode::Fconst(_, _, upvalues) if state.function && !state.unpacked => {
+                for (slot, _) in upvalues.values() {
+                    if *slot >= state.depth {
+                        return fail(
+                            ip,
+                            Problem::InvalidSlot {
+                                slot: *slot,
+                                depth: state.depth,
+                            },
+                        );
+                    }
+                }
+            }
+            Opcode::GetEnv(id) if !defined.contains(id) => {
+                return fail(
+                    ip,
+                    Problem::UndefinedIdentifier(names.name(*id).to_string()),
+                );
+            }
+            Opcode::Propagate | Opcode::Ret if !state.function => {
+                return fail(ip, Problem::OutsideFunction);
+            }
+            _ => {}
+        }
+
+        let (needed, pushed) = stack_effect(opcode);
+        if needed > state.depth {
+            if !state.unpacked {
+                return fail(
+                    ip,
+                    Problem::StackUnderflow {
+                        needed,
+                        depth: state.depth,
+                    },
+                );
+            }
+            state.depth = needed;
+        }
+        state.depth = state.depth - needed + pushed;
+        if let Opcode::Untuple = opcode {
+            state.unpacked = true;
+        }
+
+        let mut next = Vec::new();
+        match opcode {
+            Opcode::Jmp(offset) => next.push(ip as i64 + offset),
+            Opcode::Jnz(offset) | Opcode::Jz(offset) => {
+                next.push(ip as i64 + 1);
+                next.push(ip as i64 + offset);
+            }
+            Opcode::Ret => {}
+            _ => next.push(ip as i64 + 1),
+        }
+        // The handler of a try is given the error message on top of the
+        // stack as it was when the try began.
+        if let Opcode::Try(offset) = opcode {
+            let target = ip as i64 + offset;
+            if target < 0 || target as usize >= len {
+                return fail(ip, Problem::OutOfBounds(target));
+            }
+            let handler = State {
+                depth: state.depth + 1,
+                ..state
+            };
+            reach(&mut states, &mut pending, target as usize, handler)?;
+        }
+        for target in next {
+            if target < 0 || target as usize > len {
+                return fail(ip, Problem::OutOfBounds(target));
+            }
+            let target = target as usize;
+            if target == len {
+                // Running past the last instruction ends the program, which
+                // only the program itself can do.
+                if state.function {
+                    return fail(ip, Problem::MissingReturn);
+                }
+                continue;
+            }
+            reach(&mut states, &mut pending, target, state)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::codegen;
+    use crate::parser;
+    use crate::symbol::Names;
+    use crate::verify::{self, Problem, VerifyError};
+    use crate::vm::{self, Opcode};
+    use std::collections::HashMap;
+
+    macro_rules! verifies {
+        ($input:expr) => {{
+            let mut vm = vm::VirtualMachine::new();
+            let ast = parser::parse($input).ok().unwrap();
+            codegen::compile(&mut vm, &ast).ok().unwrap();
+            assert!(verify::verify(&vm.instructions, vm.ip, &vm.env, &vm.names).is_ok());
+        }};
+    }
+
+    macro_rules! rejects {
+        ($instructions:expr, $ip:expr, $problem:expr) => {{
+            rejects!(Names::new(), $instructions, $ip, $problem)
+        }};
+        ($names:expr, $instructions:expr, $ip:expr, $problem:expr) => {{
+            let env = vm::Environment::new();
+            let err = verify::verify(&$instructions, 0, &env, &$names)
+                .err()
+                .unwrap();
+            let source = err.source.unwrap();
+            let err = source.downcast_ref::<VerifyError>().unwrap();
+            assert_eq!(err.ip, $ip);
+            assert_eq!(err.problem, $problem);
+        }};
+    }
+
+    #[test]
+    fn verify() {
+        verifies!("1 + 2 * 3");
+        verifies!("if 1 < 2 then \"a\" elsif 2 < 3 then \"b\" else \"c\" end");
+        verifies!(
+            "def adder := fn (t) -> fn (x) -> let y = x + t in y end end
+             def f := adder (1)
+             f (2)"
+        );
+        verifies!(
+            "type List := Cons (integer, List) | Nil end
+             fn len (xs) -> match xs with Nil -> 0 | Cons (x, xs) -> 1 + len (xs) end end
+             len (Cons (1, Cons (2, Nil)))"
+        );
+        verifies!(
+            "letrec
+                 fn even (n) -> if n == 0 then true else odd (n - 1) end end
+                 fn odd (n) -> if n == 0 then false else even (n - 1) end end
+             end
+             even (10)"
+        );
+        verifies!(
+            "def g := fn (x, y) -> fn (z) -> x + y + z end end
+             def r := (g (1, 2)) (3)
+             r"
+        );
+        verifies!("{ { x = 1, y = 2 } with y = 3 }.y");
+        verifies!("fn f (x) -> try 1 / x catch e -> 0 end end f (0)");
+    }
+
+    #[test]
+    fn rejects() {
+        rejects!(
+            [Opcode::Iconst(1), Opcode::Jmp(5)],
+            1,
+            Problem::OutOfBounds(6)
+        );
+        rejects!(
+            [Opcode::Bconst(true), Opcode::Jz(-100)],
+            1,
+            Problem::OutOfBounds(-99)
+        );
+        rejects!(
+            [Opcode::Fconst(None, 1000, HashMap::new())],
+            0,
+            Problem::OutOfBounds(1000)
+        );
+        rejects!(
+            [Opcode::Iconst(1), Opcode::Add],
+            1,
+            Problem::StackUnderflow {
+                needed: 2,
+                depth: 1
+            }
+        );
+        rejects!(
+            [
+                Opcode::Bconst(true),
+                Opcode::Jz(3),
+                Opcode::Iconst(1),
+                Opcode::Iconst(2),
+                Opcode::Uconst
+            ],
+            4,
+            Problem::InconsistentDepth {
+                expected: 0,
+                found: 2
+            }
+        );
+        let mut names = Names::new();
+        let missing = names.intern("missing");
+        rejects!(
+            names,
+            [Opcode::GetEnv(missing)],
+            0,
+            Problem::UndefinedIdentifier("missing".to_string())
+        );
+        rejects!([Opcode::Arg(0)], 0, Problem::OutsideFunction);
+        rejects!([Opcode::Uconst, Opcode::Ret], 1, Problem::OutsideFunction);
+        rejects!(
+            [
+                Opcode::Fconst(None, 2, HashMap::new()),
+                Opcode::Jmp(4),
+                Opcode::Iconst(1),
+                Opcode::Arg(2),
+                Opcode::Ret
+            ],
+            3,
+            Problem::InvalidSlot { slot: 2, depth: 2 }
+        );
+        rejects!(
+            [
+                Opcode::Fconst(None, 2, HashMap::new()),
+                Opcode::Jmp(2),
+                Opcode::Arg(0)
+            ],
+            2,
+            Problem::MissingReturn
+        );
+        rejects!([Opcode::Try(2), Opcode::EndTry], 0, Problem::OutOfBounds(2));
+        rejects!(
+            [Opcode::Try(3), Opcode::EndTry, Opcode::Jmp(1), Opcode::Pop],
+            3,
+            Problem::InconsistentDepth {
+                expected: 1,
+                found: 0
+            }
+        );
+    }
+}
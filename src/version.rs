@@ -0,0 +1,52 @@
+use std::fmt;
+
+// Bump this whenever the encoding of compiled programs changes so that
+// bytecode produced by an incompatible version is rejected when loaded.
+pub const BYTECODE_VERSION: u32 = 35;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Version {
+    pub version: &'static str,
+    pub git_hash: &'static str,
+    pub features: Vec<&'static str>,
+    pub bytecode: u32,
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.version, self.git_hash)
+    }
+}
+
+pub fn version() -> Version {
+    Version {
+        version: env!("CARGO_PKG_VERSION"),
+        git_hash: env!("PLOVER_GIT_HASH"),
+        features: [
+            ("csv", cfg!(feature = "csv")),
+            ("graphemes", cfg!(feature = "graphemes")),
+            ("kernel", cfg!(feature = "kernel")),
+            ("normalization", cfg!(feature = "normalization")),
+            ("regex", cfg!(feature = "regex")),
+            ("panic-free", cfg!(feature = "panic-free")),
+        ]
+        .iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(feature, _)| *feature)
+        .collect(),
+        bytecode: BYTECODE_VERSION,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::version;
+
+    #[test]
+    fn versions() {
+        let v = version::version();
+        assert_eq!(v.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(v.bytecode, version::BYTECODE_VERSION);
+        assert!(v.to_string().starts_with(env!("CARGO_PKG_VERSION")));
+    }
+}
@@ -1,147 +1,1048 @@
+use crate::bigint::BigInt;
 use crate::codegen;
+#[cfg(feature = "csv")]
+use crate::csv;
+use crate::effects;
+use crate::encoding;
+#[cfg(feature = "graphemes")]
+use crate::graphemes;
+use crate::http;
+use crate::panics;
+use crate::parser;
+#[cfg(feature = "regex")]
+use crate::regex;
+use crate::symbol::{Names, Symbol};
+use crate::time;
 use crate::typeinfer;
 use std::borrow::Borrow;
 use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::convert::TryFrom;
 use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Write};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+#[cfg(feature = "normalization")]
+use unicode_normalization::UnicodeNormalization;
 
 macro_rules! err {
-    ($vm:expr, $msg:expr) => {{
-        return Err(codegen::InterpreterError {
-            err: $msg.to_string(),
-            line: $vm.line,
-            col: $vm.col,
-        });
+    ($vm:expr, $kind:ident, $msg:expr) => {{
+        return Err($vm.error(codegen::RuntimeError::$kind, $msg.to_string()));
+    }};
+}
+
+// Reports an instruction that found values on the stack which the compiler
+// never leaves there. This is a bug in the compiler unless the program was
+// loaded from bytecode, so it only becomes an error in checked mode.
+macro_rules! invalid {
+    ($vm:expr) => {{
+        if !$vm.checked {
+            unreachable!()
+        }
+        return Err($vm.invalid());
     }};
 }
 
-pub enum Opcode {
-    Add,
-    And,
-    Arg(usize),
-    Bconst(bool),
-    Call,
-    Div,
-    Dup,
-    Equal,
-    ExtVal,
-    Dconst(String, String, usize),
+pub struct OpcodeInfo {
+    pub name: &'static str,
+    pub operands: &'static str,
+    pub stack: &'static str,
+    pub description: &'static str,
+}
+
+// The opcodes are declared through this macro so that the description of
+// each one lives next to its definition. Stack effects are written as
+// "before -- after" with the top of the stack rightmost.
+macro_rules! opcodes {
+    ($($(#[doc = $doc:expr])+ $name:ident $(($($field:ty),+))? => $operands:expr, $stack:expr;)+) => {
+        #[derive(Clone)]
+        pub enum Opcode {
+            $($(#[doc = $doc])+ $name $(($($field),+))?,)+
+        }
+
+        pub const OPCODES: &[OpcodeInfo] = &[
+            $(OpcodeInfo {
+                name: stringify!($name),
+                operands: $operands,
+                stack: $stack,
+                description: concat!($($doc),+),
+            },)+
+        ];
+
+        impl Opcode {
+            pub fn name(&self) -> &'static str {
+                match self {
+                    $(Opcode::$name { .. } => stringify!($name),)+
+                }
+            }
+        }
+    };
+}
+
+opcodes! {
+    /// Absolute value of an integer.
+    Abs => "", "x -- |x|";
+    /// Adds two integers or two floats.
+    Add => "", "y x -- x+y";
+    /// Logical and of two booleans.
+    And => "", "y x -- x&&y";
+    /// Pushes the argument in the given slot of the current frame.
+    Arg(usize) => "slot", "-- value";
+    /// Pushes a boolean constant.
+    Bconst(bool) => "value", "-- value";
+    /// Pushes a character constant.
+    Cconst(char) => "value", "-- value";
+    /// Calls a function, pushing a new frame whose base is the argument.
+    Call => "", "arg fn -- result";
+    /// Concatenates two strings.
+    Concat => "", "y x -- x++y";
+    /// Divides two integers or two floats. Integer division by zero is an error.
+    Div => "", "y x -- x/y";
+    /// Duplicates the top of the stack.
+    Dup => "", "x -- x x";
+    /// Compares two values for equality.
+    Equal => "", "y x -- x==y";
+    /// Checks that a message taken from a mailbox has the type the program
+    /// expects it to have.
+    Expect(typeinfer::Type) => "type", "value -- value";
+    /// Extracts the value a datatype variant was constructed with.
+    ExtVal => "", "datatype -- value";
+    /// Pushes the named field of a record, or the element of a tuple at a
+    /// numeric position.
+    Field(String) => "field", "record -- value";
+    /// Takes the first element of a pair.
+    Fst => "", "(x, y) -- x";
+    /// Wraps a value in the named variant of a datatype.
+    Dconst(String, String) => "type variant", "value -- datatype";
+    /// Creates a closure over the current environment and the given upvalues.
     Fconst(
-        Option<String>,
+        Option<Symbol>,
         usize,
-        HashMap<String, (usize, typeinfer::Type)>,
-    ),
-    GetEnv(String),
-    Greater,
-    GreaterEqual,
-    Iconst(i64),
-    Jmp(i64),
-    Jz(i64),
-    Less,
-    LessEqual,
-    Mod,
-    Mul,
-    Not,
-    NotEqual,
-    Or,
-    Pop,
-    Ret(usize),
-    Rot,
-    SetEnv(String),
-    Srcpos(usize, usize),
-    Sub,
-    TypeEq(String),
-    Uconst,
+        HashMap<Symbol, (usize, typeinfer::Type)>
+    ) => "name ip upvalues", "-- fn";
+    /// Pushes the value bound to an identifier in the current environment.
+    GetEnv(Symbol) => "id", "-- value";
+    /// Greater than comparison of two integers or two floats.
+    Greater => "", "y x -- x>y";
+    /// Greater than or equal comparison of two integers or two floats.
+    GreaterEqual => "", "y x -- x>=y";
+    /// Pushes an integer constant.
+    Iconst(i64) => "value", "-- value";
+    /// Jumps by a relative offset.
+    Jmp(i64) => "offset", "--";
+    /// Jumps by a relative offset if the boolean on top of the stack is false.
+    Jz(i64) => "offset", "cond --";
+    /// Jumps by a relative offset if the boolean on top of the stack is true.
+    Jnz(i64) => "offset", "cond --";
+    /// Less than comparison of two integers or two floats.
+    Less => "", "y x -- x<y";
+    /// Less than or equal comparison of two integers or two floats.
+    LessEqual => "", "y x -- x<=y";
+    /// Larger of two integers.
+    Max => "", "(x, y) -- max(x, y)";
+    /// Wraps a function so that its results are cached by argument.
+    Memo => "", "fn -- fn";
+    /// Smaller of two integers.
+    Min => "", "(x, y) -- min(x, y)";
+    /// Remainder of two integers or two floats. Integer modulus by zero is an error.
+    Mod => "", "y x -- x%y";
+    /// Multiplies two integers or two floats.
+    Mul => "", "y x -- x*y";
+    /// Negates an integer or a float.
+    Neg => "", "x -- -x";
+    /// Raises an integer or a float to a power. Integer powers must not be negative.
+    Pow => "", "y x -- x**y";
+    /// Pushes a bigint constant.
+    Nconst(BigInt) => "value", "-- value";
+    /// Applies a pure function to each element of a tuple in parallel.
+    ParMap => "", "(fn, (x1, ..., xn)) -- (fn(x1), ..., fn(xn))";
+    /// Logical not of a boolean.
+    Not => "", "x -- ~x";
+    /// Compares two values for inequality.
+    NotEqual => "", "y x -- x~=y";
+    /// Registers a function to be called when the host emits the named event.
+    On => "", "(name, fn) -- ()";
+    /// Logical or of two booleans.
+    Or => "", "y x -- x||y";
+    /// Adds two integers, wrapping around on overflow.
+    WrappingAdd => "", "(x, y) -- x+y";
+    /// Subtracts two integers, wrapping around on overflow.
+    WrappingSub => "", "(x, y) -- x-y";
+    /// Multiplies two integers, wrapping around on overflow.
+    WrappingMul => "", "(x, y) -- x*y";
+    /// Adds two integers, giving the nearest bound on overflow.
+    SaturatingAdd => "", "(x, y) -- x+y";
+    /// Subtracts two integers, giving the nearest bound on overflow.
+    SaturatingSub => "", "(x, y) -- x-y";
+    /// Multiplies two integers, giving the nearest bound on overflow.
+    SaturatingMul => "", "(x, y) -- x*y";
+    /// Discards the top of the stack.
+    Pop => "", "x --";
+    /// Writes a value to standard output. Requires the io effect.
+    Print => "", "x -- ()";
+    /// Formats a float the same way values are printed.
+    FloatToString => "", "x -- string";
+    /// Parses a float written the way values are printed. Other strings are
+    /// an error.
+    ParseFloat => "", "string -- x";
+    /// Formats an integer in a radix from 2 to 36, with a separator between
+    /// groups of digits. Other radixes are an error.
+    FormatInt => "", "(n, radix, separator) -- string";
+    /// Counts the bits of an integer that are set.
+    Popcount => "", "x -- n";
+    /// Counts the zero bits of an integer before the most significant set bit.
+    LeadingZeros => "", "x -- n";
+    /// Rotates the bits of an integer to the left, wrapping around.
+    RotateLeft => "", "(x, n) -- y";
+    /// Rotates the bits of an integer to the right, wrapping around.
+    RotateRight => "", "(x, n) -- y";
+    /// Hashes a value to an integer that is the same on every platform.
+    /// Values containing functions or channels are an error.
+    HashValue => "", "x -- n";
+    /// Counts the bytes in a bytes value.
+    BytesLength => "", "bytes -- n";
+    /// Takes the byte at an index as an integer. Indices outside of the bytes
+    /// are an error.
+    ByteAt => "", "(bytes, i) -- byte";
+    /// Takes the bytes from a start index up to but not including an end
+    /// index. Ranges outside of the bytes are an error.
+    BytesSlice => "", "(bytes, start, end) -- bytes";
+    /// Encodes a string as UTF-8 bytes.
+    StringToBytes => "", "string -- bytes";
+    /// Decodes UTF-8 bytes as a string. Bytes that are not valid UTF-8 are an
+    /// error.
+    BytesToString => "", "bytes -- string";
+    /// Takes the character at an index, counted in characters rather than
+    /// bytes. Indices outside of the string are an error.
+    CharAt => "", "(string, i) -- char";
+    /// Gives the code point of a character.
+    CharCode => "", "char -- n";
+    /// Gives the character with a code point. Other integers are an error.
+    CharFromCode => "", "n -- char";
+    /// Calls a function with an accumulator and each character of a string
+    /// in turn, giving the last accumulator it returns.
+    FoldChars => "", "(fn, acc, string) -- acc";
+    /// Counts the characters in a string or the elements of an array.
+    Length => "", "string -- n";
+    /// Concatenates a pair of strings or a pair of arrays.
+    Join => "", "(x, y) -- x++y";
+    /// Takes the characters from a start index up to but not including an end
+    /// index. Ranges outside of the string are an error.
+    StringSlice => "", "(string, start, end) -- string";
+    /// Gives the characters of a string as an array.
+    StringChars => "", "string -- [char]";
+    /// Counts the bytes in the UTF-8 encoding of a string.
+    StringByteLength => "", "string -- n";
+    /// Takes the bytes of a string from a start offset up to but not
+    /// including an end offset. Ranges outside of the string or that split a
+    /// character are an error.
+    StringByteSlice => "", "(string, start, end) -- string";
+    /// Splits a string into the characters that a reader sees. Only built
+    /// with the graphemes feature, and an error otherwise.
+    Graphemes => "", "string -- [string]";
+    /// Converts a string to lowercase, the same way in every locale.
+    ToLower => "", "string -- string";
+    /// Converts a string to uppercase, the same way in every locale.
+    ToUpper => "", "string -- string";
+    /// Puts a string into Unicode normalization form C. Only built with the
+    /// normalization feature, and an error otherwise.
+    Nfc => "", "string -- string";
+    /// Puts a string into Unicode normalization form D. Only built with the
+    /// normalization feature, and an error otherwise.
+    Nfd => "", "string -- string";
+    /// Reads comma-separated values into an array of records, each an array
+    /// of fields. Only built with the csv feature, and an error otherwise.
+    CsvParse => "", "string -- [[string]]";
+    /// Writes records as comma-separated values. Only built with the csv
+    /// feature, and an error otherwise.
+    CsvString => "", "[[string]] -- string";
+    /// Whether a regular expression matches anywhere in a string. Only built
+    /// with the regex feature, and an error otherwise.
+    RegexMatch => "", "(pattern, string) -- boolean";
+    /// The text of the first match of a regular expression in a string and of
+    /// each of its groups, or None if it does not match. Only built with the
+    /// regex feature, and an error otherwise.
+    RegexCaptures => "", "(pattern, string) -- option";
+    /// Replaces every match of a regular expression in a string. Only built
+    /// with the regex feature, and an error otherwise.
+    RegexReplace => "", "(pattern, string, replacement) -- string";
+    /// Splits a string at each occurrence of a separator, giving a tuple of
+    /// the parts. An empty separator is an error.
+    StringSplit => "", "(string, separator) -- (part1, ..., partn)";
+    /// Tells whether a string occurs within another.
+    StringContains => "", "(string, substring) -- bool";
+    /// Builds an array from the given number of values.
+    Aconst(usize) => "count", "x1 ... xn -- [x1, ..., xn]";
+    /// Gives the element of an array at an index. Indices outside of the
+    /// array are an error.
+    ArrayAt => "", "(array, i) -- x";
+    /// Gives a copy of an array with a value added to its end.
+    ArrayPush => "", "(array, x) -- array";
+    /// Builds a map from the given number of keys, each followed by its value.
+    /// Later entries replace earlier ones with the same key.
+    Mconst(usize) => "count", "k1 v1 ... kn vn -- map";
+    /// Gives a copy of a map with a key set to a value.
+    MapInsert => "", "(map, k, v) -- map";
+    /// Gives the value of a key. Keys that are not in the map are an error.
+    MapLookup => "", "(map, k) -- v";
+    /// Gives a copy of a map without a key.
+    MapRemove => "", "(map, k) -- map";
+    /// Gives the keys of a map as an array, in the order they were inserted.
+    MapKeys => "", "map -- [k1, ..., kn]";
+    /// Tells whether a key is in a map.
+    MapHasKey => "", "(map, k) -- bool";
+    /// Adds a value to the front of a list.
+    Cons => "", "tail x -- list";
+    /// Builds a list from the given number of values.
+    Lconst(usize) => "count", "x1 ... xn -- list";
+    /// Gives the first element of a list. Empty lists are an error.
+    ListHead => "", "list -- x";
+    /// Gives a list without its first element. Empty lists are an error.
+    ListTail => "", "list -- list";
+    /// Stops the program with an exit status, which try does not catch.
+    Exit => "", "status --";
+    /// Raises a runtime error with the given message.
+    Abort => "", "message --";
+    /// Gives the integers from one up to but not including another.
+    Range => "", "y x -- [x, ..., y-1]";
+    /// Schedules a function to be called with unit once the function that
+    /// is running returns, or is left by a runtime error.
+    Defer => "", "fn --";
+    /// Calls a function with each element of an array, giving an array of
+    /// the results.
+    ArrayMap => "", "(fn, [x1, ..., xn]) -- [fn(x1), ..., fn(xn)]";
+    /// Calls a function with each element of an array, keeping the elements
+    /// for which it returns true.
+    ArrayFilter => "", "(fn, array) -- array";
+    /// Calls a function with an accumulator and each element of an array from
+    /// first to last, giving the last accumulator it returns.
+    ArrayFoldl => "", "(fn, acc, array) -- acc";
+    /// Calls a function with each element of an array from last to first and
+    /// an accumulator, giving the last accumulator it returns.
+    ArrayFoldr => "", "(fn, acc, array) -- acc";
+    /// Encodes bytes as lowercase hex digits.
+    HexEncode => "", "bytes -- string";
+    /// Decodes hex digits in either case. Other strings are an error.
+    HexDecode => "", "string -- bytes";
+    /// Encodes bytes as padded base64.
+    Base64Encode => "", "bytes -- string";
+    /// Decodes padded base64. Other strings are an error.
+    Base64Decode => "", "string -- bytes";
+    /// Pushes the current time.
+    Now => "", "() -- timestamp";
+    /// Formats a timestamp in UTC with strftime style directives.
+    FormatTime => "", "(timestamp, format) -- string";
+    /// Parses a timestamp in UTC with strftime style directives. Strings that
+    /// do not match the format are an error.
+    ParseTime => "", "(string, format) -- timestamp";
+    /// Adds a duration in milliseconds to a timestamp.
+    AddTime => "", "(timestamp, ms) -- timestamp";
+    /// Takes the number of milliseconds from the second timestamp to the
+    /// first.
+    TimeDiff => "", "(timestamp, timestamp) -- ms";
+    /// Runs a command with an array of strings as its arguments, giving its
+    /// exit code and output.
+    RunCommand => "", "(command, args) -- (code, stdout, stderr)";
+    /// Fetches a URL, giving the status, headers and body of the response.
+    HttpGet => "", "url -- {body, headers, status}";
+    /// Posts a body of the given content type to a URL, giving the status,
+    /// headers and body of the response.
+    HttpPost => "", "(url, type, body) -- {body, headers, status}";
+    /// Pushes a float constant.
+    Rconst(f64) => "value", "-- value";
+    /// Takes the next value from a channel, waiting for the host to send one.
+    Recv => "", "channel -- value";
+    /// Takes the next message from the current process's mailbox, suspending
+    /// the process until one arrives.
+    Receive => "", "() -- message";
+    /// Lets a function refer to the other functions in its letrec group.
+    Rec(Vec<(Symbol, usize)>) => "group", "fn -- fn";
+    /// Builds a record from one value for each of the given field names.
+    Record(Vec<String>) => "fields", "x1 ... xn -- {f1 = x1, ..., fn = xn}";
+    /// Returns from the current function, discarding its frame.
+    Ret => "", "frame... result -- result";
+    /// Unwraps Some or Ok, or otherwise returns the value from the function
+    /// being run, leaving the frames of any let bodies and match cases in it.
+    Propagate => "", "frame... datatype -- value";
+    /// Marks the start of a let body or match case that contains Propagate,
+    /// whose frame is not that of a function. Does nothing when run.
+    Scope => "", "--";
+    /// Catches the runtime errors of the code that follows until the matching
+    /// EndTry, jumping to the handler at a relative offset with the message.
+    Try(i64) => "offset", "--";
+    /// Stops catching the errors of the code started by the last Try.
+    EndTry => "", "--";
+    /// Pushes a string constant.
+    Sconst(String) => "value", "-- value";
+    /// Pushes the pid of the current process.
+    SelfPid => "", "() -- pid";
+    /// Appends a message to the mailbox of a process, or sends it on a channel.
+    Send => "", "(target, message) -- ()";
+    /// Binds an identifier in the current environment.
+    SetEnv(Symbol) => "id", "value --";
+    /// Formats a value the same way print writes it.
+    Show => "", "x -- string";
+    /// Finds the first part in which two values differ, as an option of its
+    /// path and both sides formatted the same way show does.
+    Diff => "", "(x, y) -- option";
+    /// Takes the second element of a pair.
+    Snd => "", "(x, y) -- y";
+    /// Starts a new process which calls a function with unit.
+    Spawn => "", "fn -- pid";
+    /// Records the source position used when reporting runtime errors.
+    Srcpos(usize, usize) => "line col", "--";
+    /// Sorts the elements of a tuple, using a function which tells whether
+    /// one element should come before another.
+    SortBy => "", "(fn, (x1, ..., xn)) -- (y1, ..., yn)";
+    /// Subtracts two integers or two floats.
+    Sub => "", "y x -- x-y";
+    /// Calls a function in tail position, replacing the current frame.
+    TailCall => "", "frame... arg fn -- arg";
+    /// Builds a tuple from the given number of values.
+    Tconst(usize) => "count", "x1 ... xn -- (x1, ..., xn)";
+    /// Tests whether a datatype was built with the named variant.
+    TypeEq(String) => "variant", "datatype -- boolean";
+    /// Pushes the unit value.
+    Uconst => "", "-- ()";
+    /// Pushes a bytes constant.
+    Xconst(Vec<u8>) => "value", "-- value";
+    /// Unpacks a tuple into its elements.
+    Untuple => "", "(x1, ..., xn) -- x1 ... xn";
+    /// Copies a record, replacing the given fields with new values.
+    Update(Vec<String>) => "fields", "record x1 ... xn -- record";
+    /// Returns control to the host if the deadline has passed.
+    Yield => "", "() -- ()";
+}
+
+// Generates a markdown reference table of all opcodes.
+pub fn opcode_reference() -> String {
+    let mut reference = String::new();
+    reference.push_str("| Opcode | Operands | Stack | Description |\n");
+    reference.push_str("|--------|----------|-------|-------------|\n");
+    for info in OPCODES {
+        reference.push_str(&format!(
+            "| {} | {} | {} | {} |\n",
+            info.name,
+            info.operands,
+            info.stack.replace('|', "\\|"),
+            info.description.trim()
+        ));
+    }
+    reference
 }
 
 impl fmt::Display for Opcode {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
+            Opcode::Abs => write!(f, "abs"),
             Opcode::Add => write!(f, "add"),
             Opcode::And => write!(f, "and"),
             Opcode::Arg(n) => write!(f, "arg {}", n),
             Opcode::Bconst(b) => write!(f, "const {}", b),
+            Opcode::Cconst(c) => write!(f, "const {}", Value::Char(*c)),
             Opcode::Call => write!(f, "call"),
+            Opcode::Concat => write!(f, "concat"),
             Opcode::Div => write!(f, "div"),
             Opcode::Dup => write!(f, "dup"),
             Opcode::Equal => write!(f, "eq"),
+            Opcode::Expect(typ) => write!(f, "expect {}", typ),
             Opcode::ExtVal => write!(f, "extval"),
-            Opcode::Dconst(_, ctor, count) => write!(f, "const {} {}", ctor, count),
+            Opcode::Field(field) => write!(f, "field {}", field),
+            Opcode::Fst => write!(f, "fst"),
+            Opcode::Dconst(_, ctor) => write!(f, "const {}", ctor),
             Opcode::Fconst(id, ip, _) => {
                 if let Some(id) = id {
-                    write!(f, "{} @{}", id, ip)
+                    write!(f, "{:?} @{}", id, ip)
                 } else {
                     write!(f, "lambda @{}", ip)
                 }
             }
-            Opcode::GetEnv(id) => write!(f, "getenv {}", id),
+            Opcode::GetEnv(id) => write!(f, "getenv {:?}", id),
             Opcode::Greater => write!(f, "gt"),
             Opcode::GreaterEqual => write!(f, "ge"),
             Opcode::Iconst(i) => write!(f, "const {}", i),
             Opcode::Jmp(ip) => write!(f, "jmp {}", ip),
             Opcode::Jz(ip) => write!(f, "jz {}", ip),
+            Opcode::Jnz(ip) => write!(f, "jnz {}", ip),
             Opcode::Less => write!(f, "lt"),
             Opcode::LessEqual => write!(f, "le"),
+            Opcode::Max => write!(f, "max"),
+            Opcode::Memo => write!(f, "memo"),
+            Opcode::Min => write!(f, "min"),
             Opcode::Mod => write!(f, "mod"),
             Opcode::Mul => write!(f, "mul"),
+            Opcode::Pow => write!(f, "pow"),
+            Opcode::Nconst(n) => write!(f, "const {}n", n),
+            Opcode::ParMap => write!(f, "parmap"),
             Opcode::Not => write!(f, "not"),
             Opcode::NotEqual => write!(f, "neq"),
+            Opcode::On => write!(f, "on"),
             Opcode::Or => write!(f, "or"),
             Opcode::Pop => write!(f, "pop"),
-            Opcode::Ret(n) => write!(f, "ret {}", n),
-            Opcode::Rot => write!(f, "rot"),
-            Opcode::SetEnv(id) => write!(f, "setenv {}", id),
+            Opcode::Print => write!(f, "print"),
+            Opcode::FloatToString => write!(f, "floattostring"),
+            Opcode::FormatInt => write!(f, "format_int"),
+            Opcode::ParseFloat => write!(f, "parsefloat"),
+            Opcode::Rconst(r) => write!(f, "const {:?}", r),
+            Opcode::Receive => write!(f, "receive"),
+            Opcode::Recv => write!(f, "recv"),
+            Opcode::Rec(group) => {
+                write!(f, "rec")?;
+                for (id, ip) in group {
+                    write!(f, " {:?} @{}", id, ip)?;
+                }
+                Ok(())
+            }
+            Opcode::Record(fields) => write!(f, "const record {}", fields.join(" ")),
+            Opcode::Ret => write!(f, "ret"),
+            Opcode::Propagate => write!(f, "propagate"),
+            Opcode::Scope => write!(f, "scope"),
+            Opcode::Try(ip) => write!(f, "try {}", ip),
+            Opcode::EndTry => write!(f, "endtry"),
+            Opcode::Sconst(s) => write!(f, "const {:?}", s),
+            Opcode::SelfPid => write!(f, "self"),
+            Opcode::Send => write!(f, "send"),
+            Opcode::SetEnv(id) => write!(f, "setenv {:?}", id),
+            Opcode::Show => write!(f, "show"),
+            Opcode::Diff => write!(f, "diff"),
+            Opcode::Snd => write!(f, "snd"),
+            Opcode::WrappingAdd => write!(f, "wrapping_add"),
+            Opcode::WrappingSub => write!(f, "wrapping_sub"),
+            Opcode::WrappingMul => write!(f, "wrapping_mul"),
+            Opcode::SaturatingAdd => write!(f, "saturating_add"),
+            Opcode::SaturatingSub => write!(f, "saturating_sub"),
+            Opcode::SaturatingMul => write!(f, "saturating_mul"),
+            Opcode::HashValue => write!(f, "hash"),
+            Opcode::Popcount => write!(f, "popcount"),
+            Opcode::LeadingZeros => write!(f, "leading_zeros"),
+            Opcode::RotateLeft => write!(f, "rotate_left"),
+            Opcode::RotateRight => write!(f, "rotate_right"),
+            Opcode::Spawn => write!(f, "spawn"),
             Opcode::Srcpos(line, col) => write!(f, "srcpos {} {}", line, col),
+            Opcode::SortBy => write!(f, "sortby"),
             Opcode::Sub => write!(f, "sub"),
+            Opcode::Neg => write!(f, "neg"),
+            Opcode::BytesLength => write!(f, "bytes_length"),
+            Opcode::ByteAt => write!(f, "byte_at"),
+            Opcode::BytesSlice => write!(f, "bytes_slice"),
+            Opcode::StringToBytes => write!(f, "string_to_bytes"),
+            Opcode::BytesToString => write!(f, "bytes_to_string"),
+            Opcode::CharAt => write!(f, "char_at"),
+            Opcode::CharCode => write!(f, "char_code"),
+            Opcode::CharFromCode => write!(f, "char_from_code"),
+            Opcode::FoldChars => write!(f, "fold_chars"),
+            Opcode::Length => write!(f, "length"),
+            Opcode::Join => write!(f, "concat"),
+            Opcode::StringSlice => write!(f, "slice"),
+            Opcode::StringChars => write!(f, "chars"),
+            Opcode::StringByteLength => write!(f, "byte_length"),
+            Opcode::StringByteSlice => write!(f, "byte_slice"),
+            Opcode::Graphemes => write!(f, "graphemes"),
+            Opcode::ToLower => write!(f, "to_lower"),
+            Opcode::ToUpper => write!(f, "to_upper"),
+            Opcode::Nfc => write!(f, "nfc"),
+            Opcode::CsvParse => write!(f, "csv_parse"),
+            Opcode::CsvString => write!(f, "csv_string"),
+            Opcode::RegexMatch => write!(f, "regex_match"),
+            Opcode::RegexCaptures => write!(f, "regex_captures"),
+            Opcode::RegexReplace => write!(f, "regex_replace"),
+            Opcode::Nfd => write!(f, "nfd"),
+            Opcode::StringSplit => write!(f, "split"),
+            Opcode::StringContains => write!(f, "contains"),
+            Opcode::HexEncode => write!(f, "hex_encode"),
+            Opcode::HexDecode => write!(f, "hex_decode"),
+            Opcode::Base64Encode => write!(f, "base64_encode"),
+            Opcode::Base64Decode => write!(f, "base64_decode"),
+            Opcode::Now => write!(f, "now"),
+            Opcode::FormatTime => write!(f, "format_time"),
+            Opcode::ParseTime => write!(f, "parse_time"),
+            Opcode::AddTime => write!(f, "add_time"),
+            Opcode::TimeDiff => write!(f, "time_diff"),
+            Opcode::HttpGet => write!(f, "http_get"),
+            Opcode::HttpPost => write!(f, "http_post"),
+            Opcode::RunCommand => write!(f, "run_command"),
+            Opcode::TailCall => write!(f, "tailcall"),
+            Opcode::Tconst(n) => write!(f, "const tuple {}", n),
+            Opcode::Aconst(n) => write!(f, "const array {}", n),
+            Opcode::ArrayAt => write!(f, "at"),
+            Opcode::ArrayPush => write!(f, "push"),
+            Opcode::Mconst(n) => write!(f, "const map {}", n),
+            Opcode::MapInsert => write!(f, "insert"),
+            Opcode::MapLookup => write!(f, "lookup"),
+            Opcode::MapRemove => write!(f, "remove"),
+            Opcode::MapKeys => write!(f, "keys"),
+            Opcode::MapHasKey => write!(f, "has_key"),
+            Opcode::Cons => write!(f, "cons"),
+            Opcode::Lconst(n) => write!(f, "const list {}", n),
+            Opcode::ListHead => write!(f, "head"),
+            Opcode::ListTail => write!(f, "tail"),
+            Opcode::Exit => write!(f, "exit"),
+            Opcode::Abort => write!(f, "abort"),
+            Opcode::Range => write!(f, "range"),
+            Opcode::Defer => write!(f, "defer"),
+            Opcode::ArrayMap => write!(f, "map"),
+            Opcode::ArrayFilter => write!(f, "filter"),
+            Opcode::ArrayFoldl => write!(f, "foldl"),
+            Opcode::ArrayFoldr => write!(f, "foldr"),
             Opcode::TypeEq(typ) => write!(f, "typeq {}", typ),
             Opcode::Uconst => write!(f, "const"),
+            Opcode::Xconst(bytes) => write!(f, "const {}", Value::Bytes(bytes.clone())),
+            Opcode::Untuple => write!(f, "untuple"),
+            Opcode::Update(fields) => write!(f, "update {}", fields.join(" ")),
+            Opcode::Yield => write!(f, "yield"),
+        }
+    }
+}
+
+// Displays an instruction with the identifiers it refers to written by name,
+// rather than as their symbols.
+pub struct OpcodeDisplay<'a> {
+    opcode: &'a Opcode,
+    names: &'a Names,
+}
+
+impl fmt::Display for OpcodeDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names = self.names;
+        match self.opcode {
+            Opcode::Fconst(Some(id), ip, _) => write!(f, "{} @{}", names.name(*id), ip),
+            Opcode::GetEnv(id) => write!(f, "getenv {}", names.name(*id)),
+            Opcode::Rec(group) => {
+                write!(f, "rec")?;
+                for (id, ip) in group {
+                    write!(f, " {} @{}", names.name(*id), ip)?;
+                }
+                Ok(())
+            }
+            Opcode::SetEnv(id) => write!(f, "setenv {}", names.name(*id)),
+            opcode => write!(f, "{}", opcode),
+        }
+    }
+}
+
+impl Opcode {
+    pub fn display<'a>(&'a self, names: &'a Names) -> OpcodeDisplay<'a> {
+        OpcodeDisplay {
+            opcode: self,
+            names,
         }
     }
 }
 
+// Environments are shared rather than copied, as every closure and every call
+// has one. The values bound in a scope are kept apart from those of the scopes
+// enclosing it, so a closure only allocates for the values it adds, and maps
+// are only copied when a shared scope is changed.
 #[derive(Clone, Debug, PartialEq)]
 pub struct Environment {
-    pub fun: Option<(String, usize)>,
-    pub values: HashMap<String, Value>,
-    pub types: HashMap<String, typeinfer::Type>,
+    pub fun: Option<(Symbol, usize)>,
+    // The functions defined in the same letrec group. Like fun, these are
+    // rebuilt from this environment when they are referenced, as an
+    // environment can not contain itself.
+    pub group: Vec<(Symbol, usize)>,
+    pub values: Arc<HashMap<Symbol, Value>>,
+    pub parent: Option<Arc<Environment>>,
+    pub types: Arc<HashMap<String, typeinfer::Type>>,
+    pub datatypes: Arc<HashMap<String, HashSet<String>>>,
+    pub effects: Arc<HashMap<String, effects::Effects>>,
+}
+
+impl Default for Environment {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Environment {
+    // Returns the environment for a function in the letrec group, which is
+    // this one but with the function as the one being called.
+    fn rebuilt(&self, id: Symbol, ip: usize) -> Environment {
+        let mut env = self.clone();
+        env.fun = Some((id, ip));
+        env
+    }
+
+    // Returns a new scope within this environment binding the given values.
+    fn extended(&self, values: HashMap<Symbol, Value>) -> Environment {
+        if values.is_empty() {
+            return self.clone();
+        }
+        Environment {
+            fun: self.fun,
+            group: self.group.clone(),
+            values: Arc::new(values),
+            parent: Some(Arc::new(self.clone())),
+            types: self.types.clone(),
+            datatypes: self.datatypes.clone(),
+            effects: self.effects.clone(),
+        }
+    }
+
     pub fn new() -> Environment {
         Environment {
             fun: None,
-            values: HashMap::new(),
-            types: HashMap::new(),
+            group: Vec::new(),
+            values: Arc::new(HashMap::new()),
+            parent: None,
+            types: Arc::new(HashMap::new()),
+            datatypes: Arc::new(HashMap::new()),
+            effects: Arc::new(HashMap::new()),
+        }
+    }
+
+    // Looks up a value, starting from the innermost scope.
+    pub fn get(&self, id: &Symbol) -> Option<&Value> {
+        let mut env = self;
+        loop {
+            if let Some(value) = env.values.get(id) {
+                return Some(value);
+            }
+            env = env.parent.as_deref()?;
+        }
+    }
+
+    // Binds a value in the innermost scope.
+    pub fn insert(&mut self, id: Symbol, value: Value) {
+        Arc::make_mut(&mut self.values).insert(id, value);
+    }
+
+    // Lists every value that can be looked up, sorted by the names of their
+    // identifiers.
+    pub fn bindings(&self, names: &Names) -> Vec<(Symbol, &Value)> {
+        let mut bindings: HashMap<Symbol, &Value> = HashMap::new();
+        let mut env = Some(self);
+        while let Some(scope) = env {
+            for (id, value) in scope.values.iter() {
+                bindings.entry(*id).or_insert(value);
+            }
+            env = scope.parent.as_deref();
+        }
+        let mut bindings: Vec<_> = bindings.into_iter().collect();
+        bindings.sort_by_key(|(id, _)| names.name(*id));
+        bindings
+    }
+}
+
+// A channel carries values of one type between a script and the application
+// embedding it, which may run the virtual machine on another thread. The host
+// creates channels with VirtualMachine::channel.
+#[derive(Default)]
+struct ChannelState {
+    values: VecDeque<Value>,
+    closed: bool,
+}
+
+#[derive(Clone)]
+pub struct Channel {
+    state: Arc<(Mutex<ChannelState>, Condvar)>,
+    element: typeinfer::Type,
+}
+
+impl Channel {
+    pub fn new(element: typeinfer::Type) -> Channel {
+        Channel {
+            state: Arc::default(),
+            element,
+        }
+    }
+
+    // Appends a value to the channel, returning false if it has been closed.
+    pub fn send(&self, value: Value) -> bool {
+        let (state, ready) = &*self.state;
+        let mut state = state.lock().unwrap();
+        if state.closed {
+            return false;
         }
+        state.values.push_back(value);
+        ready.notify_one();
+        true
+    }
+
+    // Takes the next value from the channel, waiting until one is sent. This
+    // returns None once the channel is closed and empty.
+    pub fn recv(&self) -> Option<Value> {
+        let (state, ready) = &*self.state;
+        let mut state = state.lock().unwrap();
+        loop {
+            if let Some(value) = state.values.pop_front() {
+                return Some(value);
+            }
+            if state.closed {
+                return None;
+            }
+            state = ready.wait(state).unwrap();
+        }
+    }
+
+    pub fn try_recv(&self) -> Option<Value> {
+        self.state.0.lock().unwrap().values.pop_front()
+    }
+
+    // Closes the channel. Values that were already sent can still be
+    // received.
+    pub fn close(&self) {
+        let (state, ready) = &*self.state;
+        state.lock().unwrap().closed = true;
+        ready.notify_all();
+    }
+}
+
+impl fmt::Debug for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Channel")
+    }
+}
+
+impl PartialEq for Channel {
+    fn eq(&self, other: &Channel) -> bool {
+        Arc::ptr_eq(&self.state, &other.state)
+    }
+}
+
+// A function implemented by the application embedding the interpreter, which
+// registers it with VirtualMachine::register. It takes a single argument, which
+// is a tuple for functions of several arguments, and its error is reported as
+// a runtime error of the program calling it.
+#[derive(Clone)]
+pub struct NativeFunction {
+    pub name: String,
+    fun: Arc<NativeFn>,
+}
+
+type NativeFn = dyn Fn(Value) -> Result<Value, String> + Send + Sync;
+
+impl NativeFunction {
+    pub fn call(&self, arg: Value) -> Result<Value, String> {
+        (self.fun)(arg)
+    }
+}
+
+impl fmt::Debug for NativeFunction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "NativeFunction({})", self.name)
+    }
+}
+
+impl PartialEq for NativeFunction {
+    fn eq(&self, other: &NativeFunction) -> bool {
+        Arc::ptr_eq(&self.fun, &other.fun)
     }
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum Value {
+    Array(Vec<Value>),
+    BigInt(BigInt),
     Boolean(bool),
+    Bytes(Vec<u8>),
+    Channel(Channel),
+    Char(char),
     Datatype(String, String, Box<Value>),
+    Float(f64),
     Function(usize, Environment),
     Integer(i64),
+    List(List),
+    Map(Map),
+    Memo(usize, Box<Value>),
+    NativeFunction(NativeFunction),
+    Pid(usize),
+    Record(Vec<(String, Value)>),
+    Str(String),
+    Timestamp(i64),
     Tuple(Vec<Value>),
     Unit,
 }
 
 impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write(f, None, None)
+    }
+}
+
+// Displays a value with its functions named by a symbol table, and its
+// integers written in the given format if there is one.
+pub struct ValueDisplay<'a> {
+    value: &'a Value,
+    symbols: &'a HashMap<usize, FunctionInfo>,
+    ints: Option<&'a IntFormat>,
+}
+
+impl<'a> ValueDisplay<'a> {
+    pub fn ints(self, ints: &'a IntFormat) -> ValueDisplay<'a> {
+        ValueDisplay {
+            ints: Some(ints),
+            ..self
+        }
+    }
+}
+
+impl fmt::Display for ValueDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.value.write(f, Some(self.symbols), self.ints)
+    }
+}
+
+impl Value {
+    pub fn display<'a>(&'a self, symbols: &'a HashMap<usize, FunctionInfo>) -> ValueDisplay<'a> {
+        ValueDisplay {
+            value: self,
+            symbols,
+            ints: None,
+        }
+    }
+
+    fn write(
+        &self,
+        f: &mut fmt::Formatter,
+        symbols: Option<&HashMap<usize, FunctionInfo>>,
+        ints: Option<&IntFormat>,
+    ) -> fmt::Result {
         match self {
+            Value::Array(elements) => {
+                write!(f, "[")?;
+                for i in 0..elements.len() {
+                    elements[i].write(f, symbols, ints)?;
+                    if i + 1 != elements.len() {
+                        write!(f, ", ")?;
+                    }
+                }
+                write!(f, "]")
+            }
+            Value::BigInt(n) => match ints {
+                Some(ints) => write!(f, "{}", ints.bigint(n)),
+                None => write!(f, "{}", n),
+            },
             Value::Boolean(b) => write!(f, "{}", b),
-            Value::Datatype(n, _, v) => {
-                if let Value::Unit = v.borrow() {
-                    write!(f, "{}", n)
-                } else {
-                    write!(f, "{} {}", n, v)
+            Value::Bytes(bytes) => {
+                write!(f, "b\"")?;
+                for b in bytes {
+                    match b {
+                        b'"' => write!(f, "\\\"")?,
+                        b'\\' => write!(f, "\\\\")?,
+                        b'\n' => write!(f, "\\n")?,
+                        b'\r' => write!(f, "\\r")?,
+                        b'\t' => write!(f, "\\t")?,
+                        0 => write!(f, "\\0")?,
+                        b' '..=b'~' => write!(f, "{}", *b as char)?,
+                        b => write!(f, "\\x{:02x}", b)?,
+                    }
+                }
+                write!(f, "\"")
+            }
+            Value::Channel(_) => write!(f, "(channel)"),
+            Value::Char(c) => match c {
+                '\'' => write!(f, "'\\''"),
+                '\\' => write!(f, "'\\\\'"),
+                '\n' => write!(f, "'\\n'"),
+                '\r' => write!(f, "'\\r'"),
+                '\t' => write!(f, "'\\t'"),
+                '\0' => write!(f, "'\\0'"),
+                c => write!(f, "'{}'", c),
+            },
+            Value::Datatype(_, variant, v) => match v.borrow() {
+                Value::Unit => write!(f, "{}", variant),
+                Value::Tuple(_) => {
+                    write!(f, "{} ", variant)?;
+                    v.write(f, symbols, ints)
+                }
+                _ => {
+                    write!(f, "{} (", variant)?;
+                    v.write(f, symbols, ints)?;
+                    write!(f, ")")
+                }
+            },
+            Value::Float(v) => write!(f, "{}", format_float(*v)),
+            Value::Function(ip, _) => match symbols.and_then(|symbols| symbols.get(ip)) {
+                Some(FunctionInfo {
+                    name: Some(name),
+                    line: usize::MAX,
+                    ..
+                }) => write!(f, "(fn {})", name),
+                Some(FunctionInfo {
+                    name: Some(name),
+                    line,
+                    col,
+                }) => write!(f, "(fn {} at {}:{})", name, line, col),
+                Some(FunctionInfo {
+                    name: None,
+                    line,
+                    col,
+                }) if *line != usize::MAX => write!(f, "(lambda at {}:{})", line, col),
+                _ => write!(f, "(lambda @{})", ip),
+            },
+            Value::Integer(v) => match ints {
+                Some(ints) => write!(f, "{}", ints.integer(*v)),
+                None => write!(f, "{}", v),
+            },
+            Value::List(list) => {
+                write!(f, "%[")?;
+                for (i, element) in list.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, ", ")?;
+                    }
+                    element.write(f, symbols, ints)?;
+                }
+                write!(f, "]")
+            }
+            Value::Map(map) => {
+                write!(f, "%{{")?;
+                for (i, (key, value)) in map.iter().enumerate() {
+                    key.write(f, symbols, ints)?;
+                    write!(f, " => ")?;
+                    value.write(f, symbols, ints)?;
+                    if i + 1 != map.len() {
+                        write!(f, ", ")?;
+                    }
+                }
+                write!(f, "}}")
+            }
+            Value::Memo(_, fun) => {
+                write!(f, "(memo ")?;
+                fun.write(f, symbols, ints)?;
+                write!(f, ")")
+            }
+            Value::NativeFunction(native) => write!(f, "(native {})", native.name),
+            Value::Pid(pid) => write!(f, "(pid {})", pid),
+            Value::Record(fields) => {
+                write!(f, "{{")?;
+                for i in 0..fields.len() {
+                    write!(f, "{} = ", fields[i].0)?;
+                    fields[i].1.write(f, symbols, ints)?;
+                    if i + 1 != fields.len() {
+                        write!(f, ", ")?;
+                    }
+                }
+                write!(f, "}}")
+            }
+            Value::Str(s) => {
+                write!(f, "\"")?;
+                for c in s.chars() {
+                    match c {
+                        '"' => write!(f, "\\\"")?,
+                        '\\' => write!(f, "\\\\")?,
+                        '\n' => write!(f, "\\n")?,
+                        '\r' => write!(f, "\\r")?,
+                        '\t' => write!(f, "\\t")?,
+                        '\0' => write!(f, "\\0")?,
+                        c => write!(f, "{}", c)?,
+                    }
                 }
+                write!(f, "\"")
             }
-            Value::Function(ip, _) => write!(f, "(lambda @{})", ip),
-            Value::Integer(v) => write!(f, "{}", v),
+            Value::Timestamp(t) => write!(
+                f,
+                "{}",
+                time::format(*t, "%Y-%m-%dT%H:%M:%S.%fZ").unwrap_or_default()
+            ),
             Value::Tuple(elements) => {
                 write!(f, "(")?;
                 for i in 0..elements.len() {
-                    write!(f, "{}", elements[i])?;
+                    elements[i].write(f, symbols, ints)?;
                     if i + 1 != elements.len() {
                         write!(f, ", ")?;
                     }
@@ -153,344 +1054,4866 @@ impl fmt::Display for Value {
     }
 }
 
-pub struct VirtualMachine {
-    pub instructions: Vec<Opcode>,
-    pub ip: usize,
-    pub stack: Vec<Value>,
-    pub callstack: Vec<(usize, Environment, usize, usize)>,
+// Floats are written as the shortest decimal that reads back as the same
+// float, which does not depend upon the locale, with a fraction or exponent so
+// that they are not mistaken for integers.
+pub fn format_float(v: f64) -> String {
+    format!("{:?}", v)
+}
 
-    pub env: Environment,
+// How integers are written by format_int and, when the host sets one, when
+// values are displayed: in a radix from 2 to 36 with lowercase digits, and a
+// separator between groups of three digits in decimal or four in any other
+// radix, counting from the right.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IntFormat {
+    radix: u32,
+    separator: String,
+}
 
-    pub line: usize,
-    pub col: usize,
+impl Default for IntFormat {
+    fn default() -> Self {
+        IntFormat {
+            radix: 10,
+            separator: String::new(),
+        }
+    }
 }
 
-impl VirtualMachine {
-    #[allow(clippy::cognitive_complexity)]
-    pub fn run(&mut self) -> Result<(), codegen::InterpreterError> {
-        while self.ip < self.instructions.len() {
-            match &self.instructions[self.ip] {
-                Opcode::Add => match self.stack.pop() {
-                    Some(Value::Integer(x)) => match self.stack.pop() {
-                        Some(Value::Integer(y)) => {
-                            self.stack.push(Value::Integer(x + y));
-                        }
-                        _ => unreachable!(),
-                    },
-                    _ => unreachable!(),
-                },
-                Opcode::And => match self.stack.pop() {
-                    Some(Value::Boolean(x)) => match self.stack.pop() {
-                        Some(Value::Boolean(y)) => {
-                            self.stack.push(Value::Boolean(x && y));
-                        }
-                        _ => unreachable!(),
-                    },
-                    _ => unreachable!(),
-                },
-                Opcode::Arg(offset) => match self.callstack.last() {
-                    Some((_, _, sp, _)) => {
-                        self.stack.push(self.stack[*sp - offset].clone());
-                    }
-                    None => unreachable!(),
-                },
-                Opcode::Bconst(b) => {
-                    self.stack.push(Value::Boolean(*b));
-                }
-                Opcode::Call => match self.stack.pop() {
-                    Some(Value::Function(ip, env)) => {
-                        let return_ip = self.ip;
-                        self.ip = ip;
-                        self.callstack
-                            .push((ip, env, self.stack.len() - 1, return_ip));
-                        continue;
-                    }
-                    _ => unreachable!(),
-                },
-                Opcode::Div => match self.stack.pop() {
-                    Some(Value::Integer(x)) => match self.stack.pop() {
-                        Some(Value::Integer(y)) => {
-                            if y == 0 {
-                                err!(self, "Division by zero.")
-                            }
-                            self.stack.push(Value::Integer(x / y));
-                        }
-                        _ => unreachable!(),
-                    },
-                    _ => unreachable!(),
-                },
-                Opcode::Dup => match self.stack.pop() {
-                    Some(v) => {
-                        self.stack.push(v.clone());
-                        self.stack.push(v);
-                    }
-                    _ => unreachable!(),
-                },
-                Opcode::ExtVal => match self.stack.pop() {
-                    Some(Value::Datatype(_, _, v)) => {
-                        if let Value::Tuple(elements) = *v {
-                            for element in elements {
-                                self.stack.push(element);
-                            }
-                        } else {
-                            self.stack.push(*v);
-                        }
-                    }
-                    _ => unreachable!(),
-                },
-                Opcode::Equal => match self.stack.pop() {
-                    Some(x) => match self.stack.pop() {
-                        Some(y) => {
-                            self.stack.push(Value::Boolean(x == y));
-                        }
-                        _ => unreachable!(),
-                    },
-                    _ => unreachable!(),
-                },
-                Opcode::Dconst(typ, ctor, count) => {
-                    if *count == 0 {
-                        unreachable!();
-                    } else if *count == 1 {
-                        match self.stack.pop() {
-                            Some(value) => {
-                                self.stack.push(Value::Datatype(
-                                    typ.to_string(),
-                                    ctor.to_string(),
-                                    Box::new(value),
-                                ));
-                            }
-                            _ => unreachable!(),
-                        }
-                    } else {
-                        let mut elements = Vec::new();
-                        for _ in 0..*count {
-                            match self.stack.pop() {
-                                Some(value) => {
-                                    elements.push(value);
-                                }
-                                _ => unreachable!(),
-                            }
-                        }
-                        elements.reverse();
-                        self.stack.push(Value::Datatype(
-                            typ.to_string(),
-                            ctor.to_string(),
-                            Box::new(Value::Tuple(elements)),
-                        ));
-                    }
-                }
-                Opcode::Fconst(id, ip, upvalues) => {
-                    let len = self.callstack.len();
-                    let mut env = if len > 0 {
-                        self.callstack[len - 1].1.clone()
-                    } else {
-                        self.env.clone()
-                    };
-                    if let Some((ident, ip)) = &env.fun {
-                        env.values
-                            .insert(ident.to_string(), Value::Function(*ip, env.clone()));
-                    }
-                    for upvalue in upvalues {
-                        if let Some((_, _, sp, _)) = self.callstack.last() {
-                            let id = upvalue.0;
-                            let offset = (upvalue.1).0;
-                            let value = self.stack[*sp - offset].clone();
-                            env.values.insert(id.to_string(), value);
-                            env.types.insert(id.to_string(), (upvalue.1).1.clone());
-                        }
-                    }
-                    if let Some(id) = id {
-                        env.fun = Some((id.clone(), *ip));
-                    }
-                    self.stack.push(Value::Function(*ip, env));
-                }
-                Opcode::GetEnv(id) => {
-                    let len = self.callstack.len();
-                    let env = if len > 0 {
-                        &self.callstack[len - 1].1
-                    } else {
-                        &self.env
-                    };
-                    match env.values.get(id) {
-                        Some(x) => {
-                            self.stack.push(x.clone());
-                        }
-                        None => {
-                            if let Some((ident, ip)) = &env.fun {
-                                if id == ident {
-                                    self.stack.push(Value::Function(*ip, env.clone()));
-                                }
-                            } else {
-                                unreachable!()
-                            }
-                        }
-                    }
-                }
-                Opcode::Greater => match self.stack.pop() {
-                    Some(Value::Integer(x)) => match self.stack.pop() {
-                        Some(Value::Integer(y)) => {
-                            self.stack.push(Value::Boolean(x > y));
-                        }
-                        _ => unreachable!(),
-                    },
-                    _ => unreachable!(),
-                },
-                Opcode::GreaterEqual => match self.stack.pop() {
-                    Some(Value::Integer(x)) => match self.stack.pop() {
-                        Some(Value::Integer(y)) => {
-                            self.stack.push(Value::Boolean(x >= y));
-                        }
-                        _ => unreachable!(),
-                    },
-                    _ => unreachable!(),
-                },
-                Opcode::Iconst(i) => {
-                    self.stack.push(Value::Integer(*i));
-                }
+impl IntFormat {
+    pub fn new(radix: i64, separator: &str) -> Option<IntFormat> {
+        match u32::try_from(radix) {
+            Ok(radix @ 2..=36) => Some(IntFormat {
+                radix,
+                separator: separator.to_string(),
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn integer(&self, n: i64) -> String {
+        if self.radix == 10 && self.separator.is_empty() {
+            return n.to_string();
+        }
+        self.bigint(&BigInt::from(n))
+    }
+
+    pub fn bigint(&self, n: &BigInt) -> String {
+        let digits = n.to_str_radix(self.radix);
+        if self.separator.is_empty() {
+            return digits;
+        }
+        let (sign, digits) = match digits.strip_prefix('-') {
+            Some(digits) => ("-", digits),
+            None => ("", digits.as_str()),
+        };
+        let group = if self.radix == 10 { 3 } else { 4 };
+        let mut result = sign.to_string();
+        for (i, digit) in digits.chars().enumerate() {
+            if i != 0 && (digits.len() - i) % group == 0 {
+                result.push_str(&self.separator);
+            }
+            result.push(digit);
+        }
+        result
+    }
+}
+
+// Integers and bigints as bigints, for arithmetic on integers that have been
+// promoted or on bigints.
+// Raises an integer to a power that is not negative, if the result fits.
+pub fn checked_pow(x: i64, y: i64) -> Option<i64> {
+    match x {
+        _ if y < 0 => None,
+        0 | 1 => Some(if y == 0 { 1 } else { x }),
+        -1 => Some(if y % 2 == 0 { 1 } else { -1 }),
+        _ => x.checked_pow(u32::try_from(y).ok()?),
+    }
+}
+
+fn wrapping_pow(mut x: i64, mut y: i64) -> i64 {
+    let mut result: i64 = 1;
+    while y > 0 {
+        if y & 1 == 1 {
+            result = result.wrapping_mul(x);
+        }
+        x = x.wrapping_mul(x);
+        y >>= 1;
+    }
+    result
+}
+
+fn bigints(x: &Value, y: &Value) -> Option<(BigInt, BigInt)> {
+    let bigint = |value: &Value| match value {
+        Value::Integer(i) => Some(BigInt::from(*i)),
+        Value::BigInt(n) => Some(n.clone()),
+        _ => None,
+    };
+    Some((bigint(x)?, bigint(y)?))
+}
+
+fn compare_integers(x: &Value, y: &Value) -> Option<std::cmp::Ordering> {
+    match (x, y) {
+        (Value::Integer(x), Value::Integer(y)) => Some(x.cmp(y)),
+        _ => bigints(x, y).map(|(x, y)| x.cmp(&y)),
+    }
+}
+
+// Values are hashed so that they can be used as keys in memo caches. Functions
+// are hashed by their entry point alone, as their environments can not be
+// hashed. Floats are hashed by their bits, so 0.0 and -0.0 are distinct keys,
+// and NaN is never found, which only costs a cache miss.
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            Value::Array(elements) => elements.hash(state),
+            Value::BigInt(n) => n.hash(state),
+            Value::Boolean(b) => b.hash(state),
+            Value::Bytes(bytes) => bytes.hash(state),
+            Value::Channel(channel) => Arc::as_ptr(&channel.state).hash(state),
+            Value::Char(c) => c.hash(state),
+            Value::Datatype(typ, variant, v) => {
+                typ.hash(state);
+                variant.hash(state);
+                v.hash(state);
+            }
+            Value::Float(v) => v.to_bits().hash(state),
+            Value::Function(ip, _) => ip.hash(state),
+            Value::Integer(v) => v.hash(state),
+            Value::List(list) => list.hash(state),
+            Value::Map(map) => map.hash(state),
+            Value::Memo(cache, _) => cache.hash(state),
+            Value::NativeFunction(native) => Arc::as_ptr(&native.fun).cast::<u8>().hash(state),
+            Value::Pid(pid) => pid.hash(state),
+            Value::Record(fields) => fields.hash(state),
+            Value::Str(s) => s.hash(state),
+            Value::Timestamp(t) => t.hash(state),
+            Value::Tuple(elements) => elements.hash(state),
+            Value::Unit => {}
+        }
+    }
+}
+
+impl Eq for Value {}
+
+// Lists are chains of shared cells, so that adding to the front of a list or
+// taking its tail does not copy it. They are compared, hashed and dropped a
+// cell at a time rather than recursively, so that long lists can not
+// overflow the stack.
+#[derive(Clone, Default)]
+pub struct List(Option<Arc<(Value, List)>>);
+
+impl List {
+    pub fn new() -> List {
+        List::default()
+    }
+
+    pub fn cons(head: Value, tail: List) -> List {
+        List(Some(Arc::new((head, tail))))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_none()
+    }
+
+    pub fn head(&self) -> Option<&Value> {
+        self.0.as_ref().map(|cell| &cell.0)
+    }
+
+    pub fn tail(&self) -> Option<&List> {
+        self.0.as_ref().map(|cell| &cell.1)
+    }
+
+    pub fn iter(&self) -> ListIter<'_> {
+        ListIter(self)
+    }
+}
+
+pub struct ListIter<'a>(&'a List);
+
+impl<'a> Iterator for ListIter<'a> {
+    type Item = &'a Value;
+
+    fn next(&mut self) -> Option<&'a Value> {
+        let cell = self.0 .0.as_ref()?;
+        self.0 = &cell.1;
+        Some(&cell.0)
+    }
+}
+
+impl Drop for List {
+    fn drop(&mut self) {
+        let mut next = self.0.take();
+        while let Some(cell) = next {
+            match Arc::try_unwrap(cell) {
+                Ok((_, mut tail)) => next = tail.0.take(),
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+impl PartialEq for List {
+    fn eq(&self, other: &List) -> bool {
+        self.iter().eq(other.iter())
+    }
+}
+
+impl Hash for List {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        for element in self.iter() {
+            element.hash(state);
+        }
+        self.iter().count().hash(state);
+    }
+}
+
+impl fmt::Debug for List {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+// Maps keep their entries in the order in which their keys were first
+// inserted, so that they are displayed and their keys listed the same way every
+// time, along with an index from each key to its entry. Maps with the same
+// entries are equal whatever their order.
+#[derive(Clone, Debug, Default)]
+pub struct Map {
+    entries: Vec<(Value, Value)>,
+    index: HashMap<Value, usize>,
+}
+
+impl Map {
+    pub fn new() -> Map {
+        Map::default()
+    }
+
+    pub fn insert(&mut self, key: Value, value: Value) {
+        match self.index.get(&key) {
+            Some(i) => self.entries[*i].1 = value,
+            None => {
+                self.index.insert(key.clone(), self.entries.len());
+                self.entries.push((key, value));
+            }
+        }
+    }
+
+    pub fn get(&self, key: &Value) -> Option<&Value> {
+        self.index.get(key).map(|i| &self.entries[*i].1)
+    }
+
+    pub fn remove(&mut self, key: &Value) -> Option<Value> {
+        let i = self.index.remove(key)?;
+        let (_, value) = self.entries.remove(i);
+        for (key, _) in &self.entries[i..] {
+            if let Some(j) = self.index.get_mut(key) {
+                *j -= 1;
+            }
+        }
+        Some(value)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, (Value, Value)> {
+        self.entries.iter()
+    }
+}
+
+impl PartialEq for Map {
+    fn eq(&self, other: &Map) -> bool {
+        self.len() == other.len()
+            && self
+                .iter()
+                .all(|(key, value)| other.get(key) == Some(value))
+    }
+}
+
+// The entries are hashed on their own and their hashes summed, so that equal
+// maps hash the same whatever the order of their entries.
+impl Hash for Map {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        let mut sum: u64 = 0;
+        for entry in self.iter() {
+            let mut hasher = std::collections::hash_map::DefaultHasher::new();
+            entry.hash(&mut hasher);
+            sum = sum.wrapping_add(hasher.finish());
+        }
+        self.len().hash(state);
+        sum.hash(state);
+    }
+}
+
+// A part of two values that differs, found by following the same path of
+// record fields, tuple elements and datatype variants into both, such as
+// ".x.0" for the first element of field x. The path of the values themselves
+// is empty.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Difference {
+    pub path: String,
+    pub left: Value,
+    pub right: Value,
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.path.is_empty() {
+            write!(f, "{} ~= {}", self.left, self.right)
+        } else {
+            write!(f, "{}: {} ~= {}", self.path, self.left, self.right)
+        }
+    }
+}
+
+impl Value {
+    // Whether a value has a type, as far as can be told at run time. Type
+    // variables match any value. Functions never match a function type, as
+    // their parameter and result types are not kept, and calling one with an
+    // argument of another shape would leave the stack in an invalid state.
+    pub fn has_type(&self, typ: &typeinfer::Type) -> bool {
+        match (self, typ) {
+            (_, typeinfer::Type::Polymorphic(_)) => true,
+            (Value::Array(elements), typeinfer::Type::Array(element)) => {
+                elements.iter().all(|value| value.has_type(element))
+            }
+            (Value::List(list), typeinfer::Type::List(element)) => {
+                list.iter().all(|value| value.has_type(element))
+            }
+            (Value::Map(map), typeinfer::Type::Map(key, value)) => map
+                .iter()
+                .all(|(k, v)| k.has_type(key) && v.has_type(value)),
+            (Value::Tuple(elements), typeinfer::Type::Tuple(types)) => {
+                elements.len() == types.len()
+                    && elements
+                        .iter()
+                        .zip(types)
+                        .all(|(value, typ)| value.has_type(typ))
+            }
+            (Value::Record(fields), typeinfer::Type::Record(types)) => {
+                fields.len() == types.len()
+                    && types.iter().all(|(name, typ)| {
+                        fields
+                            .iter()
+                            .any(|field| field.0 == *name && field.1.has_type(typ))
+                    })
+            }
+            (Value::Datatype(name, _, _), typeinfer::Type::Datatype(typ)) => name == typ,
+            (Value::Channel(channel), typeinfer::Type::Channel(element)) => {
+                channel.element == **element
+            }
+            (Value::BigInt(_), typeinfer::Type::BigInt)
+            | (Value::Boolean(_), typeinfer::Type::Boolean)
+            | (Value::Bytes(_), typeinfer::Type::Bytes)
+            | (Value::Char(_), typeinfer::Type::Char)
+            | (Value::Float(_), typeinfer::Type::Float)
+            | (Value::Integer(_), typeinfer::Type::Integer)
+            | (Value::Pid(_), typeinfer::Type::Pid)
+            | (Value::Str(_), typeinfer::Type::String)
+            | (Value::Timestamp(_), typeinfer::Type::Timestamp)
+            | (Value::Unit, typeinfer::Type::Unit) => true,
+            _ => false,
+        }
+    }
+
+    // Returns the parts in which two values differ, from left to right, which
+    // are as deep inside of them as the values have the same shape.
+    pub fn diff(&self, other: &Value) -> Vec<Difference> {
+        let mut differences = Vec::new();
+        self.diff_at(other, &mut String::new(), &mut differences);
+        differences
+    }
+
+    fn diff_at(&self, other: &Value, path: &mut String, differences: &mut Vec<Difference>) {
+        let len = path.len();
+        match (self, other) {
+            (Value::Datatype(typ, variant, v), Value::Datatype(other_typ, other_variant, w))
+                if typ == other_typ && variant == other_variant =>
+            {
+                path.push('.');
+                path.push_str(variant);
+                v.diff_at(w, path, differences);
+            }
+            (Value::Record(fields), Value::Record(other_fields))
+                if fields.len() == other_fields.len()
+                    && fields
+                        .iter()
+                        .all(|(id, _)| other_fields.iter().any(|(other_id, _)| id == other_id)) =>
+            {
+                for (id, v) in fields {
+                    if let Some((_, w)) = other_fields.iter().find(|(other_id, _)| id == other_id) {
+                        path.push('.');
+                        path.push_str(id);
+                        v.diff_at(w, path, differences);
+                        path.truncate(len);
+                    }
+                }
+            }
+            (Value::Array(elements), Value::Array(other_elements))
+            | (Value::Tuple(elements), Value::Tuple(other_elements))
+                if elements.len() == other_elements.len() =>
+            {
+                for (i, (v, w)) in elements.iter().zip(other_elements).enumerate() {
+                    path.push_str(&format!(".{}", i));
+                    v.diff_at(w, path, differences);
+                    path.truncate(len);
+                }
+            }
+            _ => {
+                if self != other {
+                    differences.push(Difference {
+                        path: path.to_string(),
+                        left: self.clone(),
+                        right: other.clone(),
+                    });
+                }
+            }
+        }
+        path.truncate(len);
+    }
+}
+
+// Hashes values with 64 bit FNV-1a over an encoding that does not depend on
+// the platform or on how the program was compiled, so that scripts can store
+// and compare hashes. Values are encoded as a tag for their kind followed by
+// their contents: integers, counts and the bits of floats as 8 little endian
+// bytes, strings as their length and UTF-8 bytes, and bigints as the string
+// of their decimal digits, and bytes as their length and contents. Floats that compare equal hash the same, so -0.0
+// is hashed as 0.0 and every NaN as the same NaN.
+struct StableHasher(u64);
+
+impl StableHasher {
+    fn new() -> StableHasher {
+        StableHasher(0xcbf29ce484222325)
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn string(&mut self, s: &str) {
+        self.u64(s.len() as u64);
+        self.bytes(s.as_bytes());
+    }
+
+    // Returns the value that could not be hashed, if there is one.
+    fn value<'a>(&mut self, value: &'a Value) -> Result<(), &'a Value> {
+        match value {
+            Value::Unit => self.bytes(&[0]),
+            Value::Boolean(b) => self.bytes(&[1, *b as u8]),
+            Value::Integer(i) => {
+                self.bytes(&[2]);
+                self.u64(*i as u64);
+            }
+            Value::Float(x) => {
+                let x = if *x == 0.0 {
+                    0.0
+                } else if x.is_nan() {
+                    f64::NAN
+                } else {
+                    *x
+                };
+                self.bytes(&[3]);
+                self.u64(x.to_bits());
+            }
+            Value::Str(s) => {
+                self.bytes(&[4]);
+                self.string(s);
+            }
+            Value::Tuple(elements) => {
+                self.bytes(&[5]);
+                self.u64(elements.len() as u64);
+                for element in elements {
+                    self.value(element)?;
+                }
+            }
+            Value::Record(fields) => {
+                self.bytes(&[6]);
+                self.u64(fields.len() as u64);
+                for (field, value) in fields {
+                    self.string(field);
+                    self.value(value)?;
+                }
+            }
+            Value::Datatype(typ, variant, value) => {
+                self.bytes(&[7]);
+                self.string(typ);
+                self.string(variant);
+                self.value(value)?;
+            }
+            Value::BigInt(n) => {
+                self.bytes(&[8]);
+                self.string(&n.to_string());
+            }
+            Value::Pid(pid) => {
+                self.bytes(&[9]);
+                self.u64(*pid as u64);
+            }
+            Value::Bytes(bytes) => {
+                self.bytes(&[10]);
+                self.u64(bytes.len() as u64);
+                self.bytes(bytes);
+            }
+            Value::Timestamp(t) => {
+                self.bytes(&[11]);
+                self.u64(*t as u64);
+            }
+            Value::Char(c) => {
+                self.bytes(&[12]);
+                self.u64(u64::from(*c));
+            }
+            Value::Array(elements) => {
+                self.bytes(&[13]);
+                self.u64(elements.len() as u64);
+                for element in elements {
+                    self.value(element)?;
+                }
+            }
+            Value::List(list) => {
+                self.bytes(&[15]);
+                self.u64(list.iter().count() as u64);
+                for element in list.iter() {
+                    self.value(element)?;
+                }
+            }
+            // As with memo caches, the hashes of the entries are summed so
+            // that the order they were inserted in does not matter.
+            Value::Map(map) => {
+                self.bytes(&[14]);
+                self.u64(map.len() as u64);
+                let mut sum: u64 = 0;
+                for (key, value) in map.iter() {
+                    let mut hasher = StableHasher::new();
+                    hasher.value(key)?;
+                    hasher.value(value)?;
+                    sum = sum.wrapping_add(hasher.0);
+                }
+                self.u64(sum);
+            }
+            Value::Channel(_)
+            | Value::Function(..)
+            | Value::Memo(..)
+            | Value::NativeFunction(_) => return Err(value),
+        }
+        Ok(())
+    }
+}
+
+// The result of running the virtual machine. A program that has yielded
+// continues from where it left off when it is run again.
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, PartialEq)]
+pub enum Status {
+    Finished(Value),
+    Yielded,
+    Breakpoint,
+}
+
+// Stops the virtual machine before it executes an instruction, either at each
+// one when stepping, or at the addresses and source lines with breakpoints.
+#[derive(Debug, Default)]
+pub struct Debugger {
+    pub breakpoints: HashSet<usize>,
+    pub lines: HashSet<usize>,
+    pub step: bool,
+    // Set when the virtual machine has stopped, so that the instruction it
+    // stopped at is executed when it is run again.
+    resumed: bool,
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger::default()
+    }
+}
+
+// Counts the instructions run for each source line and the time spent running
+// them. The time of an instruction is only known once the next one starts, so
+// it is added to its line then.
+// An entry in the symbol table, which records the name a function was defined
+// with and where its definition starts for each function entry ip. Lambdas
+// have no name, and builtins no position.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FunctionInfo {
+    pub name: Option<String>,
+    pub line: usize,
+    pub col: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct Profile {
+    pub lines: HashMap<usize, LineProfile>,
+    // The number of instructions run with each call stack, given as the entry
+    // ips of its functions from the outermost one in.
+    pub stacks: HashMap<Vec<usize>, u64>,
+    last: Option<(usize, Instant)>,
+    stack: Vec<usize>,
+    samples: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LineProfile {
+    pub count: u64,
+    pub time: Duration,
+}
+
+impl Profile {
+    pub fn new() -> Profile {
+        Profile::default()
+    }
+
+    fn record(&mut self, line: usize, callstack: &[(usize, Environment, usize, usize)]) {
+        let now = Instant::now();
+        if let Some((last, start)) = self.last {
+            self.lines.entry(last).or_default().time += now - start;
+        }
+        self.lines.entry(line).or_default().count += 1;
+        self.last = Some((line, now));
+        // A single instruction can only push, pop or replace the innermost
+        // frame, so the stack has changed if it has a different depth or a
+        // different innermost function.
+        let innermost = callstack.last().map(|frame| frame.0);
+        if self.stack.len() != callstack.len() || self.stack.last().copied() != innermost {
+            self.flush();
+            self.stack = callstack.iter().map(|frame| frame.0).collect();
+        }
+        self.samples += 1;
+    }
+
+    fn flush(&mut self) {
+        if self.samples > 0 {
+            *self.stacks.entry(self.stack.clone()).or_default() += self.samples;
+            self.samples = 0;
+        }
+    }
+
+    // Adds the time of the last instruction run, once the program finishes.
+    pub fn stop(&mut self) {
+        if let Some((last, start)) = self.last.take() {
+            self.lines.entry(last).or_default().time += start.elapsed();
+        }
+        self.flush();
+    }
+
+    // Gives the call stacks in the folded format read by flamegraph tools, a
+    // line for each stack with its functions separated by semicolons and
+    // followed by the number of instructions run in it. Functions are named
+    // by their entry ips. Let bodies and match cases have no name, and are
+    // left out so that they count towards the function they are in.
+    pub fn folded(&self, names: &HashMap<usize, String>) -> String {
+        let mut counts: HashMap<String, u64> = HashMap::new();
+        for (stack, count) in &self.stacks {
+            let mut line = "main".to_string();
+            for name in stack.iter().filter_map(|ip| names.get(ip)) {
+                line.push(';');
+                line.push_str(name);
+            }
+            *counts.entry(line).or_default() += count;
+        }
+        let mut lines: Vec<(String, u64)> = counts.into_iter().collect();
+        lines.sort();
+        lines
+            .iter()
+            .map(|(line, count)| format!("{} {}\n", line, count))
+            .collect()
+    }
+
+    // The time spent running all of the lines.
+    pub fn total(&self) -> Duration {
+        self.lines.values().map(|line| line.time).sum()
+    }
+}
+
+// A handler for the runtime errors of the code inside a try expression, with
+// the sizes of the stacks to unwind to when it is run. Only errors of the
+// code the host or a builtin called are caught by the handlers pushed by it.
+#[derive(Clone, Debug)]
+pub struct Catch {
+    pub ip: usize,
+    pub sp: usize,
+    pub depth: usize,
+    pub memo_calls: usize,
+    pub calls: usize,
+}
+
+// Whether an error is one that a program can recover from, which try catches
+// and which runs the functions deferred by the frames it leaves.
+fn catchable(err: &codegen::InterpreterError) -> bool {
+    match &err.kind {
+        codegen::ErrorKind::Runtime { kind, .. } => !matches!(
+            kind,
+            codegen::RuntimeError::Deadlock
+                | codegen::RuntimeError::EffectNotPermitted
+                | codegen::RuntimeError::Frozen
+                | codegen::RuntimeError::InvalidBytecode
+                | codegen::RuntimeError::InvalidStack
+                | codegen::RuntimeError::OutOfFuel
+                | codegen::RuntimeError::WouldBlock
+        ),
+        _ => false,
+    }
+}
+
+// The state of a process that is not currently running. The running process
+// keeps its state in the virtual machine itself.
+#[derive(Debug)]
+pub struct Process {
+    pub ip: usize,
+    pub stack: Vec<Value>,
+    pub callstack: Vec<(usize, Environment, usize, usize)>,
+    pub memo_calls: Vec<(usize, usize, Value)>,
+    pub catches: Vec<Catch>,
+    pub defers: Vec<(usize, Value)>,
+}
+
+// A function value held by the host. The function is kept by the virtual
+// machine that created the handle until the handle is released, and a handle
+// can only be used with that virtual machine.
+#[derive(Debug, PartialEq)]
+pub struct FunctionHandle {
+    vm: u64,
+    slot: usize,
+}
+
+// The instruction that failed in checked mode, given as the source of the
+// error. The stack is as the instruction left it, without the values it had
+// already taken.
+pub struct InvalidInstruction {
+    pub opcode: Opcode,
+    pub ip: usize,
+    pub stack: Vec<Value>,
+}
+
+impl fmt::Display for InvalidInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at {} with stack [", self.opcode, self.ip)?;
+        for i in 0..self.stack.len() {
+            write!(f, "{}", self.stack[i])?;
+            if i + 1 != self.stack.len() {
+                write!(f, ", ")?;
+            }
+        }
+        write!(f, "]")
+    }
+}
+
+impl fmt::Debug for InvalidInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "InvalidInstruction({})", self)
+    }
+}
+
+impl std::error::Error for InvalidInstruction {}
+
+static NEXT_VM_ID: AtomicU64 = AtomicU64::new(0);
+
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 10000;
+
+pub const DEFAULT_NET_TIMEOUT: Duration = Duration::from_secs(30);
+
+const PRELUDE: &str = include_str!("prelude.plover");
+
+// Integer arithmetic that overflows either fails, wraps around, or promotes
+// the result to a bigint so that integers behave as if they were unbounded.
+// Once promoted, results that fit are turned back into integers, so that
+// integers and bigints with the same value are never both around to compare
+// unequal.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Overflow {
+    Trap,
+    Wrap,
+    Promote,
+}
+
+pub struct VirtualMachine {
+    // The program is shared with the worker virtual machines used by
+    // par_map, which only ever read it.
+    pub instructions: Arc<Vec<Opcode>>,
+    pub symbols: Arc<HashMap<usize, FunctionInfo>>,
+    // The names of the identifiers the program refers to, see symbol::Names.
+    pub names: Arc<Names>,
+    pub ip: usize,
+    pub stack: Vec<Value>,
+    pub callstack: Vec<(usize, Environment, usize, usize)>,
+
+    pub env: Environment,
+    pub warnings: Vec<codegen::Warning>,
+    pub features: HashSet<String>,
+    // When set, programs are compiled with codegen::optimize.
+    pub optimize: bool,
+    pub allowed_effects: effects::Effects,
+    // When set, yield_now only returns control to the host once this has
+    // passed, so scripts can call it often and still run for a whole slice.
+    pub deadline: Option<Instant>,
+    // The number of instructions left to run, if it is limited.
+    fuel: Option<u64>,
+    // The number of frames the call stack can grow to before running the
+    // program fails with a stack overflow.
+    pub max_call_depth: usize,
+    // When set, running the program adds a warning the first time the call
+    // stack grows to this many frames or the fuel left falls to this much,
+    // so hosts can notice scripts that are close to the limits. Each is only
+    // warned about once until the virtual machine is reset.
+    pub call_depth_warning: Option<usize>,
+    pub fuel_warning: Option<u64>,
+    warned_call_depth: bool,
+    warned_fuel: bool,
+    // How long a network request may take before it fails with a timeout.
+    pub net_timeout: Duration,
+    // When set, instructions that find unexpected values on the stack fail
+    // with an error instead of panicking.
+    pub checked: bool,
+    // What integer arithmetic does when the result does not fit.
+    pub overflow: Overflow,
+    // How the host writes integers when it displays values, such as the
+    // results printed by the REPL.
+    pub int_format: IntFormat,
+    // When set, print adds the lines it prints to this instead of writing
+    // them to standard output, so that the host can capture them.
+    pub output: Option<String>,
+
+    // The result caches of memoized functions, and the memoized calls that
+    // are in progress as the depth of their frame, their cache and argument.
+    pub memos: Vec<HashMap<Value, Value>>,
+    pub memo_calls: Vec<(usize, usize, Value)>,
+
+    // The handlers of the try expressions being run, innermost last.
+    pub catches: Vec<Catch>,
+    // The functions scheduled by defer, most recent last, with the depth of
+    // the frame of the function that scheduled each one, or zero for the
+    // code of the program itself.
+    pub defers: Vec<(usize, Value)>,
+
+    // The functions registered by scripts for each event, in the order they
+    // were registered.
+    pub handlers: HashMap<String, Vec<Value>>,
+
+    // The addresses of the builtins that are only defined once the host
+    // enables them, such as http_get with enable_net.
+    gated_builtins: HashMap<String, usize>,
+    // The globals that the host has frozen, which programs can not redefine.
+    frozen: HashSet<Symbol>,
+
+    id: u64,
+    handles: Vec<Option<Value>>,
+    // The patterns used by the regex builtins, compiled the first time each
+    // one is used.
+    #[cfg(feature = "regex")]
+    regexes: HashMap<String, Arc<regex::Regex>>,
+    // The number of calls into the virtual machine that are in progress, made
+    // by builtins such as sort_by or by the host while a program is running.
+    calls: usize,
+
+    pub debugger: Option<Debugger>,
+    // When set, every instruction run is counted against its source line.
+    pub profile: Option<Profile>,
+
+    // Processes are scheduled cooperatively: the running process continues
+    // until it waits for a message or finishes. The main process is pid 0,
+    // and the program ends when it does.
+    pub pid: usize,
+    pub next_pid: usize,
+    pub processes: HashMap<usize, Process>,
+    pub runnable: VecDeque<usize>,
+    pub waiting: HashSet<usize>,
+    pub mailboxes: HashMap<usize, VecDeque<Value>>,
+
+    pub line: usize,
+    pub col: usize,
+}
+
+impl Default for VirtualMachine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VirtualMachine {
+    #[allow(clippy::cognitive_complexity)]
+    pub fn run(&mut self) -> Result<Status, codegen::InterpreterError> {
+        loop {
+            match self.execute() {
+                Ok(Some(status)) => return Ok(status),
+                Ok(None) => {}
+                Err(err) => return Err(self.unwind(0, err)),
+            }
+            if self.pid == 0 {
+                self.run_defers(0)?;
+                self.processes.clear();
+                self.runnable.clear();
+                self.waiting.clear();
+                self.mailboxes.retain(|pid, _| *pid == 0);
+                return match self.stack.pop() {
+                    Some(value) => Ok(Status::Finished(value)),
+                    None => Err(codegen::InterpreterError::new(
+                        codegen::ErrorKind::Runtime {
+                            kind: codegen::RuntimeError::StackUnderflow,
+                            backtrace: Vec::new(),
+                        },
+                        "Stack underflow.".to_string(),
+                        usize::MAX,
+                        usize::MAX,
+                    )),
+                };
+            }
+            self.mailboxes.remove(&self.pid);
+            self.schedule()?;
+        }
+    }
+
+    // Runs the current process until it finishes, returning the status if it
+    // yields to the host or stops at a breakpoint instead. Runtime errors
+    // inside a try expression continue with its handler.
+    fn execute(&mut self) -> Result<Option<Status>, codegen::InterpreterError> {
+        loop {
+            match self.execute_until_error() {
+                Err(err) => self.catch(err)?,
+                result => return result,
+            }
+        }
+    }
+
+    // Unwinds to the innermost handler of the code being run and passes it
+    // the message of the error, unless the error is not one that a program
+    // can recover from.
+    fn catch(&mut self, err: codegen::InterpreterError) -> Result<(), codegen::InterpreterError> {
+        match self.catches.last() {
+            Some(catch) if catchable(&err) && catch.calls == self.calls => {
+                let err = self.unwind(catch.depth + 1, err);
+                if !catchable(&err) {
+                    return Err(err);
+                }
+                let catch = self.catches.pop().unwrap();
+                self.stack.truncate(catch.sp);
+                self.callstack.truncate(catch.depth);
+                self.memo_calls.truncate(catch.memo_calls);
+                self.stack.push(Value::Str(err.err.to_string()));
+                self.ip = catch.ip;
+                Ok(())
+            }
+            _ => Err(err),
+        }
+    }
+
+    // Runs the functions deferred by the frames at or above a depth that an
+    // error is leaving. An error raised by one of them is reported in place
+    // of the one it was run for.
+    fn unwind(
+        &mut self,
+        depth: usize,
+        mut err: codegen::InterpreterError,
+    ) -> codegen::InterpreterError {
+        while catchable(&err) {
+            match self.run_defers(depth) {
+                Ok(()) => break,
+                Err(next) => err = next,
+            }
+        }
+        err
+    }
+
+    // Calls the functions deferred by the frames at or above a depth, the most
+    // recently deferred first.
+    fn run_defers(&mut self, depth: usize) -> Result<(), codegen::InterpreterError> {
+        while let Some((frame, _)) = self.defers.last() {
+            if *frame < depth {
+                break;
+            }
+            if let Some((_, fun)) = self.defers.pop() {
+                self.call(fun, Value::Unit)?;
+            }
+        }
+        Ok(())
+    }
+
+    #[allow(clippy::cognitive_complexity)]
+    fn execute_until_error(&mut self) -> Result<Option<Status>, codegen::InterpreterError> {
+        let _guard = panics::enter();
+        while self.ip < self.instructions.len() {
+            if let Some(fuel) = &mut self.fuel {
+                if *fuel == 0 {
+                    err!(self, OutOfFuel, "Out of fuel.")
+                }
+                *fuel -= 1;
+                if !self.warned_fuel && self.fuel_warning.is_some_and(|warning| *fuel <= warning) {
+                    self.warned_fuel = true;
+                    let msg = format!("Only {} instructions of fuel are left.", fuel);
+                    self.warn(msg);
+                }
+            }
+            if let Some(profile) = &mut self.profile {
+                // A srcpos instruction belongs to the line it starts.
+                let line = match self.instructions[self.ip] {
+                    Opcode::Srcpos(line, _) => line,
+                    _ => self.line,
+                };
+                profile.record(line, &self.callstack);
+            }
+            // The host can only step through the code it is running itself,
+            // not through the functions that builtins call.
+            if self.calls == 0 {
+                if let Some(debugger) = &mut self.debugger {
+                    if debugger.resumed {
+                        debugger.resumed = false;
+                    } else if debugger.step
+                        || debugger.breakpoints.contains(&self.ip)
+                        || matches!(&self.instructions[self.ip],
+                            Opcode::Srcpos(line, _) if debugger.lines.contains(line))
+                    {
+                        debugger.resumed = true;
+                        return Ok(Some(Status::Breakpoint));
+                    }
+                }
+            }
+            match &self.instructions[self.ip] {
+                Opcode::Add => match (self.stack.pop(), self.stack.pop()) {
+                    (Some(Value::Integer(x)), Some(Value::Integer(y))) => {
+                        let result = match x.checked_add(y) {
+                            Some(z) => Value::Integer(z),
+                            None => self.overflowed(x.wrapping_add(y), (x, y), |x, y| &x + &y)?,
+                        };
+                        self.stack.push(result);
+                    }
+                    (Some(Value::Float(x)), Some(Value::Float(y))) => {
+                        self.stack.push(Value::Float(x + y));
+                    }
+                    (Some(x), Some(y)) => match bigints(&x, &y) {
+                        Some((x, y)) => self.stack.push(self.bigint(&x + &y)),
+                        None => invalid!(self),
+                    },
+                    _ => invalid!(self),
+                },
+                Opcode::And => match self.stack.pop() {
+                    Some(Value::Boolean(x)) => match self.stack.pop() {
+                        Some(Value::Boolean(y)) => {
+                            self.stack.push(Value::Boolean(x && y));
+                        }
+                        _ => invalid!(self),
+                    },
+                    _ => invalid!(self),
+                },
+                Opcode::Arg(offset) => {
+                    let arg = match self.callstack.last() {
+                        Some((_, _, sp, _)) => self.stack.get(*sp + offset).cloned(),
+                        None => None,
+                    };
+                    match arg {
+                        Some(arg) => self.stack.push(arg),
+                        None => invalid!(self),
+                    }
+                }
+                Opcode::Bconst(b) => {
+                    self.stack.push(Value::Boolean(*b));
+                }
+                Opcode::Cconst(c) => {
+                    self.stack.push(Value::Char(*c));
+                }
+                Opcode::Call => match self.stack.pop() {
+                    Some(Value::Function(ip, env)) => {
+                        self.check_depth()?;
+                        let return_ip = self.ip;
+                        self.ip = ip;
+                        self.callstack
+                            .push((ip, env, self.stack.len() - 1, return_ip));
+                        continue;
+                    }
+                    Some(Value::Memo(cache, fun)) => {
+                        self.check_depth()?;
+                        if self.call_memo(cache, *fun)? {
+                            continue;
+                        }
+                    }
+                    Some(Value::NativeFunction(native)) => match self.stack.pop() {
+                        Some(arg) => {
+                            let result = self.call_native(&native, arg)?;
+                            self.stack.push(result);
+                        }
+                        None => invalid!(self),
+                    },
+                    _ => invalid!(self),
+                },
+                Opcode::Concat => match self.stack.pop() {
+                    Some(Value::Str(x)) => match self.stack.pop() {
+                        Some(Value::Str(y)) => {
+                            self.stack.push(Value::Str(x + &y));
+                        }
+                        _ => invalid!(self),
+                    },
+                    _ => invalid!(self),
+                },
+                Opcode::Div => match (self.stack.pop(), self.stack.pop()) {
+                    (Some(Value::Integer(x)), Some(Value::Integer(y))) => {
+                        if y == 0 {
+                            err!(self, DivisionByZero, "Division by zero.")
+                        }
+                        let result = match x.checked_div(y) {
+                            Some(z) => Value::Integer(z),
+                            None => self.overflowed(x.wrapping_div(y), (x, y), |x, y| {
+                                x.checked_div(&y).unwrap()
+                            })?,
+                        };
+                        self.stack.push(result);
+                    }
+                    (Some(Value::Float(x)), Some(Value::Float(y))) => {
+                        self.stack.push(Value::Float(x / y));
+                    }
+                    (Some(x), Some(y)) => match bigints(&x, &y) {
+                        Some((x, y)) => match x.checked_div(&y) {
+                            Some(z) => self.stack.push(self.bigint(z)),
+                            None => err!(self, DivisionByZero, "Division by zero."),
+                        },
+                        None => invalid!(self),
+                    },
+                    _ => invalid!(self),
+                },
+                Opcode::Dup => match self.stack.pop() {
+                    Some(v) => {
+                        self.stack.push(v.clone());
+                        self.stack.push(v);
+                    }
+                    _ => invalid!(self),
+                },
+                Opcode::Expect(typ) => match self.stack.last() {
+                    Some(value) if value.has_type(typ) => {}
+                    Some(value) => {
+                        err!(
+                            self,
+                            UnexpectedValue,
+                            format!(
+                                "Expected {} but found {}.",
+                                typ,
+                                value.display(&self.symbols)
+                            )
+                        )
+                    }
+                    None => invalid!(self),
+                },
+                Opcode::ExtVal => match self.stack.pop() {
+                    Some(Value::Datatype(_, _, v)) => {
+                        self.stack.push(*v);
+                    }
+                    Some(Value::List(list)) => match (list.head(), list.tail()) {
+                        (Some(head), Some(tail)) => self
+                            .stack
+                            .push(Value::Tuple(vec![head.clone(), Value::List(tail.clone())])),
+                        _ => invalid!(self),
+                    },
+                    _ => invalid!(self),
+                },
+                Opcode::Equal => match self.stack.pop() {
+                    Some(x) => match self.stack.pop() {
+                        Some(y) => {
+                            self.stack.push(Value::Boolean(x == y));
+                        }
+                        _ => invalid!(self),
+                    },
+                    _ => invalid!(self),
+                },
+                Opcode::Dconst(typ, ctor) => match self.stack.pop() {
+                    Some(value) => {
+                        self.stack.push(Value::Datatype(
+                            typ.to_string(),
+                            ctor.to_string(),
+                            Box::new(value),
+                        ));
+                    }
+                    _ => invalid!(self),
+                },
+                Opcode::Fconst(id, ip, upvalues) => {
+                    let len = self.callstack.len();
+                    let outer = if len > 0 {
+                        &self.callstack[len - 1].1
+                    } else {
+                        &self.env
+                    };
+                    let mut values = HashMap::new();
+                    if let Some((ident, ip)) = outer.fun {
+                        values.insert(ident, Value::Function(ip, outer.clone()));
+                    }
+                    for (ident, ip) in &outer.group {
+                        let value = Value::Function(*ip, outer.rebuilt(*ident, *ip));
+                        values.insert(*ident, value);
+                    }
+                    for upvalue in upvalues {
+                        if let Some((_, _, sp, _)) = self.callstack.last() {
+                            let id = upvalue.0;
+                            let offset = (upvalue.1).0;
+                            let value = match self.stack.get(*sp + offset) {
+                                Some(value) => value.clone(),
+                                None => invalid!(self),
+                            };
+                            values.insert(*id, value);
+                        }
+                    }
+                    let mut env = outer.extended(values);
+                    if let Some(id) = id {
+                        env.fun = Some((*id, *ip));
+                    }
+                    self.stack.push(Value::Function(*ip, env));
+                }
+                Opcode::GetEnv(id) => {
+                    let len = self.callstack.len();
+                    let env = if len > 0 {
+                        &self.callstack[len - 1].1
+                    } else {
+                        &self.env
+                    };
+                    match env.get(id) {
+                        Some(x) => {
+                            self.stack.push(x.clone());
+                        }
+                        None => {
+                            if let Some((ident, ip)) = &env.fun {
+                                if id == ident {
+                                    self.stack.push(Value::Function(*ip, env.clone()));
+                                    self.ip += 1;
+                                    continue;
+                                }
+                            }
+                            match env.group.iter().find(|(ident, _)| id == ident) {
+                                Some((ident, ip)) => {
+                                    let value = Value::Function(*ip, env.rebuilt(*ident, *ip));
+                                    self.stack.push(value);
+                                }
+                                None => invalid!(self),
+                            }
+                        }
+                    }
+                }
+                Opcode::Greater => match (self.stack.pop(), self.stack.pop()) {
+                    (Some(Value::Integer(x)), Some(Value::Integer(y))) => {
+                        self.stack.push(Value::Boolean(x > y));
+                    }
+                    (Some(Value::Float(x)), Some(Value::Float(y))) => {
+                        self.stack.push(Value::Boolean(x > y));
+                    }
+                    (Some(Value::Char(x)), Some(Value::Char(y))) => {
+                        self.stack.push(Value::Boolean(x > y));
+                    }
+                    (Some(x), Some(y)) => match bigints(&x, &y) {
+                        Some((x, y)) => self.stack.push(Value::Boolean(x > y)),
+                        None => invalid!(self),
+                    },
+                    _ => invalid!(self),
+                },
+                Opcode::GreaterEqual => match (self.stack.pop(), self.stack.pop()) {
+                    (Some(Value::Integer(x)), Some(Value::Integer(y))) => {
+                        self.stack.push(Value::Boolean(x >= y));
+                    }
+                    (Some(Value::Float(x)), Some(Value::Float(y))) => {
+                        self.stack.push(Value::Boolean(x >= y));
+                    }
+                    (Some(Value::Char(x)), Some(Value::Char(y))) => {
+                        self.stack.push(Value::Boolean(x >= y));
+                    }
+                    (Some(x), Some(y)) => match bigints(&x, &y) {
+                        Some((x, y)) => self.stack.push(Value::Boolean(x >= y)),
+                        None => invalid!(self),
+                    },
+                    _ => invalid!(self),
+                },
+                Opcode::Iconst(i) => {
+                    self.stack.push(Value::Integer(*i));
+                }
+                Opcode::Nconst(n) => {
+                    self.stack.push(self.bigint(n.clone()));
+                }
                 Opcode::Jmp(offset) => {
                     self.ip = (self.ip as i64 + offset) as usize;
                     continue;
                 }
-                Opcode::Jz(offset) => match self.stack.pop() {
-                    Some(Value::Boolean(v)) => {
-                        if !v {
-                            self.ip = (self.ip as i64 + offset) as usize;
+                Opcode::Jz(offset) => match self.stack.pop() {
+                    Some(Value::Boolean(v)) => {
+                        if !v {
+                            self.ip = (self.ip as i64 + offset) as usize;
+                            continue;
+                        }
+                    }
+                    _ => invalid!(self),
+                },
+                Opcode::Jnz(offset) => match self.stack.pop() {
+                    Some(Value::Boolean(v)) => {
+                        if v {
+                            self.ip = (self.ip as i64 + offset) as usize;
+                            continue;
+                        }
+                    }
+                    _ => invalid!(self),
+                },
+                Opcode::Less => match (self.stack.pop(), self.stack.pop()) {
+                    (Some(Value::Integer(x)), Some(Value::Integer(y))) => {
+                        self.stack.push(Value::Boolean(x < y));
+                    }
+                    (Some(Value::Float(x)), Some(Value::Float(y))) => {
+                        self.stack.push(Value::Boolean(x < y));
+                    }
+                    (Some(Value::Char(x)), Some(Value::Char(y))) => {
+                        self.stack.push(Value::Boolean(x < y));
+                    }
+                    (Some(x), Some(y)) => match bigints(&x, &y) {
+                        Some((x, y)) => self.stack.push(Value::Boolean(x < y)),
+                        None => invalid!(self),
+                    },
+                    _ => invalid!(self),
+                },
+                Opcode::LessEqual => match (self.stack.pop(), self.stack.pop()) {
+                    (Some(Value::Integer(x)), Some(Value::Integer(y))) => {
+                        self.stack.push(Value::Boolean(x <= y));
+                    }
+                    (Some(Value::Float(x)), Some(Value::Float(y))) => {
+                        self.stack.push(Value::Boolean(x <= y));
+                    }
+                    (Some(Value::Char(x)), Some(Value::Char(y))) => {
+                        self.stack.push(Value::Boolean(x <= y));
+                    }
+                    (Some(x), Some(y)) => match bigints(&x, &y) {
+                        Some((x, y)) => self.stack.push(Value::Boolean(x <= y)),
+                        None => invalid!(self),
+                    },
+                    _ => invalid!(self),
+                },
+                Opcode::Mod => match (self.stack.pop(), self.stack.pop()) {
+                    (Some(Value::Integer(x)), Some(Value::Integer(y))) => {
+                        if y == 0 {
+                            err!(self, DivisionByZero, "Division by zero.")
+                        }
+                        let result = match x.checked_rem(y) {
+                            Some(z) => Value::Integer(z),
+                            None => self.overflowed(x.wrapping_rem(y), (x, y), |x, y| {
+                                x.checked_rem(&y).unwrap()
+                            })?,
+                        };
+                        self.stack.push(result);
+                    }
+                    (Some(Value::Float(x)), Some(Value::Float(y))) => {
+                        self.stack.push(Value::Float(x % y));
+                    }
+                    (Some(x), Some(y)) => match bigints(&x, &y) {
+                        Some((x, y)) => match x.checked_rem(&y) {
+                            Some(z) => self.stack.push(self.bigint(z)),
+                            None => err!(self, DivisionByZero, "Division by zero."),
+                        },
+                        None => invalid!(self),
+                    },
+                    _ => invalid!(self),
+                },
+                Opcode::Pow => match (self.stack.pop(), self.stack.pop()) {
+                    (Some(Value::Integer(x)), Some(Value::Integer(y))) => {
+                        if y < 0 {
+                            err!(self, InvalidExponent, "Negative exponent.")
+                        }
+                        let result = match checked_pow(x, y) {
+                            Some(z) => Value::Integer(z),
+                            None => self.overflowed(wrapping_pow(x, y), (x, y), |x, y| {
+                                x.pow(y.to_i64().unwrap() as u64)
+                            })?,
+                        };
+                        self.stack.push(result);
+                    }
+                    (Some(Value::Float(x)), Some(Value::Float(y))) => {
+                        self.stack.push(Value::Float(x.powf(y)));
+                    }
+                    (Some(x), Some(y)) => match bigints(&x, &y) {
+                        Some((x, y)) => match y.to_i64() {
+                            Some(y) if y < 0 => {
+                                err!(self, InvalidExponent, "Negative exponent.")
+                            }
+                            Some(y) => self.stack.push(self.bigint(x.pow(y as u64))),
+                            None if y < BigInt::from(0) => {
+                                err!(self, InvalidExponent, "Negative exponent.")
+                            }
+                            None => err!(self, InvalidExponent, "Exponent too large."),
+                        },
+                        None => invalid!(self),
+                    },
+                    _ => invalid!(self),
+                },
+                Opcode::Mul => match (self.stack.pop(), self.stack.pop()) {
+                    (Some(Value::Integer(x)), Some(Value::Integer(y))) => {
+                        let result = match x.checked_mul(y) {
+                            Some(z) => Value::Integer(z),
+                            None => self.overflowed(x.wrapping_mul(y), (x, y), |x, y| &x * &y)?,
+                        };
+                        self.stack.push(result);
+                    }
+                    (Some(Value::Float(x)), Some(Value::Float(y))) => {
+                        self.stack.push(Value::Float(x * y));
+                    }
+                    (Some(x), Some(y)) => match bigints(&x, &y) {
+                        Some((x, y)) => self.stack.push(self.bigint(&x * &y)),
+                        None => invalid!(self),
+                    },
+                    _ => invalid!(self),
+                },
+                Opcode::NotEqual => match self.stack.pop() {
+                    Some(x) => match self.stack.pop() {
+                        Some(y) => {
+                            self.stack.push(Value::Boolean(x != y));
+                        }
+                        _ => invalid!(self),
+                    },
+                    _ => invalid!(self),
+                },
+                Opcode::Not => match self.stack.pop() {
+                    Some(Value::Boolean(x)) => {
+                        self.stack.push(Value::Boolean(!x));
+                    }
+                    _ => invalid!(self),
+                },
+                Opcode::On => match self.stack.pop() {
+                    Some(Value::Tuple(mut args)) if args.len() == 2 => {
+                        let handler = args.pop().unwrap();
+                        match (args.pop(), handler) {
+                            (Some(Value::Str(event)), handler)
+                                if matches!(
+                                    handler,
+                                    Value::Function(..)
+                                        | Value::Memo(..)
+                                        | Value::NativeFunction(..)
+                                ) =>
+                            {
+                                self.handlers.entry(event).or_default().push(handler);
+                                self.stack.push(Value::Unit);
+                            }
+                            (Some(event), handler) => err!(
+                                self,
+                                UnexpectedValue,
+                                format!(
+                                    "Expected event name and function but found ({}, {}).",
+                                    event, handler
+                                )
+                            ),
+                            (None, _) => invalid!(self),
+                        }
+                    }
+                    Some(value) => err!(
+                        self,
+                        UnexpectedValue,
+                        format!("Expected event name and function but found {}.", value)
+                    ),
+                    None => invalid!(self),
+                },
+                Opcode::Or => match self.stack.pop() {
+                    Some(Value::Boolean(x)) => match self.stack.pop() {
+                        Some(Value::Boolean(y)) => {
+                            self.stack.push(Value::Boolean(x || y));
+                        }
+                        _ => invalid!(self),
+                    },
+                    _ => invalid!(self),
+                },
+                Opcode::Pop => match self.stack.pop() {
+                    Some(_) => {}
+                    _ => invalid!(self),
+                },
+                Opcode::Memo => match self.stack.pop() {
+                    Some(Value::Function(ip, env)) => {
+                        self.memos.push(HashMap::new());
+                        self.stack.push(Value::Memo(
+                            self.memos.len() - 1,
+                            Box::new(Value::Function(ip, env)),
+                        ));
+                    }
+                    Some(Value::Memo(cache, fun)) => {
+                        self.stack.push(Value::Memo(cache, fun));
+                    }
+                    Some(value) => err!(
+                        self,
+                        UnexpectedValue,
+                        format!("Expected function but found {}.", value)
+                    ),
+                    None => invalid!(self),
+                },
+                Opcode::ParMap => match self.stack.pop() {
+                    Some(Value::Tuple(args)) if args.len() == 2 => match &args[1] {
+                        Value::Array(elements) => {
+                            let results = self.par_map(&args[0], elements)?;
+                            self.stack.push(Value::Array(results));
+                        }
+                        _ => invalid!(self),
+                    },
+                    _ => invalid!(self),
+                },
+                Opcode::Print => {
+                    self.perform(effects::Effect::Io)?;
+                    let line = match self.stack.pop() {
+                        Some(Value::Str(s)) => s.to_string(),
+                        Some(value) => value.to_string(),
+                        None => invalid!(self),
+                    };
+                    match &mut self.output {
+                        Some(output) => {
+                            output.push_str(&line);
+                            output.push('\n');
+                        }
+                        None => {
+                            if let Err(e) = writeln!(io::stdout(), "{}", line) {
+                                err!(
+                                    self,
+                                    OutputError,
+                                    format!("Unable to write to standard output: {}.", e)
+                                );
+                            }
+                        }
+                    }
+                    self.stack.push(Value::Unit);
+                }
+                Opcode::Show => match self.stack.pop() {
+                    Some(Value::Str(s)) => self.stack.push(Value::Str(s)),
+                    Some(Value::Char(c)) => self.stack.push(Value::Str(c.to_string())),
+                    Some(value) => {
+                        let s = value.display(&self.symbols).to_string();
+                        self.stack.push(Value::Str(s))
+                    }
+                    None => invalid!(self),
+                },
+                Opcode::Diff => match self.stack.pop() {
+                    Some(Value::Tuple(args)) if args.len() == 2 => {
+                        let result = match args[0].diff(&args[1]).into_iter().next() {
+                            Some(difference) => Value::Datatype(
+                                "Option".to_string(),
+                                "Some".to_string(),
+                                Box::new(Value::Tuple(vec![
+                                    Value::Str(difference.path),
+                                    Value::Str(difference.left.to_string()),
+                                    Value::Str(difference.right.to_string()),
+                                ])),
+                            ),
+                            None => Value::Datatype(
+                                "Option".to_string(),
+                                "None".to_string(),
+                                Box::new(Value::Unit),
+                            ),
+                        };
+                        self.stack.push(result);
+                    }
+                    _ => invalid!(self),
+                },
+                Opcode::Abs => match self.stack.pop() {
+                    Some(Value::Integer(x)) => {
+                        let result = match x.checked_abs() {
+                            Some(z) => Value::Integer(z),
+                            None => self.overflowed(x.wrapping_abs(), (x, 0), |x, _| x.abs())?,
+                        };
+                        self.stack.push(result);
+                    }
+                    Some(Value::BigInt(x)) => self.stack.push(self.bigint(x.abs())),
+                    _ => invalid!(self),
+                },
+                Opcode::HashValue => match self.stack.pop() {
+                    Some(value) => {
+                        let mut hasher = StableHasher::new();
+                        if let Err(mut unhashable) = hasher.value(&value) {
+                            if let Value::Memo(_, fun) = unhashable {
+                                unhashable = fun;
+                            }
+                            // Functions are named by the symbol table rather
+                            // than shown with their address.
+                            let what = match unhashable {
+                                Value::Function(ip, _) => {
+                                    match self.symbols.get(ip).and_then(|info| info.name.as_ref()) {
+                                        Some(name) => format!("function {}", name),
+                                        None => "a function".to_string(),
+                                    }
+                                }
+                                Value::NativeFunction(native) => {
+                                    format!("function {}", native.name)
+                                }
+                                _ => "a channel".to_string(),
+                            };
+                            err!(self, UnexpectedValue, format!("Can not hash {}.", what))
+                        }
+                        self.stack.push(Value::Integer(hasher.0 as i64));
+                    }
+                    None => invalid!(self),
+                },
+                Opcode::Neg => match self.stack.pop() {
+                    Some(Value::Integer(x)) => {
+                        let result = match x.checked_neg() {
+                            Some(z) => Value::Integer(z),
+                            None => self.overflowed(x.wrapping_neg(), (x, 0), |x, _| -&x)?,
+                        };
+                        self.stack.push(result);
+                    }
+                    Some(Value::Float(x)) => self.stack.push(Value::Float(-x)),
+                    Some(Value::BigInt(x)) => self.stack.push(self.bigint(-&x)),
+                    _ => invalid!(self),
+                },
+                Opcode::BytesLength => match self.stack.pop() {
+                    Some(Value::Bytes(bytes)) => {
+                        self.stack.push(Value::Integer(bytes.len() as i64))
+                    }
+                    _ => invalid!(self),
+                },
+                Opcode::ByteAt => match self.stack.pop() {
+                    Some(Value::Tuple(pair)) if pair.len() == 2 => match (&pair[0], &pair[1]) {
+                        (Value::Bytes(bytes), Value::Integer(i)) => {
+                            match usize::try_from(*i).ok().and_then(|i| bytes.get(i)) {
+                                Some(b) => self.stack.push(Value::Integer(i64::from(*b))),
+                                None => err!(
+                                    self,
+                                    IndexOutOfRange,
+                                    format!(
+                                        "Index {} is out of range for {} bytes.",
+                                        i,
+                                        bytes.len()
+                                    )
+                                ),
+                            }
+                        }
+                        _ => invalid!(self),
+                    },
+                    _ => invalid!(self),
+                },
+                Opcode::BytesSlice => match self.stack.pop() {
+                    Some(Value::Tuple(args)) if args.len() == 3 => {
+                        match (&args[0], &args[1], &args[2]) {
+                            (Value::Bytes(bytes), Value::Integer(start), Value::Integer(end)) => {
+                                let range = usize::try_from(*start)
+                                    .ok()
+                                    .zip(usize::try_from(*end).ok())
+                                    .and_then(|(start, end)| bytes.get(start..end));
+                                match range {
+                                    Some(slice) => self.stack.push(Value::Bytes(slice.to_vec())),
+                                    None => err!(
+                                        self,
+                                        IndexOutOfRange,
+                                        format!(
+                                            "Range {} to {} is out of range for {} bytes.",
+                                            start,
+                                            end,
+                                            bytes.len()
+                                        )
+                                    ),
+                                }
+                            }
+                            _ => invalid!(self),
+                        }
+                    }
+                    _ => invalid!(self),
+                },
+                Opcode::StringToBytes => match self.stack.pop() {
+                    Some(Value::Str(s)) => self.stack.push(Value::Bytes(s.into_bytes())),
+                    _ => invalid!(self),
+                },
+                Opcode::BytesToString => match self.stack.pop() {
+                    Some(Value::Bytes(bytes)) => match String::from_utf8(bytes) {
+                        Ok(s) => self.stack.push(Value::Str(s)),
+                        Err(e) => err!(
+                            self,
+                            UnexpectedValue,
+                            format!(
+                                "Invalid UTF-8 in bytes at offset {}.",
+                                e.utf8_error().valid_up_to()
+                            )
+                        ),
+                    },
+                    _ => invalid!(self),
+                },
+                Opcode::CharAt => match self.stack.pop() {
+                    Some(Value::Tuple(pair)) if pair.len() == 2 => match (&pair[0], &pair[1]) {
+                        (Value::Str(s), Value::Integer(i)) => {
+                            match usize::try_from(*i).ok().and_then(|i| s.chars().nth(i)) {
+                                Some(c) => self.stack.push(Value::Char(c)),
+                                None => err!(
+                                    self,
+                                    IndexOutOfRange,
+                                    format!(
+                                        "Index {} is out of range for {} characters.",
+                                        i,
+                                        s.chars().count()
+                                    )
+                                ),
+                            }
+                        }
+                        _ => invalid!(self),
+                    },
+                    _ => invalid!(self),
+                },
+                Opcode::CharCode => match self.stack.pop() {
+                    Some(Value::Char(c)) => {
+                        self.stack.push(Value::Integer(i64::from(u32::from(c))))
+                    }
+                    _ => invalid!(self),
+                },
+                Opcode::CharFromCode => match self.stack.pop() {
+                    Some(Value::Integer(code)) => {
+                        match u32::try_from(code).ok().and_then(char::from_u32) {
+                            Some(c) => self.stack.push(Value::Char(c)),
+                            None => err!(
+                                self,
+                                UnexpectedValue,
+                                format!("Invalid character code {}.", code)
+                            ),
+                        }
+                    }
+                    _ => invalid!(self),
+                },
+                Opcode::FoldChars => match self.stack.pop() {
+                    Some(Value::Tuple(mut args)) if args.len() == 3 => {
+                        match (args.pop(), args.pop(), args.pop()) {
+                            (Some(Value::Str(s)), Some(mut acc), Some(fun)) => {
+                                for c in s.chars() {
+                                    let arg = Value::Tuple(vec![acc, Value::Char(c)]);
+                                    acc = self.call(fun.clone(), arg)?;
+                                }
+                                self.stack.push(acc);
+                            }
+                            _ => invalid!(self),
+                        }
+                    }
+                    _ => invalid!(self),
+                },
+                Opcode::Length => match self.stack.pop() {
+                    Some(Value::Str(s)) => {
+                        self.stack.push(Value::Integer(s.chars().count() as i64))
+                    }
+                    Some(Value::Array(elements)) => {
+                        self.stack.push(Value::Integer(elements.len() as i64))
+                    }
+                    _ => invalid!(self),
+                },
+                Opcode::Join => match self.stack.pop() {
+                    Some(Value::Tuple(mut pair)) if pair.len() == 2 => {
+                        match (pair.pop(), pair.pop()) {
+                            (Some(Value::Str(y)), Some(Value::Str(x))) => {
+                                self.stack.push(Value::Str(x + &y))
+                            }
+                            (Some(Value::Array(y)), Some(Value::Array(mut x))) => {
+                                x.extend(y);
+                                self.stack.push(Value::Array(x))
+                            }
+                            _ => invalid!(self),
+                        }
+                    }
+                    _ => invalid!(self),
+                },
+                Opcode::ArrayAt => match self.stack.pop() {
+                    Some(Value::Tuple(mut pair)) if pair.len() == 2 => {
+                        match (pair.pop(), pair.pop()) {
+                            (Some(Value::Integer(i)), Some(Value::Array(elements))) => {
+                                let len = elements.len();
+                                match usize::try_from(i)
+                                    .ok()
+                                    .and_then(|i| elements.into_iter().nth(i))
+                                {
+                                    Some(element) => self.stack.push(element),
+                                    None => err!(
+                                        self,
+                                        IndexOutOfRange,
+                                        format!(
+                                            "Index {} is out of range for {} elements.",
+                                            i, len
+                                        )
+                                    ),
+                                }
+                            }
+                            _ => invalid!(self),
+                        }
+                    }
+                    _ => invalid!(self),
+                },
+                Opcode::ArrayPush => match self.stack.pop() {
+                    Some(Value::Tuple(mut pair)) if pair.len() == 2 => {
+                        match (pair.pop(), pair.pop()) {
+                            (Some(element), Some(Value::Array(mut elements))) => {
+                                elements.push(element);
+                                self.stack.push(Value::Array(elements))
+                            }
+                            _ => invalid!(self),
+                        }
+                    }
+                    _ => invalid!(self),
+                },
+                Opcode::MapInsert => match self.stack.pop() {
+                    Some(Value::Tuple(mut args)) if args.len() == 3 => {
+                        match (args.pop(), args.pop(), args.pop()) {
+                            (Some(value), Some(key), Some(Value::Map(mut map))) => {
+                                map.insert(key, value);
+                                self.stack.push(Value::Map(map))
+                            }
+                            _ => invalid!(self),
+                        }
+                    }
+                    _ => invalid!(self),
+                },
+                Opcode::MapLookup => match self.stack.pop() {
+                    Some(Value::Tuple(args)) if args.len() == 2 => match (&args[0], &args[1]) {
+                        (Value::Map(map), key) => match map.get(key) {
+                            Some(value) => self.stack.push(value.clone()),
+                            None => {
+                                err!(self, KeyNotFound, format!("Key {} is not in the map.", key))
+                            }
+                        },
+                        _ => invalid!(self),
+                    },
+                    _ => invalid!(self),
+                },
+                Opcode::MapRemove => match self.stack.pop() {
+                    Some(Value::Tuple(mut pair)) if pair.len() == 2 => {
+                        match (pair.pop(), pair.pop()) {
+                            (Some(key), Some(Value::Map(mut map))) => {
+                                map.remove(&key);
+                                self.stack.push(Value::Map(map))
+                            }
+                            _ => invalid!(self),
+                        }
+                    }
+                    _ => invalid!(self),
+                },
+                Opcode::MapKeys => match self.stack.pop() {
+                    Some(Value::Map(map)) => {
+                        let keys = map.iter().map(|(key, _)| key.clone()).collect();
+                        self.stack.push(Value::Array(keys))
+                    }
+                    _ => invalid!(self),
+                },
+                Opcode::MapHasKey => match self.stack.pop() {
+                    Some(Value::Tuple(args)) if args.len() == 2 => match (&args[0], &args[1]) {
+                        (Value::Map(map), key) => {
+                            self.stack.push(Value::Boolean(map.get(key).is_some()))
+                        }
+                        _ => invalid!(self),
+                    },
+                    _ => invalid!(self),
+                },
+                Opcode::Cons => match (self.stack.pop(), self.stack.pop()) {
+                    (Some(head), Some(Value::List(tail))) => {
+                        self.stack.push(Value::List(List::cons(head, tail)))
+                    }
+                    _ => invalid!(self),
+                },
+                Opcode::ListHead => match self.stack.pop() {
+                    Some(Value::List(list)) => match list.head() {
+                        Some(head) => self.stack.push(head.clone()),
+                        None => err!(self, EmptyList, "Head of an empty list."),
+                    },
+                    _ => invalid!(self),
+                },
+                Opcode::ListTail => match self.stack.pop() {
+                    Some(Value::List(list)) => match list.tail() {
+                        Some(tail) => self.stack.push(Value::List(tail.clone())),
+                        None => err!(self, EmptyList, "Tail of an empty list."),
+                    },
+                    _ => invalid!(self),
+                },
+                Opcode::Exit => match self.stack.pop() {
+                    Some(Value::Integer(status)) => match i32::try_from(status) {
+                        Ok(status) => {
+                            return Err(codegen::InterpreterError::new(
+                                codegen::ErrorKind::Exit(status),
+                                format!("Exited with status {}.", status),
+                                self.line,
+                                self.col,
+                            ));
+                        }
+                        Err(_) => err!(
+                            self,
+                            UnexpectedValue,
+                            format!("Exit status {} is out of range.", status)
+                        ),
+                    },
+                    _ => invalid!(self),
+                },
+                Opcode::Abort => match self.stack.pop() {
+                    Some(Value::Str(msg)) => err!(self, Aborted, msg),
+                    _ => invalid!(self),
+                },
+                Opcode::Defer => match self.stack.pop() {
+                    Some(fun) => {
+                        // Functions deferred in a let body or match case
+                        // belong to the function it is in.
+                        let mut depth = self.callstack.len();
+                        while depth > 0
+                            && matches!(
+                                self.instructions.get(self.callstack[depth - 1].0),
+                                Some(Opcode::Scope)
+                            )
+                        {
+                            depth -= 1;
+                        }
+                        self.defers.push((depth, fun));
+                    }
+                    None => invalid!(self),
+                },
+                Opcode::Range => match (self.stack.pop(), self.stack.pop()) {
+                    (Some(Value::Integer(start)), Some(Value::Integer(end))) => self
+                        .stack
+                        .push(Value::Array((start..end).map(Value::Integer).collect())),
+                    _ => invalid!(self),
+                },
+                Opcode::ArrayMap => match self.stack.pop() {
+                    Some(Value::Tuple(mut args)) if args.len() == 2 => {
+                        match (args.pop(), args.pop()) {
+                            (Some(Value::Array(elements)), Some(fun)) => {
+                                let mut results = Vec::with_capacity(elements.len());
+                                for element in elements {
+                                    results.push(self.call(fun.clone(), element)?);
+                                }
+                                self.stack.push(Value::Array(results));
+                            }
+                            _ => invalid!(self),
+                        }
+                    }
+                    _ => invalid!(self),
+                },
+                Opcode::ArrayFilter => match self.stack.pop() {
+                    Some(Value::Tuple(mut args)) if args.len() == 2 => {
+                        match (args.pop(), args.pop()) {
+                            (Some(Value::Array(elements)), Some(fun)) => {
+                                let mut results = Vec::new();
+                                for element in elements {
+                                    match self.call(fun.clone(), element.clone())? {
+                                        Value::Boolean(true) => results.push(element),
+                                        Value::Boolean(false) => {}
+                                        _ => invalid!(self),
+                                    }
+                                }
+                                self.stack.push(Value::Array(results));
+                            }
+                            _ => invalid!(self),
+                        }
+                    }
+                    _ => invalid!(self),
+                },
+                Opcode::ArrayFoldl => match self.stack.pop() {
+                    Some(Value::Tuple(mut args)) if args.len() == 3 => {
+                        match (args.pop(), args.pop(), args.pop()) {
+                            (Some(Value::Array(elements)), Some(mut acc), Some(fun)) => {
+                                for element in elements {
+                                    let arg = Value::Tuple(vec![acc, element]);
+                                    acc = self.call(fun.clone(), arg)?;
+                                }
+                                self.stack.push(acc);
+                            }
+                            _ => invalid!(self),
+                        }
+                    }
+                    _ => invalid!(self),
+                },
+                Opcode::ArrayFoldr => match self.stack.pop() {
+                    Some(Value::Tuple(mut args)) if args.len() == 3 => {
+                        match (args.pop(), args.pop(), args.pop()) {
+                            (Some(Value::Array(elements)), Some(mut acc), Some(fun)) => {
+                                for element in elements.into_iter().rev() {
+                                    let arg = Value::Tuple(vec![element, acc]);
+                                    acc = self.call(fun.clone(), arg)?;
+                                }
+                                self.stack.push(acc);
+                            }
+                            _ => invalid!(self),
+                        }
+                    }
+                    _ => invalid!(self),
+                },
+                Opcode::StringSlice => match self.stack.pop() {
+                    Some(Value::Tuple(args)) if args.len() == 3 => {
+                        match (&args[0], &args[1], &args[2]) {
+                            (Value::Str(s), Value::Integer(start), Value::Integer(end)) => {
+                                let len = s.chars().count();
+                                let range = usize::try_from(*start)
+                                    .ok()
+                                    .zip(usize::try_from(*end).ok())
+                                    .filter(|(start, end)| start <= end && *end <= len);
+                                match range {
+                                    Some((start, end)) => self.stack.push(Value::Str(
+                                        s.chars().skip(start).take(end - start).collect(),
+                                    )),
+                                    None => err!(
+                                        self,
+                                        IndexOutOfRange,
+                                        format!(
+                                            "Range {} to {} is out of range for {} characters.",
+                                            start, end, len
+                                        )
+                                    ),
+                                }
+                            }
+                            _ => invalid!(self),
+                        }
+                    }
+                    _ => invalid!(self),
+                },
+                Opcode::StringChars => match self.stack.pop() {
+                    Some(Value::Str(s)) => self
+                        .stack
+                        .push(Value::Array(s.chars().map(Value::Char).collect())),
+                    _ => invalid!(self),
+                },
+                Opcode::StringByteLength => match self.stack.pop() {
+                    Some(Value::Str(s)) => self.stack.push(Value::Integer(s.len() as i64)),
+                    _ => invalid!(self),
+                },
+                Opcode::StringByteSlice => match self.stack.pop() {
+                    Some(Value::Tuple(args)) if args.len() == 3 => {
+                        match (&args[0], &args[1], &args[2]) {
+                            (Value::Str(s), Value::Integer(start), Value::Integer(end)) => {
+                                let range = usize::try_from(*start)
+                                    .ok()
+                                    .zip(usize::try_from(*end).ok())
+                                    .and_then(|(start, end)| s.get(start..end));
+                                match range {
+                                    Some(slice) => self.stack.push(Value::Str(slice.to_string())),
+                                    None => err!(
+                                        self,
+                                        IndexOutOfRange,
+                                        format!(
+                                            "Range {} to {} is out of range for {} bytes or splits a character.",
+                                            start,
+                                            end,
+                                            s.len()
+                                        )
+                                    ),
+                                }
+                            }
+                            _ => invalid!(self),
+                        }
+                    }
+                    _ => invalid!(self),
+                },
+                #[cfg(feature = "graphemes")]
+                Opcode::Graphemes => match self.stack.pop() {
+                    Some(Value::Str(s)) => self.stack.push(Value::Array(
+                        graphemes::split(&s)
+                            .into_iter()
+                            .map(|cluster| Value::Str(cluster.to_string()))
+                            .collect(),
+                    )),
+                    _ => invalid!(self),
+                },
+                #[cfg(not(feature = "graphemes"))]
+                Opcode::Graphemes => err!(
+                    self,
+                    InvalidBytecode,
+                    "graphemes needs the graphemes feature."
+                ),
+                opcode @ (Opcode::ToLower | Opcode::ToUpper) => match self.stack.pop() {
+                    Some(Value::Str(s)) => self.stack.push(Value::Str(match opcode {
+                        Opcode::ToLower => s.to_lowercase(),
+                        _ => s.to_uppercase(),
+                    })),
+                    _ => invalid!(self),
+                },
+                #[cfg(feature = "normalization")]
+                opcode @ (Opcode::Nfc | Opcode::Nfd) => match self.stack.pop() {
+                    Some(Value::Str(s)) => self.stack.push(Value::Str(match opcode {
+                        Opcode::Nfc => s.nfc().collect(),
+                        _ => s.nfd().collect(),
+                    })),
+                    _ => invalid!(self),
+                },
+                #[cfg(not(feature = "normalization"))]
+                opcode @ (Opcode::Nfc | Opcode::Nfd) => err!(
+                    self,
+                    InvalidBytecode,
+                    format!("{} needs the normalization feature.", opcode)
+                ),
+                #[cfg(feature = "csv")]
+                Opcode::CsvParse => match self.stack.pop() {
+                    Some(Value::Str(s)) => match csv::parse(&s) {
+                        Ok(records) => self.stack.push(Value::Array(
+                            records
+                                .into_iter()
+                                .map(|record| {
+                                    Value::Array(record.into_iter().map(Value::Str).collect())
+                                })
+                                .collect(),
+                        )),
+                        Err(msg) => err!(self, UnexpectedValue, msg),
+                    },
+                    _ => invalid!(self),
+                },
+                #[cfg(feature = "csv")]
+                Opcode::CsvString => match self.stack.pop() {
+                    Some(Value::Array(records)) => {
+                        let mut fields = Vec::new();
+                        for record in &records {
+                            match record {
+                                Value::Array(record) => {
+                                    let mut strings = Vec::new();
+                                    for field in record {
+                                        match field {
+                                            Value::Str(s) => strings.push(s.as_str()),
+                                            _ => invalid!(self),
+                                        }
+                                    }
+                                    fields.push(strings);
+                                }
+                                _ => invalid!(self),
+                            }
+                        }
+                        self.stack.push(Value::Str(csv::format(&fields)));
+                    }
+                    _ => invalid!(self),
+                },
+                #[cfg(not(feature = "csv"))]
+                opcode @ (Opcode::CsvParse | Opcode::CsvString) => err!(
+                    self,
+                    InvalidBytecode,
+                    format!("{} needs the csv feature.", opcode)
+                ),
+                #[cfg(feature = "regex")]
+                Opcode::RegexMatch => match self.stack.pop() {
+                    Some(Value::Tuple(args)) => match &args[..] {
+                        [Value::Str(pattern), Value::Str(s)] => {
+                            let matched = self.regex(pattern)?.is_match(s);
+                            self.stack.push(Value::Boolean(matched));
+                        }
+                        _ => invalid!(self),
+                    },
+                    _ => invalid!(self),
+                },
+                #[cfg(feature = "regex")]
+                Opcode::RegexCaptures => match self.stack.pop() {
+                    Some(Value::Tuple(args)) => match &args[..] {
+                        [Value::Str(pattern), Value::Str(s)] => {
+                            let result = match self.regex(pattern)?.captures(s) {
+                                Some(groups) => Value::Datatype(
+                                    "Option".to_string(),
+                                    "Some".to_string(),
+                                    Box::new(Value::Array(
+                                        groups.into_iter().map(Value::Str).collect(),
+                                    )),
+                                ),
+                                None => Value::Datatype(
+                                    "Option".to_string(),
+                                    "None".to_string(),
+                                    Box::new(Value::Unit),
+                                ),
+                            };
+                            self.stack.push(result);
+                        }
+                        _ => invalid!(self),
+                    },
+                    _ => invalid!(self),
+                },
+                #[cfg(feature = "regex")]
+                Opcode::RegexReplace => match self.stack.pop() {
+                    Some(Value::Tuple(args)) => match &args[..] {
+                        [Value::Str(pattern), Value::Str(s), Value::Str(replacement)] => {
+                            let replaced = self.regex(pattern)?.replace(s, replacement);
+                            self.stack.push(Value::Str(replaced));
+                        }
+                        _ => invalid!(self),
+                    },
+                    _ => invalid!(self),
+                },
+                #[cfg(not(feature = "regex"))]
+                opcode @ (Opcode::RegexMatch | Opcode::RegexCaptures | Opcode::RegexReplace) => {
+                    err!(
+                        self,
+                        InvalidBytecode,
+                        format!("{} needs the regex feature.", opcode)
+                    )
+                }
+                Opcode::StringSplit => match self.stack.pop() {
+                    Some(Value::Tuple(pair)) if pair.len() == 2 => match (&pair[0], &pair[1]) {
+                        (Value::Str(_), Value::Str(separator)) if separator.is_empty() => {
+                            err!(self, UnexpectedValue, "Cannot split at an empty separator.")
+                        }
+                        (Value::Str(s), Value::Str(separator)) => {
+                            let parts = s
+                                .split(separator.as_str())
+                                .map(|part| Value::Str(part.to_string()))
+                                .collect();
+                            self.stack.push(Value::Array(parts));
+                        }
+                        _ => invalid!(self),
+                    },
+                    _ => invalid!(self),
+                },
+                Opcode::StringContains => match self.stack.pop() {
+                    Some(Value::Tuple(pair)) if pair.len() == 2 => match (&pair[0], &pair[1]) {
+                        (Value::Str(s), Value::Str(substring)) => self
+                            .stack
+                            .push(Value::Boolean(s.contains(substring.as_str()))),
+                        _ => invalid!(self),
+                    },
+                    _ => invalid!(self),
+                },
+                opcode @ (Opcode::HexEncode | Opcode::Base64Encode) => match self.stack.pop() {
+                    Some(Value::Bytes(bytes)) => self.stack.push(Value::Str(match opcode {
+                        Opcode::HexEncode => encoding::hex_encode(&bytes),
+                        _ => encoding::base64_encode(&bytes),
+                    })),
+                    _ => invalid!(self),
+                },
+                opcode @ (Opcode::HexDecode | Opcode::Base64Decode) => match self.stack.pop() {
+                    Some(Value::Str(s)) => {
+                        let (decoded, name) = match opcode {
+                            Opcode::HexDecode => (encoding::hex_decode(&s), "hex"),
+                            _ => (encoding::base64_decode(&s), "base64"),
+                        };
+                        match decoded {
+                            Ok(bytes) => self.stack.push(Value::Bytes(bytes)),
+                            Err(offset) => err!(
+                                self,
+                                UnexpectedValue,
+                                format!("Invalid {} at offset {}.", name, offset)
+                            ),
+                        }
+                    }
+                    _ => invalid!(self),
+                },
+                Opcode::Now => {
+                    self.perform(effects::Effect::Time)?;
+                    self.stack.pop();
+                    let millis = match SystemTime::now().duration_since(UNIX_EPOCH) {
+                        Ok(elapsed) => i64::try_from(elapsed.as_millis()).unwrap_or(i64::MAX),
+                        Err(e) => -i64::try_from(e.duration().as_millis()).unwrap_or(i64::MAX),
+                    };
+                    self.stack.push(Value::Timestamp(millis));
+                }
+                Opcode::FormatTime => match self.stack.pop() {
+                    Some(Value::Tuple(pair)) if pair.len() == 2 => match (&pair[0], &pair[1]) {
+                        (Value::Timestamp(t), Value::Str(fmt)) => match time::format(*t, fmt) {
+                            Ok(s) => self.stack.push(Value::Str(s)),
+                            Err(msg) => err!(self, UnexpectedValue, msg),
+                        },
+                        _ => invalid!(self),
+                    },
+                    _ => invalid!(self),
+                },
+                Opcode::ParseTime => match self.stack.pop() {
+                    Some(Value::Tuple(pair)) if pair.len() == 2 => match (&pair[0], &pair[1]) {
+                        (Value::Str(s), Value::Str(fmt)) => match time::parse(s, fmt) {
+                            Ok(t) => self.stack.push(Value::Timestamp(t)),
+                            Err(msg) => err!(self, UnexpectedValue, msg),
+                        },
+                        _ => invalid!(self),
+                    },
+                    _ => invalid!(self),
+                },
+                Opcode::AddTime => match self.stack.pop() {
+                    Some(Value::Tuple(pair)) if pair.len() == 2 => match (&pair[0], &pair[1]) {
+                        (Value::Timestamp(t), Value::Integer(ms)) => match t.checked_add(*ms) {
+                            Some(t) => self.stack.push(Value::Timestamp(t)),
+                            None => err!(self, UnexpectedValue, "Timestamp out of range."),
+                        },
+                        _ => invalid!(self),
+                    },
+                    _ => invalid!(self),
+                },
+                Opcode::TimeDiff => match self.stack.pop() {
+                    Some(Value::Tuple(pair)) if pair.len() == 2 => match (&pair[0], &pair[1]) {
+                        (Value::Timestamp(t), Value::Timestamp(u)) => match t.checked_sub(*u) {
+                            Some(ms) => self.stack.push(Value::Integer(ms)),
+                            None => err!(self, UnexpectedValue, "Duration out of range."),
+                        },
+                        _ => invalid!(self),
+                    },
+                    _ => invalid!(self),
+                },
+                Opcode::HttpGet => match self.stack.pop() {
+                    Some(Value::Str(url)) => self.http("GET", &url, None)?,
+                    _ => invalid!(self),
+                },
+                Opcode::HttpPost => match self.stack.pop() {
+                    Some(Value::Tuple(args)) if args.len() == 3 => {
+                        match (&args[0], &args[1], &args[2]) {
+                            (Value::Str(url), Value::Str(typ), Value::Str(body)) => {
+                                self.http("POST", url, Some((typ, body.as_bytes())))?
+                            }
+                            _ => invalid!(self),
+                        }
+                    }
+                    _ => invalid!(self),
+                },
+                Opcode::RunCommand => match self.stack.pop() {
+                    Some(Value::Tuple(pair)) if pair.len() == 2 => match (&pair[0], &pair[1]) {
+                        (Value::Str(command), Value::Array(args)) => {
+                            self.perform(effects::Effect::Process)?;
+                            let mut strings = Vec::new();
+                            for arg in args {
+                                match arg {
+                                    Value::Str(arg) => strings.push(arg.as_str()),
+                                    _ => invalid!(self),
+                                }
+                            }
+                            let output = match process::Command::new(command).args(strings).output()
+                            {
+                                Ok(output) => output,
+                                Err(e) => err!(
+                                    self,
+                                    CommandError,
+                                    format!("Can not run {}: {}.", command, e)
+                                ),
+                            };
+                            let mut streams = Vec::new();
+                            for (name, bytes) in
+                                [("output", output.stdout), ("error output", output.stderr)]
+                            {
+                                match String::from_utf8(bytes) {
+                                    Ok(s) => streams.push(Value::Str(s)),
+                                    Err(e) => err!(
+                                        self,
+                                        CommandError,
+                                        format!(
+                                            "Invalid UTF-8 in the {} of {} at offset {}.",
+                                            name,
+                                            command,
+                                            e.utf8_error().valid_up_to()
+                                        )
+                                    ),
+                                }
+                            }
+                            // A command killed by a signal has no exit code.
+                            let code = Value::Integer(output.status.code().map_or(-1, i64::from));
+                            streams.insert(0, code);
+                            self.stack.push(Value::Tuple(streams));
+                        }
+                        _ => invalid!(self),
+                    },
+                    _ => invalid!(self),
+                },
+                Opcode::Popcount => match self.stack.pop() {
+                    Some(Value::Integer(x)) => {
+                        self.stack.push(Value::Integer(x.count_ones().into()))
+                    }
+                    _ => invalid!(self),
+                },
+                Opcode::LeadingZeros => match self.stack.pop() {
+                    Some(Value::Integer(x)) => {
+                        self.stack.push(Value::Integer(x.leading_zeros().into()))
+                    }
+                    _ => invalid!(self),
+                },
+                // Rotating by a negative count rotates the other way, and
+                // counts are taken modulo the 64 bits of an integer.
+                opcode @ (Opcode::RotateLeft | Opcode::RotateRight) => match self.stack.pop() {
+                    Some(Value::Tuple(pair)) if pair.len() == 2 => match (&pair[0], &pair[1]) {
+                        (Value::Integer(x), Value::Integer(n)) => {
+                            let n = if let Opcode::RotateLeft = opcode {
+                                *n
+                            } else {
+                                n.wrapping_neg()
+                            };
+                            let y = x.rotate_left(n.rem_euclid(64) as u32);
+                            self.stack.push(Value::Integer(y));
+                        }
+                        _ => invalid!(self),
+                    },
+                    _ => invalid!(self),
+                },
+                Opcode::Min => match self.stack.pop() {
+                    Some(Value::Tuple(mut pair)) if pair.len() == 2 => {
+                        match compare_integers(&pair[0], &pair[1]) {
+                            Some(ordering) => {
+                                let first = ordering != std::cmp::Ordering::Greater;
+                                self.stack.push(pair.swap_remove(if first { 0 } else { 1 }))
+                            }
+                            None => invalid!(self),
+                        }
+                    }
+                    _ => invalid!(self),
+                },
+                Opcode::Max => match self.stack.pop() {
+                    Some(Value::Tuple(mut pair)) if pair.len() == 2 => {
+                        match compare_integers(&pair[0], &pair[1]) {
+                            Some(ordering) => {
+                                let first = ordering != std::cmp::Ordering::Less;
+                                self.stack.push(pair.swap_remove(if first { 0 } else { 1 }))
+                            }
+                            None => invalid!(self),
+                        }
+                    }
+                    _ => invalid!(self),
+                },
+                // These ignore the overflow mode, so scripts can choose how
+                // overflow is handled where it matters.
+                opcode @ (Opcode::WrappingAdd
+                | Opcode::WrappingSub
+                | Opcode::WrappingMul
+                | Opcode::SaturatingAdd
+                | Opcode::SaturatingSub
+                | Opcode::SaturatingMul) => match self.stack.pop() {
+                    Some(Value::Tuple(pair)) if pair.len() == 2 => match (&pair[0], &pair[1]) {
+                        (Value::Integer(x), Value::Integer(y)) => {
+                            let z = match opcode {
+                                Opcode::WrappingAdd => x.wrapping_add(*y),
+                                Opcode::WrappingSub => x.wrapping_sub(*y),
+                                Opcode::WrappingMul => x.wrapping_mul(*y),
+                                Opcode::SaturatingAdd => x.saturating_add(*y),
+                                Opcode::SaturatingSub => x.saturating_sub(*y),
+                                _ => x.saturating_mul(*y),
+                            };
+                            self.stack.push(Value::Integer(z));
+                        }
+                        _ => invalid!(self),
+                    },
+                    _ => invalid!(self),
+                },
+                Opcode::Fst => match self.stack.pop() {
+                    Some(Value::Tuple(mut pair)) if pair.len() == 2 => {
+                        self.stack.push(pair.swap_remove(0));
+                    }
+                    _ => invalid!(self),
+                },
+                Opcode::Snd => match self.stack.pop() {
+                    Some(Value::Tuple(mut pair)) if pair.len() == 2 => {
+                        self.stack.push(pair.swap_remove(1));
+                    }
+                    _ => invalid!(self),
+                },
+                Opcode::FloatToString => match self.stack.pop() {
+                    Some(Value::Float(x)) => self.stack.push(Value::Str(format_float(x))),
+                    _ => invalid!(self),
+                },
+                Opcode::FormatInt => match self.stack.pop() {
+                    Some(Value::Tuple(args)) if args.len() == 3 => {
+                        let format = match (&args[1], &args[2]) {
+                            (Value::Integer(radix), Value::Str(separator)) => {
+                                match IntFormat::new(*radix, separator) {
+                                    Some(format) => format,
+                                    None => err!(
+                                        self,
+                                        UnexpectedValue,
+                                        format!("Invalid radix {}.", radix)
+                                    ),
+                                }
+                            }
+                            _ => invalid!(self),
+                        };
+                        match &args[0] {
+                            Value::Integer(n) => self.stack.push(Value::Str(format.integer(*n))),
+                            Value::BigInt(n) => self.stack.push(Value::Str(format.bigint(n))),
+                            _ => invalid!(self),
+                        }
+                    }
+                    _ => invalid!(self),
+                },
+                Opcode::ParseFloat => match self.stack.pop() {
+                    Some(Value::Str(s)) => match s.parse::<f64>() {
+                        Ok(x) => self.stack.push(Value::Float(x)),
+                        Err(_) => err!(
+                            self,
+                            UnexpectedValue,
+                            format!("Can not parse {} as a float.", Value::Str(s))
+                        ),
+                    },
+                    _ => invalid!(self),
+                },
+                Opcode::Rconst(r) => {
+                    self.stack.push(Value::Float(*r));
+                }
+                Opcode::Receive => {
+                    self.perform(effects::Effect::Nondeterminism)?;
+                    let message = match self.mailboxes.get_mut(&self.pid) {
+                        Some(mailbox) => mailbox.pop_front(),
+                        None => None,
+                    };
+                    match message {
+                        Some(message) => {
+                            self.stack.pop();
+                            self.stack.push(message);
+                        }
+                        None if self.calls > 0 => {
+                            err!(
+                                self,
+                                WouldBlock,
+                                "Can not wait for a message inside a callback."
+                            )
+                        }
+                        None => {
+                            // The receive is run again once the process is
+                            // resumed by a message arriving.
+                            self.waiting.insert(self.pid);
+                            self.suspend();
+                            self.schedule()?;
+                            continue;
+                        }
+                    }
+                }
+                Opcode::Recv => {
+                    self.perform(effects::Effect::Io)?;
+                    let channel = match self.stack.last() {
+                        Some(Value::Channel(channel)) => channel.clone(),
+                        Some(value) => {
+                            err!(
+                                self,
+                                UnexpectedValue,
+                                format!("Expected channel but found {}.", value)
+                            )
+                        }
+                        None => invalid!(self),
+                    };
+                    let value = match channel.try_recv() {
+                        Some(value) => value,
+                        None if self.calls == 0 && !self.runnable.is_empty() => {
+                            // Let the other processes run before waiting
+                            // for the host.
+                            self.runnable.push_back(self.pid);
+                            self.suspend();
+                            self.schedule()?;
                             continue;
                         }
+                        None => match channel.recv() {
+                            Some(value) => value,
+                            None => err!(self, ChannelClosed, "Channel is closed."),
+                        },
+                    };
+                    // The host can send values of any type, so they are
+                    // checked against the type the channel carries.
+                    if !value.has_type(&channel.element) {
+                        err!(
+                            self,
+                            UnexpectedValue,
+                            format!("Expected {} but found {}.", channel.element, value)
+                        )
+                    }
+                    self.stack.pop();
+                    self.stack.push(value);
+                }
+                Opcode::SelfPid => {
+                    self.stack.pop();
+                    self.stack.push(Value::Pid(self.pid));
+                }
+                Opcode::Send => {
+                    match self.stack.pop() {
+                        Some(Value::Tuple(mut args)) if args.len() == 2 => {
+                            let message = args.pop().unwrap();
+                            match args.pop() {
+                                Some(Value::Channel(channel)) => {
+                                    self.perform(effects::Effect::Io)?;
+                                    if !message.has_type(&channel.element) {
+                                        err!(
+                                            self,
+                                            UnexpectedValue,
+                                            format!(
+                                                "Expected {} but found {}.",
+                                                channel.element, message
+                                            )
+                                        )
+                                    }
+                                    if !channel.send(message) {
+                                        err!(self, ChannelClosed, "Channel is closed.")
+                                    }
+                                }
+                                Some(Value::Pid(pid)) => {
+                                    self.perform(effects::Effect::Nondeterminism)?;
+                                    // Messages to processes that have
+                                    // finished are dropped.
+                                    if let Some(mailbox) = self.mailboxes.get_mut(&pid) {
+                                        mailbox.push_back(message);
+                                        if self.waiting.remove(&pid) {
+                                            self.runnable.push_back(pid);
+                                        }
+                                    }
+                                }
+                                Some(value) => err!(
+                                    self,
+                                    UnexpectedValue,
+                                    format!("Expected pid or channel but found {}.", value)
+                                ),
+                                None => invalid!(self),
+                            }
+                        }
+                        Some(value) => {
+                            err!(
+                                self,
+                                UnexpectedValue,
+                                format!("Expected target and message but found {}.", value)
+                            )
+                        }
+                        None => invalid!(self),
+                    }
+                    self.stack.push(Value::Unit);
+                }
+                Opcode::Spawn => {
+                    self.perform(effects::Effect::Nondeterminism)?;
+                    match self.stack.pop() {
+                        Some(Value::Function(ip, env)) => {
+                            let pid = self.next_pid;
+                            self.next_pid += 1;
+                            // Returning to the end of the program finishes
+                            // the process.
+                            let process = Process {
+                                ip,
+                                stack: vec![Value::Unit],
+                                callstack: vec![(ip, env, 0, self.instructions.len())],
+                                memo_calls: Vec::new(),
+                                catches: Vec::new(),
+                                defers: Vec::new(),
+                            };
+                            self.processes.insert(pid, process);
+                            self.mailboxes.insert(pid, VecDeque::new());
+                            self.runnable.push_back(pid);
+                            self.stack.push(Value::Pid(pid));
+                        }
+                        Some(value) => {
+                            err!(
+                                self,
+                                UnexpectedValue,
+                                format!("Expected function but found {}.", value)
+                            )
+                        }
+                        None => invalid!(self),
+                    }
+                }
+                Opcode::Ret => self.ret()?,
+                Opcode::Propagate => match self.stack.pop() {
+                    Some(Value::Datatype(typ, variant, value))
+                        if (typ == "Option" && variant == "Some")
+                            || (typ == "Result" && variant == "Ok") =>
+                    {
+                        self.stack.push(*value);
+                    }
+                    Some(value @ Value::Datatype(..)) => {
+                        while let Some((ip, _, _, _)) = self.callstack.last() {
+                            if !matches!(self.instructions.get(*ip), Some(Opcode::Scope)) {
+                                break;
+                            }
+                            self.callstack.pop();
+                        }
+                        self.stack.push(value);
+                        // A ? inside a try expression leaves it as well,
+                        // before any deferred functions are run.
+                        let depth = self.callstack.len();
+                        while let Some(catch) = self.catches.last() {
+                            if catch.depth < depth {
+                                break;
+                            }
+                            self.catches.pop();
+                        }
+                        self.ret()?;
+                    }
+                    _ => invalid!(self),
+                },
+                Opcode::Scope => {}
+                Opcode::Try(offset) => {
+                    self.catches.push(Catch {
+                        ip: (self.ip as i64 + offset) as usize,
+                        sp: self.stack.len(),
+                        depth: self.callstack.len(),
+                        memo_calls: self.memo_calls.len(),
+                        calls: self.calls,
+                    });
+                }
+                Opcode::EndTry => {
+                    self.catches.pop();
+                }
+                Opcode::Rec(group) => match self.stack.pop() {
+                    Some(Value::Function(ip, mut env)) => {
+                        env.group = group.clone();
+                        self.stack.push(Value::Function(ip, env));
+                    }
+                    _ => invalid!(self),
+                },
+                Opcode::Sconst(s) => {
+                    self.stack.push(Value::Str(s.to_string()));
+                }
+                Opcode::Xconst(bytes) => {
+                    self.stack.push(Value::Bytes(bytes.clone()));
+                }
+                Opcode::SetEnv(id) => match self.stack.pop() {
+                    Some(x) => {
+                        match self.callstack.last_mut() {
+                            // Rather than copying the values of a function's
+                            // scope that are shared with closures, the new
+                            // value is bound in a scope of its own.
+                            Some((_, env, _, _)) if Arc::strong_count(&env.values) > 1 => {
+                                *env = env.extended(HashMap::from([(*id, x)]));
+                            }
+                            Some((_, env, _, _)) => env.insert(*id, x),
+                            None if self.frozen.contains(id) => err!(
+                                self,
+                                Frozen,
+                                format!(
+                                    "Can not redefine {}, which the host has frozen.",
+                                    self.names.name(*id)
+                                )
+                            ),
+                            None => self.env.insert(*id, x),
+                        }
                     }
-                    _ => unreachable!(),
+                    _ => invalid!(self),
                 },
-                Opcode::Less => match self.stack.pop() {
-                    Some(Value::Integer(x)) => match self.stack.pop() {
-                        Some(Value::Integer(y)) => {
-                            self.stack.push(Value::Boolean(x < y));
+                Opcode::Srcpos(line, col) => {
+                    self.line = *line;
+                    self.col = *col;
+                }
+                Opcode::SortBy => match self.stack.pop() {
+                    Some(Value::Tuple(mut args)) if args.len() == 2 => match args.pop() {
+                        Some(Value::Array(elements)) => {
+                            let sorted = self.sort_by(&args[0], elements)?;
+                            self.stack.push(Value::Array(sorted));
                         }
-                        _ => unreachable!(),
+                        _ => invalid!(self),
                     },
-                    _ => unreachable!(),
+                    _ => invalid!(self),
                 },
-                Opcode::LessEqual => match self.stack.pop() {
-                    Some(Value::Integer(x)) => match self.stack.pop() {
-                        Some(Value::Integer(y)) => {
-                            self.stack.push(Value::Boolean(x <= y));
-                        }
-                        _ => unreachable!(),
+                Opcode::Sub => match (self.stack.pop(), self.stack.pop()) {
+                    (Some(Value::Integer(x)), Some(Value::Integer(y))) => {
+                        let result = match x.checked_sub(y) {
+                            Some(z) => Value::Integer(z),
+                            None => self.overflowed(x.wrapping_sub(y), (x, y), |x, y| &x - &y)?,
+                        };
+                        self.stack.push(result);
+                    }
+                    (Some(Value::Float(x)), Some(Value::Float(y))) => {
+                        self.stack.push(Value::Float(x - y));
+                    }
+                    (Some(x), Some(y)) => match bigints(&x, &y) {
+                        Some((x, y)) => self.stack.push(self.bigint(&x - &y)),
+                        None => invalid!(self),
                     },
-                    _ => unreachable!(),
+                    _ => invalid!(self),
                 },
-                Opcode::Mod => match self.stack.pop() {
-                    Some(Value::Integer(x)) => match self.stack.pop() {
-                        Some(Value::Integer(y)) => {
-                            if y == 0 {
-                                err!(self, "Division by zero.")
+                Opcode::TailCall => match self.stack.pop() {
+                    // A function that has deferred functions keeps its frame
+                    // until the call returns, so that they run after it.
+                    Some(Value::Function(ip, env))
+                        if self.symbols.contains_key(&ip)
+                            && self
+                                .defers
+                                .last()
+                                .is_some_and(|(depth, _)| *depth == self.callstack.len()) =>
+                    {
+                        self.check_depth()?;
+                        let return_ip = self.ip;
+                        self.ip = ip;
+                        self.callstack
+                            .push((ip, env, self.stack.len() - 1, return_ip));
+                        continue;
+                    }
+                    Some(Value::Function(ip, env)) => {
+                        let arg = match self.stack.pop() {
+                            Some(arg) => arg,
+                            None => invalid!(self),
+                        };
+                        match self.callstack.last_mut() {
+                            Some(frame) => {
+                                // Replace the current frame rather than
+                                // pushing a new one: the argument takes the
+                                // place of the caller's argument and we
+                                // return directly to the caller's caller.
+                                self.stack.truncate(frame.2);
+                                self.stack.push(arg);
+                                // A let body or match case in tail position
+                                // still belongs to the function whose frame
+                                // it takes, and returns from it. Only
+                                // functions are in the symbol table.
+                                if self.symbols.contains_key(&ip) {
+                                    frame.0 = ip;
+                                }
+                                frame.1 = env;
+                                self.ip = ip;
+                                continue;
                             }
-                            self.stack.push(Value::Integer(x % y));
-                        }
-                        _ => unreachable!(),
-                    },
-                    _ => unreachable!(),
-                },
-                Opcode::Mul => match self.stack.pop() {
-                    Some(Value::Integer(x)) => match self.stack.pop() {
-                        Some(Value::Integer(y)) => {
-                            self.stack.push(Value::Integer(x * y));
+                            None => invalid!(self),
                         }
-                        _ => unreachable!(),
-                    },
-                    _ => unreachable!(),
-                },
-                Opcode::NotEqual => match self.stack.pop() {
-                    Some(x) => match self.stack.pop() {
-                        Some(y) => {
-                            self.stack.push(Value::Boolean(x != y));
+                    }
+                    // The result has to be cached once the call returns, so
+                    // memoized functions are not called in place.
+                    Some(Value::Memo(cache, fun)) => {
+                        self.check_depth()?;
+                        if self.call_memo(cache, *fun)? {
+                            continue;
                         }
-                        _ => unreachable!(),
-                    },
-                    _ => unreachable!(),
-                },
-                Opcode::Not => match self.stack.pop() {
-                    Some(Value::Boolean(x)) => {
-                        self.stack.push(Value::Boolean(!x));
                     }
-                    _ => unreachable!(),
-                },
-                Opcode::Or => match self.stack.pop() {
-                    Some(Value::Boolean(x)) => match self.stack.pop() {
-                        Some(Value::Boolean(y)) => {
-                            self.stack.push(Value::Boolean(x || y));
+                    // Native functions do not use a frame, so they are called
+                    // as they would be outside of tail position.
+                    Some(Value::NativeFunction(native)) => match self.stack.pop() {
+                        Some(arg) => {
+                            let result = self.call_native(&native, arg)?;
+                            self.stack.push(result);
                         }
-                        _ => unreachable!(),
+                        None => invalid!(self),
                     },
-                    _ => unreachable!(),
-                },
-                Opcode::Pop => match self.stack.pop() {
-                    Some(_) => {}
-                    _ => unreachable!(),
+                    _ => invalid!(self),
                 },
-                Opcode::Ret(n) => match self.callstack.pop() {
-                    Some((_, _, sp, ip)) => {
-                        self.stack.drain(sp..sp + n);
-                        self.ip = ip;
+                Opcode::Tconst(n) => {
+                    if self.stack.len() < *n {
+                        invalid!(self);
                     }
-                    None => unreachable!(),
-                },
-                Opcode::Rot => {
-                    if self.stack.len() < 3 {
-                        unreachable!();
+                    let elements = self.stack.split_off(self.stack.len() - n);
+                    self.stack.push(Value::Tuple(elements));
+                }
+                Opcode::Aconst(n) => {
+                    if self.stack.len() < *n {
+                        invalid!(self);
+                    }
+                    let elements = self.stack.split_off(self.stack.len() - n);
+                    self.stack.push(Value::Array(elements));
+                }
+                Opcode::Mconst(n) => {
+                    if self.stack.len() < 2 * n {
+                        invalid!(self);
                     }
-                    if let Some(a) = self.stack.pop() {
-                        self.stack.insert(self.stack.len() - 2, a);
+                    let mut entries = self.stack.split_off(self.stack.len() - 2 * n).into_iter();
+                    let mut map = Map::new();
+                    while let (Some(key), Some(value)) = (entries.next(), entries.next()) {
+                        map.insert(key, value);
                     }
+                    self.stack.push(Value::Map(map));
                 }
-                Opcode::SetEnv(id) => match self.stack.pop() {
-                    Some(x) => {
-                        let len = self.callstack.len();
-                        let values = if len > 0 {
-                            &mut self.callstack[len - 1].1.values
-                        } else {
-                            &mut self.env.values
-                        };
-                        values.insert(id.to_string(), x);
+                Opcode::Lconst(n) => {
+                    if self.stack.len() < *n {
+                        invalid!(self);
                     }
-                    _ => unreachable!(),
-                },
-                Opcode::Srcpos(line, col) => {
-                    self.line = *line;
-                    self.col = *col;
+                    let mut list = List::new();
+                    for element in self.stack.split_off(self.stack.len() - n).into_iter().rev() {
+                        list = List::cons(element, list);
+                    }
+                    self.stack.push(Value::List(list));
                 }
-                Opcode::Sub => match self.stack.pop() {
-                    Some(Value::Integer(x)) => match self.stack.pop() {
-                        Some(Value::Integer(y)) => {
-                            self.stack.push(Value::Integer(x - y));
+                Opcode::Record(fields) => {
+                    if self.stack.len() < fields.len() {
+                        invalid!(self);
+                    }
+                    let values = self.stack.split_off(self.stack.len() - fields.len());
+                    // Fields are kept sorted by name so that records with
+                    // the same fields compare equal.
+                    let mut record: Vec<(String, Value)> =
+                        fields.iter().cloned().zip(values).collect();
+                    record.sort_by(|a, b| a.0.cmp(&b.0));
+                    self.stack.push(Value::Record(record));
+                }
+                Opcode::Field(field) => match self.stack.pop() {
+                    Some(Value::Record(record)) => {
+                        match record.into_iter().find(|f| f.0 == *field) {
+                            Some((_, value)) => self.stack.push(value),
+                            None => err!(
+                                self,
+                                UnexpectedValue,
+                                format!("Record has no field {}.", field)
+                            ),
                         }
-                        _ => unreachable!(),
-                    },
-                    _ => unreachable!(),
+                    }
+                    Some(Value::Tuple(elements)) if field.parse::<usize>().is_ok() => {
+                        let len = elements.len();
+                        match elements.into_iter().nth(field.parse().unwrap()) {
+                            Some(value) => self.stack.push(value),
+                            None => err!(
+                                self,
+                                IndexOutOfRange,
+                                format!("Index {} is out of range for {} elements.", field, len)
+                            ),
+                        }
+                    }
+                    Some(value) => err!(
+                        self,
+                        UnexpectedValue,
+                        format!("Expected record but found {}.", value)
+                    ),
+                    None => invalid!(self),
                 },
+                Opcode::Update(fields) => {
+                    if self.stack.len() < fields.len() + 1 {
+                        invalid!(self);
+                    }
+                    let values = self.stack.split_off(self.stack.len() - fields.len());
+                    match self.stack.pop() {
+                        Some(Value::Record(mut record)) => {
+                            for (field, value) in fields.iter().zip(values) {
+                                match record.iter_mut().find(|f| f.0 == *field) {
+                                    Some(f) => f.1 = value,
+                                    None => err!(
+                                        self,
+                                        UnexpectedValue,
+                                        format!("Record has no field {}.", field)
+                                    ),
+                                }
+                            }
+                            self.stack.push(Value::Record(record));
+                        }
+                        Some(value) => {
+                            err!(
+                                self,
+                                UnexpectedValue,
+                                format!("Expected record but found {}.", value)
+                            )
+                        }
+                        None => invalid!(self),
+                    }
+                }
                 Opcode::TypeEq(typ) => match self.stack.pop() {
                     Some(Value::Datatype(_, variant, _)) => {
                         self.stack.push(Value::Boolean(variant == *typ));
                     }
-                    _ => unreachable!(),
+                    Some(Value::List(list)) => {
+                        let variant = if list.is_empty() {
+                            typeinfer::NIL
+                        } else {
+                            typeinfer::CONS
+                        };
+                        self.stack.push(Value::Boolean(variant == typ));
+                    }
+                    _ => invalid!(self),
                 },
                 Opcode::Uconst => {
                     self.stack.push(Value::Unit);
                 }
+                Opcode::Untuple => match self.stack.pop() {
+                    Some(Value::Tuple(elements)) => {
+                        self.stack.extend(elements);
+                    }
+                    _ => invalid!(self),
+                },
+                Opcode::Yield => {
+                    self.stack.pop();
+                    self.stack.push(Value::Unit);
+                    if self.calls == 0
+                        && self
+                            .deadline
+                            .is_none_or(|deadline| Instant::now() >= deadline)
+                    {
+                        self.ip += 1;
+                        return Ok(Some(Status::Yielded));
+                    }
+                }
             }
             self.ip += 1;
         }
-        Ok(())
+        Ok(None)
     }
 
     pub fn new() -> VirtualMachine {
+        let mut vm = VirtualMachine {
+            instructions: Arc::new(Vec::new()),
+            symbols: Arc::new(HashMap::new()),
+            names: Arc::new(Names::new()),
+            ip: 0,
+            stack: Vec::new(),
+            callstack: Vec::new(),
+            env: Environment::new(),
+            warnings: Vec::new(),
+            features: HashSet::new(),
+            optimize: false,
+            allowed_effects: effects::all(),
+            deadline: None,
+            fuel: None,
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            call_depth_warning: None,
+            fuel_warning: None,
+            warned_call_depth: false,
+            warned_fuel: false,
+            net_timeout: DEFAULT_NET_TIMEOUT,
+            checked: false,
+            overflow: Overflow::Trap,
+            int_format: IntFormat::default(),
+            output: None,
+            memos: Vec::new(),
+            memo_calls: Vec::new(),
+            catches: Vec::new(),
+            defers: Vec::new(),
+            handlers: HashMap::new(),
+            gated_builtins: HashMap::new(),
+            frozen: HashSet::new(),
+            id: NEXT_VM_ID.fetch_add(1, Ordering::Relaxed),
+            handles: Vec::new(),
+            #[cfg(feature = "regex")]
+            regexes: HashMap::new(),
+            calls: 0,
+            debugger: None,
+            profile: None,
+            pid: 0,
+            next_pid: 1,
+            processes: HashMap::new(),
+            runnable: VecDeque::new(),
+            waiting: HashSet::new(),
+            mailboxes: HashMap::from([(0, VecDeque::new())]),
+            line: usize::MAX,
+            col: usize::MAX,
+        };
+        vm.builtin(
+            "print",
+            Opcode::Print,
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::Polymorphic("a".to_string())),
+                Box::new(typeinfer::Type::Unit),
+            ),
+            &[effects::Effect::Io],
+        );
+        vm.builtin(
+            "memo",
+            Opcode::Memo,
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::Function(
+                    Box::new(typeinfer::Type::Polymorphic("a".to_string())),
+                    Box::new(typeinfer::Type::Polymorphic("b".to_string())),
+                )),
+                Box::new(typeinfer::Type::Function(
+                    Box::new(typeinfer::Type::Polymorphic("a".to_string())),
+                    Box::new(typeinfer::Type::Polymorphic("b".to_string())),
+                )),
+            ),
+            &[],
+        );
+        vm.builtin(
+            "par_map",
+            Opcode::ParMap,
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::Tuple(vec![
+                    typeinfer::Type::Function(
+                        Box::new(typeinfer::Type::Polymorphic("a".to_string())),
+                        Box::new(typeinfer::Type::Polymorphic("b".to_string())),
+                    ),
+                    typeinfer::Type::Array(Box::new(typeinfer::Type::Polymorphic("a".to_string()))),
+                ])),
+                Box::new(typeinfer::Type::Array(Box::new(
+                    typeinfer::Type::Polymorphic("b".to_string()),
+                ))),
+            ),
+            &[],
+        );
+        vm.builtin(
+            "spawn",
+            Opcode::Spawn,
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::Function(
+                    Box::new(typeinfer::Type::Unit),
+                    Box::new(typeinfer::Type::Polymorphic("a".to_string())),
+                )),
+                Box::new(typeinfer::Type::Pid),
+            ),
+            &[effects::Effect::Nondeterminism],
+        );
+        vm.builtin(
+            "send",
+            Opcode::Send,
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::Tuple(vec![
+                    typeinfer::Type::Polymorphic("a".to_string()),
+                    typeinfer::Type::Polymorphic("b".to_string()),
+                ])),
+                Box::new(typeinfer::Type::Unit),
+            ),
+            &[effects::Effect::Nondeterminism],
+        );
+        vm.builtin(
+            "recv",
+            Opcode::Recv,
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::Channel(Box::new(
+                    typeinfer::Type::Polymorphic("a".to_string()),
+                ))),
+                Box::new(typeinfer::Type::Polymorphic("a".to_string())),
+            ),
+            &[effects::Effect::Io],
+        );
+        vm.builtin(
+            "receive",
+            Opcode::Receive,
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::Unit),
+                Box::new(typeinfer::Type::Polymorphic("a".to_string())),
+            ),
+            &[effects::Effect::Nondeterminism],
+        );
+        vm.builtin(
+            "on",
+            Opcode::On,
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::Tuple(vec![
+                    typeinfer::Type::String,
+                    typeinfer::Type::Function(
+                        Box::new(typeinfer::Type::Polymorphic("a".to_string())),
+                        Box::new(typeinfer::Type::Polymorphic("b".to_string())),
+                    ),
+                ])),
+                Box::new(typeinfer::Type::Unit),
+            ),
+            &[],
+        );
+        vm.builtin(
+            "yield_now",
+            Opcode::Yield,
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::Unit),
+                Box::new(typeinfer::Type::Unit),
+            ),
+            &[],
+        );
+        vm.builtin(
+            "self",
+            Opcode::SelfPid,
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::Unit),
+                Box::new(typeinfer::Type::Pid),
+            ),
+            &[],
+        );
+        vm.builtin(
+            "sort_by",
+            Opcode::SortBy,
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::Tuple(vec![
+                    typeinfer::Type::Function(
+                        Box::new(typeinfer::Type::Tuple(vec![
+                            typeinfer::Type::Polymorphic("a".to_string()),
+                            typeinfer::Type::Polymorphic("a".to_string()),
+                        ])),
+                        Box::new(typeinfer::Type::Boolean),
+                    ),
+                    typeinfer::Type::Array(Box::new(typeinfer::Type::Polymorphic("a".to_string()))),
+                ])),
+                Box::new(typeinfer::Type::Array(Box::new(
+                    typeinfer::Type::Polymorphic("a".to_string()),
+                ))),
+            ),
+            &[],
+        );
+        vm.builtin(
+            "float_to_string",
+            Opcode::FloatToString,
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::Float),
+                Box::new(typeinfer::Type::String),
+            ),
+            &[],
+        );
+        vm.builtin(
+            "format_int",
+            Opcode::FormatInt,
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::Tuple(vec![
+                    typeinfer::Type::Integer,
+                    typeinfer::Type::Integer,
+                    typeinfer::Type::String,
+                ])),
+                Box::new(typeinfer::Type::String),
+            ),
+            &[],
+        );
+        vm.builtin(
+            "parse_float",
+            Opcode::ParseFloat,
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::String),
+                Box::new(typeinfer::Type::Float),
+            ),
+            &[],
+        );
+        vm.builtin(
+            "show",
+            Opcode::Show,
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::Polymorphic("a".to_string())),
+                Box::new(typeinfer::Type::String),
+            ),
+            &[],
+        );
+        vm.builtin(
+            "diff",
+            Opcode::Diff,
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::Tuple(vec![
+                    typeinfer::Type::Polymorphic("a".to_string()),
+                    typeinfer::Type::Polymorphic("a".to_string()),
+                ])),
+                Box::new(typeinfer::Type::Datatype("Option".to_string())),
+            ),
+            &[],
+        );
+        vm.builtin(
+            "abs",
+            Opcode::Abs,
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::Integer),
+                Box::new(typeinfer::Type::Integer),
+            ),
+            &[],
+        );
+        vm.builtin(
+            "min",
+            Opcode::Min,
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::Tuple(vec![
+                    typeinfer::Type::Integer,
+                    typeinfer::Type::Integer,
+                ])),
+                Box::new(typeinfer::Type::Integer),
+            ),
+            &[],
+        );
+        vm.builtin(
+            "max",
+            Opcode::Max,
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::Tuple(vec![
+                    typeinfer::Type::Integer,
+                    typeinfer::Type::Integer,
+                ])),
+                Box::new(typeinfer::Type::Integer),
+            ),
+            &[],
+        );
+        for (id, opcode) in [
+            ("wrapping_add", Opcode::WrappingAdd),
+            ("wrapping_sub", Opcode::WrappingSub),
+            ("wrapping_mul", Opcode::WrappingMul),
+            ("saturating_add", Opcode::SaturatingAdd),
+            ("saturating_sub", Opcode::SaturatingSub),
+            ("saturating_mul", Opcode::SaturatingMul),
+        ] {
+            vm.builtin(
+                id,
+                opcode,
+                typeinfer::Type::Function(
+                    Box::new(typeinfer::Type::Tuple(vec![
+                        typeinfer::Type::Integer,
+                        typeinfer::Type::Integer,
+                    ])),
+                    Box::new(typeinfer::Type::Integer),
+                ),
+                &[],
+            );
+        }
+        vm.builtin(
+            "hash",
+            Opcode::HashValue,
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::Polymorphic("a".to_string())),
+                Box::new(typeinfer::Type::Integer),
+            ),
+            &[],
+        );
+        for (id, opcode) in [
+            ("popcount", Opcode::Popcount),
+            ("leading_zeros", Opcode::LeadingZeros),
+        ] {
+            vm.builtin(
+                id,
+                opcode,
+                typeinfer::Type::Function(
+                    Box::new(typeinfer::Type::Integer),
+                    Box::new(typeinfer::Type::Integer),
+                ),
+                &[],
+            );
+        }
+        for (id, opcode) in [
+            ("rotate_left", Opcode::RotateLeft),
+            ("rotate_right", Opcode::RotateRight),
+        ] {
+            vm.builtin(
+                id,
+                opcode,
+                typeinfer::Type::Function(
+                    Box::new(typeinfer::Type::Tuple(vec![
+                        typeinfer::Type::Integer,
+                        typeinfer::Type::Integer,
+                    ])),
+                    Box::new(typeinfer::Type::Integer),
+                ),
+                &[],
+            );
+        }
+        vm.builtin(
+            "bytes_length",
+            Opcode::BytesLength,
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::Bytes),
+                Box::new(typeinfer::Type::Integer),
+            ),
+            &[],
+        );
+        vm.builtin(
+            "byte_at",
+            Opcode::ByteAt,
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::Tuple(vec![
+                    typeinfer::Type::Bytes,
+                    typeinfer::Type::Integer,
+                ])),
+                Box::new(typeinfer::Type::Integer),
+            ),
+            &[],
+        );
+        vm.builtin(
+            "bytes_slice",
+            Opcode::BytesSlice,
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::Tuple(vec![
+                    typeinfer::Type::Bytes,
+                    typeinfer::Type::Integer,
+                    typeinfer::Type::Integer,
+                ])),
+                Box::new(typeinfer::Type::Bytes),
+            ),
+            &[],
+        );
+        vm.builtin(
+            "string_to_bytes",
+            Opcode::StringToBytes,
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::String),
+                Box::new(typeinfer::Type::Bytes),
+            ),
+            &[],
+        );
+        vm.builtin(
+            "bytes_to_string",
+            Opcode::BytesToString,
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::Bytes),
+                Box::new(typeinfer::Type::String),
+            ),
+            &[],
+        );
+        vm.builtin(
+            "chars",
+            Opcode::StringChars,
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::String),
+                Box::new(typeinfer::Type::Array(Box::new(typeinfer::Type::Char))),
+            ),
+            &[],
+        );
+        vm.builtin(
+            "byte_length",
+            Opcode::StringByteLength,
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::String),
+                Box::new(typeinfer::Type::Integer),
+            ),
+            &[],
+        );
+        vm.builtin(
+            "byte_slice",
+            Opcode::StringByteSlice,
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::Tuple(vec![
+                    typeinfer::Type::String,
+                    typeinfer::Type::Integer,
+                    typeinfer::Type::Integer,
+                ])),
+                Box::new(typeinfer::Type::String),
+            ),
+            &[],
+        );
+        #[cfg(feature = "graphemes")]
+        vm.builtin(
+            "graphemes",
+            Opcode::Graphemes,
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::String),
+                Box::new(typeinfer::Type::Array(Box::new(typeinfer::Type::String))),
+            ),
+            &[],
+        );
+        for (id, opcode) in [("to_lower", Opcode::ToLower), ("to_upper", Opcode::ToUpper)] {
+            vm.builtin(
+                id,
+                opcode,
+                typeinfer::Type::Function(
+                    Box::new(typeinfer::Type::String),
+                    Box::new(typeinfer::Type::String),
+                ),
+                &[],
+            );
+        }
+        #[cfg(feature = "normalization")]
+        for (id, opcode) in [("nfc", Opcode::Nfc), ("nfd", Opcode::Nfd)] {
+            vm.builtin(
+                id,
+                opcode,
+                typeinfer::Type::Function(
+                    Box::new(typeinfer::Type::String),
+                    Box::new(typeinfer::Type::String),
+                ),
+                &[],
+            );
+        }
+        #[cfg(feature = "csv")]
+        {
+            let records = typeinfer::Type::Array(Box::new(typeinfer::Type::Array(Box::new(
+                typeinfer::Type::String,
+            ))));
+            vm.builtin(
+                "csv_parse",
+                Opcode::CsvParse,
+                typeinfer::Type::Function(
+                    Box::new(typeinfer::Type::String),
+                    Box::new(records.clone()),
+                ),
+                &[],
+            );
+            vm.builtin(
+                "csv_string",
+                Opcode::CsvString,
+                typeinfer::Type::Function(Box::new(records), Box::new(typeinfer::Type::String)),
+                &[],
+            );
+        }
+        #[cfg(feature = "regex")]
+        {
+            let pair =
+                typeinfer::Type::Tuple(vec![typeinfer::Type::String, typeinfer::Type::String]);
+            vm.builtin(
+                "regex_match",
+                Opcode::RegexMatch,
+                typeinfer::Type::Function(
+                    Box::new(pair.clone()),
+                    Box::new(typeinfer::Type::Boolean),
+                ),
+                &[],
+            );
+            vm.builtin(
+                "regex_captures",
+                Opcode::RegexCaptures,
+                typeinfer::Type::Function(
+                    Box::new(pair),
+                    Box::new(typeinfer::Type::Datatype("Option".to_string())),
+                ),
+                &[],
+            );
+            vm.builtin(
+                "regex_replace",
+                Opcode::RegexReplace,
+                typeinfer::Type::Function(
+                    Box::new(typeinfer::Type::Tuple(vec![
+                        typeinfer::Type::String,
+                        typeinfer::Type::String,
+                        typeinfer::Type::String,
+                    ])),
+                    Box::new(typeinfer::Type::String),
+                ),
+                &[],
+            );
+        }
+        vm.builtin(
+            "char_at",
+            Opcode::CharAt,
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::Tuple(vec![
+                    typeinfer::Type::String,
+                    typeinfer::Type::Integer,
+                ])),
+                Box::new(typeinfer::Type::Char),
+            ),
+            &[],
+        );
+        vm.builtin(
+            "char_code",
+            Opcode::CharCode,
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::Char),
+                Box::new(typeinfer::Type::Integer),
+            ),
+            &[],
+        );
+        vm.builtin(
+            "char_from_code",
+            Opcode::CharFromCode,
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::Integer),
+                Box::new(typeinfer::Type::Char),
+            ),
+            &[],
+        );
+        vm.builtin(
+            "fold_chars",
+            Opcode::FoldChars,
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::Tuple(vec![
+                    typeinfer::Type::Function(
+                        Box::new(typeinfer::Type::Tuple(vec![
+                            typeinfer::Type::Polymorphic("a".to_string()),
+                            typeinfer::Type::Char,
+                        ])),
+                        Box::new(typeinfer::Type::Polymorphic("a".to_string())),
+                    ),
+                    typeinfer::Type::Polymorphic("a".to_string()),
+                    typeinfer::Type::String,
+                ])),
+                Box::new(typeinfer::Type::Polymorphic("a".to_string())),
+            ),
+            &[],
+        );
+        vm.builtin(
+            "length",
+            Opcode::Length,
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::String),
+                Box::new(typeinfer::Type::Integer),
+            ),
+            &[],
+        );
+        vm.builtin(
+            "concat",
+            Opcode::Join,
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::Tuple(vec![
+                    typeinfer::Type::String,
+                    typeinfer::Type::String,
+                ])),
+                Box::new(typeinfer::Type::String),
+            ),
+            &[],
+        );
+        vm.builtin(
+            "slice",
+            Opcode::StringSlice,
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::Tuple(vec![
+                    typeinfer::Type::String,
+                    typeinfer::Type::Integer,
+                    typeinfer::Type::Integer,
+                ])),
+                Box::new(typeinfer::Type::String),
+            ),
+            &[],
+        );
+        vm.builtin(
+            "split",
+            Opcode::StringSplit,
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::Tuple(vec![
+                    typeinfer::Type::String,
+                    typeinfer::Type::String,
+                ])),
+                Box::new(typeinfer::Type::Array(Box::new(typeinfer::Type::String))),
+            ),
+            &[],
+        );
+        vm.builtin(
+            "contains",
+            Opcode::StringContains,
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::Tuple(vec![
+                    typeinfer::Type::String,
+                    typeinfer::Type::String,
+                ])),
+                Box::new(typeinfer::Type::Boolean),
+            ),
+            &[],
+        );
+        let sequence = typeinfer::Type::Polymorphic(typeinfer::SEQUENCE.to_string());
+        vm.overload(
+            "length",
+            typeinfer::Type::Function(
+                Box::new(sequence.clone()),
+                Box::new(typeinfer::Type::Integer),
+            ),
+        );
+        vm.overload(
+            "concat",
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::Tuple(vec![
+                    sequence.clone(),
+                    sequence.clone(),
+                ])),
+                Box::new(sequence),
+            ),
+        );
+        let array = typeinfer::Type::Array(Box::new(typeinfer::Type::Polymorphic("a".to_string())));
+        vm.builtin(
+            "at",
+            Opcode::ArrayAt,
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::Tuple(vec![
+                    array.clone(),
+                    typeinfer::Type::Integer,
+                ])),
+                Box::new(typeinfer::Type::Polymorphic("a".to_string())),
+            ),
+            &[],
+        );
+        vm.builtin(
+            "push",
+            Opcode::ArrayPush,
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::Tuple(vec![
+                    array.clone(),
+                    typeinfer::Type::Polymorphic("a".to_string()),
+                ])),
+                Box::new(array.clone()),
+            ),
+            &[],
+        );
+        let element = typeinfer::Type::Polymorphic("a".to_string());
+        let result = typeinfer::Type::Polymorphic("b".to_string());
+        vm.builtin(
+            "map",
+            Opcode::ArrayMap,
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::Tuple(vec![
+                    typeinfer::Type::Function(Box::new(element.clone()), Box::new(result.clone())),
+                    array.clone(),
+                ])),
+                Box::new(typeinfer::Type::Array(Box::new(result.clone()))),
+            ),
+            &[],
+        );
+        vm.builtin(
+            "filter",
+            Opcode::ArrayFilter,
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::Tuple(vec![
+                    typeinfer::Type::Function(
+                        Box::new(element.clone()),
+                        Box::new(typeinfer::Type::Boolean),
+                    ),
+                    array.clone(),
+                ])),
+                Box::new(array.clone()),
+            ),
+            &[],
+        );
+        vm.builtin(
+            "foldl",
+            Opcode::ArrayFoldl,
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::Tuple(vec![
+                    typeinfer::Type::Function(
+                        Box::new(typeinfer::Type::Tuple(vec![
+                            result.clone(),
+                            element.clone(),
+                        ])),
+                        Box::new(result.clone()),
+                    ),
+                    result.clone(),
+                    array.clone(),
+                ])),
+                Box::new(result.clone()),
+            ),
+            &[],
+        );
+        vm.builtin(
+            "foldr",
+            Opcode::ArrayFoldr,
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::Tuple(vec![
+                    typeinfer::Type::Function(
+                        Box::new(typeinfer::Type::Tuple(vec![element, result.clone()])),
+                        Box::new(result.clone()),
+                    ),
+                    result.clone(),
+                    array,
+                ])),
+                Box::new(result),
+            ),
+            &[],
+        );
+        let key = typeinfer::Type::Polymorphic("a".to_string());
+        let value = typeinfer::Type::Polymorphic("b".to_string());
+        let map = typeinfer::Type::Map(Box::new(key.clone()), Box::new(value.clone()));
+        vm.builtin(
+            "insert",
+            Opcode::MapInsert,
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::Tuple(vec![
+                    map.clone(),
+                    key.clone(),
+                    value.clone(),
+                ])),
+                Box::new(map.clone()),
+            ),
+            &[],
+        );
+        vm.builtin(
+            "lookup",
+            Opcode::MapLookup,
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::Tuple(vec![map.clone(), key.clone()])),
+                Box::new(value),
+            ),
+            &[],
+        );
+        vm.builtin(
+            "remove",
+            Opcode::MapRemove,
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::Tuple(vec![map.clone(), key.clone()])),
+                Box::new(map.clone()),
+            ),
+            &[],
+        );
+        vm.builtin(
+            "keys",
+            Opcode::MapKeys,
+            typeinfer::Type::Function(
+                Box::new(map.clone()),
+                Box::new(typeinfer::Type::Array(Box::new(key.clone()))),
+            ),
+            &[],
+        );
+        vm.builtin(
+            "has_key",
+            Opcode::MapHasKey,
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::Tuple(vec![map, key])),
+                Box::new(typeinfer::Type::Boolean),
+            ),
+            &[],
+        );
+        let list = typeinfer::Type::List(Box::new(typeinfer::Type::Polymorphic("a".to_string())));
+        vm.builtin(
+            "head",
+            Opcode::ListHead,
+            typeinfer::Type::Function(
+                Box::new(list.clone()),
+                Box::new(typeinfer::Type::Polymorphic("a".to_string())),
+            ),
+            &[],
+        );
+        vm.builtin(
+            "tail",
+            Opcode::ListTail,
+            typeinfer::Type::Function(Box::new(list.clone()), Box::new(list)),
+            &[],
+        );
+        vm.builtin(
+            "exit",
+            Opcode::Exit,
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::Integer),
+                Box::new(typeinfer::Type::Polymorphic("a".to_string())),
+            ),
+            &[],
+        );
+        vm.builtin(
+            "abort",
+            Opcode::Abort,
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::String),
+                Box::new(typeinfer::Type::Polymorphic("a".to_string())),
+            ),
+            &[],
+        );
+        for (id, opcode) in [
+            ("hex_encode", Opcode::HexEncode),
+            ("base64_encode", Opcode::Base64Encode),
+        ] {
+            vm.builtin(
+                id,
+                opcode,
+                typeinfer::Type::Function(
+                    Box::new(typeinfer::Type::Bytes),
+                    Box::new(typeinfer::Type::String),
+                ),
+                &[],
+            );
+        }
+        for (id, opcode) in [
+            ("hex_decode", Opcode::HexDecode),
+            ("base64_decode", Opcode::Base64Decode),
+        ] {
+            vm.builtin(
+                id,
+                opcode,
+                typeinfer::Type::Function(
+                    Box::new(typeinfer::Type::String),
+                    Box::new(typeinfer::Type::Bytes),
+                ),
+                &[],
+            );
+        }
+        vm.builtin(
+            "format_time",
+            Opcode::FormatTime,
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::Tuple(vec![
+                    typeinfer::Type::Timestamp,
+                    typeinfer::Type::String,
+                ])),
+                Box::new(typeinfer::Type::String),
+            ),
+            &[],
+        );
+        vm.builtin(
+            "parse_time",
+            Opcode::ParseTime,
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::Tuple(vec![
+                    typeinfer::Type::String,
+                    typeinfer::Type::String,
+                ])),
+                Box::new(typeinfer::Type::Timestamp),
+            ),
+            &[],
+        );
+        vm.builtin(
+            "add_time",
+            Opcode::AddTime,
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::Tuple(vec![
+                    typeinfer::Type::Timestamp,
+                    typeinfer::Type::Integer,
+                ])),
+                Box::new(typeinfer::Type::Timestamp),
+            ),
+            &[],
+        );
+        vm.builtin(
+            "time_diff",
+            Opcode::TimeDiff,
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::Tuple(vec![
+                    typeinfer::Type::Timestamp,
+                    typeinfer::Type::Timestamp,
+                ])),
+                Box::new(typeinfer::Type::Integer),
+            ),
+            &[],
+        );
+        // The gated builtins are always compiled, so that the builtins are
+        // laid out the same way in bytecode whether or not they are enabled.
+        for (id, opcode) in [
+            ("http_get", Opcode::HttpGet),
+            ("http_post", Opcode::HttpPost),
+            ("run_command", Opcode::RunCommand),
+            ("now", Opcode::Now),
+        ] {
+            let ip = vm.emit(vec![opcode, Opcode::Ret]);
+            vm.gated_builtins.insert(id.to_string(), ip);
+        }
+        vm.builtin(
+            "fst",
+            Opcode::Fst,
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::Tuple(vec![
+                    typeinfer::Type::Polymorphic("a".to_string()),
+                    typeinfer::Type::Polymorphic("b".to_string()),
+                ])),
+                Box::new(typeinfer::Type::Polymorphic("a".to_string())),
+            ),
+            &[],
+        );
+        vm.builtin(
+            "snd",
+            Opcode::Snd,
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::Tuple(vec![
+                    typeinfer::Type::Polymorphic("a".to_string()),
+                    typeinfer::Type::Polymorphic("b".to_string()),
+                ])),
+                Box::new(typeinfer::Type::Polymorphic("b".to_string())),
+            ),
+            &[],
+        );
+        vm.datatype("Option", &[("Some", true), ("None", false)]);
+        vm.datatype("Result", &[("Ok", true), ("Err", true)]);
+        vm.prelude();
+        vm
+    }
+
+    // Defines the functions of the prelude, which are written in the language
+    // itself and compiled along with the builtins. Like them, they are the
+    // same for every virtual machine, so loaded bytecode can refer to them,
+    // and are given declared types so that their arguments are checked.
+    fn prelude(&mut self) {
+        let ast = parser::parse(PRELUDE).ok().unwrap();
+        codegen::eval(self, &ast).ok().unwrap();
+        self.warnings.clear();
+        let parser = typeinfer::Type::Function(
+            Box::new(typeinfer::Type::String),
+            Box::new(typeinfer::Type::Datatype("Option".to_string())),
+        );
+        for (id, typ) in [
+            (
+                "char",
+                typeinfer::Type::Function(
+                    Box::new(typeinfer::Type::Char),
+                    Box::new(parser.clone()),
+                ),
+            ),
+            ("digit", parser.clone()),
+            (
+                "seq",
+                typeinfer::Type::Function(
+                    Box::new(typeinfer::Type::Tuple(vec![parser.clone(), parser.clone()])),
+                    Box::new(parser.clone()),
+                ),
+            ),
+            (
+                "many",
+                typeinfer::Type::Function(Box::new(parser.clone()), Box::new(parser.clone())),
+            ),
+        ] {
+            Arc::make_mut(&mut self.env.types).insert(id.to_string(), typ);
+        }
+    }
+
+    // Creates a channel carrying values of the given type and binds it to an
+    // identifier, so that the host can exchange values with scripts run on
+    // this virtual machine.
+    pub fn channel(&mut self, id: &str, element: typeinfer::Type) -> Channel {
+        let channel = Channel::new(element.clone());
+        let symbol = self.symbol(id);
+        self.env.insert(symbol, Value::Channel(channel.clone()));
+        Arc::make_mut(&mut self.env.types)
+            .insert(id.to_string(), typeinfer::Type::Channel(Box::new(element)));
+        channel
+    }
+
+    // Binds a value provided by the host to an identifier, so that programs
+    // can use it as a global of the given type.
+    pub fn global(&mut self, id: &str, typ: typeinfer::Type, value: Value) {
+        let symbol = self.symbol(id);
+        self.env.insert(symbol, value);
+        Arc::make_mut(&mut self.env.types).insert(id.to_string(), typ);
+    }
+
+    // Stops programs from redefining a global, such as configuration that the
+    // host shares between the scripts it runs. Defining it again is a runtime
+    // error that try does not catch.
+    pub fn freeze(&mut self, id: &str) {
+        let symbol = self.symbol(id);
+        self.frozen.insert(symbol);
+    }
+
+    // Limits the number of instructions that can be run, including those run
+    // by callbacks and by par_map's workers, after which running the program
+    // fails. The budget is shared by everything run until it is set again.
+    pub fn set_fuel(&mut self, fuel: u64) {
+        self.fuel = Some(fuel);
+        self.warned_fuel = false;
+    }
+
+    // Lets programs run any number of instructions again.
+    pub fn clear_fuel(&mut self) {
+        self.fuel = None;
+        self.warned_fuel = false;
+    }
+
+    // Returns the fuel that is left, if it is limited.
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    // Interns an identifier, giving the symbol instructions refer to it by.
+    pub fn symbol(&mut self, name: &str) -> Symbol {
+        Arc::make_mut(&mut self.names).intern(name)
+    }
+
+    // The environment of the function being run, or of the program itself
+    // outside of any function.
+    pub fn environment(&self) -> &Environment {
+        match self.callstack.last() {
+            Some((_, env, _, _)) => env,
+            None => &self.env,
+        }
+    }
+
+    // Discards the state left behind by a program that failed.
+    pub fn reset(&mut self) {
+        if self.pid != 0 {
+            self.suspend();
+            if let Some(main) = self.processes.remove(&0) {
+                self.resume(0, main);
+            }
+        }
+        self.stack.clear();
+        self.callstack.clear();
+        self.memo_calls.clear();
+        self.catches.clear();
+        self.defers.clear();
+        self.processes.clear();
+        self.runnable.clear();
+        self.waiting.clear();
+        self.mailboxes = HashMap::from([(0, VecDeque::new())]);
+        self.warned_call_depth = false;
+        self.warned_fuel = false;
+        if let Some(debugger) = &mut self.debugger {
+            debugger.resumed = false;
+        }
+    }
+
+    // Appends instructions to the program, returning the address of the first
+    // one.
+    pub fn emit(&mut self, instr: Vec<Opcode>) -> usize {
+        let ip = self.instructions.len();
+        Arc::make_mut(&mut self.instructions).extend(instr);
+        ip
+    }
+
+    // Calls a function value and runs it to completion, returning its result.
+    // This can be done while a program is running, by a builtin calling back
+    // into it, or while it has yielded to the host. The function runs in the
+    // current process on the same fuel and does not yield, and the state of
+    // the program is left as it was once it returns, even if it fails.
+    pub fn call(&mut self, fun: Value, arg: Value) -> Result<Value, codegen::InterpreterError> {
+        let ip = self.ip;
+        let sp = self.stack.len();
+        let depth = self.callstack.len();
+        let memo_calls = self.memo_calls.len();
+        let catches = self.catches.len();
+        self.calls += 1;
+        let result = self
+            .callback(fun, arg)
+            .map_err(|err| self.unwind(depth + 1, err));
+        self.calls -= 1;
+        self.ip = ip;
+        if result.is_err() {
+            self.defers.retain(|(frame, _)| *frame <= depth);
+            self.stack.truncate(sp);
+            self.callstack.truncate(depth);
+            self.memo_calls.truncate(memo_calls);
+            self.catches.truncate(catches);
+        }
+        result
+    }
+
+    fn callback(&mut self, fun: Value, arg: Value) -> Result<Value, codegen::InterpreterError> {
+        if let Value::NativeFunction(native) = &fun {
+            return self.call_native(native, arg);
+        }
+        self.check_depth()?;
+        // Returning to the end of the program stops the virtual machine.
+        self.ip = self.instructions.len();
+        self.stack.push(arg);
+        match fun {
+            Value::Function(ip, env) => {
+                self.callstack
+                    .push((ip, env, self.stack.len() - 1, self.ip));
+                self.ip = ip;
+            }
+            Value::Memo(cache, fun) => {
+                self.call_memo(cache, *fun)?;
+            }
+            value => err!(
+                self,
+                UnexpectedValue,
+                format!("Expected function but found {}.", value)
+            ),
+        }
+        // Yields and breakpoints are ignored, and waiting for messages is an
+        // error, so this only returns once the function has.
+        self.execute()?;
+        match self.stack.pop() {
+            Some(value) => Ok(value),
+            None => err!(self, StackUnderflow, "Stack underflow."),
+        }
+    }
+
+    // Returns a handle to a function value, so that the host can keep it and
+    // call it later.
+    pub fn handle(&mut self, fun: Value) -> Result<FunctionHandle, codegen::InterpreterError> {
+        match fun {
+            Value::Function(..) | Value::Memo(..) | Value::NativeFunction(..) => {
+                let slot = match self.handles.iter().position(|handle| handle.is_none()) {
+                    Some(slot) => {
+                        self.handles[slot] = Some(fun);
+                        slot
+                    }
+                    None => {
+                        self.handles.push(Some(fun));
+                        self.handles.len() - 1
+                    }
+                };
+                Ok(FunctionHandle { vm: self.id, slot })
+            }
+            value => err!(
+                self,
+                UnexpectedValue,
+                format!("Expected function but found {}.", value)
+            ),
+        }
+    }
+
+    pub fn call_handle(
+        &mut self,
+        handle: &FunctionHandle,
+        arg: Value,
+    ) -> Result<Value, codegen::InterpreterError> {
+        let fun = match self.handles.get(handle.slot) {
+            Some(Some(fun)) if handle.vm == self.id => fun.clone(),
+            _ => err!(self, InvalidHandle, "Invalid function handle."),
+        };
+        self.call(fun, arg)
+    }
+
+    pub fn release(&mut self, handle: FunctionHandle) {
+        if handle.vm == self.id && handle.slot < self.handles.len() {
+            self.handles[handle.slot] = None;
+        }
+    }
+
+    // Calls each of the functions registered for an event with the argument,
+    // returning their results.
+    pub fn emit_event(
+        &mut self,
+        event: &str,
+        arg: Value,
+    ) -> Result<Vec<Value>, codegen::InterpreterError> {
+        let handlers = self.handlers.get(event).cloned().unwrap_or_default();
+        let mut results = Vec::new();
+        for handler in handlers {
+            results.push(self.call(handler, arg.clone())?);
+        }
+        Ok(results)
+    }
+
+    // Splits the elements into one chunk for each available thread, and maps
+    // each chunk on a separate virtual machine sharing this one's program.
+    // Workers are not permitted to perform any effects, which ensures the
+    // function is pure, so the results do not depend upon the order in which
+    // the chunks are evaluated.
+    fn par_map(
+        &mut self,
+        fun: &Value,
+        elements: &[Value],
+    ) -> Result<Vec<Value>, codegen::InterpreterError> {
+        if elements.is_empty() {
+            return Ok(Vec::new());
+        }
+        let threads = thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1)
+            .min(elements.len());
+        let chunk_size = elements.len().div_ceil(threads);
+        // Each worker may use all of the fuel that is left, and what they used
+        // between them is taken from it once they have finished.
+        type Chunk = (Result<Vec<Value>, codegen::InterpreterError>, u64);
+        let fuel = self.fuel;
+        let chunks: Vec<Chunk> = thread::scope(|scope| {
+            let workers: Vec<_> = elements
+                .chunks(chunk_size)
+                .map(|chunk| {
+                    let mut worker = self.worker();
+                    scope.spawn(move || {
+                        let results = chunk
+                            .iter()
+                            .map(|element| worker.call(fun.clone(), element.clone()))
+                            .collect();
+                        let used = match (fuel, worker.fuel) {
+                            (Some(fuel), Some(left)) => fuel - left,
+                            _ => 0,
+                        };
+                        (results, used)
+                    })
+                })
+                .collect();
+            workers
+                .into_iter()
+                .map(|worker| match worker.join() {
+                    Ok(result) => result,
+                    Err(payload) => std::panic::resume_unwind(payload),
+                })
+                .collect()
+        });
+        let used: u64 = chunks.iter().map(|(_, used)| used).sum();
+        if let Some(fuel) = self.fuel {
+            if used > fuel {
+                self.fuel = Some(0);
+                err!(self, OutOfFuel, "Out of fuel.")
+            }
+            self.fuel = Some(fuel - used);
+        }
+        let mut results = Vec::with_capacity(elements.len());
+        for (chunk, _) in chunks {
+            results.extend(chunk?);
+        }
+        Ok(results)
+    }
+
+    // A merge sort, as the function deciding the order is written by the
+    // script, so it may fail or be inconsistent.
+    fn sort_by(
+        &mut self,
+        before: &Value,
+        mut elements: Vec<Value>,
+    ) -> Result<Vec<Value>, codegen::InterpreterError> {
+        if elements.len() < 2 {
+            return Ok(elements);
+        }
+        let right = elements.split_off(elements.len() / 2);
+        let mut left = self.sort_by(before, elements)?.into_iter().peekable();
+        let mut right = self.sort_by(before, right)?.into_iter().peekable();
+        let mut sorted = Vec::with_capacity(left.len() + right.len());
+        while let (Some(x), Some(y)) = (left.peek(), right.peek()) {
+            // Elements on the left stay first unless the one on the right
+            // comes before them, which keeps the sort stable.
+            let arg = Value::Tuple(vec![y.clone(), x.clone()]);
+            match self.call(before.clone(), arg)? {
+                Value::Boolean(true) => sorted.extend(right.next()),
+                Value::Boolean(false) => sorted.extend(left.next()),
+                value => err!(
+                    self,
+                    UnexpectedValue,
+                    format!("Expected boolean but found {}.", value)
+                ),
+            }
+        }
+        sorted.extend(left);
+        sorted.extend(right);
+        Ok(sorted)
+    }
+
+    // Builds a runtime error at the current source position, with a backtrace
+    // of the functions being run. The position of each frame but the
+    // innermost is that of the call it is making. Let bodies and match cases
+    // that are not in tail position run in frames of their own, which are
+    // folded into the function they are in.
+    // Compiles a pattern for the regex builtins, or finds it already compiled.
+    #[cfg(feature = "regex")]
+    fn regex(&mut self, pattern: &str) -> Result<Arc<regex::Regex>, codegen::InterpreterError> {
+        if let Some(regex) = self.regexes.get(pattern) {
+            return Ok(regex.clone());
+        }
+        match regex::Regex::new(pattern) {
+            Ok(regex) => {
+                let regex = Arc::new(regex);
+                self.regexes.insert(pattern.to_string(), regex.clone());
+                Ok(regex)
+            }
+            Err(msg) => err!(self, UnexpectedValue, msg),
+        }
+    }
+
+    fn error(&self, kind: codegen::RuntimeError, err: String) -> codegen::InterpreterError {
+        let mut backtrace = Vec::new();
+        let mut scope = Some((self.ip, self.line, self.col));
+        let mut ip = self.ip;
+        for (fun, _, _, return_ip) in self.callstack.iter().rev() {
+            let (frame_ip, line, col) = scope.take().unwrap_or_else(|| {
+                let (line, col) = self.position(ip);
+                (ip, line, col)
+            });
+            match self.symbols.get(fun) {
+                // Builtins have no source, so are not given a position.
+                Some(info) if info.line == usize::MAX => backtrace.push(codegen::Frame {
+                    function: info.name.clone(),
+                    ip: frame_ip,
+                    line: usize::MAX,
+                    col: usize::MAX,
+                }),
+                Some(info) => backtrace.push(codegen::Frame {
+                    function: info.name.clone(),
+                    ip: frame_ip,
+                    line,
+                    col,
+                }),
+                None => scope = Some((frame_ip, line, col)),
+            }
+            ip = *return_ip;
+        }
+        let (ip, line, col) = scope.unwrap_or_else(|| {
+            let (line, col) = self.position(ip);
+            (ip, line, col)
+        });
+        backtrace.push(codegen::Frame {
+            function: None,
+            ip,
+            line,
+            col,
+        });
+        codegen::InterpreterError::new(
+            codegen::ErrorKind::Runtime { kind, backtrace },
+            err,
+            self.line,
+            self.col,
+        )
+    }
+
+    // Finds the source position set last before an ip. Every call sets the
+    // position just before it, so for the ip a frame returns to this is the
+    // call it made.
+    fn position(&self, ip: usize) -> (usize, usize) {
+        let code = self.instructions.get(..ip).unwrap_or_default();
+        code.iter()
+            .rev()
+            .find_map(|opcode| match opcode {
+                Opcode::Srcpos(line, col) => Some((*line, *col)),
+                _ => None,
+            })
+            .unwrap_or((usize::MAX, usize::MAX))
+    }
+
+    // Builds the error for an instruction that found unexpected values on the
+    // stack, keeping the instruction and the values it left on the stack.
+    fn invalid(&self) -> codegen::InterpreterError {
+        let opcode = self.instructions[self.ip].clone();
+        let mut err = self.error(
+            codegen::RuntimeError::InvalidStack,
+            format!(
+                "Invalid stack for {} at {}.",
+                opcode.display(&self.names),
+                self.ip
+            ),
+        );
+        err.source = Some(Box::new(InvalidInstruction {
+            opcode,
+            ip: self.ip,
+            stack: self.stack.clone(),
+        }));
+        err
+    }
+
+    // The result of integer arithmetic that overflowed, given the wrapped
+    // result and how to compute it exactly from the operands.
+    fn overflowed(
+        &self,
+        wrapped: i64,
+        (x, y): (i64, i64),
+        exact: fn(BigInt, BigInt) -> BigInt,
+    ) -> Result<Value, codegen::InterpreterError> {
+        match self.overflow {
+            Overflow::Trap => err!(self, IntegerOverflow, "Integer overflow."),
+            Overflow::Wrap => Ok(Value::Integer(wrapped)),
+            Overflow::Promote => Ok(Value::BigInt(exact(BigInt::from(x), BigInt::from(y)))),
+        }
+    }
+
+    fn bigint(&self, n: BigInt) -> Value {
+        match (self.overflow, n.to_i64()) {
+            (Overflow::Promote, Some(i)) => Value::Integer(i),
+            _ => Value::BigInt(n),
+        }
+    }
+
+    fn call_native(
+        &self,
+        native: &NativeFunction,
+        arg: Value,
+    ) -> Result<Value, codegen::InterpreterError> {
+        match native.call(arg) {
+            Ok(value) => Ok(value),
+            Err(msg) => err!(self, NativeError, msg),
+        }
+    }
+
+    // Fails if calling a function would grow the call stack past its limit.
+    fn check_depth(&mut self) -> Result<(), codegen::InterpreterError> {
+        if self.callstack.len() >= self.max_call_depth {
+            err!(self, StackOverflow, "Stack overflow.")
+        }
+        if !self.warned_call_depth
+            && self
+                .call_depth_warning
+                .is_some_and(|warning| self.callstack.len() + 1 >= warning)
+        {
+            self.warned_call_depth = true;
+            let msg = format!("Call depth reached {} frames.", self.callstack.len() + 1);
+            self.warn(msg);
+        }
+        Ok(())
+    }
+
+    fn warn(&mut self, msg: String) {
+        self.warnings.push(codegen::Warning {
+            msg,
+            line: self.line,
+            col: self.col,
+        });
+    }
+
+    // Checks that the running code is permitted to perform an effect.
+    fn perform(&self, effect: effects::Effect) -> Result<(), codegen::InterpreterError> {
+        if !self.allowed_effects.contains(&effect) {
+            err!(
+                self,
+                EffectNotPermitted,
+                format!("Effect not permitted: {}.", effect)
+            )
+        }
+        if !self.memo_calls.is_empty() {
+            err!(
+                self,
+                EffectNotPermitted,
+                format!("Effect not permitted in memoized function: {}.", effect)
+            )
+        }
+        Ok(())
+    }
+
+    // Saves the state of the running process so that another can be run.
+    fn suspend(&mut self) {
+        let process = Process {
+            ip: self.ip,
+            stack: std::mem::take(&mut self.stack),
+            callstack: std::mem::take(&mut self.callstack),
+            memo_calls: std::mem::take(&mut self.memo_calls),
+            catches: std::mem::take(&mut self.catches),
+            defers: std::mem::take(&mut self.defers),
+        };
+        self.processes.insert(self.pid, process);
+    }
+
+    fn resume(&mut self, pid: usize, process: Process) {
+        self.pid = pid;
+        self.ip = process.ip;
+        self.stack = process.stack;
+        self.callstack = process.callstack;
+        self.memo_calls = process.memo_calls;
+        self.catches = process.catches;
+        self.defers = process.defers;
+    }
+
+    // Resumes the next process that is ready to run. If there is none, every
+    // process, including the main one, is waiting for a message that can
+    // never arrive.
+    fn schedule(&mut self) -> Result<(), codegen::InterpreterError> {
+        match self.runnable.pop_front() {
+            Some(pid) => match self.processes.remove(&pid) {
+                Some(process) => {
+                    self.resume(pid, process);
+                    Ok(())
+                }
+                None => unreachable!(),
+            },
+            None => err!(
+                self,
+                Deadlock,
+                "Deadlock: every process is waiting for a message."
+            ),
+        }
+    }
+
+    fn worker(&self) -> VirtualMachine {
         VirtualMachine {
-            instructions: Vec::new(),
+            instructions: Arc::clone(&self.instructions),
+            symbols: Arc::clone(&self.symbols),
+            names: Arc::clone(&self.names),
             ip: 0,
             stack: Vec::new(),
             callstack: Vec::new(),
             env: Environment::new(),
-            line: usize::max_value(),
-            col: usize::max_value(),
+            warnings: Vec::new(),
+            features: HashSet::new(),
+            optimize: false,
+            allowed_effects: effects::Effects::new(),
+            deadline: None,
+            fuel: self.fuel,
+            max_call_depth: self.max_call_depth,
+            call_depth_warning: None,
+            fuel_warning: None,
+            warned_call_depth: false,
+            warned_fuel: false,
+            net_timeout: self.net_timeout,
+            checked: self.checked,
+            overflow: self.overflow,
+            int_format: self.int_format.clone(),
+            output: None,
+            memos: self.memos.clone(),
+            memo_calls: Vec::new(),
+            catches: Vec::new(),
+            defers: Vec::new(),
+            handlers: HashMap::new(),
+            gated_builtins: self.gated_builtins.clone(),
+            frozen: self.frozen.clone(),
+            id: NEXT_VM_ID.fetch_add(1, Ordering::Relaxed),
+            handles: Vec::new(),
+            #[cfg(feature = "regex")]
+            regexes: HashMap::new(),
+            calls: 0,
+            debugger: None,
+            profile: None,
+            pid: 0,
+            next_pid: 1,
+            processes: HashMap::new(),
+            runnable: VecDeque::new(),
+            waiting: HashSet::new(),
+            mailboxes: HashMap::from([(0, VecDeque::new())]),
+            line: self.line,
+            col: self.col,
+        }
+    }
+
+    // Calls a memoized function with the argument on top of the stack. If the
+    // result is already cached it replaces the argument, otherwise a frame is
+    // pushed for the function and this returns true. Recursive calls made
+    // through the function's name go through the cache as well.
+    fn call_memo(&mut self, cache: usize, fun: Value) -> Result<bool, codegen::InterpreterError> {
+        let arg = match self.stack.last() {
+            Some(arg) => arg.clone(),
+            None => invalid!(self),
+        };
+        if let Some(result) = self.memos[cache].get(&arg) {
+            let len = self.stack.len();
+            self.stack[len - 1] = result.clone();
+            return Ok(false);
+        }
+        match &fun {
+            Value::Function(ip, env) => {
+                let env = match env.fun {
+                    Some((ident, _)) => {
+                        let memo = Value::Memo(cache, Box::new(fun.clone()));
+                        env.extended(HashMap::from([(ident, memo)]))
+                    }
+                    None => env.clone(),
+                };
+                let return_ip = self.ip;
+                self.ip = *ip;
+                self.callstack
+                    .push((*ip, env, self.stack.len() - 1, return_ip));
+                self.memo_calls.push((self.callstack.len(), cache, arg));
+                Ok(true)
+            }
+            _ => invalid!(self),
+        }
+    }
+
+    // Makes a function implemented by the host available to programs under
+    // the given name. The type is used to check the programs that call it,
+    // and the function is assumed to perform no effects.
+    pub fn register<F>(&mut self, id: &str, typ: typeinfer::Type, fun: F)
+    where
+        F: Fn(Value) -> Result<Value, String> + Send + Sync + 'static,
+    {
+        let native = NativeFunction {
+            name: id.to_string(),
+            fun: Arc::new(fun),
+        };
+        let symbol = self.symbol(id);
+        self.env.insert(symbol, Value::NativeFunction(native));
+        Arc::make_mut(&mut self.env.types).insert(id.to_string(), typ);
+        Arc::make_mut(&mut self.env.effects).insert(id.to_string(), effects::Effects::new());
+    }
+
+    // Defines http_get and http_post, which perform the net effect. They are
+    // not defined otherwise, so programs can only make requests if the host
+    // allows it.
+    pub fn enable_net(&mut self) {
+        let response = typeinfer::Type::Record(vec![
+            ("body".to_string(), typeinfer::Type::String),
+            ("headers".to_string(), typeinfer::Type::String),
+            ("status".to_string(), typeinfer::Type::Integer),
+        ]);
+        self.define(
+            "http_get",
+            self.gated_builtins["http_get"],
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::String),
+                Box::new(response.clone()),
+            ),
+            &[effects::Effect::Net],
+        );
+        self.define(
+            "http_post",
+            self.gated_builtins["http_post"],
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::Tuple(vec![
+                    typeinfer::Type::String,
+                    typeinfer::Type::String,
+                    typeinfer::Type::String,
+                ])),
+                Box::new(response),
+            ),
+            &[effects::Effect::Net],
+        );
+    }
+
+    // Defines run_command, which performs the process effect. It is not
+    // defined otherwise, so programs can only run commands if the host allows
+    // it.
+    pub fn enable_process(&mut self) {
+        self.define(
+            "run_command",
+            self.gated_builtins["run_command"],
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::Tuple(vec![
+                    typeinfer::Type::String,
+                    typeinfer::Type::Array(Box::new(typeinfer::Type::String)),
+                ])),
+                Box::new(typeinfer::Type::Tuple(vec![
+                    typeinfer::Type::Integer,
+                    typeinfer::Type::String,
+                    typeinfer::Type::String,
+                ])),
+            ),
+            &[effects::Effect::Process],
+        );
+    }
+
+    // Defines now, which performs the time effect. It is not defined
+    // otherwise, so programs can only read the clock if the host allows it.
+    pub fn enable_time(&mut self) {
+        self.define(
+            "now",
+            self.gated_builtins["now"],
+            typeinfer::Type::Function(
+                Box::new(typeinfer::Type::Unit),
+                Box::new(typeinfer::Type::Timestamp),
+            ),
+            &[effects::Effect::Time],
+        );
+    }
+
+    // Sends a request for http_get or http_post, and pushes the response as a
+    // record.
+    fn http(
+        &mut self,
+        method: &str,
+        url: &str,
+        body: Option<(&str, &[u8])>,
+    ) -> Result<(), codegen::InterpreterError> {
+        self.perform(effects::Effect::Net)?;
+        // A request may not run past the deadline the host has set, so a slow
+        // server can not keep control from the host for longer than that.
+        let timeout = match self.deadline {
+            Some(deadline) => self
+                .net_timeout
+                .min(deadline.saturating_duration_since(Instant::now())),
+            None => self.net_timeout,
+        };
+        match http::request(method, url, body, timeout) {
+            Ok(response) => {
+                let body = match String::from_utf8(response.body) {
+                    Ok(body) => body,
+                    Err(e) => err!(
+                        self,
+                        NetworkError,
+                        format!(
+                            "Invalid UTF-8 in response from {} at offset {}.",
+                            url,
+                            e.utf8_error().valid_up_to()
+                        )
+                    ),
+                };
+                self.stack.push(Value::Record(vec![
+                    ("body".to_string(), Value::Str(body)),
+                    (
+                        "headers".to_string(),
+                        Value::Str(response.headers.join("\n")),
+                    ),
+                    ("status".to_string(), Value::Integer(response.status)),
+                ]));
+                Ok(())
+            }
+            Err(http::Error::Timeout) => {
+                err!(self, Timeout, format!("Request to {} timed out.", url))
+            }
+            Err(http::Error::Failed(msg)) => err!(
+                self,
+                NetworkError,
+                format!("Request to {} failed: {}.", url, msg)
+            ),
+        }
+    }
+
+    // Returns from the current frame with the value on top of the stack.
+    fn ret(&mut self) -> Result<(), codegen::InterpreterError> {
+        match self.callstack.pop() {
+            Some((_, _, sp, ip)) => match self.stack.pop() {
+                Some(value) => {
+                    if let Some((depth, _, _)) = self.memo_calls.last() {
+                        if *depth == self.callstack.len() + 1 {
+                            if let Some((_, cache, arg)) = self.memo_calls.pop() {
+                                self.memos[cache].insert(arg, value.clone());
+                            }
+                        }
+                    }
+                    self.stack.truncate(sp);
+                    self.stack.push(value);
+                    self.ip = ip;
+                    self.run_defers(self.callstack.len() + 1)
+                }
+                _ => invalid!(self),
+            },
+            None => invalid!(self),
+        }
+    }
+
+    // Defines a datatype as if it were declared with type, with variants that
+    // take a value that can be of any type or take nothing.
+    fn datatype(&mut self, name: &str, variants: &[(&str, bool)]) {
+        let typ = typeinfer::Type::Datatype(name.to_string());
+        for (variant, param) in variants {
+            if *param {
+                let ip = self.emit(vec![
+                    Opcode::Dconst(name.to_string(), variant.to_string()),
+                    Opcode::Ret,
+                ]);
+                let ctor = typeinfer::Type::Function(
+                    Box::new(typeinfer::Type::Polymorphic("a".to_string())),
+                    Box::new(typ.clone()),
+                );
+                self.define(variant, ip, ctor, &[]);
+            } else {
+                let value =
+                    Value::Datatype(name.to_string(), variant.to_string(), Box::new(Value::Unit));
+                let symbol = self.symbol(variant);
+                self.env.insert(symbol, value);
+                Arc::make_mut(&mut self.env.types).insert(variant.to_string(), typ.clone());
+            }
+        }
+        let variants = variants
+            .iter()
+            .map(|(variant, _)| variant.to_string())
+            .collect();
+        Arc::make_mut(&mut self.env.datatypes).insert(name.to_string(), variants);
+    }
+
+    // Builtins are functions implemented by a single instruction that takes
+    // the argument from the top of the stack and replaces it with the result.
+    fn builtin(
+        &mut self,
+        id: &str,
+        opcode: Opcode,
+        typ: typeinfer::Type,
+        effects: &[effects::Effect],
+    ) {
+        let ip = self.emit(vec![opcode, Opcode::Ret]);
+        self.define(id, ip, typ, effects);
+    }
+
+    fn define(&mut self, id: &str, ip: usize, typ: typeinfer::Type, effects: &[effects::Effect]) {
+        Arc::make_mut(&mut self.symbols).insert(
+            ip,
+            FunctionInfo {
+                name: Some(id.to_string()),
+                line: usize::MAX,
+                col: usize::MAX,
+            },
+        );
+        let symbol = self.symbol(id);
+        self.env
+            .insert(symbol, Value::Function(ip, Environment::new()));
+        Arc::make_mut(&mut self.env.types).insert(id.to_string(), typ);
+        Arc::make_mut(&mut self.env.effects)
+            .insert(id.to_string(), effects.iter().cloned().collect());
+    }
+
+    // Gives a builtin that takes strings the type it has when it is called,
+    // in which it may be given arrays instead, see typeinfer::sequence_type.
+    fn overload(&mut self, id: &str, typ: typeinfer::Type) {
+        Arc::make_mut(&mut self.env.types).insert(typeinfer::sequence_type(id), typ);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::codegen;
+    use crate::vm;
+    use std::error::Error;
+    use std::sync::Arc;
+
+    #[test]
+    fn opcodes() {
+        for (i, info) in vm::OPCODES.iter().enumerate() {
+            assert!(!info.description.trim().is_empty());
+            for other in &vm::OPCODES[i + 1..] {
+                assert_ne!(info.name, other.name);
+            }
+        }
+        assert_eq!(vm::Opcode::Add.name(), "Add");
+        assert_eq!(vm::Opcode::Jz(2).name(), "Jz");
+        let reference = vm::opcode_reference();
+        assert_eq!(reference.lines().count(), vm::OPCODES.len() + 2);
+        assert!(reference.contains("| Or |  | y x -- x\\|\\|y | Logical or of two booleans. |"));
+    }
+
+    #[test]
+    fn environments() {
+        use crate::symbol::Names;
+        use std::collections::HashMap;
+        use vm::Value::Integer;
+
+        let mut names = Names::new();
+        let (y, x) = (names.intern("y"), names.intern("x"));
+        let mut env = vm::Environment::new();
+        env.insert(x, Integer(1));
+        env.insert(y, Integer(2));
+        let inner = env.extended(HashMap::from([(x, Integer(3))]));
+        assert!(Arc::ptr_eq(
+            &inner.parent.as_ref().unwrap().values,
+            &env.values
+        ));
+        assert_eq!(inner.get(&x), Some(&Integer(3)));
+        assert_eq!(inner.get(&y), Some(&Integer(2)));
+        assert_eq!(env.get(&x), Some(&Integer(1)));
+        assert_eq!(
+            inner.bindings(&names),
+            vec![(x, &Integer(3)), (y, &Integer(2))]
+        );
+
+        let same = env.extended(HashMap::new());
+        assert!(Arc::ptr_eq(&same.values, &env.values));
+        assert!(same.parent.is_none());
+    }
+
+    #[test]
+    fn diff() {
+        use vm::Value::{Integer, Record, Str, Tuple};
+
+        let left = Record(vec![
+            ("a".to_string(), Integer(1)),
+            (
+                "b".to_string(),
+                Tuple(vec![Integer(2), Str("x".to_string())]),
+            ),
+        ]);
+        let right = Record(vec![
+            (
+                "b".to_string(),
+                Tuple(vec![Integer(3), Str("y".to_string())]),
+            ),
+            ("a".to_string(), Integer(1)),
+        ]);
+        let differences = left.diff(&right);
+        assert_eq!(
+            differences,
+            vec![
+                vm::Difference {
+                    path: ".b.0".to_string(),
+                    left: Integer(2),
+                    right: Integer(3)
+                },
+                vm::Difference {
+                    path: ".b.1".to_string(),
+                    left: Str("x".to_string()),
+                    right: Str("y".to_string())
+                },
+            ]
+        );
+        assert_eq!(differences[1].to_string(), ".b.1: \"x\" ~= \"y\"");
+        assert!(left.diff(&left).is_empty());
+        let short = Tuple(vec![Integer(1)]);
+        let differences = short.diff(&Tuple(vec![Integer(1), Integer(2)]));
+        assert_eq!(differences.len(), 1);
+        assert_eq!(differences[0].to_string(), "(1) ~= (1, 2)");
+    }
+
+    #[test]
+    fn checked() {
+        let mut vm = vm::VirtualMachine::new();
+        vm.checked = true;
+        vm.ip = 0;
+        vm.instructions = Arc::new(vec![
+            vm::Opcode::Iconst(7),
+            vm::Opcode::Bconst(true),
+            vm::Opcode::Iconst(1),
+            vm::Opcode::Not,
+        ]);
+        let err = vm.run().err().unwrap();
+        assert_eq!(err.err, "Invalid stack for not at 3.");
+        match &err.kind {
+            codegen::ErrorKind::Runtime { kind, .. } => {
+                assert_eq!(*kind, codegen::RuntimeError::InvalidStack);
+            }
+            _ => panic!("expected a runtime error"),
         }
+        let source = err.source().unwrap();
+        assert_eq!(source.to_string(), "not at 3 with stack [7, true]");
+        let invalid = source.downcast_ref::<vm::InvalidInstruction>().unwrap();
+        assert_eq!(invalid.ip, 3);
+        assert_eq!(invalid.opcode.name(), "Not");
+        assert_eq!(
+            invalid.stack,
+            vec![vm::Value::Integer(7), vm::Value::Boolean(true)]
+        );
+
+        let mut vm = vm::VirtualMachine::new();
+        vm.checked = true;
+        vm.ip = 0;
+        vm.instructions = Arc::new(vec![vm::Opcode::Arg(0)]);
+        let err = vm.run().err().unwrap();
+        assert_eq!(err.err, "Invalid stack for arg 0 at 0.");
+
+        let mut vm = vm::VirtualMachine::new();
+        vm.checked = true;
+        vm.ip = 0;
+        let x = vm.symbol("x");
+        vm.instructions = Arc::new(vec![vm::Opcode::SetEnv(x)]);
+        let err = vm.run().err().unwrap();
+        assert_eq!(err.err, "Invalid stack for setenv x at 0.");
     }
 }
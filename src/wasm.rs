@@ -0,0 +1,871 @@
+use crate::codegen::{ErrorKind, InterpreterError};
+use crate::parser;
+use crate::typeinfer::{infer, type_of, Type, TypedAST};
+use crate::vm::{Overflow, VirtualMachine};
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+// Compiles programs to standalone WebAssembly modules, so that they can run
+// without the virtual machine. Only programs that use integers and booleans
+// are supported: integers are i64 and booleans i32, named functions become
+// wasm functions taking the elements of a tuple parameter as separate
+// parameters, and definitions and let bindings become globals and locals.
+// Functions that are used as values or that capture the locals of enclosing
+// functions can not be compiled. Integer arithmetic that overflows traps, as
+// it fails in the virtual machine, unless the virtual machine wraps around on
+// overflow, in which case the module does too. Programs can not be compiled
+// for a virtual machine that promotes integers to bigints. Division by zero
+// traps. Calls in tail position are compiled to return_call, from the tail
+// call extension of WebAssembly.
+//
+// The program itself is exported as main, returning the value of its last
+// expression, and every function defined at the top level is exported under
+// its name.
+
+#[derive(Clone, Copy, PartialEq)]
+enum ValType {
+    I32,
+    I64,
+}
+
+impl ValType {
+    fn byte(self) -> u8 {
+        match self {
+            ValType::I32 => 0x7f,
+            ValType::I64 => 0x7e,
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Binding {
+    Local(u32, ValType),
+    Global(u32, ValType),
+    Function(u32, Vec<ValType>, ValType),
+    // A local of an enclosing function, which can not be referred to.
+    Captured,
+}
+
+struct Function {
+    typ: u32,
+    locals: Vec<ValType>,
+    code: Vec<u8>,
+}
+
+#[derive(Default)]
+struct Module {
+    types: Vec<(Vec<ValType>, Vec<ValType>)>,
+    functions: Vec<Option<Function>>,
+    globals: Vec<ValType>,
+    exports: BTreeMap<String, u32>,
+    // Whether integer arithmetic wraps around on overflow rather than
+    // trapping.
+    wrapping: bool,
+}
+
+// The code for a function being compiled, along with the identifiers that are
+// in scope at the current point in it.
+struct Builder {
+    params: usize,
+    locals: Vec<ValType>,
+    scope: HashMap<String, Binding>,
+    code: Vec<u8>,
+    main: bool,
+    // Whether the expression being compiled is in tail position, in which
+    // case calls return what the function they call returns.
+    tail: bool,
+}
+
+impl Builder {
+    fn local(&mut self, typ: ValType) -> u32 {
+        self.locals.push(typ);
+        (self.params + self.locals.len() - 1) as u32
+    }
+}
+
+fn unsupported(what: &str) -> InterpreterError {
+    InterpreterError::new(
+        ErrorKind::Unsupported,
+        format!("Can not compile {} to WebAssembly.", what),
+        usize::MAX,
+        usize::MAX,
+    )
+}
+
+// Values whose type is not fixed by the program, such as the parameters of
+// functions that only do arithmetic on them, are taken to be integers. Calls
+// check that their arguments and results are the types the function was
+// compiled for.
+fn valtype(typ: &Type) -> Result<ValType, InterpreterError> {
+    match typ {
+        Type::Boolean => Ok(ValType::I32),
+        Type::Integer | Type::Polymorphic(_) => Ok(ValType::I64),
+        typ => Err(unsupported(&format!("values of type {}", typ))),
+    }
+}
+
+fn unsigned(code: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            code.push(byte);
+            break;
+        }
+        code.push(byte | 0x80);
+    }
+}
+
+fn signed(code: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+            code.push(byte);
+            break;
+        }
+        code.push(byte | 0x80);
+    }
+}
+
+fn name(code: &mut Vec<u8>, s: &str) {
+    unsigned(code, s.len() as u64);
+    code.extend(s.as_bytes());
+}
+
+fn section(module: &mut Vec<u8>, id: u8, count: usize, contents: &[u8]) {
+    let mut section = Vec::new();
+    unsigned(&mut section, count as u64);
+    section.extend(contents);
+    module.push(id);
+    unsigned(module, section.len() as u64);
+    module.extend(section);
+}
+
+impl Module {
+    fn typ(&mut self, params: Vec<ValType>, results: Vec<ValType>) -> u32 {
+        let signature = (params, results);
+        match self.types.iter().position(|typ| *typ == signature) {
+            Some(index) => index as u32,
+            None => {
+                self.types.push(signature);
+                (self.types.len() - 1) as u32
+            }
+        }
+    }
+
+    // Reserves the index of a function before its body is compiled, so that
+    // it can call itself and the other functions in its letrec group.
+    fn declare(
+        &mut self,
+        id: &str,
+        param: &TypedAST,
+        body: &TypedAST,
+    ) -> Result<Binding, InterpreterError> {
+        let params = match param {
+            TypedAST::Identifier(typ, _) => vec![valtype(typ)?],
+            TypedAST::Tuple(_, elements) => elements
+                .iter()
+                .map(|element| valtype(&type_of(element)))
+                .collect::<Result<_, _>>()?,
+            _ => Vec::new(),
+        };
+        let result = valtype(&type_of(body)).map_err(|_| {
+            unsupported(&format!("function {}, which returns {}", id, type_of(body)))
+        })?;
+        self.functions.push(None);
+        Ok(Binding::Function(
+            (self.functions.len() - 1) as u32,
+            params,
+            result,
+        ))
+    }
+
+    fn function(
+        &mut self,
+        binding: &Binding,
+        param: &TypedAST,
+        body: &TypedAST,
+        scope: &HashMap<String, Binding>,
+    ) -> Result<(), InterpreterError> {
+        let (index, params, result) = match binding {
+            Binding::Function(index, params, result) => (*index, params, *result),
+            _ => unreachable!(),
+        };
+        let mut builder = Builder {
+            params: params.len(),
+            locals: Vec::new(),
+            scope: scope
+                .iter()
+                .map(|(id, binding)| match binding {
+                    Binding::Local(..) => (id.to_string(), Binding::Captured),
+                    binding => (id.to_string(), binding.clone()),
+                })
+                .collect(),
+            code: Vec::new(),
+            main: false,
+            tail: true,
+        };
+        let ids: Vec<&TypedAST> = match param {
+            TypedAST::Identifier(..) => vec![param],
+            TypedAST::Tuple(_, elements) => elements.iter().collect(),
+            _ => Vec::new(),
+        };
+        for (i, (id, typ)) in ids.iter().zip(params).enumerate() {
+            if let TypedAST::Identifier(_, id) = id {
+                builder
+                    .scope
+                    .insert(id.to_string(), Binding::Local(i as u32, *typ));
+            }
+        }
+        self.expression(&mut builder, body)?;
+        builder.code.push(0x0b);
+        self.functions[index as usize] = Some(Function {
+            typ: self.typ(params.clone(), vec![result]),
+            locals: builder.locals,
+            code: builder.code,
+        });
+        Ok(())
+    }
+
+    // Binds a definition in the current scope, leaving its value on the
+    // stack. The definitions of the program are globals, so that functions
+    // can refer to them.
+    fn define(
+        &mut self,
+        builder: &mut Builder,
+        id: &str,
+        value: &TypedAST,
+    ) -> Result<Option<ValType>, InterpreterError> {
+        match value {
+            TypedAST::Function(_, param, body, ..) => {
+                let binding = self.declare(id, param, body)?;
+                builder.scope.insert(id.to_string(), binding.clone());
+                self.function(&binding, param, body, &builder.scope.clone())?;
+                if let (true, Binding::Function(index, ..)) = (builder.main, &binding) {
+                    self.exports.insert(id.to_string(), *index);
+                }
+                Ok(None)
+            }
+            _ => {
+                let typ = valtype(&type_of(value))?;
+                self.expression(builder, value)?;
+                if builder.main {
+                    self.globals.push(typ);
+                    let index = (self.globals.len() - 1) as u32;
+                    builder.code.push(0x24);
+                    unsigned(&mut builder.code, u64::from(index));
+                    builder.code.push(0x23);
+                    unsigned(&mut builder.code, u64::from(index));
+                    builder
+                        .scope
+                        .insert(id.to_string(), Binding::Global(index, typ));
+                } else {
+                    let index = builder.local(typ);
+                    builder.code.push(0x22);
+                    unsigned(&mut builder.code, u64::from(index));
+                    builder
+                        .scope
+                        .insert(id.to_string(), Binding::Local(index, typ));
+                }
+                Ok(Some(typ))
+            }
+        }
+    }
+
+    // Compiles an expression, returning the type of the value it leaves on
+    // the stack, if any.
+    fn expression(
+        &mut self,
+        builder: &mut Builder,
+        ast: &TypedAST,
+    ) -> Result<Option<ValType>, InterpreterError> {
+        // Only the expressions that give the value of this one are in tail
+        // position, and they are marked as such below.
+        let tail = std::mem::replace(&mut builder.tail, false);
+        match ast {
+            TypedAST::BinaryOp(_, op, lhs, rhs, _, _) => {
+                let operands = valtype(&type_of(lhs))?;
+                self.expression(builder, lhs)?;
+                self.expression(builder, rhs)?;
+                if operands == ValType::I64 {
+                    if let Some(opcode) = match op {
+                        parser::Operator::Plus => Some(0x7c),
+                        parser::Operator::Minus => Some(0x7d),
+                        parser::Operator::Multiply => Some(0x7e),
+                        parser::Operator::Divide => Some(0x7f),
+                        parser::Operator::Mod => Some(0x81),
+                        _ => None,
+                    } {
+                        self.arithmetic(builder, opcode);
+                        return Ok(Some(ValType::I64));
+                    }
+                }
+                let (opcode, result) = match (op, operands) {
+                    (parser::Operator::Equal, ValType::I64) => (0x51, ValType::I32),
+                    (parser::Operator::NotEqual, ValType::I64) => (0x52, ValType::I32),
+                    (parser::Operator::Less, ValType::I64) => (0x53, ValType::I32),
+                    (parser::Operator::Greater, ValType::I64) => (0x55, ValType::I32),
+                    (parser::Operator::LessEqual, ValType::I64) => (0x57, ValType::I32),
+                    (parser::Operator::GreaterEqual, ValType::I64) => (0x59, ValType::I32),
+                    (parser::Operator::Equal, ValType::I32) => (0x46, ValType::I32),
+                    (parser::Operator::NotEqual, ValType::I32) => (0x47, ValType::I32),
+                    (parser::Operator::And, ValType::I32) => (0x71, ValType::I32),
+                    (parser::Operator::Or, ValType::I32) => (0x72, ValType::I32),
+                    (op, _) => return Err(unsupported(&format!("the {} operator", op))),
+                };
+                builder.code.push(opcode);
+                Ok(Some(result))
+            }
+            TypedAST::Boolean(b) => {
+                builder.code.push(0x41);
+                signed(&mut builder.code, *b as i64);
+                Ok(Some(ValType::I32))
+            }
+            TypedAST::Call(fun, arg, ..) => {
+                let (id, index, params, result) = match &**fun {
+                    TypedAST::Identifier(_, id) => match builder.scope.get(id) {
+                        Some(Binding::Function(index, params, result)) => {
+                            (id, *index, params.clone(), *result)
+                        }
+                        _ => return Err(unsupported(&format!("calls to {}", id))),
+                    },
+                    _ => return Err(unsupported("calls to anonymous functions")),
+                };
+                let args: Vec<&TypedAST> = match (&**arg, params.len()) {
+                    (TypedAST::Unit, 0) => Vec::new(),
+                    (TypedAST::Tuple(_, elements), n) if n > 1 => elements.iter().collect(),
+                    (arg, 1) => vec![arg],
+                    _ => return Err(unsupported("tuples")),
+                };
+                for (arg, param) in args.into_iter().zip(&params) {
+                    if self.expression(builder, arg)? != Some(*param) {
+                        return Err(unsupported(&format!(
+                            "the call to {} with an argument of type {}",
+                            id,
+                            type_of(arg)
+                        )));
+                    }
+                }
+                if valtype(&type_of(ast))? != result {
+                    return Err(unsupported(&format!(
+                        "the call to {} returning {}",
+                        id,
+                        type_of(ast)
+                    )));
+                }
+                // Calls in tail position are return_calls, so that recursion
+                // in tail position runs in constant space.
+                builder.code.push(if tail { 0x12 } else { 0x10 });
+                unsigned(&mut builder.code, u64::from(index));
+                Ok(Some(result))
+            }
+            TypedAST::Define(_, id, value) => self.define(builder, id, value),
+            TypedAST::Function(Some(id), ..) => self.define(builder, id, ast),
+            TypedAST::Identifier(_, id) => match builder.scope.get(id) {
+                Some(Binding::Local(index, typ)) => {
+                    builder.code.push(0x20);
+                    unsigned(&mut builder.code, u64::from(*index));
+                    Ok(Some(*typ))
+                }
+                Some(Binding::Global(index, typ)) => {
+                    builder.code.push(0x23);
+                    unsigned(&mut builder.code, u64::from(*index));
+                    Ok(Some(*typ))
+                }
+                Some(Binding::Function(..)) => {
+                    Err(unsupported(&format!("the function {} as a value", id)))
+                }
+                Some(Binding::Captured) => {
+                    Err(unsupported(&format!("functions that capture {}", id)))
+                }
+                None => Err(unsupported(&format!("references to {}", id))),
+            },
+            TypedAST::If(conds, els, _, _) => {
+                let typ = valtype(&type_of(ast))?;
+                for (cond, then) in conds {
+                    self.expression(builder, cond)?;
+                    builder.code.push(0x04);
+                    builder.code.push(typ.byte());
+                    builder.tail = tail;
+                    self.expression(builder, then)?;
+                    builder.code.push(0x05);
+                }
+                builder.tail = tail;
+                self.expression(builder, els)?;
+                for _ in conds {
+                    builder.code.push(0x0b);
+                }
+                Ok(Some(typ))
+            }
+            TypedAST::Integer(i) => {
+                builder.code.push(0x42);
+                signed(&mut builder.code, *i);
+                Ok(Some(ValType::I64))
+            }
+            TypedAST::Let(param, value, body, _, _) => {
+                let id = match &**param {
+                    TypedAST::Identifier(_, id) => id,
+                    _ => return Err(unsupported("tuples")),
+                };
+                let typ = valtype(&type_of(value))?;
+                self.expression(builder, value)?;
+                let index = builder.local(typ);
+                builder.code.push(0x21);
+                unsigned(&mut builder.code, u64::from(index));
+                let scope = builder.scope.clone();
+                let main = builder.main;
+                builder
+                    .scope
+                    .insert(id.to_string(), Binding::Local(index, typ));
+                // Definitions in the body are only visible within it.
+                builder.main = false;
+                builder.tail = tail;
+                let result = self.expression(builder, body);
+                builder.scope = scope;
+                builder.main = main;
+                result
+            }
+            TypedAST::Letrec(functions) => {
+                let mut bindings = Vec::new();
+                for function in functions {
+                    if let TypedAST::Function(Some(id), param, body, ..) = function {
+                        let binding = self.declare(id, param, body)?;
+                        builder.scope.insert(id.to_string(), binding.clone());
+                        bindings.push((id, binding));
+                    }
+                }
+                for (function, (id, binding)) in functions.iter().zip(bindings) {
+                    if let TypedAST::Function(_, param, body, ..) = function {
+                        self.function(&binding, param, body, &builder.scope.clone())?;
+                        if let (true, Binding::Function(index, ..)) = (builder.main, &binding) {
+                            self.exports.insert(id.to_string(), *index);
+                        }
+                    }
+                }
+                Ok(None)
+            }
+            TypedAST::Program(_, expressions) => {
+                let mut result = None;
+                for (i, expression) in expressions.iter().enumerate() {
+                    builder.tail = tail && i + 1 == expressions.len();
+                    result = self.expression(builder, expression)?;
+                    if i + 1 != expressions.len() && result.is_some() {
+                        builder.code.push(0x1a);
+                    }
+                }
+                Ok(result)
+            }
+            TypedAST::UnaryOp(typ, parser::Operator::Minus, value) => {
+                valtype(typ)?;
+                builder.code.push(0x42);
+                signed(&mut builder.code, 0);
+                self.expression(builder, value)?;
+                self.arithmetic(builder, 0x7d);
+                Ok(Some(ValType::I64))
+            }
+            TypedAST::UnaryOp(_, parser::Operator::Not, value) => {
+                self.expression(builder, value)?;
+                builder.code.push(0x45);
+                Ok(Some(ValType::I32))
+            }
+            TypedAST::Array(..) => Err(unsupported("arrays")),
+            TypedAST::BigInt(_) => Err(unsupported("bigints")),
+            TypedAST::Bytes(_) => Err(unsupported("bytes")),
+            TypedAST::Char(_) => Err(unsupported("chars")),
+            TypedAST::Datatype(..) | TypedAST::Match(..) | TypedAST::Propagate(..) => {
+                Err(unsupported("datatypes"))
+            }
+            TypedAST::Field(..) | TypedAST::Record(..) | TypedAST::Update(..) => {
+                Err(unsupported("records"))
+            }
+            TypedAST::Defer(..) => Err(unsupported("defer expressions")),
+            TypedAST::Float(_) => Err(unsupported("floats")),
+            TypedAST::For(..) => Err(unsupported("for expressions")),
+            TypedAST::List(..) => Err(unsupported("lists")),
+            TypedAST::Map(..) => Err(unsupported("maps")),
+            TypedAST::Function(None, ..) => Err(unsupported("anonymous functions")),
+            TypedAST::String(_) => Err(unsupported("strings")),
+            TypedAST::Try(..) => Err(unsupported("try expressions")),
+            TypedAST::While(..) => Err(unsupported("while loops")),
+            TypedAST::Tuple(..) => Err(unsupported("tuples")),
+            TypedAST::Unit => Err(unsupported("unit")),
+            TypedAST::UnaryOp(..) => unreachable!(),
+        }
+    }
+
+    // Compiles integer addition, subtraction, multiplication, division or
+    // remainder of the two operands on the stack, given the opcode for it.
+    // Results that overflow trap, unless the module wraps around on overflow,
+    // in which case dividing the smallest integer by -1 gives itself rather
+    // than trapping as WebAssembly does.
+    fn arithmetic(&self, builder: &mut Builder, opcode: u8) {
+        // WebAssembly division traps on overflow and the others wrap around,
+        // so only those that behave otherwise in the virtual machine need
+        // more than the opcode.
+        if (opcode == 0x7f) != self.wrapping {
+            builder.code.push(opcode);
+            return;
+        }
+        let x = builder.local(ValType::I64);
+        let y = builder.local(ValType::I64);
+        let r = builder.local(ValType::I64);
+        let code = &mut builder.code;
+        let local = |code: &mut Vec<u8>, op: u8, index: u32| {
+            code.push(op);
+            unsigned(code, u64::from(index));
+        };
+        // Traps if the i32 on top of the stack is true.
+        let trap_if = |code: &mut Vec<u8>| code.extend([0x04, 0x40, 0x00, 0x0b]);
+        local(code, 0x21, y);
+        local(code, 0x21, x);
+        match opcode {
+            0x7c | 0x7d => {
+                local(code, 0x20, x);
+                local(code, 0x20, y);
+                code.push(opcode);
+                local(code, 0x22, r);
+                // A sum overflowed if its sign differs from that of both
+                // operands, and a difference if it differs from that of the
+                // first and the operands differ in sign.
+                local(code, 0x20, x);
+                local(code, 0x20, r);
+                code.push(0x85);
+                local(code, 0x20, if opcode == 0x7c { y } else { x });
+                local(code, 0x20, if opcode == 0x7c { r } else { y });
+                code.extend([0x85, 0x83, 0x42, 0x00, 0x53]);
+                trap_if(code);
+            }
+            0x7e => {
+                local(code, 0x20, x);
+                local(code, 0x20, y);
+                code.push(0x7e);
+                local(code, 0x22, r);
+                // A product overflowed if dividing it by one operand does not
+                // give the other, where the division itself traps for the
+                // smallest integer times -1.
+                local(code, 0x20, x);
+                code.extend([0x42, 0x00, 0x52, 0x04, 0x40]);
+                local(code, 0x20, r);
+                local(code, 0x20, x);
+                code.push(0x7f);
+                local(code, 0x20, y);
+                code.push(0x52);
+                trap_if(code);
+                code.push(0x0b);
+            }
+            0x81 => {
+                local(code, 0x20, x);
+                code.push(0x42);
+                signed(code, i64::MIN);
+                code.push(0x51);
+                local(code, 0x20, y);
+                code.extend([0x42, 0x7f, 0x51, 0x71]);
+                trap_if(code);
+                local(code, 0x20, x);
+                local(code, 0x20, y);
+                code.push(0x81);
+            }
+            _ => {
+                local(code, 0x20, y);
+                code.extend([0x42, 0x7f, 0x51, 0x04, 0x7e, 0x42, 0x00]);
+                local(code, 0x20, x);
+                code.extend([0x7d, 0x05]);
+                local(code, 0x20, x);
+                local(code, 0x20, y);
+                code.extend([0x7f, 0x0b]);
+            }
+        }
+    }
+
+    fn encode(self, main: Function) -> Vec<u8> {
+        let mut module = b"\0asm\x01\0\0\0".to_vec();
+        let mut functions: Vec<Function> = self.functions.into_iter().flatten().collect();
+        functions.push(main);
+
+        let mut types = Vec::new();
+        for (params, results) in &self.types {
+            types.push(0x60);
+            unsigned(&mut types, params.len() as u64);
+            types.extend(params.iter().map(|typ| typ.byte()));
+            unsigned(&mut types, results.len() as u64);
+            types.extend(results.iter().map(|typ| typ.byte()));
+        }
+        section(&mut module, 1, self.types.len(), &types);
+
+        let mut indices = Vec::new();
+        for function in &functions {
+            unsigned(&mut indices, u64::from(function.typ));
+        }
+        section(&mut module, 3, functions.len(), &indices);
+
+        if !self.globals.is_empty() {
+            let mut globals = Vec::new();
+            for typ in &self.globals {
+                globals.push(typ.byte());
+                globals.push(0x01);
+                globals.push(if *typ == ValType::I64 { 0x42 } else { 0x41 });
+                globals.push(0x00);
+                globals.push(0x0b);
+            }
+            section(&mut module, 6, self.globals.len(), &globals);
+        }
+
+        let mut exports = Vec::new();
+        for (id, index) in &self.exports {
+            name(&mut exports, id);
+            exports.push(0x00);
+            unsigned(&mut exports, u64::from(*index));
+        }
+        name(&mut exports, "main");
+        exports.push(0x00);
+        unsigned(&mut exports, (functions.len() - 1) as u64);
+        section(&mut module, 7, self.exports.len() + 1, &exports);
+
+        let mut code = Vec::new();
+        for function in &functions {
+            let mut body = Vec::new();
+            let mut groups: Vec<(u32, ValType)> = Vec::new();
+            for typ in &function.locals {
+                match groups.last_mut() {
+                    Some((count, last)) if last == typ => *count += 1,
+                    _ => groups.push((1, *typ)),
+                }
+            }
+            unsigned(&mut body, groups.len() as u64);
+            for (count, typ) in groups {
+                unsigned(&mut body, u64::from(count));
+                body.push(typ.byte());
+            }
+            body.extend(&function.code);
+            unsigned(&mut code, body.len() as u64);
+            code.extend(body);
+        }
+        section(&mut module, 10, functions.len(), &code);
+        module
+    }
+}
+
+// Type checks a program and compiles it to a WebAssembly module.
+pub fn compile(vm: &mut VirtualMachine, ast: &parser::AST) -> Result<Vec<u8>, InterpreterError> {
+    let mut ast = ast.clone();
+    parser::resolve_features(&mut ast, &vm.features);
+    let typed_ast = infer(
+        &ast,
+        Arc::make_mut(&mut vm.env.types),
+        Arc::make_mut(&mut vm.env.datatypes),
+    )?;
+    let mut module = Module {
+        wrapping: match vm.overflow {
+            Overflow::Trap => false,
+            Overflow::Wrap => true,
+            Overflow::Promote => {
+                return Err(unsupported(
+                    "programs whose integers are promoted to bigints on overflow",
+                ))
+            }
+        },
+        ..Module::default()
+    };
+    let mut builder = Builder {
+        params: 0,
+        locals: Vec::new(),
+        scope: HashMap::new(),
+        code: Vec::new(),
+        main: true,
+        tail: false,
+    };
+    let result = module.expression(&mut builder, &typed_ast)?;
+    builder.code.push(0x0b);
+    if module.exports.contains_key("main") {
+        return Err(InterpreterError::new(
+            ErrorKind::Unsupported,
+            "Can not export a function named main to WebAssembly, as the program is exported as main."
+                .to_string(),
+            usize::MAX,
+            usize::MAX,
+        ));
+    }
+    let main = Function {
+        typ: module.typ(Vec::new(), result.into_iter().collect()),
+        locals: builder.locals,
+        code: builder.code,
+    };
+    Ok(module.encode(main))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::codegen::{self, ErrorKind};
+    use crate::parser;
+    use crate::vm;
+    use crate::wasm;
+
+    fn compile(src: &str) -> Result<Vec<u8>, String> {
+        let mut vm = vm::VirtualMachine::new();
+        let ast = parser::parse(src).ok().unwrap();
+        wasm::compile(&mut vm, &ast).map_err(|err| {
+            assert_eq!(err.kind, ErrorKind::Unsupported);
+            err.err
+        })
+    }
+
+    #[test]
+    fn modules() {
+        assert_eq!(
+            compile("40 + 2"),
+            Ok(vec![
+                0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, // header
+                0x01, 0x05, 0x01, 0x60, 0x00, 0x01, 0x7e, // () -> i64
+                0x03, 0x02, 0x01, 0x00, // main has type 0
+                0x07, 0x08, 0x01, 0x04, b'm', b'a', b'i', b'n', 0x00, 0x00, // export main
+                0x0a, 0x27, 0x01, 0x25, 0x01, 0x03, 0x7e, // main has three i64 locals
+                0x42, 0x28, 0x42, 0x02, 0x21, 0x01, 0x21, 0x00, // x = 40, y = 2
+                0x20, 0x00, 0x20, 0x01, 0x7c, 0x22, 0x02, // r = x + y
+                0x20, 0x00, 0x20, 0x02, 0x85, 0x20, 0x01, 0x20, 0x02, 0x85, // x ^ r, y ^ r
+                0x83, 0x42, 0x00, 0x53, 0x04, 0x40, 0x00, 0x0b, // trap if both are negative
+                0x0b,
+            ])
+        );
+
+        let module = compile(
+            "def limit := 10
+             fn fib (n) -> if n < 2 then n else fib (n - 1) + fib (n - 2) end end
+             fn clamp (n, lo) -> let m = if n > limit then limit else n end in
+                 if m < lo then lo else m end end
+             letrec
+                 fn even (n) -> if n == 0 then true else odd (n - 1) end end
+                 fn odd (n) -> if n == 0 then false else even (n - 1) end end
+             end
+             even (clamp ((fib (7), -1)))",
+        )
+        .unwrap();
+        assert!(module.starts_with(b"\0asm\x01\0\0\0"));
+        // The calls even and odd make to each other are tail calls, while
+        // those fib makes to itself and the one the program makes are not.
+        let (fib, even, odd) = (0, 2, 3);
+        let call =
+            |opcode: u8, index: u8| module.windows(2).any(|window| window == [opcode, index]);
+        assert!(call(0x12, even) && call(0x12, odd) && call(0x10, even));
+        assert!(call(0x10, fib) && !call(0x12, fib));
+        for export in ["clamp", "even", "fib", "main", "odd"] {
+            assert!(module
+                .windows(export.len() + 1)
+                .any(|window| window[0] as usize == export.len()
+                    && &window[1..] == export.as_bytes()));
+        }
+    }
+
+    // Runs the main function of a module with node, if it is installed,
+    // giving its result or "trap" if it trapped.
+    fn run(module: &[u8]) -> Option<String> {
+        let path = std::env::temp_dir().join(format!("plover-{}.wasm", std::process::id()));
+        std::fs::write(&path, module).ok()?;
+        let output = std::process::Command::new("node")
+            .arg("-e")
+            .arg(
+                "const fs = require('fs');
+                 const module = new WebAssembly.Module(fs.readFileSync(process.argv[1]));
+                 try {
+                     console.log(String(new WebAssembly.Instance(module).exports.main()));
+                 } catch (e) {
+                     console.log('trap');
+                 }",
+            )
+            .arg(&path)
+            .output();
+        std::fs::remove_file(&path).ok()?;
+        Some(
+            String::from_utf8(output.ok()?.stdout)
+                .ok()?
+                .trim()
+                .to_string(),
+        )
+    }
+
+    #[test]
+    fn overflow() {
+        let min = "(-9223372036854775807 - 1)";
+        for program in [
+            "9223372036854775807 + 1".to_string(),
+            "-9223372036854775807 - 2".to_string(),
+            "4611686018427387904 * 2".to_string(),
+            "3037000500 * -3037000500".to_string(),
+            format!("-1 * {}", min),
+            format!("{} / -1", min),
+            format!("{} % -1", min),
+            format!("-{}", min),
+            "fn f (x, y) -> x * y - 1 end f ((-4611686018427387904, 2))".to_string(),
+        ] {
+            for overflow in [vm::Overflow::Trap, vm::Overflow::Wrap] {
+                let mut vm = vm::VirtualMachine::new();
+                vm.overflow = overflow;
+                let ast = parser::parse(&program).ok().unwrap();
+                let module = wasm::compile(&mut vm, &ast).unwrap();
+                let expected = match codegen::eval(&mut vm, &ast) {
+                    Ok(value) => value.to_string(),
+                    Err(err) => {
+                        assert_eq!(err.err, "Integer overflow.");
+                        "trap".to_string()
+                    }
+                };
+                if let Some(result) = run(&module) {
+                    assert_eq!(result, expected, "{}", program);
+                }
+            }
+        }
+
+        let mut vm = vm::VirtualMachine::new();
+        vm.overflow = vm::Overflow::Promote;
+        let ast = parser::parse("1 + 2").ok().unwrap();
+        assert_eq!(
+            wasm::compile(&mut vm, &ast).err().map(|err| err.err),
+            Some(
+                "Can not compile programs whose integers are promoted to bigints on overflow to WebAssembly."
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn unsupported() {
+        assert_eq!(
+            compile("def x := 1.5"),
+            Err("Can not compile values of type float to WebAssembly.".to_string())
+        );
+        assert_eq!(
+            compile("\"a\" ++ \"b\""),
+            Err("Can not compile values of type string to WebAssembly.".to_string())
+        );
+        assert_eq!(
+            compile("fn f (x) -> fn g (y) -> x + y end end"),
+            Err(
+                "Can not compile function f, which returns integer -> integer to WebAssembly."
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            compile("fn f (n) -> def x := n fn g (y) -> x + y end g (1) end"),
+            Err("Can not compile functions that capture x to WebAssembly.".to_string())
+        );
+        assert_eq!(
+            compile("fn main () -> 1 end 2"),
+            Err(
+                "Can not export a function named main to WebAssembly, as the program is exported as main."
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            compile("fn id (x) -> x end id (true)"),
+            Err(
+                "Can not compile the call to id with an argument of type boolean to WebAssembly."
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            compile("abs (-1)"),
+            Err("Can not compile calls to abs to WebAssembly.".to_string())
+        );
+    }
+}
@@ -0,0 +1,159 @@
+// A minimal implementation of ZMTP 3.0, the protocol that ZeroMQ sockets speak
+// over TCP, with just enough to serve the channels of a Jupyter kernel: the
+// NULL security mechanism and messages of one or more frames. The routing and
+// subscriptions that ZeroMQ sockets do are left to the caller, which answers
+// each peer on the connection it came from, and commands such as SUBSCRIBE are
+// ignored.
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+
+// Frames are flagged as having more frames after them in the same message,
+// as having a size that takes eight bytes, or as being commands.
+const MORE: u8 = 1;
+const LONG: u8 = 2;
+const COMMAND: u8 = 4;
+
+// Frames larger than this are taken to be a broken or hostile peer.
+const MAX_FRAME: u64 = 1 << 26;
+
+pub struct Connection {
+    stream: TcpStream,
+}
+
+fn invalid<T>(msg: &str) -> io::Result<T> {
+    Err(io::Error::new(io::ErrorKind::InvalidData, msg.to_string()))
+}
+
+impl Connection {
+    // Exchanges greetings and READY commands with a peer that has just
+    // connected, telling it the type of socket this is, such as ROUTER.
+    pub fn handshake(stream: TcpStream, socket_type: &str) -> io::Result<Connection> {
+        let mut connection = Connection { stream };
+        let mut greeting = [0; 64];
+        greeting[0] = 0xff;
+        greeting[9] = 0x7f;
+        greeting[10] = 3;
+        greeting[12..16].copy_from_slice(b"NULL");
+        connection.stream.write_all(&greeting)?;
+
+        let mut peer = [0; 64];
+        connection.stream.read_exact(&mut peer)?;
+        if peer[0] != 0xff || peer[9] & 1 != 1 || peer[10] < 3 {
+            return invalid("Unsupported ZMTP version.");
+        }
+        if &peer[12..16] != b"NULL" || peer[16..32].iter().any(|b| *b != 0) {
+            return invalid("Unsupported ZMTP security mechanism.");
+        }
+
+        let mut ready = vec![5];
+        ready.extend(b"READY");
+        ready.push(11);
+        ready.extend(b"Socket-Type");
+        ready.extend((socket_type.len() as u32).to_be_bytes());
+        ready.extend(socket_type.as_bytes());
+        connection.write_frame(&ready, COMMAND)?;
+        loop {
+            let (flags, body) = connection.read_frame()?;
+            if flags & COMMAND == 0 {
+                return invalid("Expected a READY command.");
+            }
+            match command_name(&body) {
+                Some(b"READY") => return Ok(connection),
+                Some(b"ERROR") => return invalid("The peer refused the connection."),
+                _ => {}
+            }
+        }
+    }
+
+    pub fn try_clone(&self) -> io::Result<Connection> {
+        Ok(Connection {
+            stream: self.stream.try_clone()?,
+        })
+    }
+
+    // Reads the frames of the next message, skipping any commands before it.
+    pub fn recv(&mut self) -> io::Result<Vec<Vec<u8>>> {
+        let mut frames = Vec::new();
+        loop {
+            let (flags, body) = self.read_frame()?;
+            if flags & COMMAND != 0 {
+                continue;
+            }
+            frames.push(body);
+            if flags & MORE == 0 {
+                return Ok(frames);
+            }
+        }
+    }
+
+    pub fn send(&mut self, frames: &[Vec<u8>]) -> io::Result<()> {
+        for (i, frame) in frames.iter().enumerate() {
+            self.write_frame(frame, if i + 1 < frames.len() { MORE } else { 0 })?;
+        }
+        self.stream.flush()
+    }
+
+    fn read_frame(&mut self) -> io::Result<(u8, Vec<u8>)> {
+        let mut flags = [0];
+        self.stream.read_exact(&mut flags)?;
+        let size = if flags[0] & LONG != 0 {
+            let mut size = [0; 8];
+            self.stream.read_exact(&mut size)?;
+            u64::from_be_bytes(size)
+        } else {
+            let mut size = [0];
+            self.stream.read_exact(&mut size)?;
+            size[0] as u64
+        };
+        if size > MAX_FRAME {
+            return invalid("ZMTP frame too large.");
+        }
+        let mut body = vec![0; size as usize];
+        self.stream.read_exact(&mut body)?;
+        Ok((flags[0], body))
+    }
+
+    fn write_frame(&mut self, body: &[u8], flags: u8) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(body.len() + 9);
+        if body.len() > 255 {
+            frame.push(flags | LONG);
+            frame.extend((body.len() as u64).to_be_bytes());
+        } else {
+            frame.push(flags);
+            frame.push(body.len() as u8);
+        }
+        frame.extend(body);
+        self.stream.write_all(&frame)
+    }
+}
+
+// The name that a command starts with, after the byte giving its length.
+fn command_name(body: &[u8]) -> Option<&[u8]> {
+    let len = *body.first()? as usize;
+    body.get(1..1 + len)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::zmq::Connection;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    #[test]
+    fn messages() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let peer = thread::spawn(move || {
+            let stream = TcpStream::connect(addr).unwrap();
+            let mut connection = Connection::handshake(stream, "DEALER").unwrap();
+            let frames = connection.recv().unwrap();
+            connection.send(&frames).unwrap();
+        });
+        let (stream, _) = listener.accept().unwrap();
+        let mut connection = Connection::handshake(stream, "ROUTER").unwrap();
+        let frames = vec![b"<IDS|MSG>".to_vec(), Vec::new(), vec![7; 1000]];
+        connection.send(&frames).unwrap();
+        assert_eq!(connection.recv().unwrap(), frames);
+        peer.join().unwrap();
+    }
+}